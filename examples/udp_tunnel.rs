@@ -7,6 +7,7 @@ use futures::TryFutureExt;
 use moka::future::{Cache, CacheBuilder};
 use quic_tunnel::counters::TunnelCounters;
 use quic_tunnel::log::configure_logging;
+use quic_tunnel::shutdown::CancellationToken;
 use quic_tunnel::{get_tunnel_timeout, TunnelCacheKey};
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
@@ -34,7 +35,7 @@ struct UdpTunnel {
 async fn main() -> anyhow::Result<()> {
     let command: UdpTunnel = argh::from_env();
 
-    configure_logging();
+    configure_logging(&Default::default())?;
 
     let local_socket = UdpSocket::bind(command.local_addr).await?;
 
@@ -81,7 +82,7 @@ async fn main() -> anyhow::Result<()> {
         default_timeout,
     ));
 
-    let mut stats_handle = counts.spawn_stats_loop();
+    let mut stats_handle = counts.spawn_stats_loop(Default::default(), CancellationToken::new());
 
     select! {
         x = &mut local_handle => {
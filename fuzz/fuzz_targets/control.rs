@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use quic_tunnel::protocol::{ControlMessage, MAX_CONTROL_FRAME_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((x, used)) = ControlMessage::decode(data) {
+        assert!(used <= MAX_CONTROL_FRAME_LEN + 2);
+
+        // anything we accept must round trip
+        assert_eq!(x.encode().unwrap(), data[..used]);
+    }
+});
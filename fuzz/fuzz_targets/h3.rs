@@ -0,0 +1,42 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use quic_tunnel::h3::{
+    decode_fields, decode_settings, encode_settings, get_varint, put_varint,
+    CapsuleReader, MAX_DATA_CHUNK,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((x, used)) = get_varint(data) {
+        let mut buf = vec![];
+        put_varint(&mut buf, x);
+
+        // the shortest encoding is never longer than what was sent
+        assert!(buf.len() <= used);
+    }
+
+    let _ = decode_fields(data);
+
+    if let Ok(x) = decode_settings(data) {
+        let x: Vec<_> = x.into_iter().collect();
+
+        assert_eq!(decode_settings(&encode_settings(&x)).unwrap().len(), x.len());
+    }
+
+    // capsules split at every byte come out the same as all at once
+    let mut whole = CapsuleReader::default();
+    whole.push(data);
+
+    let mut split = CapsuleReader::default();
+
+    for x in data.chunks(1) {
+        split.push(x);
+
+        while let Ok(Some((ty, payload))) = split.next_capsule() {
+            assert!(payload.len() as u64 <= MAX_DATA_CHUNK);
+
+            let (a, b) = whole.next_capsule().unwrap().unwrap();
+            assert_eq!((a, &b), (ty, &payload));
+        }
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use quic_tunnel::protocol::{StreamPreamble, MAX_PREAMBLE_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((x, used)) = StreamPreamble::decode(data) {
+        assert!(used <= MAX_PREAMBLE_LEN);
+
+        // anything we accept must round trip
+        assert_eq!(x.encode(), data[..used]);
+    }
+});
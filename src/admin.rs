@@ -0,0 +1,292 @@
+//! A small JSON API on a Unix socket for operating a long-running server without restarting it.
+//!
+//! Send one request per line and read one response per line:
+//!
+//! ```text
+//! $ echo '{"cmd": "clients"}' | socat - UNIX-CONNECT:admin.sock
+//! {"ok":true,"data":[{"id":1,"remote_addr":"127.0.0.1:50792","connected_secs":12,"streams":1,"rtt_ms":0}]}
+//! ```
+//!
+//! Commands: `status`, `clients`, `streams`, `counters`, `usage`, `kill` (with an `id`), and `config`.
+//!
+//! Anyone who can open the socket can kill connections, so keep its permissions tight.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::select;
+use tracing::{debug, info, trace, warn};
+
+use crate::counters::TunnelCounters;
+use crate::registry::Registry;
+use crate::shutdown::CancellationToken;
+use crate::unix::{self, SocketFile, UnixListener, UnixStream};
+use crate::upgrade::OwnedFd;
+use crate::usage::UsageStore;
+
+/// requests are tiny. don't let a client make us buffer forever
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// uptime and how many clients and streams there are
+    Status,
+    /// connected tunnel clients
+    Clients,
+    /// active user streams with byte counts
+    Streams,
+    /// traffic totals, and broken down by connection and by listener
+    Counters,
+    /// totals for each client identity across reconnects and restarts. see the `usage` module
+    Usage,
+    /// close a tunnel client's connection
+    Kill { id: u64 },
+    /// the config the server was started with
+    Config,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AdminStatus {
+    pub uptime_secs: u64,
+    pub clients: usize,
+    pub streams: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct AdminResponse {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl AdminResponse {
+    fn ok(data: impl Serialize) -> Self {
+        match serde_json::to_value(data) {
+            Ok(x) => Self {
+                ok: true,
+                data: Some(x),
+                error: None,
+            },
+            Err(err) => Self::err(err),
+        }
+    }
+
+    fn err(err: impl ToString) -> Self {
+        Self {
+            ok: false,
+            data: None,
+            error: Some(err.to_string()),
+        }
+    }
+}
+
+pub struct AdminServer {
+    listener: UnixListener,
+    /// removed once the last connection is done
+    _file: SocketFile,
+    registry: Arc<Registry>,
+    counters: Arc<TunnelCounters>,
+    usage: Arc<UsageStore>,
+    config: Value,
+}
+
+impl AdminServer {
+    pub fn bind(
+        path: PathBuf,
+        registry: Arc<Registry>,
+        counters: Arc<TunnelCounters>,
+        usage: Arc<UsageStore>,
+        config: Value,
+    ) -> anyhow::Result<Self> {
+        let (listener, file) = unix::bind_listener(&path, &Default::default())?;
+
+        info!(path = %path.display(), "admin api listening");
+
+        Ok(Self {
+            listener,
+            _file: file,
+            registry,
+            counters,
+            usage,
+            config,
+        })
+    }
+
+    /// a copy of the socket to hand to a new process during an upgrade
+    #[cfg(unix)]
+    pub fn handover_fd(&self) -> std::io::Result<OwnedFd> {
+        use std::os::fd::AsFd;
+
+        self.listener.as_fd().try_clone_to_owned()
+    }
+
+    /// a copy of the socket to hand to a new process during an upgrade
+    #[cfg(not(unix))]
+    pub fn handover_fd(&self) -> std::io::Result<OwnedFd> {
+        match self.listener {}
+    }
+
+    pub async fn serve(self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let this = Arc::new(self);
+
+        loop {
+            let stream = select! {
+                x = this.listener.accept() => x,
+                _ = shutdown.cancelled() => break,
+            };
+
+            let stream = match stream {
+                Ok((x, _)) => x,
+                Err(err) => {
+                    warn!(?err, "admin accept failed");
+                    continue;
+                }
+            };
+
+            let this = this.clone();
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                select! {
+                    x = this.handle(stream) => {
+                        if let Err(err) = x {
+                            debug!(?err, "admin connection closed");
+                        }
+                    }
+                    _ = shutdown.cancelled() => {}
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn handle(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let (read, mut write) = stream.into_split();
+
+        let mut read = BufReader::new(read);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+
+            let n = (&mut read)
+                .take(MAX_REQUEST_LEN as u64)
+                .read_line(&mut line)
+                .await?;
+
+            if n == 0 {
+                return Ok(());
+            }
+
+            // we can't tell where the next request starts, so give up on this connection
+            let too_long = n == MAX_REQUEST_LEN && !line.ends_with('\n');
+
+            let response = if too_long {
+                AdminResponse::err("request is too long")
+            } else if line.trim().is_empty() {
+                continue;
+            } else {
+                match serde_json::from_str::<AdminRequest>(&line) {
+                    Ok(x) => self.respond(x),
+                    Err(err) => AdminResponse::err(err),
+                }
+            };
+
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+
+            write.write_all(&response).await?;
+
+            if too_long {
+                return Ok(());
+            }
+        }
+    }
+
+    fn respond(&self, request: AdminRequest) -> AdminResponse {
+        trace!(?request, "admin request");
+
+        match request {
+            AdminRequest::Status => AdminResponse::ok(AdminStatus {
+                uptime_secs: self.registry.uptime_secs(),
+                clients: self.registry.num_clients(),
+                streams: self.registry.num_streams(),
+            }),
+            AdminRequest::Clients => AdminResponse::ok(self.registry.clients()),
+            AdminRequest::Streams => AdminResponse::ok(self.registry.streams()),
+            AdminRequest::Counters => AdminResponse::ok(self.counters.snapshot()),
+            AdminRequest::Usage => AdminResponse::ok(self.usage.snapshot()),
+            AdminRequest::Kill { id } => {
+                if self.registry.kill_client(id, "closed by admin") {
+                    info!(id, "client killed by admin");
+
+                    AdminResponse::ok(json!({ "killed": id }))
+                } else {
+                    AdminResponse::err(format!("no client with id {id}"))
+                }
+            }
+            AdminRequest::Config => AdminResponse::ok(&self.config),
+        }
+    }
+}
+
+/// talks to a running server's admin api
+pub struct AdminClient {
+    read: BufReader<ReadHalf<UnixStream>>,
+    write: WriteHalf<UnixStream>,
+    line: String,
+}
+
+impl AdminClient {
+    pub async fn connect(path: &Path) -> anyhow::Result<Self> {
+        let stream = unix::connect_stream(path)
+            .await
+            .with_context(|| format!("connecting to admin socket {}", path.display()))?;
+
+        let (read, write) = tokio::io::split(stream);
+
+        Ok(Self {
+            read: BufReader::new(read),
+            write,
+            line: String::new(),
+        })
+    }
+
+    pub async fn request<T: DeserializeOwned>(
+        &mut self,
+        request: &AdminRequest,
+    ) -> anyhow::Result<T> {
+        let mut x = serde_json::to_vec(request)?;
+        x.push(b'\n');
+
+        self.write.write_all(&x).await?;
+
+        self.line.clear();
+
+        if self.read.read_line(&mut self.line).await? == 0 {
+            anyhow::bail!("admin socket closed");
+        }
+
+        let response: AdminResponse = serde_json::from_str(&self.line)?;
+
+        if !response.ok {
+            anyhow::bail!(
+                "admin request failed: {}",
+                response.error.unwrap_or_default()
+            );
+        }
+
+        Ok(serde_json::from_value(
+            response.data.unwrap_or(Value::Null),
+        )?)
+    }
+}
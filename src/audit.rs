@@ -0,0 +1,216 @@
+//! An append-only record of who used the tunnel, for compliance review. Separate from logging, which is for debugging and
+//! changes with the log level.
+//!
+//! Each line is one JSON object with a `timestamp` and an `event`: `client_connected` once a tunnel client's handshake
+//! finishes, `client_disconnected` once its connection is closed, `auth_failed` for a handshake that fails on the
+//! certificates, `quota_exceeded` when a client goes over its quota, and `stream` once a user's stream finishes. Datagram
+//! clients' connections are recorded, but not their streams. The same events go to webhooks, see the `webhook` module.
+//!
+//! The file is opened for appending and never truncated or rotated. Rotate it with `logrotate` and `copytruncate`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+use tracing::warn;
+
+use crate::webhook::Webhooks;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    ClientConnected {
+        conn_id: u64,
+        peer: SocketAddr,
+        /// SHA-256 of the client's certificate, like in the `usage` module
+        client_fingerprint: Option<String>,
+        /// "reverse" or "forward", see `Role`
+        role: String,
+    },
+    ClientDisconnected {
+        conn_id: u64,
+        peer: SocketAddr,
+        client_fingerprint: Option<String>,
+        #[serde(with = "humantime_serde")]
+        connected_for: Duration,
+        bytes_sent: u64,
+        bytes_recv: u64,
+        close_reason: String,
+    },
+    /// the handshake failed, like for a certificate our CA didn't sign
+    AuthFailed { peer: SocketAddr, reason: String },
+    QuotaExceeded {
+        conn_id: u64,
+        peer: SocketAddr,
+        client_fingerprint: Option<String>,
+        reason: String,
+    },
+    Stream {
+        conn_id: u64,
+        stream_id: u64,
+        /// the user's address. `None` for users without one, like stdio
+        peer: Option<SocketAddr>,
+        listener: String,
+        service: String,
+        client_fingerprint: Option<String>,
+        /// from when the user was accepted
+        #[serde(with = "humantime_serde")]
+        duration: Duration,
+        bytes_from_tunnel: u64,
+        bytes_to_tunnel: u64,
+        close_reason: String,
+    },
+}
+
+impl AuditEvent {
+    /// every `event`, for picking some
+    pub const KINDS: &'static [&'static str] = &[
+        "client_connected",
+        "client_disconnected",
+        "auth_failed",
+        "quota_exceeded",
+        "stream",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ClientConnected { .. } => "client_connected",
+            Self::ClientDisconnected { .. } => "client_disconnected",
+            Self::AuthFailed { .. } => "auth_failed",
+            Self::QuotaExceeded { .. } => "quota_exceeded",
+            Self::Stream { .. } => "stream",
+        }
+    }
+
+    /// one line for people, like in a chat message
+    pub fn summary(&self) -> String {
+        let client = |fingerprint: &Option<String>, peer: &SocketAddr| match fingerprint {
+            Some(x) => format!("tunnel client {} ({peer})", &x[..x.len().min(16)]),
+            None => format!("tunnel client {peer}"),
+        };
+
+        match self {
+            Self::ClientConnected {
+                peer,
+                client_fingerprint,
+                ..
+            } => format!("{} connected", client(client_fingerprint, peer)),
+            Self::ClientDisconnected {
+                peer,
+                client_fingerprint,
+                close_reason,
+                ..
+            } => format!(
+                "{} disconnected: {close_reason}",
+                client(client_fingerprint, peer)
+            ),
+            Self::AuthFailed { peer, reason } => {
+                format!("tunnel client {peer} failed to authenticate: {reason}")
+            }
+            Self::QuotaExceeded {
+                peer,
+                client_fingerprint,
+                reason,
+                ..
+            } => format!(
+                "{} is over its quota: {reason}",
+                client(client_fingerprint, peer)
+            ),
+            Self::Stream {
+                peer,
+                service,
+                close_reason,
+                ..
+            } => match peer {
+                Some(x) => format!("stream from {x} to {service} finished: {close_reason}"),
+                None => format!("stream to {service} finished: {close_reason}"),
+            },
+        }
+    }
+}
+
+/// durations are recorded to the millisecond
+pub fn millis(x: Duration) -> Duration {
+    Duration::from_millis(x.as_millis() as u64)
+}
+
+#[derive(Serialize)]
+pub(crate) struct AuditLine<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+impl<'a> AuditLine<'a> {
+    pub fn now(event: &'a AuditEvent) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+        }
+    }
+}
+
+/// Where audit events go: the file, and webhooks that want them. Without either, they go nowhere.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Option<Mutex<File>>,
+    webhooks: Webhooks,
+}
+
+impl AuditLog {
+    pub fn open(path: Option<PathBuf>) -> io::Result<Self> {
+        let file = match &path {
+            Some(x) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(x)?,
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            path,
+            file,
+            webhooks: Default::default(),
+        })
+    }
+
+    pub fn with_webhooks(mut self, x: Webhooks) -> Self {
+        self.webhooks = x;
+        self
+    }
+
+    /// whether there is anywhere for events of `kind` to go. the rest don't need to be put together
+    pub fn wants(&self, kind: &str) -> bool {
+        self.file.is_some() || self.webhooks.wants(kind)
+    }
+
+    /// send to webhooks, and append one line. each line is one write, so lines from different streams don't interleave
+    pub fn record(&self, event: AuditEvent) {
+        self.webhooks.send(&event);
+
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let x = serde_json::to_vec(&AuditLine::now(&event))
+            .map_err(io::Error::from)
+            .and_then(|mut x| {
+                x.push(b'\n');
+
+                file.lock().unwrap().write_all(&x)
+            });
+
+        if let Err(err) = x {
+            warn!(?err, path = ?self.path, ?event, "unable to write to the audit log");
+        }
+    }
+
+    /// wait up to `timeout` for webhooks to be sent, like before exiting
+    pub async fn flush(&self, timeout: Duration) {
+        self.webhooks.flush(timeout).await;
+    }
+}
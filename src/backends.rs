@@ -0,0 +1,496 @@
+//! More than one backend for a reverse proxy client, so its streams move off one that is down.
+//!
+//! New streams go to the first backend in the list that is up. The others are only used while the ones before them are down,
+//! like the servers in the `failover` module. Streams that are already open stay where they are.
+//!
+//! With a health check, every backend is checked each `interval`, by connecting to it or by sending it an HTTP GET. One that
+//! fails `fall` checks in a row is down, and one that passes `rise` in a row is up again. Without a health check, a backend
+//! is only skipped for the stream it didn't answer. When every backend is down, they are all tried anyway, since a check can
+//! be wrong.
+//!
+//! With a circuit breaker, a backend that fails `failures` dials in a row is skipped for `cooldown`, and streams that only it
+//! could take are reset right away with [`TunnelError::CircuitOpen`] instead of dialing it again. After the cooldown, one
+//! stream tries it. If that works the circuit closes, and if not it stays open for another cooldown. This keeps a dead
+//! service from getting a storm of connections from every stream the server sends.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::select;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+use tracing::{debug, info, trace, warn};
+
+use crate::client::{Backend, DialOptions};
+use crate::error::TunnelError;
+use crate::resolve::Resolver;
+use crate::shutdown::CancellationToken;
+use crate::stream::{Stream, TcpOptions};
+
+/// a status line longer than this is a broken backend
+const MAX_STATUS_LINE_LEN: usize = 1024;
+
+/// how a backend is checked
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HealthCheck {
+    /// it accepts a connection
+    #[default]
+    Tcp,
+    /// it answers a GET for `path` with a 2xx or 3xx status
+    Http { path: String },
+}
+
+/// "tcp", "http" for `/`, or "http:/healthz"
+impl FromStr for HealthCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => return Ok(Self::Tcp),
+            "http" => {
+                return Ok(Self::Http {
+                    path: "/".to_string(),
+                })
+            }
+            _ => {}
+        }
+
+        match s.strip_prefix("http:") {
+            Some(path) if path.starts_with('/') && !path.contains(char::is_whitespace) => {
+                Ok(Self::Http {
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "unknown health check {s:?}. use tcp, http, or http:/path"
+            )),
+        }
+    }
+}
+
+impl Display for HealthCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Http { path } => write!(f, "http:{path}"),
+        }
+    }
+}
+
+impl Serialize for HealthCheck {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HealthCheck {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckOptions {
+    pub check: HealthCheck,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// how long one check gets, connecting included
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// failed checks in a row before a backend is down
+    pub fall: u32,
+    /// passed checks in a row before a backend that was down is up again
+    pub rise: u32,
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        Self {
+            check: HealthCheck::Tcp,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            fall: 2,
+            rise: 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitOptions {
+    /// failed dials in a row before a backend is skipped
+    pub failures: u32,
+    /// how long it is skipped
+    #[serde(with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitOptions {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    /// dials in a row
+    failures: u32,
+    /// skipped until then
+    open_until: Option<Instant>,
+}
+
+impl Circuit {
+    /// `Err` with how long until it may be dialed, while it is open. once the cooldown has passed, one caller that can
+    /// `probe` gets through and the rest wait another cooldown
+    fn admit(&mut self, options: &CircuitOptions, probe: bool) -> Result<(), Duration> {
+        let Some(until) = self.open_until else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+
+        if now < until || !probe {
+            return Err(until.saturating_duration_since(now));
+        }
+
+        self.open_until = Some(now + options.cooldown);
+
+        Ok(())
+    }
+
+    /// true if this closed it
+    fn succeeded(&mut self) -> bool {
+        self.failures = 0;
+        self.open_until.take().is_some()
+    }
+
+    /// true if this opened it
+    fn failed(&mut self, options: &CircuitOptions) -> bool {
+        self.failures += 1;
+
+        if self.failures < options.failures {
+            return false;
+        }
+
+        self.open_until
+            .replace(Instant::now() + options.cooldown)
+            .is_none()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    backend: Backend,
+    /// what the health checks last decided. always true without them
+    up: Arc<AtomicBool>,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl Entry {
+    fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            up: Arc::new(AtomicBool::new(true)),
+            circuit: Default::default(),
+        }
+    }
+}
+
+/// A backend and the ones it fails over to, in order.
+#[derive(Clone, Debug)]
+pub struct BackendList {
+    entries: Vec<Entry>,
+}
+
+impl BackendList {
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            entries: vec![Entry::new(backend)],
+        }
+    }
+
+    pub fn push(&mut self, backend: Backend) {
+        self.entries.push(Entry::new(backend));
+    }
+
+    pub fn get(&self, i: usize) -> &Backend {
+        &self.entries[i].backend
+    }
+
+    /// false once the health checks decide the `i`th backend is down
+    pub fn is_up(&self, i: usize) -> bool {
+        self.entries[i].up.load(Ordering::Relaxed)
+    }
+
+    /// the backends to try and their places in the list, best first. every backend if none are up
+    fn candidates(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        let any_up = self.entries.iter().any(|x| x.up.load(Ordering::Relaxed));
+
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(move |(_, x)| !any_up || x.up.load(Ordering::Relaxed))
+    }
+
+    /// connect to the first backend that answers, giving each `options.timeout`. also returns its place in the list
+    pub async fn connect(
+        &self,
+        tcp: &TcpOptions,
+        resolver: &Resolver,
+        options: &DialOptions,
+    ) -> anyhow::Result<(usize, Stream)> {
+        self.connect_with_probe(tcp, resolver, options, true).await
+    }
+
+    /// `connect` for a stream that hasn't arrived yet. this doesn't take the dial an open circuit allows after its cooldown,
+    /// so it is left for a real stream
+    pub async fn connect_ahead(
+        &self,
+        tcp: &TcpOptions,
+        resolver: &Resolver,
+        options: &DialOptions,
+    ) -> anyhow::Result<(usize, Stream)> {
+        self.connect_with_probe(tcp, resolver, options, false).await
+    }
+
+    async fn connect_with_probe(
+        &self,
+        tcp: &TcpOptions,
+        resolver: &Resolver,
+        options: &DialOptions,
+        probe: bool,
+    ) -> anyhow::Result<(usize, Stream)> {
+        let mut last_err = None;
+        // the soonest an open circuit lets a dial through
+        let mut retry_in: Option<Duration> = None;
+
+        for (i, entry) in self.candidates() {
+            let backend = &entry.backend;
+
+            if let Some(circuit) = &options.circuit {
+                let x = entry
+                    .circuit
+                    .lock()
+                    .expect("circuit lock poisoned")
+                    .admit(circuit, probe);
+
+                if let Err(x) = x {
+                    trace!(?backend, "circuit is open. skipping the backend for {x:?}");
+
+                    retry_in = Some(retry_in.map_or(x, |y| y.min(x)));
+                    continue;
+                }
+            }
+
+            match backend.connect_within(tcp, resolver, options.timeout).await {
+                Ok(x) => {
+                    if options.circuit.is_some()
+                        && entry
+                            .circuit
+                            .lock()
+                            .expect("circuit lock poisoned")
+                            .succeeded()
+                    {
+                        info!(?backend, "backend answered again. circuit closed");
+                    }
+
+                    return Ok((i, x));
+                }
+                Err(err) => {
+                    debug!(?err, ?backend, "backend didn't answer. trying the next one");
+
+                    if let Some(circuit) = &options.circuit {
+                        if entry
+                            .circuit
+                            .lock()
+                            .expect("circuit lock poisoned")
+                            .failed(circuit)
+                        {
+                            warn!(
+                                ?backend,
+                                failures = circuit.failures,
+                                "backend keeps failing. circuit open. streams to it are reset for {:?}",
+                                circuit.cooldown
+                            );
+                        }
+                    }
+
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match (last_err, retry_in) {
+            (Some(err), _) => Err(err),
+            (None, Some(x)) => Err(TunnelError::CircuitOpen(x).into()),
+            (None, None) => unreachable!("a backend list is never empty"),
+        }
+    }
+
+    /// `connect`, with retries. each attempt goes through the list again
+    pub async fn dial(
+        &self,
+        tcp: &TcpOptions,
+        resolver: &Resolver,
+        options: &DialOptions,
+    ) -> anyhow::Result<(usize, Stream)> {
+        options.retry(|| self.connect(tcp, resolver, options)).await
+    }
+
+    /// check every backend until `shutdown`. does nothing for a list of one, since there is nothing to fail over to
+    pub fn spawn_checks(
+        &self,
+        options: HealthCheckOptions,
+        tcp: TcpOptions,
+        resolver: Arc<Resolver>,
+        shutdown: CancellationToken,
+    ) {
+        if self.entries.len() < 2 {
+            return;
+        }
+
+        for entry in self.entries.iter() {
+            let entry = entry.clone();
+            let options = options.clone();
+            let tcp = tcp.clone();
+            let resolver = resolver.clone();
+            let shutdown = shutdown.clone();
+
+            tokio::spawn(async move {
+                select! {
+                    _ = check_loop(&entry, &options, &tcp, &resolver) => {}
+                    _ = shutdown.cancelled() => {}
+                }
+            });
+        }
+    }
+}
+
+async fn check_loop(
+    entry: &Entry,
+    options: &HealthCheckOptions,
+    tcp: &TcpOptions,
+    resolver: &Resolver,
+) {
+    let backend = &entry.backend;
+
+    let mut ticks = interval(options.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // checks in a row that disagree with `up`
+    let mut streak = 0;
+
+    loop {
+        ticks.tick().await;
+
+        let x = match timeout(
+            options.timeout,
+            check(backend, &options.check, tcp, resolver),
+        )
+        .await
+        {
+            Ok(x) => x,
+            Err(_) => Err(anyhow::anyhow!("timed out after {:?}", options.timeout)),
+        };
+
+        let up = entry.up.load(Ordering::Relaxed);
+
+        if x.is_ok() == up {
+            streak = 0;
+            continue;
+        }
+
+        streak += 1;
+
+        match x {
+            Err(err) if streak >= options.fall => {
+                warn!(
+                    ?err,
+                    ?backend,
+                    checks = streak,
+                    "backend is down. new streams go to the next one"
+                );
+
+                entry.up.store(false, Ordering::Relaxed);
+                streak = 0;
+            }
+            Err(err) => debug!(
+                ?err,
+                ?backend,
+                checks = streak,
+                "backend failed a health check"
+            ),
+            Ok(()) if streak >= options.rise => {
+                info!(?backend, checks = streak, "backend is back up");
+
+                entry.up.store(true, Ordering::Relaxed);
+                streak = 0;
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+async fn check(
+    backend: &Backend,
+    check: &HealthCheck,
+    tcp: &TcpOptions,
+    resolver: &Resolver,
+) -> anyhow::Result<()> {
+    let stream = backend.connect(tcp, resolver).await?;
+
+    let HealthCheck::Http { path } = check else {
+        return Ok(());
+    };
+
+    // unix sockets and pipes don't have a name, but HTTP/1.1 needs a Host
+    let host = match backend {
+        Backend::Tcp(x) => x.to_string(),
+        _ => "localhost".to_string(),
+    };
+
+    let (mut rx, mut tx) = stream.into_split()?;
+
+    tx.write_all(
+        format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: quic-tunnel\r\nConnection: close\r\n\r\n")
+            .as_bytes(),
+    )
+    .await?;
+
+    let mut buf = Vec::with_capacity(128);
+
+    while !buf.contains(&b'\n') {
+        anyhow::ensure!(
+            buf.len() < MAX_STATUS_LINE_LEN,
+            "the status line is too long"
+        );
+
+        if rx.read_buf(&mut buf).await? == 0 {
+            anyhow::bail!("the backend closed the connection without answering");
+        }
+    }
+
+    let line = String::from_utf8_lossy(&buf);
+    let line = line.lines().next().unwrap_or_default();
+
+    // like "HTTP/1.1 200 OK"
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|x| x.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("not an HTTP status line: {line:?}"))?;
+
+    anyhow::ensure!((200..400).contains(&status), "{path} answered {line:?}");
+
+    Ok(())
+}
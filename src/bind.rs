@@ -0,0 +1,85 @@
+//! Picking the network a client's sockets go out of, for hosts with more than one.
+//!
+//! With `addr`, sockets send from that local address. The kernel's routes still pick the interface, so on a multi-homed
+//! host this usually needs a policy routing rule for the address too. With `device`, sockets are bound to that interface with
+//! SO_BINDTODEVICE and only go out of it, no matter the routes. That only works on linux, and needs CAP_NET_RAW. Both can be
+//! given.
+//!
+//! `TransportOptions::bind` is for a client's QUIC socket, and its TCP fallback and proxy connections. `TcpOptions::bind` is for
+//! the connections a client makes to its backends. A client with its QUIC socket bound doesn't follow network changes, like a
+//! multipath path doesn't. See the `migrate` module.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::TcpSocket;
+
+/// Where a socket sends from. Nothing set is wherever the routes go.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindOptions {
+    /// a local address to send from, like 192.168.1.2
+    pub addr: Option<IpAddr>,
+    /// an interface to send out of, like eth0
+    pub device: Option<String>,
+}
+
+impl BindOptions {
+    pub fn is_set(&self) -> bool {
+        self.addr.is_some() || self.device.is_some()
+    }
+
+    /// where a client's QUIC socket is bound. without an address, it is IPv4
+    pub fn udp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()), 0)
+    }
+
+    pub fn udp_socket(&self) -> io::Result<std::net::UdpSocket> {
+        let addr = self.udp_addr();
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+        if let Some(x) = &self.device {
+            bind_device(&socket, x)?;
+        }
+
+        socket.bind(&addr.into())?;
+
+        Ok(socket.into())
+    }
+
+    /// bind a TCP socket that will connect to `peer`. an address of the other family can't reach it
+    pub fn apply_to_tcp(&self, socket: &TcpSocket, peer: SocketAddr) -> io::Result<()> {
+        if let Some(x) = &self.device {
+            bind_device(&SockRef::from(socket), x)?;
+        }
+
+        if let Some(x) = self.addr {
+            if x.is_ipv4() != peer.is_ipv4() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{peer} can't be reached from {x}"),
+                ));
+            }
+
+            socket.bind(SocketAddr::new(x, 0))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("sending out of interface {device} only works on linux. use its address instead"),
+    ))
+}
@@ -0,0 +1,85 @@
+//! Reusable buffers for the copy loops, so thousands of short-lived streams don't each allocate and free their own.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// room for a read chunk and its compressed or decompressed copy
+pub const BUFFER_LEN: usize = 64 * 1024;
+
+/// more than this many idle buffers are freed instead of kept. 64 MiB at the default size
+const MAX_POOLED: usize = 1024;
+
+/// shared by every stream in the process
+pub static BUFFERS: BufferPool = BufferPool::new(BUFFER_LEN, MAX_POOLED);
+
+#[derive(Debug)]
+pub struct BufferPool {
+    len: usize,
+    max_pooled: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    pub const fn new(len: usize, max_pooled: usize) -> Self {
+        Self {
+            len,
+            max_pooled,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// take a buffer, allocating one if none are free. it still holds whatever its last user wrote, so only read back what you wrote
+    pub fn get(&'static self) -> PooledBuffer {
+        let buf = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.len].into_boxed_slice());
+
+        PooledBuffer {
+            buf: Some(buf),
+            pool: self,
+        }
+    }
+
+    /// how many buffers are waiting to be reused
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+/// goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Option<Box<[u8]>>,
+    pool: &'static BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let Some(buf) = self.buf.take() else {
+            return;
+        };
+
+        let mut free = self.pool.free.lock().unwrap();
+
+        if free.len() < self.pool.max_pooled {
+            free.push(buf);
+        }
+    }
+}
@@ -139,6 +139,25 @@ pub fn cert_from_pem(path: PathBuf) -> anyhow::Result<rustls::Certificate> {
     Ok(key)
 }
 
+/// get every cert from a PEM file, like a leaf and its intermediates.
+pub fn certs_from_pem(path: PathBuf) -> anyhow::Result<Vec<rustls::Certificate>> {
+    info!("loading certificates from \"{}\"", path.display());
+
+    let mut reader = BufReader::new(
+        File::open(path.clone()).context(format!("failed opening {}", path.display()))?,
+    );
+
+    let certs = rustls_pemfile::certs(&mut reader)
+        .map(|x| x.map(|der| rustls::Certificate(der.as_ref().to_vec())))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", path.display());
+    }
+
+    Ok(certs)
+}
+
 /// get the first key from a PEM file.
 pub fn key_from_pem(path: PathBuf) -> anyhow::Result<rustls::PrivateKey> {
     info!("loading key from \"{}\"", path.display());
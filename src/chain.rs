@@ -0,0 +1,427 @@
+//! Reaching a server through other quic-tunnel servers, for clients in network segments that can't reach it directly.
+//!
+//! A client with `--via a --via b` connects to `a` with [`Role::Hop`] and sends `Hop` with `b`'s address. `a` forwards the
+//! client's QUIC datagrams to `b` as UDP packets, and the packets that come back as datagrams. On top of that connection the
+//! client runs another endpoint, whose "socket" is those datagrams, and connects through it to `b` the same way, with the
+//! real server as the next address. The client's own connection goes inside the last one. Every connection is TLS from the
+//! client, so the hops only see encrypted QUIC packets, and each hop only knows the one before it and the one after.
+//!
+//! Each layer costs [`HOP_OVERHEAD`] bytes of every packet, and QUIC needs 1200 byte packets to connect, so the connection
+//! to each hop starts with a bigger MTU to make room for the ones inside it. Paths with a smaller MTU than the outermost
+//! needs don't work. A hop that closes is connected again in the background, and packets in between are lost like on any
+//! network, so the connections inside it usually survive.
+//!
+//! Hops use the same server name as the server, so they need certs for it from the same CA.
+
+use std::fmt::Debug;
+use std::io::{self, IoSliceMut};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use futures::StreamExt;
+use ipnet::IpNet;
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::{AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+use tokio_util::bytes::Bytes;
+use tracing::{debug, info, trace, warn};
+
+use crate::counters::ScopedCounters;
+use crate::failover::{ServerAddr, ServerList};
+use crate::protocol::{CloseCode, ControlMessage, Role, PREAMBLE_TIMEOUT};
+use crate::quic::{
+    build_client_endpoint, matching_bind_address, quic_client_config, transport_config,
+    TransportOptions,
+};
+use crate::resolve::Resolver;
+use crate::tls::TlsOptions;
+
+/// what a QUIC packet inside a datagram costs: a short header with quinn's connection IDs, the packet number, the
+/// datagram frame, and the AEAD tag, with some room to spare
+pub const HOP_OVERHEAD: u16 = 40;
+
+/// the smallest packets QUIC connects with
+const MIN_MTU: u16 = 1200;
+
+/// how long to wait before connecting to a hop again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// datagrams from a hop waiting for the endpoint on top of it. more are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// where the server lets `Role::Hop` clients send their packets. nowhere by default
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HopOptions {
+    /// like 10.0.0.0/8. 0.0.0.0/0 and ::/0 allow anywhere
+    pub allow: Vec<IpNet>,
+}
+
+impl HopOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty()
+    }
+
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        self.allow.iter().any(|x| x.contains(&addr.ip()))
+    }
+}
+
+/// the server's side: read `conn`'s `Hop`, then forward its datagrams there and back until it closes
+pub async fn serve_hop(
+    conn: &Connection,
+    options: &HopOptions,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    let (_tx, mut rx) = conn.accept_bi().await?;
+
+    let next = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+        Some(ControlMessage::Hop { addr }) => addr,
+        x => {
+            conn.close(CloseCode::ProtocolError.into(), b"expected Hop");
+
+            anyhow::bail!("expected Hop, got {x:?}");
+        }
+    };
+
+    if !options.allows(next) {
+        conn.close(CloseCode::Unreachable.into(), b"not allowed");
+
+        anyhow::bail!("a hop to {next} isn't allowed");
+    }
+
+    let socket = UdpSocket::bind(matching_bind_address(next)?).await?;
+
+    socket.connect(next).await?;
+
+    info!(peer = %conn.remote_address(), %next, "forwarding a hop");
+
+    let to_next = async {
+        loop {
+            let x = match conn.read_datagram().await {
+                Ok(x) => x,
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                    debug!("connection closed");
+                    return Ok(());
+                }
+                Err(err) => return Err(anyhow::Error::from(err)),
+            };
+
+            counts.recv(x.len(), 0);
+
+            // like a router, a packet that can't be sent is lost
+            if let Err(err) = socket.send(&x).await {
+                trace!(?err, "dropping a packet for the next hop");
+            }
+        }
+    };
+
+    let from_next = async {
+        let mut buf = vec![0; u16::MAX as usize];
+
+        loop {
+            let n = match socket.recv(&mut buf).await {
+                Ok(x) => x,
+                // an ICMP error for an earlier packet
+                Err(err) => {
+                    trace!(?err, "the next hop didn't take a packet");
+                    continue;
+                }
+            };
+
+            match conn.send_datagram(Bytes::copy_from_slice(&buf[..n])) {
+                Ok(()) => counts.sent(n, 0),
+                Err(err) => trace!(?err, n, "dropping a packet from the next hop"),
+            }
+        }
+    };
+
+    tokio::select! {
+        x = to_next => x,
+        x = from_next => x,
+    }
+}
+
+/// an endpoint that reaches `target` through `hops`, in order. the connections to the hops use `transport` and `tls`,
+/// and only the first one goes over a real socket. connections on the endpoint use `config`
+#[allow(clippy::too_many_arguments)]
+pub async fn connect(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    transport: &TransportOptions,
+    tls: &TlsOptions,
+    hops: &[ServerAddr],
+    server_name: &str,
+    resolver: &Resolver,
+    target: SocketAddr,
+    config: ClientConfig,
+) -> anyhow::Result<Endpoint> {
+    let mut endpoint = build_client_endpoint(
+        ca.clone(),
+        cert.clone(),
+        key.clone(),
+        transport,
+        tls,
+        Role::Hop,
+    )?;
+
+    for (i, hop) in hops.iter().enumerate() {
+        let addr = resolve(hop, resolver, endpoint.local_addr()?).await?;
+
+        let next = match hops.get(i + 1) {
+            Some(x) => resolve(x, resolver, endpoint.local_addr()?).await?,
+            None => target,
+        };
+
+        // room for every connection inside this one
+        let layers = (hops.len() - i) as u16;
+
+        let mut transport_config = transport_config(transport)?;
+        transport_config.initial_mtu(
+            (MIN_MTU + HOP_OVERHEAD * layers).max(transport.initial_mtu.unwrap_or_default()),
+        );
+
+        let mut hop_config = quic_client_config(
+            ca.clone(),
+            cert.clone(),
+            key.clone(),
+            transport,
+            tls,
+            Role::Hop,
+        )?;
+        hop_config.transport_config(Arc::new(transport_config));
+
+        let hop = Hop {
+            endpoint,
+            config: hop_config,
+            addr,
+            server_name: server_name.to_string(),
+            next,
+        };
+
+        let socket = HopSocket::start(hop).await?;
+
+        info!(hop = %addr, %next, "connected to a hop");
+
+        let runtime = quinn::default_runtime().context("no async runtime found")?;
+
+        endpoint =
+            Endpoint::new_with_abstract_socket(EndpointConfig::default(), None, socket, runtime)?;
+    }
+
+    endpoint.set_default_client_config(config);
+
+    Ok(endpoint)
+}
+
+/// the first address of `x` that an endpoint bound to `local` can send to
+async fn resolve(
+    x: &ServerAddr,
+    resolver: &Resolver,
+    local: SocketAddr,
+) -> anyhow::Result<SocketAddr> {
+    ServerList::new(vec![x.clone()])
+        .candidates(resolver)
+        .await?
+        .into_iter()
+        .find(|x| x.is_ipv4() == local.is_ipv4())
+        .with_context(|| format!("{x} has no address this hop can reach"))
+}
+
+/// how to reach one hop, and where it should send our packets
+#[derive(Debug)]
+struct Hop {
+    /// the one under this hop
+    endpoint: Endpoint,
+    config: ClientConfig,
+    addr: SocketAddr,
+    server_name: String,
+    next: SocketAddr,
+}
+
+impl Hop {
+    async fn connect(&self) -> anyhow::Result<(Connection, SendStream)> {
+        let conn = self
+            .endpoint
+            .connect_with(self.config.clone(), self.addr, &self.server_name)?
+            .await?;
+
+        let (mut tx, _rx) = conn.open_bi().await?;
+
+        tx.write_all(&ControlMessage::Hop { addr: self.next }.encode()?)
+            .await?;
+
+        Ok((conn, tx))
+    }
+}
+
+/// A UDP socket for quinn that is really a connection to a hop. Packets go out as its datagrams, to the one address the
+/// hop sends them, and come back from it.
+struct HopSocket {
+    next: SocketAddr,
+    /// `None` while the hop is connected again
+    conn: Arc<Mutex<Option<Connection>>>,
+    incoming: Mutex<flume::r#async::RecvStream<'static, Bytes>>,
+}
+
+impl Debug for HopSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HopSocket")
+            .field("next", &self.next)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HopSocket {
+    /// connect to the hop, then keep it connected until the socket is dropped
+    async fn start(hop: Hop) -> anyhow::Result<Self> {
+        let (conn, tx) = hop
+            .connect()
+            .await
+            .with_context(|| format!("connecting to the hop at {}", hop.addr))?;
+
+        let (incoming, rx) = flume::bounded(QUEUE_LEN);
+
+        let x = Self {
+            next: hop.next,
+            conn: Arc::new(Mutex::new(Some(conn.clone()))),
+            incoming: Mutex::new(rx.into_stream()),
+        };
+
+        tokio::spawn(keep_hop(hop, conn, tx, x.conn.clone(), incoming));
+
+        Ok(x)
+    }
+}
+
+async fn keep_hop(
+    hop: Hop,
+    mut conn: Connection,
+    mut _tx: SendStream,
+    slot: Arc<Mutex<Option<Connection>>>,
+    incoming: flume::Sender<Bytes>,
+) {
+    loop {
+        loop {
+            let x = match conn.read_datagram().await {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, hop = %hop.addr, "lost the hop");
+                    break;
+                }
+            };
+
+            match incoming.try_send(x) {
+                Ok(()) => {}
+                Err(flume::TrySendError::Full(_)) => {
+                    trace!("the endpoint is behind. dropping a packet")
+                }
+                // the endpoint on top is gone
+                Err(flume::TrySendError::Disconnected(_)) => {
+                    conn.close(CloseCode::Done.into(), b"hop done");
+                    return;
+                }
+            }
+        }
+
+        *slot.lock().unwrap() = None;
+
+        loop {
+            if incoming.is_disconnected() {
+                return;
+            }
+
+            sleep(RECONNECT_DELAY).await;
+
+            match hop.connect().await {
+                Ok(x) => {
+                    info!(hop = %hop.addr, "connected to the hop again");
+
+                    (conn, _tx) = x;
+                    break;
+                }
+                Err(err) => debug!(?err, hop = %hop.addr, "unable to connect to the hop"),
+            }
+        }
+
+        *slot.lock().unwrap() = Some(conn.clone());
+    }
+}
+
+impl AsyncUdpSocket for HopSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<Result<usize, io::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        for x in transmits {
+            // the hop only sends to one place. anything else, and anything while it is reconnecting, is lost
+            let Some(conn) = conn.as_ref().filter(|_| x.destination == self.next) else {
+                continue;
+            };
+
+            let segment_size = x.segment_size.unwrap_or(x.contents.len()).max(1);
+
+            for start in (0..x.contents.len()).step_by(segment_size) {
+                let end = (start + segment_size).min(x.contents.len());
+
+                if let Err(err) = conn.send_datagram(x.contents.slice(start..end)) {
+                    trace!(?err, n = end - start, "dropping a packet for the hop");
+                }
+            }
+        }
+
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut incoming = self.incoming.lock().unwrap();
+
+        let x = match incoming.poll_next_unpin(cx) {
+            Poll::Ready(Some(x)) => x,
+            Poll::Ready(None) => return Poll::Ready(Err(io::Error::other("the hop is gone"))),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let n = x.len().min(bufs[0].len());
+
+        bufs[0][..n].copy_from_slice(&x[..n]);
+
+        meta[0] = RecvMeta {
+            addr: self.next,
+            len: n,
+            stride: n,
+            ecn: None,
+            dst_ip: None,
+        };
+
+        Poll::Ready(Ok(1))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        // there isn't one. this one has the family the hop sends with
+        Ok(match self.next {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        })
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
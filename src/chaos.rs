@@ -0,0 +1,291 @@
+//! Made-up trouble, for seeing how apps behave over a bad tunnel: dropped and delayed packets, and reset streams.
+//!
+//! Written like `drop=1%,delay=20ms,reset-streams=0.1%`, on either side or both. Drops and delays happen between quinn and
+//! the UDP socket, like the `obfs` shims, so QUIC sees a lossy, slow network and recovers the way it would from a real
+//! one. They only touch packets this side sends. A stream picked for a reset has both halves of its QUIC stream reset
+//! with [`CloseCode::Chaos`] at a random moment in its first [`RESET_WITHIN`], so streams that finish sooner get away.
+//!
+//! Packet faults need quic-tunnel built with the `chaos` feature. Without it, endpoints with chaos refuse to start.
+//!
+//! [`CloseCode::Chaos`]: crate::protocol::CloseCode::Chaos
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// streams picked for a reset are reset this long after they start, at most
+pub const RESET_WITHIN: Duration = Duration::from_secs(10);
+
+/// how much trouble to make. all off by default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChaosOptions {
+    /// the fraction of packets to drop, from 0 to 1
+    pub drop: f64,
+    /// how long to hold every packet before sending it
+    pub delay: Duration,
+    /// the fraction of streams to reset, from 0 to 1
+    pub reset_streams: f64,
+}
+
+impl ChaosOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.drop > 0.0 || !self.delay.is_zero() || self.reset_streams > 0.0
+    }
+
+    /// whether packets are touched, which needs the `chaos` feature
+    pub fn affects_packets(&self) -> bool {
+        self.drop > 0.0 || !self.delay.is_zero()
+    }
+
+    /// when to reset a new stream, or `None` to leave it alone
+    pub fn reset_after(&self) -> Option<Duration> {
+        if !chance(self.reset_streams) {
+            return None;
+        }
+
+        Some(RESET_WITHIN.mul_f64(random()))
+    }
+}
+
+/// a random number from 0 to 1. without randomness, 1, so nothing happens by chance
+fn random() -> f64 {
+    let mut x = [0; 4];
+
+    match SystemRandom::new().fill(&mut x) {
+        Ok(()) => f64::from(u32::from_le_bytes(x)) / f64::from(u32::MAX),
+        Err(_) => 1.0,
+    }
+}
+
+/// true `p` of the time
+fn chance(p: f64) -> bool {
+    p > 0.0 && random() < p
+}
+
+/// "1%" or "0.01"
+fn parse_fraction(key: &str, value: &str) -> Result<f64, String> {
+    let x = match value.strip_suffix('%') {
+        Some(x) => x.parse::<f64>().map(|x| x / 100.0),
+        None => value.parse::<f64>(),
+    };
+
+    match x {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+        _ => Err(format!(
+            "{key} must be a percentage like 1% or a fraction like 0.01, not \"{value}\""
+        )),
+    }
+}
+
+impl FromStr for ChaosOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut x = Self::default();
+
+        for part in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("\"{part}\" is not like drop=1%"))?;
+
+            match key.trim() {
+                "drop" => x.drop = parse_fraction("drop", value.trim())?,
+                "delay" => {
+                    x.delay = humantime::parse_duration(value.trim())
+                        .map_err(|err| format!("delay: {err}"))?
+                }
+                "reset-streams" => x.reset_streams = parse_fraction("reset-streams", value.trim())?,
+                key => {
+                    return Err(format!(
+                        "unknown chaos \"{key}\". use drop, delay, or reset-streams"
+                    ))
+                }
+            }
+        }
+
+        Ok(x)
+    }
+}
+
+impl Display for ChaosOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "drop={}%,delay={},reset-streams={}%",
+            self.drop * 100.0,
+            humantime::format_duration(self.delay),
+            self.reset_streams * 100.0,
+        )
+    }
+}
+
+/// serialized like it is written on the command line
+impl Serialize for ChaosOptions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChaosOptions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "chaos")]
+pub use shim::runtime;
+
+#[cfg(feature = "chaos")]
+mod shim {
+    use std::future::Future;
+    use std::io::{self, IoSliceMut};
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::Instant;
+
+    use quinn::udp::{RecvMeta, Transmit, UdpState};
+    use quinn::{AsyncTimer, AsyncUdpSocket, Runtime};
+    use tracing::trace;
+
+    use super::{chance, ChaosOptions};
+
+    /// `inner`, with chaos around every socket it is given
+    pub fn runtime(options: &ChaosOptions, inner: Arc<dyn Runtime>) -> Arc<dyn Runtime> {
+        Arc::new(ChaosRuntime {
+            inner,
+            options: options.clone(),
+        })
+    }
+
+    #[derive(Debug)]
+    struct ChaosRuntime {
+        inner: Arc<dyn Runtime>,
+        options: ChaosOptions,
+    }
+
+    impl Runtime for ChaosRuntime {
+        fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+            self.inner.new_timer(i)
+        }
+
+        fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+            self.inner.spawn(future)
+        }
+
+        fn wrap_udp_socket(&self, t: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+            // shared with `send_later`. quinn polls from one task, so this only waits on delayed packets
+            let inner = Arc::new(Mutex::new(self.inner.wrap_udp_socket(t)?));
+
+            // one queue per socket, so delayed packets keep their order
+            let delayed = match self.options.delay.is_zero() {
+                true => None,
+                false => {
+                    let (tx, rx) = flume::unbounded();
+
+                    self.inner.spawn(Box::pin(send_later(inner.clone(), rx)));
+
+                    Some(tx)
+                }
+            };
+
+            Ok(Box::new(ChaosSocket {
+                inner,
+                options: self.options.clone(),
+                delayed,
+            }))
+        }
+    }
+
+    #[derive(Debug)]
+    struct ChaosSocket {
+        inner: Arc<Mutex<Box<dyn AsyncUdpSocket>>>,
+        options: ChaosOptions,
+        /// packets go here to be sent after `options.delay`
+        delayed: Option<flume::Sender<(Instant, Transmit)>>,
+    }
+
+    impl AsyncUdpSocket for ChaosSocket {
+        fn poll_send(
+            &self,
+            state: &UdpState,
+            cx: &mut Context,
+            transmits: &[Transmit],
+        ) -> Poll<io::Result<usize>> {
+            if let Some(delayed) = &self.delayed {
+                let at = Instant::now() + self.options.delay;
+
+                for x in transmits {
+                    if chance(self.options.drop) {
+                        trace!(destination = %x.destination, "chaos dropped a packet");
+                        continue;
+                    }
+
+                    let _ = delayed.send((at, x.clone()));
+                }
+
+                return Poll::Ready(Ok(transmits.len()));
+            }
+
+            // one at a time, so each is dropped or not on its own. segmentation offload is off with chaos
+            let Some(x) = transmits.first() else {
+                return Poll::Ready(Ok(0));
+            };
+
+            if chance(self.options.drop) {
+                trace!(destination = %x.destination, "chaos dropped a packet");
+
+                return Poll::Ready(Ok(1));
+            }
+
+            self.inner
+                .lock()
+                .unwrap()
+                .poll_send(state, cx, std::slice::from_ref(x))
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut Context,
+            bufs: &mut [IoSliceMut<'_>],
+            meta: &mut [RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            self.inner.lock().unwrap().poll_recv(cx, bufs, meta)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.lock().unwrap().local_addr()
+        }
+
+        fn may_fragment(&self) -> bool {
+            self.inner.lock().unwrap().may_fragment()
+        }
+    }
+
+    async fn send_later(
+        socket: Arc<Mutex<Box<dyn AsyncUdpSocket>>>,
+        queue: flume::Receiver<(Instant, Transmit)>,
+    ) {
+        let state = UdpState::new();
+
+        // ends once the socket is dropped and the queue is empty
+        while let Ok((at, x)) = queue.recv_async().await {
+            tokio::time::sleep_until(at.into()).await;
+
+            // like quinn-udp, a packet that can't be sent is lost
+            let _ = std::future::poll_fn(|cx| {
+                socket
+                    .lock()
+                    .unwrap()
+                    .poll_send(&state, cx, std::slice::from_ref(&x))
+            })
+            .await;
+        }
+    }
+}
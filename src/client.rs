@@ -0,0 +1,1331 @@
+//! The reverse proxy client. It connects out to the server and forwards every stream the server opens to a nearby service.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::try_join_all;
+use futures::TryFutureExt;
+use quinn::{Connection, ConnectionError, Endpoint, RecvStream, SendStream, ZeroRttAccepted};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tokio::select;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, info_span, trace, warn, Instrument, Span};
+
+use crate::backends::{BackendList, CircuitOptions, HealthCheckOptions};
+use crate::chain;
+use crate::compress::{copy_bidirectional_with_compression, CloseMode, CompressAlgo, CopyOptions};
+use crate::control::{self, ControlEnd, HeartbeatOptions, Liveness};
+use crate::dump::{DebugDump, DumpOptions};
+use crate::error::TunnelError;
+use crate::failover::{ServerAddr, ServerList};
+use crate::health::{HealthServer, ReadyCheck};
+use crate::migrate::{follow_network, MigrationOptions};
+use crate::multipath::{self, LocalPath, MultipathOptions, MultipathPolicy};
+use crate::originate::TlsOriginator;
+use crate::padding::{self, PaddingOptions};
+use crate::pipe;
+use crate::protocol::{
+    negotiate_version, CloseCode, ControlMessage, Role, StreamPreamble, IMPLIED_VERSION,
+    MIN_PROTOCOL_VERSION, PREAMBLE_TIMEOUT, PROTOCOL_VERSION, SESSION_VERSION,
+};
+use crate::proxy::{ProxyKind, ProxyUrl};
+use crate::quic::{build_client_endpoint, log_path_mtu, quic_client_config, TransportOptions};
+use crate::rate_limit::ClientRateLimit;
+use crate::resolve::{HostAddr, ResolveOptions, Resolver};
+use crate::runtime;
+use crate::shutdown::{CancellationToken, TaskTracker};
+use crate::stream::{Stream, TcpOptions};
+use crate::tcp_fallback::{TcpFallbackClient, TcpFallbackOptions};
+use crate::tls::TlsOptions;
+use crate::transform::TransformPipeline;
+use crate::unix;
+use crate::vsock::{VsockAddr, VsockStream};
+
+/// the longest a retry waits for the backend, however many attempts failed
+const MAX_DIAL_BACKOFF: Duration = Duration::from_secs(5);
+
+/// the wait after the server refuses a connection because our cert has too many. the one holding our place is usually an
+/// old connection the server hasn't noticed is dead yet
+const TOO_MANY_CONNECTIONS_WAIT: Duration = Duration::from_secs(5);
+
+/// How hard the client tries to reach its backend for a stream before the server is told it is unreachable.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialOptions {
+    /// how long one attempt gets, across all of a hostname's addresses
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// attempts after the first one fails
+    pub retries: u32,
+    /// the wait before the first retry. it doubles after each one
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    /// stop dialing a backend that keeps failing for a while. see the `backends` module
+    pub circuit: Option<CircuitOptions>,
+}
+
+impl Default for DialOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            circuit: None,
+        }
+    }
+}
+
+/// the nearby service that streams are forwarded to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// a hostname is looked up again as its addresses get old
+    Tcp(HostAddr),
+    Unix(PathBuf),
+    /// a windows named pipe like `\\.\pipe\docker_engine`
+    NamedPipe(String),
+    Vsock(VsockAddr),
+}
+
+impl Backend {
+    pub async fn connect(&self, tcp: &TcpOptions, resolver: &Resolver) -> anyhow::Result<Stream> {
+        match self {
+            Self::Tcp(addr) => {
+                let stream = resolver.connect_tcp(addr, tcp).await?;
+
+                debug!(
+                    "connected to nearby tcp server at {} ({})",
+                    addr,
+                    stream.peer_addr()?
+                );
+
+                Ok(Stream::Tcp(stream))
+            }
+            Self::Unix(path) => {
+                debug!("connecting to unix socket at {}", path.display());
+
+                let stream = unix::connect_stream(path).await?;
+
+                Ok(Stream::Unix(stream))
+            }
+            Self::NamedPipe(name) => {
+                debug!("connecting to named pipe at {}", name);
+
+                let stream = pipe::connect(name).await?;
+
+                Ok(Stream::NamedPipe(stream))
+            }
+            Self::Vsock(addr) => {
+                debug!("connecting to vsock at {}", addr);
+
+                let stream = VsockStream::connect(*addr).await?;
+
+                Ok(Stream::Vsock(stream))
+            }
+        }
+    }
+
+    /// `connect`, giving up after `limit`
+    pub async fn connect_within(
+        &self,
+        tcp: &TcpOptions,
+        resolver: &Resolver,
+        limit: Duration,
+    ) -> anyhow::Result<Stream> {
+        match timeout(limit, self.connect(tcp, resolver)).await {
+            Ok(x) => x,
+            Err(_) => Err(TunnelError::Timeout(limit).into()),
+        }
+    }
+}
+
+impl DialOptions {
+    /// run `attempt` until it works, sleeping between tries, or return its last error
+    pub(crate) async fn retry<T, F, Fut>(&self, mut attempt: F) -> anyhow::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut backoff = self.backoff;
+        let mut n = 0;
+
+        loop {
+            let err = match attempt().await {
+                Ok(x) => return Ok(x),
+                Err(err) => err,
+            };
+
+            // retrying is what the circuit is there to stop
+            if let Some(TunnelError::CircuitOpen(_)) = err.downcast_ref() {
+                return Err(err);
+            }
+
+            if n >= self.retries {
+                return Err(err.context(format!("the backend didn't answer {} attempts", n + 1)));
+            }
+
+            debug!(
+                ?err,
+                attempt = n,
+                "connecting to the backend failed. retrying in {backoff:?}"
+            );
+
+            sleep(backoff).await;
+
+            backoff = (backoff * 2).min(MAX_DIAL_BACKOFF);
+            n += 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReverseProxyClient {
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    servers: ServerList,
+    server_name: Option<String>,
+    /// streams on other routes go to the first of these that is up. see the `backends` module
+    backends: Arc<BackendList>,
+    health_check: Option<HealthCheckOptions>,
+    /// backends for routes
+    routes: Arc<HashMap<String, BackendList>>,
+    /// QUIC send priorities by route. see `ListenerConfig::priority` for the server's side
+    priorities: Arc<HashMap<String, i32>>,
+    /// for tcp backends that only take TLS. see the `originate` module
+    backend_tls: Option<TlsOriginator>,
+    resolver: Arc<Resolver>,
+    migration: MigrationOptions,
+    multipath: MultipathOptions,
+    /// quic-tunnel servers to go through to reach the server, in order. see the `chain` module
+    chain: Vec<ServerAddr>,
+    proxy: Option<ProxyUrl>,
+    tcp_fallback: Option<TcpFallbackOptions>,
+    transport: TransportOptions,
+    tls: TlsOptions,
+    tcp: TcpOptions,
+    compress: CompressAlgo,
+    close_mode: CloseMode,
+    padding_rate: u64,
+    /// bytes per second in each direction, shared by every stream on a connection
+    max_bandwidth: Option<u64>,
+    heartbeat: HeartbeatOptions,
+    dial: DialOptions,
+    /// see the `health` module
+    health_listen: Option<SocketAddr>,
+    /// see the `dump` module
+    debug_dump: Option<Arc<DebugDump>>,
+    /// connections that got through hello and welcome. `/readyz` wants at least one
+    connected: Arc<AtomicUsize>,
+    shutdown: CancellationToken,
+    /// streams. these are waited on during shutdown
+    tracker: TaskTracker,
+    data_plane: Option<Handle>,
+    /// counts connection attempts across every path. only used for logs
+    conn_ids: AtomicU64,
+}
+
+/// one local path to the server and the endpoint bound to it
+#[derive(Clone, Debug)]
+struct Path {
+    endpoint: Endpoint,
+    /// `None` is wherever the routes go
+    via: Option<LocalPath>,
+}
+
+pub struct ReverseProxyClientBuilder {
+    inner: ReverseProxyClient,
+}
+
+impl ReverseProxyClient {
+    pub fn builder(
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+        server_addr: ServerAddr,
+        backend: Backend,
+    ) -> ReverseProxyClientBuilder {
+        let inner = Self {
+            ca,
+            cert,
+            key,
+            servers: ServerList::new(vec![server_addr]),
+            server_name: None,
+            backends: Arc::new(BackendList::new(backend)),
+            health_check: None,
+            routes: Default::default(),
+            priorities: Default::default(),
+            backend_tls: None,
+            resolver: Default::default(),
+            migration: MigrationOptions::default(),
+            multipath: MultipathOptions::default(),
+            chain: vec![],
+            proxy: None,
+            tcp_fallback: None,
+            // since the client initiates the connections, the client needs keep alive
+            transport: TransportOptions {
+                keep_alive: true,
+                ..Default::default()
+            },
+            tls: TlsOptions::default(),
+            tcp: TcpOptions::default(),
+            compress: CompressAlgo::None,
+            close_mode: CloseMode::default(),
+            padding_rate: padding::DEFAULT_RATE,
+            max_bandwidth: None,
+            heartbeat: HeartbeatOptions::default(),
+            dial: DialOptions::default(),
+            health_listen: None,
+            debug_dump: None,
+            connected: Default::default(),
+            shutdown: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            data_plane: None,
+            conn_ids: AtomicU64::new(0),
+        };
+
+        ReverseProxyClientBuilder { inner }
+    }
+}
+
+impl ReverseProxyClientBuilder {
+    /// the name on the server's certificate. if not set, it is guessed from the client cert's file name
+    pub fn server_name(mut self, x: impl Into<String>) -> Self {
+        self.inner.server_name = Some(x.into());
+        self
+    }
+
+    pub fn transport(mut self, x: TransportOptions) -> Self {
+        self.inner.transport = x;
+        self
+    }
+
+    pub fn tls(mut self, x: TlsOptions) -> Self {
+        self.inner.tls = x;
+        self
+    }
+
+    /// another server to use when the ones before it are down. they all need the same `server_name`. see the `failover` module
+    pub fn fallback_server(mut self, x: ServerAddr) -> Self {
+        self.inner.servers.push(x);
+        self
+    }
+
+    /// how hostnames for the server and the backend are looked up
+    pub fn resolve(mut self, x: ResolveOptions) -> Self {
+        self.inner.resolver = Arc::new(Resolver::new(x));
+        self
+    }
+
+    /// another backend for streams that aren't on a route, used while the ones before it are down. can be called more than once
+    pub fn fallback_backend(mut self, x: Backend) -> Self {
+        Arc::make_mut(&mut self.inner.backends).push(x);
+        self
+    }
+
+    /// check the backends in the background, so streams skip ones that are down without waiting on them. see the
+    /// `backends` module
+    pub fn health_check(mut self, x: HealthCheckOptions) -> Self {
+        self.inner.health_check = Some(x);
+        self
+    }
+
+    /// do TLS with every tcp backend, including fallbacks and routes
+    pub fn backend_tls(mut self, x: TlsOriginator) -> Self {
+        self.inner.backend_tls = Some(x);
+        self
+    }
+
+    /// send streams on `route` to `backend` instead of the default one, like routes the server picks by sni
+    pub fn route(mut self, route: impl Into<String>, backend: Backend) -> Self {
+        Arc::make_mut(&mut self.inner.routes).insert(route.into(), BackendList::new(backend));
+        self
+    }
+
+    /// what backends send back on `route` goes before lower priorities when the connection is busy. 0 by default
+    pub fn route_priority(mut self, route: impl Into<String>, x: i32) -> Self {
+        Arc::make_mut(&mut self.inner.priorities).insert(route.into(), x);
+        self
+    }
+
+    /// how the client notices that its network changed and moves its connection. see the `migrate` module
+    pub fn migration(mut self, x: MigrationOptions) -> Self {
+        self.inner.migration = x;
+        self
+    }
+
+    /// experimental. connect over each of these local paths at once. see the `multipath` module
+    pub fn multipath(mut self, x: MultipathOptions) -> Self {
+        self.inner.multipath = x;
+        self
+    }
+
+    /// reach the server through this quic-tunnel server, after the ones added before it. it needs to allow the next one
+    pub fn via(mut self, x: ServerAddr) -> Self {
+        self.inner.chain.push(x);
+        self
+    }
+
+    /// reach the server through a SOCKS5 or HTTP proxy. over an HTTP proxy, only `tcp_fallback` gets through. see the `proxy` module
+    pub fn proxy(mut self, x: ProxyUrl) -> Self {
+        self.inner.proxy = Some(x);
+        self
+    }
+
+    /// try the server over TLS over TCP too when QUIC doesn't connect in time. see the `tcp_fallback` module
+    pub fn tcp_fallback(mut self, x: TcpFallbackOptions) -> Self {
+        self.inner.tcp_fallback = Some(x);
+        self
+    }
+
+    /// socket options for connections to the backend
+    pub fn tcp(mut self, x: TcpOptions) -> Self {
+        self.inner.tcp = x;
+        self
+    }
+
+    /// the compression this client accepts. `none` only accepts uncompressed streams. lz4 and auto accept any.
+    /// the client says what it accepts when it connects and the server refuses it if a listener needs something else.
+    ///
+    /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
+    pub fn compress(mut self, x: CompressAlgo) -> Self {
+        self.inner.compress = x;
+        self
+    }
+
+    /// whether an EOF from one side closes only that direction or the whole stream. defaults to half close
+    pub fn close_mode(mut self, x: CloseMode) -> Self {
+        self.inner.close_mode = x;
+        self
+    }
+
+    /// bytes per second for each direction of streams the server pads at a constant rate. the server picks the mode
+    pub fn padding_rate(mut self, x: u64) -> Self {
+        self.inner.padding_rate = x;
+        self
+    }
+
+    /// cap each connection to the server to this many bytes per second in each direction, with up to a second's worth at
+    /// once. quinn already paces its packets, but only by the congestion window, so this is enforced where streams are
+    /// copied. each multipath path gets its own cap
+    pub fn max_bandwidth(mut self, x: u64) -> Self {
+        self.inner.max_bandwidth = Some(x);
+        self
+    }
+
+    /// how often to ping the server on the control stream, and how long it gets to answer. see the `control` module
+    pub fn heartbeat(mut self, x: HeartbeatOptions) -> Self {
+        self.inner.heartbeat = x;
+        self
+    }
+
+    /// the timeout and retries for connecting to the backend
+    pub fn dial(mut self, x: DialOptions) -> Self {
+        self.inner.dial = x;
+        self
+    }
+
+    /// hexdump the first bytes of streams to files, for debugging an app's protocol
+    pub fn debug_dump(mut self, x: DumpOptions) -> Self {
+        self.inner.debug_dump = Some(Arc::new(DebugDump::new(x)));
+        self
+    }
+
+    /// answer `/healthz` and `/readyz` here. see the `health` module
+    pub fn health_listen(mut self, x: SocketAddr) -> Self {
+        self.inner.health_listen = Some(x);
+        self
+    }
+
+    /// cancelling this token stops every task the client spawned. use `shutdown` on the handle to also wait for them
+    pub fn shutdown_token(mut self, x: CancellationToken) -> Self {
+        self.inner.shutdown = x;
+        self
+    }
+
+    /// run QUIC and the copy loops on this runtime. defaults to `runtime::data_plane`
+    pub fn data_plane(mut self, x: Handle) -> Self {
+        self.inner.data_plane = Some(x);
+        self
+    }
+
+    pub fn build(mut self) -> anyhow::Result<ReverseProxyClient> {
+        anyhow::ensure!(
+            self.inner.max_bandwidth != Some(0),
+            "the max bandwidth must be more than 0"
+        );
+
+        if self.inner.server_name.is_none() {
+            // TODO: read the cert and use the name on it rather than the filename. filename works for our dev certs though so its fine for now
+            let client_name = self
+                .inner
+                .cert
+                .file_stem()
+                .context("no client cert file name")?
+                .to_string_lossy()
+                .to_string();
+
+            self.inner.server_name = Some(client_name.replace("client", "server"));
+        }
+
+        Ok(self.inner)
+    }
+
+    pub async fn start(self) -> anyhow::Result<ReverseProxyClientHandle> {
+        self.build()?.start().await
+    }
+}
+
+impl ReverseProxyClient {
+    pub async fn start(mut self) -> anyhow::Result<ReverseProxyClientHandle> {
+        let data_plane = self.data_plane.clone().unwrap_or_else(runtime::data_plane);
+
+        if let Some(proxy) = self.proxy.clone() {
+            self.use_proxy(&proxy).await?;
+        }
+
+        let paths = match self.chain.is_empty() {
+            true => self.bind_paths(&data_plane)?,
+            false => vec![self.connect_chain(&data_plane).await?],
+        };
+
+        let endpoints = paths.iter().map(|x| x.endpoint.clone()).collect();
+
+        let tcp_fallback = match &self.tcp_fallback {
+            Some(x) => {
+                let client = TcpFallbackClient::new(
+                    x.clone(),
+                    self.ca.clone(),
+                    self.cert.clone(),
+                    self.key.clone(),
+                    &self.transport,
+                    &self.tls,
+                )?;
+
+                match &self.proxy {
+                    Some(proxy) => Some(client.proxy(proxy.clone(), self.resolver.clone())),
+                    None => Some(client),
+                }
+            }
+            None => None,
+        };
+
+        if let Some(addr) = self.health_listen {
+            let health = HealthServer::bind(addr).await?;
+
+            let connected = self.connected.clone();
+
+            let ready: ReadyCheck = Arc::new(move || match connected.load(Ordering::SeqCst) {
+                0 => Err("not connected to a server".to_string()),
+                _ => Ok(()),
+            });
+
+            tokio::spawn(
+                health
+                    .serve(ready, self.shutdown.clone())
+                    .inspect_err(|err| warn!(?err, "health checks stopped")),
+            );
+        }
+
+        if let Some(x) = &self.health_check {
+            let _guard = data_plane.enter();
+
+            self.backends.spawn_checks(
+                x.clone(),
+                self.tcp.clone(),
+                self.resolver.clone(),
+                self.shutdown.clone(),
+            );
+        }
+
+        let shutdown = self.shutdown.clone();
+        let tracker = self.tracker.clone();
+
+        // streams are spawned from inside this task, so they stay on the data plane too
+        let task = data_plane.spawn(self.run_paths(paths, tcp_fallback));
+
+        Ok(ReverseProxyClientHandle {
+            endpoints,
+            task,
+            finished: false,
+            shutdown,
+            tracker,
+        })
+    }
+
+    /// QUIC goes through a SOCKS5 proxy's UDP relay. QUIC can't go through an HTTP proxy, so it is still tried without it
+    async fn use_proxy(&mut self, proxy: &ProxyUrl) -> anyhow::Result<()> {
+        if !self.chain.is_empty() || self.multipath.is_enabled() {
+            anyhow::bail!("a proxy can't be used with hops or multipath");
+        }
+
+        match proxy.kind {
+            ProxyKind::Socks5 => {
+                match proxy.associate(&self.resolver, &self.transport.bind).await {
+                    Ok(x) => {
+                        self.transport.socks = Some(Arc::new(x));
+
+                        // the relay is the only peer our socket talks to, and a new socket might not be let in
+                        self.migration.check_interval = None;
+                    }
+                    // lots of SOCKS5 proxies only do CONNECT
+                    Err(err) if self.tcp_fallback.is_some() => {
+                        warn!(?err, %proxy, "no UDP relay. only the TCP fallback goes through the proxy");
+                    }
+                    Err(err) => {
+                        return Err(err).with_context(|| format!("asking {proxy} for a UDP relay"));
+                    }
+                }
+            }
+            ProxyKind::Http => {
+                if self.tcp_fallback.is_none() {
+                    anyhow::bail!(
+                        "an HTTP proxy only carries the TCP fallback, so it needs tcp_fallback"
+                    );
+                }
+
+                info!(%proxy, "only the TCP fallback goes through the proxy");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// an endpoint for each multipath path, or one for wherever the routes go
+    fn bind_paths(&self, data_plane: &Handle) -> anyhow::Result<Vec<Path>> {
+        let via: Vec<_> = match self.multipath.is_enabled() {
+            true => self.multipath.via.iter().cloned().map(Some).collect(),
+            false => vec![None],
+        };
+
+        let mut paths = vec![];
+
+        for via in via {
+            // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+            let endpoint = {
+                let _guard = data_plane.enter();
+
+                build_client_endpoint(
+                    self.ca.clone(),
+                    self.cert.clone(),
+                    self.key.clone(),
+                    &self.transport,
+                    &self.tls,
+                    Role::Reverse,
+                )?
+            };
+
+            if let Some(x) = &via {
+                let socket = multipath::bind(x).with_context(|| format!("binding to path {x}"))?;
+
+                endpoint.rebind(socket)?;
+
+                info!(via = %x, local_addr = %endpoint.local_addr()?, "multipath");
+            }
+
+            paths.push(Path { endpoint, via });
+        }
+
+        Ok(paths)
+    }
+
+    /// an endpoint that reaches the server through the hops. it only sends to one address, so that is the only server
+    async fn connect_chain(&mut self, data_plane: &Handle) -> anyhow::Result<Path> {
+        if self.multipath.is_enabled() {
+            anyhow::bail!("--via doesn't work with multipath");
+        }
+
+        // TODO: the hops are only bound for IPv4, like the other endpoints
+        let target = self
+            .servers
+            .candidates(&self.resolver)
+            .await?
+            .into_iter()
+            .find(|x| x.is_ipv4())
+            .context("the server has no IPv4 address")?;
+
+        let config = quic_client_config(
+            self.ca.clone(),
+            self.cert.clone(),
+            self.key.clone(),
+            &self.transport,
+            &self.tls,
+            Role::Reverse,
+        )?;
+
+        let server_name = self.server_name.clone().unwrap_or_default();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let endpoint = {
+            let ca = self.ca.clone();
+            let cert = self.cert.clone();
+            let key = self.key.clone();
+            let transport = self.transport.clone();
+            let tls = self.tls.clone();
+            let chain = self.chain.clone();
+            let resolver = self.resolver.clone();
+
+            data_plane
+                .spawn(async move {
+                    chain::connect(
+                        ca,
+                        cert,
+                        key,
+                        &transport,
+                        &tls,
+                        &chain,
+                        &server_name,
+                        &resolver,
+                        target,
+                        config,
+                    )
+                    .await
+                })
+                .await??
+        };
+
+        info!(hops = self.chain.len(), server = %target, "connecting through hops");
+
+        self.servers = ServerList::new(vec![target.into()]);
+
+        Ok(Path {
+            endpoint,
+            via: None,
+        })
+    }
+
+    async fn run_paths(
+        self,
+        paths: Vec<Path>,
+        tcp_fallback: Option<TcpFallbackClient>,
+    ) -> anyhow::Result<()> {
+        let tcp_fallback = tcp_fallback.as_ref();
+
+        match self.multipath.policy {
+            // each path keeps its own connection
+            MultipathPolicy::Aggregate => {
+                try_join_all(paths.chunks(1).map(|x| self.run(x, tcp_fallback))).await?;
+
+                Ok(())
+            }
+            MultipathPolicy::Standby => self.run(&paths, tcp_fallback).await,
+        }
+    }
+
+    /// keep one connection to the server over one of `paths`, trying them in order
+    async fn run(
+        &self,
+        paths: &[Path],
+        tcp_fallback: Option<&TcpFallbackClient>,
+    ) -> anyhow::Result<()> {
+        let server_name = self.server_name.as_deref().unwrap_or_default();
+
+        // reconnecting on the same endpoint lets rustls resume the session. with early data, that saves a round trip
+        // TODO: backoff
+        // the path to try first
+        let mut next = 0;
+
+        // the server knows our reconnects by this, and closes the connections they leave behind
+        let mut session = [0; 8];
+
+        SystemRandom::new()
+            .fill(&mut session)
+            .map_err(|_| anyhow::anyhow!("no randomness for a session id"))?;
+
+        let session = u64::from_be_bytes(session);
+
+        loop {
+            let conn_id = self.conn_ids.fetch_add(1, Ordering::Relaxed) + 1;
+
+            let connected = select! {
+                x = self.connect(paths, next, server_name, tcp_fallback) => x,
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+
+            let (i, remote, zero_rtt) = match connected {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, "failed connecting to QUIC server");
+
+                    select! {
+                        _ = sleep(Duration::from_secs(1)) => {}
+                        _ = self.shutdown.cancelled() => return Ok(()),
+                    }
+
+                    continue;
+                }
+            };
+
+            // `None` is over TCP
+            let path = i.map(|i| &paths[i]);
+
+            info!(
+                tcp = path.is_none(),
+                "connected to QUIC server at {}",
+                remote.remote_address()
+            );
+
+            let span = info_span!(
+                "conn",
+                conn_id,
+                peer = %remote.remote_address(),
+                via = path
+                    .and_then(|x| x.via.as_ref())
+                    .map(tracing::field::display),
+            );
+
+            let f = async {
+                let control = match self.negotiate(&remote, zero_rtt, session).await {
+                    Ok(x) => {
+                        self.servers.succeeded(remote.remote_address());
+
+                        x
+                    }
+                    Err(err) => {
+                        return match remote.close_reason() {
+                            // the server refused us and will do it again
+                            Some(ConnectionError::ApplicationClosed(x))
+                                if x.error_code == CloseCode::Incompatible.into() =>
+                            {
+                                Err(anyhow::anyhow!(
+                                    "server refused this client: {}",
+                                    String::from_utf8_lossy(&x.reason)
+                                ))
+                            }
+                            Some(x) => Ok(Some(x)),
+                            None => {
+                                // so the server logs why too
+                                if let TunnelError::Incompatible(reason) = &err {
+                                    remote.close(CloseCode::Incompatible.into(), reason.as_bytes());
+                                }
+
+                                Err(err.into())
+                            }
+                        };
+                    }
+                };
+
+                let _connected = ConnectedGuard::new(&self.connected);
+
+                log_path_mtu(&remote, self.proxy_streams(&remote, control, conn_id)).await
+            };
+
+            // a path or a bind that was given stays on its address or interface. a TCP connection can't move at all
+            let follow = async {
+                match path {
+                    Some(Path {
+                        endpoint,
+                        via: None,
+                    }) if !self.transport.bind.is_set() => {
+                        follow_network(endpoint, remote.remote_address(), &self.migration).await
+                    }
+                    _ => std::future::pending().await,
+                }
+            };
+
+            let err = select! {
+                x = f.instrument(span) => x?,
+                x = follow => match x {},
+                _ = self.shutdown.cancelled() => return Ok(()),
+            };
+
+            match err {
+                Some(err) => {
+                    match CloseCode::from_error(&err) {
+                        Some((code, reason)) if code == CloseCode::QuotaExceeded as u32 => warn!(
+                            %reason,
+                            "server closed the connection because this client is over its quota. reconnecting"
+                        ),
+                        // taking it back would only start a fight with whoever has our cert now
+                        Some((code, reason)) if code == CloseCode::Replaced as u32 => {
+                            anyhow::bail!("another client with this cert took over: {reason}")
+                        }
+                        Some((code, reason)) if code == CloseCode::TooManyConnections as u32 => {
+                            warn!(
+                                %reason,
+                                "server refused the connection because this cert has too many. reconnecting in {:?}",
+                                TOO_MANY_CONNECTIONS_WAIT
+                            );
+
+                            select! {
+                                _ = sleep(TOO_MANY_CONNECTIONS_WAIT) => {}
+                                _ = self.shutdown.cancelled() => return Ok(()),
+                            }
+                        }
+                        Some((code, reason)) => info!(
+                            code = %CloseCode::describe(code),
+                            %reason,
+                            "server closed the connection. reconnecting"
+                        ),
+                        None => warn!(?err, "lost connection to QUIC server. reconnecting"),
+                    }
+
+                    self.servers.failed(remote.remote_address());
+
+                    // standby goes to the next path right away, instead of waiting on one that might be gone
+                    if let Some(i) = i {
+                        next = (i + 1) % paths.len();
+                    }
+                }
+                // streams on the old connection keep it open until they are done
+                None => {
+                    info!("server is upgrading. reconnecting");
+
+                    if let Some(i) = i {
+                        next = i;
+                    }
+                }
+            }
+        }
+    }
+
+    /// connect over QUIC, or over TCP if QUIC is taking too long and TCP works first. returns which path connected, or `None` for TCP
+    async fn connect(
+        &self,
+        paths: &[Path],
+        first: usize,
+        server_name: &str,
+        tcp_fallback: Option<&TcpFallbackClient>,
+    ) -> anyhow::Result<(Option<usize>, Connection, Option<ZeroRttAccepted>)> {
+        let quic = self
+            .connect_quic(paths, first, server_name)
+            .map_ok(|(i, conn, zero_rtt)| (Some(i), conn, zero_rtt));
+
+        let Some(tcp_fallback) = tcp_fallback else {
+            return quic.await;
+        };
+
+        let tcp = async {
+            sleep(tcp_fallback.options().after).await;
+
+            info!("QUIC hasn't connected yet. trying TCP too");
+
+            self.connect_tcp(tcp_fallback, server_name)
+                .await
+                .map(|(conn, zero_rtt)| (None, conn, zero_rtt))
+        };
+
+        tokio::pin!(quic, tcp);
+
+        // whichever connects first. if one fails, the other still gets its chance
+        select! {
+            x = &mut quic => match x {
+                Ok(x) => Ok(x),
+                Err(err) => {
+                    warn!(?err, "QUIC failed. waiting on TCP");
+
+                    tcp.await
+                }
+            },
+            x = &mut tcp => match x {
+                Ok(x) => Ok(x),
+                Err(err) => {
+                    warn!(?err, "TCP failed. waiting on QUIC");
+
+                    quic.await
+                }
+            },
+        }
+    }
+
+    /// try every server over TCP, best first, until one answers
+    async fn connect_tcp(
+        &self,
+        tcp_fallback: &TcpFallbackClient,
+        server_name: &str,
+    ) -> anyhow::Result<(Connection, Option<ZeroRttAccepted>)> {
+        let mut last_err = None;
+
+        for addr in self.servers.candidates(&self.resolver).await? {
+            match tcp_fallback
+                .connect(addr, server_name, self.tls.early_data)
+                .await
+            {
+                Ok(x) => return Ok(x),
+                Err(err) => {
+                    warn!(?err, %addr, "failed connecting to server over TCP");
+
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err).context("none of the servers answered over TCP"),
+            None => anyhow::bail!("no servers to connect to"),
+        }
+    }
+
+    /// connect over the first of `paths` that works, starting at `first`. returns which one did
+    async fn connect_quic(
+        &self,
+        paths: &[Path],
+        first: usize,
+        server_name: &str,
+    ) -> anyhow::Result<(usize, Connection, Option<ZeroRttAccepted>)> {
+        let mut last_err = None;
+
+        for i in (first..paths.len()).chain(0..first) {
+            let path = &paths[i];
+
+            match self
+                .servers
+                .connect(
+                    &path.endpoint,
+                    server_name,
+                    &self.resolver,
+                    self.tls.early_data,
+                )
+                .await
+            {
+                Ok((remote, zero_rtt)) => return Ok((i, remote, zero_rtt)),
+                Err(err) if paths.len() > 1 => {
+                    warn!(?err, via = ?path.via, "path failed. trying the next one");
+
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no paths")))
+    }
+
+    /// tell the server which compression we accept, which versions we speak, and which session this continues before it
+    /// sends us any streams. returns the version and the control stream
+    async fn negotiate(
+        &self,
+        remote: &Connection,
+        zero_rtt: Option<ZeroRttAccepted>,
+        session: u64,
+    ) -> Result<(u8, SendStream, RecvStream), TunnelError> {
+        let accepted = self.compress.accepted();
+
+        let mut hello = ControlMessage::Hello {
+            compress: accepted.clone(),
+        }
+        .encode()?;
+
+        hello.extend(
+            ControlMessage::Version {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            }
+            .encode()?,
+        );
+
+        let (mut tx, mut rx) = remote.open_bi().await?;
+
+        tx.write_all(&hello).await?;
+
+        // a server with different session keys, like one we were upgraded to, rejects 0-RTT and the hello with it
+        if let Some(x) = zero_rtt {
+            if !x.await {
+                debug!("server rejected 0-RTT. saying hello again");
+
+                (tx, rx) = remote.open_bi().await?;
+
+                tx.write_all(&hello).await?;
+            }
+        }
+
+        let mut reply = ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await?;
+
+        // version 3 servers go straight to welcome
+        let (min, max) = match reply {
+            Some(ControlMessage::Version { min, max }) => {
+                reply = ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await?;
+
+                (min, max)
+            }
+            _ => (IMPLIED_VERSION, IMPLIED_VERSION),
+        };
+
+        match reply {
+            Some(ControlMessage::Welcome { compress }) => {
+                let version = negotiate_version(min, max, "server", "client")
+                    .map_err(TunnelError::Incompatible)?;
+
+                debug!(?compress, ?accepted, version, "negotiated with server");
+
+                if version >= SESSION_VERSION {
+                    tx.write_all(&ControlMessage::Session { id: session }.encode()?)
+                        .await?;
+                }
+
+                Ok((version, tx, rx))
+            }
+            Some(ControlMessage::Error { reason, .. }) => Err(TunnelError::Incompatible(reason)),
+            x => Err(TunnelError::Incompatible(format!(
+                "unexpected reply to hello: {x:?}"
+            ))),
+        }
+    }
+
+    /// forward every stream the server opens to the nearby service. returns why the connection was lost, or `None` if the server sent `GoAway`
+    async fn proxy_streams(
+        &self,
+        remote: &Connection,
+        (version, tx, rx): (u8, SendStream, RecvStream),
+        conn_id: u64,
+    ) -> anyhow::Result<Option<ConnectionError>> {
+        let liveness = Liveness::default();
+
+        // every stream on this connection draws from the same buckets
+        let rate_limit = self.max_bandwidth.map(|x| ClientRateLimit::new(x, None));
+
+        // the server says when it is going away. the client just goes with the connection, and has nothing else to tell it
+        let control = control::run(
+            remote,
+            version,
+            tx,
+            rx,
+            &self.heartbeat,
+            flume::bounded(0).1,
+            std::future::pending(),
+            &liveness,
+        );
+
+        let streams = async {
+            loop {
+                // TODO: connection pool for re-using these streams
+                // with routes, the backend isn't known until the preamble says which route the stream is on.
+                // this is only one attempt, so a backend that is down doesn't hold up accepting. the stream tries again
+                let stream = match self.routes.is_empty() {
+                    true => {
+                        let x = self
+                            .backends
+                            .connect_ahead(&self.tcp, &self.resolver, &self.dial);
+
+                        match x.await {
+                            Ok(x) => Some(x),
+                            Err(err) => {
+                                debug!(
+                                    ?err,
+                                    "the backend isn't answering. the next stream tries again"
+                                );
+                                None
+                            }
+                        }
+                    }
+                    false => None,
+                };
+
+                let (mut remote_tx, mut remote_rx) = match remote.accept_bi().await {
+                    Ok(x) => x,
+                    Err(err) => return Ok(Some(err)),
+                };
+
+                debug!("reverse proxy server connected to us");
+
+                let accepted = self.compress.accepted();
+                let padding_rate = self.padding_rate;
+                let backends = self.backends.clone();
+                let routes = self.routes.clone();
+                let priorities = self.priorities.clone();
+                let backend_tls = self.backend_tls.clone();
+                let resolver = self.resolver.clone();
+                let tcp = self.tcp.clone();
+                let dial = self.dial.clone();
+                let debug_dump = self.debug_dump.clone();
+                let stream_id = remote_rx.id().index();
+                let copy_options = CopyOptions {
+                    close_mode: self.close_mode,
+                    rate_limit: rate_limit.clone(),
+                    reset_after: self.transport.chaos.reset_after(),
+                    ..Default::default()
+                };
+
+                // spawned tasks don't inherit the connection's span, so repeat conn_id here. the service is filled in from the preamble
+                let span = info_span!(
+                    "stream",
+                    conn_id,
+                    stream_id = remote_rx.id().index(),
+                    peer = %remote.remote_address(),
+                    service = tracing::field::Empty,
+                );
+
+                let f = async move {
+                    let preamble = StreamPreamble::read(&mut remote_rx).await?;
+
+                    trace!(?preamble, "stream preamble");
+
+                    Span::current().record("service", preamble.route());
+
+                    // the server agreed to this when we connected, so this is a bug on its side
+                    if !accepted.contains(&preamble.compress) {
+                        return Err(TunnelError::Incompatible(format!(
+                            "server sent a stream with {:?} compression",
+                            preamble.compress
+                        ))
+                        .into());
+                    }
+
+                    if let Some(x) = priorities.get(preamble.route()) {
+                        let _ = remote_tx.set_priority(*x);
+                    }
+
+                    // without routes, this is the list the stream was connected ahead from
+                    let backends = routes.get(preamble.route()).unwrap_or(&backends);
+
+                    // a backend that went down since won't answer on the connection we made to it
+                    let (i, stream) = match stream {
+                        Some((i, x)) if backends.is_up(i) => (i, x),
+                        _ => {
+                            match backends.dial(&tcp, &resolver, &dial).await {
+                                Ok(x) => x,
+                                Err(err) => {
+                                    // so the server closes its side right away, and knows why
+                                    let _ = remote_tx.reset(CloseCode::Unreachable.into());
+                                    let _ = remote_rx.stop(CloseCode::Unreachable.into());
+
+                                    return Err(err);
+                                }
+                            }
+                        }
+                    };
+
+                    let capture = debug_dump.as_ref().and_then(|x| {
+                        x.capture(
+                            &format!("conn{conn_id}-stream{stream_id}"),
+                            &format!("route={}", preamble.route()),
+                        )
+                    });
+
+                    // we pad what we send the way the server asked
+                    let copy_options = CopyOptions {
+                        padding: PaddingOptions {
+                            mode: preamble.padding,
+                            rate: padding_rate,
+                        },
+                        capture,
+                        ..copy_options
+                    };
+
+                    let mut transform = TransformPipeline::default();
+
+                    if let (Some(x), Backend::Tcp(addr)) = (&backend_tls, backends.get(i)) {
+                        transform.push(Arc::new(x.for_backend(addr)));
+                    }
+
+                    // the server decides for each listener
+                    copy_bidirectional_with_compression(
+                        preamble.compress,
+                        remote_rx,
+                        remote_tx,
+                        stream,
+                        transform,
+                        Default::default(),
+                        copy_options,
+                    )
+                    .await?;
+
+                    anyhow::Ok(())
+                };
+
+                let f = f.inspect_err(|err| debug!(?err, "reverse proxy client error"));
+
+                let shutdown = self.shutdown.clone();
+
+                self.tracker.spawn(
+                    async move {
+                        select! {
+                            _ = f => {}
+                            _ = shutdown.cancelled() => trace!("stream stopped by shutdown"),
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        };
+
+        select! {
+            x = streams => x,
+            end = control => match end {
+                ControlEnd::GoAway | ControlEnd::Stopped => Ok(None),
+                // the connection closing says the same thing, but this comes first and can't be lost with it
+                ControlEnd::Closed { code, reason } => {
+                    info!(code = %CloseCode::describe(code), %reason, "server is closing the connection");
+
+                    Ok(Some(remote.closed().await))
+                }
+                ControlEnd::TimedOut => Ok(remote.close_reason()),
+            },
+        }
+    }
+}
+
+/// counts a connection in `ReverseProxyClient::connected` until it is dropped, however the connection ends
+struct ConnectedGuard<'a>(&'a AtomicUsize);
+
+impl<'a> ConnectedGuard<'a> {
+    fn new(x: &'a AtomicUsize) -> Self {
+        x.fetch_add(1, Ordering::SeqCst);
+
+        Self(x)
+    }
+}
+
+impl Drop for ConnectedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct ReverseProxyClientHandle {
+    /// one for each path
+    endpoints: Vec<Endpoint>,
+    task: JoinHandle<anyhow::Result<()>>,
+    /// a finished JoinHandle panics if it is polled again
+    finished: bool,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl ReverseProxyClientHandle {
+    /// cancel this to stop the client
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// wait until the client stops. it reconnects on its own, so this usually means something went wrong
+    pub async fn wait(&mut self) -> anyhow::Result<()> {
+        let x = (&mut self.task).await;
+
+        self.finished = true;
+
+        x?
+    }
+
+    /// stop connecting, stop every stream, and wait for all of the client's tasks to finish
+    pub async fn shutdown(self) {
+        self.shutdown.cancel();
+
+        if !self.finished {
+            match self.task.await {
+                Ok(Err(err)) => debug!(?err, "client task failed"),
+                Err(err) => warn!(?err, "client task panicked"),
+                Ok(Ok(())) => {}
+            }
+        }
+
+        self.tracker.close();
+        self.tracker.wait().await;
+
+        for x in self.endpoints.iter() {
+            x.close(CloseCode::Done.into(), b"client done");
+        }
+
+        for x in self.endpoints.iter() {
+            x.wait_idle().await;
+        }
+    }
+}
@@ -1,16 +1,74 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
 use strum::EnumString;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
-use tracing::trace;
+use tokio::time::{interval, sleep, sleep_until, Instant, MissedTickBehavior};
+use tracing::{debug, trace};
 
+use crate::buffer::BUFFERS;
+use crate::counters::StreamCounters;
+use crate::dump::Capture;
+use crate::error::TunnelError;
+use crate::padding::{self, PaddingMode, PaddingOptions};
+use crate::protocol::{CloseCode, ProtocolError};
+use crate::rate_limit::{ClientRateLimit, TokenBucket};
 use crate::stream::Stream;
+use crate::transform::TransformPipeline;
 
-#[derive(Copy, Clone, Debug, Default, EnumString, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, Eq, PartialEq, Serialize)]
 #[strum(ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
 pub enum CompressAlgo {
     #[default]
     None,
     Lz4,
+    /// lz4, but streams that don't shrink in their first 64 KiB are sent uncompressed after that
+    Auto,
+}
+
+impl CompressAlgo {
+    /// what a peer configured with this agrees to read and write. lz4 and auto are the same on the wire
+    pub fn accepted(self) -> Vec<CompressAlgo> {
+        match self {
+            Self::None => vec![Self::None],
+            Self::Lz4 | Self::Auto => vec![Self::None, Self::Lz4, Self::Auto],
+        }
+    }
+}
+
+/// what to do when one side of a stream finishes sending
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseMode {
+    /// pass the EOF along and keep copying the other direction until it finishes too. some backends read the whole request before they answer
+    #[default]
+    Half,
+    /// close both directions as soon as either side finishes
+    Full,
+}
+
+/// how a stream is copied and what it is allowed to do
+#[derive(Clone, Debug, Default)]
+pub struct CopyOptions {
+    pub close_mode: CloseMode,
+    /// bandwidth shared with the tunnel client's other streams
+    pub rate_limit: Option<ClientRateLimit>,
+    /// close the stream if no bytes move in either direction for this long
+    pub idle_timeout: Option<Duration>,
+    /// close the stream this long after copying starts, however busy it is
+    pub max_lifetime: Option<Duration>,
+    /// the mode has to be the one in the stream's preamble
+    pub padding: PaddingOptions,
+    /// reset both halves of the QUIC stream this long after copying starts. see `ChaosOptions::reset_after`
+    pub reset_after: Option<Duration>,
+    /// hexdump what the app sends and gets. see the `dump` module
+    pub capture: Option<Capture>,
 }
 
 /// this could be generic, but we don't need it to be
@@ -19,55 +77,214 @@ pub async fn copy_bidirectional_with_compression(
     mut recv_q: quinn::RecvStream,
     mut send_q: quinn::SendStream,
     t: Stream,
-) -> anyhow::Result<(u64, u64)> {
+    transform: TransformPipeline,
+    counters: Arc<StreamCounters>,
+    options: CopyOptions,
+) -> Result<(u64, u64), TunnelError> {
     // TODO: if no compression, use copy_bidirectional here
 
-    // // TODO: use counters type
-    // let mut a_to_b = AtomicU64::new(0);
-    // let mut b_to_a = AtomicU64::new(0);
-    // let mut compressed_a_to_b = AtomicU64::new(0);
-    // let mut compressed_b_to_a = AtomicU64::new(0);
+    let transform_ctx = t.transform_context();
 
-    let (mut recv_t, mut send_t) = t.into_split();
+    let (recv_t, send_t) = t.into_split()?;
 
-    // read from a, compress, write to b
-    let a_to_b_f = async move {
-        copy_with_compression(
-            &mut recv_q,
-            &mut send_t,
-            CompressDirection::Decompress(compress_algo),
-        )
+    let (recv_t, send_t) = transform
+        .apply(&transform_ctx, recv_t, send_t)
         .await
+        .map_err(|err| TunnelError::Transform(err.into()))?;
+
+    // what the app sees, so after the transforms
+    let (mut recv_t, mut send_t) = match &options.capture {
+        Some(x) => x.tap(recv_t, send_t),
+        None => (recv_t, send_t),
+    };
+
+    // each half resets its own side of the QUIC stream. dropping one would finish it instead
+    let reset_at = options.reset_after.map(|x| Instant::now() + x);
+
+    let expires_at = options.max_lifetime.map(|x| Instant::now() + x);
+
+    // read from a, compress, write to b
+    let a_to_b_f = {
+        let counters = counters.clone();
+        let bucket = options.rate_limit.as_ref().map(|x| x.from_tunnel.clone());
+        let padding = options.padding.clone();
+
+        async move {
+            let x = select! {
+                x = copy_with_compression(
+                    &mut recv_q,
+                    &mut send_t,
+                    CompressDirection::Decompress(compress_algo),
+                    &padding,
+                    Record {
+                        data: |n, compressed| counters.add_from_tunnel(n, compressed),
+                        padding: |n| counters.add_padding_from_tunnel(n),
+                    },
+                    bucket.as_deref(),
+                ) => Some(x),
+                _ = sleep_until_maybe(reset_at) => None,
+            };
+
+            x.unwrap_or_else(|| {
+                let _ = recv_q.stop(CloseCode::Chaos.into());
+
+                Err(TunnelError::ChaosReset)
+            })
+        }
     };
 
     // read from b, decompress, write to a
-    let b_to_a_f = async move {
-        copy_with_compression(
-            &mut recv_t,
-            &mut send_q,
-            CompressDirection::Compress(compress_algo),
-        )
-        .await
+    let b_to_a_f = {
+        let counters = counters.clone();
+        let bucket = options.rate_limit.as_ref().map(|x| x.to_tunnel.clone());
+        let padding = options.padding.clone();
+
+        async move {
+            let x = select! {
+                x = copy_with_compression(
+                    &mut recv_t,
+                    &mut send_q,
+                    CompressDirection::Compress(compress_algo),
+                    &padding,
+                    Record {
+                        data: |n, compressed| counters.add_to_tunnel(n, compressed),
+                        padding: |n| counters.add_padding_to_tunnel(n),
+                    },
+                    bucket.as_deref(),
+                ) => Some(x),
+                _ = sleep_until_maybe(reset_at) => None,
+            };
+
+            x.unwrap_or_else(|| {
+                let _ = send_q.reset(CloseCode::Chaos.into());
+
+                Err(TunnelError::ChaosReset)
+            })
+        }
+    };
+
+    let mut a_to_b_f = tokio::spawn(a_to_b_f);
+    let mut b_to_a_f = tokio::spawn(b_to_a_f);
+
+    let mut a_to_b_done = false;
+    let mut b_to_a_done = false;
+
+    // each direction shuts down its own writer when it reads EOF. an error in either direction breaks the whole stream
+    let result = loop {
+        select! {
+            x = &mut a_to_b_f, if !a_to_b_done => {
+                trace!(?x, "a_to_b finished");
+
+                a_to_b_done = true;
+
+                // the other half is reset at the same moment. let it finish instead of aborting it
+                if let Ok(Err(TunnelError::ChaosReset)) = x {
+                    if !b_to_a_done {
+                        let _ = (&mut b_to_a_f).await;
+                    }
+
+                    break Err(TunnelError::ChaosReset);
+                }
+
+                // the peer couldn't reach its end, so the caller should hear about it
+                let x = match x {
+                    Ok(Err(err)) if err.stream_close_code() == Some(CloseCode::Unreachable) => break Err(err),
+                    x => x,
+                };
+
+                if options.close_mode == CloseMode::Full || !matches!(x, Ok(Ok(()))) {
+                    break Ok(());
+                }
+            },
+            x = &mut b_to_a_f, if !b_to_a_done => {
+                trace!(?x, "b_to_a finished");
+
+                b_to_a_done = true;
+
+                if let Ok(Err(TunnelError::ChaosReset)) = x {
+                    if !a_to_b_done {
+                        let _ = (&mut a_to_b_f).await;
+                    }
+
+                    break Err(TunnelError::ChaosReset);
+                }
+
+                // the peer couldn't reach its end, so the caller should hear about it
+                let x = match x {
+                    Ok(Err(err)) if err.stream_close_code() == Some(CloseCode::Unreachable) => break Err(err),
+                    x => x,
+                };
+
+                if options.close_mode == CloseMode::Full || !matches!(x, Ok(Ok(()))) {
+                    break Ok(());
+                }
+            },
+            idle = wait_for_idle(&counters, options.idle_timeout) => {
+                break Err(TunnelError::StreamIdle(idle));
+            },
+            _ = sleep_until_maybe(expires_at) => {
+                counters.expired();
+
+                break Err(TunnelError::StreamExpired(options.max_lifetime.unwrap_or_default()));
+            },
+        }
+
+        if a_to_b_done && b_to_a_done {
+            break Ok(());
+        }
     };
 
-    let a_to_b_f = tokio::spawn(a_to_b_f);
-    let b_to_a_f = tokio::spawn(b_to_a_f);
+    // dropping the halves closes them. this does nothing to a direction that already finished
+    a_to_b_f.abort();
+    b_to_a_f.abort();
+
+    result?;
 
-    select! {
-        x = a_to_b_f => {
-            trace!(?x, "a_to_b finished");
-        },
-        x = b_to_a_f => {
-            trace!(?x, "b_to_a finished");
-        },
+    Ok((counters.from_tunnel(), counters.to_tunnel()))
+}
+
+/// never returns without a deadline
+async fn sleep_until_maybe(x: Option<Instant>) {
+    match x {
+        Some(x) => sleep_until(x).await,
+        None => std::future::pending().await,
     }
+}
+
+/// returns once no bytes have moved for `idle_timeout`. never returns if there is no timeout
+async fn wait_for_idle(counters: &StreamCounters, idle_timeout: Option<Duration>) -> Duration {
+    let Some(idle_timeout) = idle_timeout else {
+        return futures::future::pending().await;
+    };
+
+    loop {
+        let idle = counters.idle_for();
 
-    // TODO: get this
-    let (a_to_b, b_to_a) = (0, 0);
+        if idle >= idle_timeout {
+            return idle;
+        }
 
-    Ok((a_to_b, b_to_a))
+        sleep(idle_timeout - idle).await;
+    }
 }
 
+/// the most read from one side at once. each read is compressed as one lz4 block
+const READ_LEN: usize = 8096;
+
+/// see the compressed frame format in `protocol`
+const FRAME_HEADER_LEN: usize = 4;
+/// set in a frame header if the payload wasn't compressed
+const FRAME_RAW: u32 = 1 << 31;
+/// set in a frame header if the payload is padding
+const FRAME_PAD: u32 = 1 << 30;
+/// a compressed READ_LEN chunk is a bit over 8 KiB at worst
+const MAX_FRAME_LEN: usize = 16 * 1024;
+
+/// how much of each stream `CompressAlgo::Auto` compresses before deciding whether to keep going
+const AUTO_SAMPLE_LEN: usize = 64 * 1024;
+/// `CompressAlgo::Auto` stops compressing a stream whose sample shrank by less than this
+const AUTO_MIN_SAVINGS: f64 = 0.1;
+
 #[derive(Clone, Copy, Debug)]
 pub enum CompressDirection {
     None,
@@ -75,70 +292,648 @@ pub enum CompressDirection {
     Decompress(CompressAlgo),
 }
 
+/// what a copy loop counts
+struct Record<D: Fn(usize, usize), P: Fn(usize)> {
+    /// called with the uncompressed and compressed sizes of each chunk. compressed is 0 if the stream isn't framed
+    data: D,
+    /// called with the size of each padding frame
+    padding: P,
+}
+
 async fn copy_with_compression<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized>(
     r: &mut R,
     w: &mut W,
     d: CompressDirection,
-) -> anyhow::Result<()> {
-    // if compression is disabled, just use copy_bidirectional to avoid buffering
+    padding: &PaddingOptions,
+    record: Record<impl Fn(usize, usize), impl Fn(usize)>,
+    // bytes read are taken from this before they are written
+    bucket: Option<&TokenBucket>,
+) -> Result<(), TunnelError> {
+    // TODO: if compression is disabled, just use copy_bidirectional to avoid buffering
+    let mut buf = BUFFERS.get();
+
+    match (d, padding.mode) {
+        (CompressDirection::None, _)
+        | (CompressDirection::Compress(CompressAlgo::None), PaddingMode::Off)
+        | (CompressDirection::Decompress(CompressAlgo::None), PaddingMode::Off) => {
+            copy_plain(r, w, &mut buf, record.data, bucket).await
+        }
+        (CompressDirection::Compress(algo), PaddingMode::ConstantRate) => {
+            copy_paced(r, w, algo, padding, &mut buf, record, bucket).await
+        }
+        (CompressDirection::Compress(algo), mode) => {
+            copy_compress(r, w, algo, mode, &mut buf, record, bucket).await
+        }
+        // auto only changes what the sender does. every frame says how to read it
+        (CompressDirection::Decompress(_), _) => {
+            copy_decompress(r, w, &mut buf, record, bucket).await
+        }
+    }
+}
+
+async fn copy_plain<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized>(
+    r: &mut R,
+    w: &mut W,
+    buf: &mut [u8],
+    record: impl Fn(usize, usize),
+    bucket: Option<&TokenBucket>,
+) -> Result<(), TunnelError> {
+    let buf = &mut buf[..READ_LEN];
+
+    loop {
+        let n = r.read(buf).await?;
+
+        trace!("read {} bytes", n);
+
+        if let Some(x) = bucket {
+            x.consume(n).await;
+        }
+
+        if n == 0 {
+            // if they send 0, forward 0
+            trace!("closing");
+            w.shutdown().await?;
+            return Ok(());
+        }
+
+        w.write_all(&buf[..n]).await?;
+
+        record(n, 0);
+    }
+}
+
+async fn copy_compress<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized>(
+    r: &mut R,
+    w: &mut W,
+    algo: CompressAlgo,
+    padding: PaddingMode,
+    buf: &mut [u8],
+    record: Record<impl Fn(usize, usize), impl Fn(usize)>,
+    bucket: Option<&TokenBucket>,
+) -> Result<(), TunnelError> {
+    // the front of the buffer is read into. the rest holds the frame and its padding
+    let (read_buf, out_buf) = buf.split_at_mut(READ_LEN);
 
-    let mut read_buf = [0; 8096];
+    // padded streams are framed even when they aren't compressed
+    let mut compressing = algo != CompressAlgo::None;
+    let mut sampling = algo == CompressAlgo::Auto;
+    let mut sampled = 0;
+    let mut sampled_on_wire = 0;
 
     loop {
-        let n = r.read(&mut read_buf).await?;
+        let n = r.read(read_buf).await?;
+
+        trace!("read {} bytes. compressing: {}", n, compressing);
 
-        trace!("read {} bytes. {:?}", n, d);
+        if let Some(x) = bucket {
+            x.consume(n).await;
+        }
 
-        let n_written = if n == 0 {
+        if n == 0 {
             // if they send 0, forward 0. don't waste time compressing 0
+            trace!("closing");
+            w.shutdown().await?;
+            return Ok(());
+        }
+
+        let frame_len = encode_frame(&read_buf[..n], out_buf, compressing)?;
+
+        let pad_len = match padding {
+            PaddingMode::Bucketed => padding::bucket_padding(frame_len, FRAME_HEADER_LEN),
+            _ => 0,
+        };
+
+        if pad_len > 0 {
+            encode_pad_frame(&mut out_buf[frame_len..frame_len + pad_len]);
+        }
+
+        w.write_all(&out_buf[..frame_len + pad_len]).await?;
+
+        (record.data)(n, frame_len);
+
+        if pad_len > 0 {
+            (record.padding)(pad_len);
+        }
+
+        trace!("a -> b = {} -> {}", n, frame_len);
+
+        // TLS, video, and archives don't shrink. stop spending cpu on them once the sample shows it
+        if sampling {
+            sampled += n;
+            sampled_on_wire += frame_len;
+
+            if sampled >= AUTO_SAMPLE_LEN {
+                // the decision is final for this stream
+                sampling = false;
+                compressing = sampled_on_wire as f64 <= sampled as f64 * (1.0 - AUTO_MIN_SAVINGS);
+
+                debug!(
+                    sampled,
+                    sampled_on_wire, compressing, "sampled stream compression"
+                );
+            }
+        }
+    }
+}
+
+/// send one `padding::CELL_LEN` cell every tick, with whatever data is ready when it goes and padding for the rest
+async fn copy_paced<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized>(
+    r: &mut R,
+    w: &mut W,
+    algo: CompressAlgo,
+    padding: &PaddingOptions,
+    buf: &mut [u8],
+    record: Record<impl Fn(usize, usize), impl Fn(usize)>,
+    bucket: Option<&TokenBucket>,
+) -> Result<(), TunnelError> {
+    // a data frame and a padding frame's header always fit in a cell
+    let (read_buf, out_buf) = buf.split_at_mut(padding::CELL_LEN - 2 * FRAME_HEADER_LEN);
+
+    let mut ticks = interval(padding.cell_interval());
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        // reads are cancel safe, so one that isn't ready yet is tried again next tick
+        let n = match r.read(read_buf).now_or_never() {
+            None => 0,
+            Some(Ok(0)) => {
+                trace!("closing");
+                w.shutdown().await?;
+                return Ok(());
+            }
+            Some(x) => x?,
+        };
+
+        if let Some(x) = bucket {
+            x.consume(n).await;
+        }
+
+        let frame_len = match n {
+            0 => 0,
+            n => encode_frame(&read_buf[..n], out_buf, algo != CompressAlgo::None)?,
+        };
+
+        // lz4 can run past the cell while it works, but a frame it keeps is smaller than the raw one
+        let cell = &mut out_buf[..padding::CELL_LEN];
+
+        encode_pad_frame(&mut cell[frame_len..]);
+
+        w.write_all(cell).await?;
+
+        if n > 0 {
+            (record.data)(n, frame_len);
+        }
+
+        (record.padding)(padding::CELL_LEN - frame_len);
+    }
+}
+
+async fn copy_decompress<R: AsyncRead + Unpin + ?Sized, W: AsyncWrite + Unpin + ?Sized>(
+    r: &mut R,
+    w: &mut W,
+    buf: &mut [u8],
+    record: Record<impl Fn(usize, usize), impl Fn(usize)>,
+    bucket: Option<&TokenBucket>,
+) -> Result<(), TunnelError> {
+    // the front of the buffer holds the frame. the rest holds the decompressed chunk
+    let (frame_buf, out_buf) = buf.split_at_mut(MAX_FRAME_LEN);
+
+    loop {
+        let Some(header) = read_frame_header(r).await? else {
+            trace!("closing");
             w.shutdown().await?;
+            return Ok(());
+        };
+
+        let raw = header & FRAME_RAW != 0;
+        let pad = header & FRAME_PAD != 0;
+        let len = (header & !(FRAME_RAW | FRAME_PAD)) as usize;
+
+        if len > MAX_FRAME_LEN {
+            return Err(ProtocolError::TooLong {
+                what: "compressed frame",
+                len,
+                max: MAX_FRAME_LEN,
+            }
+            .into());
+        }
+
+        r.read_exact(&mut frame_buf[..len])
+            .await
+            .map_err(|_| ProtocolError::UnexpectedEof("compressed frame"))?;
+
+        trace!("read {} byte frame. raw: {}. pad: {}", len, raw, pad);
+
+        if pad {
+            (record.padding)(len + FRAME_HEADER_LEN);
+            continue;
+        }
+
+        if let Some(x) = bucket {
+            x.consume(len + FRAME_HEADER_LEN).await;
+        }
 
-            0
+        let data = if raw {
+            &frame_buf[..len]
         } else {
-            // TODO: if n < 5 (or whatever the min size is): don't compress
-            match d {
-                CompressDirection::None
-                | CompressDirection::Compress(CompressAlgo::None)
-                | CompressDirection::Decompress(CompressAlgo::None) => {
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+            let (size, compressed) = lz4_flex::block::uncompressed_size(&frame_buf[..len])
+                .map_err(TunnelError::Decompress)?;
 
-                    w.write_all(&read_buf[..n]).await?;
+            let out_len = out_buf.len();
+            let out = out_buf.get_mut(..size).ok_or(TunnelError::Decompress(
+                lz4_flex::block::DecompressError::OutputTooSmall {
+                    expected: size,
+                    actual: out_len,
+                },
+            ))?;
 
-                    n
-                }
-                CompressDirection::Compress(CompressAlgo::Lz4) => {
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+            let decompressed = lz4_flex::block::decompress_into(compressed, out)
+                .map_err(TunnelError::Decompress)?;
 
-                    let compressed = lz4_flex::compress_prepend_size(&read_buf[..n]);
+            &out[..decompressed]
+        };
 
-                    // compressed_a_to_b.fetch_add(compressed.len() as u64, atomic::Ordering::SeqCst);
+        w.write_all(data).await?;
 
-                    w.write_all(&compressed).await?;
+        (record.data)(data.len(), len + FRAME_HEADER_LEN);
+    }
+}
 
-                    compressed.len()
-                }
-                CompressDirection::Decompress(CompressAlgo::Lz4) => {
-                    // compressed_a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+/// write `chunk` into `out` as one frame and return the frame's length. it is sent raw if compressing wouldn't make it smaller
+fn encode_frame(chunk: &[u8], out: &mut [u8], compress: bool) -> Result<usize, TunnelError> {
+    if compress {
+        // the payload is in the same format as lz4_flex::compress_prepend_size
+        out[FRAME_HEADER_LEN..FRAME_HEADER_LEN + 4]
+            .copy_from_slice(&(chunk.len() as u32).to_le_bytes());
 
-                    let decompressed = lz4_flex::decompress_size_prepended(&read_buf[..n])
-                        .map_err(|err| anyhow::anyhow!("decompress err: {:?}", err))?;
+        let payload = lz4_flex::block::compress_into(chunk, &mut out[FRAME_HEADER_LEN + 4..])
+            .map_err(|err| io::Error::other(err.to_string()))?
+            + 4;
 
-                    // a_to_b.fetch_add(n as u64, atomic::Ordering::SeqCst);
+        if payload < chunk.len() {
+            out[..FRAME_HEADER_LEN].copy_from_slice(&(payload as u32).to_be_bytes());
 
-                    w.write_all(&decompressed).await?;
+            return Ok(FRAME_HEADER_LEN + payload);
+        }
+    }
+
+    out[..FRAME_HEADER_LEN].copy_from_slice(&(chunk.len() as u32 | FRAME_RAW).to_be_bytes());
+    out[FRAME_HEADER_LEN..FRAME_HEADER_LEN + chunk.len()].copy_from_slice(chunk);
+
+    Ok(FRAME_HEADER_LEN + chunk.len())
+}
+
+/// fill `out` with one padding frame. it has to be at least `FRAME_HEADER_LEN` long
+fn encode_pad_frame(out: &mut [u8]) {
+    let len = out.len() - FRAME_HEADER_LEN;
+
+    out[..FRAME_HEADER_LEN].copy_from_slice(&(len as u32 | FRAME_PAD).to_be_bytes());
+
+    // pooled buffers still hold another stream's bytes
+    out[FRAME_HEADER_LEN..].fill(0);
+}
+
+/// `None` if the stream ended cleanly between frames
+async fn read_frame_header<R: AsyncRead + Unpin + ?Sized>(
+    r: &mut R,
+) -> Result<Option<u32>, TunnelError> {
+    let mut header = [0; FRAME_HEADER_LEN];
+
+    let n = r.read(&mut header).await?;
+
+    if n == 0 {
+        return Ok(None);
+    }
+
+    r.read_exact(&mut header[n..])
+        .await
+        .map_err(|_| ProtocolError::UnexpectedEof("compressed frame header"))?;
 
-                    decompressed.len()
+    Ok(Some(u32::from_be_bytes(header)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use tokio::io::duplex;
+
+    /// bytes lz4 can't shrink
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x2545f4914f6cdd1du64;
+
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    fn text(len: usize) -> Vec<u8> {
+        b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    /// what a copy loop counted: uncompressed bytes, bytes on the wire, and padding
+    #[derive(Debug, Default, PartialEq)]
+    struct Counted {
+        data: usize,
+        framed: usize,
+        padding: usize,
+    }
+
+    async fn copy(
+        r: &mut (impl AsyncRead + Unpin),
+        w: &mut (impl AsyncWrite + Unpin),
+        d: CompressDirection,
+        padding: &PaddingOptions,
+    ) -> Counted {
+        let data = Cell::new((0, 0));
+        let pad = Cell::new(0);
+
+        let record = Record {
+            data: |n, framed| data.set((data.get().0 + n, data.get().1 + framed)),
+            padding: |n| pad.set(pad.get() + n),
+        };
+
+        copy_with_compression(r, w, d, padding, record, None)
+            .await
+            .unwrap();
+
+        Counted {
+            data: data.get().0,
+            framed: data.get().1,
+            padding: pad.get(),
+        }
+    }
+
+    /// each frame's header and length, header included
+    fn frames(mut wire: &[u8]) -> Vec<(u32, usize)> {
+        let mut x = vec![];
+
+        while !wire.is_empty() {
+            let header = u32::from_be_bytes(wire[..FRAME_HEADER_LEN].try_into().unwrap());
+            let len = FRAME_HEADER_LEN + (header & !(FRAME_RAW | FRAME_PAD)) as usize;
+
+            x.push((header, len));
+            wire = &wire[len..];
+        }
+
+        x
+    }
+
+    fn padding(mode: PaddingMode) -> PaddingOptions {
+        PaddingOptions {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn bucketed_round_trip() {
+        let options = padding(PaddingMode::Bucketed);
+
+        for algo in [CompressAlgo::None, CompressAlgo::Lz4] {
+            for data in [text(20_000), noise(20_000), noise(1), noise(READ_LEN)] {
+                let mut wire = vec![];
+                let sent = copy(
+                    &mut data.as_slice(),
+                    &mut wire,
+                    CompressDirection::Compress(algo),
+                    &options,
+                )
+                .await;
+
+                assert_eq!(sent.data, data.len());
+                assert_eq!(sent.framed + sent.padding, wire.len());
+
+                // each data frame and the padding after it fill a bucket
+                let mut buckets = vec![];
+                for (header, len) in frames(&wire) {
+                    match header & FRAME_PAD {
+                        0 => buckets.push(len),
+                        _ => *buckets.last_mut().unwrap() += len,
+                    }
+                }
+                for x in buckets {
+                    assert!(x >= 512 && x.is_power_of_two(), "{algo:?} {x}");
                 }
+
+                let mut out = vec![];
+                let recv = copy(
+                    &mut wire.as_slice(),
+                    &mut out,
+                    CompressDirection::Decompress(algo),
+                    &options,
+                )
+                .await;
+
+                assert_eq!(out, data, "{algo:?}");
+                assert_eq!(recv, sent, "{algo:?}");
             }
+        }
+    }
+
+    #[tokio::test]
+    async fn constant_rate_round_trip() {
+        let options = PaddingOptions {
+            mode: PaddingMode::ConstantRate,
+            // a cell a millisecond
+            rate: padding::CELL_LEN as u64 * 1000,
         };
 
-        trace!("a -> b = {} -> {}", n, n_written);
+        for algo in [CompressAlgo::None, CompressAlgo::Lz4] {
+            let data = [text(3000), noise(100)].concat();
 
-        if n == 0 {
-            trace!("closing");
-            break;
+            let (mut app, mut from_app) = duplex(64 * 1024);
+
+            // a pause, so some cells go out with nothing in them
+            let writer = {
+                let data = data.clone();
+
+                tokio::spawn(async move {
+                    app.write_all(&data[..3000]).await.unwrap();
+                    sleep(Duration::from_millis(20)).await;
+                    app.write_all(&data[3000..]).await.unwrap();
+                })
+            };
+
+            let mut wire = vec![];
+            let sent = copy(
+                &mut from_app,
+                &mut wire,
+                CompressDirection::Compress(algo),
+                &options,
+            )
+            .await;
+
+            writer.await.unwrap();
+
+            assert_eq!(sent.data, data.len());
+            assert_eq!(sent.framed + sent.padding, wire.len());
+
+            // every cell is the same size, and every one ends with padding
+            assert_eq!(wire.len() % padding::CELL_LEN, 0);
+
+            let mut empty = 0;
+            for cell in wire.chunks(padding::CELL_LEN) {
+                let x = frames(cell);
+
+                assert!(x.len() <= 2, "{x:?}");
+                assert_ne!(x.last().unwrap().0 & FRAME_PAD, 0);
+
+                if x.len() == 1 {
+                    empty += 1;
+                }
+            }
+            assert!(empty > 0);
+
+            let mut out = vec![];
+            let recv = copy(
+                &mut wire.as_slice(),
+                &mut out,
+                CompressDirection::Decompress(algo),
+                &options,
+            )
+            .await;
+
+            assert_eq!(out, data, "{algo:?}");
+            assert_eq!(recv, sent, "{algo:?}");
         }
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn padding_frames_are_dropped() {
+        let mut wire = vec![0; 64];
+
+        let mut n = encode_frame(b"hello", &mut wire, false).unwrap();
+        encode_pad_frame(&mut wire[n..n + 20]);
+        n += 20;
+        // only a header
+        encode_pad_frame(&mut wire[n..n + FRAME_HEADER_LEN]);
+        n += FRAME_HEADER_LEN;
+        n += encode_frame(b" world", &mut wire[n..], false).unwrap();
+
+        let mut out = vec![];
+        let recv = copy(
+            &mut &wire[..n],
+            &mut out,
+            CompressDirection::Decompress(CompressAlgo::None),
+            &padding(PaddingMode::Bucketed),
+        )
+        .await;
+
+        assert_eq!(out, b"hello world");
+        assert_eq!(
+            recv,
+            Counted {
+                data: 11,
+                framed: 11 + 2 * FRAME_HEADER_LEN,
+                padding: 20 + FRAME_HEADER_LEN,
+            }
+        );
+    }
+
+    #[test]
+    fn frames_are_raw_unless_smaller() {
+        let mut out = vec![0; MAX_FRAME_LEN];
+
+        let x = text(READ_LEN);
+        let n = encode_frame(&x, &mut out, true).unwrap();
+        assert!(n < x.len() / 2, "{n}");
+        assert_eq!(frames(&out[..n]), [((n - FRAME_HEADER_LEN) as u32, n)]);
+
+        let x = noise(READ_LEN);
+        let n = encode_frame(&x, &mut out, true).unwrap();
+        assert_eq!(n, FRAME_HEADER_LEN + x.len());
+        assert_eq!(frames(&out[..n]), [(x.len() as u32 | FRAME_RAW, n)]);
+        assert_eq!(&out[FRAME_HEADER_LEN..n], x);
+    }
+
+    #[test]
+    fn pad_frames_are_zeroed() {
+        let mut x = vec![0xff; 32];
+        encode_pad_frame(&mut x);
+
+        assert_eq!(frames(&x), [(28 | FRAME_PAD, 32)]);
+        assert!(x[FRAME_HEADER_LEN..].iter().all(|x| *x == 0));
+    }
+
+    /// whether each data frame was sent raw
+    async fn raw_frames(algo: CompressAlgo, data: &[u8]) -> Vec<bool> {
+        let mut wire = vec![];
+        copy(
+            &mut &data[..],
+            &mut wire,
+            CompressDirection::Compress(algo),
+            &PaddingOptions::default(),
+        )
+        .await;
+
+        let mut out = vec![];
+        copy(
+            &mut wire.as_slice(),
+            &mut out,
+            CompressDirection::Decompress(algo),
+            &PaddingOptions::default(),
+        )
+        .await;
+        assert_eq!(out, data);
+
+        frames(&wire)
+            .into_iter()
+            .map(|(header, _)| header & FRAME_RAW != 0)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn auto_stops_after_an_incompressible_sample() {
+        // reads are READ_LEN at a time, so the sample is the first reads that add up to AUTO_SAMPLE_LEN
+        let sample = AUTO_SAMPLE_LEN.div_ceil(READ_LEN);
+        let data = [noise(sample * READ_LEN), text(4 * READ_LEN)].concat();
+
+        let x = raw_frames(CompressAlgo::Auto, &data).await;
+        assert_eq!(x, [true; 13]);
+
+        // lz4 keeps trying, so the text after it shrinks
+        let x = raw_frames(CompressAlgo::Lz4, &data).await;
+        assert_eq!(x[sample..], [false; 4]);
+    }
+
+    #[tokio::test]
+    async fn auto_keeps_compressing_text() {
+        let x = raw_frames(CompressAlgo::Auto, &text(AUTO_SAMPLE_LEN * 3)).await;
+
+        assert!(
+            x.len() > AUTO_SAMPLE_LEN.div_ceil(READ_LEN) + 1,
+            "{}",
+            x.len()
+        );
+        assert!(x.iter().all(|x| !x), "{x:?}");
+    }
+
+    #[tokio::test]
+    async fn auto_needs_real_savings() {
+        // each read is mostly noise, so the sample shrinks, but by less than AUTO_MIN_SAVINGS
+        let chunk = [
+            noise(READ_LEN * 19 / 20),
+            vec![0; READ_LEN - READ_LEN * 19 / 20],
+        ]
+        .concat();
+        let sample = AUTO_SAMPLE_LEN.div_ceil(READ_LEN);
+        let data = [chunk.repeat(sample), text(2 * READ_LEN)].concat();
+
+        let x = raw_frames(CompressAlgo::Auto, &data).await;
+        assert!(x[..sample].iter().all(|x| !x), "{x:?}");
+        assert_eq!(x[sample..], [true; 2]);
+    }
 }
@@ -0,0 +1,247 @@
+//! The control stream after hello and welcome: heartbeats, and why a connection is going away.
+//!
+//! Both sides send `Ping` every `interval` and answer the other's with `Pong`, so a connection that is quiet but fine looks
+//! different from one whose peer is gone or stuck. A peer that says nothing for `timeout` is closed with
+//! [`CloseCode::HeartbeatTimeout`]. Peers from before [`HEARTBEAT_VERSION`] never ping or answer, so they aren't pinged,
+//! and are only timed out once they have said something.
+//!
+//! Long before `timeout`, a connection that has gone quiet is likely dead. The server reads [`Liveness`] so it doesn't give
+//! users to one.
+//!
+//! The server ends its side with `GoAway` while draining or `Close` while shutting down, and the client logs which.
+//!
+//! `Role::Probe` connections, from `ping`, have no hello or heartbeats. [`answer_pings`] answers every `Ping` on them.
+
+use std::future::Future;
+use std::time::Duration;
+
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+use tracing::{debug, trace, warn};
+
+use crate::protocol::{CloseCode, ControlMessage, HEARTBEAT_VERSION};
+
+/// how long a probe stream can go without a ping
+const PROBE_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatOptions {
+    /// how often to ping the peer
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// how long the peer can go without a ping or pong before the connection is closed
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// When the peer last said something on the control stream.
+#[derive(Debug)]
+pub struct Liveness(watch::Sender<Option<Instant>>);
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self(watch::Sender::new(None))
+    }
+}
+
+impl Liveness {
+    fn heard(&self) {
+        self.0.send_replace(Some(Instant::now()));
+    }
+
+    /// `None` until the peer shows it does heartbeats
+    fn heard_at(&self) -> Option<Instant> {
+        *self.0.borrow()
+    }
+
+    /// false if the peer has been heard from before, but not in the last `quiet`
+    pub fn heard_within(&self, quiet: Duration) -> bool {
+        self.heard_at().is_none_or(|x| x.elapsed() <= quiet)
+    }
+
+    /// resolves the next time the peer is heard from
+    pub async fn next_heard(&self) {
+        let _ = self.0.subscribe().changed().await;
+    }
+}
+
+/// why `run` finished
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlEnd {
+    /// the peer is going away and asked us to reconnect. see `ControlMessage::GoAway`
+    GoAway,
+    /// the peer said it is closing the connection and why
+    Closed { code: u32, reason: String },
+    /// the peer stopped answering. the connection was closed with `CloseCode::HeartbeatTimeout`
+    TimedOut,
+    /// `stop` finished, and its message was sent if it had one
+    Stopped,
+}
+
+/// heartbeat over the control stream until the peer goes away or `stop` finishes. messages from `outbox`, like errors,
+/// are sent along the way. `stop`'s message, like `GoAway`, is the last thing sent. `liveness` hears what we hear
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    conn: &Connection,
+    version: u8,
+    mut tx: SendStream,
+    mut rx: RecvStream,
+    options: &HeartbeatOptions,
+    outbox: flume::Receiver<ControlMessage>,
+    stop: impl Future<Output = Option<ControlMessage>>,
+    liveness: &Liveness,
+) -> ControlEnd {
+    let (pongs, pongs_rx) = flume::unbounded();
+
+    let read = async {
+        loop {
+            let x = match ControlMessage::read(&mut rx, Duration::MAX).await {
+                Ok(Some(x)) => x,
+                // an old peer finishes its side after hello. a broken stream means the connection says why soon
+                Ok(None) | Err(_) => return std::future::pending().await,
+            };
+
+            match x {
+                ControlMessage::Ping(x) => {
+                    liveness.heard();
+
+                    let _ = pongs.send(x);
+                }
+                ControlMessage::Pong(x) => {
+                    liveness.heard();
+
+                    trace!(ping = x, "pong");
+                }
+                ControlMessage::GoAway => return ControlEnd::GoAway,
+                ControlMessage::Close { code, reason } => {
+                    return ControlEnd::Closed { code, reason }
+                }
+                ControlMessage::Error { code, reason } => match CloseCode::from_u32(code) {
+                    Some(CloseCode::QuotaExceeded) => {
+                        warn!(%reason, "this client is over its quota. the server won't send it new streams until the quota allows");
+                    }
+                    Some(CloseCode::TooManyStreams) => {
+                        warn!(%reason, "this client is at the server's stream limit. new users wait for its streams to finish");
+                    }
+                    _ => warn!(code = %CloseCode::describe(code), %reason, "peer sent an error"),
+                },
+                x => debug!(?x, "ignoring control message"),
+            }
+        }
+    };
+
+    let write = async {
+        let mut ticks = interval(options.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut pings = 0u64;
+        let mut writable = true;
+        let mut outbox_open = true;
+
+        tokio::pin!(stop);
+
+        // a failed write means the peer stopped reading. it won't hear anything else either
+        loop {
+            let x = select! {
+                x = &mut stop => {
+                    if let (Some(x), true) = (x, writable) {
+                        if let Ok(x) = x.encode() {
+                            let _ = timeout(Duration::from_secs(1), async {
+                                tx.write_all(&x).await?;
+                                tx.finish().await
+                            })
+                            .await;
+                        }
+                    }
+
+                    return ControlEnd::Stopped;
+                }
+                _ = ticks.tick(), if version >= HEARTBEAT_VERSION => {
+                    if liveness.heard_at().is_some_and(|x| x.elapsed() > options.timeout) {
+                        let reason = format!("no heartbeat in {:?}", options.timeout);
+
+                        warn!(%reason, "peer stopped answering. closing the connection");
+
+                        conn.close(CloseCode::HeartbeatTimeout.into(), reason.as_bytes());
+
+                        return ControlEnd::TimedOut;
+                    }
+
+                    pings += 1;
+
+                    ControlMessage::Ping(pings)
+                }
+                Ok(x) = pongs_rx.recv_async() => ControlMessage::Pong(x),
+                x = outbox.recv_async(), if outbox_open => match x {
+                    Ok(x) => x,
+                    Err(_) => {
+                        outbox_open = false;
+                        continue;
+                    }
+                },
+            };
+
+            if !writable {
+                continue;
+            }
+
+            let Ok(x) = x.encode() else {
+                continue;
+            };
+
+            if let Err(err) = tx.write_all(&x).await {
+                debug!(?err, "peer stopped reading the control stream");
+
+                writable = false;
+            }
+        }
+    };
+
+    select! {
+        x = read => x,
+        x = write => x,
+    }
+}
+
+/// answer `Ping` with `Pong` on every stream a `Role::Probe` client opens, until it closes the connection
+pub async fn answer_pings(conn: &Connection) -> Result<(), ConnectionError> {
+    loop {
+        let (mut tx, mut rx) = match conn.accept_bi().await {
+            Ok(x) => x,
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+
+        tokio::spawn(async move {
+            // the pong carries the ping's number back, so the client can tell which one it answers
+            while let Ok(Some(ControlMessage::Ping(x))) =
+                ControlMessage::read(&mut rx, PROBE_IDLE_TIMEOUT).await
+            {
+                let Ok(x) = ControlMessage::Pong(x).encode() else {
+                    break;
+                };
+
+                if tx.write_all(&x).await.is_err() {
+                    break;
+                }
+            }
+
+            let _ = tx.finish().await;
+        });
+    }
+}
@@ -1,19 +1,272 @@
-use std::fmt::Debug;
-use std::sync::atomic::{self, AtomicUsize};
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
 
+use hdrhistogram::Histogram;
+use quinn::Connection;
+use quinn_proto::ConnectionStats;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::select;
 use tokio::sync::watch;
-use tokio::time::interval;
+use tokio::time::{interval, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
+use crate::ecn::EcnSnapshot;
+use crate::udp_session::{SessionCounters, SessionSnapshot};
+
+/// one level of counts. the same numbers are kept for the totals, each QUIC connection, and each listener
+#[derive(Debug, Default)]
+pub struct CounterSet {
+    packets_sent: AtomicU64,
+    packets_recv: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_recv: AtomicU64,
+    compressed_bytes_sent: AtomicU64,
+    compressed_bytes_recv: AtomicU64,
+    padding_bytes_sent: AtomicU64,
+    padding_bytes_recv: AtomicU64,
+    streams: AtomicU64,
+    expired_streams: AtomicU64,
+    /// only for a connection's counts, once [`ScopedCounters::with_path`] is called. goes away with them
+    path: Mutex<Option<Connection>>,
+}
+
+/// a copy of a [`CounterSet`] that can be printed or sent over the admin api
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CounterSnapshot {
+    pub packets_sent: u64,
+    pub packets_recv: u64,
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub compressed_bytes_sent: u64,
+    pub compressed_bytes_recv: u64,
+    /// what padding cost on the wire, on top of the data. see the `padding` module
+    #[serde(default)]
+    pub padding_bytes_sent: u64,
+    #[serde(default)]
+    pub padding_bytes_recv: u64,
+    pub streams: u64,
+    /// streams closed for outliving the server's max stream lifetime
+    #[serde(default)]
+    pub expired_streams: u64,
+}
+
+impl CounterSnapshot {
+    /// `f` applied to each pair of counts, like `u64::saturating_add` to sum two snapshots
+    pub fn combine(&self, other: &Self, f: impl Fn(u64, u64) -> u64) -> Self {
+        Self {
+            packets_sent: f(self.packets_sent, other.packets_sent),
+            packets_recv: f(self.packets_recv, other.packets_recv),
+            bytes_sent: f(self.bytes_sent, other.bytes_sent),
+            bytes_recv: f(self.bytes_recv, other.bytes_recv),
+            compressed_bytes_sent: f(self.compressed_bytes_sent, other.compressed_bytes_sent),
+            compressed_bytes_recv: f(self.compressed_bytes_recv, other.compressed_bytes_recv),
+            padding_bytes_sent: f(self.padding_bytes_sent, other.padding_bytes_sent),
+            padding_bytes_recv: f(self.padding_bytes_recv, other.padding_bytes_recv),
+            streams: f(self.streams, other.streams),
+            expired_streams: f(self.expired_streams, other.expired_streams),
+        }
+    }
+}
+
+/// What quinn knows about a connection's path right now. quinn 0.10 doesn't expose bytes in flight or PTO counts, so
+/// those aren't here.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PathSnapshot {
+    pub rtt_us: u64,
+    /// congestion window in bytes
+    pub cwnd: u64,
+    /// times quinn slowed down, for lost packets or ECN marks
+    pub congestion_events: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    /// times the path stopped carrying packets as big as before, so quinn went back to small ones
+    pub black_holes_detected: u64,
+}
+
+impl From<&ConnectionStats> for PathSnapshot {
+    fn from(x: &ConnectionStats) -> Self {
+        Self {
+            rtt_us: x.path.rtt.as_micros().try_into().unwrap_or(u64::MAX),
+            cwnd: x.path.cwnd,
+            congestion_events: x.path.congestion_events,
+            sent_packets: x.path.sent_packets,
+            lost_packets: x.path.lost_packets,
+            lost_bytes: x.path.lost_bytes,
+            black_holes_detected: x.path.black_holes_detected,
+        }
+    }
+}
+
+/// keeps samples from 1µs to 1 minute. slower samples are counted as 1 minute
+pub struct LatencyHistogram {
+    inner: Mutex<Histogram<u64>>,
+}
+
+/// percentiles in microseconds since the server started. `None` until there is a sample
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub p50_us: Option<u64>,
+    pub p95_us: Option<u64>,
+    pub p99_us: Option<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        let x = Histogram::new_with_bounds(1, 60_000_000, 3).expect("histogram bounds are valid");
+
+        Self {
+            inner: Mutex::new(x),
+        }
+    }
+}
+
+impl Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, x: Duration) {
+        let x = x.as_micros().try_into().unwrap_or(u64::MAX);
+
+        self.inner.lock().unwrap().saturating_record(x);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let x = self.inner.lock().unwrap();
+
+        if x.is_empty() {
+            return Default::default();
+        }
+
+        LatencySnapshot {
+            count: x.len(),
+            p50_us: Some(x.value_at_quantile(0.50)),
+            p95_us: Some(x.value_at_quantile(0.95)),
+            p99_us: Some(x.value_at_quantile(0.99)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CountersSnapshot {
+    pub total: CounterSnapshot,
+    /// from accepting a user to the first byte coming back out of the tunnel
+    pub stream_setup: LatencySnapshot,
+    /// the connection's round trip time, sampled once per stream
+    pub rtt: LatencySnapshot,
+    /// keyed by connection id. connections that have closed are not included
+    pub connections: BTreeMap<u64, CounterSnapshot>,
+    /// keyed by the listener's address, like "tcp 127.0.0.1:8080"
+    pub listeners: BTreeMap<String, CounterSnapshot>,
+    /// keyed by connection id, like `connections`, for the connections whose path is tracked
+    #[serde(default)]
+    pub paths: BTreeMap<u64, PathSnapshot>,
+    #[serde(default)]
+    pub udp_sessions: SessionSnapshot,
+    /// the marks on received packets, for the whole process
+    #[serde(default)]
+    pub ecn: EcnSnapshot,
+}
+
+impl From<&CounterSnapshot> for CounterSet {
+    /// start from counts saved earlier
+    fn from(x: &CounterSnapshot) -> Self {
+        Self {
+            packets_sent: AtomicU64::new(x.packets_sent),
+            packets_recv: AtomicU64::new(x.packets_recv),
+            bytes_sent: AtomicU64::new(x.bytes_sent),
+            bytes_recv: AtomicU64::new(x.bytes_recv),
+            compressed_bytes_sent: AtomicU64::new(x.compressed_bytes_sent),
+            compressed_bytes_recv: AtomicU64::new(x.compressed_bytes_recv),
+            padding_bytes_sent: AtomicU64::new(x.padding_bytes_sent),
+            padding_bytes_recv: AtomicU64::new(x.padding_bytes_recv),
+            streams: AtomicU64::new(x.streams),
+            expired_streams: AtomicU64::new(x.expired_streams),
+            path: Default::default(),
+        }
+    }
+}
+
+impl CounterSet {
+    fn sent(&self, n: usize, compressed: usize) {
+        self.packets_sent.fetch_add(1, atomic::Ordering::SeqCst);
+        self.bytes_sent
+            .fetch_add(n as u64, atomic::Ordering::SeqCst);
+        self.compressed_bytes_sent
+            .fetch_add(compressed as u64, atomic::Ordering::SeqCst);
+    }
+
+    fn recv(&self, n: usize, compressed: usize) {
+        self.packets_recv.fetch_add(1, atomic::Ordering::SeqCst);
+        self.bytes_recv
+            .fetch_add(n as u64, atomic::Ordering::SeqCst);
+        self.compressed_bytes_recv
+            .fetch_add(compressed as u64, atomic::Ordering::SeqCst);
+    }
+
+    fn padding_sent(&self, n: usize) {
+        self.padding_bytes_sent
+            .fetch_add(n as u64, atomic::Ordering::SeqCst);
+    }
+
+    fn padding_recv(&self, n: usize) {
+        self.padding_bytes_recv
+            .fetch_add(n as u64, atomic::Ordering::SeqCst);
+    }
+
+    fn stream_opened(&self) {
+        self.streams.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    fn stream_expired(&self) {
+        self.expired_streams.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+
+    /// this doesn't lock the counters, so requests while copying may be missed
+    fn path_snapshot(&self) -> Option<PathSnapshot> {
+        let path = self.path.lock().unwrap();
+
+        Some(PathSnapshot::from(&path.as_ref()?.stats()))
+    }
+
+    pub fn snapshot(&self) -> CounterSnapshot {
+        CounterSnapshot {
+            packets_sent: self.packets_sent.load(atomic::Ordering::SeqCst),
+            packets_recv: self.packets_recv.load(atomic::Ordering::SeqCst),
+            bytes_sent: self.bytes_sent.load(atomic::Ordering::SeqCst),
+            bytes_recv: self.bytes_recv.load(atomic::Ordering::SeqCst),
+            compressed_bytes_sent: self.compressed_bytes_sent.load(atomic::Ordering::SeqCst),
+            compressed_bytes_recv: self.compressed_bytes_recv.load(atomic::Ordering::SeqCst),
+            padding_bytes_sent: self.padding_bytes_sent.load(atomic::Ordering::SeqCst),
+            padding_bytes_recv: self.padding_bytes_recv.load(atomic::Ordering::SeqCst),
+            streams: self.streams.load(atomic::Ordering::SeqCst),
+            expired_streams: self.expired_streams.load(atomic::Ordering::SeqCst),
+        }
+    }
+}
+
+/// "sent" is always into the tunnel and "recv" is always out of it, on both the client and the server
 pub struct TunnelCounters {
-    packets_sent: AtomicUsize,
-    packets_recv: AtomicUsize,
-    bytes_sent: AtomicUsize,
-    bytes_recv: AtomicUsize,
-    compressed_bytes_sent: AtomicUsize,
-    compressed_bytes_recv: AtomicUsize,
+    total: CounterSet,
+    /// weak so a connection's counts go away with its last [`ScopedCounters`]
+    connections: Mutex<BTreeMap<u64, Weak<CounterSet>>>,
+    /// listeners live as long as the server, so these are never removed
+    listeners: Mutex<BTreeMap<String, Arc<CounterSet>>>,
+    stream_setup: LatencyHistogram,
+    rtt: LatencyHistogram,
+    /// only a udp_client has these. see the `udp_session` module
+    udp_sessions: SessionCounters,
     watch: watch::Sender<()>,
 }
 
@@ -23,96 +276,585 @@ impl TunnelCounters {
         let (watch, _) = watch::channel(());
 
         let data = Self {
-            packets_sent: AtomicUsize::new(0),
-            packets_recv: AtomicUsize::new(0),
-            bytes_sent: AtomicUsize::new(0),
-            bytes_recv: AtomicUsize::new(0),
-            compressed_bytes_sent: AtomicUsize::new(0),
-            compressed_bytes_recv: AtomicUsize::new(0),
+            total: Default::default(),
+            connections: Default::default(),
+            listeners: Default::default(),
+            stream_setup: Default::default(),
+            rtt: Default::default(),
+            udp_sessions: Default::default(),
             watch,
         };
 
         Arc::new(data)
     }
+
+    pub fn udp_sessions(&self) -> &SessionCounters {
+        &self.udp_sessions
+    }
+
+    /// counts for one QUIC connection. they are included in the breakdown until every clone of the returned value is dropped
+    pub fn connection(self: &Arc<Self>, id: u64) -> ScopedCounters {
+        let mut connections = self.connections.lock().unwrap();
+
+        let connection = match connections.get(&id).and_then(Weak::upgrade) {
+            Some(x) => x,
+            None => {
+                let x = Arc::new(CounterSet::default());
+
+                connections.insert(id, Arc::downgrade(&x));
+
+                x
+            }
+        };
+
+        ScopedCounters {
+            root: self.clone(),
+            connection: Some(connection),
+            listener: None,
+            identity: None,
+        }
+    }
+
+    fn listener(&self, name: &str) -> Arc<CounterSet> {
+        self.listeners
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let (connections, paths) = {
+            let mut connections = self.connections.lock().unwrap();
+
+            // closed connections are cleaned up here instead of needing a guard
+            connections.retain(|_, x| x.strong_count() > 0);
+
+            let sets: Vec<_> = connections
+                .iter()
+                .filter_map(|(id, x)| Some((*id, x.upgrade()?)))
+                .collect();
+
+            let paths = sets
+                .iter()
+                .filter_map(|(id, x)| Some((*id, x.path_snapshot()?)))
+                .collect();
+
+            (
+                sets.iter().map(|(id, x)| (*id, x.snapshot())).collect(),
+                paths,
+            )
+        };
+
+        let listeners = self
+            .listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, x)| (name.clone(), x.snapshot()))
+            .collect();
+
+        CountersSnapshot {
+            total: self.total.snapshot(),
+            stream_setup: self.stream_setup.snapshot(),
+            rtt: self.rtt.snapshot(),
+            connections,
+            listeners,
+            paths,
+            udp_sessions: self.udp_sessions.snapshot(),
+            ecn: crate::ecn::snapshot(),
+        }
+    }
 }
 
 impl Debug for TunnelCounters {
-    /// this doesn't lock the counters, so requests while printing may be missed
+    /// only the totals. use `snapshot` for the breakdown
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total.snapshot();
+
         let mut state = f.debug_struct("TunnelCounters");
 
-        state.field(
-            "packets_sent",
-            &self.packets_sent.load(atomic::Ordering::SeqCst),
-        );
-        state.field(
-            "bytes_sent",
-            &self.bytes_sent.load(atomic::Ordering::SeqCst),
-        );
-        state.field(
-            "compressed_bytes_sent",
-            &self.compressed_bytes_sent.load(atomic::Ordering::SeqCst),
-        );
+        state.field("packets_sent", &total.packets_sent);
+        state.field("bytes_sent", &total.bytes_sent);
+        state.field("compressed_bytes_sent", &total.compressed_bytes_sent);
+        state.field("padding_bytes_sent", &total.padding_bytes_sent);
 
-        state.field(
-            "packets_recv",
-            &self.packets_recv.load(atomic::Ordering::SeqCst),
-        );
-        state.field(
-            "bytes_recv",
-            &self.bytes_recv.load(atomic::Ordering::SeqCst),
-        );
-        state.field(
-            "compressed_bytes_recv",
-            &self.compressed_bytes_recv.load(atomic::Ordering::SeqCst),
-        );
+        state.field("packets_recv", &total.packets_recv);
+        state.field("bytes_recv", &total.bytes_recv);
+        state.field("compressed_bytes_recv", &total.compressed_bytes_recv);
+        state.field("padding_bytes_recv", &total.padding_bytes_recv);
+
+        state.field("streams", &total.streams);
+        state.field("expired_streams", &total.expired_streams);
 
         state.finish()
     }
 }
 
 impl TunnelCounters {
+    /// count towards the totals only. use [`TunnelCounters::connection`] to also count per connection
     pub fn sent(&self, n: usize, compressed: usize) {
-        self.packets_sent.fetch_add(1, atomic::Ordering::SeqCst);
-        self.bytes_sent.fetch_add(n, atomic::Ordering::SeqCst);
-        self.compressed_bytes_sent
-            .fetch_add(compressed, atomic::Ordering::SeqCst);
+        self.total.sent(n, compressed);
 
         self.watch.send_replace(());
     }
 
+    /// count towards the totals only. use [`TunnelCounters::connection`] to also count per connection
     pub fn recv(&self, n: usize, compressed: usize) {
-        self.packets_recv.fetch_add(1, atomic::Ordering::SeqCst);
-        self.bytes_recv.fetch_add(n, atomic::Ordering::SeqCst);
-        self.compressed_bytes_recv
-            .fetch_add(compressed, atomic::Ordering::SeqCst);
+        self.total.recv(n, compressed);
 
         self.watch.send_replace(());
     }
 
-    pub fn spawn_stats_loop(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+    /// write the counts every `options.interval` if they have changed. stops when `shutdown` is cancelled
+    pub fn spawn_stats_loop(
+        self: Arc<Self>,
+        options: StatsOptions,
+        shutdown: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
         let mut watch = self.watch.subscribe();
         watch.borrow_and_update();
 
         let f = async move {
-            let mut i = interval(Duration::from_secs(10));
+            let mut file = match options.output {
+                StatsOutput::Stderr => None,
+                StatsOutput::File(path) => Some(StatsFile::new(path)),
+                StatsOutput::Disabled => {
+                    // callers treat this task finishing as something going wrong
+                    shutdown.cancelled().await;
+                    return;
+                }
+            };
+
+            let mut i = interval(options.interval);
             i.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
             loop {
-                i.tick().await;
+                select! {
+                    _ = i.tick() => {}
+                    _ = shutdown.cancelled() => break,
+                }
 
-                if let Err(err) = watch.changed().await {
+                let changed = select! {
+                    x = watch.changed() => x,
+                    _ = shutdown.cancelled() => break,
+                };
+
+                if let Err(err) = changed {
                     warn!("watch channel closed: {}", err);
                     break;
                 };
 
                 watch.borrow_and_update();
 
-                // TODO: subsctibe to a watch channel. only print counts if they have changed.
-                info!(counts=?self, "stats");
+                match file.as_mut() {
+                    None => self.log_snapshot(),
+                    Some(file) => {
+                        if let Err(err) = file.write(&self.snapshot()).await {
+                            warn!(?err, path = %file.path.display(), "failed to write stats");
+                        }
+                    }
+                }
             }
         };
 
         tokio::spawn(f)
     }
+
+    /// totals, then a line for each listener and each open connection
+    pub fn log_snapshot(&self) {
+        let snapshot = self.snapshot();
+
+        info!(
+            counts=?snapshot.total,
+            stream_setup=?snapshot.stream_setup,
+            rtt=?snapshot.rtt,
+            ecn=?snapshot.ecn,
+            "stats",
+        );
+
+        if snapshot.udp_sessions != SessionSnapshot::default() {
+            info!(sessions = ?snapshot.udp_sessions, "udp session stats");
+        }
+
+        for (listener, counts) in snapshot.listeners.iter() {
+            info!(%listener, ?counts, "listener stats");
+        }
+
+        for (conn_id, counts) in snapshot.connections.iter() {
+            let path = snapshot.paths.get(conn_id);
+
+            info!(conn_id, ?counts, ?path, "connection stats");
+        }
+    }
+}
+
+/// where the stats loop writes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum StatsOutput {
+    /// through the logger, which writes to stderr
+    #[default]
+    Stderr,
+    /// one JSON snapshot per line. when the file gets to 10 MiB, it is moved to `<path>.1` and a new one is started
+    File(PathBuf),
+    Disabled,
+}
+
+impl FromStr for StatsOutput {
+    type Err = String;
+
+    /// "stderr", "off", or a file path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("stats output can't be empty".to_string()),
+            "stderr" => Ok(Self::Stderr),
+            "off" | "none" | "disabled" => Ok(Self::Disabled),
+            x => Ok(Self::File(x.into())),
+        }
+    }
+}
+
+impl Display for StatsOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stderr => write!(f, "stderr"),
+            Self::File(x) => write!(f, "{}", x.display()),
+            Self::Disabled => write!(f, "off"),
+        }
+    }
+}
+
+impl Serialize for StatsOutput {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StatsOutput {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsOptions {
+    /// how often to write the counts. nothing is written if they haven't changed
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    pub output: StatsOutput,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            output: Default::default(),
+        }
+    }
+}
+
+/// rotate the stats file when it gets this big
+const MAX_STATS_FILE_LEN: u64 = 10 * 1024 * 1024;
+
+/// opened lazily so a bad path only warns instead of stopping the server
+struct StatsFile {
+    path: PathBuf,
+    file: Option<tokio::fs::File>,
+    len: u64,
+}
+
+#[derive(Serialize)]
+struct StatsLine<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    snapshot: &'a CountersSnapshot,
+}
+
+impl StatsFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            len: 0,
+        }
+    }
+
+    async fn write(&mut self, snapshot: &CountersSnapshot) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(&StatsLine {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            snapshot,
+        })?;
+        line.push(b'\n');
+
+        if self.file.is_none() {
+            self.open().await?;
+        }
+
+        if self.len > 0 && self.len + line.len() as u64 > MAX_STATS_FILE_LEN {
+            self.file = None;
+
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+
+            tokio::fs::rename(&self.path, rotated).await?;
+
+            self.open().await?;
+        }
+
+        let file = self.file.as_mut().expect("file was just opened");
+
+        file.write_all(&line).await?;
+
+        self.len += line.len() as u64;
+
+        Ok(())
+    }
+
+    async fn open(&mut self) -> std::io::Result<()> {
+        let x = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        self.len = x.metadata().await?.len();
+        self.file = Some(x);
+
+        Ok(())
+    }
+}
+
+/// counts that go to the totals, one connection, and (optionally) one listener and one client identity at the same time
+#[derive(Clone)]
+pub struct ScopedCounters {
+    root: Arc<TunnelCounters>,
+    connection: Option<Arc<CounterSet>>,
+    listener: Option<Arc<CounterSet>>,
+    /// see the `usage` module
+    identity: Option<Arc<CounterSet>>,
+}
+
+impl Debug for ScopedCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScopedCounters")
+            .field(
+                "connection",
+                &self.connection.as_ref().map(|x| x.snapshot()),
+            )
+            .field("listener", &self.listener.as_ref().map(|x| x.snapshot()))
+            .field("identity", &self.identity.as_ref().map(|x| x.snapshot()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScopedCounters {
+    /// also count towards a listener
+    pub fn with_listener(&self, name: &str) -> Self {
+        Self {
+            listener: Some(self.root.listener(name)),
+            ..self.clone()
+        }
+    }
+
+    /// also count towards a client identity, from `UsageStore::counters`
+    pub fn with_identity(&self, x: Arc<CounterSet>) -> Self {
+        Self {
+            identity: Some(x),
+            ..self.clone()
+        }
+    }
+
+    /// what the connection has counted so far. `None` if this isn't for one connection
+    pub fn connection_snapshot(&self) -> Option<CounterSnapshot> {
+        self.connection.as_ref().map(|x| x.snapshot())
+    }
+
+    /// also put `conn`'s path stats next to this connection's counts in snapshots. does nothing without a connection
+    pub fn with_path(self, conn: &Connection) -> Self {
+        if let Some(x) = &self.connection {
+            *x.path.lock().unwrap() = Some(conn.clone());
+        }
+
+        self
+    }
+
+    fn sets(&self) -> impl Iterator<Item = &CounterSet> {
+        [
+            Some(&self.root.total),
+            self.connection.as_deref(),
+            self.listener.as_deref(),
+            self.identity.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    pub fn sent(&self, n: usize, compressed: usize) {
+        self.sets().for_each(|x| x.sent(n, compressed));
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn recv(&self, n: usize, compressed: usize) {
+        self.sets().for_each(|x| x.recv(n, compressed));
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn padding_sent(&self, n: usize) {
+        self.sets().for_each(|x| x.padding_sent(n));
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn padding_recv(&self, n: usize) {
+        self.sets().for_each(|x| x.padding_recv(n));
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn stream_opened(&self) {
+        self.sets().for_each(|x| x.stream_opened());
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn stream_expired(&self) {
+        self.sets().for_each(|x| x.stream_expired());
+
+        self.root.watch.send_replace(());
+    }
+
+    pub fn stream_setup(&self, x: Duration) {
+        self.root.stream_setup.record(x);
+    }
+
+    /// call with `Connection::rtt` once per stream
+    pub fn rtt(&self, x: Duration) {
+        self.root.rtt.record(x);
+    }
+}
+
+/// bytes copied by a single proxied stream. these are the uncompressed bytes
+#[derive(Debug)]
+pub struct StreamCounters {
+    /// read from QUIC and written to the local side
+    pub from_tunnel: AtomicU64,
+    /// read from the local side and written to QUIC
+    pub to_tunnel: AtomicU64,
+    /// where else to count these bytes
+    scope: Option<ScopedCounters>,
+    accepted_at: Option<Instant>,
+    /// set when the first byte comes out of the tunnel
+    setup_recorded: AtomicBool,
+    created_at: Instant,
+    /// milliseconds after `created_at` that bytes last moved in either direction
+    last_active_ms: AtomicU64,
+}
+
+impl Default for StreamCounters {
+    fn default() -> Self {
+        Self {
+            from_tunnel: Default::default(),
+            to_tunnel: Default::default(),
+            scope: None,
+            accepted_at: None,
+            setup_recorded: AtomicBool::new(false),
+            created_at: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl StreamCounters {
+    /// also count this stream's bytes in `scope` as they are copied.
+    /// the stream setup latency is measured from `accepted_at` to the first byte from the tunnel
+    pub fn new(scope: ScopedCounters, accepted_at: Instant) -> Self {
+        scope.stream_opened();
+
+        Self {
+            from_tunnel: Default::default(),
+            to_tunnel: Default::default(),
+            scope: Some(scope),
+            accepted_at: Some(accepted_at),
+            ..Default::default()
+        }
+    }
+
+    fn touch(&self) {
+        let x = self.created_at.elapsed().as_millis() as u64;
+
+        self.last_active_ms.store(x, atomic::Ordering::Relaxed);
+    }
+
+    /// how long since bytes moved in either direction
+    pub fn idle_for(&self) -> Duration {
+        let last_active =
+            Duration::from_millis(self.last_active_ms.load(atomic::Ordering::Relaxed));
+
+        self.created_at.elapsed().saturating_sub(last_active)
+    }
+
+    /// count this stream as closed for living too long
+    pub fn expired(&self) {
+        if let Some(x) = &self.scope {
+            x.stream_expired();
+        }
+    }
+
+    /// `compressed` is the size on the tunnel side, or 0 if compression is off
+    pub fn add_from_tunnel(&self, n: usize, compressed: usize) {
+        self.from_tunnel
+            .fetch_add(n as u64, atomic::Ordering::Relaxed);
+        self.touch();
+
+        if let Some(x) = &self.scope {
+            x.recv(n, compressed);
+
+            // check before swapping so later chunks don't write to the shared cache line
+            if let Some(accepted_at) = self.accepted_at {
+                if !self.setup_recorded.load(atomic::Ordering::Relaxed)
+                    && !self.setup_recorded.swap(true, atomic::Ordering::Relaxed)
+                {
+                    x.stream_setup(accepted_at.elapsed());
+                }
+            }
+        }
+    }
+
+    /// `compressed` is the size on the tunnel side, or 0 if compression is off
+    pub fn add_to_tunnel(&self, n: usize, compressed: usize) {
+        self.to_tunnel
+            .fetch_add(n as u64, atomic::Ordering::Relaxed);
+        self.touch();
+
+        if let Some(x) = &self.scope {
+            x.sent(n, compressed);
+        }
+    }
+
+    /// padding doesn't count as the stream being active, so idle streams still time out
+    pub fn add_padding_from_tunnel(&self, n: usize) {
+        if let Some(x) = &self.scope {
+            x.padding_recv(n);
+        }
+    }
+
+    pub fn add_padding_to_tunnel(&self, n: usize) {
+        if let Some(x) = &self.scope {
+            x.padding_sent(n);
+        }
+    }
+
+    pub fn from_tunnel(&self) -> u64 {
+        self.from_tunnel.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn to_tunnel(&self) -> u64 {
+        self.to_tunnel.load(atomic::Ordering::Relaxed)
+    }
 }
@@ -0,0 +1,152 @@
+//! Running in the background without systemd.
+//!
+//! `--daemon` starts a copy of this binary in its own session with the same arguments, with nothing on stdin and its output going to a log file, and returns.
+//! The copy knows it is the daemon from `DAEMON_ENV`. An upgraded server is started by one that already detached, so it stays where it is.
+//! `--daemon` only works on Unix. On Windows, run it as a service instead.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::{debug, info, warn};
+
+use crate::upgrade;
+
+/// set in the copy that runs in the background
+pub const DAEMON_ENV: &str = "QUIC_TUNNEL_DAEMON";
+
+/// true if we should start a background copy, or false if we already are one
+pub fn needs_spawn() -> bool {
+    std::env::var_os(DAEMON_ENV).is_none() && !upgrade::is_successor()
+}
+
+/// start a copy of this binary in the background and return its pid. `log` gets its stdout and stderr, like a panic, and they go nowhere without it
+#[cfg(unix)]
+pub fn spawn(log: Option<&Path>) -> anyhow::Result<u32> {
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let exe = std::env::current_exe().context("unable to find our own binary")?;
+
+    let (stdout, stderr) = match log {
+        Some(path) => {
+            let x = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("unable to open {}", path.display()))?;
+
+            (x.try_clone()?.into(), x.into())
+        }
+        None => (Stdio::null(), Stdio::null()),
+    };
+
+    let mut command = Command::new(&exe);
+
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+
+    // SAFETY: setsid is async-signal-safe and touches nothing but the new process.
+    // a new session has no controlling terminal, so closing the terminal doesn't send us SIGHUP
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    let child = command
+        .spawn()
+        .with_context(|| format!("unable to start {}", exe.display()))?;
+
+    Ok(child.id())
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_log: Option<&Path>) -> anyhow::Result<u32> {
+    anyhow::bail!("--daemon only works on unix. run it as a windows service instead")
+}
+
+/// Our pid, written to a file for init scripts. The file is removed when this is dropped, unless another process has written its pid there since.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// fails if the file names a process that is still running. a stale one is replaced
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        let pid = std::process::id();
+
+        match read_pid(&path) {
+            // an upgraded server takes over the old one's file
+            Some(x) if x != pid && is_running(x) && !upgrade::is_successor() => {
+                anyhow::bail!("{} says pid {} is already running", path.display(), x);
+            }
+            Some(x) if x != pid && !is_running(x) => {
+                info!(path = %path.display(), pid = x, "replacing stale pid file");
+            }
+            _ => {}
+        }
+
+        // write the whole thing somewhere else first, so nobody reads half a pid
+        let tmp = path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)
+            .with_context(|| format!("unable to write pid file {}", tmp.display()))?;
+        writeln!(file, "{pid}")?;
+        drop(file);
+
+        fs::rename(&tmp, &path)
+            .with_context(|| format!("unable to write pid file {}", path.display()))?;
+
+        Ok(Self { path, pid })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if read_pid(&self.path) != Some(self.pid) {
+            debug!(path = %self.path.display(), "pid file belongs to someone else now");
+            return;
+        }
+
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(?err, path = %self.path.display(), "failed to remove pid file");
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// signal 0 only checks that the process exists and we are allowed to signal it
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+
+    // SAFETY: kill has no memory safety requirements
+    let x = unsafe { libc::kill(pid, 0) };
+
+    x == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// there is no signal 0 to check with, so a pid file left behind is always replaced
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
@@ -0,0 +1,517 @@
+//! UDP and Unix datagram sockets behind one type, so the datagram tunnel can forward either.
+//!
+//! A udp_client sends each of its senders on a stream of its own, or with `--udp-transport datagram`, as a session in QUIC
+//! datagrams.
+//! Servers forward both, so either works against them. See the `fragment` module for how the datagrams are laid out.
+//!
+//! For discovery protocols like SSDP and mDNS across sites, a UDP listener can join multicast groups with
+//! [`DatagramSocket::bind_multicast`], and a target that is a multicast group or 255.255.255.255 is sent to as one. Those
+//! targets take replies from anyone, since the devices that answer do it from their own addresses. What we send to a group
+//! isn't looped back to this host, and goes one hop, with a TTL of 1.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use moka::future::{Cache, CacheBuilder};
+use quinn::Connection;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::task::AbortHandle;
+use tracing::{debug, error, info, trace};
+
+use crate::counters::ScopedCounters;
+use crate::fragment::{self, Fragment, Reassembler};
+use crate::get_tunnel_timeout;
+use crate::listen::ListenTarget;
+use crate::quic::matching_bind_address;
+use crate::unix::{self, SocketFile, UnixDatagram, UnixSocketOptions};
+
+/// the biggest UDP payload
+pub const MAX_UDP_PAYLOAD: usize = 65535;
+
+/// datagram sessions a server keeps for one client. the client has its own limits, so this is only a backstop
+const MAX_DATAGRAM_SESSIONS: u64 = 10_000;
+
+/// where datagrams are read from or forwarded to. parsed from "127.0.0.1:51820" or "unix:/run/statsd.sock"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatagramTarget {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for DatagramTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: needs a path".to_string());
+            }
+
+            return Ok(Self::Unix(path.into()));
+        }
+
+        s.parse()
+            .map(Self::Udp)
+            .map_err(|_| format!("\"{s}\" is not an address like 127.0.0.1:51820 or unix:/path"))
+    }
+}
+
+impl Display for DatagramTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Udp(x) => write!(f, "{x}"),
+            Self::Unix(x) => write!(f, "unix:{}", x.display()),
+        }
+    }
+}
+
+/// serialized like it is displayed, "unix:/run/statsd.sock"
+impl Serialize for DatagramTarget {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DatagramTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl DatagramTarget {
+    pub fn listen_target(&self) -> ListenTarget {
+        match self {
+            Self::Udp(x) => ListenTarget::Udp(*x),
+            Self::Unix(x) => ListenTarget::UnixDatagram(x.clone()),
+        }
+    }
+}
+
+/// who sent a datagram. replies go back here
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DatagramPeer {
+    Udp(SocketAddr),
+    /// `None` for senders that never bound an address. they all share one session and can't get replies
+    Unix(Option<PathBuf>),
+}
+
+impl Display for DatagramPeer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Udp(x) => write!(f, "{x}"),
+            Self::Unix(Some(x)) => write!(f, "unix:{}", x.display()),
+            Self::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DatagramSocket {
+    Udp(UdpSocket),
+    /// sends to a multicast group or a broadcast address, and takes replies from any address
+    Group(UdpSocket, SocketAddr),
+    /// the socket file is removed when this is dropped
+    Unix(UnixDatagram, SocketFile),
+}
+
+impl DatagramSocket {
+    /// listen for datagrams. this registers with the current runtime's reactor, so enter the right one first
+    pub fn bind(target: &DatagramTarget, unix: &UnixSocketOptions) -> io::Result<Self> {
+        match target {
+            DatagramTarget::Udp(x) => {
+                let x = std::net::UdpSocket::bind(x)?;
+                x.set_nonblocking(true)?;
+
+                Ok(Self::Udp(UdpSocket::from_std(x)?))
+            }
+            DatagramTarget::Unix(x) => {
+                let (x, file) = unix::bind_datagram(x, unix)?;
+
+                Ok(Self::Unix(x, file))
+            }
+        }
+    }
+
+    /// listen on `addr` for datagrams sent to `groups` too. other programs can listen for the same groups on the same port,
+    /// like they can for SSDP. `interface` is the address of the one to join on. the OS picks by default
+    pub fn bind_multicast(
+        addr: SocketAddr,
+        groups: &[IpAddr],
+        interface: Option<Ipv4Addr>,
+    ) -> io::Result<Self> {
+        use socket2::{Domain, Socket, Type};
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+
+        for x in groups {
+            match x {
+                IpAddr::V4(x) => {
+                    socket.join_multicast_v4(x, &interface.unwrap_or(Ipv4Addr::UNSPECIFIED))?
+                }
+                IpAddr::V6(x) => socket.join_multicast_v6(x, 0)?,
+            }
+        }
+
+        Ok(Self::Udp(UdpSocket::from_std(socket.into())?))
+    }
+
+    /// a socket that only talks to `target`, or sends to it as a group. `peer` picks the UDP address family
+    pub async fn connect(target: &DatagramTarget, peer: SocketAddr) -> anyhow::Result<Self> {
+        match target {
+            DatagramTarget::Udp(x) if is_group(x.ip()) => {
+                let socket = UdpSocket::bind(matching_bind_address(*x)?).await?;
+
+                match x {
+                    SocketAddr::V4(_) => {
+                        socket.set_broadcast(true)?;
+                        socket.set_multicast_loop_v4(false)?;
+                        socket.set_multicast_ttl_v4(1)?;
+                    }
+                    SocketAddr::V6(_) => socket.set_multicast_loop_v6(false)?,
+                }
+
+                Ok(Self::Group(socket, *x))
+            }
+            DatagramTarget::Udp(x) => {
+                let socket = UdpSocket::bind(matching_bind_address(peer)?).await?;
+                socket.connect(x).await?;
+
+                Ok(Self::Udp(socket))
+            }
+            DatagramTarget::Unix(x) => {
+                let socket = unix::unbound_datagram()?;
+                unix::connect_datagram(&socket, x)?;
+
+                Ok(Self::Unix(socket, SocketFile::default()))
+            }
+        }
+    }
+
+    pub fn local_target(&self) -> io::Result<DatagramTarget> {
+        match self {
+            Self::Udp(x) | Self::Group(x, _) => x.local_addr().map(DatagramTarget::Udp),
+            Self::Unix(x, _) => Ok(DatagramTarget::Unix(
+                unix::peer_path(x.local_addr()?).unwrap_or_default(),
+            )),
+        }
+    }
+
+    pub async fn readable(&self) -> io::Result<()> {
+        match self {
+            Self::Udp(x) | Self::Group(x, _) => x.readable().await,
+            Self::Unix(x, _) => x.readable().await,
+        }
+    }
+
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, DatagramPeer)> {
+        match self {
+            Self::Udp(x) | Self::Group(x, _) => x
+                .try_recv_from(buf)
+                .map(|(n, from)| (n, DatagramPeer::Udp(from))),
+            Self::Unix(x, _) => x
+                .try_recv_from(buf)
+                .map(|(n, from)| (n, DatagramPeer::Unix(unix::peer_path(from)))),
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], peer: &DatagramPeer) -> io::Result<usize> {
+        match (self, peer) {
+            (Self::Udp(x) | Self::Group(x, _), DatagramPeer::Udp(peer)) => {
+                x.send_to(buf, peer).await
+            }
+            (Self::Unix(x, _), DatagramPeer::Unix(Some(peer))) => unix::send_to(x, buf, peer).await,
+            (Self::Unix(..), DatagramPeer::Unix(None)) => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "unix sender has no path to reply to",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "peer is a different kind of socket",
+            )),
+        }
+    }
+
+    /// for connected sockets
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(x) => x.send(buf).await,
+            Self::Group(x, group) => x.send_to(buf, group).await,
+            Self::Unix(x, _) => x.send(buf).await,
+        }
+    }
+
+    /// for connected sockets
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(x) => x.recv(buf).await,
+            Self::Group(x, _) => x.recv_from(buf).await.map(|(n, _)| n),
+            Self::Unix(x, _) => x.recv(buf).await,
+        }
+    }
+}
+
+/// multicast groups and the broadcast address. a subnet's broadcast address can't be told apart from a host's
+pub fn is_group(x: IpAddr) -> bool {
+    match x {
+        IpAddr::V4(x) => x.is_multicast() || x.is_broadcast(),
+        IpAddr::V6(x) => x.is_multicast(),
+    }
+}
+
+/// forward every stream a `Role::Forward` client opens to `target`, each from its own socket, until the connection closes
+pub async fn forward_streams(
+    conn_a: &Connection,
+    addr_b: &DatagramTarget,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    loop {
+        // each new QUIC stream gets a new socket
+        let stream_a = conn_a.accept_bi().await;
+
+        let socket_b = DatagramSocket::connect(addr_b, conn_a.remote_address()).await?;
+
+        let socket_b = Arc::new(socket_b);
+
+        let (tx_a, rx_a) = match stream_a {
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed");
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+            Ok(s) => s,
+        };
+
+        counts.stream_opened();
+        counts.rtt(conn_a.rtt());
+
+        let f = forward_stream(tx_a, rx_a, socket_b, counts.clone());
+
+        // spawn to handle multiple requests at once
+        tokio::spawn(async move {
+            if let Err(e) = f.await {
+                error!("failed: {reason}", reason = e.to_string());
+            }
+        });
+    }
+}
+
+/// one of a client's senders in datagram mode. its replies stop when this is dropped
+struct DatagramSession {
+    socket: Arc<DatagramSocket>,
+    reassembler: std::sync::Mutex<Reassembler>,
+    replies: AbortHandle,
+}
+
+impl Drop for DatagramSession {
+    fn drop(&mut self) {
+        self.replies.abort();
+    }
+}
+
+/// forward the payloads a `Role::Forward` client sends in QUIC datagrams to `target`, each of its sessions from its own
+/// socket, until the connection closes
+pub async fn forward_datagrams(
+    conn_a: &Connection,
+    addr_b: &DatagramTarget,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    // the client says when a session closes, but that can be lost like any datagram
+    let sessions: Cache<u64, Arc<DatagramSession>> = CacheBuilder::new(MAX_DATAGRAM_SESSIONS)
+        .time_to_idle(get_tunnel_timeout())
+        .build();
+
+    loop {
+        let x = match conn_a.read_datagram().await {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let Some(fragment) = Fragment::parse(x.clone()) else {
+            match fragment::closed_session(&x) {
+                Some(id) => {
+                    if let Some(x) = sessions.remove(&id).await {
+                        x.replies.abort();
+                    }
+                }
+                None => trace!(n = x.len(), "dropping a datagram that isn't a fragment"),
+            }
+
+            continue;
+        };
+
+        let id = fragment.session;
+
+        let session = match sessions.get(&id).await {
+            Some(x) => x,
+            None => {
+                let socket = DatagramSocket::connect(addr_b, conn_a.remote_address()).await?;
+                let socket = Arc::new(socket);
+
+                let replies = tokio::spawn(reply_datagrams(
+                    conn_a.clone(),
+                    socket.clone(),
+                    id,
+                    counts.clone(),
+                ));
+
+                let x = Arc::new(DatagramSession {
+                    socket,
+                    reassembler: Default::default(),
+                    replies: replies.abort_handle(),
+                });
+
+                sessions.insert(id, x.clone()).await;
+
+                x
+            }
+        };
+
+        let Some(payload) = session.reassembler.lock().unwrap().push(fragment) else {
+            continue;
+        };
+
+        trace!("datagram -> socket_b = {}", payload.len());
+
+        if let Err(err) = session.socket.send(&payload).await {
+            debug!(?err, id, "failed to send to the target");
+            continue;
+        }
+
+        counts.recv(payload.len(), 0);
+    }
+}
+
+/// send what the target sends `session`'s socket back to the client
+async fn reply_datagrams(
+    conn_a: Connection,
+    socket_b: Arc<DatagramSocket>,
+    session: u64,
+    counts: ScopedCounters,
+) {
+    let mut buf = vec![0; MAX_UDP_PAYLOAD];
+    let mut packet = 0u16;
+
+    loop {
+        let n = match socket_b.recv(&mut buf).await {
+            Ok(x) => x,
+            // an icmp error from the target, like nothing listening on the port. it might come up later
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                trace!(?err, "target refused");
+                continue;
+            }
+            Err(err) => {
+                error!("failed to read from socket: {}", err);
+                return;
+            }
+        };
+
+        trace!("socket_b -> datagram = {}", n);
+
+        if fragment::send(&conn_a, session, packet, &buf[..n]) {
+            counts.sent(n, 0);
+        }
+
+        packet = packet.wrapping_add(1);
+    }
+}
+
+/// TODO: i think if we use UdpFramed, we can use tokio::io::copy
+async fn forward_stream(
+    mut tx_a: quinn::SendStream,
+    mut rx_a: quinn::RecvStream,
+    socket_b: Arc<DatagramSocket>,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    // listen on rx. when anything arrives, forward it to socket_b
+    let read_f = {
+        let socket_b = socket_b.clone();
+        let counts = counts.clone();
+
+        async move {
+            // let max_size = rx_a.max_datagram_size().unwrap_or(8096);
+            let max_size = 8096;
+
+            let mut buf = Vec::with_capacity(max_size);
+
+            loop {
+                buf.clear();
+
+                let n = rx_a.read_buf(&mut buf).await?;
+
+                // the client finished the stream
+                if n == 0 {
+                    return Ok(());
+                }
+
+                trace!("rx_a -> socket_b = {}", n);
+
+                socket_b.send(&buf[..n]).await?;
+
+                counts.recv(n, 0);
+            }
+        }
+    };
+    // TODO: log errors and return ()
+    let mut read_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(read_f);
+
+    let write_f = async move {
+        loop {
+            socket_b.readable().await?;
+
+            let mut buf = [0; 8096];
+
+            match socket_b.recv(&mut buf).await {
+                Ok(n) => {
+                    trace!("socket_b -> tx_a = {}", n);
+
+                    tx_a.write_all(&buf[..n]).await?;
+
+                    counts.sent(n, 0);
+                }
+                Err(e) => {
+                    error!("failed to read from socket: {}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    };
+
+    // TODO: log errors and return ()
+    let mut write_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(write_f);
+
+    select! {
+        x = &mut read_f => {
+            trace!("read_f finished: {:?}", x);
+        }
+        x = &mut write_f => {
+            trace!("write_f finished: {:?}", x);
+        }
+    }
+
+    read_f.abort();
+    write_f.abort();
+
+    info!("request finished");
+
+    Ok(())
+}
@@ -0,0 +1,343 @@
+//! Hexdumps of what goes through each stream, for debugging an app's protocol through the tunnel.
+//!
+//! Each captured stream gets a file in the dump directory with both directions, in the order they happened:
+//!
+//! ```text
+//! # conn1-stream3 route=web peer=203.0.113.7:51234
+//!      0.512ms > 00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|
+//!      2.040ms < 00000000  48 54 54 50 2f 31 2e 31  20 32 30 30 20 4f 4b 0d  |HTTP/1.1 200 OK.|
+//! ```
+//!
+//! `>` is toward the tunnel and `<` is from it, each with its own offset. The bytes are what the app on this side sent and
+//! got: after transforms, before compression and encryption. That is the app's data in the clear, so the files are only
+//! readable by us. Only [`CAPTURE_BURST`] new streams are captured at once, then one a second, and only the first
+//! `max_bytes` of each. Writing never holds up a stream; if the file falls behind, what doesn't fit is skipped and noted.
+
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tracing::{trace, warn};
+
+use crate::rate_limit::TokenBucket;
+use crate::transform::{BoxedRead, BoxedWrite};
+
+/// how much of each stream is captured if not set, both directions together
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// streams captured at once before the rate applies
+pub const CAPTURE_BURST: u64 = 10;
+
+/// new streams captured each second after the burst
+const CAPTURE_RATE: u64 = 1;
+
+/// reads and writes waiting on the file for one stream. more are skipped
+const QUEUE_LEN: usize = 256;
+
+const BYTES_PER_LINE: usize = 16;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DumpOptions {
+    /// made if it doesn't exist
+    pub dir: PathBuf,
+    /// how much of each stream to capture, both directions together
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_MAX_BYTES
+}
+
+impl DumpOptions {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DebugDump {
+    options: DumpOptions,
+    /// one token for each stream captured
+    captures: TokenBucket,
+}
+
+impl DebugDump {
+    pub fn new(options: DumpOptions) -> Self {
+        warn!(dir = %options.dir.display(), "dumping the plaintext of streams. only use this for debugging");
+
+        Self {
+            options,
+            captures: TokenBucket::new(CAPTURE_RATE, CAPTURE_BURST),
+        }
+    }
+
+    /// start capturing a stream, unless too many have started lately. `name` goes in the file name, and `about` after it
+    /// on the first line
+    pub fn capture(&self, name: &str, about: &str) -> Option<Capture> {
+        if !self.captures.try_consume(1) {
+            trace!(name, "too many new streams to capture this one");
+            return None;
+        }
+
+        // stream ids start over when the process does, so the time keeps files from earlier runs
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let path = self.options.dir.join(format!("{millis}-{name}.hexdump"));
+
+        let (tx, rx) = flume::bounded(QUEUE_LEN);
+
+        tokio::spawn(write_capture(path, format!("# {name} {about}\n"), rx));
+
+        Some(Capture {
+            tx,
+            started: Instant::now(),
+            left: Arc::new(AtomicU64::new(self.options.max_bytes)),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    ToTunnel,
+    FromTunnel,
+}
+
+impl Direction {
+    fn symbol(self) -> char {
+        match self {
+            Self::ToTunnel => '>',
+            Self::FromTunnel => '<',
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Chunk {
+    at: Duration,
+    direction: Direction,
+    offset: u64,
+    data: Vec<u8>,
+    /// nothing more is captured after this one
+    last: bool,
+}
+
+/// one stream's capture. cheap to clone, and the file is finished once every clone is dropped
+#[derive(Clone, Debug)]
+pub struct Capture {
+    tx: flume::Sender<Chunk>,
+    started: Instant,
+    /// bytes left under `max_bytes`, shared by both directions
+    left: Arc<AtomicU64>,
+}
+
+impl Capture {
+    fn record(&self, direction: Direction, offset: u64, data: &[u8]) {
+        let Ok(left) = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                (x > 0).then(|| x.saturating_sub(data.len() as u64))
+            })
+        else {
+            return;
+        };
+
+        let n = data.len().min(left as usize);
+
+        let chunk = Chunk {
+            at: self.started.elapsed(),
+            direction,
+            offset,
+            data: data[..n].to_vec(),
+            last: n as u64 == left,
+        };
+
+        // the writer notices the gap
+        let _ = self.tx.try_send(chunk);
+    }
+
+    /// wrap both halves of the app's side of a stream. reads go toward the tunnel, and writes came from it
+    pub fn tap(&self, read: BoxedRead, write: BoxedWrite) -> (BoxedRead, BoxedWrite) {
+        let read = Tap {
+            inner: read,
+            capture: self.clone(),
+            offset: 0,
+        };
+
+        let write = Tap {
+            inner: write,
+            capture: self.clone(),
+            offset: 0,
+        };
+
+        (Box::new(read), Box::new(write))
+    }
+}
+
+struct Tap<T> {
+    inner: T,
+    capture: Capture,
+    /// how many bytes went through in this direction
+    offset: u64,
+}
+
+impl AsyncRead for Tap<BoxedRead> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+
+        let x = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        let new = &buf.filled()[before..];
+
+        if !new.is_empty() {
+            this.capture.record(Direction::ToTunnel, this.offset, new);
+            this.offset += new.len() as u64;
+        }
+
+        x
+    }
+}
+
+impl AsyncWrite for Tap<BoxedWrite> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        let x = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = x {
+            this.capture
+                .record(Direction::FromTunnel, this.offset, &buf[..n]);
+            this.offset += n as u64;
+        }
+
+        x
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_capture(path: PathBuf, header: String, rx: flume::Receiver<Chunk>) {
+    if let Err(err) = try_write_capture(&path, header, rx).await {
+        warn!(?err, path = %path.display(), "unable to write debug dump");
+    }
+}
+
+async fn try_write_capture(
+    path: &Path,
+    header: String,
+    rx: flume::Receiver<Chunk>,
+) -> std::io::Result<()> {
+    if let Some(x) = path.parent() {
+        tokio::fs::create_dir_all(x).await?;
+    }
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let file = options.open(path).await?;
+
+    let mut file = BufWriter::new(file);
+
+    file.write_all(header.as_bytes()).await?;
+
+    // where the next chunk in each direction should start
+    let mut next = [0u64; 2];
+
+    while let Ok(x) = rx.recv_async().await {
+        let i = x.direction as usize;
+
+        if x.offset > next[i] {
+            let line = format!(
+                "# {} skipped {} bytes. the dump fell behind\n",
+                x.direction.symbol(),
+                x.offset - next[i]
+            );
+
+            file.write_all(line.as_bytes()).await?;
+        }
+
+        next[i] = x.offset + x.data.len() as u64;
+
+        file.write_all(hexdump(&x).as_bytes()).await?;
+
+        if x.last {
+            file.write_all(b"# reached max_bytes. the rest of the stream isn't captured\n")
+                .await?;
+        }
+
+        // so a stream that hangs still shows what it got
+        if rx.is_empty() {
+            file.flush().await?;
+        }
+    }
+
+    file.flush().await
+}
+
+/// like `hexdump -C`, with when and which way at the start of each line
+fn hexdump(x: &Chunk) -> String {
+    let mut out = String::new();
+
+    let at = format!("{:>10.3}ms", x.at.as_secs_f64() * 1000.0);
+
+    for (i, line) in x.data.chunks(BYTES_PER_LINE).enumerate() {
+        let mut hex = String::new();
+
+        for j in 0..BYTES_PER_LINE {
+            match line.get(j) {
+                Some(b) => hex.push_str(&format!("{b:02x} ")),
+                None => hex.push_str("   "),
+            }
+
+            if j == BYTES_PER_LINE / 2 - 1 {
+                hex.push(' ');
+            }
+        }
+
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect();
+
+        out.push_str(&format!(
+            "{at} {} {:08x}  {hex} |{ascii}|\n",
+            x.direction.symbol(),
+            x.offset + (i * BYTES_PER_LINE) as u64,
+        ));
+    }
+
+    out
+}
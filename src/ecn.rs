@@ -0,0 +1,151 @@
+//! Explicit congestion notification: routers that support it mark packets instead of dropping them when a queue fills up.
+//!
+//! quinn marks every packet it sends as ECN capable, and stops if the peer's acks show the marks don't survive the path.
+//! quinn-udp reads the marks on the packets we receive where the OS reports them, like on linux. A packet marked
+//! congestion experienced (CE) slows the connection down like a lost packet would, without losing it.
+//!
+//! quinn doesn't count the marks, so [`runtime`] wraps every socket and counts them as packets come in. The counts are
+//! for the whole process, in [`snapshot`] and in the stats, and CE marks are logged as they start showing up. Obfuscated and SOCKS sockets don't see marks. Without
+//! `TransportOptions::ecn`, packets are sent unmarked, for paths with middleboxes that mishandle them.
+
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use quinn::udp::{EcnCodepoint, RecvMeta, Transmit, UdpState};
+use quinn::{AsyncTimer, AsyncUdpSocket, Runtime};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// received packets marked ECT(0) or ECT(1)
+static ECT_RECV: AtomicU64 = AtomicU64::new(0);
+
+/// received packets marked CE
+static CE_RECV: AtomicU64 = AtomicU64::new(0);
+
+/// The marks on the packets this process has received.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct EcnSnapshot {
+    /// marked capable and not congested
+    pub ect_packets_recv: u64,
+    /// marked congestion experienced by a router on the way
+    pub ce_packets_recv: u64,
+}
+
+/// the counts so far
+pub fn snapshot() -> EcnSnapshot {
+    EcnSnapshot {
+        ect_packets_recv: ECT_RECV.load(Ordering::Relaxed),
+        ce_packets_recv: CE_RECV.load(Ordering::Relaxed),
+    }
+}
+
+/// count `n` CE packets. logged the first time, then each time the total doubles, so a congested path doesn't flood the
+/// logs
+fn congested(n: u64) {
+    let old = CE_RECV.fetch_add(n, Ordering::Relaxed);
+    let total = old + n;
+
+    if old.leading_zeros() != total.leading_zeros() {
+        info!(
+            new = n,
+            total, "routers marked packets congestion experienced (ECN). the path is congested"
+        );
+    }
+}
+
+/// `inner`, counting the marks on every packet its sockets receive. `send` false sends packets unmarked
+pub fn runtime(send: bool, inner: Arc<dyn Runtime>) -> Arc<dyn Runtime> {
+    Arc::new(EcnRuntime { inner, send })
+}
+
+#[derive(Debug)]
+struct EcnRuntime {
+    inner: Arc<dyn Runtime>,
+    send: bool,
+}
+
+impl Runtime for EcnRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        self.inner.new_timer(i)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        self.inner.spawn(future)
+    }
+
+    fn wrap_udp_socket(&self, t: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        Ok(Box::new(EcnSocket {
+            inner: self.inner.wrap_udp_socket(t)?,
+            send: self.send,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct EcnSocket {
+    inner: Box<dyn AsyncUdpSocket>,
+    send: bool,
+}
+
+impl AsyncUdpSocket for EcnSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        if self.send {
+            return self.inner.poll_send(state, cx, transmits);
+        }
+
+        let unmarked: Vec<_> = transmits
+            .iter()
+            .map(|x| Transmit {
+                ecn: None,
+                ..x.clone()
+            })
+            .collect();
+
+        self.inner.poll_send(state, cx, &unmarked)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let x = self.inner.poll_recv(cx, bufs, meta);
+
+        if let Poll::Ready(Ok(n)) = x {
+            for m in &meta[..n] {
+                // with receive offload, one datagram is several packets with the same mark
+                let packets = m.len.div_ceil(m.stride.max(1)).max(1) as u64;
+
+                match m.ecn {
+                    Some(EcnCodepoint::Ce) => congested(packets),
+                    Some(_) => {
+                        ECT_RECV.fetch_add(packets, Ordering::Relaxed);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        x
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
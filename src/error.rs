@@ -0,0 +1,108 @@
+//! The errors the library returns, so embedders can tell a bad config from a dropped connection from one broken stream.
+//!
+//! The CLI still uses anyhow. Everything here converts into `anyhow::Error` with `?`.
+
+use std::error::Error as StdError;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::protocol::{CloseCode, ProtocolError};
+
+pub type BoxError = Box<dyn StdError + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TunnelError {
+    /// loading certs and keys or building the rustls config failed
+    #[error("tls config")]
+    Tls(#[source] BoxError),
+    /// an option can't be used. retrying won't help
+    #[error("invalid config: {0}")]
+    Config(String),
+    /// the QUIC socket couldn't be bound
+    #[error("binding {addr}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    /// the QUIC connection failed or was closed
+    #[error(transparent)]
+    Connection(#[from] quinn::ConnectionError),
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+    /// no bytes moved on a stream in either direction for this long, so it was closed
+    #[error("stream idle for {0:?}")]
+    StreamIdle(Duration),
+    /// the stream was open for this long, its max lifetime, so it was closed
+    #[error("stream open for longer than {0:?}")]
+    StreamExpired(Duration),
+    /// `chaos` picked this stream to reset
+    #[error("stream reset by chaos")]
+    ChaosReset,
+    /// a backend failed too many dials in a row, so it isn't dialed again for this long. see the `backends` module
+    #[error("the backend's circuit is open for another {0:?}")]
+    CircuitOpen(Duration),
+    /// a stream transformer refused the stream
+    #[error("stream transform")]
+    Transform(#[source] BoxError),
+    /// lz4_flex is built without std, so this isn't a `std::error::Error`
+    #[error("lz4 decompress: {0}")]
+    Decompress(lz4_flex::block::DecompressError),
+    #[error("{0} is not supported")]
+    Unsupported(&'static str),
+    /// the peer's settings can't work with ours. retrying won't help
+    #[error("incompatible peer: {0}")]
+    Incompatible(String),
+    /// the peer sent something we don't understand on a stream
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Write(#[from] quinn::WriteError),
+    /// reading or writing a single stream failed. other streams on the connection are fine
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TunnelError {
+    /// true if the whole connection is gone, not just one stream
+    pub fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            Self::Connection(_)
+                | Self::Timeout(_)
+                | Self::Write(quinn::WriteError::ConnectionLost(_))
+        )
+    }
+
+    /// the code the peer reset or stopped this stream with, if it was one of ours
+    pub fn stream_close_code(&self) -> Option<CloseCode> {
+        let code = match self {
+            Self::Write(quinn::WriteError::Stopped(x)) => *x,
+            Self::Io(err) => {
+                let inner = err.get_ref()?;
+
+                match (inner.downcast_ref(), inner.downcast_ref()) {
+                    (Some(quinn::ReadError::Reset(x)), _) => *x,
+                    (_, Some(quinn::WriteError::Stopped(x))) => *x,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+
+        CloseCode::from_u32(code.into_inner() as u32)
+    }
+
+    /// true if this was caused by the config and will happen again on retry
+    pub fn is_config_error(&self) -> bool {
+        matches!(
+            self,
+            Self::Tls(_)
+                | Self::Config(_)
+                | Self::Bind { .. }
+                | Self::Unsupported(_)
+                | Self::Incompatible(_)
+        )
+    }
+}
@@ -0,0 +1,256 @@
+//! Picking a server for clients that have more than one, so a tunnel survives a server host going down.
+//!
+//! Servers are tried in the order they were given. One that fails is skipped for a while, longer each time it fails in a row,
+//! and is only tried again after the ones that are still up. `srv:_quic-tunnel._udp.example.com` is looked up as a DNS SRV record
+//! and stands for its targets, by priority and weight. `mdns:` stands for the servers advertising on the LAN, or `mdns:name`
+//! for the one called `name`. See the `mdns` module.
+//!
+//! A client stays on the server it has until that connection is lost, even if one earlier in the list comes back.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use quinn::{Connection, Endpoint, ZeroRttAccepted};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{info, warn};
+
+use crate::quic::connect_with_0rtt;
+use crate::resolve::{HostAddr, Resolver};
+
+/// how long a server that keeps failing is skipped, at most
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// a server from the command line or a config file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerAddr {
+    Host(HostAddr),
+    /// a DNS SRV name
+    Srv(String),
+    /// servers found with mDNS. only the instance with this name, if it has one
+    Mdns(Option<String>),
+}
+
+impl FromStr for ServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("srv:") {
+            if name.is_empty() {
+                return Err("srv: needs a name like _quic-tunnel._udp.example.com".to_string());
+            }
+
+            return Ok(Self::Srv(name.to_string()));
+        }
+
+        if let Some(name) = s.strip_prefix("mdns:") {
+            return Ok(Self::Mdns((!name.is_empty()).then(|| name.to_string())));
+        }
+
+        s.parse().map(Self::Host)
+    }
+}
+
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Host(x) => write!(f, "{x}"),
+            Self::Srv(x) => write!(f, "srv:{x}"),
+            Self::Mdns(x) => write!(f, "mdns:{}", x.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+impl From<HostAddr> for ServerAddr {
+    fn from(value: HostAddr) -> Self {
+        Self::Host(value)
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self::Host(value.into())
+    }
+}
+
+/// serialized like it is displayed, "srv:_quic-tunnel._udp.example.com"
+impl Serialize for ServerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug)]
+struct Health {
+    /// in a row
+    failures: u32,
+    /// skipped until then
+    retry_at: Instant,
+}
+
+/// Every server a client can use and how they have been doing.
+#[derive(Debug)]
+pub struct ServerList {
+    servers: Vec<ServerAddr>,
+    /// only servers that failed since they last worked
+    health: Mutex<HashMap<SocketAddr, Health>>,
+}
+
+impl ServerList {
+    pub fn new(servers: Vec<ServerAddr>) -> Self {
+        Self {
+            servers,
+            health: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, x: ServerAddr) {
+        self.servers.push(x);
+    }
+
+    /// every address to try, best first. servers that failed recently go last, the ones that can be retried soonest first
+    pub async fn candidates(&self, resolver: &Resolver) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut addrs = vec![];
+        let mut last_err = None;
+
+        for server in self.servers.iter() {
+            let hosts = match server {
+                ServerAddr::Host(x) => vec![x.clone()],
+                ServerAddr::Srv(name) => match resolver.resolve_srv(name).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        warn!(?err, %server, "failed looking up server");
+                        last_err = Some(err);
+                        continue;
+                    }
+                },
+                ServerAddr::Mdns(name) => match resolver.resolve_mdns(name.as_deref()).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        warn!(?err, %server, "failed looking up server");
+                        last_err = Some(err);
+                        continue;
+                    }
+                },
+            };
+
+            for host in hosts {
+                match resolver.resolve(&host).await {
+                    Ok(x) => {
+                        for x in x {
+                            if !addrs.contains(&x) {
+                                addrs.push(x);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!(?err, %host, "failed looking up server");
+                        last_err = Some(err);
+                    }
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(match last_err {
+                Some(err) => anyhow::Error::new(err).context("none of the servers could be found"),
+                None => anyhow::anyhow!("no servers"),
+            });
+        }
+
+        let now = Instant::now();
+        let health = self.health.lock().expect("server list lock poisoned");
+
+        // stable, so the order they were given in decides everything else
+        addrs.sort_by_key(|x| health.get(x).map(|x| x.retry_at).filter(|x| *x > now));
+
+        Ok(addrs)
+    }
+
+    /// skip `addr` for a while. one second at first, doubling each time it fails in a row
+    pub fn failed(&self, addr: SocketAddr) {
+        let mut health = self.health.lock().expect("server list lock poisoned");
+
+        let x = health.entry(addr).or_insert(Health {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+
+        x.failures += 1;
+
+        let backoff = Duration::from_secs(1)
+            .saturating_mul(1 << (x.failures - 1).min(6))
+            .min(MAX_BACKOFF);
+
+        x.retry_at = Instant::now() + backoff;
+
+        warn!(%addr, failures = x.failures, ?backoff, "server failed. trying the others first");
+    }
+
+    /// `addr` works now
+    pub fn succeeded(&self, addr: SocketAddr) {
+        let mut health = self.health.lock().expect("server list lock poisoned");
+
+        if let Some(x) = health.remove(&addr) {
+            info!(%addr, failures = x.failures, "server is back");
+        }
+    }
+
+    /// try every server that `endpoint` can reach, best first, until one answers. see `connect_with_0rtt` for `zero_rtt`.
+    /// the server isn't marked as working, since with 0-RTT that isn't known yet. call `succeeded` once it is
+    pub async fn connect(
+        &self,
+        endpoint: &Endpoint,
+        server_name: &str,
+        resolver: &Resolver,
+        zero_rtt: bool,
+    ) -> anyhow::Result<(Connection, Option<ZeroRttAccepted>)> {
+        // TODO: the endpoint is only bound for IPv4
+        let ipv4 = endpoint.local_addr()?.is_ipv4();
+
+        let candidates: Vec<_> = self
+            .candidates(resolver)
+            .await?
+            .into_iter()
+            .filter(|x| x.is_ipv4() == ipv4)
+            .collect();
+
+        let mut last_err = None;
+
+        for addr in candidates {
+            let connecting = endpoint.connect(addr, server_name)?;
+
+            match connect_with_0rtt(connecting, zero_rtt).await {
+                Ok(x) => return Ok(x),
+                Err(err) => {
+                    warn!(?err, %addr, "failed connecting to QUIC server");
+
+                    self.failed(addr);
+
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) => Err(err).context("none of the servers answered"),
+            None => {
+                let family = if ipv4 { "IPv4" } else { "IPv6" };
+
+                anyhow::bail!("none of the servers have an {family} address")
+            }
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Splitting UDP payloads across QUIC datagrams, for udp_client's datagram mode.
+//!
+//! A QUIC datagram has to fit in one packet, so without this anything bigger than the path MTU allows, like a 4 KiB DNS
+//! answer or a QUIC packet from a tunnel inside ours, couldn't go through. Every datagram starts with the sender's session
+//! as a QUIC varint, then a 16 bit packet number, the fragment's index, and how many fragments the payload has. A payload
+//! that fits goes in one fragment. The session alone, with nothing after it, tells the server that session closed.
+//!
+//! The receiver only hands a payload on once it has all of it. One that is still missing fragments after
+//! [`REASSEMBLY_TIMEOUT`], or when too many others are waiting, is dropped whole, like a lost packet. UDP apps already
+//! handle loss, and half a payload is worse than none.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use quinn::Connection;
+use tokio_util::bytes::{Bytes, BytesMut};
+use tracing::trace;
+
+use crate::h3::{get_varint, put_varint};
+
+/// a payload is dropped if its fragments don't all come in this long
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// payloads per session waiting for more fragments. the oldest is dropped for a new one past this
+const MAX_PARTIAL: usize = 16;
+
+/// the packet number, index, and count after the session
+const HEADER_LEN: usize = 4;
+
+/// One QUIC datagram's worth of a payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub session: u64,
+    pub packet: u16,
+    pub index: u8,
+    pub count: u8,
+    pub data: Bytes,
+}
+
+impl Fragment {
+    /// `None` if `x` is too short for the header, or the header doesn't make sense
+    pub fn parse(x: Bytes) -> Option<Self> {
+        let (session, n) = get_varint(&x)?;
+        let header = x.get(n..n + HEADER_LEN)?;
+
+        let packet = u16::from_be_bytes([header[0], header[1]]);
+        let (index, count) = (header[2], header[3]);
+
+        if index >= count {
+            return None;
+        }
+
+        Some(Self {
+            session,
+            packet,
+            index,
+            count,
+            data: x.slice(n + HEADER_LEN..),
+        })
+    }
+}
+
+/// the datagram that says `session` closed
+pub fn close(session: u64) -> Bytes {
+    let mut x = vec![];
+    put_varint(&mut x, session);
+
+    x.into()
+}
+
+/// the session `x` says closed, if that is what it is
+pub fn closed_session(x: &[u8]) -> Option<u64> {
+    get_varint(x).filter(|(_, n)| *n == x.len()).map(|(x, _)| x)
+}
+
+/// send `payload` on `conn` in as many datagrams as it takes. false if it was dropped
+pub fn send(conn: &Connection, session: u64, packet: u16, payload: &[u8]) -> bool {
+    let Some(max) = conn.max_datagram_size() else {
+        trace!("dropping a payload. the peer doesn't take datagrams");
+        return false;
+    };
+
+    let Some(x) = split(session, packet, payload, max) else {
+        trace!(
+            n = payload.len(),
+            max,
+            "dropping a payload too big for 255 datagrams"
+        );
+        return false;
+    };
+
+    for x in x {
+        // too big for a path MTU that just went down, or the connection is gone
+        if let Err(err) = conn.send_datagram(x) {
+            trace!(?err, n = payload.len(), "dropping a payload");
+            return false;
+        }
+    }
+
+    true
+}
+
+/// `payload` as datagrams of at most `max_datagram` bytes each. `None` if it would take more than 255
+pub fn split(session: u64, packet: u16, payload: &[u8], max_datagram: usize) -> Option<Vec<Bytes>> {
+    let mut session_bytes = vec![];
+    put_varint(&mut session_bytes, session);
+
+    let room = max_datagram.checked_sub(session_bytes.len() + HEADER_LEN)?;
+
+    if room == 0 {
+        return None;
+    }
+
+    let count = u8::try_from(payload.len().div_ceil(room).max(1)).ok()?;
+
+    let x = (0..count)
+        .map(|index| {
+            let start = index as usize * room;
+            let data = &payload[start..(start + room).min(payload.len())];
+
+            let mut x = BytesMut::with_capacity(session_bytes.len() + HEADER_LEN + data.len());
+            x.extend_from_slice(&session_bytes);
+            x.extend_from_slice(&packet.to_be_bytes());
+            x.extend_from_slice(&[index, count]);
+            x.extend_from_slice(data);
+
+            x.freeze()
+        })
+        .collect();
+
+    Some(x)
+}
+
+/// a payload that is missing fragments
+#[derive(Debug)]
+struct Partial {
+    packet: u16,
+    parts: Vec<Option<Bytes>>,
+    missing: usize,
+    started: Instant,
+}
+
+/// Puts one session's payloads back together.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    partial: VecDeque<Partial>,
+}
+
+impl Reassembler {
+    /// the whole payload once `x` was the last fragment it was missing
+    pub fn push(&mut self, x: Fragment) -> Option<Bytes> {
+        self.push_at(x, Instant::now())
+    }
+
+    fn push_at(&mut self, x: Fragment, now: Instant) -> Option<Bytes> {
+        if x.count == 1 {
+            return Some(x.data);
+        }
+
+        // the oldest are at the front
+        while let Some(p) = self.partial.front() {
+            if now.duration_since(p.started) < REASSEMBLY_TIMEOUT {
+                break;
+            }
+
+            trace!(
+                packet = p.packet,
+                missing = p.missing,
+                "dropping a partial payload that timed out"
+            );
+
+            self.partial.pop_front();
+        }
+
+        let i = match self
+            .partial
+            .iter()
+            .position(|p| p.packet == x.packet && p.parts.len() == x.count as usize)
+        {
+            Some(i) => i,
+            None => {
+                if self.partial.len() >= MAX_PARTIAL {
+                    if let Some(p) = self.partial.pop_front() {
+                        trace!(
+                            packet = p.packet,
+                            missing = p.missing,
+                            "dropping a partial payload for a newer one"
+                        );
+                    }
+                }
+
+                self.partial.push_back(Partial {
+                    packet: x.packet,
+                    parts: vec![None; x.count as usize],
+                    missing: x.count as usize,
+                    started: now,
+                });
+
+                self.partial.len() - 1
+            }
+        };
+
+        let p = &mut self.partial[i];
+        let part = &mut p.parts[x.index as usize];
+
+        // QUIC doesn't repeat datagrams, but a packet number that wrapped around could
+        if part.is_some() {
+            return None;
+        }
+
+        *part = Some(x.data);
+        p.missing -= 1;
+
+        if p.missing > 0 {
+            return None;
+        }
+
+        let p = self.partial.remove(i)?;
+
+        let mut payload = BytesMut::new();
+
+        for x in p.parts.into_iter().flatten() {
+            payload.extend_from_slice(&x);
+        }
+
+        Some(payload.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(n: usize) -> Vec<u8> {
+        (0..n).map(|x| (x * 7 % 251) as u8).collect()
+    }
+
+    fn fragments(session: u64, packet: u16, payload: &[u8], max_datagram: usize) -> Vec<Fragment> {
+        split(session, packet, payload, max_datagram)
+            .unwrap()
+            .into_iter()
+            .map(|x| {
+                assert!(x.len() <= max_datagram);
+                Fragment::parse(x).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        for session in [0, 63, 64, 1 << 30] {
+            for n in [0, 1, 50, 51, 1000, 4096] {
+                let payload = payload(n);
+                let mut x = fragments(session, 9, &payload, 60);
+
+                let mut session_bytes = vec![];
+                put_varint(&mut session_bytes, session);
+
+                let room = 60 - session_bytes.len() - HEADER_LEN;
+                let count = n.div_ceil(room).max(1);
+
+                assert_eq!(x.len(), count);
+
+                for (i, x) in x.iter().enumerate() {
+                    assert_eq!((x.session, x.packet, x.index), (session, 9, i as u8));
+                    assert_eq!(x.count as usize, count);
+                }
+
+                // out of order: every other one, then the rest backwards
+                let (mut odd, even): (Vec<_>, Vec<_>) = x.drain(..).partition(|x| x.index % 2 == 1);
+                odd.extend(even.into_iter().rev());
+
+                let mut r = Reassembler::default();
+                let last = odd.pop().unwrap();
+
+                for x in odd {
+                    assert_eq!(r.push(x), None);
+                }
+
+                assert_eq!(r.push(last).unwrap(), payload, "{session} {n}");
+                assert!(r.partial.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn fragment_cap() {
+        // 60 - 1 byte of session - the header
+        let room = 55;
+
+        assert_eq!(split(0, 0, &payload(room * 255), 60).unwrap().len(), 255);
+        assert_eq!(split(0, 0, &payload(room * 255 + 1), 60), None);
+
+        // no room for any data after the header
+        assert_eq!(split(0, 0, &[], 5), None);
+        assert_eq!(split(0, 0, &[], 4), None);
+        assert_eq!(split(0, 0, &[1], 6).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn bad_fragments() {
+        assert_eq!(Fragment::parse(Bytes::from_static(&[0, 0, 0, 2])), None);
+        assert_eq!(Fragment::parse(Bytes::from_static(&[0, 0, 0, 2, 2])), None);
+        assert_eq!(Fragment::parse(Bytes::from_static(&[0, 0, 0, 0, 0])), None);
+        assert!(Fragment::parse(Bytes::from_static(&[0, 0, 0, 1, 2])).is_some());
+    }
+
+    #[test]
+    fn closes() {
+        for session in [0, 63, 64, 1 << 30] {
+            assert_eq!(closed_session(&close(session)), Some(session));
+        }
+
+        let x = split(5, 0, &[1], 60).unwrap().remove(0);
+
+        assert_eq!(closed_session(&x), None);
+        assert_eq!(closed_session(&[]), None);
+    }
+
+    #[test]
+    fn duplicates() {
+        let payload = payload(200);
+        let x = fragments(0, 1, &payload, 60);
+        let mut r = Reassembler::default();
+
+        assert_eq!(r.push(x[0].clone()), None);
+        assert_eq!(r.push(x[0].clone()), None);
+        assert_eq!(r.push(x[1].clone()), None);
+        assert_eq!(r.push(x[2].clone()), None);
+        assert_eq!(r.push(x[3].clone()).unwrap(), payload);
+
+        // once it's whole, the same fragments start a new payload
+        assert_eq!(r.push(x[3].clone()), None);
+        assert_eq!(r.partial.len(), 1);
+    }
+
+    #[test]
+    fn packet_numbers_wrap() {
+        let a = payload(100);
+        let b = payload(110);
+        let a_parts = fragments(0, u16::MAX, &a, 60);
+        let b_parts = fragments(0, 0, &b, 60);
+        let mut r = Reassembler::default();
+
+        for (a, b) in a_parts.iter().zip(&b_parts).skip(1) {
+            assert_eq!(r.push(a.clone()), None);
+            assert_eq!(r.push(b.clone()), None);
+        }
+
+        assert_eq!(r.push(b_parts[0].clone()).unwrap(), b);
+        assert_eq!(r.push(a_parts[0].clone()).unwrap(), a);
+
+        // a packet number used again with another count is another payload
+        let c = payload(300);
+        let c_parts = fragments(0, u16::MAX, &c, 60);
+        let mut r = Reassembler::default();
+
+        assert_eq!(r.push(a_parts[0].clone()), None);
+
+        for x in &c_parts {
+            assert_eq!(
+                r.push(x.clone()).is_some(),
+                x.index as usize == c_parts.len() - 1
+            );
+        }
+
+        assert_eq!(r.push(a_parts[1].clone()).unwrap(), a);
+    }
+
+    #[test]
+    fn timeouts() {
+        let payload = payload(100);
+        let x = fragments(0, 1, &payload, 60);
+        let start = Instant::now();
+        let mut r = Reassembler::default();
+
+        assert_eq!(r.push_at(x[0].clone(), start), None);
+        assert_eq!(r.push_at(x[1].clone(), start + REASSEMBLY_TIMEOUT), None);
+
+        // the first fragment went with the old payload, so the new one needs it again
+        assert_eq!(r.partial.len(), 1);
+        assert_eq!(
+            r.push_at(x[0].clone(), start + REASSEMBLY_TIMEOUT).unwrap(),
+            payload
+        );
+
+        // just inside the timeout is fine
+        let mut r = Reassembler::default();
+        let late = start + REASSEMBLY_TIMEOUT - Duration::from_millis(1);
+
+        assert_eq!(r.push_at(x[0].clone(), start), None);
+        assert_eq!(r.push_at(x[1].clone(), late).unwrap(), payload);
+    }
+
+    #[test]
+    fn too_many_partials() {
+        let payload = payload(100);
+        let mut r = Reassembler::default();
+
+        for packet in 0..=MAX_PARTIAL as u16 {
+            assert_eq!(r.push(fragments(0, packet, &payload, 60).remove(0)), None);
+        }
+
+        assert_eq!(r.partial.len(), MAX_PARTIAL);
+
+        // the oldest was dropped for the newest
+        assert_eq!(
+            r.push(fragments(0, 1, &payload, 60).remove(1)).unwrap(),
+            payload
+        );
+        assert_eq!(r.push(fragments(0, 0, &payload, 60).remove(1)), None);
+    }
+}
@@ -0,0 +1,1290 @@
+//! Just enough HTTP/3 for the MASQUE server and WebTransport: frames, settings, capsules, and QPACK without a dynamic table.
+//!
+//! We tell peers our QPACK table holds nothing, so every field line they send is a static table reference or a literal.
+//! Anything that isn't for us, like unknown frames, settings, and capsules, is skipped the way RFC 9114 says to.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use quinn::{Connection, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::watch;
+use tracing::{debug, trace};
+
+pub const FRAME_DATA: u64 = 0x00;
+pub const FRAME_HEADERS: u64 = 0x01;
+pub const FRAME_SETTINGS: u64 = 0x04;
+
+pub const STREAM_CONTROL: u64 = 0x00;
+pub const STREAM_QPACK_ENCODER: u64 = 0x02;
+pub const STREAM_QPACK_DECODER: u64 = 0x03;
+
+pub const SETTING_QPACK_MAX_TABLE_CAPACITY: u64 = 0x01;
+pub const SETTING_MAX_FIELD_SECTION_SIZE: u64 = 0x06;
+/// RFC 9220. lets a client send `:protocol`, like CONNECT-UDP does
+pub const SETTING_ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+/// RFC 9297
+pub const SETTING_H3_DATAGRAM: u64 = 0x33;
+
+/// RFC 9297. the only capsule we know
+pub const CAPSULE_DATAGRAM: u64 = 0x00;
+
+pub const H3_NO_ERROR: u32 = 0x0100;
+pub const H3_GENERAL_PROTOCOL_ERROR: u32 = 0x0101;
+pub const H3_STREAM_CREATION_ERROR: u32 = 0x0103;
+pub const H3_CLOSED_CRITICAL_STREAM: u32 = 0x0104;
+pub const H3_FRAME_UNEXPECTED: u32 = 0x0105;
+pub const H3_FRAME_ERROR: u32 = 0x0106;
+pub const H3_MISSING_SETTINGS: u32 = 0x010a;
+pub const H3_REQUEST_REJECTED: u32 = 0x010b;
+pub const H3_MESSAGE_ERROR: u32 = 0x010e;
+pub const H3_DATAGRAM_ERROR: u32 = 0x33;
+pub const QPACK_DECOMPRESSION_FAILED: u32 = 0x0200;
+
+/// headers, settings, and capsules we care about are small. bigger ones are refused
+pub const MAX_FRAME_LEN: u64 = 16 * 1024;
+
+/// bigger DATA frames are read in pieces rather than refused, since they can carry a lot of capsules
+pub const MAX_DATA_CHUNK: u64 = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum H3Error {
+    #[error("malformed frame: {0}")]
+    Frame(&'static str),
+    #[error("{0} frame of {1} bytes is too big")]
+    TooBig(&'static str, u64),
+    #[error("field section: {0}")]
+    Qpack(&'static str),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl H3Error {
+    /// what to reset the stream or close the connection with
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::Frame(_) | Self::TooBig(..) => H3_FRAME_ERROR,
+            Self::Qpack(_) => QPACK_DECOMPRESSION_FAILED,
+            Self::Io(_) => H3_GENERAL_PROTOCOL_ERROR,
+        }
+    }
+}
+
+pub fn put_varint(buf: &mut Vec<u8>, x: u64) {
+    match x {
+        0..=0x3f => buf.push(x as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(x as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(x as u32 | 0x8000_0000).to_be_bytes()),
+        // quic varints stop at 2^62
+        _ => buf.extend_from_slice(&(x | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// a varint from the start of `buf` and how many bytes it took, or `None` if `buf` ends first
+pub fn get_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+
+    let bytes = buf.get(..len)?;
+
+    let x = bytes[1..]
+        .iter()
+        .fold((first & 0x3f) as u64, |x, b| (x << 8) | *b as u64);
+
+    Some((x, len))
+}
+
+/// `None` if the stream ended before the first byte
+pub async fn read_varint<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<u64>, H3Error> {
+    let mut buf = [0; 8];
+
+    if r.read(&mut buf[..1]).await? == 0 {
+        return Ok(None);
+    }
+
+    let len = 1 << (buf[0] >> 6);
+
+    r.read_exact(&mut buf[1..len]).await?;
+
+    Ok(get_varint(&buf[..len]).map(|(x, _)| x))
+}
+
+/// an HTTP/3 frame
+#[derive(Debug)]
+pub struct Frame {
+    pub ty: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(ty: u64, payload: Vec<u8>) -> Self {
+        Self { ty, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut x = Vec::with_capacity(self.payload.len() + 16);
+
+        put_varint(&mut x, self.ty);
+        put_varint(&mut x, self.payload.len() as u64);
+        x.extend_from_slice(&self.payload);
+
+        x
+    }
+
+    /// the next frame, or `None` if the stream ended between frames. DATA frames over `MAX_DATA_CHUNK` come in pieces,
+    /// each its own `Frame`. anything else over `MAX_FRAME_LEN` is an error
+    pub async fn read<R: AsyncRead + Unpin>(
+        r: &mut R,
+        pending_data: &mut u64,
+    ) -> Result<Option<Self>, H3Error> {
+        if *pending_data > 0 {
+            return Self::read_payload(r, FRAME_DATA, *pending_data, pending_data)
+                .await
+                .map(Some);
+        }
+
+        let Some(ty) = read_varint(r).await? else {
+            return Ok(None);
+        };
+
+        Self::read_after_type(r, ty, pending_data).await.map(Some)
+    }
+
+    /// like `read`, for when the frame type was already read to tell what kind of stream this is
+    pub async fn read_after_type<R: AsyncRead + Unpin>(
+        r: &mut R,
+        ty: u64,
+        pending_data: &mut u64,
+    ) -> Result<Self, H3Error> {
+        let len = read_varint(r)
+            .await?
+            .ok_or(H3Error::Frame("stream ended inside a frame"))?;
+
+        Self::read_payload(r, ty, len, pending_data).await
+    }
+
+    async fn read_payload<R: AsyncRead + Unpin>(
+        r: &mut R,
+        ty: u64,
+        len: u64,
+        pending_data: &mut u64,
+    ) -> Result<Self, H3Error> {
+        let eof = || H3Error::Frame("stream ended inside a frame");
+
+        let len = match ty {
+            FRAME_DATA => {
+                let chunk = len.min(MAX_DATA_CHUNK);
+
+                *pending_data = len - chunk;
+
+                chunk
+            }
+            _ if len > MAX_FRAME_LEN => {
+                return Err(H3Error::TooBig(frame_name(ty), len));
+            }
+            _ => len,
+        };
+
+        let mut payload = vec![0; len as usize];
+
+        r.read_exact(&mut payload).await.map_err(|_| eof())?;
+
+        Ok(Self { ty, payload })
+    }
+}
+
+/// open our control stream and send `settings` on it. closing it is an error, so keep it until the connection is done
+pub async fn open_control(
+    conn: &Connection,
+    settings: &[(u64, u64)],
+) -> anyhow::Result<SendStream> {
+    let mut control = conn.open_uni().await?;
+
+    let mut x = vec![];
+    put_varint(&mut x, STREAM_CONTROL);
+    x.extend(Frame::new(FRAME_SETTINGS, encode_settings(settings)).encode());
+
+    control.write_all(&x).await?;
+
+    Ok(control)
+}
+
+/// read the peer's control stream, and the QPACK streams that should stay quiet since we have no table.
+/// `settings` gets the peer's settings once they arrive
+pub async fn accept_uni_streams(
+    conn: &Connection,
+    settings: watch::Sender<Option<HashMap<u64, u64>>>,
+) -> anyhow::Result<()> {
+    let settings = Arc::new(settings);
+
+    loop {
+        let mut rx = conn.accept_uni().await?;
+
+        let conn = conn.clone();
+        let settings = settings.clone();
+
+        // each on its own, so a peer that opens a stream and says nothing doesn't hold up the others
+        tokio::spawn(async move {
+            let Ok(Some(ty)) = read_varint(&mut rx).await else {
+                return;
+            };
+
+            match ty {
+                STREAM_CONTROL => {
+                    if let Err(err) = read_control(&mut rx, &settings).await {
+                        debug!(?err, "control stream failed");
+
+                        conn.close(err.code().into(), err.to_string().as_bytes());
+                    }
+                }
+                STREAM_QPACK_ENCODER | STREAM_QPACK_DECODER => {
+                    let mut buf = [0; 1024];
+
+                    while let Ok(Some(n)) = rx.read(&mut buf).await {
+                        trace!(stream_type = ty, n, "ignoring QPACK instructions");
+                    }
+                }
+                // push streams and anything newer. we never allow pushes
+                _ => {
+                    trace!(stream_type = ty, "ignoring unidirectional stream");
+
+                    let _ = rx.stop(H3_STREAM_CREATION_ERROR.into());
+                }
+            }
+        });
+    }
+}
+
+async fn read_control(
+    rx: &mut RecvStream,
+    settings: &watch::Sender<Option<HashMap<u64, u64>>>,
+) -> Result<(), H3Error> {
+    let mut pending = 0;
+
+    let x = match Frame::read(rx, &mut pending).await? {
+        Some(x) if x.ty == FRAME_SETTINGS => decode_settings(&x.payload)?,
+        _ => {
+            return Err(H3Error::Frame(
+                "the control stream has to start with SETTINGS",
+            ))
+        }
+    };
+
+    debug!(settings = ?x, "peer settings");
+
+    let _ = settings.send(Some(x));
+
+    // GOAWAY and anything else the peer says here doesn't change what we do
+    while let Some(x) = Frame::read(rx, &mut pending).await? {
+        trace!(frame_type = x.ty, "ignoring control frame");
+    }
+
+    Err(H3Error::Frame("the peer closed its control stream"))
+}
+
+fn frame_name(ty: u64) -> &'static str {
+    match ty {
+        FRAME_DATA => "DATA",
+        FRAME_HEADERS => "HEADERS",
+        FRAME_SETTINGS => "SETTINGS",
+        _ => "unknown",
+    }
+}
+
+/// the payload of a SETTINGS frame
+pub fn encode_settings(settings: &[(u64, u64)]) -> Vec<u8> {
+    let mut x = vec![];
+
+    for (id, value) in settings {
+        put_varint(&mut x, *id);
+        put_varint(&mut x, *value);
+    }
+
+    x
+}
+
+pub fn decode_settings(payload: &[u8]) -> Result<HashMap<u64, u64>, H3Error> {
+    let mut settings = HashMap::new();
+    let mut pos = 0;
+
+    while pos < payload.len() {
+        let malformed = || H3Error::Frame("truncated setting");
+
+        let (id, n) = get_varint(&payload[pos..]).ok_or_else(malformed)?;
+        pos += n;
+        let (value, n) = get_varint(&payload[pos..]).ok_or_else(malformed)?;
+        pos += n;
+
+        if settings.insert(id, value).is_some() {
+            return Err(H3Error::Frame("duplicate setting"));
+        }
+    }
+
+    Ok(settings)
+}
+
+/// RFC 9297 capsules, which arrive split across DATA frames however the peer likes
+#[derive(Debug, Default)]
+pub struct CapsuleReader {
+    buf: Vec<u8>,
+}
+
+impl CapsuleReader {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// the next whole capsule's type and payload, if there is one yet
+    pub fn next_capsule(&mut self) -> Result<Option<(u64, Vec<u8>)>, H3Error> {
+        let Some((ty, a)) = get_varint(&self.buf) else {
+            return Ok(None);
+        };
+
+        let Some((len, b)) = get_varint(&self.buf[a..]) else {
+            return Ok(None);
+        };
+
+        // a DATAGRAM capsule only needs to hold one UDP payload
+        if len > MAX_DATA_CHUNK {
+            return Err(H3Error::TooBig("capsule", len));
+        }
+
+        let start = a + b;
+        let end = start + len as usize;
+
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+
+        let payload = self.buf[start..end].to_vec();
+
+        self.buf.drain(..end);
+
+        Ok(Some((ty, payload)))
+    }
+}
+
+pub fn encode_capsule(ty: u64, payload: &[u8]) -> Vec<u8> {
+    let mut x = Vec::with_capacity(payload.len() + 16);
+
+    put_varint(&mut x, ty);
+    put_varint(&mut x, payload.len() as u64);
+    x.extend_from_slice(payload);
+
+    x
+}
+
+/// the QPACK static table, RFC 9204 appendix A
+const STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+/// a QPACK prefixed integer (RFC 7541 5.1) whose first byte uses its low `bits` bits
+fn put_prefixed(buf: &mut Vec<u8>, flags: u8, bits: u8, x: u64) {
+    let max = (1u64 << bits) - 1;
+
+    if x < max {
+        buf.push(flags | x as u8);
+        return;
+    }
+
+    buf.push(flags | max as u8);
+
+    let mut x = x - max;
+
+    while x >= 0x80 {
+        buf.push((x & 0x7f) as u8 | 0x80);
+        x >>= 7;
+    }
+
+    buf.push(x as u8);
+}
+
+fn get_prefixed(buf: &[u8], pos: &mut usize, bits: u8) -> Result<u64, H3Error> {
+    let truncated = || H3Error::Qpack("truncated integer");
+
+    let max = (1u64 << bits) - 1;
+
+    let mut x = (*buf.get(*pos).ok_or_else(truncated)? as u64) & max;
+    *pos += 1;
+
+    if x < max {
+        return Ok(x);
+    }
+
+    let mut shift = 0;
+
+    loop {
+        let b = *buf.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+
+        if shift > 56 {
+            return Err(H3Error::Qpack("integer too big"));
+        }
+
+        x += ((b & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if b & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+}
+
+/// a string whose length uses the low `bits` bits of its first byte, with the Huffman flag just above them
+fn get_string(buf: &[u8], pos: &mut usize, bits: u8) -> Result<String, H3Error> {
+    let huffman = *buf.get(*pos).ok_or(H3Error::Qpack("truncated string"))? & (1 << bits) != 0;
+
+    let len = get_prefixed(buf, pos, bits)?;
+
+    // a length past the end of the field section can be too big to add to `pos`
+    let raw = usize::try_from(len)
+        .ok()
+        .and_then(|x| x.checked_add(*pos))
+        .and_then(|end| buf.get(*pos..end))
+        .ok_or(H3Error::Qpack("truncated string"))?;
+
+    let len = raw.len();
+
+    *pos += len;
+
+    let raw = match huffman {
+        true => huffman_decode(raw)?,
+        false => raw.to_vec(),
+    };
+
+    String::from_utf8(raw).map_err(|_| H3Error::Qpack("field isn't utf8"))
+}
+
+fn put_string(buf: &mut Vec<u8>, flags: u8, bits: u8, x: &str) {
+    // we never Huffman encode. our fields are few and short
+    put_prefixed(buf, flags, bits, x.len() as u64);
+    buf.extend_from_slice(x.as_bytes());
+}
+
+fn static_entry(index: u64) -> Result<(&'static str, &'static str), H3Error> {
+    STATIC_TABLE
+        .get(index as usize)
+        .copied()
+        .ok_or(H3Error::Qpack("static table index out of range"))
+}
+
+/// the fields in a HEADERS frame, in order. fails on anything that needs a dynamic table, since we said we have none
+pub fn decode_fields(payload: &[u8]) -> Result<Vec<(String, String)>, H3Error> {
+    let mut pos = 0;
+
+    // required insert count, then the base
+    if get_prefixed(payload, &mut pos, 8)? != 0 {
+        return Err(H3Error::Qpack("dynamic table reference"));
+    }
+
+    get_prefixed(payload, &mut pos, 7)?;
+
+    let mut fields = vec![];
+
+    while pos < payload.len() {
+        let b = payload[pos];
+
+        let field = match b {
+            // indexed field line
+            _ if b & 0x80 != 0 => {
+                if b & 0x40 == 0 {
+                    return Err(H3Error::Qpack("dynamic table reference"));
+                }
+
+                let (name, value) = static_entry(get_prefixed(payload, &mut pos, 6)?)?;
+
+                (name.to_string(), value.to_string())
+            }
+            // literal field line with name reference
+            _ if b & 0x40 != 0 => {
+                if b & 0x10 == 0 {
+                    return Err(H3Error::Qpack("dynamic table reference"));
+                }
+
+                let (name, _) = static_entry(get_prefixed(payload, &mut pos, 4)?)?;
+
+                (name.to_string(), get_string(payload, &mut pos, 7)?)
+            }
+            // literal field line with literal name
+            _ if b & 0x20 != 0 => {
+                let name = get_string(payload, &mut pos, 3)?;
+
+                (name, get_string(payload, &mut pos, 7)?)
+            }
+            // the post-base forms only point into the dynamic table
+            _ => return Err(H3Error::Qpack("dynamic table reference")),
+        };
+
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+/// a HEADERS frame payload that only uses the static table
+pub fn encode_fields(fields: &[(&str, &str)]) -> Vec<u8> {
+    // no dynamic table, so the required insert count and base are 0
+    let mut x = vec![0, 0];
+
+    for &(name, value) in fields {
+        if let Some(i) = STATIC_TABLE.iter().position(|x| *x == (name, value)) {
+            put_prefixed(&mut x, 0xc0, 6, i as u64);
+        } else if let Some(i) = STATIC_TABLE.iter().position(|x| x.0 == name) {
+            put_prefixed(&mut x, 0x50, 4, i as u64);
+            put_string(&mut x, 0x00, 7, value);
+        } else {
+            put_string(&mut x, 0x20, 3, name);
+            put_string(&mut x, 0x00, 7, value);
+        }
+    }
+
+    x
+}
+
+/// the code for each byte and EOS, RFC 7541 appendix B. (bits, code)
+const HUFFMAN_CODES: [(u8, u32); 257] = [
+    (13, 0x1ff8),
+    (23, 0x7fffd8),
+    (28, 0xfffffe2),
+    (28, 0xfffffe3),
+    (28, 0xfffffe4),
+    (28, 0xfffffe5),
+    (28, 0xfffffe6),
+    (28, 0xfffffe7),
+    (28, 0xfffffe8),
+    (24, 0xffffea),
+    (30, 0x3ffffffc),
+    (28, 0xfffffe9),
+    (28, 0xfffffea),
+    (30, 0x3ffffffd),
+    (28, 0xfffffeb),
+    (28, 0xfffffec),
+    (28, 0xfffffed),
+    (28, 0xfffffee),
+    (28, 0xfffffef),
+    (28, 0xffffff0),
+    (28, 0xffffff1),
+    (28, 0xffffff2),
+    (30, 0x3ffffffe),
+    (28, 0xffffff3),
+    (28, 0xffffff4),
+    (28, 0xffffff5),
+    (28, 0xffffff6),
+    (28, 0xffffff7),
+    (28, 0xffffff8),
+    (28, 0xffffff9),
+    (28, 0xffffffa),
+    (28, 0xffffffb),
+    (6, 0x14),
+    (10, 0x3f8),
+    (10, 0x3f9),
+    (12, 0xffa),
+    (13, 0x1ff9),
+    (6, 0x15),
+    (8, 0xf8),
+    (11, 0x7fa),
+    (10, 0x3fa),
+    (10, 0x3fb),
+    (8, 0xf9),
+    (11, 0x7fb),
+    (8, 0xfa),
+    (6, 0x16),
+    (6, 0x17),
+    (6, 0x18),
+    (5, 0x0),
+    (5, 0x1),
+    (5, 0x2),
+    (6, 0x19),
+    (6, 0x1a),
+    (6, 0x1b),
+    (6, 0x1c),
+    (6, 0x1d),
+    (6, 0x1e),
+    (6, 0x1f),
+    (7, 0x5c),
+    (8, 0xfb),
+    (15, 0x7ffc),
+    (6, 0x20),
+    (12, 0xffb),
+    (10, 0x3fc),
+    (13, 0x1ffa),
+    (6, 0x21),
+    (7, 0x5d),
+    (7, 0x5e),
+    (7, 0x5f),
+    (7, 0x60),
+    (7, 0x61),
+    (7, 0x62),
+    (7, 0x63),
+    (7, 0x64),
+    (7, 0x65),
+    (7, 0x66),
+    (7, 0x67),
+    (7, 0x68),
+    (7, 0x69),
+    (7, 0x6a),
+    (7, 0x6b),
+    (7, 0x6c),
+    (7, 0x6d),
+    (7, 0x6e),
+    (7, 0x6f),
+    (7, 0x70),
+    (7, 0x71),
+    (7, 0x72),
+    (8, 0xfc),
+    (7, 0x73),
+    (8, 0xfd),
+    (13, 0x1ffb),
+    (19, 0x7fff0),
+    (13, 0x1ffc),
+    (14, 0x3ffc),
+    (6, 0x22),
+    (15, 0x7ffd),
+    (5, 0x3),
+    (6, 0x23),
+    (5, 0x4),
+    (6, 0x24),
+    (5, 0x5),
+    (6, 0x25),
+    (6, 0x26),
+    (6, 0x27),
+    (5, 0x6),
+    (7, 0x74),
+    (7, 0x75),
+    (6, 0x28),
+    (6, 0x29),
+    (6, 0x2a),
+    (5, 0x7),
+    (6, 0x2b),
+    (7, 0x76),
+    (6, 0x2c),
+    (5, 0x8),
+    (5, 0x9),
+    (6, 0x2d),
+    (7, 0x77),
+    (7, 0x78),
+    (7, 0x79),
+    (7, 0x7a),
+    (7, 0x7b),
+    (15, 0x7ffe),
+    (11, 0x7fc),
+    (14, 0x3ffd),
+    (13, 0x1ffd),
+    (28, 0xffffffc),
+    (20, 0xfffe6),
+    (22, 0x3fffd2),
+    (20, 0xfffe7),
+    (20, 0xfffe8),
+    (22, 0x3fffd3),
+    (22, 0x3fffd4),
+    (22, 0x3fffd5),
+    (23, 0x7fffd9),
+    (22, 0x3fffd6),
+    (23, 0x7fffda),
+    (23, 0x7fffdb),
+    (23, 0x7fffdc),
+    (23, 0x7fffdd),
+    (23, 0x7fffde),
+    (24, 0xffffeb),
+    (23, 0x7fffdf),
+    (24, 0xffffec),
+    (24, 0xffffed),
+    (22, 0x3fffd7),
+    (23, 0x7fffe0),
+    (24, 0xffffee),
+    (23, 0x7fffe1),
+    (23, 0x7fffe2),
+    (23, 0x7fffe3),
+    (23, 0x7fffe4),
+    (21, 0x1fffdc),
+    (22, 0x3fffd8),
+    (23, 0x7fffe5),
+    (22, 0x3fffd9),
+    (23, 0x7fffe6),
+    (23, 0x7fffe7),
+    (24, 0xffffef),
+    (22, 0x3fffda),
+    (21, 0x1fffdd),
+    (20, 0xfffe9),
+    (22, 0x3fffdb),
+    (22, 0x3fffdc),
+    (23, 0x7fffe8),
+    (23, 0x7fffe9),
+    (21, 0x1fffde),
+    (23, 0x7fffea),
+    (22, 0x3fffdd),
+    (22, 0x3fffde),
+    (24, 0xfffff0),
+    (21, 0x1fffdf),
+    (22, 0x3fffdf),
+    (23, 0x7fffeb),
+    (23, 0x7fffec),
+    (21, 0x1fffe0),
+    (21, 0x1fffe1),
+    (22, 0x3fffe0),
+    (21, 0x1fffe2),
+    (23, 0x7fffed),
+    (22, 0x3fffe1),
+    (23, 0x7fffee),
+    (23, 0x7fffef),
+    (20, 0xfffea),
+    (22, 0x3fffe2),
+    (22, 0x3fffe3),
+    (22, 0x3fffe4),
+    (23, 0x7ffff0),
+    (22, 0x3fffe5),
+    (22, 0x3fffe6),
+    (23, 0x7ffff1),
+    (26, 0x3ffffe0),
+    (26, 0x3ffffe1),
+    (20, 0xfffeb),
+    (19, 0x7fff1),
+    (22, 0x3fffe7),
+    (23, 0x7ffff2),
+    (22, 0x3fffe8),
+    (25, 0x1ffffec),
+    (26, 0x3ffffe2),
+    (26, 0x3ffffe3),
+    (26, 0x3ffffe4),
+    (27, 0x7ffffde),
+    (27, 0x7ffffdf),
+    (26, 0x3ffffe5),
+    (24, 0xfffff1),
+    (25, 0x1ffffed),
+    (19, 0x7fff2),
+    (21, 0x1fffe3),
+    (26, 0x3ffffe6),
+    (27, 0x7ffffe0),
+    (27, 0x7ffffe1),
+    (26, 0x3ffffe7),
+    (27, 0x7ffffe2),
+    (24, 0xfffff2),
+    (21, 0x1fffe4),
+    (21, 0x1fffe5),
+    (26, 0x3ffffe8),
+    (26, 0x3ffffe9),
+    (28, 0xffffffd),
+    (27, 0x7ffffe3),
+    (27, 0x7ffffe4),
+    (27, 0x7ffffe5),
+    (20, 0xfffec),
+    (24, 0xfffff3),
+    (20, 0xfffed),
+    (21, 0x1fffe6),
+    (22, 0x3fffe9),
+    (21, 0x1fffe7),
+    (21, 0x1fffe8),
+    (23, 0x7ffff3),
+    (22, 0x3fffea),
+    (22, 0x3fffeb),
+    (25, 0x1ffffee),
+    (25, 0x1ffffef),
+    (24, 0xfffff4),
+    (24, 0xfffff5),
+    (26, 0x3ffffea),
+    (23, 0x7ffff4),
+    (26, 0x3ffffeb),
+    (27, 0x7ffffe6),
+    (26, 0x3ffffec),
+    (26, 0x3ffffed),
+    (27, 0x7ffffe7),
+    (27, 0x7ffffe8),
+    (27, 0x7ffffe9),
+    (27, 0x7ffffea),
+    (27, 0x7ffffeb),
+    (28, 0xffffffe),
+    (27, 0x7ffffec),
+    (27, 0x7ffffed),
+    (27, 0x7ffffee),
+    (27, 0x7ffffef),
+    (27, 0x7fffff0),
+    (26, 0x3ffffee),
+    (30, 0x3fffffff),
+];
+
+/// the symbol for each (bits, code)
+fn huffman_symbols() -> &'static HashMap<(u8, u32), u16> {
+    static SYMBOLS: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+
+    SYMBOLS.get_or_init(|| {
+        HUFFMAN_CODES
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (*x, i as u16))
+            .collect()
+    })
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, H3Error> {
+    let symbols = huffman_symbols();
+
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut bits = 0u8;
+
+    for byte in raw {
+        for i in (0..8).rev() {
+            code = (code << 1) | ((byte >> i) & 1) as u32;
+            bits += 1;
+
+            match symbols.get(&(bits, code)) {
+                Some(256) => return Err(H3Error::Qpack("EOS in a Huffman string")),
+                Some(x) => {
+                    out.push(*x as u8);
+                    code = 0;
+                    bits = 0;
+                }
+                // the longest code is 30 bits
+                None if bits >= 30 => return Err(H3Error::Qpack("bad Huffman code")),
+                None => {}
+            }
+        }
+    }
+
+    // the end is padded with the start of EOS, which is all ones, and less than a byte of it
+    if bits >= 8 || code != (1 << bits) - 1 {
+        return Err(H3Error::Qpack("bad Huffman padding"));
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(x: &str) -> Vec<u8> {
+        let x: String = x.split_whitespace().collect();
+
+        (0..x.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&x[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn varints() {
+        // RFC 9000 appendix A.1
+        let cases = [
+            ("c2 19 7c 5e ff 14 e8 8c", 151_288_809_941_952_652),
+            ("9d 7f 3e 7d", 494_878_333),
+            ("7b bd", 15_293),
+            ("25", 37),
+            ("40 25", 37),
+        ];
+
+        for (bytes, x) in cases {
+            let bytes = hex(bytes);
+
+            assert_eq!(get_varint(&bytes), Some((x, bytes.len())), "{bytes:02x?}");
+        }
+
+        for x in [
+            0,
+            63,
+            64,
+            16_383,
+            16_384,
+            1_073_741_823,
+            1_073_741_824,
+            (1 << 62) - 1,
+        ] {
+            let mut buf = vec![];
+            put_varint(&mut buf, x);
+
+            assert_eq!(get_varint(&buf), Some((x, buf.len())));
+        }
+    }
+
+    #[test]
+    fn truncated_varints() {
+        assert_eq!(get_varint(&[]), None);
+        assert_eq!(get_varint(&hex("40")), None);
+        assert_eq!(get_varint(&hex("9d 7f 3e")), None);
+        assert_eq!(get_varint(&hex("c2 19 7c 5e ff 14 e8")), None);
+    }
+
+    #[test]
+    fn prefixed_integers() {
+        // RFC 7541 appendix C.1
+        let cases = [("0a", 5, 10), ("1f 9a 0a", 5, 1337), ("2a", 8, 42)];
+
+        for (bytes, bits, x) in cases {
+            let bytes = hex(bytes);
+
+            let mut buf = vec![];
+            put_prefixed(&mut buf, 0, bits, x);
+            assert_eq!(buf, bytes);
+
+            let mut pos = 0;
+            assert_eq!(get_prefixed(&bytes, &mut pos, bits).unwrap(), x);
+            assert_eq!(pos, bytes.len());
+        }
+    }
+
+    #[test]
+    fn bad_prefixed_integers() {
+        let cases = [
+            ("", "truncated integer"),
+            ("1f", "truncated integer"),
+            ("1f 9a", "truncated integer"),
+            ("1f ff ff ff ff ff ff ff ff ff ff 01", "integer too big"),
+        ];
+
+        for (bytes, err) in cases {
+            let mut pos = 0;
+
+            assert!(
+                matches!(get_prefixed(&hex(bytes), &mut pos, 5), Err(H3Error::Qpack(x)) if x == err),
+                "{bytes}"
+            );
+        }
+    }
+
+    #[test]
+    fn huffman() {
+        // RFC 7541 appendix C.4
+        let cases = [
+            ("f1e3 c2e5 f23a 6ba0 ab90 f4ff", "www.example.com"),
+            ("a8eb 1064 9cbf", "no-cache"),
+            ("25a8 49e9 5ba9 7d7f", "custom-key"),
+            ("25a8 49e9 5bb8 e8b4 bf", "custom-value"),
+            // 5 bits of "0", then 3 of padding
+            ("07", "0"),
+            ("", ""),
+        ];
+
+        for (bytes, x) in cases {
+            assert_eq!(
+                huffman_decode(&hex(bytes)).unwrap(),
+                x.as_bytes(),
+                "{bytes}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_huffman() {
+        let cases = [
+            // padding that isn't all ones
+            ("00", "bad Huffman padding"),
+            // a whole byte of padding
+            ("a8eb 1064 9cbf ff", "bad Huffman padding"),
+            // EOS, which is 30 ones
+            ("ff ff ff fc", "EOS in a Huffman string"),
+        ];
+
+        for (bytes, err) in cases {
+            assert!(
+                matches!(huffman_decode(&hex(bytes)), Err(H3Error::Qpack(x)) if x == err),
+                "{bytes}"
+            );
+        }
+    }
+
+    #[test]
+    fn fields() {
+        // RFC 9204 appendix B.1
+        let payload = hex("0000 510b 2f69 6e64 6578 2e68 746d 6c");
+        let fields = vec![(":path".to_string(), "/index.html".to_string())];
+
+        assert_eq!(decode_fields(&payload).unwrap(), fields);
+        assert_eq!(encode_fields(&[(":path", "/index.html")]), payload);
+
+        let x = [
+            (":method", "CONNECT"),
+            (":protocol", "connect-udp"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/.well-known/masque/udp/192.0.2.6/443/"),
+            ("capsule-protocol", "?1"),
+        ];
+
+        let decoded = decode_fields(&encode_fields(&x)).unwrap();
+
+        assert_eq!(
+            decoded
+                .iter()
+                .map(|(a, b)| (a.as_str(), b.as_str()))
+                .collect::<Vec<_>>(),
+            x
+        );
+    }
+
+    #[test]
+    fn huffman_fields() {
+        // RFC 9204 appendix B.4 has Huffman strings, but inserts them into the dynamic table. this is a literal name
+        // and value, both Huffman encoded: "custom-key: custom-value"
+        let payload = hex("0000 2f01 25a8 49e9 5ba9 7d7f 89 25a8 49e9 5bb8 e8b4 bf");
+
+        assert_eq!(
+            decode_fields(&payload).unwrap(),
+            vec![("custom-key".to_string(), "custom-value".to_string())]
+        );
+    }
+
+    #[test]
+    fn bad_fields() {
+        let cases = [
+            ("", "truncated integer"),
+            ("00", "truncated integer"),
+            // RFC 9204 appendix B.2 refers to the dynamic table
+            ("0381 10 11", "dynamic table reference"),
+            // indexed, but not static
+            ("0000 81", "dynamic table reference"),
+            // post-base index
+            ("0000 10", "dynamic table reference"),
+            ("0000 ff 24", "static table index out of range"),
+            // a value longer than the payload
+            ("0000 51 0b 2f69", "truncated string"),
+            // a value length that overflows
+            ("0000 51 7f ff ff ff ff ff ff ff ff 7f", "truncated string"),
+            ("0000 51 01 ff", "field isn't utf8"),
+            ("0000 51 81 ff", "bad Huffman padding"),
+        ];
+
+        for (bytes, err) in cases {
+            assert!(
+                matches!(decode_fields(&hex(bytes)), Err(H3Error::Qpack(x)) if x == err),
+                "{bytes}: {:?}",
+                decode_fields(&hex(bytes))
+            );
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let x = [
+            (SETTING_QPACK_MAX_TABLE_CAPACITY, 0),
+            (SETTING_ENABLE_CONNECT_PROTOCOL, 1),
+            (SETTING_H3_DATAGRAM, 1),
+            // unknown settings are kept for the caller to ignore
+            (0x1f * 7 + 0x21, 1 << 40),
+        ];
+
+        assert_eq!(
+            decode_settings(&encode_settings(&x)).unwrap(),
+            x.into_iter().collect()
+        );
+
+        let bad = [
+            (hex("01"), "malformed frame: truncated setting"),
+            (hex("01 40"), "malformed frame: truncated setting"),
+            (hex("01 00 01 01"), "malformed frame: duplicate setting"),
+        ];
+
+        for (bytes, err) in bad {
+            assert_eq!(decode_settings(&bytes).unwrap_err().to_string(), err);
+        }
+    }
+
+    #[test]
+    fn capsules() {
+        let mut x = CapsuleReader::default();
+
+        let a = encode_capsule(CAPSULE_DATAGRAM, b"hello");
+        let b = encode_capsule(0x2a, &[7; 300]);
+
+        // split anywhere, even inside the varints
+        let all = [a, b].concat();
+        let (first, rest) = all.split_at(1);
+
+        x.push(first);
+        assert_eq!(x.next_capsule().unwrap(), None);
+
+        x.push(&rest[..10]);
+        assert_eq!(
+            x.next_capsule().unwrap(),
+            Some((CAPSULE_DATAGRAM, b"hello".to_vec()))
+        );
+        assert_eq!(x.next_capsule().unwrap(), None);
+
+        x.push(&rest[10..]);
+        assert_eq!(x.next_capsule().unwrap(), Some((0x2a, vec![7; 300])));
+        assert_eq!(x.next_capsule().unwrap(), None);
+    }
+
+    #[test]
+    fn oversize_capsule() {
+        let mut x = CapsuleReader::default();
+
+        let mut buf = vec![];
+        put_varint(&mut buf, CAPSULE_DATAGRAM);
+        put_varint(&mut buf, MAX_DATA_CHUNK + 1);
+        x.push(&buf);
+
+        assert!(matches!(
+            x.next_capsule(),
+            Err(H3Error::TooBig("capsule", _))
+        ));
+
+        // and one at the very end of the varint range doesn't overflow anything
+        let mut x = CapsuleReader::default();
+        x.push(&hex("00 ff ff ff ff ff ff ff ff"));
+
+        assert!(matches!(
+            x.next_capsule(),
+            Err(H3Error::TooBig("capsule", _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn frames() {
+        let headers = Frame::new(FRAME_HEADERS, encode_fields(&[(":status", "200")])).encode();
+        let data = Frame::new(FRAME_DATA, vec![1; MAX_DATA_CHUNK as usize + 10]).encode();
+
+        let all = [headers, data].concat();
+        let mut r = all.as_slice();
+        let mut pending = 0;
+
+        let x = Frame::read(&mut r, &mut pending).await.unwrap().unwrap();
+        assert_eq!(x.ty, FRAME_HEADERS);
+        assert_eq!(decode_fields(&x.payload).unwrap()[0].1, "200");
+
+        // a big DATA frame comes in pieces
+        let x = Frame::read(&mut r, &mut pending).await.unwrap().unwrap();
+        assert_eq!(
+            (x.ty, x.payload.len()),
+            (FRAME_DATA, MAX_DATA_CHUNK as usize)
+        );
+        assert_eq!(pending, 10);
+
+        let x = Frame::read(&mut r, &mut pending).await.unwrap().unwrap();
+        assert_eq!((x.ty, x.payload.len()), (FRAME_DATA, 10));
+        assert_eq!(pending, 0);
+
+        assert!(Frame::read(&mut r, &mut pending).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bad_frames() {
+        let mut big = vec![];
+        put_varint(&mut big, FRAME_HEADERS);
+        put_varint(&mut big, MAX_FRAME_LEN + 1);
+
+        let cases = [
+            (big, "HEADERS frame of 16385 bytes is too big"),
+            (hex("01"), "malformed frame: stream ended inside a frame"),
+            (
+                hex("01 05 00 00"),
+                "malformed frame: stream ended inside a frame",
+            ),
+        ];
+
+        for (bytes, err) in cases {
+            let mut r = bytes.as_slice();
+
+            let x = Frame::read(&mut r, &mut 0).await.unwrap_err();
+
+            assert_eq!(x.to_string(), err);
+        }
+    }
+}
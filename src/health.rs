@@ -0,0 +1,148 @@
+//! `/healthz` and `/readyz` over plain HTTP, for Kubernetes probes and load balancer health checks.
+//!
+//! `/healthz` is 200 while the process is answering at all. `/readyz` is 200 when it can carry traffic, and 503 with the
+//! reason when it can't: for the server, once its QUIC endpoint is bound, while it isn't draining, and while a tunnel client
+//! is connected. For the client, while it is connected to a server. Anything else is 404.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::select;
+use tokio::time::timeout;
+use tracing::{info, trace, warn};
+
+use crate::listen::{ListenTarget, Listener};
+use crate::shutdown::CancellationToken;
+use crate::stream::Stream;
+use crate::upgrade::OwnedFd;
+
+/// a probe gets this long to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// probes send a request line and a few headers. the rest is ignored
+const MAX_REQUEST_LEN: usize = 4096;
+
+/// `Ok` when ready, or why not
+pub type ReadyCheck = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+pub struct HealthServer {
+    listener: Listener,
+    addr: SocketAddr,
+}
+
+impl HealthServer {
+    /// a socket handed over by an upgrade is used instead of binding a new one
+    pub async fn bind(addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = Listener::bind(
+            &ListenTarget::Tcp(addr),
+            &Default::default(),
+            &Default::default(),
+        )
+        .await?;
+
+        let addr = listener.local_addr().unwrap_or(addr);
+
+        info!(%addr, "health checks listening");
+
+        Ok(Self { listener, addr })
+    }
+
+    /// the address we actually bound. useful when binding port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// a copy of the socket to hand to a new process during an upgrade
+    pub fn handover_fd(&self) -> std::io::Result<OwnedFd> {
+        self.listener
+            .handover_fd()
+            .expect("tcp listeners can be handed over")
+    }
+
+    pub async fn serve(self, ready: ReadyCheck, shutdown: CancellationToken) -> anyhow::Result<()> {
+        loop {
+            let stream = select! {
+                x = self.listener.accept() => x,
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+
+            let Ok(Stream::Tcp(mut stream)) =
+                stream.inspect_err(|err| warn!(?err, "health check accept failed"))
+            else {
+                continue;
+            };
+
+            let ready = ready.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = respond(&mut stream, &ready).await {
+                    trace!(?err, "health check failed");
+                }
+            });
+        }
+    }
+}
+
+async fn respond(stream: &mut tokio::net::TcpStream, ready: &ReadyCheck) -> std::io::Result<()> {
+    let mut buf = vec![0; MAX_REQUEST_LEN];
+    let mut n = 0;
+
+    // only the request line matters, but the headers are read too. closing with unread bytes resets the connection, which
+    // can throw away the response before the probe reads it
+    let line = timeout(REQUEST_TIMEOUT, async {
+        loop {
+            if buf[..n].windows(4).any(|x| x == b"\r\n\r\n") {
+                let end = buf.windows(2).position(|x| x == b"\r\n").unwrap_or(n);
+
+                return Ok::<_, std::io::Error>(String::from_utf8_lossy(&buf[..end]).into_owned());
+            }
+
+            if n == buf.len() {
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+
+            match stream.read(&mut buf[n..]).await? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                x => n += x,
+            }
+        }
+    })
+    .await
+    .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let mut parts = line.split(' ');
+    let (method, path) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+
+    // probes don't send queries, but a load balancer might add one
+    let path = path.split_once('?').map_or(path, |(x, _)| x);
+
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => ("200 OK", "ok".to_string()),
+        ("GET" | "HEAD", "/readyz") => match ready() {
+            Ok(()) => ("200 OK", "ok".to_string()),
+            Err(reason) => ("503 Service Unavailable", reason),
+        },
+        ("GET" | "HEAD", _) => ("404 Not Found", "not found".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed".to_string()),
+    };
+
+    trace!(method, path, status, "health check");
+
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        body.len() + 1,
+    );
+
+    if method != "HEAD" {
+        response.push_str(&body);
+        response.push('\n');
+    }
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
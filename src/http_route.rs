@@ -0,0 +1,365 @@
+//! Routing plain HTTP users by the Host header and path of their first request, so one port can front many web services.
+//!
+//! Like `sni`, the request head is peeked, not read, so the backend gets the request as the user sent it. Only the first
+//! request of a connection is looked at. Later requests on the same keep-alive connection go to the same route.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tracing::trace;
+
+use crate::sni::{self, host_matches, Parsed};
+
+/// request heads longer than this go to the listener's route
+const MAX_REQUEST_HEAD_LEN: usize = 16 * 1024;
+
+/// users whose first request matches `host` and `path` go to `route`. a rule without `host` or `path` matches any
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpRule {
+    /// a name like "git.example.com", a wildcard like "*.example.com", or "*". the port in the Host header is ignored
+    pub host: Option<String>,
+    /// a path prefix like "/api". it matches whole segments, so "/api" matches "/api/users" but not "/apis"
+    pub path: Option<String>,
+    pub route: String,
+}
+
+impl HttpRule {
+    pub fn matches(&self, request: &RequestHead) -> bool {
+        let host = match (&self.host, &request.host) {
+            (None, _) => true,
+            (Some(x), Some(host)) => host_matches(x, host),
+            (Some(_), None) => false,
+        };
+
+        host && self
+            .path
+            .as_deref()
+            .is_none_or(|x| path_matches(x, &request.path))
+    }
+}
+
+impl FromStr for HttpRule {
+    type Err = String;
+
+    /// "host=route", "/path=route", or "host/path=route"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("\"{s}\" is not a rule like example.com/api=web");
+
+        let (matcher, route) = s.split_once('=').ok_or_else(err)?;
+
+        if matcher.is_empty() || route.is_empty() {
+            return Err(err());
+        }
+
+        let (host, path) = match matcher.find('/') {
+            Some(i) => (&matcher[..i], Some(matcher[i..].to_string())),
+            None => (matcher, None),
+        };
+
+        Ok(Self {
+            host: Some(host).filter(|x| !x.is_empty()).map(|x| x.to_string()),
+            path,
+            route: route.to_string(),
+        })
+    }
+}
+
+/// what the rules look at in a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestHead {
+    /// without the port
+    pub host: Option<String>,
+    /// without the query
+    pub path: String,
+}
+
+/// the route of the first rule that matches, if any
+pub fn route<'a>(rules: &'a [HttpRule], request: &RequestHead) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|x| x.matches(request))
+        .map(|x| x.route.as_str())
+}
+
+/// the head of the first request the user is sending, without taking it off the socket.
+/// `None` if it isn't HTTP/1 or doesn't arrive within `sni::PEEK_TIMEOUT`
+pub async fn peek_request(stream: &TcpStream) -> Option<RequestHead> {
+    let x = sni::peek(stream, MAX_REQUEST_HEAD_LEN, parse_request_head).await;
+
+    trace!(request = ?x, "peeked request head");
+
+    x
+}
+
+fn path_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn parse_request_head(buf: &[u8]) -> Parsed<RequestHead> {
+    // a request line is printable text. anything else, like a ClientHello, isn't worth waiting for
+    let line_end = buf
+        .windows(2)
+        .position(|x| x == b"\r\n")
+        .unwrap_or(buf.len());
+
+    // the request line's "\r\n" can be split, with only the "\r" here so far
+    let line = match line_end == buf.len() {
+        true => buf.strip_suffix(b"\r").unwrap_or(buf),
+        false => &buf[..line_end],
+    };
+
+    if !line.iter().all(|x| x.is_ascii_graphic() || *x == b' ') {
+        return Parsed::Done(None);
+    }
+
+    let Some(end) = buf.windows(4).position(|x| x == b"\r\n\r\n") else {
+        return Parsed::Incomplete;
+    };
+
+    let Ok(head) = std::str::from_utf8(&buf[..end]) else {
+        return Parsed::Done(None);
+    };
+
+    let mut lines = head.split("\r\n");
+
+    Parsed::Done(lines.next().and_then(|x| request_line(x, lines)))
+}
+
+fn request_line<'a>(line: &str, headers: impl Iterator<Item = &'a str>) -> Option<RequestHead> {
+    let mut parts = line.split(' ');
+
+    let (_method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+
+    if !version.starts_with("HTTP/1.") || parts.next().is_some() {
+        return None;
+    }
+
+    // proxies send the absolute form, like "http://example.com/path"
+    let (authority, path) = match target.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(i) => (Some(&rest[..i]), &rest[i..]),
+            None => (Some(rest), "/"),
+        },
+        None => (None, target),
+    };
+
+    let host = authority.or_else(|| {
+        headers
+            .filter_map(|x| x.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+            .map(|(_, value)| value.trim())
+    });
+
+    let path = path.split_once('?').map_or(path, |(x, _)| x);
+
+    Some(RequestHead {
+        host: host.map(strip_port).map(|x| x.to_string()),
+        path: path.to_string(),
+    })
+}
+
+/// "example.com:8080" is "example.com", and "[::1]:8080" is "::1"
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split_once(']').map_or(rest, |(x, _)| x);
+    }
+
+    host.rsplit_once(':').map_or(host, |(x, _)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(host: Option<&str>, path: &str) -> RequestHead {
+        RequestHead {
+            host: host.map(|x| x.to_string()),
+            path: path.to_string(),
+        }
+    }
+
+    fn parsed(buf: &[u8]) -> Option<Option<RequestHead>> {
+        match parse_request_head(buf) {
+            Parsed::Incomplete => None,
+            Parsed::Done(x) => Some(x),
+        }
+    }
+
+    #[test]
+    fn request_heads() {
+        let cases: [(&[u8], _); 9] = [
+            (
+                b"GET /api/users HTTP/1.1\r\nHost: example.com\r\n\r\n",
+                head(Some("example.com"), "/api/users"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nhost:example.com:8080\r\n\r\n",
+                head(Some("example.com"), "/"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHOST: [::1]:8080\r\n\r\n",
+                head(Some("::1"), "/"),
+            ),
+            (
+                b"GET /search?q=a/b HTTP/1.1\r\nHost: example.com\r\n\r\n",
+                head(Some("example.com"), "/search"),
+            ),
+            (
+                b"GET http://example.com:8080/api?x=1 HTTP/1.1\r\nHost: other.example.com\r\n\r\n",
+                head(Some("example.com"), "/api"),
+            ),
+            (
+                b"GET http://example.com HTTP/1.1\r\n\r\n",
+                head(Some("example.com"), "/"),
+            ),
+            (
+                b"POST /api HTTP/1.0\r\nContent-Length: 2\r\n\r\nhi",
+                head(None, "/api"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nX-Host: a.example.com\r\nHost: b.example.com\r\n\r\n",
+                head(Some("b.example.com"), "/"),
+            ),
+            (
+                b"OPTIONS * HTTP/1.1\r\nHost: example.com\r\n\r\n",
+                head(Some("example.com"), "*"),
+            ),
+        ];
+
+        for (buf, x) in cases {
+            assert_eq!(
+                parsed(buf),
+                Some(Some(x)),
+                "{}",
+                String::from_utf8_lossy(buf)
+            );
+        }
+    }
+
+    #[test]
+    fn not_http() {
+        let cases: [&[u8]; 6] = [
+            // a ClientHello
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03",
+            b"SSH-2.0-OpenSSH_9.6\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+        ];
+
+        for buf in cases {
+            assert_eq!(
+                parsed(buf),
+                Some(None),
+                "{:?}",
+                String::from_utf8_lossy(buf)
+            );
+        }
+    }
+
+    #[test]
+    fn incomplete_heads() {
+        let x = b"GET /api HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        for n in 0..x.len() {
+            assert_eq!(parsed(&x[..n]), None, "{n}");
+        }
+
+        assert!(parsed(x).is_some());
+    }
+
+    #[test]
+    fn ports() {
+        let cases = [
+            ("example.com", "example.com"),
+            ("example.com:8080", "example.com"),
+            ("[::1]:8080", "::1"),
+            ("[::1]", "::1"),
+            ("[::1", "::1"),
+            ("127.0.0.1:80", "127.0.0.1"),
+        ];
+
+        for (host, x) in cases {
+            assert_eq!(strip_port(host), x, "{host}");
+        }
+    }
+
+    #[test]
+    fn paths() {
+        let cases = [
+            ("/api", "/api", true),
+            ("/api", "/api/users", true),
+            ("/api", "/apis", false),
+            ("/api", "/ap", false),
+            ("/api/", "/api/users", true),
+            ("/api/", "/api", false),
+            ("/", "/anything", true),
+            ("/api", "/API", false),
+        ];
+
+        for (prefix, path, x) in cases {
+            assert_eq!(path_matches(prefix, path), x, "{prefix} {path}");
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let rule = |s: &str| s.parse::<HttpRule>().unwrap();
+
+        assert_eq!(
+            rule("example.com/api=api"),
+            HttpRule {
+                host: Some("example.com".to_string()),
+                path: Some("/api".to_string()),
+                route: "api".to_string(),
+            }
+        );
+        assert_eq!(
+            rule("/api=api"),
+            HttpRule {
+                host: None,
+                path: Some("/api".to_string()),
+                route: "api".to_string(),
+            }
+        );
+        assert_eq!(
+            rule("*.example.com=web"),
+            HttpRule {
+                host: Some("*.example.com".to_string()),
+                path: None,
+                route: "web".to_string(),
+            }
+        );
+
+        for x in ["", "example.com", "=web", "example.com="] {
+            assert!(x.parse::<HttpRule>().is_err(), "{x}");
+        }
+
+        let rules = [
+            rule("example.com/api=api"),
+            rule("*.example.com=web"),
+            rule("/static=static"),
+        ];
+
+        let cases = [
+            (head(Some("example.com"), "/api/users"), Some("api")),
+            (head(Some("EXAMPLE.com"), "/api"), Some("api")),
+            (head(Some("example.com"), "/apis"), None),
+            (head(Some("www.example.com"), "/api"), Some("web")),
+            (head(Some("example.org"), "/static/x.css"), Some("static")),
+            // a rule with a host never matches a request without one
+            (head(None, "/api"), None),
+            (head(None, "/static"), Some("static")),
+        ];
+
+        for (x, expected) in cases {
+            assert_eq!(route(&rules, &x), expected, "{x:?}");
+        }
+    }
+}
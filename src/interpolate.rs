@@ -0,0 +1,352 @@
+//! References in a config file's strings, so secrets can come from wherever an orchestrator puts them instead of being
+//! written into the file.
+//!
+//! ```toml
+//! [server]
+//! certs = "${CERTS_DIR}/tunnel"
+//!
+//! [[server.webhooks]]
+//! url = "https://hooks.example.com/tunnel"
+//! headers = { Authorization = "file:/run/secrets/webhook-token" }
+//! ```
+//!
+//! `${NAME}` anywhere in a string is the environment variable `NAME`, and `$${` is a plain `${`. A string written with
+//! `file:` at the start is the contents of the file after it, without the last line ending, like a Kubernetes secret or
+//! a systemd credential. The path can use variables, and a relative path is relative to the directory the config file
+//! is in. A string that only starts with `file:` once a variable is filled in stays as it is, and so do the values of
+//! variables, so whoever sets the environment can't make the config read a file. `$file:` at the start is a plain `file:`.
+//!
+//! Only string values are touched, and only the strings that change are rewritten, so syntax errors later in the file
+//! still point at the right line. A variable that isn't set, or a file that can't be read, is an error. Nothing falls
+//! back to an empty string.
+
+use std::path::Path;
+
+use toml_edit::{ImDocument, Item, Table, Value};
+
+const FILE_PREFIX: &str = "file:";
+const ESCAPED_FILE_PREFIX: &str = "$file:";
+
+/// A reference that couldn't be filled in.
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {key}: {message}")]
+pub struct InterpolateError {
+    pub line: usize,
+    /// the value's key, like "server.listeners[1].tls_cert"
+    pub key: String,
+    pub message: String,
+}
+
+/// `text` with the references in its strings filled in. `base` is where relative `file:` paths start. text that doesn't
+/// parse is returned as it is, for the config's own parser to report
+pub fn interpolate(text: &str, base: &Path) -> Result<String, InterpolateError> {
+    let Ok(doc) = ImDocument::parse(text) else {
+        return Ok(text.to_string());
+    };
+
+    let mut strings = vec![];
+    table_strings(doc.as_table(), "", &mut strings);
+
+    // a table's values can come after other tables in the file
+    strings.sort_by_key(|(_, span, _)| span.as_ref().map(|x| x.start));
+
+    let mut changed = vec![];
+
+    for (key, span, value) in strings {
+        let Some(span) = span else {
+            continue;
+        };
+
+        let new = fill(value, base).map_err(|message| InterpolateError {
+            line: text[..span.start].matches('\n').count() + 1,
+            key,
+            message,
+        })?;
+
+        if new != value {
+            changed.push((span, Value::from(new).to_string()));
+        }
+    }
+
+    // from the end, so the spans before each one stay where they are
+    let mut x = text.to_string();
+
+    for (span, new) in changed.into_iter().rev() {
+        x.replace_range(span, &new);
+    }
+
+    Ok(x)
+}
+
+/// what `x` is once its references are filled in
+fn fill(x: &str, base: &Path) -> Result<String, String> {
+    if let Some(rest) = x.strip_prefix(ESCAPED_FILE_PREFIX) {
+        return Ok(format!("{FILE_PREFIX}{}", expand_vars(rest)?));
+    }
+
+    let Some(path) = x.strip_prefix(FILE_PREFIX) else {
+        return expand_vars(x);
+    };
+
+    let path = base.join(expand_vars(path)?);
+
+    let mut contents = std::fs::read_to_string(&path)
+        .map_err(|err| format!("reading {}: {err}", path.display()))?;
+
+    if contents.ends_with('\n') {
+        contents.pop();
+
+        if contents.ends_with('\r') {
+            contents.pop();
+        }
+    }
+
+    Ok(contents)
+}
+
+/// `x` with every `${NAME}` replaced by the environment variable
+fn expand_vars(x: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(x.len());
+    let mut rest = x;
+
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("\"${{\" without a \"}}\" in {x:?}"))?;
+            let name = &after[..end];
+
+            let valid = name.starts_with(|x: char| x.is_ascii_alphabetic() || x == '_')
+                && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_');
+
+            if !valid {
+                return Err(format!("{name:?} isn't an environment variable name"));
+            }
+
+            let value = std::env::var(name).map_err(|err| format!("${{{name}}}: {err}"))?;
+
+            out.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+type Strings<'a> = Vec<(String, Option<std::ops::Range<usize>>, &'a str)>;
+
+fn table_strings<'a>(x: &'a Table, key: &str, out: &mut Strings<'a>) {
+    for (name, item) in x.iter() {
+        let key = join(key, name);
+
+        match item {
+            Item::Value(x) => value_strings(x, &key, out),
+            Item::Table(x) => table_strings(x, &key, out),
+            Item::ArrayOfTables(x) => {
+                for (i, x) in x.iter().enumerate() {
+                    table_strings(x, &format!("{key}[{i}]"), out);
+                }
+            }
+            Item::None => {}
+        }
+    }
+}
+
+fn value_strings<'a>(x: &'a Value, key: &str, out: &mut Strings<'a>) {
+    match x {
+        Value::String(x) => out.push((key.to_string(), x.span(), x.value())),
+        Value::Array(x) => {
+            for (i, x) in x.iter().enumerate() {
+                value_strings(x, &format!("{key}[{i}]"), out);
+            }
+        }
+        Value::InlineTable(x) => {
+            for (name, x) in x.iter() {
+                value_strings(x, &join(key, name), out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn join(key: &str, name: &str) -> String {
+    match key {
+        "" => name.to_string(),
+        _ => format!("{key}.{name}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// a directory of its own under the temp directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let x = std::env::temp_dir().join(format!(
+                "quic-tunnel-interpolate-{name}-{}",
+                std::process::id()
+            ));
+
+            std::fs::create_dir_all(&x).unwrap();
+
+            Self(x)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn vars() {
+        // names of their own, since tests run at the same time and share the environment
+        std::env::set_var("QT_INTERPOLATE_HOST", "example.com");
+        std::env::set_var("QT_INTERPOLATE_REF", "file:/etc/passwd");
+        std::env::set_var("QT_INTERPOLATE_NESTED", "${QT_INTERPOLATE_HOST}");
+
+        let cases = [
+            ("plain", "plain"),
+            ("${QT_INTERPOLATE_HOST}", "example.com"),
+            (
+                "https://${QT_INTERPOLATE_HOST}:${QT_INTERPOLATE_HOST}/",
+                "https://example.com:example.com/",
+            ),
+            ("$${QT_INTERPOLATE_HOST}", "${QT_INTERPOLATE_HOST}"),
+            // a plain `$`, then an escaped `${`
+            ("$$${QT_INTERPOLATE_HOST}", "$${QT_INTERPOLATE_HOST}"),
+            ("cost: $5, $x, $", "cost: $5, $x, $"),
+            // values are used as they are
+            ("${QT_INTERPOLATE_NESTED}", "${QT_INTERPOLATE_HOST}"),
+            ("${QT_INTERPOLATE_REF}", "file:/etc/passwd"),
+            ("$file:${QT_INTERPOLATE_HOST}", "file:example.com"),
+            ("$file:", "file:"),
+            ("not file:at the start", "not file:at the start"),
+        ];
+
+        for (x, expected) in cases {
+            assert_eq!(fill(x, Path::new("/")).unwrap(), expected, "{x:?}");
+        }
+    }
+
+    #[test]
+    fn bad_vars() {
+        let cases = [
+            ("${QT_INTERPOLATE_HOST", "without"),
+            ("${", "without"),
+            ("${}", "isn't an environment variable name"),
+            ("${1X}", "isn't an environment variable name"),
+            ("${A-B}", "isn't an environment variable name"),
+            ("${A B}", "isn't an environment variable name"),
+            ("${QT_INTERPOLATE_NOT_SET}", "QT_INTERPOLATE_NOT_SET"),
+            ("file:${QT_INTERPOLATE_NOT_SET}", "QT_INTERPOLATE_NOT_SET"),
+        ];
+
+        for (x, expected) in cases {
+            let err = fill(x, Path::new("/")).unwrap_err();
+
+            assert!(err.contains(expected), "{x:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn files() {
+        let dir = TempDir::new("files");
+
+        std::fs::create_dir_all(dir.0.join("secrets")).unwrap();
+
+        for (name, contents) in [
+            ("lf", "token\n"),
+            ("crlf", "token\r\n"),
+            ("none", "token"),
+            ("two", "token\n\n"),
+            ("cr", "token\r"),
+            ("lines", "a\r\nb\r\n"),
+        ] {
+            std::fs::write(dir.0.join("secrets").join(name), contents).unwrap();
+        }
+
+        std::env::set_var("QT_INTERPOLATE_SECRETS", "secrets");
+
+        let cases = [
+            ("file:secrets/lf", "token"),
+            ("file:secrets/crlf", "token"),
+            ("file:secrets/none", "token"),
+            // only the last line ending
+            ("file:secrets/two", "token\n"),
+            ("file:secrets/cr", "token\r"),
+            ("file:secrets/lines", "a\r\nb"),
+            ("file:${QT_INTERPOLATE_SECRETS}/lf", "token"),
+        ];
+
+        for (x, expected) in cases {
+            assert_eq!(fill(x, &dir.0).unwrap(), expected, "{x:?}");
+        }
+
+        // an absolute path ignores the base
+        let absolute = format!("file:{}", dir.0.join("secrets/lf").display());
+
+        assert_eq!(fill(&absolute, Path::new("/nowhere")).unwrap(), "token");
+
+        let err = fill("file:secrets/missing", &dir.0).unwrap_err();
+
+        assert!(err.contains("secrets/missing"), "{err}");
+    }
+
+    #[test]
+    fn documents() {
+        let dir = TempDir::new("documents");
+
+        std::fs::write(dir.0.join("key"), "hunter2\n").unwrap();
+        std::env::set_var("QT_INTERPOLATE_PORT", "4433");
+
+        let text = r#"# a comment that stays
+[server]
+quic_addr = "127.0.0.1:${QT_INTERPOLATE_PORT}"
+names = ["a", 'file:key']
+
+[[server.webhooks]]
+headers = { Authorization = "file:key" }
+"#;
+
+        let expected = r#"# a comment that stays
+[server]
+quic_addr = "127.0.0.1:4433"
+names = ["a", "hunter2"]
+
+[[server.webhooks]]
+headers = { Authorization = "hunter2" }
+"#;
+
+        assert_eq!(interpolate(text, &dir.0).unwrap(), expected);
+
+        // left for the config's own parser to report
+        assert_eq!(interpolate("[server", &dir.0).unwrap(), "[server");
+
+        let err = interpolate(
+            "[server]\nquic_addr = \"x\"\n\n[[server.listeners]]\n\n[[server.listeners]]\ntls_cert = \"${QT_INTERPOLATE_NOT_SET}\"\n",
+            &dir.0,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.line, 7);
+        assert_eq!(err.key, "server.listeners[1].tls_cert");
+    }
+}
@@ -3,13 +3,72 @@ use std::{net::SocketAddr, sync::Arc, time::Duration};
 use moka::future::Cache;
 use tokio::sync::Mutex;
 
+pub mod admin;
+pub mod audit;
+pub mod backends;
+pub mod bind;
+pub mod buffer;
 pub mod certs;
+pub mod chain;
+pub mod chaos;
+pub mod client;
 pub mod compress;
+pub mod config;
+pub mod control;
 pub mod counters;
+pub mod daemon;
+pub mod datagram;
+pub mod dump;
+pub mod ecn;
+pub mod error;
+pub mod failover;
+pub mod fragment;
+pub mod h3;
+pub mod health;
+pub mod http_route;
+pub mod interpolate;
+pub mod listen;
 pub mod log;
+pub mod masque;
+pub mod mdns;
+pub mod migrate;
+pub mod multipath;
+pub mod obfs;
+pub mod originate;
+pub mod padding;
+pub mod pipe;
+pub mod pool;
+pub mod protocol;
+pub mod proxy;
 pub mod quic;
+pub mod quota;
+pub mod rate_limit;
+pub mod registry;
+pub mod reject;
+pub mod rendezvous;
+pub mod resolve;
+pub mod runtime;
+pub mod server;
+pub mod service;
+pub mod shutdown;
+pub mod sni;
+pub mod srv;
 pub mod stream;
+pub mod tcp_fallback;
+pub mod terminate;
+pub mod testing;
 pub mod tls;
+pub mod transform;
+pub mod transparent;
+pub mod udp_session;
+pub mod unix;
+pub mod upgrade;
+pub mod usage;
+pub mod vpn;
+pub mod vsock;
+pub mod warm_up;
+pub mod webhook;
+pub mod webtransport;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TunnelCacheKey {
@@ -19,8 +78,8 @@ pub struct TunnelCacheKey {
 }
 
 /// Since UDP is stateless, we need to keep track of destinations so we can send responses to the right stream.
-pub type TunnelCache = Cache<
-    TunnelCacheKey,
+pub type TunnelCache<K = TunnelCacheKey> = Cache<
+    K,
     (
         Arc<Mutex<quinn::SendStream>>,
         Arc<Mutex<Option<quinn::RecvStream>>>,
@@ -0,0 +1,390 @@
+//! Check every listen target before starting anything so a conflict is one clear error instead of a dead task.
+
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use serde::{Serialize, Serializer};
+use tokio::net::{TcpListener, TcpSocket};
+
+use crate::pipe::PipeListener;
+use crate::stream::{StdioStream, Stream, TcpOptions};
+use crate::unix::{self, SocketFile, UnixListener, UnixSocketOptions};
+use crate::upgrade::{self, OwnedFd};
+use crate::vsock::{self, VsockAddr, VsockListener};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenTarget {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    Unix(PathBuf),
+    UnixDatagram(PathBuf),
+    /// a windows named pipe like `\\.\pipe\quic-tunnel`
+    NamedPipe(String),
+    Vsock(VsockAddr),
+    /// this process's stdin and stdout. a single user, and the server stops when they are done
+    Stdio,
+}
+
+/// serialized like it is displayed, "tcp 127.0.0.1:8080"
+impl Serialize for ListenTarget {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Display for ListenTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(x) => write!(f, "tcp {x}"),
+            Self::Udp(x) => write!(f, "udp {x}"),
+            Self::Unix(x) => write!(f, "unix {}", x.display()),
+            Self::UnixDatagram(x) => write!(f, "unixgram {}", x.display()),
+            Self::NamedPipe(x) => write!(f, "pipe {x}"),
+            Self::Vsock(x) => write!(f, "vsock {x}"),
+            Self::Stdio => write!(f, "stdio"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ListenConflict {
+    pub target: ListenTarget,
+    pub problem: String,
+    /// the process holding the address, if we could figure it out
+    pub owner: Option<String>,
+}
+
+impl Display for ListenConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.target, self.problem)?;
+
+        if let Some(owner) = &self.owner {
+            write!(f, " (held by {owner})")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub struct ListenConflicts(pub Vec<ListenConflict>);
+
+impl Display for ListenConflicts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} listen target(s) can't be used:", self.0.len())?;
+
+        for x in self.0.iter() {
+            writeln!(f, "  - {x}")?;
+        }
+
+        write!(
+            f,
+            "stop the other process or choose a different address, then try again"
+        )
+    }
+}
+
+/// Try binding every target and report all of the failures at once. A target listed twice is reported once, without
+/// trying it, since binding it a second time would fail later however free it is now.
+///
+/// The sockets are closed right away, so something else could still take the address before we really bind it.
+pub fn check_listen_targets(targets: &[ListenTarget]) -> Result<(), ListenConflicts> {
+    let mut conflicts = vec![];
+
+    for (i, x) in targets.iter().enumerate() {
+        // reported with the first one
+        if targets[..i].contains(x) {
+            continue;
+        }
+
+        // every bind to port 0 gets a port of its own
+        let any_port =
+            matches!(x, ListenTarget::Tcp(addr) | ListenTarget::Udp(addr) if addr.port() == 0);
+
+        let conflict = if !any_port && targets[i + 1..].contains(x) {
+            Some(ListenConflict {
+                target: x.clone(),
+                problem: "listed more than once".to_string(),
+                owner: None,
+            })
+        } else {
+            check_listen_target(x)
+        };
+
+        conflicts.extend(conflict);
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(ListenConflicts(conflicts))
+    }
+}
+
+fn check_listen_target(target: &ListenTarget) -> Option<ListenConflict> {
+    // the server we are upgrading from is still bound to it
+    if upgrade::is_inherited(target) {
+        return None;
+    }
+
+    let (x, port) = match target {
+        ListenTarget::Stdio => return None,
+        ListenTarget::Tcp(addr) => (
+            std::net::TcpListener::bind(addr).map(drop),
+            Some(("tcp", addr.port())),
+        ),
+        ListenTarget::Udp(addr) => (
+            std::net::UdpSocket::bind(addr).map(drop),
+            Some(("udp", addr.port())),
+        ),
+        ListenTarget::NamedPipe(name) => (PipeListener::bind(name).map(drop), None),
+        ListenTarget::Vsock(addr) => (vsock::check_bind(*addr), None),
+        ListenTarget::Unix(path) | ListenTarget::UnixDatagram(path) => {
+            let problem = unix::check_bind(path, matches!(target, ListenTarget::UnixDatagram(_)))?;
+
+            return Some(ListenConflict {
+                target: target.clone(),
+                problem,
+                owner: None,
+            });
+        }
+    };
+
+    let err = x.err()?;
+
+    let owner = match (err.kind(), port) {
+        (std::io::ErrorKind::AddrInUse, Some((proto, port))) => find_port_owner(proto, port),
+        _ => None,
+    };
+
+    Some(ListenConflict {
+        target: target.clone(),
+        problem: err.to_string(),
+        owner,
+    })
+}
+
+/// look through procfs for a process with a socket bound to this port
+#[cfg(target_os = "linux")]
+fn find_port_owner(proto: &str, port: u16) -> Option<String> {
+    let mut inodes = vec![];
+
+    for table in [proto.to_string(), format!("{proto}6")] {
+        let Ok(x) = std::fs::read_to_string(format!("/proc/net/{table}")) else {
+            continue;
+        };
+
+        for line in x.lines().skip(1) {
+            let fields: Vec<_> = line.split_whitespace().collect();
+
+            if fields.len() < 10 {
+                continue;
+            }
+
+            // tcp sockets that aren't listening (0A) are just connections that happen to use the port
+            if proto == "tcp" && fields[3] != "0A" {
+                continue;
+            }
+
+            let local_port = fields[1]
+                .rsplit_once(':')
+                .and_then(|(_, x)| u16::from_str_radix(x, 16).ok());
+
+            if local_port == Some(port) {
+                inodes.push(format!("socket:[{}]", fields[9]));
+            }
+        }
+    }
+
+    if inodes.is_empty() {
+        return None;
+    }
+
+    // this only finds processes that we are allowed to inspect
+    for proc_entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(fds) = std::fs::read_dir(proc_entry.path().join("fd")) else {
+            continue;
+        };
+
+        for fd in fds.flatten() {
+            let Ok(link) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+
+            if inodes.iter().any(|x| link.as_os_str() == x.as_str()) {
+                let pid = proc_entry.file_name().to_string_lossy().to_string();
+
+                let comm = std::fs::read_to_string(proc_entry.path().join("comm"))
+                    .map(|x| x.trim().to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+
+                return Some(format!("{comm}, pid {pid}"));
+            }
+        }
+    }
+
+    Some("a process we aren't allowed to inspect".to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_port_owner(_proto: &str, _port: u16) -> Option<String> {
+    None
+}
+
+/// a bound listener for user connections
+#[derive(Debug)]
+pub enum Listener {
+    /// the options are applied to every accepted connection
+    Tcp(TcpListener, TcpOptions),
+    /// the socket file is removed when the listener is dropped
+    Unix(UnixListener, SocketFile),
+    NamedPipe(PipeListener),
+    Vsock(VsockListener),
+    /// hands out stdio once, then never accepts again
+    Stdio(std::sync::Mutex<Option<StdioStream>>),
+}
+
+impl Listener {
+    /// `tcp` is ignored for unix sockets and `unix` is ignored for tcp.
+    /// a socket handed over by an upgrade is used instead of binding a new one
+    pub async fn bind(
+        target: &ListenTarget,
+        tcp: &TcpOptions,
+        unix: &UnixSocketOptions,
+    ) -> anyhow::Result<Self> {
+        let x = match target {
+            ListenTarget::Tcp(addr) => {
+                if let Some(fd) = upgrade::take(target) {
+                    let x = std::net::TcpListener::from(fd);
+                    x.set_nonblocking(true)?;
+
+                    return Ok(Self::Tcp(TcpListener::from_std(x)?, tcp.clone()));
+                }
+
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+
+                // TcpListener::bind does this too
+                #[cfg(unix)]
+                socket.set_reuseaddr(true)?;
+
+                tcp.apply_to_socket(&socket)?;
+
+                socket.bind(*addr)?;
+
+                Self::Tcp(socket.listen(tcp.backlog.unwrap_or(1024))?, tcp.clone())
+            }
+            ListenTarget::Unix(path) => {
+                let (x, file) = unix::bind_listener(path, unix)?;
+
+                Self::Unix(x, file)
+            }
+            ListenTarget::NamedPipe(name) => Self::NamedPipe(PipeListener::bind(name)?),
+            ListenTarget::Vsock(addr) => match upgrade::take(target) {
+                Some(fd) => Self::Vsock(VsockListener::from_fd(fd)?),
+                None => Self::Vsock(VsockListener::bind(*addr)?),
+            },
+            ListenTarget::Stdio => Self::Stdio(Some(StdioStream::default()).into()),
+            ListenTarget::Udp(_) | ListenTarget::UnixDatagram(_) => {
+                anyhow::bail!("datagram listeners don't accept connections")
+            }
+        };
+
+        Ok(x)
+    }
+
+    pub async fn accept(&self) -> std::io::Result<Stream> {
+        match self {
+            Self::Tcp(x, tcp) => {
+                let (x, _) = x.accept().await?;
+
+                tcp.apply_to_stream(&x)?;
+
+                Ok(Stream::Tcp(x))
+            }
+            Self::Unix(x, _) => x.accept().await.map(|(x, _)| Stream::Unix(x)),
+            Self::NamedPipe(x) => x.accept().await.map(Stream::NamedPipe),
+            Self::Vsock(x) => x.accept().await.map(|(x, _)| Stream::Vsock(x)),
+            Self::Stdio(x) => {
+                let x = x.lock().expect("stdio lock poisoned").take();
+
+                match x {
+                    Some(x) => Ok(Stream::Stdio(x)),
+                    None => std::future::pending().await,
+                }
+            }
+        }
+    }
+
+    /// a copy of the socket to hand to a new process during an upgrade. `None` for things that can't be handed over, like stdio
+    #[cfg(unix)]
+    pub fn handover_fd(&self) -> Option<std::io::Result<OwnedFd>> {
+        use std::os::fd::AsFd;
+
+        let x = match self {
+            Self::Tcp(x, _) => x.as_fd(),
+            Self::Unix(x, _) => x.as_fd(),
+            Self::Vsock(x) => x.as_fd(),
+            Self::NamedPipe(_) | Self::Stdio(_) => return None,
+        };
+
+        Some(x.try_clone_to_owned())
+    }
+
+    /// nothing can be handed over without unix upgrades
+    #[cfg(not(unix))]
+    pub fn handover_fd(&self) -> Option<std::io::Result<OwnedFd>> {
+        None
+    }
+
+    /// the tcp address we actually bound. useful when binding port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(x, _) => x.local_addr().ok(),
+            Self::Unix(..) | Self::NamedPipe(_) | Self::Vsock(_) | Self::Stdio(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_targets() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let held = ListenTarget::Tcp(held.local_addr().unwrap());
+        let any = ListenTarget::Tcp("127.0.0.1:0".parse().unwrap());
+
+        let err = check_listen_targets(&[
+            held.clone(),
+            ListenTarget::Stdio,
+            any.clone(),
+            ListenTarget::Stdio,
+            held.clone(),
+            any,
+            ListenTarget::Stdio,
+        ])
+        .unwrap_err();
+
+        let x: Vec<_> = err.0.iter().map(|x| (&x.target, &*x.problem)).collect();
+
+        assert_eq!(
+            x,
+            [
+                (&held, "listed more than once"),
+                (&ListenTarget::Stdio, "listed more than once"),
+            ]
+        );
+
+        // once, it is tried
+        let err = check_listen_targets(&[held]).unwrap_err();
+
+        assert_eq!(err.0.len(), 1);
+        assert_ne!(err.0[0].problem, "listed more than once");
+    }
+}
@@ -1,14 +1,80 @@
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use strum::EnumString;
 use tracing::{info, Level};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[derive(Clone, Copy, Debug, Default, EnumString, PartialEq)]
+#[strum(ascii_case_insensitive)]
+pub enum LogFormat {
+    /// multi-line and colorful. for humans
+    #[default]
+    Pretty,
+    /// one JSON object per line. for Loki, Elasticsearch, and friends.
+    ///
+    /// Connection and stream logs have `conn_id`, `stream_id`, `peer`, and `service` fields in `span`.
+    Json,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LogOptions {
+    pub format: LogFormat,
+    /// send spans to this OTLP collector over gRPC. only works when built with the `otel` feature.
+    ///
+    /// Each tunneled stream is a `stream` span with `listener`, `client_fingerprint`, byte counts, and `close_reason`.
+    pub otlp_endpoint: Option<String>,
+    /// append logs to this file instead of writing them to stderr. for daemons and services, which have nowhere else to write
+    pub file: Option<PathBuf>,
+}
 
+/// logs go to stderr (or `file`) so stdout is left for command output like `check --json`
+/// call [`shutdown_logging`] before exiting so buffered spans are exported
 /// TODO: filtered fmt layer, plus a different filter for tokio-console
 /// TODO: verbosity options from command
 /// TODO: better way of setting defaults
 /// TODO: sentry
 /// TODO: panic handler
-pub fn configure_logging() {
+pub fn configure_logging(options: &LogOptions) -> anyhow::Result<()> {
+    let (writer, ansi) = match &options.file {
+        Some(path) => {
+            let x = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("unable to open log file {}", path.display()))?;
+
+            (BoxMakeWriter::new(Mutex::new(x)), false)
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), true),
+    };
+
+    let fmt_layer = match options.format {
+        LogFormat::Pretty => fmt::layer()
+            .pretty()
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(writer)
+            .boxed(),
+    };
+
+    let otel_layer = match &options.otlp_endpoint {
+        Some(x) => Some(otel_layer(x)?),
+        None => None,
+    };
+
     tracing_subscriber::registry()
-        .with(fmt::layer().pretty())
+        .with(fmt_layer)
+        .with(otel_layer)
         .with(
             EnvFilter::builder()
                 .with_default_directive(Level::INFO.into())
@@ -17,4 +83,52 @@ pub fn configure_logging() {
         .init();
 
     info!("hello, world!");
+
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+fn otel_layer<S>(endpoint: &str) -> anyhow::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: tracing::Subscriber + Send + Sync + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use opentelemetry_otlp::WithExportConfig;
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+            opentelemetry_sdk::Resource::new([opentelemetry::KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )]),
+        ))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+#[cfg(not(feature = "otel"))]
+fn otel_layer<S>(_endpoint: &str) -> anyhow::Result<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: tracing::Subscriber,
+{
+    anyhow::bail!("OTLP export needs quic-tunnel to be built with the otel feature")
+}
+
+/// flush any spans that haven't been exported yet
+pub async fn shutdown_logging() {
+    #[cfg(feature = "otel")]
+    {
+        // this blocks until the batch exporter is done
+        if let Err(err) =
+            tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await
+        {
+            eprintln!("failed to flush spans: {err}");
+        }
+    }
 }
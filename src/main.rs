@@ -1,25 +1,74 @@
 mod subcommands;
 
+use std::path::PathBuf;
+
 use argh::FromArgs;
-use quic_tunnel::log::configure_logging;
+use quic_tunnel::daemon::{self, PidFile};
+use quic_tunnel::log::{configure_logging, shutdown_logging, LogFormat, LogOptions};
+use quic_tunnel::runtime::{build_data_plane_runtime, set_data_plane};
 use subcommands::{
-    QuickCertsSubCommand, ReverseProxyClientSubCommand, ReverseProxyServerSubCommand,
+    BenchSubCommand, CheckSubCommand, EchoServerSubCommand, MasqueServerSubCommand,
+    PeerClientSubCommand, PeerServerSubCommand, PingSubCommand, QuickCertsSubCommand,
+    ReverseProxyClientSubCommand, ReverseProxyServerSubCommand, RunSubCommand, ServiceSubCommand,
+    TopSubCommand, TransparentClientSubCommand, TunClientSubCommand, TunServerSubCommand,
     UdpClientSubCommand, UdpServerSubCommand,
 };
+use tracing::info;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Top-level command.
 struct TopLevel {
+    /// how to write logs: pretty or json
+    #[argh(option, default = "LogFormat::Pretty")]
+    log_format: LogFormat,
+
+    /// export spans to this OTLP gRPC collector, like http://localhost:4317. needs the otel feature
+    #[argh(option)]
+    otlp_endpoint: Option<String>,
+
+    /// run QUIC and the copy loops on their own runtime with this many threads, apart from stats and management tasks.
+    ///
+    /// If not set, everything shares one runtime.
+    #[argh(option)]
+    data_plane_threads: Option<usize>,
+
+    /// run in the background, detached from the terminal
+    #[argh(switch)]
+    daemon: bool,
+
+    /// append logs to this file instead of writing them to stderr. with daemon, stdout and stderr go here too instead of nowhere
+    #[argh(option)]
+    log_file: Option<PathBuf>,
+
+    /// write our pid to this file, and remove it when we exit
+    #[argh(option)]
+    pid_file: Option<PathBuf>,
+
     #[argh(subcommand)]
     nested: MySubCommandEnum,
 }
 
+// argh can't box subcommands. this is only built once, so the size doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum MySubCommandEnum {
+    Bench(BenchSubCommand),
+    Check(CheckSubCommand),
+    EchoServer(EchoServerSubCommand),
+    MasqueServer(MasqueServerSubCommand),
+    PeerClient(PeerClientSubCommand),
+    PeerServer(PeerServerSubCommand),
+    Ping(PingSubCommand),
     QuickCerts(QuickCertsSubCommand),
     ReverseProxyClient(ReverseProxyClientSubCommand),
     ReverseProxyServer(ReverseProxyServerSubCommand),
+    Run(RunSubCommand),
+    Service(ServiceSubCommand),
+    Top(TopSubCommand),
+    TransparentClient(TransparentClientSubCommand),
+    TunClient(TunClientSubCommand),
+    TunServer(TunServerSubCommand),
     UdpClient(UdpClientSubCommand),
     UdpServer(UdpServerSubCommand),
 }
@@ -28,15 +77,66 @@ enum MySubCommandEnum {
 async fn main() -> anyhow::Result<()> {
     let command: TopLevel = argh::from_env();
 
-    configure_logging();
+    configure_logging(&LogOptions {
+        format: command.log_format,
+        otlp_endpoint: command.otlp_endpoint,
+        file: command.log_file.clone(),
+    })?;
+
+    if command.daemon && daemon::needs_spawn() {
+        let pid = daemon::spawn(command.log_file.as_deref())?;
+
+        info!(pid, "running in the background");
+
+        shutdown_logging().await;
+
+        return Ok(());
+    }
+
+    // removed when main returns
+    let _pid_file = command.pid_file.map(PidFile::create).transpose()?;
+
+    let data_plane = match command.data_plane_threads {
+        Some(0) => anyhow::bail!("data_plane_threads must be at least 1"),
+        Some(x) => {
+            let data_plane = build_data_plane_runtime(x)?;
+
+            set_data_plane(data_plane.handle().clone())
+                .expect("data plane should only be set once");
+
+            Some(data_plane)
+        }
+        None => None,
+    };
+
+    let x = match command.nested {
+        MySubCommandEnum::Bench(subcommand) => subcommand.main().await,
+        MySubCommandEnum::Check(subcommand) => subcommand.main(),
+        MySubCommandEnum::EchoServer(subcommand) => subcommand.main().await,
+        MySubCommandEnum::MasqueServer(subcommand) => subcommand.main().await,
+        MySubCommandEnum::PeerClient(subcommand) => subcommand.main().await,
+        MySubCommandEnum::PeerServer(subcommand) => subcommand.main().await,
+        MySubCommandEnum::Ping(subcommand) => subcommand.main().await,
+        MySubCommandEnum::QuickCerts(subcommand) => subcommand.main(),
+        MySubCommandEnum::ReverseProxyClient(subcommand) => subcommand.main().await,
+        MySubCommandEnum::ReverseProxyServer(subcommand) => subcommand.main().await,
+        MySubCommandEnum::Run(subcommand) => subcommand.main().await,
+        MySubCommandEnum::Service(subcommand) => subcommand.main().await,
+        MySubCommandEnum::Top(subcommand) => subcommand.main().await,
+        MySubCommandEnum::TransparentClient(subcommand) => subcommand.main().await,
+        MySubCommandEnum::TunClient(subcommand) => subcommand.main().await,
+        MySubCommandEnum::TunServer(subcommand) => subcommand.main().await,
+        MySubCommandEnum::UdpClient(subcommand) => subcommand.main().await,
+        MySubCommandEnum::UdpServer(subcommand) => subcommand.main().await,
+    };
+
+    // export spans even if the subcommand failed
+    shutdown_logging().await;
 
-    match command.nested {
-        MySubCommandEnum::QuickCerts(subcommand) => subcommand.main()?,
-        MySubCommandEnum::ReverseProxyClient(subcommand) => subcommand.main().await?,
-        MySubCommandEnum::ReverseProxyServer(subcommand) => subcommand.main().await?,
-        MySubCommandEnum::UdpClient(subcommand) => subcommand.main().await?,
-        MySubCommandEnum::UdpServer(subcommand) => subcommand.main().await?,
+    // dropping a runtime blocks, which isn't allowed inside another runtime
+    if let Some(x) = data_plane {
+        x.shutdown_background();
     }
 
-    Ok(())
+    x
 }
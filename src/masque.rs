@@ -0,0 +1,773 @@
+//! A MASQUE CONNECT-UDP server (RFC 9298), so standard HTTP/3 clients and relays can use us to reach UDP services, not just
+//! our own udp_client.
+//!
+//! A client sends an extended CONNECT (RFC 9220) for `/.well-known/masque/udp/{host}/{port}/`, and once we answer 200,
+//! each UDP payload goes back and forth as an HTTP datagram (RFC 9297). Those ride in QUIC datagrams when the client supports
+//! them, or in DATAGRAM capsules on the request stream when it doesn't. Every request gets its own UDP socket.
+//!
+//! Clients still need a cert signed by our CA, since anyone else could use us to send UDP anywhere. `MasqueOptions::allow`
+//! limits where they can send it. See the `h3` module for what is left out of HTTP/3.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use flume::{Receiver, Sender};
+use ipnet::IpNet;
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+use crate::counters::{ScopedCounters, TunnelCounters};
+use crate::error::TunnelError;
+use crate::h3::{
+    self, encode_capsule, encode_fields, get_varint, put_varint, CapsuleReader, Frame, H3Error,
+};
+use crate::protocol::PREAMBLE_TIMEOUT;
+use crate::quic::{matching_bind_address, transport_config, TransportOptions};
+use crate::resolve::{HostAddr, Resolver};
+use crate::shutdown::CancellationToken;
+use crate::tls::{self, TlsOptions};
+
+pub const ALPN: &[u8] = b"h3";
+
+/// RFC 9298's default URI template, `/.well-known/masque/udp/{target_host}/{target_port}/`
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// RFC 9298. context 0 is a UDP payload, anything else is an extension we don't know
+const CONTEXT_UDP: u64 = 0;
+
+/// datagrams for one request that haven't been sent on yet. more than this are dropped, like a full socket would
+const DATAGRAM_QUEUE: usize = 256;
+
+/// the biggest UDP payload
+const MAX_UDP_PAYLOAD: usize = 65535;
+
+#[derive(Clone, Debug)]
+pub struct MasqueOptions {
+    /// where clients may send UDP. empty allows anywhere
+    pub allow: Vec<IpNet>,
+    /// close a request that hasn't moved a datagram either way for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for MasqueOptions {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// an endpoint that speaks HTTP/3 instead of our own protocol
+pub fn build_masque_endpoint(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    listen: SocketAddr,
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+) -> Result<Endpoint, TunnelError> {
+    let (mut tls_config, _root_ca) = tls::build_server_config(ca, cert, key, tls_options)
+        .map_err(|err| TunnelError::Tls(err.into()))?;
+
+    tls_config.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(tls_config));
+
+    let mut transport_config = transport_config(transport)?;
+
+    // the client's control stream and its two QPACK streams
+    transport_config.max_concurrent_uni_streams(3u32.into());
+
+    server_config.transport_config(Arc::new(transport_config));
+    server_config.migration(transport.migration.unwrap_or(true));
+
+    Endpoint::server(server_config, listen).map_err(|source| TunnelError::Bind {
+        addr: listen,
+        source,
+    })
+}
+
+/// accept connections on `endpoint` until `shutdown` is cancelled
+pub async fn serve(
+    endpoint: Endpoint,
+    options: MasqueOptions,
+    resolver: Resolver,
+    counts: Arc<TunnelCounters>,
+    shutdown: CancellationToken,
+) {
+    let shared = Arc::new(Shared {
+        options,
+        resolver,
+        counts,
+    });
+
+    loop {
+        let conn = select! {
+            x = endpoint.accept() => x,
+            _ = shutdown.cancelled() => break,
+        };
+
+        let Some(conn) = conn else {
+            break;
+        };
+
+        let span = info_span!("masque", peer = %conn.remote_address());
+
+        let f = handle_connection(conn, shared.clone());
+
+        tokio::spawn(
+            async move {
+                if let Err(err) = f.await {
+                    debug!(?err, "connection closed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    options: MasqueOptions,
+    resolver: Resolver,
+    counts: Arc<TunnelCounters>,
+}
+
+type Settings = HashMap<u64, u64>;
+
+/// every request with an open UDP socket on one connection, by quarter stream id
+type Tunnels = Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>;
+
+async fn handle_connection(conn: Connecting, shared: Arc<Shared>) -> anyhow::Result<()> {
+    let conn = timeout(PREAMBLE_TIMEOUT, conn)
+        .await
+        .context("handshake timed out")??;
+
+    debug!("HTTP/3 client connected");
+
+    let control = h3::open_control(
+        &conn,
+        &[
+            (h3::SETTING_QPACK_MAX_TABLE_CAPACITY, 0),
+            (h3::SETTING_MAX_FIELD_SECTION_SIZE, h3::MAX_FRAME_LEN),
+            (h3::SETTING_ENABLE_CONNECT_PROTOCOL, 1),
+            (h3::SETTING_H3_DATAGRAM, 1),
+        ],
+    )
+    .await?;
+
+    let counts = shared
+        .counts
+        .connection(conn.stable_id() as u64)
+        .with_path(&conn);
+
+    let tunnels = Tunnels::default();
+
+    // the client's settings, once they arrive
+    let (settings_tx, settings_rx) = watch::channel(None);
+
+    let err = select! {
+        x = h3::accept_uni_streams(&conn, settings_tx) => x,
+        x = route_datagrams(&conn, &tunnels) => x,
+        x = accept_requests(&conn, &shared, &tunnels, settings_rx, &counts) => x,
+    };
+
+    drop(control);
+
+    match err {
+        Err(err) => {
+            if let Some(x) = err.downcast_ref::<H3Error>() {
+                conn.close(x.code().into(), x.to_string().as_bytes());
+            }
+
+            Err(err)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// hand each QUIC datagram to the request it is for
+async fn route_datagrams(conn: &Connection, tunnels: &Tunnels) -> anyhow::Result<()> {
+    loop {
+        let x = conn.read_datagram().await?;
+
+        let Some((quarter_id, n)) = get_varint(&x) else {
+            conn.close(
+                h3::H3_DATAGRAM_ERROR.into(),
+                b"datagram without a stream id",
+            );
+
+            anyhow::bail!("datagram without a stream id");
+        };
+
+        let tunnel = tunnels
+            .lock()
+            .expect("tunnels lock poisoned")
+            .get(&quarter_id)
+            .cloned();
+
+        match tunnel {
+            Some(tx) => {
+                if tx.try_send(x[n..].to_vec()).is_err() {
+                    trace!(quarter_id, "dropping datagram. the socket is behind");
+                }
+            }
+            // it might be for a request that just ended, or one we haven't read yet
+            None => trace!(quarter_id, "dropping datagram for an unknown request"),
+        }
+    }
+}
+
+async fn accept_requests(
+    conn: &Connection,
+    shared: &Arc<Shared>,
+    tunnels: &Tunnels,
+    settings: watch::Receiver<Option<Settings>>,
+    counts: &ScopedCounters,
+) -> anyhow::Result<()> {
+    loop {
+        let (tx, rx) = match conn.accept_bi().await {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        counts.stream_opened();
+        counts.rtt(conn.rtt());
+
+        let request = Request {
+            conn: conn.clone(),
+            shared: shared.clone(),
+            tunnels: tunnels.clone(),
+            settings: settings.clone(),
+            counts: counts.clone(),
+        };
+
+        let span = info_span!("request", stream_id = rx.id().index());
+
+        tokio::spawn(
+            async move {
+                if let Err(err) = request.handle(tx, rx).await {
+                    debug!(?err, "request failed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// a reason to turn a request away, as an HTTP status
+#[derive(Debug)]
+struct Refused {
+    status: &'static str,
+    reason: String,
+}
+
+fn refused(status: &'static str, reason: impl Into<String>) -> Refused {
+    Refused {
+        status,
+        reason: reason.into(),
+    }
+}
+
+/// where a CONNECT-UDP request wants to send to
+fn parse_target(fields: &[(String, String)]) -> Result<HostAddr, Refused> {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, x)| x.as_str())
+    };
+
+    if field(":method") != Some("CONNECT") {
+        return Err(refused("405", "only CONNECT-UDP is supported"));
+    }
+
+    if field(":protocol") != Some("connect-udp") {
+        return Err(refused("501", "only CONNECT-UDP is supported"));
+    }
+
+    if field(":scheme") != Some("https") || field(":authority").is_none() {
+        return Err(refused("400", "missing :scheme or :authority"));
+    }
+
+    let path = field(":path").unwrap_or_default();
+
+    let target = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .strip_prefix(PATH_PREFIX)
+        .ok_or_else(|| refused("404", format!("{path} is not a CONNECT-UDP path")))?;
+
+    let mut parts = target.split('/');
+
+    let (Some(host), Some(port), None | Some("")) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(refused("404", format!("{path} is not a CONNECT-UDP path")));
+    };
+
+    let bad_target = || refused("400", format!("{path} has a bad target"));
+
+    let host = percent_decode(host).ok_or_else(bad_target)?;
+    let port: u16 = port.parse().map_err(|_| bad_target())?;
+
+    if host.is_empty() || port == 0 {
+        return Err(bad_target());
+    }
+
+    Ok(match host.parse() {
+        Ok(ip) => HostAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => HostAddr::Name { host, port },
+    })
+}
+
+/// IPv6 targets have their colons encoded as %3A
+fn percent_decode(x: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(x.len());
+    let mut bytes = x.bytes();
+
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+
+        let hex = [bytes.next()?, bytes.next()?];
+
+        // from_str_radix would take a sign, like "%+1"
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+
+        out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+
+    String::from_utf8(out).ok()
+}
+
+struct Request {
+    conn: Connection,
+    shared: Arc<Shared>,
+    tunnels: Tunnels,
+    settings: watch::Receiver<Option<Settings>>,
+    counts: ScopedCounters,
+}
+
+/// stops routing datagrams to a request when it ends
+struct TunnelGuard {
+    tunnels: Tunnels,
+    quarter_id: u64,
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.tunnels
+            .lock()
+            .expect("tunnels lock poisoned")
+            .remove(&self.quarter_id);
+    }
+}
+
+impl Request {
+    async fn handle(mut self, mut tx: SendStream, mut rx: RecvStream) -> anyhow::Result<()> {
+        let mut pending = 0;
+
+        let fields = loop {
+            let frame = match timeout(PREAMBLE_TIMEOUT, Frame::read(&mut rx, &mut pending)).await {
+                Ok(x) => x,
+                Err(_) => anyhow::bail!("no request headers after {PREAMBLE_TIMEOUT:?}"),
+            };
+
+            match frame {
+                Ok(Some(x)) if x.ty == h3::FRAME_HEADERS => match h3::decode_fields(&x.payload) {
+                    Ok(x) => break x,
+                    Err(err) => return Err(reset(&mut tx, &mut rx, err)),
+                },
+                Ok(Some(x)) if x.ty == h3::FRAME_DATA => {
+                    return Err(reset(
+                        &mut tx,
+                        &mut rx,
+                        H3Error::Frame("DATA before HEADERS"),
+                    ))
+                }
+                // reserved and unknown frames
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(reset(&mut tx, &mut rx, err)),
+            }
+        };
+
+        trace!(?fields, "request");
+
+        let socket = match self.open(&fields).await {
+            Ok(x) => x,
+            Err(x) => {
+                info!(status = x.status, reason = x.reason, "refused request");
+
+                let frame = Frame::new(h3::FRAME_HEADERS, encode_fields(&[(":status", x.status)]));
+
+                tx.write_all(&frame.encode()).await?;
+                tx.finish().await?;
+
+                return Ok(());
+            }
+        };
+
+        let frame = Frame::new(
+            h3::FRAME_HEADERS,
+            encode_fields(&[(":status", "200"), ("capsule-protocol", "?1")]),
+        );
+
+        tx.write_all(&frame.encode()).await?;
+
+        // a client that hasn't sent its settings yet gets capsules until it does
+        let use_datagrams = {
+            let settled = self.settings.wait_for(|x| x.is_some());
+
+            match timeout(PREAMBLE_TIMEOUT, settled).await {
+                Ok(Ok(x)) => x
+                    .as_ref()
+                    .is_some_and(|x| x.get(&h3::SETTING_H3_DATAGRAM) == Some(&1)),
+                _ => false,
+            }
+        } && self.conn.max_datagram_size().is_some();
+
+        let quarter_id = VarInt::from(rx.id()).into_inner() / 4;
+
+        let (datagram_tx, datagram_rx) = flume::bounded(DATAGRAM_QUEUE);
+
+        self.tunnels
+            .lock()
+            .expect("tunnels lock poisoned")
+            .insert(quarter_id, datagram_tx);
+
+        let _guard = TunnelGuard {
+            tunnels: self.tunnels.clone(),
+            quarter_id,
+        };
+
+        info!(target = %socket.peer_addr()?, use_datagrams, "relaying UDP");
+
+        let x = self
+            .relay(
+                &socket,
+                tx,
+                rx,
+                pending,
+                datagram_rx,
+                quarter_id,
+                use_datagrams,
+            )
+            .await;
+
+        info!("request finished");
+
+        x
+    }
+
+    /// resolve the target and connect a socket to it
+    async fn open(&self, fields: &[(String, String)]) -> Result<UdpSocket, Refused> {
+        let target = parse_target(fields)?;
+
+        let addrs = self
+            .shared
+            .resolver
+            .resolve(&target)
+            .await
+            .map_err(|err| refused("502", format!("looking up {target}: {err}")))?;
+
+        let allow = &self.shared.options.allow;
+
+        let addr = addrs
+            .into_iter()
+            .find(|x| allow.is_empty() || allow.iter().any(|net| net.contains(&x.ip())))
+            .ok_or_else(|| refused("403", format!("{target} is not allowed")))?;
+
+        let connect = async {
+            let socket =
+                UdpSocket::bind(matching_bind_address(addr).map_err(io::Error::other)?).await?;
+
+            socket.connect(addr).await?;
+
+            io::Result::Ok(socket)
+        };
+
+        connect
+            .await
+            .map_err(|err| refused("502", format!("connecting to {addr}: {err}")))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn relay(
+        &self,
+        socket: &UdpSocket,
+        mut tx: SendStream,
+        mut rx: RecvStream,
+        mut pending: u64,
+        datagram_rx: Receiver<Vec<u8>>,
+        quarter_id: u64,
+        use_datagrams: bool,
+    ) -> anyhow::Result<()> {
+        let started = Instant::now();
+        // millis since `started`
+        let last_active = AtomicU64::new(0);
+        let touch = || last_active.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        // an HTTP datagram payload to the target
+        let forward = |payload: &[u8]| {
+            let (context, n) = get_varint(payload)?;
+
+            if context != CONTEXT_UDP {
+                trace!(context, "dropping datagram for an unknown context");
+                return None;
+            }
+
+            touch();
+
+            Some(payload[n..].to_vec())
+        };
+
+        // the client closing its side of the stream ends the request
+        let from_stream = async {
+            let mut capsules = CapsuleReader::default();
+
+            while let Some(x) = Frame::read(&mut rx, &mut pending).await? {
+                if x.ty != h3::FRAME_DATA {
+                    continue;
+                }
+
+                capsules.push(&x.payload);
+
+                while let Some((ty, payload)) = capsules.next_capsule()? {
+                    if ty != h3::CAPSULE_DATAGRAM {
+                        trace!(capsule_type = ty, "ignoring capsule");
+                        continue;
+                    }
+
+                    if let Some(x) = forward(&payload) {
+                        socket.send(&x).await?;
+
+                        self.counts.recv(x.len(), 0);
+                    }
+                }
+            }
+
+            anyhow::Ok(())
+        };
+
+        let from_datagrams = async {
+            while let Ok(payload) = datagram_rx.recv_async().await {
+                if let Some(x) = forward(&payload) {
+                    if let Err(err) = socket.send(&x).await {
+                        debug!(?err, "failed sending to target");
+                    }
+
+                    self.counts.recv(x.len(), 0);
+                }
+            }
+
+            anyhow::Ok(())
+        };
+
+        let to_client = async {
+            let mut buf = vec![0; MAX_UDP_PAYLOAD];
+
+            loop {
+                let n = match socket.recv(&mut buf).await {
+                    Ok(x) => x,
+                    // an icmp error from the target, like nothing listening on the port. it might come up later
+                    Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                        trace!(?err, "target refused");
+                        continue;
+                    }
+                    Err(err) => return Err(anyhow::Error::from(err)),
+                };
+
+                touch();
+
+                let mut payload = Vec::with_capacity(n + 16);
+
+                if use_datagrams {
+                    put_varint(&mut payload, quarter_id);
+                    put_varint(&mut payload, CONTEXT_UDP);
+                    payload.extend_from_slice(&buf[..n]);
+
+                    let max = self.conn.max_datagram_size().unwrap_or_default();
+
+                    if payload.len() > max {
+                        trace!(n, max, "dropping a UDP payload too big for a datagram");
+                        continue;
+                    }
+
+                    if let Err(err) = self.conn.send_datagram(payload.into()) {
+                        debug!(?err, "failed sending datagram");
+                    }
+                } else {
+                    put_varint(&mut payload, CONTEXT_UDP);
+                    payload.extend_from_slice(&buf[..n]);
+
+                    let capsule = encode_capsule(h3::CAPSULE_DATAGRAM, &payload);
+
+                    tx.write_all(&Frame::new(h3::FRAME_DATA, capsule).encode())
+                        .await?;
+                }
+
+                self.counts.sent(n, 0);
+            }
+        };
+
+        let idle_timeout = self.shared.options.idle_timeout;
+
+        let idle = async {
+            loop {
+                let active = Duration::from_millis(last_active.load(Ordering::Relaxed));
+                let idle_for = started.elapsed().saturating_sub(active);
+
+                if idle_for >= idle_timeout {
+                    return;
+                }
+
+                sleep(idle_timeout - idle_for).await;
+            }
+        };
+
+        let x = select! {
+            x = from_stream => x,
+            x = from_datagrams => x,
+            x = to_client => x,
+            _ = idle => {
+                debug!(?idle_timeout, "closing idle request");
+                Ok(())
+            }
+        };
+
+        if let Err(err) = &x {
+            if let Some(err) = err.downcast_ref::<H3Error>() {
+                let code = err.code();
+
+                let _ = rx.stop(code.into());
+                let _ = tx.reset(code.into());
+
+                return x;
+            }
+        }
+
+        // the client may have stopped reading already
+        let _ = tx.finish().await;
+
+        x
+    }
+}
+
+/// give up on a request stream the client broke
+fn reset(tx: &mut SendStream, rx: &mut RecvStream, err: H3Error) -> anyhow::Error {
+    warn!(?err, "bad request stream");
+
+    let code = err.code();
+
+    let _ = rx.stop(code.into());
+    let _ = tx.reset(code.into());
+
+    err.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Vec<(String, String)> {
+        [
+            (":method", "CONNECT"),
+            (":protocol", "connect-udp"),
+            (":scheme", "https"),
+            (":authority", "proxy.example.com"),
+            (":path", path),
+        ]
+        .into_iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn targets() {
+        let cases = [
+            (
+                "/.well-known/masque/udp/192.0.2.6/443/",
+                HostAddr::Ip("192.0.2.6:443".parse().unwrap()),
+            ),
+            (
+                "/.well-known/masque/udp/2001%3Adb8%3A%3A42/53/",
+                HostAddr::Ip("[2001:db8::42]:53".parse().unwrap()),
+            ),
+            (
+                "/.well-known/masque/udp/example.com/53",
+                HostAddr::Name {
+                    host: "example.com".to_string(),
+                    port: 53,
+                },
+            ),
+            (
+                "/.well-known/masque/udp/example.com/53/?x=1",
+                HostAddr::Name {
+                    host: "example.com".to_string(),
+                    port: 53,
+                },
+            ),
+        ];
+
+        for (path, x) in cases {
+            assert_eq!(parse_target(&request(path)).unwrap(), x, "{path}");
+        }
+    }
+
+    #[test]
+    fn bad_targets() {
+        let cases = [
+            ("/", "404"),
+            ("/.well-known/masque/udp/", "404"),
+            ("/.well-known/masque/udp/example.com/", "400"),
+            ("/.well-known/masque/udp/example.com/53/x", "404"),
+            ("/.well-known/masque/udp/example.com/0/", "400"),
+            ("/.well-known/masque/udp/example.com/65536/", "400"),
+            ("/.well-known/masque/udp//53/", "400"),
+            ("/.well-known/masque/udp/%3/53/", "400"),
+            ("/.well-known/masque/udp/%+1/53/", "400"),
+            ("/.well-known/masque/udp/%ff/53/", "400"),
+        ];
+
+        for (path, status) in cases {
+            assert_eq!(
+                parse_target(&request(path)).unwrap_err().status,
+                status,
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_requests() {
+        let mut x = request("/.well-known/masque/udp/192.0.2.6/443/");
+        x[0].1 = "GET".to_string();
+        assert_eq!(parse_target(&x).unwrap_err().status, "405");
+
+        let mut x = request("/.well-known/masque/udp/192.0.2.6/443/");
+        x[1].1 = "websocket".to_string();
+        assert_eq!(parse_target(&x).unwrap_err().status, "501");
+
+        let mut x = request("/.well-known/masque/udp/192.0.2.6/443/");
+        x.remove(3);
+        assert_eq!(parse_target(&x).unwrap_err().status, "400");
+    }
+}
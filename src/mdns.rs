@@ -0,0 +1,635 @@
+//! Finding quic-tunnel servers on the LAN with mDNS (RFC 6762) and DNS-SD (RFC 6763), for home labs where the server's
+//! address keeps changing.
+//!
+//! A server with `--advertise` answers queries for [`SERVICE`] with its QUIC port and the address the asker reaches it at,
+//! like a printer would. Its instance is named after its host and port, like `lab-8443`, so servers on one host don't
+//! clash. A client with `mdns:` as its server, or `--discover`, asks once, takes every answer that comes within
+//! [`BROWSE_TIME`], and tries them like a SRV record's targets. `mdns:name` only takes the instance called `name`.
+//!
+//! Only IPv4 is advertised. Anyone on the LAN can answer, but the server's cert is checked like always, so a fake answer
+//! only points the client at a server that fails the handshake.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, info, trace};
+
+use crate::datagram::{DatagramPeer, DatagramSocket};
+use crate::shutdown::CancellationToken;
+use crate::srv::{encode_name, truncated, Reader};
+
+/// the DNS-SD service type servers advertise
+pub const SERVICE: &str = "_quic-tunnel._udp.local";
+
+/// how long a client waits for servers to answer
+pub const BROWSE_TIME: Duration = Duration::from_secs(1);
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// on a question, that the answer can come straight back. on a record, that it replaces what caches had for the name
+const TOP_BIT: u16 = 0x8000;
+
+/// RFC 6762's TTL for records with a host name in them. answers to one-shot queries get at most 10 seconds
+const TTL: u32 = 120;
+const ONE_SHOT_TTL: u32 = 10;
+
+/// the addresses of the servers that answered. only `instance` if it is given
+pub async fn browse(instance: Option<&str>) -> io::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_ttl_v4(255)?;
+
+    let mut query = Vec::with_capacity(SERVICE.len() + 18);
+    // id, flags, one question, nothing else
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut query, SERVICE)?;
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&(CLASS_IN | TOP_BIT).to_be_bytes());
+
+    socket.send_to(&query, (GROUP, PORT)).await?;
+
+    let mut found = Found::default();
+    let mut buf = vec![0; 9000];
+
+    let deadline = Instant::now() + BROWSE_TIME;
+
+    while let Ok(x) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = x?;
+
+        if let Err(err) = found.read(&buf[..n], from.ip()) {
+            trace!(?err, %from, "ignoring a bad mDNS answer");
+        }
+    }
+
+    let addrs = found.addrs(instance);
+
+    debug!(?instance, ?addrs, "mDNS browse");
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no servers answered on mDNS",
+        ));
+    }
+
+    Ok(addrs)
+}
+
+/// the records from every answer so far
+#[derive(Debug, Default)]
+struct Found {
+    /// instance names, in the order they came
+    instances: Vec<String>,
+    /// instance to host and port
+    srv: HashMap<String, (String, u16)>,
+    /// host to address
+    a: HashMap<String, Ipv4Addr>,
+    /// where the answer with each host came from, for answers without an A record
+    sources: HashMap<String, IpAddr>,
+}
+
+impl Found {
+    fn read(&mut self, packet: &[u8], from: IpAddr) -> io::Result<()> {
+        let mut r = Reader { packet, pos: 0 };
+
+        r.skip(2)?;
+
+        // not a response
+        if r.u16()? & 0x8000 == 0 {
+            return Ok(());
+        }
+
+        let questions = r.u16()?;
+        let records = r.u16()? as usize + r.u16()? as usize + r.u16()? as usize;
+
+        for _ in 0..questions {
+            r.name()?;
+            r.skip(4)?;
+        }
+
+        for _ in 0..records {
+            let name = r.name()?.to_ascii_lowercase();
+            let ty = r.u16()?;
+            r.skip(6)?;
+            let len = r.u16()? as usize;
+
+            let end = r.pos + len;
+
+            if end > packet.len() {
+                return Err(truncated());
+            }
+
+            match ty {
+                TYPE_PTR if name == SERVICE => {
+                    let x = r.name()?.to_ascii_lowercase();
+
+                    if !self.instances.contains(&x) {
+                        self.instances.push(x);
+                    }
+                }
+                TYPE_SRV => {
+                    r.skip(4)?;
+                    let port = r.u16()?;
+                    let host = r.name()?.to_ascii_lowercase();
+
+                    self.sources.insert(host.clone(), from);
+                    self.srv.insert(name, (host, port));
+                }
+                TYPE_A if len == 4 => {
+                    let x = &packet[r.pos..end];
+
+                    self.a.insert(name, Ipv4Addr::new(x[0], x[1], x[2], x[3]));
+                }
+                _ => {}
+            }
+
+            r.pos = end;
+        }
+
+        Ok(())
+    }
+
+    fn addrs(&self, instance: Option<&str>) -> Vec<SocketAddr> {
+        let wanted = instance.map(|x| instance_name(x).to_ascii_lowercase());
+
+        let mut addrs = vec![];
+
+        for x in &self.instances {
+            if wanted.as_ref().is_some_and(|wanted| wanted != x) {
+                continue;
+            }
+
+            let Some((host, port)) = self.srv.get(x) else {
+                continue;
+            };
+
+            let ip = match self.a.get(host) {
+                Some(x) => IpAddr::V4(*x),
+                None => match self.sources.get(host) {
+                    Some(x) => *x,
+                    None => continue,
+                },
+            };
+
+            let x = SocketAddr::new(ip, *port);
+
+            if !addrs.contains(&x) {
+                addrs.push(x);
+            }
+        }
+
+        addrs
+    }
+}
+
+/// answer queries for [`SERVICE`] with `port` until `shutdown`. other mDNS responders, like avahi, keep working
+pub async fn advertise(port: u16, shutdown: CancellationToken) -> io::Result<()> {
+    let socket = DatagramSocket::bind_multicast(
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), PORT),
+        &[GROUP.into()],
+        None,
+    )?;
+
+    let host = host_name();
+    let instance = instance_name(&format!("{host}-{port}"));
+
+    info!(%instance, port, "advertising on mDNS");
+
+    let mut buf = vec![0; 9000];
+
+    loop {
+        tokio::select! {
+            x = socket.readable() => x?,
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+
+        let (n, from) = match socket.try_recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        };
+
+        let DatagramPeer::Udp(from) = from else {
+            continue;
+        };
+
+        let question = match asks_for_us(&buf[..n], &instance) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(err) => {
+                trace!(?err, %from, "ignoring a bad mDNS query");
+                continue;
+            }
+        };
+
+        let Some(ip) = local_ip_toward(from.ip()) else {
+            continue;
+        };
+
+        // a query from a port other than mDNS's is a one-shot one that wants its answer back, like ours. so does one
+        // with the top bit on its class. everyone else's answers go to the group, for every cache on the LAN
+        let one_shot = from.port() != PORT;
+        let to = if one_shot || question.unicast {
+            from
+        } else {
+            SocketAddr::new(GROUP.into(), PORT)
+        };
+
+        let answer = encode_answer(&question, one_shot, &instance, &host, port, ip)?;
+
+        debug!(%from, %to, %ip, "answering an mDNS query");
+
+        socket.send_to(&answer, &DatagramPeer::Udp(to)).await?;
+    }
+}
+
+/// the question we answer
+#[derive(Debug)]
+struct Question {
+    id: u16,
+    name: String,
+    ty: u16,
+    class: u16,
+    unicast: bool,
+}
+
+/// the first question in `packet` for [`SERVICE`] or our instance, if there is one
+fn asks_for_us(packet: &[u8], instance: &str) -> io::Result<Option<Question>> {
+    let mut r = Reader { packet, pos: 0 };
+
+    let id = r.u16()?;
+
+    // a response
+    if r.u16()? & 0x8000 != 0 {
+        return Ok(None);
+    }
+
+    let questions = r.u16()?;
+    r.skip(6)?;
+
+    for _ in 0..questions {
+        let name = r.name()?;
+        let ty = r.u16()?;
+        let class = r.u16()?;
+
+        let wanted = match ty {
+            TYPE_PTR => name.eq_ignore_ascii_case(SERVICE),
+            TYPE_SRV | TYPE_TXT => name.eq_ignore_ascii_case(instance),
+            TYPE_ANY => name.eq_ignore_ascii_case(SERVICE) || name.eq_ignore_ascii_case(instance),
+            _ => false,
+        };
+
+        if wanted && class & !TOP_BIT == CLASS_IN {
+            return Ok(Some(Question {
+                id,
+                name,
+                ty,
+                class,
+                unicast: class & TOP_BIT != 0,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// the PTR record that names our instance, and its SRV, TXT, and A records
+fn encode_answer(
+    question: &Question,
+    one_shot: bool,
+    instance: &str,
+    host: &str,
+    port: u16,
+    ip: Ipv4Addr,
+) -> io::Result<Vec<u8>> {
+    let mut x = Vec::with_capacity(256);
+
+    // one-shot answers repeat the query's id and question
+    let id = if one_shot { question.id } else { 0 };
+    let questions = one_shot as u16;
+
+    x.extend_from_slice(&id.to_be_bytes());
+    // an authoritative response
+    x.extend_from_slice(&0x8400u16.to_be_bytes());
+    x.extend_from_slice(&questions.to_be_bytes());
+    // the PTR, then the rest as additional records
+    x.extend_from_slice(&[0, 1, 0, 0, 0, 3]);
+
+    if one_shot {
+        encode_name(&mut x, &question.name)?;
+        x.extend_from_slice(&question.ty.to_be_bytes());
+        x.extend_from_slice(&question.class.to_be_bytes());
+    }
+
+    let ttl = if one_shot { ONE_SHOT_TTL } else { TTL };
+
+    // caches shouldn't be told to drop other answers for a name only we have, unless they are on the LAN
+    let unique = if one_shot {
+        CLASS_IN
+    } else {
+        CLASS_IN | TOP_BIT
+    };
+
+    let mut record = |name: &str, ty: u16, class: u16, data: &[u8]| -> io::Result<()> {
+        encode_name(&mut x, name)?;
+        x.extend_from_slice(&ty.to_be_bytes());
+        x.extend_from_slice(&class.to_be_bytes());
+        x.extend_from_slice(&ttl.to_be_bytes());
+        x.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        x.extend_from_slice(data);
+
+        Ok(())
+    };
+
+    let mut ptr = vec![];
+    encode_name(&mut ptr, instance)?;
+    record(SERVICE, TYPE_PTR, CLASS_IN, &ptr)?;
+
+    let target = format!("{host}.local");
+
+    // no priority or weight
+    let mut srv = vec![0, 0, 0, 0];
+    srv.extend_from_slice(&port.to_be_bytes());
+    encode_name(&mut srv, &target)?;
+    record(instance, TYPE_SRV, unique, &srv)?;
+
+    // DNS-SD needs a TXT record, even an empty one
+    record(instance, TYPE_TXT, unique, &[0])?;
+
+    record(&target, TYPE_A, unique, &ip.octets())?;
+
+    Ok(x)
+}
+
+/// `name` as a full instance name, like `lab._quic-tunnel._udp.local`
+fn instance_name(name: &str) -> String {
+    format!("{name}.{SERVICE}")
+}
+
+/// the first label of this host's name. "quic-tunnel" if it has none we can use
+fn host_name() -> String {
+    #[cfg(unix)]
+    let x = {
+        let mut buf = [0u8; 256];
+
+        // SAFETY: the buffer is as long as we say, and gethostname leaves it nul terminated when it fits
+        let ok = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0;
+
+        ok.then(|| {
+            let end = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+
+            String::from_utf8_lossy(&buf[..end]).into_owned()
+        })
+    };
+
+    #[cfg(not(unix))]
+    let x = std::env::var("COMPUTERNAME").ok();
+
+    x.and_then(|x| x.split('.').next().map(str::to_string))
+        .filter(|x| !x.is_empty() && x.len() <= 63)
+        .unwrap_or_else(|| "quic-tunnel".to_string())
+}
+
+/// the address of ours that `peer` reaches us at
+fn local_ip_toward(peer: IpAddr) -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((peer, PORT)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(x) => Some(x),
+        IpAddr::V6(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTANCE: &str = "lab-8443._quic-tunnel._udp.local";
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+
+    fn query(questions: &[(&str, u16, u16)]) -> Vec<u8> {
+        let mut x = vec![0x12, 0x34, 0, 0];
+        x.extend((questions.len() as u16).to_be_bytes());
+        x.extend([0; 6]);
+
+        for (name, ty, class) in questions {
+            encode_name(&mut x, name).unwrap();
+            x.extend(ty.to_be_bytes());
+            x.extend(class.to_be_bytes());
+        }
+
+        x
+    }
+
+    fn question(packet: &[u8]) -> Option<Question> {
+        asks_for_us(packet, INSTANCE).unwrap()
+    }
+
+    fn answer(one_shot: bool) -> Vec<u8> {
+        let x = question(&query(&[(SERVICE, TYPE_PTR, CLASS_IN)])).unwrap();
+
+        encode_answer(&x, one_shot, INSTANCE, "lab", 8443, IP).unwrap()
+    }
+
+    fn found(packet: &[u8], from: IpAddr) -> Found {
+        let mut x = Found::default();
+        x.read(packet, from).unwrap();
+        x
+    }
+
+    #[test]
+    fn round_trip() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 99));
+        let server = SocketAddr::new(IP.into(), 8443);
+
+        for one_shot in [false, true] {
+            let x = found(&answer(one_shot), from);
+
+            assert_eq!(x.addrs(None), [server], "{one_shot}");
+            assert_eq!(x.addrs(Some("lab-8443")), [server]);
+            assert_eq!(x.addrs(Some("LAB-8443")), [server]);
+            assert_eq!(x.addrs(Some("lab-8444")), []);
+        }
+
+        // two answers from the same server are one address
+        let mut x = found(&answer(false), from);
+        x.read(&answer(true), from).unwrap();
+        assert_eq!(x.addrs(None), [server]);
+    }
+
+    #[test]
+    fn answers_without_addresses() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 99));
+
+        // without its A record, the server is wherever the answer came from
+        let mut x = answer(false);
+        x[11] = 2;
+        assert_eq!(found(&x, from).addrs(None), [SocketAddr::new(from, 8443)]);
+
+        // and without its SRV record, there is nothing to connect to
+        x[11] = 0;
+        assert_eq!(found(&x, from).addrs(None), []);
+    }
+
+    #[test]
+    fn answer_headers() {
+        let x = answer(true);
+
+        // the query's id and question come back
+        assert_eq!(&x[..6], [0x12, 0x34, 0x84, 0, 0, 1]);
+        assert_eq!(
+            Reader {
+                packet: &x,
+                pos: 12
+            }
+            .name()
+            .unwrap(),
+            SERVICE
+        );
+
+        let x = answer(false);
+        assert_eq!(&x[..6], [0, 0, 0x84, 0, 0, 0]);
+    }
+
+    /// each record's type, class, and ttl
+    fn records(packet: &[u8]) -> Vec<(u16, u16, u32)> {
+        let mut r = Reader { packet, pos: 4 };
+
+        let questions = r.u16().unwrap();
+        r.skip(6).unwrap();
+
+        for _ in 0..questions {
+            r.name().unwrap();
+            r.skip(4).unwrap();
+        }
+
+        let mut x = vec![];
+
+        while r.pos < packet.len() {
+            r.name().unwrap();
+            let ty = r.u16().unwrap();
+            let class = r.u16().unwrap();
+            let ttl = (r.u16().unwrap() as u32) << 16 | r.u16().unwrap() as u32;
+            let len = r.u16().unwrap() as usize;
+            r.skip(len).unwrap();
+
+            x.push((ty, class, ttl));
+        }
+
+        x
+    }
+
+    #[test]
+    fn cache_flush_bits() {
+        // the PTR is shared with other servers, and only the LAN's caches are told to drop the rest
+        let flush = CLASS_IN | TOP_BIT;
+
+        assert_eq!(
+            records(&answer(false)),
+            [
+                (TYPE_PTR, CLASS_IN, TTL),
+                (TYPE_SRV, flush, TTL),
+                (TYPE_TXT, flush, TTL),
+                (TYPE_A, flush, TTL),
+            ]
+        );
+        assert_eq!(
+            records(&answer(true)),
+            [
+                (TYPE_PTR, CLASS_IN, ONE_SHOT_TTL),
+                (TYPE_SRV, CLASS_IN, ONE_SHOT_TTL),
+                (TYPE_TXT, CLASS_IN, ONE_SHOT_TTL),
+                (TYPE_A, CLASS_IN, ONE_SHOT_TTL),
+            ]
+        );
+    }
+
+    #[test]
+    fn questions() {
+        let x = question(&query(&[(SERVICE, TYPE_PTR, CLASS_IN)])).unwrap();
+        assert_eq!((x.id, x.ty, x.unicast), (0x1234, TYPE_PTR, false));
+
+        // the top bit asks for the answer to come straight back
+        let x = question(&query(&[(SERVICE, TYPE_PTR, CLASS_IN | TOP_BIT)])).unwrap();
+        assert!(x.unicast);
+        assert_eq!(x.class, CLASS_IN | TOP_BIT);
+
+        let x = question(&query(&[("_quic-tunnel._UDP.local", TYPE_PTR, CLASS_IN)])).unwrap();
+        assert_eq!(x.name, "_quic-tunnel._UDP.local");
+
+        for ty in [TYPE_SRV, TYPE_TXT, TYPE_ANY] {
+            let x = question(&query(&[(INSTANCE, ty, CLASS_IN)])).unwrap();
+            assert_eq!(x.ty, ty);
+        }
+
+        let x = question(&query(&[
+            ("_http._tcp.local", TYPE_PTR, CLASS_IN),
+            (SERVICE, TYPE_ANY, CLASS_IN),
+        ]))
+        .unwrap();
+        assert_eq!(x.ty, TYPE_ANY);
+    }
+
+    #[test]
+    fn not_for_us() {
+        let cases = [
+            query(&[("_http._tcp.local", TYPE_PTR, CLASS_IN)]),
+            query(&[("other-8443._quic-tunnel._udp.local", TYPE_SRV, CLASS_IN)]),
+            // a PTR is only for the service, and an A record isn't something we answer
+            query(&[(INSTANCE, TYPE_PTR, CLASS_IN)]),
+            query(&[(INSTANCE, TYPE_A, CLASS_IN)]),
+            // chaos class
+            query(&[(SERVICE, TYPE_PTR, 3)]),
+            query(&[]),
+            answer(false),
+        ];
+
+        for x in cases {
+            assert!(question(&x).is_none(), "{x:?}");
+        }
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 99));
+
+        let x = answer(true);
+        for n in 0..x.len() {
+            assert!(Found::default().read(&x[..n], from).is_err(), "{n}");
+        }
+
+        let x = query(&[(SERVICE, TYPE_PTR, CLASS_IN)]);
+        for n in 0..x.len() {
+            assert!(asks_for_us(&x[..n], INSTANCE).is_err(), "{n}");
+        }
+
+        // a name that points back at itself
+        let mut x = query(&[]);
+        x[5] = 1;
+        x.extend([1, b'a', 0xc0, 12, 0, 12, 0, 1]);
+        assert!(asks_for_us(&x, INSTANCE).is_err());
+
+        // a record longer than the packet
+        let mut x = answer(false);
+        let len = x.len();
+        x[len - 6..len - 4].copy_from_slice(&100u16.to_be_bytes());
+        assert!(Found::default().read(&x, from).is_err());
+
+        // a response that isn't one
+        let mut x = answer(false);
+        x[2] = 0;
+        assert_eq!(found(&x, from).addrs(None), []);
+    }
+}
@@ -0,0 +1,118 @@
+//! Keeping a client's connection when the network under it changes, like moving from wifi to LTE.
+//!
+//! A server follows a client to its new address on its own unless `TransportOptions::migration` is off, and a NAT
+//! rebinding looks the same to it. What the client has to notice is that the address it would send from has changed, since its socket
+//! can stay stuck on an interface that is gone. Every `check_interval`, it asks the kernel which local address reaches the server.
+//! When that changes, the endpoint moves to a new socket, and its connections and their streams move with it.
+
+use std::convert::Infallible;
+use std::future::pending;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use quinn::Endpoint;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use crate::quic::matching_bind_address;
+
+/// How a client notices that its network changed. Servers ignore these.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MigrationOptions {
+    /// how often to check which local address reaches the server. `None` never checks
+    #[serde(with = "humantime_serde")]
+    pub check_interval: Option<Duration>,
+    /// move to a new local port this often, even if nothing changed. for testing that connections survive it
+    #[serde(with = "humantime_serde")]
+    pub test_rebind_interval: Option<Duration>,
+}
+
+impl Default for MigrationOptions {
+    fn default() -> Self {
+        Self {
+            check_interval: Some(Duration::from_secs(5)),
+            test_rebind_interval: None,
+        }
+    }
+}
+
+/// move `endpoint` to a new socket whenever the local address that reaches `server` changes. this never returns, so race it with the connection
+pub async fn follow_network(
+    endpoint: &Endpoint,
+    server: SocketAddr,
+    options: &MigrationOptions,
+) -> Infallible {
+    let mut check = options.check_interval.map(delayed_interval);
+    let mut test_rebind = options.test_rebind_interval.map(delayed_interval);
+
+    let mut source = route_source(server);
+
+    loop {
+        select! {
+            _ = tick(&mut check) => {
+                let x = route_source(server);
+
+                if x == source {
+                    continue;
+                }
+
+                info!(old = ?source, new = ?x, "the local address changed. moving the connection");
+
+                source = x;
+
+                // there is no route at all. a socket can't fix that, so wait for one
+                if x.is_none() {
+                    continue;
+                }
+            }
+            _ = tick(&mut test_rebind) => debug!("moving to a new local port for testing"),
+        }
+
+        match rebind(endpoint, server) {
+            Ok(x) => info!(local_addr = %x, "moved to a new local socket"),
+            Err(err) => warn!(?err, "failed moving to a new local socket"),
+        }
+    }
+}
+
+/// like `interval`, but the first tick is a period from now instead of right away
+fn delayed_interval(period: Duration) -> Interval {
+    let mut x = interval_at(Instant::now() + period, period);
+
+    x.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    x
+}
+
+async fn tick(x: &mut Option<Interval>) {
+    match x {
+        Some(x) => {
+            x.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// connecting a UDP socket only looks up the route, so nothing is sent
+fn route_source(server: SocketAddr) -> Option<IpAddr> {
+    let socket = std::net::UdpSocket::bind(matching_bind_address(server).ok()?).ok()?;
+
+    socket.connect(server).ok()?;
+
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn rebind(endpoint: &Endpoint, server: SocketAddr) -> io::Result<SocketAddr> {
+    let socket =
+        std::net::UdpSocket::bind(matching_bind_address(server).map_err(io::Error::other)?)?;
+
+    let addr = socket.local_addr()?;
+
+    endpoint.rebind(socket)?;
+
+    Ok(addr)
+}
@@ -0,0 +1,147 @@
+//! Experimental. Using more than one network at once, for clients with more than one interface, like wired and LTE.
+//!
+//! quinn can't send one connection over several paths, so this works at the level of streams instead. The client opens its own
+//! connection over each path in `MultipathOptions::via`, and the server treats them like any other connections from that client.
+//! With `aggregate`, they all carry streams. A server hands each new stream to whichever connection asks for one first, so the faster
+//! paths take more of them, but a single stream never goes faster than its path. With `standby`, only one path carries streams,
+//! and when its connection is lost, the next one takes over without waiting for the lost one to come back.
+//!
+//! Each path keeps the local address or interface it was given, so connections on them don't follow network changes.
+//! This needs quic-tunnel to be built with the `multipath` feature.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use strum::EnumString;
+
+/// how streams are spread across paths
+#[derive(Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive, serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MultipathPolicy {
+    /// every path carries streams, for more throughput
+    #[default]
+    Aggregate,
+    /// only the first path that works carries streams. the others are for when it is lost
+    Standby,
+}
+
+/// a path to send over
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LocalPath {
+    /// send from this local address. the kernel's routes still pick the interface, so this may need source routing
+    Addr(IpAddr),
+    /// send out of this interface, like eth0. only works on linux, and needs CAP_NET_RAW
+    Device(String),
+}
+
+impl FromStr for LocalPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(x) = s.parse() {
+            return Ok(Self::Addr(x));
+        }
+
+        if s.is_empty() || s.contains([':', '/', ' ']) {
+            return Err(format!(
+                "\"{s}\" is not a local address like 192.168.1.2 or an interface like eth0"
+            ));
+        }
+
+        Ok(Self::Device(s.to_string()))
+    }
+}
+
+impl Display for LocalPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Addr(x) => write!(f, "{x}"),
+            Self::Device(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+/// serialized like it is displayed, "eth0"
+impl Serialize for LocalPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LocalPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Which paths a client connects over. Empty `via` is the default, one connection over whatever the routes pick.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MultipathOptions {
+    pub policy: MultipathPolicy,
+    pub via: Vec<LocalPath>,
+}
+
+impl MultipathOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.via.is_empty()
+    }
+}
+
+/// a socket that sends over `path`
+#[cfg(feature = "multipath")]
+pub fn bind(path: &LocalPath) -> io::Result<std::net::UdpSocket> {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    // TODO: interfaces only get IPv4, like the client's default endpoint
+    let addr = match path {
+        LocalPath::Addr(x) => return std::net::UdpSocket::bind(SocketAddr::new(*x, 0)),
+        LocalPath::Device(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+    };
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    bind_device(&socket, path)?;
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+#[cfg(not(feature = "multipath"))]
+pub fn bind(_path: &LocalPath) -> io::Result<std::net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "multipath needs quic-tunnel to be built with the multipath feature",
+    ))
+}
+
+#[cfg(all(feature = "multipath", any(target_os = "linux", target_os = "android")))]
+fn bind_device(socket: &socket2::Socket, path: &LocalPath) -> io::Result<()> {
+    match path {
+        LocalPath::Device(x) => socket.bind_device(Some(x.as_bytes())),
+        LocalPath::Addr(_) => Ok(()),
+    }
+}
+
+#[cfg(all(
+    feature = "multipath",
+    not(any(target_os = "linux", target_os = "android"))
+))]
+fn bind_device(_socket: &socket2::Socket, path: &LocalPath) -> io::Result<()> {
+    match path {
+        LocalPath::Device(x) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("sending out of interface {x} only works on linux. use its address instead"),
+        )),
+        LocalPath::Addr(_) => Ok(()),
+    }
+}
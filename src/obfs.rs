@@ -0,0 +1,297 @@
+//! Shims between quinn and the UDP socket, for networks that throttle or block traffic that looks like QUIC.
+//!
+//! Every packet quinn sends goes through `Transport::encode` on the way out, and every datagram read goes through
+//! `Transport::decode` on the way in. QUIC is already encrypted, so a shim only has to hide what a middlebox matches on,
+//! like the fixed header bits, the version in long headers, and the packet sizes. Both sides need the same shim.
+//!
+//! The shim is put in by a quinn `Runtime` that wraps every socket it is given, so a socket from a migration or a multipath
+//! path gets it too. Shimmed sockets send and read one datagram at a time, without segmentation offload or ECN.
+//!
+//! `Null` changes nothing and is only useful for measuring the cost of the shim itself. `XorPad` is a simple example.
+//! Library users can bring their own with `Obfuscation::Custom`.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::{AsyncTimer, AsyncUdpSocket, Runtime, TokioRuntime};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tokio::io::ReadBuf;
+use tracing::{debug, trace};
+
+use crate::error::TunnelError;
+
+/// padding `XorPad` adds when `max_pad` isn't given
+pub const DEFAULT_MAX_PAD: u8 = 32;
+
+/// What packets look like on the wire.
+pub trait Transport: Debug + Send + Sync + 'static {
+    /// append what to send for `packet` to `out`
+    fn encode(&self, packet: &[u8], out: &mut Vec<u8>);
+
+    /// turn a datagram from the wire back into a packet in place, and return how long the packet is. `None` drops the datagram
+    fn decode(&self, datagram: &mut [u8]) -> Option<usize>;
+}
+
+/// Which shim to use. Both sides need the same one.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Obfuscation {
+    /// plain QUIC on the socket
+    #[default]
+    Off,
+    Null,
+    /// see `XorPad`
+    XorPad {
+        key: String,
+        #[serde(default = "default_max_pad")]
+        max_pad: u8,
+    },
+    /// a shim from a library user
+    #[serde(skip)]
+    Custom(Arc<dyn Transport>),
+}
+
+fn default_max_pad() -> u8 {
+    DEFAULT_MAX_PAD
+}
+
+impl PartialEq for Obfuscation {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Off, Self::Off) | (Self::Null, Self::Null) => true,
+            (
+                Self::XorPad { key, max_pad },
+                Self::XorPad {
+                    key: other_key,
+                    max_pad: other_max_pad,
+                },
+            ) => key == other_key && max_pad == other_max_pad,
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Obfuscation {
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, Self::Off)
+    }
+
+    pub fn transport(&self) -> Result<Option<Arc<dyn Transport>>, TunnelError> {
+        let x: Arc<dyn Transport> = match self {
+            Self::Off => return Ok(None),
+            Self::Null => Arc::new(Null),
+            Self::XorPad { key, max_pad } => Arc::new(XorPad::new(key.as_bytes(), *max_pad)?),
+            Self::Custom(x) => x.clone(),
+        };
+
+        Ok(Some(x))
+    }
+
+    /// a quinn runtime that puts the shim around every socket. `None` if there is no shim
+    pub fn runtime(&self) -> Result<Option<Arc<dyn Runtime>>, TunnelError> {
+        Ok(self
+            .transport()?
+            .map(|transport| Arc::new(ObfsRuntime { transport }) as Arc<dyn Runtime>))
+    }
+}
+
+/// Sends packets as they are.
+#[derive(Debug)]
+pub struct Null;
+
+impl Transport for Null {
+    fn encode(&self, packet: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(packet);
+    }
+
+    fn decode(&self, datagram: &mut [u8]) -> Option<usize> {
+        Some(datagram.len())
+    }
+}
+
+/// Puts a random amount of random padding after each packet, with its length in front, and XORs all of it with a repeating key.
+///
+/// This is an example and not encryption. It hides QUIC's header bits and packet sizes from a simple filter, but anyone who
+/// looks at a few packets can find the key. Padding makes packets bigger than quinn thinks they are, so keep `max_pad` small.
+#[derive(Debug)]
+pub struct XorPad {
+    key: Vec<u8>,
+    max_pad: u8,
+    rng: SystemRandom,
+}
+
+impl XorPad {
+    pub fn new(key: &[u8], max_pad: u8) -> Result<Self, TunnelError> {
+        if key.is_empty() {
+            return Err(TunnelError::Config("the xor_pad key is empty".to_string()));
+        }
+
+        Ok(Self {
+            key: key.to_vec(),
+            max_pad,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn xor(&self, x: &mut [u8]) {
+        for (x, k) in x.iter_mut().zip(self.key.iter().cycle()) {
+            *x ^= k;
+        }
+    }
+}
+
+impl Transport for XorPad {
+    fn encode(&self, packet: &[u8], out: &mut Vec<u8>) {
+        let mut pad = [0; 1];
+
+        // without randomness, no padding still works
+        let _ = self.rng.fill(&mut pad);
+
+        let pad = match self.max_pad {
+            0 => 0,
+            max => (u16::from(pad[0]) % (u16::from(max) + 1)) as u8,
+        };
+
+        let start = out.len();
+
+        out.push(pad);
+        out.extend_from_slice(packet);
+        out.resize(out.len() + pad as usize, 0);
+
+        let pad_start = out.len() - pad as usize;
+        let _ = self.rng.fill(&mut out[pad_start..]);
+
+        self.xor(&mut out[start..]);
+    }
+
+    fn decode(&self, datagram: &mut [u8]) -> Option<usize> {
+        self.xor(datagram);
+
+        let (&pad, _) = datagram.split_first()?;
+
+        let len = datagram.len().checked_sub(1 + pad as usize)?;
+
+        datagram.copy_within(1..1 + len, 0);
+
+        Some(len)
+    }
+}
+
+/// tokio, with every UDP socket wrapped in an `ObfsSocket`
+#[derive(Debug)]
+struct ObfsRuntime {
+    transport: Arc<dyn Transport>,
+}
+
+impl Runtime for ObfsRuntime {
+    fn new_timer(&self, i: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        TokioRuntime.new_timer(i)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        TokioRuntime.spawn(future)
+    }
+
+    fn wrap_udp_socket(&self, t: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        t.set_nonblocking(true)?;
+
+        Ok(Box::new(ObfsSocket {
+            io: tokio::net::UdpSocket::from_std(t)?,
+            transport: self.transport.clone(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct ObfsSocket {
+    io: tokio::net::UdpSocket,
+    transport: Arc<dyn Transport>,
+}
+
+impl AsyncUdpSocket for ObfsSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = Vec::new();
+
+        for (i, x) in transmits.iter().enumerate() {
+            // segmentation offload is off for shimmed endpoints, but one transmit could still be several packets
+            for packet in x
+                .contents
+                .chunks(x.segment_size.unwrap_or(x.contents.len()).max(1))
+            {
+                buf.clear();
+                self.transport.encode(packet, &mut buf);
+
+                match self.io.poll_send_to(cx, &buf, x.destination) {
+                    Poll::Ready(Ok(_)) => {}
+                    // like quinn-udp, a packet that can't be sent is lost instead of stopping the endpoint
+                    Poll::Ready(Err(err)) => {
+                        debug!(?err, destination = %x.destination, "unable to send a packet");
+                    }
+                    // packets of this transmit that went out already are sent again. QUIC ignores the copies
+                    Poll::Pending if i == 0 => return Poll::Pending,
+                    Poll::Pending => return Poll::Ready(Ok(i)),
+                }
+            }
+        }
+
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut n = 0;
+
+        while n < bufs.len().min(meta.len()) {
+            let mut read = ReadBuf::new(&mut bufs[n]);
+
+            let addr = match self.io.poll_recv_from(cx, &mut read) {
+                Poll::Ready(Ok(addr)) => addr,
+                Poll::Ready(Err(err)) if n == 0 => return Poll::Ready(Err(err)),
+                Poll::Pending if n == 0 => return Poll::Pending,
+                // the next call gets the error again
+                _ => break,
+            };
+
+            let len = read.filled().len();
+
+            let Some(len) = self.transport.decode(&mut bufs[n][..len]) else {
+                trace!(%addr, "dropping a datagram the shim can't decode");
+                continue;
+            };
+
+            meta[n] = RecvMeta {
+                addr,
+                len,
+                stride: len,
+                ecn: None,
+                dst_ip: None,
+            };
+
+            n += 1;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
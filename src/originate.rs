@@ -0,0 +1,136 @@
+//! Speaking TLS to a reverse proxy client's backends, for internal services that only take TLS.
+//!
+//! [`TlsOriginator`] is a stream transformer for the client's side of a stream. It does the handshake with a tcp backend,
+//! then the plaintext from the tunnel goes through it. Users don't see any of this. They talk to the server's listener like
+//! before.
+//!
+//! The backend's cert is checked against the system's CAs, or only against `ca` when that is set, which is what a service
+//! with an internal CA needs. The name checked is `server_name`, or else the backend's host, so an IP address backend
+//! needs a cert for that IP or a `server_name`. Unix, named pipe, and vsock backends are left alone.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::certs::certs_from_pem;
+use crate::resolve::HostAddr;
+use crate::terminate::over_tls;
+use crate::tls::build_key_log;
+use crate::transform::{BoxedRead, BoxedWrite, StreamTransformer, TransformContext};
+
+/// How the client checks its backends' certs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendTlsOptions {
+    /// the name to send as SNI and check the cert for. the backend's host by default
+    pub server_name: Option<String>,
+    /// a PEM file of CAs to trust instead of the system's
+    pub ca: Option<PathBuf>,
+}
+
+/// Do TLS with a backend and pass the tunnel's plaintext through it.
+#[derive(Clone)]
+pub struct TlsOriginator {
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl std::fmt::Debug for TlsOriginator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsOriginator")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl TlsOriginator {
+    /// `keylog` is like `TlsOptions::keylog`
+    pub fn new(options: &BackendTlsOptions, keylog: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+
+        match &options.ca {
+            Some(path) => {
+                let certs = certs_from_pem(path.clone())
+                    .with_context(|| format!("loading the backend CA {}", path.display()))?;
+
+                for x in certs {
+                    roots.add(&x)?;
+                }
+            }
+            None => {
+                // a cert the system has but rustls can't parse shouldn't stop the others from working
+                for x in rustls_native_certs::load_native_certs()
+                    .context("loading the system's CA certificates")?
+                {
+                    let _ = roots.add(&rustls::Certificate(x.0));
+                }
+            }
+        }
+
+        if let Some(x) = &options.server_name {
+            ServerName::try_from(x.as_str())
+                .with_context(|| format!("{x} can't be a TLS server name"))?;
+        }
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        config.key_log = build_key_log(keylog)?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: options.server_name.clone(),
+        })
+    }
+
+    /// the originator for a backend at `addr`. its host is the name unless one was given
+    pub fn for_backend(&self, addr: &HostAddr) -> Self {
+        let server_name = self.server_name.clone().unwrap_or_else(|| match addr {
+            HostAddr::Ip(x) => x.ip().to_string(),
+            HostAddr::Name { host, .. } => host.clone(),
+        });
+
+        Self {
+            config: self.config.clone(),
+            server_name: Some(server_name),
+        }
+    }
+}
+
+impl StreamTransformer for TlsOriginator {
+    fn transform<'a>(
+        &'a self,
+        _ctx: &'a TransformContext,
+        read: BoxedRead,
+        write: BoxedWrite,
+    ) -> BoxFuture<'a, anyhow::Result<(BoxedRead, BoxedWrite)>> {
+        Box::pin(async move {
+            let name = self
+                .server_name
+                .as_deref()
+                .context("no server name for the backend")?;
+
+            let server_name = ServerName::try_from(name)
+                .with_context(|| format!("{name} can't be a TLS server name"))?;
+
+            let conn = ClientConnection::new(self.config.clone(), server_name)?;
+
+            match over_tls(conn.into(), read, write).await {
+                Ok(x) => Ok(x),
+                Err(err) => {
+                    // usually a cert or name that doesn't match, which won't fix itself
+                    warn!(?err, server_name = name, "TLS with the backend failed");
+
+                    Err(err)
+                }
+            }
+        })
+    }
+}
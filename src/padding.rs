@@ -0,0 +1,106 @@
+//! Padding and pacing tunneled streams, so someone watching the tunnel learns less from how big streams are and when they send.
+//!
+//! Padded streams use the compressed frame format even if compression is off, with padding frames the other side throws away.
+//! The server picks the mode and sends it in each stream's preamble. Both sides pad what they send with it.
+//!
+//! Padding costs bandwidth. The bytes are counted apart from the data as `padding_bytes_sent` and `padding_bytes_recv`.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use strum::EnumString;
+
+/// what a `ConstantRate` stream sends each tick, whether or not there is data
+pub const CELL_LEN: usize = 1024;
+
+/// bytes per second for each direction of a `ConstantRate` stream when none is given
+pub const DEFAULT_RATE: u64 = 64 * 1024;
+
+/// `Bucketed` rounds up to at least this much
+const MIN_BUCKET_LEN: usize = 512;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, Eq, PartialEq, Serialize)]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
+pub enum PaddingMode {
+    #[default]
+    Off,
+    /// pad each write out to the next power of two, from 512 bytes. hides sizes but not timing
+    Bucketed,
+    /// send one `CELL_LEN` cell every tick, padding when there is little or no data. hides sizes and timing,
+    /// but every open stream costs `rate` in each direction and can't go faster than it
+    ConstantRate,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaddingOptions {
+    pub mode: PaddingMode,
+    /// bytes per second for `ConstantRate`. each side uses its own
+    pub rate: u64,
+}
+
+impl Default for PaddingOptions {
+    fn default() -> Self {
+        Self {
+            mode: PaddingMode::Off,
+            rate: DEFAULT_RATE,
+        }
+    }
+}
+
+impl PaddingOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.mode != PaddingMode::Off
+    }
+
+    /// how often a `ConstantRate` stream sends a cell
+    pub fn cell_interval(&self) -> Duration {
+        Duration::from_secs_f64(CELL_LEN as f64 / self.rate.max(1) as f64)
+    }
+}
+
+/// how much padding `Bucketed` adds after `len` bytes. never less than `min_frame`, since a padding frame needs room for its header
+pub fn bucket_padding(len: usize, min_frame: usize) -> usize {
+    let mut bucket = len.max(MIN_BUCKET_LEN).next_power_of_two();
+
+    if bucket > len && bucket - len < min_frame {
+        bucket *= 2;
+    }
+
+    bucket - len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// what a compressed frame's header takes
+    const FRAME_HEADER_LEN: usize = 4;
+
+    #[test]
+    fn buckets() {
+        let cases = [
+            // exactly a power of two needs nothing
+            (512, 0),
+            (1024, 0),
+            (16384, 0),
+            // small writes still fill the smallest bucket
+            (0, 512),
+            (1, 511),
+            (100, 412),
+            // a padding frame needs room for its header, so 1-3 bytes left over go to the next bucket
+            (511, 513),
+            (509, 515),
+            (1023, 1025),
+            (1021, 1027),
+            (508, 4),
+            (1020, 4),
+            (513, 511),
+        ];
+
+        for (len, x) in cases {
+            assert_eq!(bucket_padding(len, FRAME_HEADER_LEN), x, "{len}");
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Windows named pipes like `\\.\pipe\docker_engine`, so Windows services can be tunneled the same way Unix sockets are.
+//!
+//! The types exist everywhere so the rest of the code doesn't need cfgs, but binding or connecting only works on Windows.
+
+#[cfg(windows)]
+pub use windows::{connect, NamedPipe, PipeListener};
+
+#[cfg(not(windows))]
+pub use other::{connect, NamedPipe, PipeListener};
+
+#[cfg(windows)]
+mod windows {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::net::windows::named_pipe::{
+        ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+    };
+    use tokio::sync::Mutex;
+    use tokio::time::sleep;
+
+    /// every instance of the pipe is connected to someone else
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    /// a connected pipe. the server side for listeners and the client side for backends
+    #[derive(Debug)]
+    pub enum NamedPipe {
+        Server(NamedPipeServer),
+        Client(NamedPipeClient),
+    }
+
+    impl AsyncRead for NamedPipe {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                Self::Server(x) => Pin::new(x).poll_read(cx, buf),
+                Self::Client(x) => Pin::new(x).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl AsyncWrite for NamedPipe {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.get_mut() {
+                Self::Server(x) => Pin::new(x).poll_write(cx, buf),
+                Self::Client(x) => Pin::new(x).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                Self::Server(x) => Pin::new(x).poll_flush(cx),
+                Self::Client(x) => Pin::new(x).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                Self::Server(x) => Pin::new(x).poll_shutdown(cx),
+                Self::Client(x) => Pin::new(x).poll_shutdown(cx),
+            }
+        }
+    }
+
+    /// named pipes don't have a listening socket. each user connects to their own instance of the pipe, so there is always one waiting
+    #[derive(Debug)]
+    pub struct PipeListener {
+        name: String,
+        next: Mutex<NamedPipeServer>,
+    }
+
+    impl PipeListener {
+        /// fails if another process already has a pipe with this name
+        pub fn bind(name: &str) -> io::Result<Self> {
+            let next = ServerOptions::new()
+                .first_pipe_instance(true)
+                .create(name)?;
+
+            Ok(Self {
+                name: name.to_string(),
+                next: Mutex::new(next),
+            })
+        }
+
+        pub async fn accept(&self) -> io::Result<NamedPipe> {
+            let mut next = self.next.lock().await;
+
+            next.connect().await?;
+
+            // the next user gets "file not found" if there isn't a new instance waiting for them
+            let waiting = ServerOptions::new().create(&self.name)?;
+
+            Ok(NamedPipe::Server(std::mem::replace(&mut *next, waiting)))
+        }
+    }
+
+    pub async fn connect(name: &str) -> io::Result<NamedPipe> {
+        // a busy pipe frees up as soon as the server makes a new instance
+        for _ in 0..20 {
+            match ClientOptions::new().open(name) {
+                Ok(x) => return Ok(NamedPipe::Client(x)),
+                Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                    sleep(Duration::from_millis(50)).await
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "named pipe stayed busy",
+        ))
+    }
+}
+
+#[cfg(not(windows))]
+mod other {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "named pipes only exist on windows",
+        )
+    }
+
+    /// can't be made outside of windows
+    #[derive(Debug)]
+    pub enum NamedPipe {}
+
+    impl AsyncRead for NamedPipe {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+
+    impl AsyncWrite for NamedPipe {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+
+    /// can't be made outside of windows
+    #[derive(Debug)]
+    pub enum PipeListener {}
+
+    impl PipeListener {
+        pub fn bind(_name: &str) -> io::Result<Self> {
+            Err(unsupported())
+        }
+
+        pub async fn accept(&self) -> io::Result<NamedPipe> {
+            match *self {}
+        }
+    }
+
+    pub async fn connect(_name: &str) -> io::Result<NamedPipe> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,82 @@
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+use tokio::task::JoinHandle;
+use tracing::trace;
+
+/// Keep a few QUIC streams opened ahead of time so a new user doesn't have to wait on `open_bi`.
+///
+/// Opening a stream doesn't send anything to the peer until it is written to, so idle streams are cheap.
+/// They do count against the peer's max concurrent streams though.
+pub struct StreamPool {
+    conn: Connection,
+    ready: Option<flume::Receiver<(SendStream, RecvStream)>>,
+    refill_handle: Option<JoinHandle<()>>,
+}
+
+impl StreamPool {
+    /// a size of 0 disables the pool and every stream is opened on demand
+    pub fn new(conn: Connection, size: usize) -> Self {
+        if size == 0 {
+            return Self {
+                conn,
+                ready: None,
+                refill_handle: None,
+            };
+        }
+
+        let (tx, rx) = flume::bounded(size);
+
+        let f = {
+            let conn = conn.clone();
+
+            async move {
+                loop {
+                    let x = match conn.open_bi().await {
+                        Ok(x) => x,
+                        Err(err) => {
+                            trace!(?err, "stream pool stopped");
+                            break;
+                        }
+                    };
+
+                    // this waits while the pool is full
+                    if tx.send_async(x).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        };
+
+        Self {
+            conn,
+            ready: Some(rx),
+            refill_handle: Some(tokio::spawn(f)),
+        }
+    }
+
+    /// get a pre-opened stream if one is ready. otherwise open one now
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), ConnectionError> {
+        if let Some(x) = self.ready.as_ref().and_then(|ready| ready.try_recv().ok()) {
+            trace!("using pre-opened stream");
+            return Ok(x);
+        }
+
+        self.conn.open_bi().await
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// resolves when the connection is closed by either side
+    pub async fn closed(&self) -> ConnectionError {
+        self.conn.closed().await
+    }
+}
+
+impl Drop for StreamPool {
+    fn drop(&mut self) {
+        if let Some(x) = self.refill_handle.take() {
+            x.abort();
+        }
+    }
+}
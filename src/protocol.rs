@@ -0,0 +1,1148 @@
+//! The bytes quic-tunnel itself puts on the wire.
+//!
+//! Anyone with a valid client cert can send us anything here, so every length is bounded and every read has a timeout.
+//!
+//! Stream preamble (server -> client, first bytes of every proxied stream):
+//!
+//! ```text
+//! magic: b"QT" | version: u8 | padding: 2 bits | compress: 6 bits | route_len: u8 | route: [u8; route_len] (utf8)
+//! ```
+//!
+//! Control frame:
+//!
+//! ```text
+//! len: u16 (big endian, counts kind + payload) | kind: u8 | payload: [u8; len - 1]
+//! ```
+//!
+//! Right after connecting, the client opens a stream and sends `Hello` with the compression it accepts, then `Version` with
+//! the protocol versions it speaks. The server answers `Version` with its own, then `Welcome` with the compression its
+//! listeners use. Both sides use the highest version they share. If they share none, or the client can't read a listener's
+//! compression, the server answers `Error` instead and closes the connection with [`CloseCode::Incompatible`]. From version 5,
+//! the client then sends `Session` with an id (u64 big endian) it keeps for as long as it reconnects over the same path.
+//! After that, both sides keep the stream open for heartbeats, and the server ends it with `GoAway` when it is being
+//! upgraded or `Close` when it is shutting down. See the `control` module.
+//!
+//! Compatibility: each release speaks its own [`PROTOCOL_VERSION`] and the one before it ([`MIN_PROTOCOL_VERSION`]), so a
+//! deployment can upgrade its servers and clients one at a time. Peers further apart are refused with a reason that says
+//! which side to upgrade. Version 3 peers don't send `Version`, so the client's `Hello` alone, or the server's `Welcome`
+//! alone, means version 3.
+//!
+//! Compressed frame (both directions of a stream when compression is on, after the preamble):
+//!
+//! ```text
+//! raw: 1 bit | pad: 1 bit | len: 30 bits (u32 big endian) | payload: [u8; len]
+//! ```
+//!
+//! A raw payload is the bytes as they are. Otherwise it is an lz4 block with its uncompressed size prepended (u32 little endian).
+//! A pad payload is thrown away. Padded streams use these frames even without compression. See the `padding` module.
+//!
+//! Clients offer the [`Role`] of their connection as the TLS ALPN, so a server can take several kinds of tunnel on one port
+//! and turn away the kinds it doesn't serve in the handshake. Clients from before ALPN offer none and get the server's
+//! main role. Every server takes [`Role::Probe`], whose streams carry only `Ping` and `Pong` frames.
+//!
+//! A [`Role::Connect`] client starts each stream with a `Connect` frame saying where the server should connect it. The
+//! address is a family byte (4 or 6), the IP's bytes, and the port (u16 big endian). A server that won't or can't connect
+//! there resets the stream with [`CloseCode::Unreachable`]. See the `transparent` module.
+//!
+//! A [`Role::Tun`] server opens a stream to each client with only an `Address` frame: the IP like above without
+//! the port, then the prefix length. After that, both sides send IP packets as QUIC datagrams. See the `vpn` module.
+//!
+//! A [`Role::Rendezvous`] client opens a stream and sends `Meet` with a name (u8 length, then utf8) and whether it is
+//! waiting to be reached. The server answers each side with `Peer` and the other side's address, like `Connect` has it.
+//! Peers then connect straight to each other with [`Role::Peer`]. See the `rendezvous` module.
+//!
+//! A [`Role::Hop`] client opens a stream and sends `Hop` with the address of the next server. After that, the server sends
+//! the client's datagrams to that address over UDP, and what comes back in datagrams, so the client's QUIC connection to
+//! the next server goes inside this one. See the `chain` module.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use crate::compress::CompressAlgo;
+use crate::padding::PaddingMode;
+
+pub const PREAMBLE_MAGIC: &[u8; 2] = b"QT";
+/// 2 added the compress byte to the preamble. 3 added the hello and welcome control stream.
+/// 4 added the version exchange and heartbeats. 5 added the session id
+pub const PROTOCOL_VERSION: u8 = 5;
+
+/// the oldest version we still talk to. see the module docs for the policy
+pub const MIN_PROTOCOL_VERSION: u8 = 4;
+
+/// what a peer that doesn't send `Version` speaks
+pub const IMPLIED_VERSION: u8 = 3;
+
+/// the first version that pings on the control stream
+pub const HEARTBEAT_VERSION: u8 = 4;
+
+/// the first version whose clients send `Session`
+pub const SESSION_VERSION: u8 = 5;
+
+/// magic, version, compress, and route_len
+const PREAMBLE_HEADER_LEN: usize = PREAMBLE_MAGIC.len() + 3;
+
+/// route names are short labels like "tcp" or "postgres"
+pub const MAX_ROUTE_LEN: usize = u8::MAX as usize;
+pub const MAX_PREAMBLE_LEN: usize = PREAMBLE_HEADER_LEN + MAX_ROUTE_LEN;
+
+/// control messages are small. anything bigger is an attack or a bug
+pub const MAX_CONTROL_FRAME_LEN: usize = 4096;
+pub const MAX_REASON_LEN: usize = 1024;
+
+/// rendezvous names are labels, like route names
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+
+/// a peer that opens a stream and then says nothing is holding resources for free
+pub const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a tunnel connection was closed. These are the QUIC application close codes, and the codes in `Close` and `Error`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum CloseCode {
+    /// on purpose, like a shutdown. reconnecting is fine
+    Done = 0,
+    /// the peer sent something this side can't parse
+    ProtocolError = 1,
+    /// a peer we can't work with. the reason says why and retrying won't help
+    Incompatible = 2,
+    /// the peer stopped answering heartbeats. see the `control` module
+    HeartbeatTimeout = 3,
+    /// an operator closed the connection, like with the admin api
+    Kicked = 4,
+    /// the client used up its quota. see the `quota` module
+    QuotaExceeded = 5,
+    /// a stream reset on purpose, to test how apps cope. see the `chaos` module
+    Chaos = 6,
+    /// a `Connect` stream's destination isn't allowed or didn't answer, no rendezvous peer has the name, a `Hop` isn't
+    /// allowed, or a reverse proxy client's backend didn't answer. see the `transparent`, `rendezvous`, and `chain` modules
+    Unreachable = 7,
+    /// a newer connection with the same client cert took this one's place, under the server's identity limit. the client
+    /// stops instead of taking it back. see `IdentityLimit`
+    Replaced = 8,
+    /// the client cert already has as many connections as the server allows. trying again later is fine
+    TooManyConnections = 9,
+    /// the same client connected again, so the server closed the connection it had left behind. users go to the new one.
+    /// see `ClientGuard::set_session`
+    Superseded = 10,
+    /// the client has as many streams open as the server allows it. new users wait for one to finish or go to other
+    /// clients. see `max_streams_per_client`
+    TooManyStreams = 11,
+}
+
+impl CloseCode {
+    pub fn from_u32(x: u32) -> Option<Self> {
+        [
+            Self::Done,
+            Self::ProtocolError,
+            Self::Incompatible,
+            Self::HeartbeatTimeout,
+            Self::Kicked,
+            Self::QuotaExceeded,
+            Self::Chaos,
+            Self::Unreachable,
+            Self::Replaced,
+            Self::TooManyConnections,
+            Self::Superseded,
+            Self::TooManyStreams,
+        ]
+        .into_iter()
+        .find(|code| *code as u32 == x)
+    }
+
+    /// the name of `x` if we know it, for logs. codes from newer peers are shown as numbers
+    pub fn describe(x: u32) -> String {
+        match Self::from_u32(x) {
+            Some(code) => format!("{code:?}"),
+            None => format!("unknown ({x})"),
+        }
+    }
+
+    /// the code and reason the peer closed `err`'s connection with, if it closed it on purpose
+    pub fn from_error(err: &quinn::ConnectionError) -> Option<(u32, String)> {
+        match err {
+            quinn::ConnectionError::ApplicationClosed(x) => Some((
+                x.error_code.into_inner() as u32,
+                String::from_utf8_lossy(&x.reason).into_owned(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+impl From<CloseCode> for quinn::VarInt {
+    fn from(x: CloseCode) -> Self {
+        Self::from_u32(x as u32)
+    }
+}
+
+/// the highest version both sides speak, or a reason that says which side to upgrade.
+/// `peer` and `us` are "client" and "server", one each way
+pub fn negotiate_version(min: u8, max: u8, peer: &str, us: &str) -> Result<u8, String> {
+    let upgrade = if min > PROTOCOL_VERSION {
+        us
+    } else if max < MIN_PROTOCOL_VERSION || max < min {
+        peer
+    } else {
+        return Ok(max.min(PROTOCOL_VERSION));
+    };
+
+    Err(format!(
+        "the {peer} speaks protocol {} but this {us} speaks {}. upgrade the {upgrade}",
+        describe_versions(min, max),
+        describe_versions(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+    ))
+}
+
+fn describe_versions(min: u8, max: u8) -> String {
+    match min == max {
+        true => format!("version {min}"),
+        false => format!("versions {min} to {max}"),
+    }
+}
+
+/// what a tunnel connection is for
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// the server opens streams for its users and the client forwards them. `reverse_proxy_client`
+    Reverse,
+    /// the client opens streams of datagrams and the server forwards them. `udp_client`
+    Forward,
+    /// the client sends `Ping` and the server answers `Pong`, on as many streams as it likes. nothing is forwarded. `ping`
+    Probe,
+    /// the client opens streams that each start with `Connect`, and the server connects them there. `transparent_client`
+    Connect,
+    /// the server sends `Address`, then both sides send IP packets in datagrams. `tun_client`
+    Tun,
+    /// the client sends `Meet` and the server answers `Peer`, and relays the client's streams if it has to. `peer_client`
+    /// and `peer_server`
+    Rendezvous,
+    /// between two peers that met at a rendezvous. the client opens streams and the server forwards them. `peer_client`
+    Peer,
+    /// the client sends `Hop`, then the server forwards its datagrams as UDP packets. clients with `--via`
+    Hop,
+}
+
+impl Role {
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Self::Reverse => b"qt-reverse",
+            Self::Forward => b"qt-forward",
+            Self::Probe => b"qt-probe",
+            Self::Connect => b"qt-connect",
+            Self::Tun => b"qt-tun",
+            Self::Rendezvous => b"qt-rendezvous",
+            Self::Peer => b"qt-peer",
+            Self::Hop => b"qt-hop",
+        }
+    }
+
+    pub fn from_alpn(x: &[u8]) -> Option<Self> {
+        [
+            Self::Reverse,
+            Self::Forward,
+            Self::Probe,
+            Self::Connect,
+            Self::Tun,
+            Self::Rendezvous,
+            Self::Peer,
+            Self::Hop,
+        ]
+        .into_iter()
+        .find(|role| role.alpn() == x)
+    }
+
+    /// the role the client asked for. `None` if it didn't offer one
+    pub fn negotiated(conn: &quinn::Connection) -> Option<Self> {
+        let x = conn
+            .handshake_data()?
+            .downcast::<quinn::crypto::rustls::HandshakeData>()
+            .ok()?;
+
+        Self::from_alpn(x.protocol.as_deref()?)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("need at least {0} more bytes")]
+    Incomplete(usize),
+    #[error("bad magic bytes")]
+    BadMagic,
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("{what} is {len} bytes but the limit is {max}")]
+    TooLong {
+        what: &'static str,
+        len: usize,
+        max: usize,
+    },
+    #[error("{0} is not valid utf8")]
+    InvalidUtf8(&'static str),
+    #[error("unknown compression algorithm {0}")]
+    UnknownCompression(u8),
+    #[error("unknown padding mode {0}")]
+    UnknownPadding(u8),
+    #[error("unknown address family {0}")]
+    UnknownFamily(u8),
+    #[error("prefix length {0} is too long for the address")]
+    BadPrefix(u8),
+    #[error("unknown control message kind {0}")]
+    UnknownKind(u8),
+    #[error("control frame payload is truncated")]
+    Truncated,
+    #[error("empty control frame")]
+    EmptyFrame,
+    #[error("{0} bytes of trailing data in control frame")]
+    TrailingData(usize),
+    #[error("timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error("stream closed before {0} was complete")]
+    UnexpectedEof(&'static str),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Tells the client what a new stream is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamPreamble {
+    pub version: u8,
+    /// how both directions of this stream are compressed. the server picks it for each listener
+    pub compress: CompressAlgo,
+    /// how both sides pad this stream. the server picks it
+    pub padding: PaddingMode,
+    /// private so it can't be made longer than `MAX_ROUTE_LEN` after `new` checked it
+    route: String,
+}
+
+impl StreamPreamble {
+    pub fn new(route: &str) -> Result<Self, ProtocolError> {
+        check_len("route", route.len(), MAX_ROUTE_LEN)?;
+
+        Ok(Self {
+            version: PROTOCOL_VERSION,
+            compress: CompressAlgo::None,
+            padding: PaddingMode::Off,
+            route: route.to_string(),
+        })
+    }
+
+    /// which of the client's services the stream goes to
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    /// the version negotiated for the connection. a version 3 client doesn't read preambles from newer versions
+    pub fn with_version(mut self, x: u8) -> Self {
+        self.version = x;
+        self
+    }
+
+    pub fn with_compress(mut self, x: CompressAlgo) -> Self {
+        self.compress = x;
+        self
+    }
+
+    pub fn with_padding(mut self, x: PaddingMode) -> Self {
+        self.padding = x;
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut x = Vec::with_capacity(PREAMBLE_HEADER_LEN + self.route.len());
+
+        x.extend_from_slice(PREAMBLE_MAGIC);
+        x.push(self.version);
+        x.push(encode_padding(self.padding) << 6 | encode_compress(self.compress));
+        x.push(self.route.len() as u8);
+        x.extend_from_slice(self.route.as_bytes());
+
+        x
+    }
+
+    /// parse a preamble from the start of `buf`. returns the preamble and how many bytes it used
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), ProtocolError> {
+        let header_len = PREAMBLE_HEADER_LEN;
+
+        if buf.len() < header_len {
+            return Err(ProtocolError::Incomplete(header_len - buf.len()));
+        }
+
+        if &buf[..2] != PREAMBLE_MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+
+        let version = buf[2];
+
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let padding = decode_padding(buf[3] >> 6)?;
+        let compress = decode_compress(buf[3] & 0x3f)?;
+
+        let route_len = buf[4] as usize;
+        let total = header_len + route_len;
+
+        if buf.len() < total {
+            return Err(ProtocolError::Incomplete(total - buf.len()));
+        }
+
+        let route = std::str::from_utf8(&buf[header_len..total])
+            .map_err(|_| ProtocolError::InvalidUtf8("route"))?
+            .to_string();
+
+        Ok((
+            Self {
+                version,
+                compress,
+                padding,
+                route,
+            },
+            total,
+        ))
+    }
+
+    /// read exactly one preamble without reading past it
+    pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> Result<Self, ProtocolError> {
+        timeout(PREAMBLE_TIMEOUT, async {
+            let mut buf = [0; MAX_PREAMBLE_LEN];
+
+            let header_len = PREAMBLE_HEADER_LEN;
+
+            read_exact(r, &mut buf[..header_len], "preamble").await?;
+
+            let total = match Self::decode(&buf[..header_len]) {
+                Err(ProtocolError::Incomplete(n)) => header_len + n,
+                Ok((x, _)) => return Ok(x),
+                Err(err) => return Err(err),
+            };
+
+            read_exact(r, &mut buf[header_len..total], "preamble").await?;
+
+            Self::decode(&buf[..total]).map(|(x, _)| x)
+        })
+        .await
+        .map_err(|_| ProtocolError::Timeout("preamble"))?
+    }
+}
+
+fn encode_compress(x: CompressAlgo) -> u8 {
+    match x {
+        CompressAlgo::None => 0,
+        CompressAlgo::Lz4 => 1,
+        CompressAlgo::Auto => 2,
+    }
+}
+
+fn decode_compress(x: u8) -> Result<CompressAlgo, ProtocolError> {
+    match x {
+        0 => Ok(CompressAlgo::None),
+        1 => Ok(CompressAlgo::Lz4),
+        2 => Ok(CompressAlgo::Auto),
+        x => Err(ProtocolError::UnknownCompression(x)),
+    }
+}
+
+fn encode_padding(x: PaddingMode) -> u8 {
+    match x {
+        PaddingMode::Off => 0,
+        PaddingMode::Bucketed => 1,
+        PaddingMode::ConstantRate => 2,
+    }
+}
+
+fn decode_padding(x: u8) -> Result<PaddingMode, ProtocolError> {
+    match x {
+        0 => Ok(PaddingMode::Off),
+        1 => Ok(PaddingMode::Bucketed),
+        2 => Ok(PaddingMode::ConstantRate),
+        x => Err(ProtocolError::UnknownPadding(x)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    Ping(u64),
+    Pong(u64),
+    /// the sender is done and the connection should close. `code` is an application close code
+    Close {
+        code: u32,
+        reason: String,
+    },
+    /// something went wrong, but the connection can stay open
+    Error {
+        code: u32,
+        reason: String,
+    },
+    /// the client's first message on its control stream. the compression it can read and write
+    Hello {
+        compress: Vec<CompressAlgo>,
+    },
+    /// the server's answer to `Hello`. the compression its listeners use
+    Welcome {
+        compress: Vec<CompressAlgo>,
+    },
+    /// the server is being upgraded. connect again to reach the new process and let the streams on this connection finish
+    GoAway,
+    /// the protocol versions the sender speaks. the client sends it after `Hello` and the server answers before `Welcome`
+    Version {
+        min: u8,
+        max: u8,
+    },
+    /// the first frame of a `Role::Connect` stream. where the server should connect it
+    Connect {
+        addr: SocketAddr,
+    },
+    /// the only frame on the stream a `Role::Tun` server opens. the client's address, and the prefix of the network it is on
+    Address {
+        addr: IpNet,
+    },
+    /// the first frame of a `Role::Rendezvous` client. `listen` if it waits for peers to reach it by `name`
+    Meet {
+        name: String,
+        listen: bool,
+    },
+    /// the server's answer to `Meet`, once there is a peer. where the peer's packets come from
+    Peer {
+        addr: SocketAddr,
+    },
+    /// the first frame of a `Role::Hop` client. where the server should send its datagrams
+    Hop {
+        addr: SocketAddr,
+    },
+    /// the client's answer to `Welcome`. the same id for every connection one client makes over one path
+    Session {
+        id: u64,
+    },
+}
+
+impl ControlMessage {
+    const KIND_PING: u8 = 1;
+    const KIND_PONG: u8 = 2;
+    const KIND_CLOSE: u8 = 3;
+    const KIND_ERROR: u8 = 4;
+    const KIND_HELLO: u8 = 5;
+    const KIND_WELCOME: u8 = 6;
+    const KIND_GO_AWAY: u8 = 7;
+    const KIND_VERSION: u8 = 8;
+    const KIND_CONNECT: u8 = 9;
+    const KIND_ADDRESS: u8 = 10;
+    const KIND_MEET: u8 = 11;
+    const KIND_PEER: u8 = 12;
+    const KIND_HOP: u8 = 13;
+    const KIND_SESSION: u8 = 14;
+
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut payload = Vec::new();
+
+        let kind = match self {
+            Self::Ping(x) => {
+                payload.extend_from_slice(&x.to_be_bytes());
+                Self::KIND_PING
+            }
+            Self::Pong(x) => {
+                payload.extend_from_slice(&x.to_be_bytes());
+                Self::KIND_PONG
+            }
+            Self::Close { code, reason } => {
+                encode_code_reason(&mut payload, *code, reason)?;
+                Self::KIND_CLOSE
+            }
+            Self::Error { code, reason } => {
+                encode_code_reason(&mut payload, *code, reason)?;
+                Self::KIND_ERROR
+            }
+            Self::Hello { compress } => {
+                encode_compress_list(&mut payload, compress)?;
+                Self::KIND_HELLO
+            }
+            Self::Welcome { compress } => {
+                encode_compress_list(&mut payload, compress)?;
+                Self::KIND_WELCOME
+            }
+            Self::GoAway => Self::KIND_GO_AWAY,
+            Self::Version { min, max } => {
+                payload.extend_from_slice(&[*min, *max]);
+                Self::KIND_VERSION
+            }
+            Self::Connect { addr } => {
+                encode_addr(&mut payload, addr);
+                Self::KIND_CONNECT
+            }
+            Self::Address { addr } => {
+                encode_ip(&mut payload, addr.addr());
+                payload.push(addr.prefix_len());
+                Self::KIND_ADDRESS
+            }
+            Self::Meet { name, listen } => {
+                check_len("name", name.len(), MAX_NAME_LEN)?;
+
+                payload.push(name.len() as u8);
+                payload.extend_from_slice(name.as_bytes());
+                payload.push(*listen as u8);
+                Self::KIND_MEET
+            }
+            Self::Peer { addr } => {
+                encode_addr(&mut payload, addr);
+                Self::KIND_PEER
+            }
+            Self::Hop { addr } => {
+                encode_addr(&mut payload, addr);
+                Self::KIND_HOP
+            }
+            Self::Session { id } => {
+                payload.extend_from_slice(&id.to_be_bytes());
+                Self::KIND_SESSION
+            }
+        };
+
+        let len = 1 + payload.len();
+
+        check_len("control frame", len, MAX_CONTROL_FRAME_LEN)?;
+
+        let mut x = Vec::with_capacity(2 + len);
+        x.extend_from_slice(&(len as u16).to_be_bytes());
+        x.push(kind);
+        x.extend_from_slice(&payload);
+
+        Ok(x)
+    }
+
+    /// parse a frame from the start of `buf`. returns the message and how many bytes it used
+    pub fn decode(buf: &[u8]) -> Result<(Self, usize), ProtocolError> {
+        if buf.len() < 2 {
+            return Err(ProtocolError::Incomplete(2 - buf.len()));
+        }
+
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+
+        if len == 0 {
+            return Err(ProtocolError::EmptyFrame);
+        }
+
+        check_len("control frame", len, MAX_CONTROL_FRAME_LEN)?;
+
+        let total = 2 + len;
+
+        if buf.len() < total {
+            return Err(ProtocolError::Incomplete(total - buf.len()));
+        }
+
+        let x = Self::decode_body(buf[2], &buf[3..total])?;
+
+        Ok((x, total))
+    }
+
+    fn decode_body(kind: u8, mut payload: &[u8]) -> Result<Self, ProtocolError> {
+        let x = match kind {
+            Self::KIND_PING => Self::Ping(take_u64(&mut payload)?),
+            Self::KIND_PONG => Self::Pong(take_u64(&mut payload)?),
+            Self::KIND_CLOSE => {
+                let (code, reason) = take_code_reason(&mut payload)?;
+                Self::Close { code, reason }
+            }
+            Self::KIND_ERROR => {
+                let (code, reason) = take_code_reason(&mut payload)?;
+                Self::Error { code, reason }
+            }
+            Self::KIND_HELLO => Self::Hello {
+                compress: take_compress_list(&mut payload)?,
+            },
+            Self::KIND_WELCOME => Self::Welcome {
+                compress: take_compress_list(&mut payload)?,
+            },
+            Self::KIND_GO_AWAY => Self::GoAway,
+            Self::KIND_VERSION => {
+                let x = take(&mut payload, 2)?;
+                Self::Version {
+                    min: x[0],
+                    max: x[1],
+                }
+            }
+            Self::KIND_CONNECT => Self::Connect {
+                addr: take_addr(&mut payload)?,
+            },
+            Self::KIND_ADDRESS => {
+                let ip = take_ip(&mut payload)?;
+                let prefix = take(&mut payload, 1)?[0];
+
+                Self::Address {
+                    addr: IpNet::new(ip, prefix).map_err(|_| ProtocolError::BadPrefix(prefix))?,
+                }
+            }
+            Self::KIND_MEET => {
+                let len = take(&mut payload, 1)?[0] as usize;
+
+                let name = std::str::from_utf8(take(&mut payload, len)?)
+                    .map_err(|_| ProtocolError::InvalidUtf8("name"))?
+                    .to_string();
+
+                Self::Meet {
+                    name,
+                    listen: take(&mut payload, 1)?[0] != 0,
+                }
+            }
+            Self::KIND_PEER => Self::Peer {
+                addr: take_addr(&mut payload)?,
+            },
+            Self::KIND_HOP => Self::Hop {
+                addr: take_addr(&mut payload)?,
+            },
+            Self::KIND_SESSION => Self::Session {
+                id: take_u64(&mut payload)?,
+            },
+            x => return Err(ProtocolError::UnknownKind(x)),
+        };
+
+        if !payload.is_empty() {
+            return Err(ProtocolError::TrailingData(payload.len()));
+        }
+
+        Ok(x)
+    }
+
+    /// read exactly one frame. `None` means the stream was closed cleanly between frames
+    pub async fn read<R: AsyncRead + Unpin>(
+        r: &mut R,
+        max_wait: Duration,
+    ) -> Result<Option<Self>, ProtocolError> {
+        timeout(max_wait, async {
+            let mut len_buf = [0; 2];
+
+            // a clean close is only allowed before a frame starts
+            let n = r.read(&mut len_buf[..1]).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            read_exact(r, &mut len_buf[1..], "control frame").await?;
+
+            let len = u16::from_be_bytes(len_buf) as usize;
+
+            if len == 0 {
+                return Err(ProtocolError::EmptyFrame);
+            }
+
+            check_len("control frame", len, MAX_CONTROL_FRAME_LEN)?;
+
+            let mut body = vec![0; len];
+            read_exact(r, &mut body, "control frame").await?;
+
+            Self::decode_body(body[0], &body[1..]).map(Some)
+        })
+        .await
+        .map_err(|_| ProtocolError::Timeout("control frame"))?
+    }
+}
+
+fn check_len(what: &'static str, len: usize, max: usize) -> Result<(), ProtocolError> {
+    if len > max {
+        Err(ProtocolError::TooLong { what, len, max })
+    } else {
+        Ok(())
+    }
+}
+
+async fn read_exact<R: AsyncRead + Unpin>(
+    r: &mut R,
+    buf: &mut [u8],
+    what: &'static str,
+) -> Result<(), ProtocolError> {
+    match r.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(ProtocolError::UnexpectedEof(what))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn encode_code_reason(buf: &mut Vec<u8>, code: u32, reason: &str) -> Result<(), ProtocolError> {
+    check_len("reason", reason.len(), MAX_REASON_LEN)?;
+
+    buf.extend_from_slice(&code.to_be_bytes());
+    buf.extend_from_slice(&(reason.len() as u16).to_be_bytes());
+    buf.extend_from_slice(reason.as_bytes());
+
+    Ok(())
+}
+
+fn encode_compress_list(buf: &mut Vec<u8>, x: &[CompressAlgo]) -> Result<(), ProtocolError> {
+    check_len("compression list", x.len(), u8::MAX as usize)?;
+
+    buf.push(x.len() as u8);
+    buf.extend(x.iter().map(|x| encode_compress(*x)));
+
+    Ok(())
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], ProtocolError> {
+    // the whole frame has already been read, so running out here means the length fields lied
+    if buf.len() < n {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let (x, rest) = buf.split_at(n);
+    *buf = rest;
+
+    Ok(x)
+}
+
+fn take_u64(buf: &mut &[u8]) -> Result<u64, ProtocolError> {
+    let x = take(buf, 8)?;
+
+    Ok(u64::from_be_bytes(x.try_into().unwrap()))
+}
+
+fn take_code_reason(buf: &mut &[u8]) -> Result<(u32, String), ProtocolError> {
+    let code = u32::from_be_bytes(take(buf, 4)?.try_into().unwrap());
+
+    let len = u16::from_be_bytes(take(buf, 2)?.try_into().unwrap()) as usize;
+
+    check_len("reason", len, MAX_REASON_LEN)?;
+
+    let reason = std::str::from_utf8(take(buf, len)?)
+        .map_err(|_| ProtocolError::InvalidUtf8("reason"))?
+        .to_string();
+
+    Ok((code, reason))
+}
+
+fn encode_ip(buf: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(x) => {
+            buf.push(4);
+            buf.extend_from_slice(&x.octets());
+        }
+        IpAddr::V6(x) => {
+            buf.push(6);
+            buf.extend_from_slice(&x.octets());
+        }
+    }
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    encode_ip(buf, addr.ip());
+
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn take_ip(buf: &mut &[u8]) -> Result<IpAddr, ProtocolError> {
+    let x = match take(buf, 1)?[0] {
+        4 => IpAddr::from(<[u8; 4]>::try_from(take(buf, 4)?).unwrap()),
+        6 => IpAddr::from(<[u8; 16]>::try_from(take(buf, 16)?).unwrap()),
+        x => return Err(ProtocolError::UnknownFamily(x)),
+    };
+
+    Ok(x)
+}
+
+fn take_addr(buf: &mut &[u8]) -> Result<SocketAddr, ProtocolError> {
+    let ip = take_ip(buf)?;
+
+    let port = u16::from_be_bytes(take(buf, 2)?.try_into().unwrap());
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn take_compress_list(buf: &mut &[u8]) -> Result<Vec<CompressAlgo>, ProtocolError> {
+    let len = take(buf, 1)?[0] as usize;
+
+    take(buf, len)?
+        .iter()
+        .map(|x| decode_compress(*x))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preambles() -> Vec<StreamPreamble> {
+        let mut x = vec![];
+
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            for compress in [CompressAlgo::None, CompressAlgo::Lz4, CompressAlgo::Auto] {
+                for padding in [
+                    PaddingMode::Off,
+                    PaddingMode::Bucketed,
+                    PaddingMode::ConstantRate,
+                ] {
+                    for route in ["", "tcp", &"r".repeat(MAX_ROUTE_LEN)] {
+                        x.push(
+                            StreamPreamble::new(route)
+                                .unwrap()
+                                .with_version(version)
+                                .with_compress(compress)
+                                .with_padding(padding),
+                        );
+                    }
+                }
+            }
+        }
+
+        x
+    }
+
+    fn messages() -> Vec<ControlMessage> {
+        vec![
+            ControlMessage::Ping(0),
+            ControlMessage::Pong(u64::MAX),
+            ControlMessage::Close {
+                code: CloseCode::Done as u32,
+                reason: "bye".to_string(),
+            },
+            ControlMessage::Error {
+                code: u32::MAX,
+                reason: "x".repeat(MAX_REASON_LEN),
+            },
+            ControlMessage::Hello {
+                compress: vec![CompressAlgo::Lz4, CompressAlgo::None],
+            },
+            ControlMessage::Welcome { compress: vec![] },
+            ControlMessage::GoAway,
+            ControlMessage::Version {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            },
+            ControlMessage::Connect {
+                addr: "127.0.0.1:80".parse().unwrap(),
+            },
+            ControlMessage::Address {
+                addr: "fd00::2/64".parse().unwrap(),
+            },
+            ControlMessage::Meet {
+                name: "n".repeat(MAX_NAME_LEN),
+                listen: true,
+            },
+            ControlMessage::Peer {
+                addr: "[2001:db8::1]:4433".parse().unwrap(),
+            },
+            ControlMessage::Hop {
+                addr: "10.0.0.1:0".parse().unwrap(),
+            },
+            ControlMessage::Session { id: 42 },
+        ]
+    }
+
+    #[tokio::test]
+    async fn preamble_round_trip() {
+        for x in preambles() {
+            let bytes = x.encode();
+
+            assert_eq!(
+                StreamPreamble::decode(&bytes).unwrap(),
+                (x.clone(), bytes.len())
+            );
+
+            // what comes after the preamble is the user's, so it has to be left on the stream
+            let stream = [bytes.as_slice(), b"after"].concat();
+            let mut r = stream.as_slice();
+
+            assert_eq!(StreamPreamble::read(&mut r).await.unwrap(), x);
+            assert_eq!(r, b"after");
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_preambles() {
+        let bytes = StreamPreamble::new("tcp").unwrap().encode();
+
+        for n in 0..bytes.len() {
+            assert!(
+                matches!(
+                    StreamPreamble::decode(&bytes[..n]),
+                    Err(ProtocolError::Incomplete(x)) if x > 0
+                ),
+                "{n} bytes"
+            );
+
+            assert!(
+                matches!(
+                    StreamPreamble::read(&mut &bytes[..n]).await,
+                    Err(ProtocolError::UnexpectedEof("preamble"))
+                ),
+                "{n} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_preambles() {
+        let good = StreamPreamble::new("tcp").unwrap().encode();
+
+        let with = |i: usize, x: u8| {
+            let mut bytes = good.clone();
+            bytes[i] = x;
+            StreamPreamble::decode(&bytes)
+        };
+
+        assert!(matches!(with(0, b'X'), Err(ProtocolError::BadMagic)));
+        assert!(matches!(with(1, b'X'), Err(ProtocolError::BadMagic)));
+        assert!(matches!(
+            with(2, MIN_PROTOCOL_VERSION - 1),
+            Err(ProtocolError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            with(2, PROTOCOL_VERSION + 1),
+            Err(ProtocolError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            with(3, 0x3f),
+            Err(ProtocolError::UnknownCompression(0x3f))
+        ));
+        assert!(matches!(
+            with(3, 3 << 6),
+            Err(ProtocolError::UnknownPadding(3))
+        ));
+        assert!(matches!(
+            with(5, 0xff),
+            Err(ProtocolError::InvalidUtf8("route"))
+        ));
+    }
+
+    #[test]
+    fn oversize_routes() {
+        assert!(StreamPreamble::new(&"r".repeat(MAX_ROUTE_LEN)).is_ok());
+
+        assert!(matches!(
+            StreamPreamble::new(&"r".repeat(MAX_ROUTE_LEN + 1)),
+            Err(ProtocolError::TooLong { what: "route", .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn control_round_trip() {
+        let mut stream = vec![];
+
+        for x in messages() {
+            let bytes = x.encode().unwrap();
+
+            assert_eq!(
+                ControlMessage::decode(&bytes).unwrap(),
+                (x.clone(), bytes.len())
+            );
+
+            stream.extend(bytes);
+        }
+
+        let mut r = stream.as_slice();
+
+        for x in messages() {
+            assert_eq!(
+                ControlMessage::read(&mut r, PREAMBLE_TIMEOUT)
+                    .await
+                    .unwrap(),
+                Some(x)
+            );
+        }
+
+        // closed between frames
+        assert_eq!(
+            ControlMessage::read(&mut r, PREAMBLE_TIMEOUT)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_control_frames() {
+        assert!(matches!(
+            ControlMessage::decode(&[]),
+            Err(ProtocolError::Incomplete(2))
+        ));
+
+        for x in messages() {
+            let bytes = x.encode().unwrap();
+
+            for n in 1..bytes.len() {
+                assert!(
+                    matches!(
+                        ControlMessage::decode(&bytes[..n]),
+                        Err(ProtocolError::Incomplete(x)) if x > 0
+                    ),
+                    "{x:?} in {n} bytes"
+                );
+
+                assert!(
+                    matches!(
+                        ControlMessage::read(&mut &bytes[..n], PREAMBLE_TIMEOUT).await,
+                        Err(ProtocolError::UnexpectedEof("control frame"))
+                    ),
+                    "{x:?} in {n} bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bad_control_frames() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0, 0], "empty control frame"),
+            (&[0, 1, 0], "unknown control message kind 0"),
+            (&[0, 1, 99], "unknown control message kind 99"),
+            // a ping is 8 bytes
+            (&[0, 5, 1, 0, 0, 0, 0], "control frame payload is truncated"),
+            (&[0, 2, 7, 0], "1 bytes of trailing data in control frame"),
+            (&[0, 2, 5, 1], "control frame payload is truncated"),
+            (&[0, 3, 5, 1, 9], "unknown compression algorithm 9"),
+            (&[0, 5, 9, 5, 0, 0, 0], "unknown address family 5"),
+            (
+                &[0, 7, 10, 4, 10, 0, 0, 1, 33],
+                "prefix length 33 is too long for the address",
+            ),
+            (&[0, 4, 11, 1, 0xff, 0], "name is not valid utf8"),
+        ];
+
+        for (bytes, err) in cases {
+            assert_eq!(
+                ControlMessage::decode(bytes).unwrap_err().to_string(),
+                *err,
+                "{bytes:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn oversize_control_frames() {
+        let too_long = |x: ControlMessage| matches!(x.encode(), Err(ProtocolError::TooLong { .. }));
+
+        assert!(too_long(ControlMessage::Close {
+            code: 0,
+            reason: "x".repeat(MAX_REASON_LEN + 1),
+        }));
+        assert!(too_long(ControlMessage::Meet {
+            name: "n".repeat(MAX_NAME_LEN + 1),
+            listen: false,
+        }));
+        assert!(too_long(ControlMessage::Hello {
+            compress: vec![CompressAlgo::None; u8::MAX as usize + 1],
+        }));
+
+        // the length is checked before waiting for that much
+        let mut header = ((MAX_CONTROL_FRAME_LEN + 1) as u16).to_be_bytes().to_vec();
+
+        assert!(matches!(
+            ControlMessage::decode(&header),
+            Err(ProtocolError::TooLong { .. })
+        ));
+        assert!(matches!(
+            ControlMessage::read(&mut header.as_slice(), PREAMBLE_TIMEOUT).await,
+            Err(ProtocolError::TooLong { .. })
+        ));
+
+        // a reason's own length can't get past the limit either
+        header = vec![0, 9, 4, 0, 0, 0, 0, 0xff, 0xff, 0, 0];
+
+        assert!(matches!(
+            ControlMessage::decode(&header),
+            Err(ProtocolError::TooLong { what: "reason", .. })
+        ));
+    }
+}
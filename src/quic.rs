@@ -1,14 +1,37 @@
 use crate::get_tunnel_timeout;
 
-use super::tls;
-use quinn::{congestion, ClientConfig, Endpoint, ServerConfig, TransportConfig};
+use super::tls::{self, TlsOptions};
+use crate::bind::BindOptions;
+use crate::chaos::ChaosOptions;
+use crate::ecn;
+use crate::error::TunnelError;
+use crate::listen::ListenTarget;
+use crate::obfs::Obfuscation;
+use crate::protocol::Role;
+use crate::proxy::UdpAssociation;
+use crate::upgrade::{self, QuicSocket};
+use quinn::{
+    congestion, ClientConfig, Connecting, Connection, Endpoint, EndpointConfig, MtuDiscoveryConfig,
+    Runtime, ServerConfig, TransportConfig, ZeroRttAccepted,
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    future::Future,
     net::{AddrParseError, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 use strum::EnumString;
-use tracing::trace;
+use tokio::select;
+use tokio::time::{interval, timeout};
+use tracing::{info, trace};
+
+/// the smallest UDP payload QUIC allows on a path
+const MIN_MTU: u16 = 1200;
+
+/// discovery moves up a step per probe, a round trip or so apart, so this sees most steps
+const MTU_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 pub fn matching_bind_address(x: SocketAddr) -> Result<SocketAddr, AddrParseError> {
     let bind = if x.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
@@ -16,8 +39,9 @@ pub fn matching_bind_address(x: SocketAddr) -> Result<SocketAddr, AddrParseError
     bind.parse()
 }
 
-#[derive(Debug, Default, EnumString, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq, Serialize)]
 #[strum(ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
 pub enum CongestionMode {
     /// good for high bandwidth networks
     Brr,
@@ -28,20 +52,76 @@ pub enum CongestionMode {
     NewReno,
 }
 
+/// Knobs for quinn's `TransportConfig`. Anything left as `None` uses our defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportOptions {
+    pub congestion_mode: CongestionMode,
+    /// only one side of the tunnel needs keep alive. this is decided by the role, not by config files
+    #[serde(skip_deserializing)]
+    pub keep_alive: bool,
+    /// defaults to a third of the idle timeout. setting this enables keep alive even if `keep_alive` is false
+    #[serde(with = "humantime_serde")]
+    pub keep_alive_interval: Option<Duration>,
+    /// defaults to `get_tunnel_timeout`
+    #[serde(with = "humantime_serde")]
+    pub max_idle_timeout: Option<Duration>,
+    /// bytes a peer may send on a single stream before waiting for us to read it
+    pub stream_receive_window: Option<u32>,
+    /// bytes a peer may send across all streams of a connection
+    pub receive_window: Option<u32>,
+    /// bytes we will buffer for sending across all streams of a connection
+    pub send_window: Option<u64>,
+    /// defaults to `u16::MAX`
+    pub max_concurrent_bidi_streams: Option<u32>,
+    /// UDP segmentation offload. defaults to on if the kernel supports it
+    pub gso: Option<bool>,
+    /// mark packets as ECN capable, so routers can signal congestion without dropping them. defaults to on. see the `ecn`
+    /// module
+    pub ecn: Option<bool>,
+    /// probe for a bigger path MTU than `initial_mtu` once connected. defaults to on
+    pub mtu_discovery: Option<bool>,
+    /// UDP payload bytes to start with. at least 1200, which is the default
+    pub initial_mtu: Option<u16>,
+    /// the most UDP payload bytes MTU discovery tries. 1452 by default, for a 1500 byte ethernet MTU
+    pub max_mtu: Option<u16>,
+    /// let clients keep their connection when their address changes, like after a NAT rebinding or a move from wifi to LTE.
+    /// only servers use this. defaults to on
+    pub migration: Option<bool>,
+    /// a shim around the UDP socket for networks that throttle QUIC. both sides need the same one. see the `obfs` module
+    pub obfuscation: Obfuscation,
+    /// drop and delay packets, and reset streams, on purpose. see the `chaos` module
+    pub chaos: ChaosOptions,
+    /// where a client's QUIC socket sends from. servers ignore this. see the `bind` module
+    pub bind: BindOptions,
+    /// send every packet through a SOCKS5 proxy's UDP relay. clients with a proxy set this, not config files. see the `proxy` module
+    #[serde(skip)]
+    pub socks: Option<Arc<UdpAssociation>>,
+}
+
 pub fn build_transport_config(
-    keep_alive: bool,
-    congestion_mode: CongestionMode,
-) -> Arc<TransportConfig> {
+    options: &TransportOptions,
+) -> Result<Arc<TransportConfig>, TunnelError> {
+    transport_config(options).map(Arc::new)
+}
+
+/// like `build_transport_config`, for callers that need to change it further
+pub fn transport_config(options: &TransportOptions) -> Result<TransportConfig, TunnelError> {
     let mut transport_config = TransportConfig::default();
 
     // uni streams are not needed
     transport_config.max_concurrent_uni_streams(0_u32.into());
     // we want lots of bi streams
-    transport_config.max_concurrent_bidi_streams(u16::MAX.into());
+    transport_config.max_concurrent_bidi_streams(
+        options
+            .max_concurrent_bidi_streams
+            .unwrap_or(u16::MAX.into())
+            .into(),
+    );
 
-    let timeout = get_tunnel_timeout();
+    let timeout = options.max_idle_timeout.unwrap_or_else(get_tunnel_timeout);
 
-    match congestion_mode {
+    match options.congestion_mode {
         CongestionMode::Brr => {
             transport_config
                 .congestion_controller_factory(Arc::new(congestion::BbrConfig::default()));
@@ -56,74 +136,437 @@ pub fn build_transport_config(
         }
     }
 
-    if keep_alive {
+    if options.keep_alive || options.keep_alive_interval.is_some() {
         // only one side needs keep alive
-        // TODO: how many keep alives per idle timeout?
-        transport_config.keep_alive_interval(Some(timeout / 3));
+        transport_config
+            .keep_alive_interval(Some(options.keep_alive_interval.unwrap_or(timeout / 3)));
+    }
+
+    transport_config.max_idle_timeout(Some(timeout.try_into().map_err(|_| {
+        TunnelError::Config(format!("idle timeout of {timeout:?} is too large for QUIC"))
+    })?));
+
+    if let Some(x) = options.stream_receive_window {
+        transport_config.stream_receive_window(x.into());
+    }
+
+    if let Some(x) = options.receive_window {
+        transport_config.receive_window(x.into());
+    }
+
+    if let Some(x) = options.send_window {
+        transport_config.send_window(x);
+    }
+
+    if let Some(x) = options.gso {
+        transport_config.enable_segmentation_offload(x);
     }
 
-    transport_config.max_idle_timeout(Some(timeout.try_into().unwrap()));
+    // a shim or a proxy sends one packet at a time, and chaos drops one at a time
+    if options.obfuscation.is_enabled()
+        || options.socks.is_some()
+        || options.chaos.affects_packets()
+    {
+        transport_config.enable_segmentation_offload(false);
+    }
+
+    for (x, name) in [(options.initial_mtu, "initial"), (options.max_mtu, "max")] {
+        if let Some(x) = x.filter(|x| *x < MIN_MTU) {
+            return Err(TunnelError::Config(format!(
+                "the {name} MTU of {x} is below the {MIN_MTU} bytes QUIC needs. on smaller links, lower the MTU of what goes through the tunnel instead"
+            )));
+        }
+    }
+
+    if let (Some(initial), Some(max)) = (options.initial_mtu, options.max_mtu) {
+        if max < initial {
+            return Err(TunnelError::Config(format!(
+                "the max MTU of {max} is below the initial MTU of {initial}"
+            )));
+        }
+    }
+
+    if let Some(x) = options.initial_mtu {
+        transport_config.initial_mtu(x);
+    }
+
+    if options.mtu_discovery == Some(false) {
+        transport_config.mtu_discovery_config(None);
+    } else if let Some(x) = options.max_mtu {
+        let mut discovery = MtuDiscoveryConfig::default();
+        discovery.upper_bound(x);
 
-    // TODO: MTU discovery
+        transport_config.mtu_discovery_config(Some(discovery));
+    }
 
-    Arc::new(transport_config)
+    Ok(transport_config)
 }
 
-/// TODO: builder pattern
-pub fn build_client_endpoint(
+/// run `f`, logging how big a datagram fits on `conn`'s path when it starts and each time MTU discovery changes it
+pub async fn log_path_mtu<T>(conn: &Connection, f: impl Future<Output = T>) -> T {
+    let watch = async {
+        let mut last = None;
+        let mut i = interval(MTU_CHECK_INTERVAL);
+
+        loop {
+            i.tick().await;
+
+            let x = conn.max_datagram_size();
+
+            if x != last {
+                if let Some(max_datagram) = x {
+                    info!(max_datagram, "path MTU");
+                }
+
+                last = x;
+            }
+        }
+    };
+
+    select! {
+        x = f => x,
+        _ = watch => unreachable!(),
+    }
+}
+
+/// log what the kernel lets quinn do with UDP, since a broken offload looks like packet loss.
+/// the sendmmsg/recvmmsg batch size is a constant in quinn-udp, so it can only be reported
+pub fn log_udp_offload(transport: &TransportOptions) {
+    let state = quinn::udp::UdpState::new();
+
+    let gso_supported = state.max_gso_segments() > 1;
+
+    info!(
+        gso = gso_supported
+            && transport.gso.unwrap_or(true)
+            && !transport.obfuscation.is_enabled()
+            && transport.socks.is_none(),
+        gso_supported,
+        max_gso_segments = state.max_gso_segments(),
+        gro_segments = state.gro_segments(),
+        batch_size = quinn::udp::BATCH_SIZE,
+        "UDP offload"
+    );
+}
+
+/// the QUIC and TLS settings for connecting to a server, shared by every endpoint a client makes
+pub fn quic_client_config(
     ca: PathBuf,
     cert: PathBuf,
     key: PathBuf,
-    congestion_mode: CongestionMode,
-    keep_alive: bool,
-) -> anyhow::Result<Endpoint> {
-    let tls_config = tls::build_client_config(ca, cert, key)?;
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+    role: Role,
+) -> Result<ClientConfig, TunnelError> {
+    let mut tls_config = tls::build_client_config(ca, cert, key, tls_options)
+        .map_err(|err| TunnelError::Tls(err.into()))?;
+
+    tls_config.alpn_protocols = vec![role.alpn().to_vec()];
 
     let mut client_config = ClientConfig::new(Arc::new(tls_config));
 
-    let transport_config = build_transport_config(keep_alive, congestion_mode);
+    let transport_config = build_transport_config(transport)?;
 
     client_config.transport_config(transport_config);
 
     trace!(?client_config);
 
+    Ok(client_config)
+}
+
+/// TODO: builder pattern
+pub fn build_client_endpoint(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+    role: Role,
+) -> Result<Endpoint, TunnelError> {
+    let client_config = quic_client_config(ca, cert, key, transport, tls_options, role)?;
+
+    log_udp_offload(transport);
+
     // TODO: do we need to be careful about ipv4 vs ipv6 here?
     // TODO: io_uring
-    let mut endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
+    let bind = transport.bind.udp_addr();
+
+    let runtime = endpoint_runtime(transport)?;
+
+    let mut endpoint = transport
+        .bind
+        .udp_socket()
+        .and_then(|socket| Endpoint::new(EndpointConfig::default(), None, socket, runtime))
+        .map_err(|source| TunnelError::Bind { addr: bind, source })?;
 
     endpoint.set_default_client_config(client_config);
 
     Ok(endpoint)
 }
 
+/// How a server makes new clients prove their address before doing any handshake work for them.
+///
+/// A client with a spoofed address never sees the retry, so a flood of fake handshakes costs the server one small packet
+/// each instead of a cert, and can't be bounced at someone else. Quinn already caps what it sends to an address that
+/// hasn't proven itself at three times what it got from it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryOptions {
+    /// costs every client a round trip, and any 0-RTT data it sent with its first packet
+    pub require: bool,
+    /// how long a client has to come back with the token from its retry
+    #[serde(with = "humantime_serde")]
+    pub token_lifetime: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            require: true,
+            token_lifetime: Duration::from_secs(15),
+        }
+    }
+}
+
 /// TODO: builder pattern
+#[allow(clippy::too_many_arguments)]
 pub fn build_server_endpoint(
     ca: PathBuf,
     cert: PathBuf,
     key: PathBuf,
-    stateless_retry: bool,
+    retry: &RetryOptions,
     listen: SocketAddr,
-    congestion_mode: CongestionMode,
-    keep_alive: bool,
-) -> anyhow::Result<Endpoint> {
-    let (tls_config, _root_ca) = tls::build_server_config(ca, cert, key)?;
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+    roles: &[Role],
+) -> Result<Endpoint, TunnelError> {
+    let sockets = bind_server_sockets(&[listen], 1)?;
+
+    let mut x =
+        build_server_endpoints(ca, cert, key, retry, transport, tls_options, roles, sockets)?;
+
+    Ok(x.remove(0))
+}
+
+/// Bind `count` UDP sockets to each address in `listen`. More than one per address are bound with SO_REUSEPORT.
+///
+/// With more than one address, IPv6 sockets only take IPv6, so `[::]:8443` and `0.0.0.0:8443` can both be bound
+/// no matter if the platform's IPv6 sockets take IPv4 by default.
+///
+/// One socket is read by one task, so more sockets spread the UDP work over more cores. The kernel picks a socket by hashing the peer's address,
+/// so a client that changes address (connection migration) may land on an endpoint that doesn't know it.
+///
+/// Sockets handed over by an upgrade are used instead of binding new ones.
+pub fn bind_server_sockets(
+    listen: &[SocketAddr],
+    count: usize,
+) -> Result<Vec<QuicSocket>, TunnelError> {
+    let only_v6 = listen.len() > 1;
+
+    let mut sockets = Vec::with_capacity(listen.len() * count);
+
+    for &listen in listen {
+        let bind_err = |source| TunnelError::Bind {
+            addr: listen,
+            source,
+        };
+
+        // the first socket decides the port if `listen` is port 0
+        let mut addr = listen;
+
+        for _ in 0..count {
+            let socket = match upgrade::take(&ListenTarget::Udp(listen)) {
+                Some(fd) => std::net::UdpSocket::from(fd),
+                None => bind_udp(addr, count > 1, only_v6 && addr.is_ipv6()).map_err(bind_err)?,
+            };
+
+            addr = socket.local_addr().map_err(bind_err)?;
+
+            sockets.push(QuicSocket::new(listen, socket));
+        }
+    }
+
+    Ok(sockets)
+}
+
+fn bind_udp(
+    addr: SocketAddr,
+    reuse_port: bool,
+    only_v6: bool,
+) -> std::io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    if !reuse_port && !only_v6 {
+        return std::net::UdpSocket::bind(addr);
+    }
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// the QUIC and TLS settings for taking tunnel clients, shared by every endpoint a server makes.
+/// clients that offer a role not in `roles` fail the handshake
+pub fn quic_server_config(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    retry: &RetryOptions,
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+    roles: &[Role],
+) -> Result<ServerConfig, TunnelError> {
+    let (mut tls_config, _root_ca) = tls::build_server_config(ca, cert, key, tls_options)
+        .map_err(|err| TunnelError::Tls(err.into()))?;
+
+    tls_config.alpn_protocols = roles.iter().map(|x| x.alpn().to_vec()).collect();
 
     let mut server_config = ServerConfig::with_crypto(Arc::new(tls_config));
 
-    let transport_config = build_transport_config(keep_alive, congestion_mode);
+    let transport_config = build_transport_config(transport)?;
 
     server_config.transport_config(transport_config);
 
     // Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
-    server_config.use_retry(stateless_retry);
+    server_config.use_retry(retry.require);
+    server_config.retry_token_lifetime(retry.token_lifetime);
 
-    // TODO: no uni streams
-    // TODO: lots more bi streams
+    server_config.migration(transport.migration.unwrap_or(true));
 
     trace!(?server_config);
 
+    Ok(server_config)
+}
+
+/// Like `build_server_endpoint`, but with an endpoint on each of `sockets`. See `bind_server_sockets`.
+///
+/// If upgrades are enabled, every connection id starts with `upgrade::cid_prefix`. See the `upgrade` module.
+#[allow(clippy::too_many_arguments)]
+pub fn build_server_endpoints(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    retry: &RetryOptions,
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+    roles: &[Role],
+    sockets: Vec<QuicSocket>,
+) -> Result<Vec<Endpoint>, TunnelError> {
+    let server_config = quic_server_config(ca, cert, key, retry, transport, tls_options, roles)?;
+
+    log_udp_offload(transport);
+
+    let runtime = endpoint_runtime(transport)?;
+
+    // cloned configs share their keys, so a retry token or stateless reset from one endpoint is accepted by the others
+    let mut endpoint_config = EndpointConfig::default();
+
+    if let Some(x) = upgrade::cid_prefix() {
+        endpoint_config.cid_generator(move || upgrade::cid_generator(x));
+    }
+
+    let mut endpoints = Vec::with_capacity(sockets.len());
+
     // TODO: io_uring
-    let endpoint = Endpoint::server(server_config, listen)?;
+    for socket in sockets {
+        let addr = socket.local_addr()?;
 
-    Ok(endpoint)
+        let endpoint = socket
+            .into_async(&*runtime)
+            .and_then(|socket| {
+                Endpoint::new_with_abstract_socket(
+                    endpoint_config.clone(),
+                    Some(server_config.clone()),
+                    socket,
+                    runtime.clone(),
+                )
+            })
+            .map_err(|source| TunnelError::Bind { addr, source })?;
+
+        endpoints.push(endpoint);
+    }
+
+    Ok(endpoints)
+}
+
+/// quinn's runtime, with `transport.obfuscation`, ECN counting, and then `transport.chaos` around every socket it is given
+fn endpoint_runtime(transport: &TransportOptions) -> Result<Arc<dyn Runtime>, TunnelError> {
+    let runtime = match (&transport.socks, transport.obfuscation.runtime()?) {
+        // the shim goes inside the SOCKS header
+        (Some(x), _) => x.runtime(transport.obfuscation.transport()?),
+        (None, Some(x)) => x,
+        (None, None) => quinn::default_runtime()
+            .ok_or_else(|| TunnelError::Io(std::io::Error::other("no async runtime found")))?,
+    };
+
+    let runtime = ecn::runtime(transport.ecn != Some(false), runtime);
+
+    if !transport.chaos.affects_packets() {
+        return Ok(runtime);
+    }
+
+    #[cfg(feature = "chaos")]
+    return Ok(crate::chaos::runtime(&transport.chaos, runtime));
+
+    #[cfg(not(feature = "chaos"))]
+    Err(TunnelError::Unsupported(
+        "dropping and delaying packets without the chaos feature",
+    ))
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &socket2::Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &socket2::Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "SO_REUSEPORT isn't available on this platform",
+    ))
+}
+
+/// finish a client's handshake.
+///
+/// with `zero_rtt` and a session ticket from an earlier connection on the same endpoint, this returns immediately and data can be sent before the handshake completes.
+/// the future says if the server accepted that data. if it didn't, streams opened before then are gone and quinn doesn't wake anyone waiting on them.
+pub async fn connect_with_0rtt(
+    connecting: Connecting,
+    zero_rtt: bool,
+) -> Result<(Connection, Option<ZeroRttAccepted>), TunnelError> {
+    if zero_rtt {
+        match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                trace!("0-rtt attempted");
+
+                return Ok((connection, Some(accepted)));
+            }
+            Err(connecting) => {
+                return Ok((finish_handshake(connecting).await?, None));
+            }
+        }
+    }
+
+    Ok((finish_handshake(connecting).await?, None))
+}
+
+async fn finish_handshake(connecting: Connecting) -> Result<Connection, TunnelError> {
+    let max_wait = Duration::from_secs(30);
+
+    let connection = timeout(max_wait, connecting)
+        .await
+        .map_err(|_| TunnelError::Timeout(max_wait))??;
+
+    Ok(connection)
 }
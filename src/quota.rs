@@ -0,0 +1,91 @@
+//! Byte quotas for each client identity, on top of the counts in the `usage` module.
+//!
+//! A client over its quota isn't handed new users. The streams it has keep going, and other clients take the users it
+//! would have had. The server tells it why with an `Error` on the control stream with [`CloseCode::QuotaExceeded`], or
+//! with `disconnect`, closes its connection with that code.
+//!
+//! Monthly quotas are for calendar months in UTC. Without a usage file, they start over when the server restarts.
+//!
+//! [`CloseCode::QuotaExceeded`]: crate::protocol::CloseCode::QuotaExceeded
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::usage::UsageStore;
+
+/// how often a client over its quota is checked again, for when a new month starts
+pub const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    /// bytes in both directions each calendar month
+    pub monthly: Option<u64>,
+    /// bytes in both directions on one tunnel connection
+    pub per_connection: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaOptions {
+    /// for clients that aren't in `clients`
+    pub monthly: Option<u64>,
+    pub per_connection: Option<u64>,
+    /// keyed by certificate fingerprint, like in `usage`. these replace the defaults for that client
+    pub clients: BTreeMap<String, QuotaLimits>,
+    /// close the connection of a client over its quota instead of only not handing it users
+    pub disconnect: bool,
+}
+
+impl QuotaOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.monthly.is_some() || self.per_connection.is_some() || !self.clients.is_empty()
+    }
+
+    /// what applies to `identity`. clients without a certificate yet, during a 0-RTT handshake, get the defaults
+    pub fn limits(&self, identity: Option<&str>) -> QuotaLimits {
+        match identity.and_then(|x| self.clients.get(x)) {
+            Some(x) => x.clone(),
+            None => QuotaLimits {
+                monthly: self.monthly,
+                per_connection: self.per_connection,
+            },
+        }
+    }
+
+    /// why `identity` can't have more users, if it can't. `connection_bytes` is what its connection has moved
+    pub fn exceeded(
+        &self,
+        identity: Option<&str>,
+        usage: &UsageStore,
+        connection_bytes: u64,
+    ) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let limits = self.limits(identity);
+
+        if let Some(x) = limits.per_connection {
+            if connection_bytes >= x {
+                return Some(format!(
+                    "used {connection_bytes} bytes of its {x} byte quota for one connection"
+                ));
+            }
+        }
+
+        if let (Some(x), Some(identity)) = (limits.monthly, identity) {
+            let used = usage.month_bytes(identity);
+
+            if used >= x {
+                return Some(format!(
+                    "used {used} bytes of its {x} byte quota for this month"
+                ));
+            }
+        }
+
+        None
+    }
+}
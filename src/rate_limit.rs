@@ -0,0 +1,143 @@
+//! Token buckets for capping how much bandwidth one tunnel client can use and how fast users can connect.
+
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use moka::future::{Cache, CacheBuilder};
+use tokio::time::{sleep, Instant};
+
+/// `rate` bytes per second with up to `burst` bytes at once.
+///
+/// Callers take what they need even if it goes negative and then wait off the debt, so a read bigger than the burst still gets through.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// starts full. `rate` must be more than 0
+    pub fn new(rate: u64, burst: u64) -> Self {
+        assert!(rate > 0, "rate must be more than 0");
+
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated_at).as_secs_f64();
+
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.updated_at = now;
+    }
+
+    /// take `n` bytes. waits if the bucket is empty
+    pub async fn consume(&self, n: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+
+            self.refill(&mut state);
+
+            state.tokens -= n as f64;
+
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+
+    /// take `n` if they are there. never goes into debt
+    pub fn try_consume(&self, n: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        self.refill(&mut state);
+
+        if state.tokens < n as f64 {
+            return false;
+        }
+
+        state.tokens -= n as f64;
+
+        true
+    }
+}
+
+/// one bucket for each direction. shared by every stream of a tunnel client
+#[derive(Clone, Debug)]
+pub struct ClientRateLimit {
+    /// read from QUIC and written to the local side
+    pub from_tunnel: Arc<TokenBucket>,
+    /// read from the local side and written to QUIC
+    pub to_tunnel: Arc<TokenBucket>,
+}
+
+impl ClientRateLimit {
+    /// `rate` is bytes per second in each direction. `burst` defaults to one second of `rate`
+    pub fn new(rate: u64, burst: Option<u64>) -> Self {
+        let burst = burst.unwrap_or(rate);
+
+        Self {
+            from_tunnel: Arc::new(TokenBucket::new(rate, burst)),
+            to_tunnel: Arc::new(TokenBucket::new(rate, burst)),
+        }
+    }
+}
+
+/// new user connections per second, for the whole server and for each source address.
+/// the burst is one second's worth
+pub struct AcceptRateLimit {
+    global: Option<TokenBucket>,
+    per_ip_rate: Option<u64>,
+    /// an idle bucket is full again after a second, so forgetting it changes nothing
+    per_ip: Cache<IpAddr, Arc<TokenBucket>>,
+}
+
+impl AcceptRateLimit {
+    pub fn new(global: Option<u64>, per_ip: Option<u64>) -> Self {
+        Self {
+            global: global.map(|x| TokenBucket::new(x, x)),
+            per_ip_rate: per_ip,
+            per_ip: CacheBuilder::new(100_000)
+                .time_to_idle(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    /// false if this connection should be refused. connections without an address (unix sockets) only count globally
+    pub async fn allow(&self, ip: Option<IpAddr>) -> bool {
+        // check the address first so one noisy address doesn't use up everyone's allowance
+        if let (Some(rate), Some(ip)) = (self.per_ip_rate, ip) {
+            let bucket = self
+                .per_ip
+                .get_with(ip, async move { Arc::new(TokenBucket::new(rate, rate)) })
+                .await;
+
+            if !bucket.try_consume(1) {
+                return false;
+            }
+        }
+
+        self.global.as_ref().is_none_or(|x| x.try_consume(1))
+    }
+}
@@ -0,0 +1,397 @@
+//! What the server is doing right now: connected tunnel clients and the streams they are carrying.
+//!
+//! Entries are removed when their guard is dropped, so a task that exits for any reason cleans up after itself.
+//!
+//! The server can also keep each client identity, its cert's fingerprint, to a few connections at once. See
+//! [`IdentityLimit`].
+//!
+//! A client that reconnects says which session it is continuing. The server closes the connections that session left
+//! behind, which are usually from before the client lost its network and haven't timed out yet. Otherwise they would
+//! keep taking users that only wait for them to time out. A session only counts with the cert it started with, so
+//! another client can't end it by sending the same id.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use strum::EnumString;
+use tracing::info;
+
+use crate::counters::{PathSnapshot, StreamCounters};
+use crate::protocol::CloseCode;
+
+#[derive(Debug)]
+struct ClientEntry {
+    conn: Connection,
+    connected_at: Instant,
+    protocol_version: Option<u8>,
+    /// `None` until the handshake is done
+    fingerprint: Option<String>,
+    /// `None` until the client says. clients from before `SESSION_VERSION` never do
+    session: Option<u64>,
+}
+
+#[derive(Debug)]
+struct StreamEntry {
+    client_id: u64,
+    route: String,
+    peer_addr: Option<SocketAddr>,
+    started_at: Instant,
+    counters: Arc<StreamCounters>,
+}
+
+/// What happens to a connection that puts its identity over `IdentityLimit::max`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive, serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityLimitPolicy {
+    /// the new connection takes over. usually the old one is from before the client lost its network, and is already dead
+    #[default]
+    CloseOldest,
+    /// the new connection is refused, and the client tries again later
+    RejectNewest,
+}
+
+/// How many connections one client cert can hold at once. A multipath client holds one for each path.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdentityLimit {
+    pub max: usize,
+    #[serde(default)]
+    pub policy: IdentityLimitPolicy,
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    started_at: Instant,
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, ClientEntry>>,
+    streams: Mutex<HashMap<u64, StreamEntry>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    pub connected_secs: u64,
+    pub streams: usize,
+    pub rtt_ms: u128,
+    /// UDP payload bytes, including QUIC overhead
+    pub udp_tx_bytes: u64,
+    pub udp_rx_bytes: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    /// `None` until the client's hello is answered
+    pub protocol_version: Option<u8>,
+    /// the rest of quinn's path stats, like the congestion window
+    #[serde(default)]
+    pub path: PathSnapshot,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamInfo {
+    pub id: u64,
+    pub client_id: u64,
+    pub route: String,
+    pub peer_addr: Option<SocketAddr>,
+    pub age_secs: u64,
+    pub bytes_from_tunnel: u64,
+    pub bytes_to_tunnel: u64,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            next_id: AtomicU64::new(1),
+            clients: Default::default(),
+            streams: Default::default(),
+        }
+    }
+}
+
+impl Registry {
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, atomic::Ordering::Relaxed)
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    /// track a tunnel client until the guard is dropped
+    pub fn add_client(self: &Arc<Self>, conn: Connection) -> ClientGuard {
+        let id = self.next_id();
+
+        self.clients.lock().unwrap().insert(
+            id,
+            ClientEntry {
+                conn,
+                connected_at: Instant::now(),
+                protocol_version: None,
+                fingerprint: None,
+                session: None,
+            },
+        );
+
+        ClientGuard {
+            id,
+            registry: self.clone(),
+        }
+    }
+
+    /// track a user stream until the guard is dropped
+    pub fn add_stream(
+        self: &Arc<Self>,
+        client_id: u64,
+        route: String,
+        peer_addr: Option<SocketAddr>,
+        counters: StreamCounters,
+    ) -> StreamGuard {
+        let id = self.next_id();
+        let counters = Arc::new(counters);
+
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamEntry {
+                client_id,
+                route,
+                peer_addr,
+                started_at: Instant::now(),
+                counters: counters.clone(),
+            },
+        );
+
+        StreamGuard {
+            id,
+            counters,
+            registry: self.clone(),
+        }
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        // lock streams first everywhere so we can't deadlock
+        let streams = self.streams.lock().unwrap();
+        let clients = self.clients.lock().unwrap();
+
+        let mut x: Vec<_> = clients
+            .iter()
+            .map(|(id, entry)| {
+                let stats = entry.conn.stats();
+
+                ClientInfo {
+                    id: *id,
+                    remote_addr: entry.conn.remote_address(),
+                    connected_secs: entry.connected_at.elapsed().as_secs(),
+                    streams: streams.values().filter(|s| s.client_id == *id).count(),
+                    rtt_ms: stats.path.rtt.as_millis(),
+                    udp_tx_bytes: stats.udp_tx.bytes,
+                    udp_rx_bytes: stats.udp_rx.bytes,
+                    sent_packets: stats.path.sent_packets,
+                    lost_packets: stats.path.lost_packets,
+                    protocol_version: entry.protocol_version,
+                    path: PathSnapshot::from(&stats),
+                }
+            })
+            .collect();
+
+        x.sort_by_key(|c| c.id);
+
+        x
+    }
+
+    pub fn streams(&self) -> Vec<StreamInfo> {
+        let streams = self.streams.lock().unwrap();
+
+        let mut x: Vec<_> = streams
+            .iter()
+            .map(|(id, entry)| StreamInfo {
+                id: *id,
+                client_id: entry.client_id,
+                route: entry.route.clone(),
+                peer_addr: entry.peer_addr,
+                age_secs: entry.started_at.elapsed().as_secs(),
+                bytes_from_tunnel: entry.counters.from_tunnel(),
+                bytes_to_tunnel: entry.counters.to_tunnel(),
+            })
+            .collect();
+
+        x.sort_by_key(|s| s.id);
+
+        x
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    /// close a tunnel client's connection. its streams stop with it. returns false if there is no client with that id
+    pub fn kill_client(&self, id: u64, reason: &str) -> bool {
+        let clients = self.clients.lock().unwrap();
+
+        let Some(entry) = clients.get(&id) else {
+            return false;
+        };
+
+        entry
+            .conn
+            .close(CloseCode::Kicked.into(), reason.as_bytes());
+
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct ClientGuard {
+    id: u64,
+    registry: Arc<Registry>,
+}
+
+impl ClientGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn set_protocol_version(&self, x: u8) {
+        if let Some(entry) = self.registry.clients.lock().unwrap().get_mut(&self.id) {
+            entry.protocol_version = Some(x);
+        }
+    }
+
+    /// record the client's cert fingerprint, and hold its identity to `limit`. false if this connection was refused for it
+    pub fn set_identity(&self, fingerprint: &str, limit: Option<&IdentityLimit>) -> bool {
+        let mut clients = self.registry.clients.lock().unwrap();
+
+        let session = clients.get(&self.id).and_then(|x| x.session);
+
+        // ids go up, so this is oldest first. closed connections stay here until their task is done.
+        // our own session's are about to be taken over, so they don't count
+        let mut others: Vec<_> = clients
+            .iter()
+            .filter(|(id, x)| {
+                **id != self.id
+                    && x.fingerprint.as_deref() == Some(fingerprint)
+                    && x.conn.close_reason().is_none()
+                    && (session.is_none() || x.session != session)
+            })
+            .map(|(id, x)| (*id, x.conn.clone()))
+            .collect();
+
+        others.sort_by_key(|(id, _)| *id);
+
+        if let Some(limit) = limit.filter(|x| others.len() >= x.max) {
+            match limit.policy {
+                IdentityLimitPolicy::CloseOldest => {
+                    for (id, conn) in others.iter().take(others.len() + 1 - limit.max) {
+                        info!(
+                            old = id,
+                            "a newer connection with this client cert takes over"
+                        );
+
+                        conn.close(
+                            CloseCode::Replaced.into(),
+                            b"another connection with this client cert took over",
+                        );
+                    }
+                }
+                IdentityLimitPolicy::RejectNewest => {
+                    if let Some(entry) = clients.get(&self.id) {
+                        entry.conn.close(
+                            CloseCode::TooManyConnections.into(),
+                            b"this client cert already has as many connections as the server allows",
+                        );
+                    }
+
+                    return false;
+                }
+            }
+        }
+
+        if let Some(entry) = clients.get_mut(&self.id) {
+            entry.fingerprint = Some(fingerprint.to_string());
+        }
+
+        self.take_over(&clients);
+
+        true
+    }
+
+    /// record the session the client says this connection continues. whichever of this and `set_identity` comes last
+    /// takes the session over
+    pub fn set_session(&self, session: u64) {
+        let mut clients = self.registry.clients.lock().unwrap();
+
+        if let Some(entry) = clients.get_mut(&self.id) {
+            entry.session = Some(session);
+        }
+
+        self.take_over(&clients);
+    }
+
+    /// close the older connections from this one's session and cert
+    fn take_over(&self, clients: &HashMap<u64, ClientEntry>) {
+        let Some(ClientEntry {
+            fingerprint: Some(fingerprint),
+            session: Some(session),
+            ..
+        }) = clients.get(&self.id)
+        else {
+            return;
+        };
+
+        for (id, x) in clients {
+            if *id < self.id
+                && x.session == Some(*session)
+                && x.fingerprint.as_ref() == Some(fingerprint)
+                && x.conn.close_reason().is_none()
+            {
+                info!(
+                    old = id,
+                    session = format_args!("{session:016x}"),
+                    "the client reconnected. this connection takes over its session"
+                );
+
+                x.conn
+                    .close(CloseCode::Superseded.into(), b"the client connected again");
+            }
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.registry.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamGuard {
+    id: u64,
+    counters: Arc<StreamCounters>,
+    registry: Arc<Registry>,
+}
+
+impl StreamGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// pass these to the copy loop
+    pub fn counters(&self) -> Arc<StreamCounters> {
+        self.counters.clone()
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.registry.streams.lock().unwrap().remove(&self.id);
+    }
+}
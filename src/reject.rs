@@ -0,0 +1,135 @@
+//! When a user connection is turned away, a bare RST is confusing. Optionally write a short hint first.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tracing::{debug, trace};
+
+use crate::stream::Stream;
+
+/// how long to wait for the user to say something so we can guess their protocol
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    NoTunnelClient,
+    QuotaExceeded,
+    AccessDenied,
+    /// too many new connections, from everyone or from the user's address
+    RateLimited,
+    /// too many open connections, on the server or the listener
+    AtCapacity,
+}
+
+impl RejectReason {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NoTunnelClient => "no tunnel client is connected",
+            Self::QuotaExceeded => "the tunnel quota has been exceeded",
+            Self::AccessDenied => "access denied",
+            Self::RateLimited => "too many connections. try again later",
+            Self::AtCapacity => "the server is full. try again later",
+        }
+    }
+
+    fn http_status(&self) -> &'static str {
+        match self {
+            Self::NoTunnelClient => "503 Service Unavailable",
+            Self::QuotaExceeded => "429 Too Many Requests",
+            Self::AccessDenied => "403 Forbidden",
+            Self::RateLimited => "429 Too Many Requests",
+            Self::AtCapacity => "503 Service Unavailable",
+        }
+    }
+}
+
+/// the protocols we know how to say "no" in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SniffedProtocol {
+    Http,
+    Ssh,
+    Unknown,
+}
+
+impl SniffedProtocol {
+    pub fn detect(first_bytes: &[u8]) -> Self {
+        const HTTP_METHODS: &[&[u8]] = &[
+            b"GET ",
+            b"HEAD ",
+            b"POST ",
+            b"PUT ",
+            b"DELETE ",
+            b"OPTIONS ",
+            b"PATCH ",
+            b"CONNECT ",
+        ];
+
+        if first_bytes.starts_with(b"SSH-") {
+            Self::Ssh
+        } else if HTTP_METHODS.iter().any(|x| first_bytes.starts_with(x)) {
+            Self::Http
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// close a user connection. if `hint` is true, try to tell them why in a way their client will display.
+pub async fn reject(stream: Stream, reason: RejectReason, hint: bool) {
+    debug!(?stream, ?reason, "rejecting user connection");
+
+    if !hint {
+        return;
+    }
+
+    let x = match stream {
+        Stream::Tcp(mut x) => write_hint(&mut x, reason).await,
+        Stream::Unix(mut x) => write_hint(&mut x, reason).await,
+        Stream::NamedPipe(mut x) => write_hint(&mut x, reason).await,
+        Stream::Vsock(mut x) => write_hint(&mut x, reason).await,
+        Stream::Stdio(mut x) => write_hint(&mut x, reason).await,
+        // a browser would only see the bytes, so there is nothing to tell it
+        Stream::Udp(_) | Stream::WebTransport(_) => Ok(()),
+    };
+
+    if let Err(err) = x {
+        trace!(?err, "failed writing rejection hint");
+    }
+}
+
+async fn write_hint<T: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut T,
+    reason: RejectReason,
+) -> std::io::Result<()> {
+    let mut buf = [0; 16];
+
+    // we are closing the connection anyways, so it is fine to consume these bytes
+    let n = match timeout(SNIFF_TIMEOUT, io.read(&mut buf)).await {
+        Ok(x) => x?,
+        Err(_) => 0,
+    };
+
+    let protocol = SniffedProtocol::detect(&buf[..n]);
+
+    trace!(?protocol, "sniffed rejected connection");
+
+    let message = reason.message();
+
+    let response = match protocol {
+        SniffedProtocol::Http => format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+            reason.http_status(),
+            message.len() + 1,
+            message,
+        ),
+        // servers may send lines before their version string. clients will usually show them
+        SniffedProtocol::Ssh => format!("quic-tunnel: {message}\r\n"),
+        SniffedProtocol::Unknown => return Ok(()),
+    };
+
+    io.write_all(response.as_bytes()).await?;
+    io.shutdown().await?;
+
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! Two peers behind NATs meet at a server, then connect straight to each other with UDP hole punching.
+//!
+//! A `peer_server` connects to the rendezvous with [`Role::Rendezvous`] and sends `Meet` with its name and `listen`. A
+//! `peer_client` sends `Meet` with the same name. The server tells each the address it sees the other's packets come from,
+//! which is the other's NAT mapping. Then, at the same time, the peer server sends QUIC packets toward the peer client so
+//! its NAT lets the client in, and the peer client connects to the peer server with [`Role::Peer`]. Both use the socket
+//! they reached the rendezvous on, so the mappings the server saw are the ones that are punched.
+//!
+//! NATs that map each destination to a different port (symmetric NATs) can't be punched. If the direct connection isn't up
+//! in [`PUNCH_TIMEOUT`], the peer client opens its streams on its rendezvous connection instead, and the server relays
+//! them to the peer server. Direct connections are TLS between the peers, with certs from the same CA. Relayed streams are
+//! decrypted and encrypted again by the server, like every other stream it forwards.
+//!
+//! Any client with a cert from the CA can take a name that isn't taken, or reach a peer with one.
+//!
+//! [`Role::Rendezvous`]: crate::protocol::Role::Rendezvous
+//! [`Role::Peer`]: crate::protocol::Role::Peer
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, SendStream};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, info, trace, warn, Instrument};
+
+use crate::compress::{copy_bidirectional_with_compression, CompressAlgo};
+use crate::counters::{ScopedCounters, StreamCounters};
+use crate::protocol::{CloseCode, ControlMessage, PREAMBLE_TIMEOUT};
+use crate::stream::Stream;
+
+/// how long a peer client tries to connect straight to the peer server before using the relay. long enough for a few of
+/// QUIC's retransmits, since the first packets usually arrive before the peer server's NAT is punched
+pub const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// peer clients waiting to be punched toward. more are turned away
+const PENDING_PEERS: usize = 64;
+
+#[derive(Clone, Debug)]
+struct Listening {
+    conn: Connection,
+    /// where each new peer client's packets come from
+    peers: flume::Sender<SocketAddr>,
+}
+
+/// the server's side: names and the peer servers that took them
+#[derive(Debug, Default)]
+pub struct Rendezvous {
+    listening: Mutex<HashMap<String, Listening>>,
+}
+
+impl Rendezvous {
+    /// read `conn`'s `Meet`, then keep its name until it closes, or introduce it to the peer with the name and relay its
+    /// streams
+    pub async fn serve(&self, conn: &Connection, counts: ScopedCounters) -> anyhow::Result<()> {
+        let (tx, mut rx) = conn.accept_bi().await?;
+
+        let (name, listen) = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+            Some(ControlMessage::Meet { name, listen }) => (name, listen),
+            x => {
+                conn.close(CloseCode::ProtocolError.into(), b"expected Meet");
+
+                anyhow::bail!("expected Meet, got {x:?}");
+            }
+        };
+
+        match listen {
+            true => self.listen(conn, name, tx).await,
+            false => self.introduce(conn, name, tx, counts).await,
+        }
+    }
+
+    async fn listen(
+        &self,
+        conn: &Connection,
+        name: String,
+        mut tx: SendStream,
+    ) -> anyhow::Result<()> {
+        let (peers, rx) = flume::bounded(PENDING_PEERS);
+
+        {
+            let mut listening = self.listening.lock().unwrap();
+
+            // one that closed without being removed yet can be replaced
+            if let Some(x) = listening.get(&name) {
+                if x.conn.close_reason().is_none() {
+                    conn.close(
+                        CloseCode::Incompatible.into(),
+                        b"another peer has that name",
+                    );
+
+                    anyhow::bail!("{name} is taken");
+                }
+            }
+
+            listening.insert(
+                name.clone(),
+                Listening {
+                    conn: conn.clone(),
+                    peers,
+                },
+            );
+        }
+
+        info!(%name, peer = %conn.remote_address(), "peer server listening");
+
+        let forward_peers = async {
+            while let Ok(addr) = rx.recv_async().await {
+                tx.write_all(&ControlMessage::Peer { addr }.encode()?)
+                    .await?;
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let x = select! {
+            x = forward_peers => x,
+            _ = conn.closed() => Ok(()),
+        };
+
+        let mut listening = self.listening.lock().unwrap();
+
+        if listening
+            .get(&name)
+            .is_some_and(|x| x.conn.stable_id() == conn.stable_id())
+        {
+            listening.remove(&name);
+        }
+
+        debug!(%name, "peer server gone");
+
+        x
+    }
+
+    async fn introduce(
+        &self,
+        conn: &Connection,
+        name: String,
+        mut tx: SendStream,
+        counts: ScopedCounters,
+    ) -> anyhow::Result<()> {
+        let listening = self.listening.lock().unwrap().get(&name).cloned();
+
+        let Some(listening) = listening else {
+            conn.close(CloseCode::Unreachable.into(), b"no peer has that name");
+
+            anyhow::bail!("no peer has the name {name}");
+        };
+
+        if listening.peers.try_send(conn.remote_address()).is_err() {
+            conn.close(CloseCode::Unreachable.into(), b"the peer is busy");
+
+            anyhow::bail!("too many peers are waiting for {name}");
+        }
+
+        tx.write_all(
+            &ControlMessage::Peer {
+                addr: listening.conn.remote_address(),
+            }
+            .encode()?,
+        )
+        .await?;
+
+        info!(
+            %name,
+            client = %conn.remote_address(),
+            server = %listening.conn.remote_address(),
+            "introduced peers"
+        );
+
+        // only used if punching fails
+        loop {
+            let (tx_a, rx_a) = match conn.accept_bi().await {
+                Ok(x) => x,
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                    debug!("connection closed");
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            counts.stream_opened();
+
+            let server = listening.conn.clone();
+            let counts = counts.clone();
+
+            let span = tracing::info_span!("relay", %name, stream_id = rx_a.id().index());
+
+            tokio::spawn(
+                async move {
+                    match relay_stream(&server, tx_a, rx_a, &counts).await {
+                        Ok((a_to_b, b_to_a)) => trace!(%a_to_b, %b_to_a, "relayed"),
+                        Err(err) => debug!(?err, "relay failed"),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+async fn relay_stream(
+    server: &Connection,
+    tx_a: SendStream,
+    rx_a: RecvStream,
+    counts: &ScopedCounters,
+) -> anyhow::Result<(u64, u64)> {
+    let (tx_b, rx_b) = server.open_bi().await?;
+
+    let (a_to_b, b_to_a) = tokio::try_join!(copy_half(rx_a, tx_b), copy_half(rx_b, tx_a))?;
+
+    counts.sent(a_to_b as usize, 0);
+    counts.recv(b_to_a as usize, 0);
+
+    Ok((a_to_b, b_to_a))
+}
+
+async fn copy_half(mut rx: RecvStream, mut tx: SendStream) -> anyhow::Result<u64> {
+    let n = tokio::io::copy(&mut rx, &mut tx).await?;
+
+    tx.finish().await?;
+
+    Ok(n)
+}
+
+/// open the control stream on a rendezvous connection and send `Meet`
+pub async fn meet(
+    conn: &Connection,
+    name: &str,
+    listen: bool,
+) -> anyhow::Result<(SendStream, RecvStream)> {
+    let (mut tx, rx) = conn.open_bi().await?;
+
+    let x = ControlMessage::Meet {
+        name: name.to_string(),
+        listen,
+    };
+
+    tx.write_all(&x.encode()?).await?;
+
+    Ok((tx, rx))
+}
+
+/// the next peer the rendezvous introduces. `None` once it closes the stream
+pub async fn next_peer(
+    rx: &mut RecvStream,
+    max_wait: Duration,
+) -> anyhow::Result<Option<SocketAddr>> {
+    match ControlMessage::read(rx, max_wait).await? {
+        Some(ControlMessage::Peer { addr }) => Ok(Some(addr)),
+        None => Ok(None),
+        x => anyhow::bail!("expected Peer, got {x:?}"),
+    }
+}
+
+/// send QUIC packets from `endpoint` toward `peer` until it has had time to connect, so our NAT lets it in. the peer
+/// doesn't answer them
+pub async fn punch(endpoint: &Endpoint, peer: SocketAddr) {
+    match endpoint.connect(peer, "punch") {
+        Ok(x) => {
+            let _ = timeout(PUNCH_TIMEOUT, x).await;
+        }
+        Err(err) => warn!(?err, %peer, "unable to punch"),
+    }
+}
+
+/// connect from `endpoint` straight to a peer server that is punching toward us. `None` if it isn't up in `PUNCH_TIMEOUT`
+pub async fn connect_direct(
+    endpoint: &Endpoint,
+    config: ClientConfig,
+    peer: SocketAddr,
+    server_name: &str,
+) -> Option<Connection> {
+    let x = match endpoint.connect_with(config, peer, server_name) {
+        Ok(x) => x,
+        Err(err) => {
+            warn!(?err, %peer, "unable to connect to the peer");
+            return None;
+        }
+    };
+
+    match timeout(PUNCH_TIMEOUT, x).await {
+        Ok(Ok(x)) => Some(x),
+        Ok(Err(err)) => {
+            info!(?err, %peer, "the peer refused a direct connection");
+            None
+        }
+        Err(_) => {
+            info!(%peer, "no direct connection in {PUNCH_TIMEOUT:?}");
+            None
+        }
+    }
+}
+
+/// the peer server's side: connect every stream on `conn` to `backend`, until the connection closes. `conn` is straight
+/// from the peer client, or relayed by the rendezvous
+pub async fn serve_peer_streams(
+    conn: &Connection,
+    backend: SocketAddr,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    loop {
+        let (tx, rx) = match conn.accept_bi().await {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed");
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let accepted_at = Instant::now();
+
+        counts.stream_opened();
+
+        let counts = counts.clone();
+
+        let span = tracing::info_span!("peer", stream_id = rx.id().index());
+
+        tokio::spawn(
+            async move {
+                let x = async {
+                    let stream = TcpStream::connect(backend).await?;
+
+                    copy_peer_stream(tx, rx, stream, counts, accepted_at).await
+                };
+
+                match x.await {
+                    Ok((a_to_b, b_to_a)) => trace!(%a_to_b, %b_to_a, "success"),
+                    Err(err) => debug!(?err, "peer stream failed"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// the peer client's side: open a stream on `conn` for `stream` and copy both ways until both are done
+pub async fn forward_to_peer(
+    conn: &Connection,
+    stream: TcpStream,
+    counts: ScopedCounters,
+) -> anyhow::Result<(u64, u64)> {
+    let accepted_at = Instant::now();
+
+    let (tx, rx) = conn.open_bi().await?;
+
+    counts.stream_opened();
+    counts.rtt(conn.rtt());
+
+    copy_peer_stream(tx, rx, stream, counts, accepted_at).await
+}
+
+async fn copy_peer_stream(
+    tx: SendStream,
+    rx: RecvStream,
+    stream: TcpStream,
+    counts: ScopedCounters,
+    accepted_at: Instant,
+) -> anyhow::Result<(u64, u64)> {
+    let counters = StreamCounters::new(counts, accepted_at);
+
+    let x = copy_bidirectional_with_compression(
+        CompressAlgo::None,
+        rx,
+        tx,
+        Stream::Tcp(stream),
+        Default::default(),
+        counters.into(),
+        Default::default(),
+    )
+    .await?;
+
+    Ok(x)
+}
@@ -0,0 +1,368 @@
+//! Hostnames for the addresses we connect to, so backends behind dynamic DNS keep working without a restart.
+//!
+//! Names are looked up with the system resolver and cached. A lookup older than `ResolveOptions::refresh` is done again the next time
+//! it is needed, and if that fails, the addresses we already had are used until it works again.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use strum::EnumString;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::select;
+use tokio::time::sleep;
+use tracing::{debug, info, trace, warn};
+
+use crate::stream::TcpOptions;
+use crate::{mdns, srv};
+
+/// how long a happy eyeballs attempt gets by default before the next address is tried alongside it. RFC 8305 recommends 250ms
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// an IP address, or a hostname that is looked up when we connect
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HostAddr {
+    Ip(SocketAddr),
+    Name { host: String, port: u16 },
+}
+
+impl FromStr for HostAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(x) = s.parse() {
+            return Ok(Self::Ip(x));
+        }
+
+        let err = || format!("\"{s}\" is not an address like 127.0.0.1:8080 or example.com:8080");
+
+        let (host, port) = s.rsplit_once(':').ok_or_else(err)?;
+
+        // anything with another colon was meant to be an IPv6 address
+        if host.is_empty() || host.contains(':') {
+            return Err(err());
+        }
+
+        Ok(Self::Name {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+impl Display for HostAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(x) => write!(f, "{x}"),
+            Self::Name { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl From<SocketAddr> for HostAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self::Ip(value)
+    }
+}
+
+/// serialized like it is displayed, "example.com:8080"
+impl Serialize for HostAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// which of a name's addresses to try first
+#[derive(Clone, Copy, Debug, Default, Deserialize, EnumString, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive, serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ResolveStrategy {
+    /// try IPv6 first, and if it hasn't connected in `attempt_delay`, start IPv4 alongside it (RFC 8305). good when either family might be broken
+    #[default]
+    HappyEyeballs,
+    /// try every IPv6 address, one at a time, before any IPv4 address
+    PreferIpv6,
+    /// try every IPv4 address, one at a time, before any IPv6 address
+    PreferIpv4,
+}
+
+impl ResolveStrategy {
+    /// put the addresses in the order they are tried
+    fn sort(self, addrs: &mut Vec<SocketAddr>) {
+        match self {
+            Self::PreferIpv6 => addrs.sort_by_key(|x| x.is_ipv4()),
+            Self::PreferIpv4 => addrs.sort_by_key(|x| x.is_ipv6()),
+            Self::HappyEyeballs => {
+                let (v6, v4): (Vec<_>, Vec<_>) = addrs.drain(..).partition(|x| x.is_ipv6());
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+
+                // alternate families so one that is broken only costs one attempt delay
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => break,
+                        (a, b) => addrs.extend(a.into_iter().chain(b)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// how long an attempt gets before the next one starts alongside it. `None` tries one at a time
+    fn attempt_delay(self, delay: Duration) -> Option<Duration> {
+        match self {
+            Self::HappyEyeballs => Some(delay),
+            Self::PreferIpv6 | Self::PreferIpv4 => None,
+        }
+    }
+}
+
+/// How hostnames are looked up. IP addresses ignore these.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolveOptions {
+    pub strategy: ResolveStrategy,
+    /// look a name up again once its addresses are this old. the system resolver may cache them longer
+    #[serde(with = "humantime_serde")]
+    pub refresh: Duration,
+    /// with happy_eyeballs, how long an attempt gets before the next address is tried alongside it
+    #[serde(with = "humantime_serde")]
+    pub attempt_delay: Duration,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            strategy: ResolveStrategy::default(),
+            refresh: Duration::from_secs(30),
+            attempt_delay: CONNECTION_ATTEMPT_DELAY,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lookup<T> {
+    answers: Vec<T>,
+    at: Instant,
+}
+
+type Cache<K, T> = Mutex<HashMap<K, Lookup<T>>>;
+
+/// Looks hostnames up and remembers the answers for `ResolveOptions::refresh`.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    options: ResolveOptions,
+    addrs: Cache<(String, u16), SocketAddr>,
+    srv: Cache<String, HostAddr>,
+    mdns: Cache<String, HostAddr>,
+}
+
+impl Resolver {
+    pub fn new(options: ResolveOptions) -> Self {
+        Self {
+            options,
+            addrs: Default::default(),
+            srv: Default::default(),
+            mdns: Default::default(),
+        }
+    }
+
+    /// every address for `addr`, in the order the strategy tries them
+    pub async fn resolve(&self, addr: &HostAddr) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = match addr {
+            HostAddr::Ip(x) => return Ok(vec![*x]),
+            HostAddr::Name { host, port } => (host, *port),
+        };
+
+        self.cached(&self.addrs, (host.clone(), port), host, async {
+            let mut x: Vec<_> = tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .collect();
+
+            self.options.strategy.sort(&mut x);
+
+            Ok(x)
+        })
+        .await
+    }
+
+    /// the targets of a SRV record like `_quic-tunnel._udp.example.com`, in the order RFC 2782 says to try them
+    pub async fn resolve_srv(&self, name: &str) -> io::Result<Vec<HostAddr>> {
+        self.cached(&self.srv, name.to_string(), name, async {
+            Ok(srv::lookup(name)
+                .await?
+                .into_iter()
+                .map(|x| HostAddr::Name {
+                    host: x.target,
+                    port: x.port,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// the servers advertising on the LAN, or only the instance `name`
+    pub async fn resolve_mdns(&self, name: Option<&str>) -> io::Result<Vec<HostAddr>> {
+        let key = name.unwrap_or_default().to_string();
+
+        self.cached(&self.mdns, key, "mdns", async {
+            Ok(mdns::browse(name)
+                .await?
+                .into_iter()
+                .map(HostAddr::Ip)
+                .collect())
+        })
+        .await
+    }
+
+    /// `lookup`'s answers, unless the cache has some that are new enough
+    async fn cached<K, T>(
+        &self,
+        cache: &Cache<K, T>,
+        key: K,
+        name: &str,
+        lookup: impl Future<Output = io::Result<Vec<T>>>,
+    ) -> io::Result<Vec<T>>
+    where
+        K: Eq + Hash,
+        T: Clone + Debug + PartialEq,
+    {
+        if let Some(x) = cache.lock().expect("resolver lock poisoned").get(&key) {
+            if x.at.elapsed() < self.options.refresh {
+                return Ok(x.answers.clone());
+            }
+        }
+
+        let answers = lookup.await.and_then(|x| match x.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} has no addresses"),
+            )),
+            false => Ok(x),
+        });
+
+        let mut cache = cache.lock().expect("resolver lock poisoned");
+
+        let answers = match answers {
+            Ok(x) => x,
+            // a DNS server that is down for a moment shouldn't take the backend down with it
+            Err(err) => {
+                return match cache.get(&key) {
+                    Some(x) => {
+                        warn!(
+                            ?err,
+                            name, "failed looking up. using the addresses we already had"
+                        );
+
+                        Ok(x.answers.clone())
+                    }
+                    None => Err(err),
+                }
+            }
+        };
+
+        match cache.get(&key) {
+            Some(x) if x.answers != answers => {
+                info!(name, ?answers, old = ?x.answers, "addresses changed")
+            }
+            Some(_) => trace!(name, ?answers, "addresses are the same"),
+            None => debug!(name, ?answers, "resolved"),
+        }
+
+        cache.insert(
+            key,
+            Lookup {
+                answers: answers.clone(),
+                at: Instant::now(),
+            },
+        );
+
+        Ok(answers)
+    }
+
+    /// connect to whichever of `addr`'s addresses answers first, the way the strategy says to
+    pub async fn connect_tcp(&self, addr: &HostAddr, tcp: &TcpOptions) -> io::Result<TcpStream> {
+        let addrs = self.resolve(addr).await?;
+
+        race(
+            addrs,
+            self.options
+                .strategy
+                .attempt_delay(self.options.attempt_delay),
+            |addr| async move {
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+
+                trace!(?socket, "new socket for {}", addr);
+
+                tcp.apply_to_socket(&socket)?;
+                tcp.bind.apply_to_tcp(&socket, addr)?;
+
+                let stream = socket.connect(addr).await?;
+
+                tcp.apply_to_stream(&stream)?;
+
+                Ok(stream)
+            },
+        )
+        .await
+    }
+}
+
+/// Try each address in order. A new attempt starts when the last one fails, or after `delay` while it is still going.
+/// The first to succeed wins and the rest are dropped. Fails with the last error if they all fail.
+async fn race<T, F, Fut>(
+    addrs: Vec<SocketAddr>,
+    delay: Option<Duration>,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut addrs = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    attempts.extend(addrs.next().map(&connect));
+
+    while !attempts.is_empty() {
+        select! {
+            Some(x) = attempts.next() => match x {
+                Ok(x) => return Ok(x),
+                Err(err) => {
+                    debug!(?err, "connection attempt failed");
+
+                    last_err = Some(err);
+
+                    attempts.extend(addrs.next().map(&connect));
+                }
+            },
+            _ = sleep(delay.unwrap_or_default()), if delay.is_some() && addrs.len() > 0 => {
+                attempts.extend(addrs.next().map(&connect));
+            }
+        }
+    }
+
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+}
@@ -0,0 +1,32 @@
+//! Forwarded traffic can run on its own tokio runtime so management work (stats, admin, reloads) never adds jitter to it.
+//!
+//! Quinn spawns its endpoint and connection drivers on whatever runtime is current when the endpoint is created,
+//! so creating the endpoint inside `data_plane().enter()` is enough to move packet processing there too.
+
+use std::sync::OnceLock;
+
+use tokio::runtime::{Builder, Handle, Runtime};
+use tracing::info;
+
+static DATA_PLANE: OnceLock<Handle> = OnceLock::new();
+
+/// build a multi-threaded runtime for forwarding traffic
+pub fn build_data_plane_runtime(worker_threads: usize) -> std::io::Result<Runtime> {
+    info!(worker_threads, "starting data plane runtime");
+
+    Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .thread_name("quic-tunnel-data")
+        .enable_all()
+        .build()
+}
+
+/// use this runtime for QUIC and the copy loops everywhere in the process. this can only be set once
+pub fn set_data_plane(handle: Handle) -> Result<(), Handle> {
+    DATA_PLANE.set(handle)
+}
+
+/// the runtime for QUIC and the copy loops. this is the current runtime unless `set_data_plane` was called
+pub fn data_plane() -> Handle {
+    DATA_PLANE.get().cloned().unwrap_or_else(Handle::current)
+}
@@ -0,0 +1,2592 @@
+//! The reverse proxy server. Users connect to the public listeners and their connections are forwarded through the QUIC tunnel to any connected client.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use flume::{Receiver, Sender};
+use futures::{FutureExt, TryFutureExt};
+use ipnet::IpNet;
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendStream};
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::sync::{oneshot, watch, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+use tokio::{join, select};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument, Span};
+
+use crate::admin::AdminServer;
+use crate::audit::{self, AuditEvent, AuditLog};
+use crate::chain::{self, HopOptions};
+use crate::chaos::ChaosOptions;
+use crate::compress::{copy_bidirectional_with_compression, CloseMode, CompressAlgo, CopyOptions};
+use crate::control::{self, ControlEnd, HeartbeatOptions, Liveness};
+use crate::counters::{ScopedCounters, StatsOptions, StreamCounters, TunnelCounters};
+use crate::datagram::{forward_datagrams, forward_streams, DatagramTarget};
+use crate::dump::{DebugDump, DumpOptions};
+use crate::error::TunnelError;
+use crate::h3::H3_NO_ERROR;
+use crate::health::{HealthServer, ReadyCheck};
+use crate::http_route::{self, HttpRule};
+use crate::listen::{check_listen_targets, ListenTarget, Listener};
+use crate::padding::PaddingOptions;
+use crate::pool::StreamPool;
+use crate::protocol::{
+    negotiate_version, CloseCode, ControlMessage, Role, StreamPreamble, IMPLIED_VERSION,
+    MIN_PROTOCOL_VERSION, PREAMBLE_TIMEOUT, PROTOCOL_VERSION, SESSION_VERSION,
+};
+use crate::quic::{
+    bind_server_sockets, build_server_endpoints, log_path_mtu, quic_server_config, RetryOptions,
+    TransportOptions,
+};
+use crate::quota::{self, QuotaOptions};
+use crate::rate_limit::{AcceptRateLimit, ClientRateLimit};
+use crate::registry::{ClientInfo, IdentityLimit, Registry};
+use crate::reject::{reject, RejectReason};
+use crate::rendezvous::Rendezvous;
+use crate::runtime;
+use crate::shutdown::{CancellationToken, TaskTracker};
+use crate::sni::{self, SniRule};
+use crate::stream::{PendingStream, Stream, TcpOptions};
+use crate::tcp_fallback;
+use crate::tls::{peer_fingerprint, TlsOptions};
+use crate::transform::TransformPipeline;
+use crate::transparent::{self, ConnectOptions};
+use crate::unix::UnixSocketOptions;
+use crate::upgrade::{self, OwnedFd, QuicHandover};
+use crate::usage::UsageStore;
+use crate::warm_up::WarmUp;
+use crate::webhook::{WebhookConfig, Webhooks};
+use crate::webtransport::{self, build_webtransport_endpoint, WebTransportOptions};
+
+/// how many tunnel clients can lose a user before they start its stream. after that, the user is turned away
+const MAX_HANDOFFS: u32 = 3;
+
+/// A public listener and what to do with the users that connect to it.
+#[derive(Clone, Debug, Serialize)]
+pub struct ListenerConfig {
+    pub target: ListenTarget,
+    /// sent to the client so it knows what the stream is for
+    pub route: String,
+    pub transform: TransformPipeline,
+    /// only users from these networks may connect. empty allows everyone
+    pub allow: Vec<IpNet>,
+    /// overrides the server's `compress` for this listener's streams
+    pub compress: Option<CompressAlgo>,
+    /// send tls users to other routes by the server name in their ClientHello. users no rule matches get `route`
+    pub sni: Vec<SniRule>,
+    /// send plain http users to other routes by the host and path of their first request. tls users are left to `sni`
+    pub http: Vec<HttpRule>,
+    /// turn users away while this many from this listener are open. on top of the server's `max_connections`
+    pub max_connections: Option<usize>,
+    /// the QUIC send priority of this listener's streams. when a connection is busy, higher goes first, like ssh before
+    /// backups. 0 by default
+    pub priority: i32,
+}
+
+/// serialized for the admin api's config dump
+#[derive(Debug, Serialize)]
+pub struct ReverseProxyServer {
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    quic_addrs: Vec<SocketAddr>,
+    quic_sockets: usize,
+    listeners: Vec<ListenerConfig>,
+    transport: TransportOptions,
+    tls: TlsOptions,
+    tcp: TcpOptions,
+    unix: UnixSocketOptions,
+    retry: RetryOptions,
+    compress: CompressAlgo,
+    close_mode: CloseMode,
+    padding: PaddingOptions,
+    stream_pool_size: usize,
+    reject_without_clients: bool,
+    error_hints: bool,
+    #[serde(with = "humantime_serde")]
+    warm_up: Duration,
+    warm_up_streams: usize,
+    admin_socket: Option<PathBuf>,
+    /// see the `health` module
+    health_listen: Option<SocketAddr>,
+    /// where client usage is saved. see the `usage` module
+    usage_file: Option<PathBuf>,
+    /// see the `audit` module
+    audit_log: Option<PathBuf>,
+    webhooks: Vec<WebhookConfig>,
+    quota: QuotaOptions,
+    stats: StatsOptions,
+    per_client_rate: Option<u64>,
+    per_client_burst: Option<u64>,
+    max_connection_rate: Option<u64>,
+    max_connection_rate_per_ip: Option<u64>,
+    max_connections: Option<usize>,
+    max_streams_per_client: Option<usize>,
+    identity_limit: Option<IdentityLimit>,
+    #[serde(with = "humantime_serde")]
+    stream_idle_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    stream_max_lifetime: Option<Duration>,
+    heartbeat: HeartbeatOptions,
+    /// how long to drain after an upgrade. `None` if upgrades are off
+    #[serde(with = "humantime_serde")]
+    upgrade: Option<Duration>,
+    webtransport: Option<WebTransportOptions>,
+    tcp_fallback: Option<SocketAddr>,
+    /// where datagrams from `udp_client`s on the same port go. `None` turns them away
+    forward: Option<DatagramTarget>,
+    /// where `transparent_client`s on the same port may connect. empty turns them away
+    connect: ConnectOptions,
+    /// if `peer_server`s and `peer_client`s on the same port may meet here. see the `rendezvous` module
+    rendezvous: bool,
+    /// where clients chaining through here with `--via` may send their packets. empty turns them away
+    hop: HopOptions,
+    /// see the `dump` module
+    debug_dump: Option<DumpOptions>,
+    #[serde(skip)]
+    shutdown: CancellationToken,
+    #[serde(skip)]
+    data_plane: Option<Handle>,
+}
+
+pub struct ReverseProxyServerBuilder {
+    inner: ReverseProxyServer,
+}
+
+impl ReverseProxyServer {
+    pub fn builder(
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+        quic_addr: SocketAddr,
+    ) -> ReverseProxyServerBuilder {
+        let inner = Self {
+            ca,
+            cert,
+            key,
+            quic_addrs: vec![quic_addr],
+            quic_sockets: 1,
+            listeners: vec![],
+            transport: TransportOptions::default(),
+            tls: TlsOptions::default(),
+            tcp: TcpOptions::default(),
+            unix: UnixSocketOptions::default(),
+            retry: RetryOptions::default(),
+            compress: CompressAlgo::None,
+            close_mode: CloseMode::default(),
+            padding: PaddingOptions::default(),
+            stream_pool_size: 0,
+            reject_without_clients: false,
+            error_hints: false,
+            warm_up: Duration::ZERO,
+            warm_up_streams: 16,
+            admin_socket: None,
+            health_listen: None,
+            usage_file: None,
+            audit_log: None,
+            webhooks: vec![],
+            quota: QuotaOptions::default(),
+            stats: StatsOptions::default(),
+            per_client_rate: None,
+            per_client_burst: None,
+            max_connection_rate: None,
+            max_connection_rate_per_ip: None,
+            max_connections: None,
+            max_streams_per_client: None,
+            identity_limit: None,
+            stream_idle_timeout: None,
+            stream_max_lifetime: None,
+            heartbeat: HeartbeatOptions::default(),
+            upgrade: None,
+            webtransport: None,
+            tcp_fallback: None,
+            forward: None,
+            connect: ConnectOptions::default(),
+            rendezvous: false,
+            hop: HopOptions::default(),
+            debug_dump: None,
+            shutdown: CancellationToken::new(),
+            data_plane: None,
+        };
+
+        ReverseProxyServerBuilder { inner }
+    }
+}
+
+impl ReverseProxyServerBuilder {
+    /// forward users that connect to `target`. the route name is sent to the client with every stream
+    pub fn listen(
+        self,
+        target: ListenTarget,
+        route: impl Into<String>,
+        transform: TransformPipeline,
+    ) -> Self {
+        self.listener(ListenerConfig {
+            target,
+            route: route.into(),
+            transform,
+            allow: vec![],
+            compress: None,
+            sni: vec![],
+            http: vec![],
+            max_connections: None,
+            priority: 0,
+        })
+    }
+
+    /// like `listen`, but with every listener option
+    pub fn listener(mut self, x: ListenerConfig) -> Self {
+        self.inner.listeners.push(x);
+        self
+    }
+
+    pub fn transport(mut self, x: TransportOptions) -> Self {
+        self.inner.transport = x;
+        self
+    }
+
+    pub fn tls(mut self, x: TlsOptions) -> Self {
+        self.inner.tls = x;
+        self
+    }
+
+    /// listen for QUIC on another address too, like `[::]:8443` next to `0.0.0.0:8443`. every address is one more set of endpoints
+    /// for the same listeners, streams, and counters. see `bind_server_sockets` for how IPv6 sockets are bound
+    pub fn quic_listen(mut self, x: SocketAddr) -> Self {
+        self.inner.quic_addrs.push(x);
+        self
+    }
+
+    /// bind this many QUIC sockets to each QUIC address with SO_REUSEPORT so the UDP work is spread over more cores. defaults to 1
+    pub fn quic_sockets(mut self, x: usize) -> Self {
+        self.inner.quic_sockets = x;
+        self
+    }
+
+    /// socket options for users connecting to the tcp listeners
+    pub fn tcp(mut self, x: TcpOptions) -> Self {
+        self.inner.tcp = x;
+        self
+    }
+
+    /// mode and owner for the unix listeners' socket files
+    pub fn unix(mut self, x: UnixSocketOptions) -> Self {
+        self.inner.unix = x;
+        self
+    }
+
+    /// Introduces an additional round-trip to the handshake to make denial of service attacks more difficult.
+    pub fn stateless_retry(mut self, x: bool) -> Self {
+        self.inner.retry.require = x;
+        self
+    }
+
+    /// `stateless_retry`, and how long its tokens are good for
+    pub fn retry(mut self, x: RetryOptions) -> Self {
+        self.inner.retry = x;
+        self
+    }
+
+    /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
+    pub fn compress(mut self, x: CompressAlgo) -> Self {
+        self.inner.compress = x;
+        self
+    }
+
+    /// whether an EOF from one side closes only that direction or the whole stream. defaults to half close
+    pub fn close_mode(mut self, x: CloseMode) -> Self {
+        self.inner.close_mode = x;
+        self
+    }
+
+    /// pad every stream so its sizes and timing leak less. clients pad with the same mode. see the `padding` module
+    pub fn padding(mut self, x: PaddingOptions) -> Self {
+        self.inner.padding = x;
+        self
+    }
+
+    /// how many QUIC streams to open ahead of time for each tunnel client. 0 opens them on demand
+    pub fn stream_pool_size(mut self, x: usize) -> Self {
+        self.inner.stream_pool_size = x;
+        self
+    }
+
+    /// refuse user connections while no tunnel client is connected instead of queueing them
+    pub fn reject_without_clients(mut self, x: bool) -> Self {
+        self.inner.reject_without_clients = x;
+        self
+    }
+
+    /// before closing a refused user connection, write a short error they can understand
+    pub fn error_hints(mut self, x: bool) -> Self {
+        self.inner.error_hints = x;
+        self
+    }
+
+    /// ramp up how fast queued users are handed to a newly connected client over this long. 0 hands them all out at once
+    pub fn warm_up(mut self, x: Duration) -> Self {
+        self.inner.warm_up = x;
+        self
+    }
+
+    /// how many queued users a newly connected client gets right away before the warm up starts pacing. defaults to 16
+    pub fn warm_up_streams(mut self, x: usize) -> Self {
+        self.inner.warm_up_streams = x;
+        self
+    }
+
+    /// serve the admin api on this unix socket. see the `admin` module
+    pub fn admin_socket(mut self, x: PathBuf) -> Self {
+        self.inner.admin_socket = Some(x);
+        self
+    }
+
+    /// answer `/healthz` and `/readyz` here. see the `health` module
+    pub fn health_listen(mut self, x: SocketAddr) -> Self {
+        self.inner.health_listen = Some(x);
+        self
+    }
+
+    /// keep each client's usage totals in this file across restarts. see the `usage` module
+    pub fn usage_file(mut self, x: PathBuf) -> Self {
+        self.inner.usage_file = Some(x);
+        self
+    }
+
+    /// append every client connection and stream to this file. see the `audit` module
+    pub fn audit_log(mut self, x: PathBuf) -> Self {
+        self.inner.audit_log = Some(x);
+        self
+    }
+
+    /// POST client connections, disconnections, auth failures, and quota breaches to this hook. see the `webhook` module
+    pub fn webhook(mut self, x: WebhookConfig) -> Self {
+        self.inner.webhooks.push(x);
+        self
+    }
+
+    /// stop handing users to clients that used up their quota. see the `quota` module
+    pub fn quota(mut self, x: QuotaOptions) -> Self {
+        self.inner.quota = x;
+        self
+    }
+
+    /// cap each tunnel client to this many bytes per second in each direction, shared by all of its streams
+    pub fn per_client_rate(mut self, x: u64) -> Self {
+        self.inner.per_client_rate = Some(x);
+        self
+    }
+
+    /// how many bytes a tunnel client can send at once before `per_client_rate` kicks in. defaults to one second of the rate
+    pub fn per_client_burst(mut self, x: u64) -> Self {
+        self.inner.per_client_burst = Some(x);
+        self
+    }
+
+    /// refuse new users past this many per second across every listener
+    pub fn max_connection_rate(mut self, x: u64) -> Self {
+        self.inner.max_connection_rate = Some(x);
+        self
+    }
+
+    /// refuse new users past this many per second from one address. unix sockets don't have one
+    pub fn max_connection_rate_per_ip(mut self, x: u64) -> Self {
+        self.inner.max_connection_rate_per_ip = Some(x);
+        self
+    }
+
+    /// turn users away while this many are open across every listener, instead of queueing them. a user counts from when
+    /// they are let in until their stream is done
+    pub fn max_connections(mut self, x: usize) -> Self {
+        self.inner.max_connections = Some(x);
+        self
+    }
+
+    /// hand each tunnel client at most this many users at once. the rest wait in the queue for a free slot or another client.
+    ///
+    /// This is on top of the QUIC limit on open streams, which also counts pooled streams. A client that reaches it is told
+    /// once on its control stream, with `CloseCode::TooManyStreams`.
+    pub fn max_streams_per_client(mut self, x: usize) -> Self {
+        self.inner.max_streams_per_client = Some(x);
+        self
+    }
+
+    /// hold each client cert to this many tunnel connections at once. without it, any number can share one
+    pub fn identity_limit(mut self, x: IdentityLimit) -> Self {
+        self.inner.identity_limit = Some(x);
+        self
+    }
+
+    /// close user streams when no bytes move in either direction for this long, so abandoned connections that never close don't pile up
+    pub fn stream_idle_timeout(mut self, x: Duration) -> Self {
+        self.inner.stream_idle_timeout = Some(x);
+        self
+    }
+
+    /// close user streams this long after they start, even busy ones, so a client that leaks connections can't hold them forever
+    pub fn stream_max_lifetime(mut self, x: Duration) -> Self {
+        self.inner.stream_max_lifetime = Some(x);
+        self
+    }
+
+    /// how often to ping tunnel clients on their control streams, and how long they get to answer. see the `control` module
+    pub fn heartbeat(mut self, x: HeartbeatOptions) -> Self {
+        self.inner.heartbeat = x;
+        self
+    }
+
+    /// let `ReverseProxyServerHandle::upgrade` hand the listening sockets to a new copy of this binary, and stop once our streams
+    /// finish or `drain_timeout` passes. the binary upgrades on SIGUSR2. see the `upgrade` module
+    pub fn upgrade(mut self, drain_timeout: Duration) -> Self {
+        self.inner.upgrade = Some(drain_timeout);
+        self
+    }
+
+    /// also take streams from browsers over WebTransport. see the `webtransport` module
+    pub fn webtransport(mut self, x: WebTransportOptions) -> Self {
+        self.inner.webtransport = Some(x);
+        self
+    }
+
+    /// also take tunnel clients over TLS over TCP here, for clients whose network drops UDP. see the `tcp_fallback` module
+    pub fn tcp_fallback(mut self, x: SocketAddr) -> Self {
+        self.inner.tcp_fallback = Some(x);
+        self
+    }
+
+    /// also take `udp_client` connections on the quic port and forward their datagrams to `x`, like `udp_server` does
+    pub fn forward(mut self, x: DatagramTarget) -> Self {
+        self.inner.forward = Some(x);
+        self
+    }
+
+    /// also take `transparent_client` connections on the quic port and connect their streams where they ask, if `x` allows it
+    pub fn connect(mut self, x: ConnectOptions) -> Self {
+        self.inner.connect = x;
+        self
+    }
+
+    /// also take `peer_server` and `peer_client` connections on the quic port, introduce them to each other so they can
+    /// connect directly, and relay between them when they can't
+    pub fn rendezvous(mut self, x: bool) -> Self {
+        self.inner.rendezvous = x;
+        self
+    }
+
+    /// also be a hop for clients with `--via` on the quic port, and forward their packets to the next server, if `x`
+    /// allows it
+    pub fn hop(mut self, x: HopOptions) -> Self {
+        self.inner.hop = x;
+        self
+    }
+
+    /// hexdump the first bytes of streams to files, for debugging an app's protocol
+    pub fn debug_dump(mut self, x: DumpOptions) -> Self {
+        self.inner.debug_dump = Some(x);
+        self
+    }
+
+    /// how often and where to write the traffic counters
+    pub fn stats(mut self, x: StatsOptions) -> Self {
+        self.inner.stats = x;
+        self
+    }
+
+    /// cancelling this token stops every task the server spawned. use `shutdown` on the handle to also wait for them
+    pub fn shutdown_token(mut self, x: CancellationToken) -> Self {
+        self.inner.shutdown = x;
+        self
+    }
+
+    /// run QUIC and the copy loops on this runtime. defaults to `runtime::data_plane`
+    pub fn data_plane(mut self, x: Handle) -> Self {
+        self.inner.data_plane = Some(x);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<ReverseProxyServer> {
+        if self.inner.listeners.is_empty() {
+            anyhow::bail!("the reverse proxy server needs at least one listener");
+        }
+
+        match (self.inner.per_client_rate, self.inner.per_client_burst) {
+            (Some(0), _) => anyhow::bail!("the per client rate must be more than 0"),
+            (None, Some(_)) => anyhow::bail!("the per client burst needs a per client rate"),
+            _ => {}
+        }
+
+        if self.inner.max_connection_rate == Some(0)
+            || self.inner.max_connection_rate_per_ip == Some(0)
+        {
+            anyhow::bail!("connection rate limits must be more than 0");
+        }
+
+        if self.inner.quic_sockets == 0 {
+            anyhow::bail!("the server needs at least one QUIC socket");
+        }
+
+        if self.inner.max_streams_per_client == Some(0) {
+            anyhow::bail!("max streams per client must be more than 0");
+        }
+
+        if self
+            .inner
+            .identity_limit
+            .as_ref()
+            .is_some_and(|x| x.max == 0)
+        {
+            anyhow::bail!("the identity limit must be more than 0");
+        }
+
+        if self.inner.retry.token_lifetime.is_zero() {
+            anyhow::bail!("the retry token lifetime must be more than 0");
+        }
+
+        if self.inner.max_connections == Some(0) {
+            anyhow::bail!("max connections must be more than 0");
+        }
+
+        if self.inner.stream_idle_timeout == Some(Duration::ZERO) {
+            anyhow::bail!("the stream idle timeout must be more than 0");
+        }
+
+        if self.inner.stream_max_lifetime == Some(Duration::ZERO) {
+            anyhow::bail!("the max stream lifetime must be more than 0");
+        }
+
+        if self.inner.stats.interval.is_zero() {
+            anyhow::bail!("the stats interval must be more than 0");
+        }
+
+        if self.inner.heartbeat.interval.is_zero() || self.inner.heartbeat.timeout.is_zero() {
+            anyhow::bail!("the heartbeat interval and timeout must be more than 0");
+        }
+
+        check_listeners(&self.inner.listeners)?;
+
+        let stdio = self
+            .inner
+            .listeners
+            .iter()
+            .any(|x| x.target == ListenTarget::Stdio);
+
+        if let Some(x) = self.inner.upgrade {
+            if x.is_zero() {
+                anyhow::bail!("the upgrade drain timeout must be more than 0");
+            }
+
+            // the new process would get a stdin of its own
+            if stdio {
+                anyhow::bail!("stdio can't be handed to a new process, so it can't be upgraded");
+            }
+
+            // TODO: hand the WebTransport socket over too
+            if self.inner.webtransport.is_some() {
+                anyhow::bail!("the WebTransport endpoint can't be handed to a new process yet");
+            }
+
+            // TODO: hand the TCP fallback listener over too
+            if self.inner.tcp_fallback.is_some() {
+                anyhow::bail!("the TCP fallback listener can't be handed to a new process yet");
+            }
+        }
+
+        Ok(self.inner)
+    }
+
+    pub async fn start(self) -> anyhow::Result<ReverseProxyServerHandle> {
+        self.build()?.start().await
+    }
+}
+
+/// the checks `build` and `reload` make of every listener
+fn check_listeners(listeners: &[ListenerConfig]) -> anyhow::Result<()> {
+    for x in listeners.iter() {
+        if let ListenTarget::Udp(_) = x.target {
+            // TODO: do we actually care about tunneling udp?
+            anyhow::bail!("udp listeners are not supported by the reverse proxy yet");
+        }
+
+        StreamPreamble::new(&x.route).context("invalid route name")?;
+
+        if x.max_connections == Some(0) {
+            anyhow::bail!("max connections must be more than 0");
+        }
+
+        if !x.allow.is_empty() && !matches!(x.target, ListenTarget::Tcp(_)) {
+            anyhow::bail!("only tcp listeners have a peer address to allow");
+        }
+
+        if !x.sni.is_empty() && !matches!(x.target, ListenTarget::Tcp(_)) {
+            anyhow::bail!("only tcp listeners can route by sni");
+        }
+
+        for rule in x.sni.iter() {
+            StreamPreamble::new(&rule.route).context("invalid sni route name")?;
+        }
+
+        if !x.http.is_empty() && !matches!(x.target, ListenTarget::Tcp(_)) {
+            anyhow::bail!("only tcp listeners can route by http host and path");
+        }
+
+        for rule in x.http.iter() {
+            StreamPreamble::new(&rule.route).context("invalid http route name")?;
+        }
+    }
+
+    for (i, x) in listeners.iter().enumerate() {
+        if listeners[..i].iter().any(|y| y.target == x.target) {
+            match x.target {
+                ListenTarget::Stdio => {
+                    anyhow::bail!("there is only one stdin and stdout to listen on")
+                }
+                _ => anyhow::bail!("more than one listener on {}", x.target),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// shared by all of the server's tasks
+struct ServerShared {
+    compress: CompressAlgo,
+    /// every algorithm a listener uses. clients have to accept all of them
+    compress_used: Vec<CompressAlgo>,
+    close_mode: CloseMode,
+    padding: PaddingOptions,
+    stream_pool_size: usize,
+    reject_without_clients: bool,
+    error_hints: bool,
+    warm_up: Duration,
+    warm_up_streams: usize,
+    per_client_rate: Option<u64>,
+    per_client_burst: Option<u64>,
+    max_streams_per_client: Option<usize>,
+    identity_limit: Option<IdentityLimit>,
+    stream_idle_timeout: Option<Duration>,
+    stream_max_lifetime: Option<Duration>,
+    heartbeat: HeartbeatOptions,
+    /// `None` if there are no connection rate limits
+    accept_rate_limit: Option<AcceptRateLimit>,
+    /// one permit for each user under `max_connections`
+    connection_slots: Option<Arc<Semaphore>>,
+    /// how many tunnel clients are reading from the stream channel
+    connected_clients: AtomicUsize,
+    stream_sender: Sender<PendingStream>,
+    stream_receiver: Receiver<PendingStream>,
+    /// every listener's routes and options. `reload` swaps them all at once
+    listeners: watch::Sender<Vec<Arc<ListenerConfig>>>,
+    counts: Arc<TunnelCounters>,
+    /// connected clients and active streams for the admin api
+    registry: Arc<Registry>,
+    /// totals by client identity
+    usage: Arc<UsageStore>,
+    audit: Arc<AuditLog>,
+    quota: QuotaOptions,
+    shutdown: CancellationToken,
+    /// cancelled when an upgrade hands our sockets to a new process, by `drain`, and by shutdown.
+    /// we stop accepting, but the streams we have keep going
+    draining: CancellationToken,
+    /// connections and streams. these are waited on during shutdown
+    tracker: TaskTracker,
+    /// forwarded traffic runs here
+    data_plane: Handle,
+    forward: Option<DatagramTarget>,
+    connect: ConnectOptions,
+    /// for connecting `Role::Connect` streams
+    tcp: TcpOptions,
+    rendezvous: Option<Rendezvous>,
+    hop: HopOptions,
+    chaos: ChaosOptions,
+    debug_dump: Option<DebugDump>,
+}
+
+impl ReverseProxyServer {
+    /// what the quic endpoints take. clients that don't say get the first
+    fn roles(&self) -> Vec<Role> {
+        let mut x = vec![Role::Reverse];
+
+        if self.forward.is_some() {
+            x.push(Role::Forward);
+        }
+
+        if self.connect.is_enabled() {
+            x.push(Role::Connect);
+        }
+
+        if self.rendezvous {
+            x.push(Role::Rendezvous);
+        }
+
+        if self.hop.is_enabled() {
+            x.push(Role::Hop);
+        }
+
+        x.push(Role::Probe);
+
+        x
+    }
+
+    pub async fn start(self) -> anyhow::Result<ReverseProxyServerHandle> {
+        // find every conflict now instead of failing on the first bind inside a spawned task
+        let roles = self.roles();
+
+        let mut listen_targets: Vec<_> = self
+            .quic_addrs
+            .iter()
+            .copied()
+            .map(ListenTarget::Udp)
+            .collect();
+        listen_targets.extend(self.listeners.iter().map(|x| x.target.clone()));
+        listen_targets.extend(self.admin_socket.clone().map(ListenTarget::Unix));
+        listen_targets.extend(self.health_listen.map(ListenTarget::Tcp));
+        listen_targets.extend(
+            self.webtransport
+                .as_ref()
+                .map(|x| ListenTarget::Udp(x.listen)),
+        );
+        listen_targets.extend(self.tcp_fallback.map(ListenTarget::Tcp));
+        check_listen_targets(&listen_targets)?;
+
+        // dump this before anything is moved out
+        let config_dump = serde_json::to_value(&self)?;
+
+        let data_plane = self.data_plane.unwrap_or_else(runtime::data_plane);
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let sockets = bind_server_sockets(&self.quic_addrs, self.quic_sockets)?;
+
+        // copies of every listening socket, for the new process if we are upgraded
+        let mut handover: Vec<(ListenTarget, OwnedFd)> = vec![];
+
+        let quic_handover = match self.upgrade {
+            Some(_) => sockets
+                .iter()
+                .map(|x| x.handover())
+                .collect::<std::io::Result<Vec<_>>>()?,
+            None => vec![],
+        };
+
+        if self.upgrade.is_some() {
+            upgrade::enable();
+        }
+
+        let webtransport_endpoint = match &self.webtransport {
+            Some(x) => {
+                let _guard = data_plane.enter();
+
+                let endpoint = build_webtransport_endpoint(
+                    self.ca.clone(),
+                    self.cert.clone(),
+                    self.key.clone(),
+                    x,
+                    &self.transport,
+                    &self.tls,
+                )?;
+
+                info!("WebTransport listening on {}", endpoint.local_addr()?);
+
+                Some(endpoint)
+            }
+            None => None,
+        };
+
+        let tcp_fallback = match self.tcp_fallback {
+            Some(listen) => {
+                let _guard = data_plane.enter();
+
+                let server_config = quic_server_config(
+                    self.ca.clone(),
+                    self.cert.clone(),
+                    self.key.clone(),
+                    &self.retry,
+                    &self.transport,
+                    &self.tls,
+                    &roles,
+                )?;
+
+                Some(tcp_fallback::bind(
+                    listen,
+                    self.ca.clone(),
+                    self.cert.clone(),
+                    self.key.clone(),
+                    server_config,
+                    &self.tls,
+                )?)
+            }
+            None => None,
+        };
+
+        let mut endpoints = {
+            let _guard = data_plane.enter();
+
+            build_server_endpoints(
+                self.ca,
+                self.cert,
+                self.key,
+                &self.retry,
+                &self.transport,
+                &self.tls,
+                &roles,
+                sockets,
+            )?
+        };
+
+        let mut quic_addrs = vec![];
+
+        for x in endpoints.iter() {
+            let addr = x.local_addr()?;
+
+            if !quic_addrs.contains(&addr) {
+                quic_addrs.push(addr);
+            }
+        }
+
+        for x in quic_addrs.iter() {
+            info!(sockets = self.quic_sockets, "QUIC listening on {}", x);
+        }
+
+        // it takes clients like the others, but isn't a QUIC address
+        let tcp_fallback_addr = match tcp_fallback {
+            Some(x) => {
+                let addr = x.local_addr()?;
+
+                endpoints.push(x);
+
+                Some(addr)
+            }
+            None => None,
+        };
+
+        let (stream_sender, stream_receiver) = flume::unbounded();
+
+        let usage = UsageStore::open(self.usage_file.clone())
+            .await
+            .context("loading client usage")?;
+
+        let audit = match &self.audit_log {
+            Some(x) => AuditLog::open(Some(x.clone()))
+                .with_context(|| format!("unable to open audit log {}", x.display()))?,
+            None => AuditLog::default(),
+        }
+        .with_webhooks(Webhooks::new(self.webhooks.clone()).context("starting webhooks")?);
+
+        let mut compress_used = vec![];
+        for x in self.listeners.iter() {
+            let x = x.compress.unwrap_or(self.compress);
+
+            if !compress_used.contains(&x) {
+                compress_used.push(x);
+            }
+        }
+
+        let shared = Arc::new(ServerShared {
+            compress: self.compress,
+            compress_used,
+            close_mode: self.close_mode,
+            padding: self.padding.clone(),
+            stream_pool_size: self.stream_pool_size,
+            reject_without_clients: self.reject_without_clients,
+            error_hints: self.error_hints,
+            warm_up: self.warm_up,
+            warm_up_streams: self.warm_up_streams,
+            per_client_rate: self.per_client_rate,
+            per_client_burst: self.per_client_burst,
+            max_streams_per_client: self.max_streams_per_client,
+            identity_limit: self.identity_limit.clone(),
+            stream_idle_timeout: self.stream_idle_timeout,
+            stream_max_lifetime: self.stream_max_lifetime,
+            heartbeat: self.heartbeat.clone(),
+            accept_rate_limit: (self.max_connection_rate.is_some()
+                || self.max_connection_rate_per_ip.is_some())
+            .then(|| {
+                AcceptRateLimit::new(self.max_connection_rate, self.max_connection_rate_per_ip)
+            }),
+            connection_slots: self.max_connections.map(|x| Arc::new(Semaphore::new(x))),
+            connected_clients: AtomicUsize::new(0),
+            stream_sender,
+            stream_receiver,
+            listeners: watch::Sender::new(self.listeners.iter().cloned().map(Arc::new).collect()),
+            counts: TunnelCounters::new(),
+            registry: Default::default(),
+            usage,
+            audit: Arc::new(audit),
+            quota: self.quota.clone(),
+            shutdown: self.shutdown.clone(),
+            draining: self.shutdown.child_token(),
+            tracker: TaskTracker::new(),
+            data_plane: data_plane.clone(),
+            forward: self.forward.clone(),
+            connect: self.connect.clone(),
+            tcp: self.tcp.clone(),
+            rendezvous: self.rendezvous.then(Rendezvous::default),
+            hop: self.hop.clone(),
+            chaos: self.transport.chaos.clone(),
+            debug_dump: self.debug_dump.clone().map(DebugDump::new),
+        });
+
+        let mut tasks = vec![];
+        let mut bound = vec![];
+
+        // the tunnel handle listens on quic and forwards user streams from the channel. every endpoint reads the same channel
+        for endpoint in endpoints.iter() {
+            tasks.push(data_plane.spawn(accept_quic_connections(endpoint.clone(), shared.clone())));
+        }
+
+        if let Some(x) = &webtransport_endpoint {
+            let f = accept_webtransport(x.clone(), shared.clone());
+
+            tasks.push(data_plane.spawn(f));
+        }
+
+        // listeners forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
+        for config in self.listeners {
+            let listener = {
+                let _guard = data_plane.enter();
+
+                Listener::bind(&config.target, &self.tcp, &self.unix).await?
+            };
+
+            if self.upgrade.is_some() {
+                if let Some(x) = listener.handover_fd() {
+                    handover.push((config.target.clone(), x?));
+                }
+            }
+
+            let addr = listener.local_addr();
+            let task = spawn_listener(listener, config.target.clone(), &shared);
+
+            bound.push((config.target, addr, task.id()));
+            tasks.push(task);
+        }
+
+        // management tasks stay off the data plane
+        if let Some(path) = self.admin_socket {
+            let admin = AdminServer::bind(
+                path.clone(),
+                shared.registry.clone(),
+                shared.counts.clone(),
+                shared.usage.clone(),
+                config_dump,
+            )?;
+
+            if self.upgrade.is_some() {
+                handover.push((ListenTarget::Unix(path), admin.handover_fd()?));
+            }
+
+            let draining = shared.draining.clone();
+            let shutdown = self.shutdown.clone();
+
+            tasks.push(tokio::spawn(async move {
+                admin.serve(draining).await?;
+
+                // after an upgrade, the new process answers instead
+                shutdown.cancelled().await;
+
+                Ok(())
+            }));
+        }
+
+        // cancelled once a new process is listening on our sockets after an upgrade
+        let succeeded = self.shutdown.child_token();
+
+        if let Some(addr) = self.health_listen {
+            let health = HealthServer::bind(addr).await?;
+
+            if self.upgrade.is_some() {
+                handover.push((ListenTarget::Tcp(addr), health.handover_fd()?));
+            }
+
+            let ready: ReadyCheck = {
+                let shared = shared.clone();
+
+                Arc::new(move || {
+                    if shared.draining.is_cancelled() {
+                        Err("draining".to_string())
+                    } else if shared.connected_clients.load(atomic::Ordering::SeqCst) == 0 {
+                        Err("no tunnel client is connected".to_string())
+                    } else {
+                        Ok(())
+                    }
+                })
+            };
+
+            let succeeded = succeeded.clone();
+            let shutdown = self.shutdown.clone();
+
+            tasks.push(tokio::spawn(async move {
+                // while draining, /readyz says so. after an upgrade, the new process answers instead
+                health.serve(ready, succeeded).await?;
+
+                shutdown.cancelled().await;
+
+                Ok(())
+            }));
+        }
+
+        let handover = self.upgrade.map(|drain_timeout| Handover {
+            sockets: handover,
+            quic: quic_handover,
+            drain_timeout,
+            succeeded,
+        });
+
+        let stats_handle = shared
+            .counts
+            .clone()
+            .spawn_stats_loop(self.stats, self.shutdown.clone());
+        tasks.push(tokio::spawn(async move {
+            stats_handle.await?;
+            Ok(())
+        }));
+
+        tasks.push(tokio::spawn(
+            shared.usage.clone().save_loop(self.shutdown.clone()),
+        ));
+
+        // if an old server started us, it can stop accepting now
+        upgrade::notify_ready().await;
+
+        Ok(ReverseProxyServerHandle {
+            endpoints,
+            webtransport_endpoint,
+            quic_addrs,
+            tcp_fallback_addr,
+            listener_addrs: bound.iter().map(|(_, x, _)| *x).collect(),
+            bound,
+            upgrade: self.upgrade.is_some(),
+            handover: tokio::sync::Mutex::new(handover),
+            unix: self.unix,
+            tasks,
+            retired: vec![],
+            shared,
+        })
+    }
+}
+
+/// what `ReverseProxyServerHandle::upgrade` gives the new process
+struct Handover {
+    sockets: Vec<(ListenTarget, OwnedFd)>,
+    quic: Vec<QuicHandover>,
+    drain_timeout: Duration,
+    /// cancelled once the new process is listening
+    succeeded: CancellationToken,
+}
+
+pub struct ReverseProxyServerHandle {
+    endpoints: Vec<Endpoint>,
+    webtransport_endpoint: Option<Endpoint>,
+    quic_addrs: Vec<SocketAddr>,
+    tcp_fallback_addr: Option<SocketAddr>,
+    listener_addrs: Vec<Option<SocketAddr>>,
+    /// the listeners' targets, the tcp addresses actually bound for them, and their tasks in `tasks`, in the order they
+    /// were added
+    bound: Vec<(ListenTarget, Option<SocketAddr>, tokio::task::Id)>,
+    /// listeners can only be added by upgrading
+    upgrade: bool,
+    /// `None` without upgrades, or once the new process has the sockets
+    handover: tokio::sync::Mutex<Option<Handover>>,
+    /// for binding the unix listeners that `reload` adds
+    unix: UnixSocketOptions,
+    tasks: Vec<JoinHandle<anyhow::Result<()>>>,
+    /// the tasks of listeners that a reload took out, until they finish. they aren't in `tasks`, so `wait` doesn't take
+    /// one of them stopping for the server stopping
+    retired: Vec<JoinHandle<anyhow::Result<()>>>,
+    shared: Arc<ServerShared>,
+}
+
+impl ReverseProxyServerHandle {
+    /// the address tunnel clients connect to. the first one, if there are more
+    pub fn quic_addr(&self) -> SocketAddr {
+        self.quic_addrs[0]
+    }
+
+    /// every address tunnel clients can connect to, in the order they were added
+    pub fn quic_addrs(&self) -> &[SocketAddr] {
+        &self.quic_addrs
+    }
+
+    /// the bound tcp addresses of the listeners, in the order they were added. `None` for unix sockets
+    pub fn listener_addrs(&self) -> &[Option<SocketAddr>] {
+        &self.listener_addrs
+    }
+
+    /// where tunnel clients connect over TCP, if they can
+    pub fn tcp_fallback_addr(&self) -> Option<SocketAddr> {
+        self.tcp_fallback_addr
+    }
+
+    /// where browsers connect with WebTransport, if they can
+    pub fn webtransport_addr(&self) -> Option<SocketAddr> {
+        self.webtransport_endpoint
+            .as_ref()
+            .and_then(|x| x.local_addr().ok())
+    }
+
+    /// switch to `listeners` without a restart. listeners on new targets are bound, ones whose targets are gone stop
+    /// accepting while their users finish, and the rest pick up their new routes and options. users that already connected
+    /// keep the routes they got. if anything is wrong with `listeners`, or a new one can't be bound, nothing changes.
+    ///
+    /// WebTransport sessions can only ask for the routes the server started with
+    pub async fn reload(&mut self, listeners: Vec<ListenerConfig>) -> anyhow::Result<()> {
+        if listeners.is_empty() {
+            anyhow::bail!("the reverse proxy server needs at least one listener");
+        }
+
+        check_listeners(&listeners)?;
+
+        for x in listeners.iter() {
+            let algo = x.compress.unwrap_or(self.shared.compress);
+
+            // clients only agree to the algorithms they were offered when they connected
+            if !self.shared.compress_used.contains(&algo) {
+                anyhow::bail!(
+                    "the listener on {} compresses with {:?}, which no listener did at startup. restart to use it",
+                    x.target,
+                    algo,
+                );
+            }
+        }
+
+        let added: Vec<_> = listeners
+            .iter()
+            .filter(|x| !self.bound.iter().any(|(target, ..)| *target == x.target))
+            .collect();
+
+        if !added.is_empty() {
+            // the new process would bind them again while we still have them
+            if self.upgrade {
+                anyhow::bail!("with upgrades on, listeners are added by upgrading");
+            }
+
+            if added.iter().any(|x| x.target == ListenTarget::Stdio) {
+                anyhow::bail!("stdio can only be listened on from the start");
+            }
+        }
+
+        // bind everything before changing anything, so a target that is taken leaves the server as it was
+        let mut new = vec![];
+
+        for x in added {
+            let listener = {
+                let _guard = self.shared.data_plane.enter();
+
+                Listener::bind(&x.target, &self.shared.tcp, &self.unix)
+                    .await
+                    .with_context(|| format!("unable to listen on {}", x.target))?
+            };
+
+            new.push((x.target.clone(), listener));
+        }
+
+        let removed: Vec<_> = self
+            .bound
+            .iter()
+            .filter(|(target, ..)| !listeners.iter().any(|x| x.target == *target))
+            .map(|(target, _, id)| (target.clone(), *id))
+            .collect();
+
+        // the old listeners see this all at once. ones that aren't in it any more stop accepting
+        self.shared
+            .listeners
+            .send_replace(listeners.into_iter().map(Arc::new).collect());
+
+        // so reloading over and over doesn't keep every listener there ever was
+        self.retired.retain(|x| !x.is_finished());
+
+        for (target, id) in removed.iter() {
+            info!("no longer listening for users on {}", target);
+
+            if let Some(i) = self.tasks.iter().position(|x| x.id() == *id) {
+                self.retired.push(self.tasks.swap_remove(i));
+            }
+        }
+
+        self.bound
+            .retain(|(target, ..)| !removed.iter().any(|(x, _)| x == target));
+
+        let added = new.len();
+
+        for (target, listener) in new {
+            let addr = listener.local_addr();
+            let task = spawn_listener(listener, target.clone(), &self.shared);
+
+            self.bound.push((target, addr, task.id()));
+            self.tasks.push(task);
+        }
+
+        self.listener_addrs = self.bound.iter().map(|(_, x, _)| *x).collect();
+
+        info!(added, removed = removed.len(), "listeners reloaded");
+
+        Ok(())
+    }
+
+    /// the tunnel clients connected right now, like the admin api lists them
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.shared.registry.clients()
+    }
+
+    /// cancel this to stop the server
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shared.shutdown.clone()
+    }
+
+    /// if the builder turned upgrades on
+    pub fn can_upgrade(&self) -> bool {
+        self.upgrade
+    }
+
+    /// start a new copy of this binary on our sockets, like the binary does on SIGUSR2. once it is listening, we drain for
+    /// up to the builder's `upgrade` timeout and stop, so `wait` returns. if it fails, nothing changes and it can be tried again
+    pub async fn upgrade(&self) -> anyhow::Result<()> {
+        let mut handover = self.handover.lock().await;
+
+        let Some(x) = handover.as_ref() else {
+            if self.upgrade {
+                anyhow::bail!("the server was already upgraded");
+            }
+
+            anyhow::bail!("the server was built without upgrades");
+        };
+
+        let pid = upgrade::spawn_successor(&x.sockets, &x.quic).await?;
+
+        // our copies of the sockets are closed here, so only the new process has them
+        let Handover {
+            drain_timeout,
+            succeeded,
+            ..
+        } = handover.take().unwrap();
+
+        info!(pid, ?drain_timeout, "the new server is listening. draining");
+
+        succeeded.cancel();
+
+        let shared = self.shared.clone();
+
+        tokio::spawn(async move {
+            drain(&shared, drain_timeout).await;
+
+            shared.shutdown.cancel();
+        });
+
+        Ok(())
+    }
+
+    /// log everything the admin api would show, even if the admin api is off. the binary does this on SIGUSR1
+    pub fn dump_stats(&self) {
+        let clients = self.shared.registry.clients();
+        let streams = self.shared.registry.streams();
+
+        info!(
+            uptime_secs = self.shared.registry.uptime_secs(),
+            clients = clients.len(),
+            streams = streams.len(),
+            "dumping stats"
+        );
+
+        self.shared.counts.log_snapshot();
+
+        for client in clients {
+            info!(?client, "client");
+        }
+
+        for stream in streams {
+            info!(?stream, "stream");
+        }
+    }
+
+    /// wait until one of the server's tasks stops. this happens on shutdown or if something went wrong
+    pub async fn wait(&mut self) -> anyhow::Result<()> {
+        let (x, i, _) = futures::future::select_all(self.tasks.iter_mut()).await;
+
+        info!(?x, i, "server task finished");
+
+        // a finished JoinHandle panics if it is polled again by shutdown
+        drop(self.tasks.swap_remove(i));
+
+        x?
+    }
+
+    /// stop accepting, tell clients to go away, and give the streams we have up to `timeout` to finish before shutting down
+    pub async fn drain(self, timeout: Duration) {
+        drain(&self.shared, timeout).await;
+
+        self.shutdown().await
+    }
+
+    /// stop accepting, stop every stream, and wait for all of the server's tasks to finish
+    pub async fn shutdown(self) {
+        self.shared.shutdown.cancel();
+
+        for x in self.tasks.into_iter().chain(self.retired) {
+            if let Err(err) = x.await {
+                error!(?err, "server task panicked");
+            }
+        }
+
+        self.shared.tracker.close();
+        self.shared.tracker.wait().await;
+
+        // the streams are done, so nothing is counted after this
+        if let Err(err) = self.shared.usage.save().await {
+            warn!(?err, "unable to save client usage");
+        }
+
+        self.shared.audit.flush(Duration::from_secs(5)).await;
+
+        for x in self.endpoints.iter() {
+            x.close(CloseCode::Done.into(), b"server done");
+        }
+
+        if let Some(x) = &self.webtransport_endpoint {
+            x.close(H3_NO_ERROR.into(), b"server done");
+        }
+
+        for x in self
+            .endpoints
+            .iter()
+            .chain(self.webtransport_endpoint.iter())
+        {
+            x.wait_idle().await;
+        }
+    }
+}
+
+async fn accept_quic_connections(
+    endpoint: Endpoint,
+    shared: Arc<ServerShared>,
+) -> anyhow::Result<()> {
+    loop {
+        let conn = select! {
+            x = endpoint.accept() => x,
+            _ = shared.draining.cancelled() => break,
+        };
+
+        let Some(conn) = conn else {
+            return Ok(());
+        };
+
+        let f = handle_quic_connection(conn, shared.clone());
+
+        // spawn to handle multiple connections at once
+        shared.tracker.spawn_on(
+            f.inspect_err(|err| trace!(?err, "reverse proxy tunnel closed")),
+            &shared.data_plane,
+        );
+    }
+
+    // during an upgrade, new connections are passed to the new process. refuse any that still reach us.
+    // the endpoint keeps running for the connections we already have
+    endpoint.set_server_config(None);
+
+    shared.shutdown.cancelled().await;
+
+    Ok(())
+}
+
+/// stop accepting and wait up to `drain_timeout` for what is already running
+async fn drain(shared: &ServerShared, drain_timeout: Duration) {
+    shared.draining.cancel();
+
+    // nothing new is spawned once the accept loops stop, so this only waits for what is already running
+    shared.tracker.close();
+
+    match timeout(drain_timeout, shared.tracker.wait()).await {
+        Ok(()) => info!("drained"),
+        Err(_) => warn!("streams still open after the drain timeout. closing them"),
+    }
+}
+
+/// accept users on `listener` with the config for `target` in `shared.listeners`
+fn spawn_listener(
+    listener: Listener,
+    target: ListenTarget,
+    shared: &Arc<ServerShared>,
+) -> JoinHandle<anyhow::Result<()>> {
+    info!("listening for users on {}", target);
+
+    let f = accept_users(listener, target, shared.clone());
+
+    shared
+        .data_plane
+        .spawn(f.inspect_err(|err| trace!(?err, "listener proxy closed")))
+}
+
+async fn accept_users(
+    listener: Listener,
+    target: ListenTarget,
+    shared: Arc<ServerShared>,
+) -> anyhow::Result<()> {
+    // stdio is a single user. once they are turned away, there is nothing left for the server to do
+    let once = target == ListenTarget::Stdio;
+
+    let mut listeners = shared.listeners.subscribe();
+
+    let find = |x: &[Arc<ListenerConfig>]| x.iter().find(|x| x.target == target).cloned();
+
+    let mut max_connections = None;
+    let mut slots = None;
+
+    // TODO: wait until at least one client has connected to the quic endpoint?
+    loop {
+        let stream = select! {
+            x = listener.accept() => x,
+            // a reload that took this listener out
+            _ = listeners.changed() => match find(&listeners.borrow_and_update()) {
+                Some(_) => continue,
+                None => break,
+            },
+            _ = shared.draining.cancelled() => break,
+        };
+
+        let Some(config) = find(&listeners.borrow()) else {
+            break;
+        };
+
+        // users holding permits from an old limit aren't counted against a new one
+        if config.max_connections != max_connections {
+            max_connections = config.max_connections;
+            slots = max_connections.map(|x| Arc::new(Semaphore::new(x)));
+        }
+
+        let stream = match stream {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, %target, "accept failed");
+                continue;
+            }
+        };
+
+        match stream {
+            // peeking can take a while, so it doesn't hold up the next user
+            Stream::Tcp(stream) if !config.sni.is_empty() || !config.http.is_empty() => {
+                let slots = slots.clone();
+                let shared_b = shared.clone();
+
+                shared.tracker.spawn_on(
+                    async move {
+                        let route = sniff_route(&stream, &config).await;
+                        let stream = Stream::Tcp(stream);
+
+                        if let Err(err) =
+                            queue_user(stream, &config, route, slots.as_ref(), &shared_b).await
+                        {
+                            debug!(?err, "unable to queue user");
+                        }
+                    },
+                    &shared.data_plane,
+                );
+            }
+            stream => {
+                let route = config.route.clone();
+
+                if !queue_user(stream, &config, route, slots.as_ref(), &shared).await? && once {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // after a reload, this frees the target for a later one
+    drop(listener);
+
+    // during an upgrade, the new process accepts on the same socket. wait here so the server doesn't look finished.
+    // a listener that a reload took out has nothing to wait for
+    if shared.draining.is_cancelled() {
+        shared.shutdown.cancelled().await;
+    }
+
+    Ok(())
+}
+
+/// the route for a tcp user by what they send first. tls users are routed by `sni` and plain http users by `http`
+async fn sniff_route(stream: &TcpStream, config: &ListenerConfig) -> String {
+    let mut route = None;
+
+    if !config.sni.is_empty() {
+        let name = sni::peek_server_name(stream).await;
+
+        route = name.as_deref().and_then(|x| sni::route(&config.sni, x));
+
+        debug!(server_name = ?name, ?route, "routing user by sni");
+    }
+
+    // a ClientHello isn't a request, so this only waits on users that sent neither
+    if route.is_none() && !config.http.is_empty() {
+        let request = http_route::peek_request(stream).await;
+
+        route = request
+            .as_ref()
+            .and_then(|x| http_route::route(&config.http, x));
+
+        debug!(?request, ?route, "routing user by http host and path");
+    }
+
+    route.unwrap_or(&config.route).to_string()
+}
+
+/// browsers' WebTransport streams are users of the listener with the route they asked for
+async fn accept_webtransport(endpoint: Endpoint, shared: Arc<ServerShared>) -> anyhow::Result<()> {
+    let routes = shared
+        .listeners
+        .borrow()
+        .iter()
+        .map(|x| x.route.clone())
+        .collect();
+
+    let (tx, rx) = flume::bounded(16);
+
+    let serve = webtransport::serve(endpoint, routes, tx, shared.draining.clone());
+
+    // sessions that are already open keep going while draining
+    let dispatch = async {
+        loop {
+            let (route, stream) = select! {
+                x = rx.recv_async() => match x {
+                    Ok(x) => x,
+                    Err(_) => break,
+                },
+                _ = shared.shutdown.cancelled() => break,
+            };
+
+            let config = shared
+                .listeners
+                .borrow()
+                .iter()
+                .find(|x| x.route == route)
+                .cloned();
+
+            // a reload took its listener out
+            let Some(config) = config else {
+                continue;
+            };
+
+            // only the server's `max_connections` counts these. the listener's are for its own socket
+            queue_user(stream, &config, route, None, &shared).await?;
+        }
+
+        anyhow::Ok(())
+    };
+
+    let ((), x) = join!(serve, dispatch);
+    x?;
+
+    // like the other listeners, so the server doesn't look finished while draining
+    shared.shutdown.cancelled().await;
+
+    Ok(())
+}
+
+/// check a user against the listener's limits and send them to the tunnel clients on `route`. false if they were turned away.
+/// `slots` is the listener's `max_connections`
+async fn queue_user(
+    stream: Stream,
+    config: &ListenerConfig,
+    route: String,
+    slots: Option<&Arc<Semaphore>>,
+    shared: &ServerShared,
+) -> anyhow::Result<bool> {
+    if !config.allow.is_empty() {
+        let allowed = stream
+            .transform_context()
+            .peer_addr
+            .is_some_and(|x| config.allow.iter().any(|net| net.contains(&x.ip())));
+
+        if !allowed {
+            debug!(target = %config.target, "user not in allow list");
+
+            shared.tracker.spawn_on(
+                reject(stream, RejectReason::AccessDenied, shared.error_hints),
+                &shared.data_plane,
+            );
+
+            return Ok(false);
+        }
+    }
+
+    if let Some(x) = &shared.accept_rate_limit {
+        let ip = stream.transform_context().peer_addr.map(|x| x.ip());
+
+        if !x.allow(ip).await {
+            debug!(target = %config.target, ?ip, "user connection rate limited");
+
+            shared.tracker.spawn_on(
+                reject(stream, RejectReason::RateLimited, shared.error_hints),
+                &shared.data_plane,
+            );
+
+            return Ok(false);
+        }
+    }
+
+    // a full server says so right away. queueing more users would only make every one of them wait
+    let mut held = vec![];
+
+    for x in [shared.connection_slots.as_ref(), slots]
+        .into_iter()
+        .flatten()
+    {
+        match x.clone().try_acquire_owned() {
+            Ok(x) => held.push(x),
+            Err(_) => {
+                debug!(target = %config.target, "at max connections");
+
+                shared.tracker.spawn_on(
+                    reject(stream, RejectReason::AtCapacity, shared.error_hints),
+                    &shared.data_plane,
+                );
+
+                return Ok(false);
+            }
+        }
+    }
+
+    if shared.reject_without_clients && shared.connected_clients.load(atomic::Ordering::SeqCst) == 0
+    {
+        shared.tracker.spawn_on(
+            reject(stream, RejectReason::NoTunnelClient, shared.error_hints),
+            &shared.data_plane,
+        );
+
+        return Ok(false);
+    }
+
+    // send the stream to a channel. one of multiple connections might handle it
+    shared
+        .stream_sender
+        .send_async(PendingStream {
+            stream,
+            listener: config.target.clone(),
+            route,
+            transform: config.transform.clone(),
+            compress: config.compress.unwrap_or(shared.compress),
+            accepted_at: Instant::now(),
+            slots: held,
+            handoffs: 0,
+            priority: config.priority,
+        })
+        .await?;
+
+    Ok(true)
+}
+
+async fn handle_quic_connection(
+    conn_a: Connecting,
+    shared: Arc<ServerShared>,
+) -> anyhow::Result<()> {
+    // TODO: are there other things I need to do to set up 0-rtt?
+    // with 0-rtt, the handshake (and so the client's certificate) finishes after we start using the connection
+    let (conn_a, handshake) = match conn_a.into_0rtt() {
+        Ok((conn_a, handshake)) => {
+            trace!("0-rtt accepted");
+            (conn_a, Some(handshake))
+        }
+        Err(conn_a) => {
+            let peer = conn_a.remote_address();
+
+            match timeout(Duration::from_secs(30), conn_a).await? {
+                Ok(x) => (x, None),
+                Err(err) => {
+                    audit_auth_failure(&shared, peer, &err);
+
+                    return Err(err.into());
+                }
+            }
+        }
+    };
+
+    // `ping`. it isn't a tunnel client, so it isn't counted or recorded as one
+    if let Some(Role::Probe) = Role::negotiated(&conn_a) {
+        debug!(peer = %conn_a.remote_address(), "answering pings");
+
+        return select! {
+            x = control::answer_pings(&conn_a) => x.map_err(Into::into),
+            _ = shared.shutdown.cancelled() => {
+                conn_a.close(CloseCode::Done.into(), b"server done");
+                Ok(())
+            }
+        };
+    }
+
+    // the handshake only lets this through if there is somewhere to forward to
+    if let (Some(Role::Forward), Some(target)) = (Role::negotiated(&conn_a), &shared.forward) {
+        let counts = side_client_connected(&shared, &conn_a, "forward");
+
+        info!(peer = %conn_a.remote_address(), "datagram client connected");
+
+        // a datagram client doesn't get `GoAway`. it keeps going until shutdown
+        return select! {
+            x = forward_streams(&conn_a, target, counts.clone()) => x,
+            x = forward_datagrams(&conn_a, target, counts) => x,
+            _ = shared.shutdown.cancelled() => {
+                conn_a.close(CloseCode::Done.into(), b"server done");
+                Ok(())
+            }
+        };
+    }
+
+    // likewise, only if something is allowed
+    if let Some(Role::Connect) = Role::negotiated(&conn_a) {
+        let counts = side_client_connected(&shared, &conn_a, "connect");
+
+        info!(peer = %conn_a.remote_address(), "transparent client connected");
+
+        return select! {
+            x = transparent::serve_connect_streams(&conn_a, &shared.connect, &shared.tcp, counts) => x,
+            _ = shared.shutdown.cancelled() => {
+                conn_a.close(CloseCode::Done.into(), b"server done");
+                Ok(())
+            }
+        };
+    }
+
+    if let (Some(Role::Rendezvous), Some(rendezvous)) =
+        (Role::negotiated(&conn_a), &shared.rendezvous)
+    {
+        let counts = side_client_connected(&shared, &conn_a, "rendezvous");
+
+        info!(peer = %conn_a.remote_address(), "rendezvous client connected");
+
+        return select! {
+            x = rendezvous.serve(&conn_a, counts) => x,
+            _ = shared.shutdown.cancelled() => {
+                conn_a.close(CloseCode::Done.into(), b"server done");
+                Ok(())
+            }
+        };
+    }
+
+    if let Some(Role::Hop) = Role::negotiated(&conn_a) {
+        let counts = side_client_connected(&shared, &conn_a, "hop");
+
+        return select! {
+            x = chain::serve_hop(&conn_a, &shared.hop, counts) => x,
+            _ = shared.shutdown.cancelled() => {
+                conn_a.close(CloseCode::Done.into(), b"server done");
+                Ok(())
+            }
+        };
+    }
+
+    shared
+        .connected_clients
+        .fetch_add(1, atomic::Ordering::SeqCst);
+
+    let client = shared.registry.add_client(conn_a.clone());
+
+    let span = info_span!(
+        "conn",
+        conn_id = client.id(),
+        peer = %conn_a.remote_address(),
+        client_fingerprint = tracing::field::Empty,
+    );
+
+    audit_disconnect(
+        &shared,
+        &conn_a,
+        client.id(),
+        shared.counts.connection(client.id()),
+    );
+
+    let pool_a = StreamPool::new(conn_a, shared.stream_pool_size);
+
+    // dropped once the client's hello is answered, or it never will be
+    let (negotiated, is_negotiated) = oneshot::channel::<()>();
+
+    let x = async {
+        info!("tunnel client connected");
+
+        let record_fingerprint = async {
+            if let Some(x) = handshake {
+                x.await;
+            }
+
+            let fingerprint = peer_fingerprint(pool_a.connection());
+
+            // a 0-rtt handshake fails after the connection is handed to us
+            if let (None, Some(err)) = (&fingerprint, pool_a.connection().close_reason()) {
+                audit_auth_failure(&shared, pool_a.connection().remote_address(), &err);
+
+                return;
+            }
+
+            // so connections our session left behind don't count against the identity limit
+            let _ = is_negotiated.await;
+
+            if let Some(x) = &fingerprint {
+                Span::current().record("client_fingerprint", x);
+
+                if !client.set_identity(x, shared.identity_limit.as_ref()) {
+                    info!("this client cert is at its connection limit. refusing the new connection");
+
+                    return;
+                }
+
+                shared.usage.connected(x);
+            }
+
+            shared.audit.record(AuditEvent::ClientConnected {
+                conn_id: client.id(),
+                peer: pool_a.connection().remote_address(),
+                client_fingerprint: fingerprint,
+                role: "reverse".to_string(),
+            });
+        };
+
+        let proxy = async {
+            let x = negotiate(pool_a.connection(), &shared).await;
+
+            if let Ok((_, Some(session), ..)) = &x {
+                client.set_session(*session);
+            }
+
+            drop(negotiated);
+
+            let (version, _, tx, rx) = x?;
+
+            client.set_protocol_version(version);
+
+            let (stop, stopped) = oneshot::channel();
+
+            let (notices, outbox) = flume::unbounded();
+
+            let liveness = Liveness::default();
+
+            let control = control::run(
+                pool_a.connection(),
+                version,
+                tx,
+                rx,
+                &shared.heartbeat,
+                outbox,
+                stopped.map(|x| x.ok().flatten()),
+                &liveness,
+            );
+
+            let streams = async {
+                let x = proxy_user_streams(
+                    &pool_a,
+                    client.id(),
+                    version,
+                    &notices,
+                    &liveness,
+                    &shared,
+                )
+                .await;
+
+                // streams we already started keep the connection open until they finish
+                let last = if shared.shutdown.is_cancelled() {
+                    Some(ControlMessage::Close {
+                        code: CloseCode::Done as u32,
+                        reason: "server shutting down".to_string(),
+                    })
+                } else if shared.draining.is_cancelled() {
+                    debug!("draining. telling the client to reconnect");
+
+                    Some(ControlMessage::GoAway)
+                } else {
+                    None
+                };
+
+                let _ = stop.send(last);
+
+                x
+            };
+
+            let (end, x) = join!(control, streams);
+
+            match end {
+                ControlEnd::Closed { code, reason } => {
+                    info!(code = %CloseCode::describe(code), %reason, "tunnel client is closing the connection");
+                }
+                ControlEnd::TimedOut => warn!("tunnel client stopped answering heartbeats"),
+                ControlEnd::GoAway | ControlEnd::Stopped => {}
+            }
+
+            x
+        };
+
+        let (_, x) = join!(
+            record_fingerprint,
+            log_path_mtu(pool_a.connection(), proxy)
+        );
+
+        if let Some((code, reason)) = pool_a
+            .connection()
+            .close_reason()
+            .as_ref()
+            .and_then(CloseCode::from_error)
+        {
+            info!(code = %CloseCode::describe(code), %reason, "tunnel client connection closed");
+        }
+
+        x
+    }
+    .instrument(span)
+    .await;
+
+    shared
+        .connected_clients
+        .fetch_sub(1, atomic::Ordering::SeqCst);
+
+    x
+}
+
+/// read the client's hello and refuse it if it can't handle every listener's compression or shares no protocol version.
+/// returns the version, the client's session, and our side of the control stream
+async fn negotiate(
+    conn: &Connection,
+    shared: &ServerShared,
+) -> anyhow::Result<(u8, Option<u64>, SendStream, RecvStream)> {
+    let (mut tx, mut rx) = timeout(PREAMBLE_TIMEOUT, conn.accept_bi())
+        .await
+        .context("client never opened its control stream")??;
+
+    let accepted = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+        Some(ControlMessage::Hello { compress }) => compress,
+        x => anyhow::bail!("expected hello from client. got {:?}", x),
+    };
+
+    // version 3 clients finish their side after hello
+    let theirs = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+        Some(ControlMessage::Version { min, max }) => Some((min, max)),
+        None => None,
+        x => anyhow::bail!("expected version from client. got {:?}", x),
+    };
+
+    trace!(?accepted, ?theirs, "client hello");
+
+    let (min, max) = theirs.unwrap_or((IMPLIED_VERSION, IMPLIED_VERSION));
+
+    let version = match negotiate_version(min, max, "client", "server") {
+        Ok(x) => x,
+        Err(reason) => return Err(refuse(conn, tx, reason).await),
+    };
+
+    let missing: Vec<_> = shared
+        .compress_used
+        .iter()
+        .filter(|x| !accepted.contains(x))
+        .collect();
+
+    if !missing.is_empty() {
+        let reason = format!(
+            "listeners use {:?} compression but the client only accepts {:?}. start the client with a matching --compress",
+            missing, accepted
+        );
+
+        return Err(refuse(conn, tx, reason).await);
+    }
+
+    if theirs.is_some() {
+        let x = ControlMessage::Version {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+
+        tx.write_all(&x.encode()?).await?;
+    }
+
+    let welcome = ControlMessage::Welcome {
+        compress: shared.compress_used.clone(),
+    };
+
+    tx.write_all(&welcome.encode()?).await?;
+
+    let session = match version >= SESSION_VERSION {
+        true => match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+            Some(ControlMessage::Session { id }) => Some(id),
+            x => anyhow::bail!("expected session from client. got {:?}", x),
+        },
+        false => None,
+    };
+
+    debug!(
+        version,
+        session = session.map(|x| format!("{x:016x}")),
+        "negotiated with client"
+    );
+
+    Ok((version, session, tx, rx))
+}
+
+/// tell the client why we won't serve it and close the connection
+async fn refuse(conn: &Connection, mut tx: SendStream, reason: String) -> anyhow::Error {
+    warn!(%reason, "refusing tunnel client");
+
+    let refusal = ControlMessage::Error {
+        code: CloseCode::Incompatible as u32,
+        reason: reason.clone(),
+    };
+
+    // best effort. the close reason says the same thing if this doesn't arrive first
+    if let Ok(x) = refusal.encode() {
+        if tx.write_all(&x).await.is_ok() {
+            let _ = timeout(Duration::from_secs(1), tx.finish()).await;
+        }
+    }
+
+    // quic caps the close reason to fit in one packet
+    conn.close(CloseCode::Incompatible.into(), reason.as_bytes());
+
+    TunnelError::Incompatible(reason).into()
+}
+
+async fn proxy_user_streams(
+    pool_a: &StreamPool,
+    client_id: u64,
+    version: u8,
+    notices: &flume::Sender<ControlMessage>,
+    liveness: &Liveness,
+    shared: &ServerShared,
+) -> anyhow::Result<()> {
+    // TODO: look at the handshake data to figure out what client connected? that way we know what TcpListener to connect it to?
+
+    let mut warm_up = WarmUp::new(shared.warm_up, shared.warm_up_streams);
+
+    // this connection's line in the stats breakdown lasts as long as it and its streams do
+    let counts = shared
+        .counts
+        .connection(client_id)
+        .with_path(pool_a.connection());
+
+    // every stream from this client draws from the same buckets
+    let copy_options = CopyOptions {
+        close_mode: shared.close_mode,
+        rate_limit: shared
+            .per_client_rate
+            .map(|x| ClientRateLimit::new(x, shared.per_client_burst)),
+        idle_timeout: shared.stream_idle_timeout,
+        max_lifetime: shared.stream_max_lifetime,
+        padding: shared.padding.clone(),
+        reset_after: None,
+        capture: None,
+    };
+
+    // a client that misses two heartbeats is likely dead, even before it times out. its users go to other clients
+    let quiet = shared.heartbeat.interval * 2;
+
+    // a permit is held by every stream until it finishes
+    let stream_slots = shared
+        .max_streams_per_client
+        .map(|x| Arc::new(Semaphore::new(x)));
+
+    // the client is told the first time, like for its quota. after that it would be every user
+    let mut told_about_slots = false;
+
+    loop {
+        if !wait_for_quota(pool_a, client_id, &counts, notices, shared).await {
+            break;
+        }
+
+        if let Some(delay) = warm_up.delay(Instant::now()) {
+            trace!(?delay, queued = shared.stream_receiver.len(), "warming up");
+
+            select! {
+                _ = sleep(delay) => {}
+                _ = shared.draining.cancelled() => break,
+            }
+        }
+
+        // don't take a user we can't start yet. other clients might have room
+        let stream_slot = match &stream_slots {
+            Some(x) => {
+                if x.available_permits() == 0 {
+                    debug!("at max streams. waiting for one to finish");
+
+                    if !told_about_slots {
+                        told_about_slots = true;
+
+                        let _ = notices.send(ControlMessage::Error {
+                            code: CloseCode::TooManyStreams as u32,
+                            reason: format!(
+                                "this client has {} streams open, the most the server allows",
+                                shared.max_streams_per_client.unwrap_or_default()
+                            ),
+                        });
+                    }
+                }
+
+                select! {
+                    x = x.clone().acquire_owned() => Some(x?),
+                    err = pool_a.closed() => {
+                        debug!(?err, "tunnel client disconnected");
+                        break;
+                    }
+                    _ = shared.draining.cancelled() => break,
+                }
+            }
+            None => None,
+        };
+
+        if !wait_until_heard(pool_a, liveness, quiet, shared).await {
+            break;
+        }
+
+        // stop reading the channel as soon as the client is gone so its users go to another client
+        let pending_b = select! {
+            x = shared.stream_receiver.recv_async() => x,
+            err = pool_a.closed() => {
+                debug!(?err, "tunnel client disconnected");
+                break;
+            }
+            _ = shared.draining.cancelled() => break,
+        };
+
+        let Ok(pending_b) = pending_b else {
+            break;
+        };
+
+        // it might have gone quiet while we waited for a user
+        if !liveness.heard_within(quiet) {
+            let _ = shared.stream_sender.send_async(pending_b).await;
+
+            continue;
+        }
+
+        debug!(?pending_b, "user connected");
+
+        warm_up.assigned();
+
+        // each new user stream gets a new QUIC stream
+        let (mut tx_a, rx_a) = match pool_a.open_bi().await {
+            Ok(x) => x,
+            Err(err) => {
+                // like when a reconnect took this connection's session over. the user hasn't been sent anywhere yet
+                hand_off(pending_b, shared).await;
+
+                return Err(err.into());
+            }
+        };
+
+        // the client sets its own side by route
+        let _ = tx_a.set_priority(pending_b.priority);
+
+        trace!("reverse proxy stream opened");
+
+        let compress_algo = pending_b.compress;
+
+        let once = pending_b.listener == ListenTarget::Stdio;
+
+        let peer_addr = pending_b.stream.transform_context().peer_addr;
+
+        // None if a 0-rtt handshake is still going
+        let client_fingerprint = peer_fingerprint(pool_a.connection());
+
+        let stream_guard = shared.registry.add_stream(
+            client_id,
+            pending_b.route.clone(),
+            peer_addr,
+            StreamCounters::new(
+                match &client_fingerprint {
+                    Some(x) => counts
+                        .with_listener(&pending_b.listener.to_string())
+                        .with_identity(shared.usage.counters(x)),
+                    None => counts.with_listener(&pending_b.listener.to_string()),
+                },
+                pending_b.accepted_at,
+            ),
+        );
+
+        // spawned tasks don't inherit the connection's span, so repeat conn_id here.
+        // the byte counts and close reason are recorded when the stream finishes. exported to OpenTelemetry, each stream is one span
+        let span = info_span!(
+            "stream",
+            conn_id = client_id,
+            stream_id = stream_guard.id(),
+            peer = peer_addr.map(tracing::field::display),
+            service = %pending_b.route,
+            listener = %pending_b.listener,
+            client_fingerprint = client_fingerprint.as_deref(),
+            bytes_from_tunnel = tracing::field::Empty,
+            bytes_to_tunnel = tracing::field::Empty,
+            close_reason = tracing::field::Empty,
+        );
+
+        let audit = shared.audit.wants("stream").then(|| {
+            (
+                shared.audit.clone(),
+                stream_guard.id(),
+                stream_guard.counters(),
+                pending_b.listener.to_string(),
+                pending_b.route.clone(),
+                client_fingerprint.clone(),
+                pending_b.accepted_at,
+            )
+        });
+
+        // TODO: counters while the stream happens
+        let conn_a = pool_a.connection().clone();
+        let rtt_counts = counts.clone();
+        let capture = shared.debug_dump.as_ref().and_then(|x| {
+            x.capture(
+                &format!("conn{client_id}-stream{}", stream_guard.id()),
+                &format!(
+                    "route={} listener={} peer={}",
+                    pending_b.route,
+                    pending_b.listener,
+                    peer_addr.map_or("-".to_string(), |x| x.to_string())
+                ),
+            )
+        });
+
+        let copy_options = CopyOptions {
+            reset_after: shared.chaos.reset_after(),
+            capture,
+            ..copy_options.clone()
+        };
+
+        let f = async move {
+            // released when the stream finishes, however it finishes
+            let _stream_slot = stream_slot;
+            let _slots = pending_b.slots;
+
+            // tell the client what this stream is for
+            let preamble = StreamPreamble::new(&pending_b.route)?
+                .with_version(version)
+                .with_compress(compress_algo)
+                .with_padding(copy_options.padding.mode);
+            tx_a.write_all(&preamble.encode()).await?;
+
+            let x = copy_bidirectional_with_compression(
+                compress_algo,
+                rx_a,
+                tx_a,
+                pending_b.stream,
+                pending_b.transform,
+                stream_guard.counters(),
+                copy_options,
+            )
+            .await;
+
+            rtt_counts.rtt(conn_a.rtt());
+
+            x
+        };
+
+        let f = f
+            .inspect_err(|e| {
+                if let TunnelError::StreamIdle(idle) = e {
+                    info!(?idle, "closed idle stream");
+
+                    Span::current().record("close_reason", "idle");
+                } else if let TunnelError::StreamExpired(lifetime) = e {
+                    info!(
+                        ?lifetime,
+                        "closed a stream that outlived the max stream lifetime"
+                    );
+
+                    Span::current().record("close_reason", "expired");
+                } else if let TunnelError::ChaosReset = e {
+                    debug!("reset by chaos");
+
+                    Span::current().record("close_reason", "chaos");
+                } else if e.stream_close_code() == Some(CloseCode::Unreachable) {
+                    warn!("the client couldn't reach its backend");
+
+                    Span::current().record("close_reason", "unreachable");
+                } else {
+                    error!("failed: {}", e);
+
+                    Span::current().record("close_reason", tracing::field::display(e));
+                }
+            })
+            .inspect_ok(|(a_to_b, b_to_a)| {
+                trace!(%a_to_b, %b_to_a, "success");
+
+                Span::current()
+                    .record("bytes_from_tunnel", a_to_b)
+                    .record("bytes_to_tunnel", b_to_a)
+                    .record("close_reason", "eof");
+            });
+
+        let shutdown = shared.shutdown.clone();
+
+        // spawn to handle multiple requests at once
+        shared.tracker.spawn_on(
+            async move {
+                let close_reason = select! {
+                    x = f => match x {
+                        Ok(_) => "eof".to_string(),
+                        Err(TunnelError::StreamIdle(_)) => "idle".to_string(),
+                        Err(TunnelError::StreamExpired(_)) => "expired".to_string(),
+                        Err(e) => e.to_string(),
+                    },
+                    _ = shutdown.cancelled() => {
+                        trace!("stream stopped by shutdown");
+
+                        Span::current().record("close_reason", "shutdown");
+
+                        "shutdown".to_string()
+                    }
+                };
+
+                if let Some((
+                    audit,
+                    stream_id,
+                    counters,
+                    listener,
+                    service,
+                    client_fingerprint,
+                    accepted_at,
+                )) = audit
+                {
+                    audit.record(AuditEvent::Stream {
+                        conn_id: client_id,
+                        stream_id,
+                        peer: peer_addr,
+                        listener,
+                        service,
+                        client_fingerprint,
+                        duration: audit::millis(accepted_at.elapsed()),
+                        bytes_from_tunnel: counters.from_tunnel.load(atomic::Ordering::Relaxed),
+                        bytes_to_tunnel: counters.to_tunnel.load(atomic::Ordering::Relaxed),
+                        close_reason,
+                    });
+                }
+
+                if once {
+                    info!("stdio user finished. stopping the server");
+
+                    shutdown.cancel();
+                }
+            }
+            .instrument(span),
+            &shared.data_plane,
+        );
+    }
+
+    Ok(())
+}
+
+/// record a handshake that failed, like on a certificate our CA didn't sign. a client that doesn't trust ours gives up on
+/// the handshake with any code it likes, so timeouts are the only failures that aren't about certificates
+fn audit_auth_failure(shared: &ServerShared, peer: SocketAddr, err: &quinn::ConnectionError) {
+    if matches!(
+        err,
+        quinn::ConnectionError::TransportError(_) | quinn::ConnectionError::ConnectionClosed(_)
+    ) {
+        shared.audit.record(AuditEvent::AuthFailed {
+            peer,
+            reason: err.to_string(),
+        });
+    }
+}
+
+/// count and audit a client that isn't a reverse proxy client, like a `udp_client`. `role` is what the audit log calls it
+fn side_client_connected(shared: &ServerShared, conn: &Connection, role: &str) -> ScopedCounters {
+    let conn_id = conn.stable_id() as u64;
+    let mut counts = shared.counts.connection(conn_id).with_path(conn);
+
+    // None if a 0-rtt handshake is still going
+    let fingerprint = peer_fingerprint(conn);
+
+    if let Some(x) = &fingerprint {
+        shared.usage.connected(x);
+
+        counts = counts.with_identity(shared.usage.counters(x));
+    }
+
+    shared.audit.record(AuditEvent::ClientConnected {
+        conn_id,
+        peer: conn.remote_address(),
+        client_fingerprint: fingerprint,
+        role: role.to_string(),
+    });
+
+    audit_disconnect(shared, conn, conn_id, counts.clone());
+
+    counts
+}
+
+/// record `conn` in the audit log once it is closed, with what `counts` has for it by then
+fn audit_disconnect(
+    shared: &ServerShared,
+    conn: &Connection,
+    conn_id: u64,
+    counts: ScopedCounters,
+) {
+    if !shared.audit.wants("client_disconnected") {
+        return;
+    }
+
+    let audit = shared.audit.clone();
+    let conn = conn.clone();
+    let connected_at = Instant::now();
+    let shutdown = shared.shutdown.clone();
+
+    shared.tracker.spawn_on(
+        async move {
+            let close_reason = select! {
+                err = conn.closed() => match CloseCode::from_error(&err) {
+                    Some((code, reason)) if reason.is_empty() => CloseCode::describe(code),
+                    Some((code, reason)) => format!("{}: {reason}", CloseCode::describe(code)),
+                    // we closed it. the reason is in the log
+                    None => err.to_string(),
+                },
+                _ = shutdown.cancelled() => "server shutting down".to_string(),
+            };
+
+            // the handshake never finished. that was `auth_failed`
+            let Some(client_fingerprint) = peer_fingerprint(&conn) else {
+                return;
+            };
+
+            let bytes = counts.connection_snapshot().unwrap_or_default();
+
+            audit.record(AuditEvent::ClientDisconnected {
+                conn_id,
+                peer: conn.remote_address(),
+                client_fingerprint: Some(client_fingerprint),
+                connected_for: audit::millis(connected_at.elapsed()),
+                bytes_sent: bytes.bytes_sent,
+                bytes_recv: bytes.bytes_recv,
+                close_reason,
+            });
+        },
+        &shared.data_plane,
+    );
+}
+
+/// give a user whose tunnel client went away before its stream started to another client, unless too many already have
+async fn hand_off(mut pending: PendingStream, shared: &ServerShared) {
+    pending.handoffs += 1;
+
+    if pending.handoffs > MAX_HANDOFFS {
+        warn!(
+            handoffs = MAX_HANDOFFS,
+            "tunnel clients keep failing under this user. turning it away"
+        );
+
+        shared.tracker.spawn_on(
+            reject(
+                pending.stream,
+                RejectReason::NoTunnelClient,
+                shared.error_hints,
+            ),
+            &shared.data_plane,
+        );
+
+        return;
+    }
+
+    debug!(
+        handoffs = pending.handoffs,
+        "tunnel client disconnected. handing the user to another client"
+    );
+
+    let _ = shared.stream_sender.send_async(pending).await;
+}
+
+/// wait until the client has been heard from in the last `quiet`. false if it disconnects or we start draining first
+async fn wait_until_heard(
+    pool_a: &StreamPool,
+    liveness: &Liveness,
+    quiet: Duration,
+    shared: &ServerShared,
+) -> bool {
+    if liveness.heard_within(quiet) {
+        return true;
+    }
+
+    debug!(
+        ?quiet,
+        "tunnel client has gone quiet. leaving users to other clients"
+    );
+
+    loop {
+        select! {
+            _ = liveness.next_heard() => {}
+            err = pool_a.closed() => {
+                debug!(?err, "tunnel client disconnected");
+                return false;
+            }
+            _ = shared.draining.cancelled() => return false,
+        }
+
+        if liveness.heard_within(quiet) {
+            debug!("tunnel client is answering again");
+
+            return true;
+        }
+    }
+}
+
+/// false if the client should get no more users, because it is gone, we are draining, or it was closed for its quota.
+/// a client over its quota waits here until the quota allows more, like when a new month starts
+async fn wait_for_quota(
+    pool_a: &StreamPool,
+    client_id: u64,
+    counts: &ScopedCounters,
+    notices: &flume::Sender<ControlMessage>,
+    shared: &ServerShared,
+) -> bool {
+    if !shared.quota.is_enabled() {
+        return true;
+    }
+
+    let exceeded = || {
+        // None if a 0-rtt handshake is still going
+        let identity = peer_fingerprint(pool_a.connection());
+
+        let bytes = counts
+            .connection_snapshot()
+            .map_or(0, |x| x.bytes_sent.saturating_add(x.bytes_recv));
+
+        shared
+            .quota
+            .exceeded(identity.as_deref(), &shared.usage, bytes)
+    };
+
+    let Some(reason) = exceeded() else {
+        return true;
+    };
+
+    warn!(%reason, "tunnel client is over its quota");
+
+    shared.audit.record(AuditEvent::QuotaExceeded {
+        conn_id: client_id,
+        peer: pool_a.connection().remote_address(),
+        client_fingerprint: peer_fingerprint(pool_a.connection()),
+        reason: reason.clone(),
+    });
+
+    if shared.quota.disconnect {
+        pool_a
+            .connection()
+            .close(CloseCode::QuotaExceeded.into(), reason.as_bytes());
+
+        return false;
+    }
+
+    let _ = notices.send(ControlMessage::Error {
+        code: CloseCode::QuotaExceeded as u32,
+        reason,
+    });
+
+    loop {
+        select! {
+            _ = sleep(quota::RECHECK_INTERVAL) => {}
+            err = pool_a.closed() => {
+                debug!(?err, "tunnel client disconnected");
+                return false;
+            }
+            _ = shared.draining.cancelled() => return false,
+        }
+
+        if exceeded().is_none() {
+            info!("tunnel client is under its quota again");
+
+            return true;
+        }
+    }
+}
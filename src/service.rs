@@ -0,0 +1,272 @@
+//! Running as a Windows service, for reverse proxy clients on Windows boxes.
+//!
+//! The service runs `service run` with a config file. Stopping it, or shutting Windows down, drains like an upgrade does.
+//! Services have no console, so use `--log-file` to keep the logs. The functions exist everywhere, but only work on Windows.
+
+#[cfg(windows)]
+pub use windows::{dispatch, install, uninstall};
+
+#[cfg(not(windows))]
+pub use other::{dispatch, install, uninstall};
+
+pub const DEFAULT_NAME: &str = "quic-tunnel";
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::{c_void, OsStr};
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+    use std::sync::{Mutex, OnceLock};
+
+    use tracing::{error, info};
+    use windows_sys::core::PWSTR;
+    use windows_sys::Win32::Foundation::{
+        ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+    };
+    use windows_sys::Win32::Security::SC_HANDLE;
+    use windows_sys::Win32::System::Services::{
+        CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW,
+        OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+        SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN,
+        SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS, SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE,
+        SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS,
+        SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE, SERVICE_STOPPED,
+        SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    };
+
+    use crate::shutdown::CancellationToken;
+
+    /// how long Windows should wait for us to stop before it thinks we are stuck
+    const STOP_WAIT_HINT_MS: u32 = 30_000;
+
+    type Run = Box<dyn FnOnce(CancellationToken) -> anyhow::Result<()> + Send>;
+
+    /// windows calls `service_main` without a way to pass it anything, so `dispatch` leaves it here
+    static SERVICE: Mutex<Option<(Vec<u16>, Run)>> = Mutex::new(None);
+
+    /// cancelled when windows asks us to stop
+    static STOP: OnceLock<CancellationToken> = OnceLock::new();
+
+    static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+
+    fn wide(x: &str) -> Vec<u16> {
+        OsStr::new(x).encode_wide().chain(Some(0)).collect()
+    }
+
+    /// closed when dropped
+    struct ScHandle(SC_HANDLE);
+
+    impl ScHandle {
+        fn new(x: SC_HANDLE) -> io::Result<Self> {
+            match x {
+                0 => Err(io::Error::last_os_error()),
+                x => Ok(Self(x)),
+            }
+        }
+    }
+
+    impl Drop for ScHandle {
+        fn drop(&mut self) {
+            // SAFETY: the handle came from the service control manager and is only closed here
+            unsafe { CloseServiceHandle(self.0) };
+        }
+    }
+
+    fn open_manager(access: u32) -> io::Result<ScHandle> {
+        // SAFETY: null means the local machine's active database
+        ScHandle::new(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+    }
+
+    /// add a service that runs `command_line` at boot. needs an administrator
+    pub fn install(name: &str, command_line: &str) -> io::Result<()> {
+        let manager = open_manager(SC_MANAGER_CREATE_SERVICE)?;
+
+        let name = wide(name);
+        let command_line = wide(command_line);
+
+        // SAFETY: the strings are nul terminated and outlive the call. the nulls are optional arguments we don't use
+        let service = unsafe {
+            CreateServiceW(
+                manager.0,
+                name.as_ptr(),
+                name.as_ptr(),
+                SERVICE_QUERY_STATUS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command_line.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+
+        ScHandle::new(service).map(drop)
+    }
+
+    /// stop the service if it is running and remove it. windows finishes removing it once it has stopped
+    pub fn uninstall(name: &str) -> io::Result<()> {
+        let manager = open_manager(SC_MANAGER_CONNECT)?;
+
+        let name = wide(name);
+
+        // SAFETY: the name is nul terminated and outlives the call
+        let service =
+            ScHandle::new(unsafe { OpenServiceW(manager.0, name.as_ptr(), SERVICE_ALL_ACCESS) })?;
+
+        // SAFETY: all zeroes is a valid SERVICE_STATUS, and ControlService only writes to it
+        let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+
+        // fails if it isn't running, which is fine
+        // SAFETY: the handle is open and `status` lives through the call
+        unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) };
+
+        // SAFETY: the handle is open
+        if unsafe { DeleteService(service.0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// hand this thread to the service control manager until the service stops. `run` is called on another thread
+    /// with a token that is cancelled when windows asks us to stop. fails if windows didn't start us as a service
+    pub fn dispatch(
+        name: &str,
+        run: impl FnOnce(CancellationToken) -> anyhow::Result<()> + Send + 'static,
+    ) -> io::Result<()> {
+        *SERVICE.lock().expect("service lock poisoned") = Some((wide(name), Box::new(run)));
+
+        let mut name = wide(name);
+
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(service_main),
+            },
+            // the table ends with an empty entry
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+
+        // SAFETY: the table and the name it points to outlive the call, which returns once the service has stopped
+        if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+        let Some((name, run)) = SERVICE.lock().expect("service lock poisoned").take() else {
+            return;
+        };
+
+        let stop = STOP.get_or_init(CancellationToken::new).clone();
+
+        // SAFETY: the name is nul terminated, and windows copies it
+        let handle =
+            unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control), ptr::null()) };
+
+        if handle == 0 {
+            error!(err = ?io::Error::last_os_error(), "unable to register the service control handler");
+            return;
+        }
+
+        STATUS_HANDLE.store(handle, Ordering::SeqCst);
+
+        set_status(SERVICE_RUNNING, NO_ERROR);
+
+        info!("running as a windows service");
+
+        let exit_code = match run(stop) {
+            Ok(()) => NO_ERROR,
+            Err(err) => {
+                error!(?err, "service failed");
+                ERROR_SERVICE_SPECIFIC_ERROR
+            }
+        };
+
+        set_status(SERVICE_STOPPED, exit_code);
+    }
+
+    extern "system" fn control(
+        control: u32,
+        _event_type: u32,
+        _event_data: *mut c_void,
+        _context: *mut c_void,
+    ) -> u32 {
+        match control {
+            SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+                info!("windows asked us to stop. draining");
+
+                set_status(SERVICE_STOP_PENDING, NO_ERROR);
+
+                if let Some(x) = STOP.get() {
+                    x.cancel();
+                }
+
+                NO_ERROR
+            }
+            SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+            _ => ERROR_CALL_NOT_IMPLEMENTED,
+        }
+    }
+
+    fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+        let status = SERVICE_STATUS {
+            dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+            dwCurrentState: state,
+            // only a running service can be stopped
+            dwControlsAccepted: match state {
+                SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+                _ => 0,
+            },
+            dwWin32ExitCode: exit_code,
+            dwServiceSpecificExitCode: (exit_code == ERROR_SERVICE_SPECIFIC_ERROR).into(),
+            dwCheckPoint: 0,
+            dwWaitHint: match state {
+                SERVICE_STOP_PENDING => STOP_WAIT_HINT_MS,
+                _ => 0,
+            },
+        };
+
+        // SAFETY: the handle is from RegisterServiceCtrlHandlerExW and `status` lives through the call
+        unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &status) };
+    }
+}
+
+#[cfg(not(windows))]
+mod other {
+    use std::io;
+
+    use crate::shutdown::CancellationToken;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "services only exist on windows. see the daemon module",
+        )
+    }
+
+    pub fn install(_name: &str, _command_line: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn uninstall(_name: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn dispatch(
+        _name: &str,
+        _run: impl FnOnce(CancellationToken) -> anyhow::Result<()> + Send + 'static,
+    ) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
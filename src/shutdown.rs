@@ -0,0 +1,41 @@
+//! Every long running task watches a `CancellationToken` so the tunnel can be stopped deterministically.
+
+pub use tokio_util::sync::CancellationToken;
+pub use tokio_util::task::TaskTracker;
+
+use tokio::signal;
+use tokio::task::JoinHandle;
+use tracing::info;
+#[cfg(unix)]
+use tracing::warn;
+
+/// cancel the token on ctrl-c or SIGTERM
+pub fn cancel_on_signal(token: CancellationToken) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let ctrl_c = signal::ctrl_c();
+
+        #[cfg(unix)]
+        let terminate = async {
+            match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+                Ok(mut x) => {
+                    x.recv().await;
+                }
+                Err(err) => {
+                    warn!(?err, "unable to listen for SIGTERM");
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => info!("ctrl-c received. shutting down"),
+            _ = terminate => info!("SIGTERM received. shutting down"),
+            _ = token.cancelled() => return,
+        }
+
+        token.cancel();
+    })
+}
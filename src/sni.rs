@@ -0,0 +1,487 @@
+//! Routing TCP users by the hostname in their TLS ClientHello, without terminating TLS.
+//!
+//! The ClientHello is peeked, not read, so the backend still gets the whole handshake. Users that don't send TLS, or send no
+//! server name, or one no rule matches, go to the listener's own route.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+use tracing::trace;
+
+/// how long a user gets to send their ClientHello or request head before they go to the listener's route
+pub const PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// big ClientHellos with post-quantum key shares are a few KiB. anything longer than this isn't worth waiting for
+const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+/// how long to wait for the rest of a ClientHello split over several TCP segments. peeking doesn't wait for new bytes
+const PEEK_RETRY: Duration = Duration::from_millis(5);
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// users asking for `host` go to `route`.
+/// `host` is a name like "db.example.com", a wildcard like "*.example.com" for any name under it, or "*" for any name at all
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRule {
+    pub host: String,
+    pub route: String,
+}
+
+impl SniRule {
+    pub fn matches(&self, name: &str) -> bool {
+        host_matches(&self.host, name)
+    }
+}
+
+/// `host` is a name, a wildcard like "*.example.com", or "*". names are compared ignoring case
+pub fn host_matches(host: &str, name: &str) -> bool {
+    if host == "*" {
+        return true;
+    }
+
+    match host.strip_prefix("*.") {
+        Some(parent) => name
+            .len()
+            .checked_sub(parent.len() + 1)
+            .filter(|&i| i > 0)
+            .is_some_and(|i| {
+                name.as_bytes()[i] == b'.' && name[i + 1..].eq_ignore_ascii_case(parent)
+            }),
+        None => name.eq_ignore_ascii_case(host),
+    }
+}
+
+impl FromStr for SniRule {
+    type Err = String;
+
+    /// "host=route"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((host, route)) if !host.is_empty() && !route.is_empty() => Ok(Self {
+                host: host.to_string(),
+                route: route.to_string(),
+            }),
+            _ => Err(format!("\"{s}\" is not a rule like example.com=web")),
+        }
+    }
+}
+
+/// the route of the first rule that matches, if any
+pub fn route<'a>(rules: &'a [SniRule], name: &str) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|x| x.matches(name))
+        .map(|x| x.route.as_str())
+}
+
+/// the server name in the ClientHello the user is sending, without taking it off the socket.
+/// `None` if they don't send one within `PEEK_TIMEOUT`
+pub async fn peek_server_name(stream: &TcpStream) -> Option<String> {
+    let x = peek(stream, MAX_CLIENT_HELLO_LEN, parse_client_hello).await;
+
+    trace!(server_name = ?x, "peeked ClientHello");
+
+    x
+}
+
+pub(crate) enum Parsed<T> {
+    Incomplete,
+    /// `None` if what the user sent isn't what we were looking for
+    Done(Option<T>),
+}
+
+/// peek at up to `max` bytes until `parse` is done with them, or `PEEK_TIMEOUT` passes
+pub(crate) async fn peek<T>(
+    stream: &TcpStream,
+    max: usize,
+    parse: impl Fn(&[u8]) -> Parsed<T>,
+) -> Option<T> {
+    let mut buf = vec![0; max];
+
+    let x = timeout(PEEK_TIMEOUT, async {
+        let mut seen = 0;
+
+        loop {
+            let n = stream.peek(&mut buf).await.ok()?;
+
+            if let Parsed::Done(x) = parse(&buf[..n]) {
+                return x;
+            }
+
+            // closed, or longer than anything we'd wait for
+            if n == 0 || n == buf.len() {
+                return None;
+            }
+
+            // peeking returns what is already there right away, so give the rest a moment to arrive
+            if n == seen {
+                sleep(PEEK_RETRY).await;
+            }
+
+            seen = n;
+        }
+    })
+    .await;
+
+    x.ok().flatten()
+}
+
+/// the ClientHello can be split over several records, so their handshake bytes are put back together first
+fn parse_client_hello(buf: &[u8]) -> Parsed<String> {
+    let mut handshake = Vec::new();
+    let mut rest = buf;
+
+    loop {
+        let Some(header) = rest.get(..RECORD_HEADER_LEN) else {
+            return Parsed::Incomplete;
+        };
+
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Parsed::Done(None);
+        }
+
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+
+        let Some(fragment) = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+            return Parsed::Incomplete;
+        };
+
+        handshake.extend_from_slice(fragment);
+        rest = &rest[RECORD_HEADER_LEN + len..];
+
+        if handshake.len() < 4 {
+            continue;
+        }
+
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return Parsed::Done(None);
+        }
+
+        let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+
+        if handshake.len() >= 4 + hello_len {
+            return Parsed::Done(server_name(&handshake[4..4 + hello_len]));
+        }
+    }
+}
+
+fn server_name(mut hello: &[u8]) -> Option<String> {
+    // version and random
+    take(&mut hello, 2 + 32)?;
+
+    let session_id = take(&mut hello, 1)?[0] as usize;
+    take(&mut hello, session_id)?;
+
+    let cipher_suites = take_u16(&mut hello)? as usize;
+    take(&mut hello, cipher_suites)?;
+
+    let compression = take(&mut hello, 1)?[0] as usize;
+    take(&mut hello, compression)?;
+
+    let extensions_len = take_u16(&mut hello)? as usize;
+    let mut extensions = take(&mut hello, extensions_len)?;
+
+    while !extensions.is_empty() {
+        let kind = take_u16(&mut extensions)?;
+        let len = take_u16(&mut extensions)? as usize;
+        let mut data = take(&mut extensions, len)?;
+
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let list_len = take_u16(&mut data)? as usize;
+        let mut list = take(&mut data, list_len)?;
+
+        while !list.is_empty() {
+            let name_type = take(&mut list, 1)?[0];
+            let len = take_u16(&mut list)? as usize;
+            let name = take(&mut list, len)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(|x| x.to_string());
+            }
+        }
+    }
+
+    None
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+
+    let (x, rest) = buf.split_at(n);
+    *buf = rest;
+
+    Some(x)
+}
+
+fn take_u16(buf: &mut &[u8]) -> Option<u16> {
+    take(buf, 2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// the first flight of a real rustls client
+    fn real_client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+
+        config.enable_sni = server_name.is_some();
+
+        let name = server_name.unwrap_or("ignored.example.com");
+
+        let mut conn =
+            rustls::ClientConnection::new(Arc::new(config), name.try_into().unwrap()).unwrap();
+
+        let mut x = vec![];
+        conn.write_tls(&mut x).unwrap();
+
+        x
+    }
+
+    /// a ClientHello with just enough in it for `server_name`, in one record
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![3, 3];
+        hello.extend([7; 32]);
+        // no session id, one cipher suite, null compression
+        hello.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        record(&handshake)
+    }
+
+    fn record(fragment: &[u8]) -> Vec<u8> {
+        let mut x = vec![CONTENT_TYPE_HANDSHAKE, 3, 1];
+        x.extend((fragment.len() as u16).to_be_bytes());
+        x.extend(fragment);
+        x
+    }
+
+    fn sni_extension(names: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut list = vec![];
+
+        for (kind, name) in names {
+            list.push(*kind);
+            list.extend((name.len() as u16).to_be_bytes());
+            list.extend(*name);
+        }
+
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend(list);
+
+        let mut x = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        x.extend((data.len() as u16).to_be_bytes());
+        x.extend(data);
+        x
+    }
+
+    fn parsed(buf: &[u8]) -> Option<Option<String>> {
+        match parse_client_hello(buf) {
+            Parsed::Incomplete => None,
+            Parsed::Done(x) => Some(x),
+        }
+    }
+
+    #[test]
+    fn real_client_hellos() {
+        let x = real_client_hello(Some("db.example.com"));
+        assert_eq!(parsed(&x), Some(Some("db.example.com".to_string())));
+
+        let x = real_client_hello(None);
+        assert_eq!(parsed(&x), Some(None));
+    }
+
+    #[test]
+    fn split_records() {
+        let x = real_client_hello(Some("db.example.com"));
+        let handshake = &x[RECORD_HEADER_LEN..];
+
+        // the first record is too short to even say how long the ClientHello is
+        for at in [1, 3, 4, 100, handshake.len() - 1] {
+            let mut split = record(&handshake[..at]);
+            split.extend(record(&handshake[at..]));
+
+            assert_eq!(
+                parsed(&split),
+                Some(Some("db.example.com".to_string())),
+                "{at}"
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_client_hellos() {
+        let x = real_client_hello(Some("db.example.com"));
+
+        for n in 0..x.len() {
+            assert_eq!(parsed(&x[..n]), None, "{n}");
+        }
+    }
+
+    #[test]
+    fn other_first_bytes() {
+        assert_eq!(parsed(b"GET / HTTP/1.1\r\n"), Some(None));
+
+        // a ServerHello
+        assert_eq!(parsed(&record(&[2, 0, 0, 0])), Some(None));
+    }
+
+    #[test]
+    fn hand_made_client_hellos() {
+        let name = |x: &[u8]| parsed(&client_hello(x));
+
+        assert_eq!(name(&[]), Some(None));
+
+        let x = sni_extension(&[(NAME_TYPE_HOST_NAME, b"db.example.com")]);
+        assert_eq!(name(&x), Some(Some("db.example.com".to_string())));
+
+        // other extensions are skipped, and so are names that aren't host names
+        let mut x = vec![0, 23, 0, 0];
+        x.extend(sni_extension(&[
+            (1, b"not-a-host"),
+            (NAME_TYPE_HOST_NAME, b"db.example.com"),
+        ]));
+        assert_eq!(name(&x), Some(Some("db.example.com".to_string())));
+
+        let x = sni_extension(&[(NAME_TYPE_HOST_NAME, b"\xff\xfe")]);
+        assert_eq!(name(&x), Some(None));
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        let mut x = sni_extension(&[(NAME_TYPE_HOST_NAME, b"db.example.com")]);
+
+        // the name says it is longer than its list
+        let at = x.len() - b"db.example.com".len() - 2;
+        x[at..at + 2].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(parsed(&client_hello(&x)), Some(None));
+
+        // the extensions say they are longer than the ClientHello
+        let mut x = client_hello(&sni_extension(&[(NAME_TYPE_HOST_NAME, b"a")]));
+        let at = RECORD_HEADER_LEN + 4 + 2 + 32 + 1 + 2 + 2 + 1 + 1;
+        x[at..at + 2].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(parsed(&x), Some(None));
+
+        // a ClientHello longer than anything it would be worth waiting for never finishes
+        let mut x = record(&[HANDSHAKE_CLIENT_HELLO, 0xff, 0xff, 0xff]);
+        x.extend(record(&[0; 1000]));
+        assert_eq!(parsed(&x), None);
+    }
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn split_across_reads() {
+        let x = real_client_hello(Some("db.example.com"));
+        let (mut client, mut server) = pair().await;
+
+        client.write_all(&x[..10]).await.unwrap();
+
+        let rest = x[10..].to_vec();
+        let writer = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            client.write_all(&rest).await.unwrap();
+        });
+
+        assert_eq!(
+            peek_server_name(&server).await,
+            Some("db.example.com".to_string())
+        );
+
+        writer.await.unwrap();
+
+        // peeking leaves the whole handshake for the backend
+        let mut read = vec![0; x.len()];
+        server.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, x);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_oversized_client_hellos() {
+        let (mut client, server) = pair().await;
+
+        let mut x = record(&[HANDSHAKE_CLIENT_HELLO, 0xff, 0xff, 0xff]);
+        while x.len() < MAX_CLIENT_HELLO_LEN {
+            x.extend(record(&[0; 1000]));
+        }
+        client.write_all(&x).await.unwrap();
+
+        // without waiting for `PEEK_TIMEOUT`
+        let x = timeout(Duration::from_secs(2), peek_server_name(&server)).await;
+        assert_eq!(x, Ok(None));
+    }
+
+    #[test]
+    fn wildcards() {
+        let cases = [
+            ("*", "db.example.com", true),
+            ("*", "", true),
+            ("db.example.com", "db.example.com", true),
+            ("db.example.com", "DB.Example.COM", true),
+            ("db.example.com", "db.example.org", false),
+            ("db.example.com", "x.db.example.com", false),
+            ("*.example.com", "db.example.com", true),
+            ("*.example.com", "DB.EXAMPLE.COM", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "ü.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", ".example.com", false),
+            ("*.example.com", "dbexample.com", false),
+            ("*.example.com", "db.example.com.evil", false),
+            // the byte before the parent is in the middle of a character
+            ("*.example.com", "éexample.com", false),
+        ];
+
+        for (host, name, x) in cases {
+            assert_eq!(host_matches(host, name), x, "{host} {name}");
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let rules: Vec<SniRule> = ["db.example.com=db", "*.example.com=web", "*=other"]
+            .into_iter()
+            .map(|x| x.parse().unwrap())
+            .collect();
+
+        assert_eq!(route(&rules, "db.example.com"), Some("db"));
+        assert_eq!(route(&rules, "www.example.com"), Some("web"));
+        assert_eq!(route(&rules, "example.org"), Some("other"));
+        assert_eq!(route(&rules[..2], "example.org"), None);
+
+        for x in ["", "example.com", "=web", "example.com="] {
+            assert!(x.parse::<SniRule>().is_err(), "{x}");
+        }
+    }
+}
@@ -0,0 +1,639 @@
+//! DNS SRV lookups, which the system resolver can't do.
+//!
+//! The name servers in /etc/resolv.conf are asked over UDP, one at a time. Search domains aren't used, so names need to be complete,
+//! like `_quic-tunnel._udp.example.com`.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::{debug, trace};
+
+use crate::quic::matching_bind_address;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// RFC 1035's limit on a name, on the wire
+const MAX_NAME_LEN: usize = 255;
+
+/// how long each name server gets to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// what glibc asks when resolv.conf doesn't name a server
+const DEFAULT_NAME_SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// the records for `name`, in the order RFC 2782 says to try them. lower priorities first, and heavier weights first more often
+pub async fn lookup(name: &str) -> io::Result<Vec<SrvRecord>> {
+    let query_name = name.strip_suffix('.').unwrap_or(name);
+
+    let mut last_err = None;
+
+    for server in name_servers() {
+        match timeout(QUERY_TIMEOUT, query(server, query_name)).await {
+            Ok(Ok(x)) => {
+                debug!(name, %server, records = ?x, "SRV lookup");
+
+                return Ok(order(x));
+            }
+            Ok(Err(err)) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(_) => {
+                last_err = Some(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{server} didn't answer"),
+                ))
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::other("no name servers")))
+}
+
+fn name_servers() -> Vec<SocketAddr> {
+    let servers: Vec<_> = std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+
+            if fields.next() != Some("nameserver") {
+                return None;
+            }
+
+            let ip: IpAddr = fields.next()?.parse().ok()?;
+
+            Some(SocketAddr::new(ip, 53))
+        })
+        .collect();
+
+    if servers.is_empty() {
+        vec![DEFAULT_NAME_SERVER]
+    } else {
+        servers
+    }
+}
+
+async fn query(server: SocketAddr, name: &str) -> io::Result<Vec<SrvRecord>> {
+    let id = random() as u16;
+
+    let socket = UdpSocket::bind(matching_bind_address(server).map_err(io::Error::other)?).await?;
+    socket.connect(server).await?;
+    socket.send(&encode_query(id, name)?).await?;
+
+    let mut buf = vec![0; 4096];
+
+    // anything that isn't the answer to our question is ignored, like a late answer to an earlier one
+    loop {
+        let n = socket.recv(&mut buf).await?;
+
+        match decode_answer(id, &buf[..n]) {
+            Ok(Some(x)) => return Ok(x),
+            Ok(None) => trace!(%server, "ignoring a DNS message for someone else"),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn encode_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{name}\" is not a DNS name"),
+        )
+    };
+
+    if name.is_empty() || name.len() > 253 {
+        return Err(invalid());
+    }
+
+    let mut x = Vec::with_capacity(name.len() + 18);
+
+    x.extend_from_slice(&id.to_be_bytes());
+    // a standard query that asks the server to recurse
+    x.extend_from_slice(&0x0100u16.to_be_bytes());
+    // one question, no answers, authorities, or additional records
+    x.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    encode_name(&mut x, name).map_err(|_| invalid())?;
+
+    x.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    x.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(x)
+}
+
+/// the SRV records in a response, or `None` if it isn't a response to query `id`
+fn decode_answer(id: u16, packet: &[u8]) -> io::Result<Option<Vec<SrvRecord>>> {
+    let mut r = Reader { packet, pos: 0 };
+
+    if r.u16()? != id {
+        return Ok(None);
+    }
+
+    let flags = r.u16()?;
+
+    // not a response
+    if flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+
+    if flags & 0x0200 != 0 {
+        return Err(io::Error::other(
+            "the SRV answer was too big for UDP. DNS over TCP isn't supported",
+        ));
+    }
+
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such name")),
+        x => {
+            return Err(io::Error::other(format!(
+                "the name server failed with code {x}"
+            )))
+        }
+    }
+
+    let questions = r.u16()?;
+    let answers = r.u16()?;
+    r.skip(4)?;
+
+    for _ in 0..questions {
+        r.name()?;
+        r.skip(4)?;
+    }
+
+    let mut records = vec![];
+
+    for _ in 0..answers {
+        r.name()?;
+
+        let ty = r.u16()?;
+        let class = r.u16()?;
+        r.skip(4)?;
+        let len = r.u16()? as usize;
+
+        let end = r.pos + len;
+
+        // CNAMEs on the way to the SRV records are skipped like anything else
+        if ty == TYPE_SRV && class == CLASS_IN {
+            let record = SrvRecord {
+                priority: r.u16()?,
+                weight: r.u16()?,
+                port: r.u16()?,
+                target: r.name()?,
+            };
+
+            // a target of "." means the service is deliberately not available
+            if !record.target.is_empty() {
+                records.push(record);
+            }
+        }
+
+        if end > packet.len() {
+            return Err(truncated());
+        }
+
+        r.pos = end;
+    }
+
+    if records.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no SRV records"));
+    }
+
+    Ok(Some(records))
+}
+
+/// `name` as DNS labels, without compression
+pub(crate) fn encode_name(x: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{name}\" is not a DNS name"),
+            ));
+        }
+
+        x.push(label.len() as u8);
+        x.extend_from_slice(label.as_bytes());
+    }
+
+    x.push(0);
+
+    Ok(())
+}
+
+pub(crate) fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "truncated DNS message")
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) packet: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl Reader<'_> {
+    pub(crate) fn skip(&mut self, n: usize) -> io::Result<()> {
+        if self.pos + n > self.packet.len() {
+            return Err(truncated());
+        }
+
+        self.pos += n;
+
+        Ok(())
+    }
+
+    pub(crate) fn u16(&mut self) -> io::Result<u16> {
+        let x = self
+            .packet
+            .get(self.pos..self.pos + 2)
+            .ok_or_else(truncated)?;
+
+        self.pos += 2;
+
+        Ok(u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    /// a name at the current position, following compression pointers. "." comes back empty
+    pub(crate) fn name(&mut self) -> io::Result<String> {
+        let mut labels: Vec<String> = vec![];
+        let mut pos = self.pos;
+        // where to continue once the name is read, if it jumped somewhere else
+        let mut resume = None;
+        // where the part of the name being read starts. a pointer has to go before it, not just before itself, or a
+        // label followed by a pointer back to that label would loop forever
+        let mut start = pos;
+        // on the wire, counting each label's length byte and the root's
+        let mut len = 1;
+
+        loop {
+            let x = *self.packet.get(pos).ok_or_else(truncated)? as usize;
+
+            match x {
+                0 => {
+                    self.pos = resume.unwrap_or(pos + 1);
+
+                    return Ok(labels.join("."));
+                }
+                x if x & 0xc0 == 0xc0 => {
+                    let low = *self.packet.get(pos + 1).ok_or_else(truncated)? as usize;
+                    let target = ((x & 0x3f) << 8) | low;
+
+                    if target >= start {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "DNS name pointer doesn't point backwards",
+                        ));
+                    }
+
+                    resume.get_or_insert(pos + 2);
+                    pos = target;
+                    start = target;
+                }
+                x => {
+                    len += 1 + x;
+
+                    if len > MAX_NAME_LEN {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "DNS name is longer than 255 bytes",
+                        ));
+                    }
+
+                    let label = self
+                        .packet
+                        .get(pos + 1..pos + 1 + x)
+                        .ok_or_else(truncated)?;
+
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + x;
+                }
+            }
+        }
+    }
+}
+
+/// sorted by priority, and shuffled by weight within each priority
+fn order(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    records.sort_by_key(|x| x.priority);
+
+    let mut ordered = Vec::with_capacity(records.len());
+
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same = records
+            .iter()
+            .take_while(|x| x.priority == priority)
+            .count();
+
+        let mut group: Vec<_> = records.drain(..same).collect();
+
+        while !group.is_empty() {
+            let total: u64 = group.iter().map(|x| x.weight as u64).sum();
+
+            // records with no weight go in the order they came when nothing else is left
+            let i = match total {
+                0 => 0,
+                _ => {
+                    let mut pick = random() % total;
+
+                    group
+                        .iter()
+                        .position(|x| {
+                            let weight = x.weight as u64;
+
+                            if pick < weight {
+                                return true;
+                            }
+
+                            pick -= weight;
+
+                            false
+                        })
+                        .unwrap_or(0)
+                }
+            };
+
+            ordered.push(group.remove(i));
+        }
+    }
+
+    ordered
+}
+
+/// std seeds every hasher randomly, which is random enough for shuffling and query ids
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const RESPONSE: u16 = 0x8180;
+
+    fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+        let mut x = ID.to_be_bytes().to_vec();
+        x.extend(flags.to_be_bytes());
+        x.extend(questions.to_be_bytes());
+        x.extend(answers.to_be_bytes());
+        x.extend([0; 4]);
+        x
+    }
+
+    fn record(x: &mut Vec<u8>, name: &[u8], ty: u16, data: &[u8]) {
+        x.extend(name);
+        x.extend(ty.to_be_bytes());
+        x.extend(CLASS_IN.to_be_bytes());
+        x.extend(300u32.to_be_bytes());
+        x.extend((data.len() as u16).to_be_bytes());
+        x.extend(data);
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &[u8]) -> Vec<u8> {
+        let mut x = vec![];
+        x.extend(priority.to_be_bytes());
+        x.extend(weight.to_be_bytes());
+        x.extend(port.to_be_bytes());
+        x.extend(target);
+        x
+    }
+
+    /// a response like a real server's, with every name after the question compressed
+    fn response() -> Vec<u8> {
+        let mut x = header(RESPONSE, 1, 4);
+
+        // at 12, with "example.com" at 21
+        encode_name(&mut x, "_qt._udp.example.com").unwrap();
+        x.extend(TYPE_SRV.to_be_bytes());
+        x.extend(CLASS_IN.to_be_bytes());
+
+        record(
+            &mut x,
+            &[0xc0, 12],
+            5,
+            &[4, b'h', b'o', b's', b't', 0xc0, 21],
+        );
+        record(
+            &mut x,
+            &[0xc0, 12],
+            TYPE_SRV,
+            &srv(10, 5, 8443, &[1, b'a', 0xc0, 21]),
+        );
+        record(
+            &mut x,
+            &[0xc0, 12],
+            TYPE_SRV,
+            &srv(20, 0, 8444, &[1, b'b', 0xc0, 21]),
+        );
+        // deliberately not available
+        record(&mut x, &[0xc0, 12], TYPE_SRV, &srv(30, 0, 8445, &[0]));
+
+        x
+    }
+
+    fn kind(x: io::Result<Option<Vec<SrvRecord>>>) -> io::ErrorKind {
+        x.unwrap_err().kind()
+    }
+
+    #[test]
+    fn answers() {
+        let x = decode_answer(ID, &response()).unwrap().unwrap();
+
+        assert_eq!(
+            x,
+            [
+                SrvRecord {
+                    priority: 10,
+                    weight: 5,
+                    port: 8443,
+                    target: "a.example.com".to_string(),
+                },
+                SrvRecord {
+                    priority: 20,
+                    weight: 0,
+                    port: 8444,
+                    target: "b.example.com".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn not_ours() {
+        assert_eq!(decode_answer(ID + 1, &response()).unwrap(), None);
+
+        let mut x = response();
+        x[2] &= 0x7f;
+        assert_eq!(decode_answer(ID, &x).unwrap(), None);
+    }
+
+    #[test]
+    fn failures() {
+        let mut x = response();
+        x[3] |= 3;
+        assert_eq!(kind(decode_answer(ID, &x)), io::ErrorKind::NotFound);
+
+        let mut x = response();
+        x[3] |= 2;
+        let err = decode_answer(ID, &x).unwrap_err();
+        assert!(err.to_string().contains("code 2"), "{err}");
+
+        // truncated, so the records that matter may be missing
+        let mut x = response();
+        x[2] |= 0x02;
+        let err = decode_answer(ID, &x).unwrap_err();
+        assert!(err.to_string().contains("too big"), "{err}");
+
+        let mut x = header(RESPONSE, 0, 1);
+        record(&mut x, &[0], 5, &[0]);
+        assert_eq!(kind(decode_answer(ID, &x)), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn truncated_responses() {
+        let x = response();
+
+        for n in 0..x.len() {
+            assert_eq!(
+                kind(decode_answer(ID, &x[..n])),
+                io::ErrorKind::InvalidData,
+                "{n}"
+            );
+        }
+    }
+
+    fn name(packet: &[u8], pos: usize) -> io::Result<String> {
+        let mut r = Reader { packet, pos };
+        let x = r.name()?;
+
+        Ok(format!("{x} {}", r.pos))
+    }
+
+    #[test]
+    fn names() {
+        let mut x = vec![];
+        encode_name(&mut x, "example.com").unwrap();
+        x.extend([3, b'w', b'w', b'w', 0xc0, 0]);
+        // a pointer to a pointer
+        x.extend([0xc0, 13]);
+
+        assert_eq!(name(&x, 0).unwrap(), "example.com 13");
+        assert_eq!(name(&x, 13).unwrap(), "www.example.com 19");
+        assert_eq!(name(&x, 19).unwrap(), "www.example.com 21");
+        assert_eq!(name(&[0], 0).unwrap(), " 1");
+
+        // the longest a name can be
+        let long = [
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(61),
+        ]
+        .join(".");
+        let mut x = vec![];
+        encode_name(&mut x, &long).unwrap();
+        assert_eq!(x.len(), MAX_NAME_LEN);
+        assert_eq!(name(&x, 0).unwrap(), format!("{long} 255"));
+    }
+
+    #[test]
+    fn bad_names() {
+        let invalid = |packet: &[u8], pos| name(packet, pos).unwrap_err().kind();
+
+        // pointing at itself, forward, and back to a label that points back again
+        assert_eq!(invalid(&[0xc0, 0], 0), io::ErrorKind::InvalidData);
+        assert_eq!(invalid(&[0xc0, 2, 0], 0), io::ErrorKind::InvalidData);
+        assert_eq!(invalid(&[1, b'a', 0xc0, 0], 0), io::ErrorKind::InvalidData);
+        assert_eq!(
+            invalid(&[0, 1, b'a', 0xc0, 1, 0xc0, 3], 5),
+            io::ErrorKind::InvalidData
+        );
+
+        // one byte too long, spelled out or reached through pointers
+        let long = [
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(62),
+        ]
+        .join(".");
+        let mut x = vec![];
+        encode_name(&mut x, &long).unwrap();
+        assert_eq!(invalid(&x, 0), io::ErrorKind::InvalidData);
+
+        let mut x = vec![];
+        encode_name(&mut x, &"a".repeat(63)).unwrap();
+        for i in 0..4 {
+            let previous = if i == 0 { 0 } else { 65 + 66 * (i - 1) };
+
+            x.push(63);
+            x.extend([b'b'; 63]);
+            x.extend([0xc0 | (previous >> 8) as u8, previous as u8]);
+        }
+        assert_eq!(name(&x, 65 + 66).unwrap().len(), 63 * 3 + 2 + 4);
+        assert_eq!(invalid(&x, 65 + 3 * 66), io::ErrorKind::InvalidData);
+
+        // truncated mid label, mid pointer, and before the root
+        for x in [&[3, b'w', b'w'][..], &[0xc0], &[1, b'a'], &[]] {
+            assert_eq!(invalid(x, 0), io::ErrorKind::InvalidData, "{x:?}");
+        }
+    }
+
+    fn record_with(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 8443,
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn priorities_first() {
+        let x = order(vec![
+            record_with(2, 1, "c"),
+            record_with(0, 1, "a"),
+            record_with(1, 0, "b1"),
+            record_with(1, 0, "b2"),
+        ]);
+
+        let targets: Vec<_> = x.iter().map(|x| x.target.as_str()).collect();
+        assert_eq!(targets, ["a", "b1", "b2", "c"]);
+    }
+
+    #[test]
+    fn heavier_first_more_often() {
+        let mut heavy_first = 0;
+
+        for _ in 0..1000 {
+            let x = order(vec![
+                record_with(0, 0, "none"),
+                record_with(0, 1, "light"),
+                record_with(0, 99, "heavy"),
+            ]);
+
+            // a record without weight only goes once the others have
+            assert_eq!(x[2].target, "none");
+
+            if x[0].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+
+        assert!(heavy_first > 900, "{heavy_first}");
+    }
+}
@@ -1,15 +1,81 @@
+use std::io;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::{TcpStream, UdpSocket, UnixStream},
-};
+use crate::bind::BindOptions;
+use crate::compress::CompressAlgo;
+use crate::error::TunnelError;
+use crate::listen::ListenTarget;
+use crate::pipe::NamedPipe;
+use crate::transform::{BoxedRead, BoxedWrite, TransformContext, TransformPipeline};
+use crate::unix::UnixStream;
+use crate::vsock::VsockStream;
+use crate::webtransport::WebTransportStream;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::Instant;
 
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
     Udp(Arc<UdpSocket>),
     Unix(UnixStream),
+    NamedPipe(NamedPipe),
+    Vsock(VsockStream),
+    /// this process's stdin and stdout, for ssh's `ProxyCommand` or inetd
+    Stdio(StdioStream),
+    /// a stream a browser opened in a WebTransport session
+    WebTransport(WebTransportStream),
+}
+
+/// stdin and stdout as one stream
+#[derive(Debug)]
+pub struct StdioStream {
+    stdin: tokio::io::Stdin,
+    stdout: tokio::io::Stdout,
+}
+
+/// only make one of these. anything else reading stdin or writing stdout would mix into the stream
+impl Default for StdioStream {
+    fn default() -> Self {
+        Self {
+            stdin: tokio::io::stdin(),
+            stdout: tokio::io::stdout(),
+        }
+    }
+}
+
+impl AsyncRead for StdioStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StdioStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_shutdown(cx)
+    }
 }
 
 impl From<TcpStream> for Stream {
@@ -24,30 +90,146 @@ impl From<UnixStream> for Stream {
     }
 }
 
+/// Socket options for the server's user connections and the client's backend connections. Anything left as `None` uses the OS default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpOptions {
+    /// send small writes right away instead of waiting to fill a packet
+    pub nodelay: bool,
+    /// idle time before the first keepalive probe. setting any of the keepalive options turns keepalive on
+    #[serde(with = "humantime_serde")]
+    pub keepalive: Option<Duration>,
+    /// time between keepalive probes
+    #[serde(with = "humantime_serde")]
+    pub keepalive_interval: Option<Duration>,
+    /// unanswered probes before the connection is dropped. windows always gives up after 10
+    pub keepalive_probes: Option<u32>,
+    /// SO_RCVBUF in bytes
+    pub recv_buffer: Option<u32>,
+    /// SO_SNDBUF in bytes
+    pub send_buffer: Option<u32>,
+    /// how many connections a listener queues before they are accepted. 1024 by default. connections we make ignore this
+    pub backlog: Option<u32>,
+    /// where connections we make go out from. listeners ignore this. see the `bind` module
+    pub bind: BindOptions,
+}
+
+impl TcpOptions {
+    /// the buffer sizes. these need to be set before connecting or listening to change the window scale in the handshake.
+    /// accepted sockets inherit them from the listener
+    pub fn apply_to_socket(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(x) = self.recv_buffer {
+            socket.set_recv_buffer_size(x)?;
+        }
+
+        if let Some(x) = self.send_buffer {
+            socket.set_send_buffer_size(x)?;
+        }
+
+        Ok(())
+    }
+
+    /// nodelay and keepalive on a connected stream
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+
+        if self.keepalive.is_some()
+            || self.keepalive_interval.is_some()
+            || self.keepalive_probes.is_some()
+        {
+            let mut keepalive = TcpKeepalive::new();
+
+            if let Some(x) = self.keepalive {
+                keepalive = keepalive.with_time(x);
+            }
+
+            if let Some(x) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(x);
+            }
+
+            #[cfg(not(windows))]
+            if let Some(x) = self.keepalive_probes {
+                keepalive = keepalive.with_retries(x);
+            }
+
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// a user connection waiting for a tunnel client to pick it up
+#[derive(Debug)]
+pub struct PendingStream {
+    pub stream: Stream,
+    /// where the user connected
+    pub listener: ListenTarget,
+    /// sent to the client in the stream preamble
+    pub route: String,
+    /// applied between the user and the QUIC stream
+    pub transform: TransformPipeline,
+    /// told to the client in the preamble
+    pub compress: CompressAlgo,
+    /// when the listener accepted the user. for the stream setup latency
+    pub accepted_at: Instant,
+    /// the user's places under `max_connections`. released when the stream is done
+    pub slots: Vec<OwnedSemaphorePermit>,
+    /// how many tunnel clients went away with the user before its stream started
+    pub handoffs: u32,
+    /// the QUIC send priority of the stream toward the client
+    pub priority: i32,
+}
+
 impl Stream {
-    pub fn into_split(
-        self,
-    ) -> (
-        Box<dyn AsyncRead + Send + Unpin>,
-        Box<dyn AsyncWrite + Send + Unpin>,
-    ) {
+    pub fn transform_context(&self) -> TransformContext {
+        match self {
+            Self::Tcp(x) => TransformContext {
+                peer_addr: x.peer_addr().ok(),
+                local_addr: x.local_addr().ok(),
+            },
+            Self::Udp(x) => TransformContext {
+                peer_addr: x.peer_addr().ok(),
+                local_addr: x.local_addr().ok(),
+            },
+            Self::WebTransport(x) => TransformContext {
+                peer_addr: Some(x.peer_addr()),
+                local_addr: x.local_addr(),
+            },
+            Self::Unix(_) | Self::NamedPipe(_) | Self::Vsock(_) | Self::Stdio(_) => {
+                TransformContext::default()
+            }
+        }
+    }
+
+    pub fn into_split(self) -> Result<(BoxedRead, BoxedWrite), TunnelError> {
         match self {
             Self::Tcp(x) => {
                 let (read_half, write_half) = x.into_split();
-                (
-                    Box::new(read_half) as Box<dyn AsyncRead + Send + Unpin>,
-                    Box::new(write_half) as Box<dyn AsyncWrite + Send + Unpin>,
-                )
+                Ok((Box::new(read_half), Box::new(write_half)))
             }
             Self::Udp(_) => {
-                todo!("UdpSocket isn't AsyncRead/AsyncWrite");
+                // TODO: UdpSocket isn't AsyncRead/AsyncWrite
+                Err(TunnelError::Unsupported("splitting a udp stream"))
             }
             Self::Unix(x) => {
                 let (read_half, write_half) = x.into_split();
-                (
-                    Box::new(read_half) as Box<dyn AsyncRead + Send + Unpin>,
-                    Box::new(write_half) as Box<dyn AsyncWrite + Send + Unpin>,
-                )
+                Ok((Box::new(read_half), Box::new(write_half)))
+            }
+            Self::NamedPipe(x) => {
+                let (read_half, write_half) = tokio::io::split(x);
+                Ok((Box::new(read_half), Box::new(write_half)))
+            }
+            Self::Vsock(x) => {
+                let (read_half, write_half) = tokio::io::split(x);
+                Ok((Box::new(read_half), Box::new(write_half)))
+            }
+            Self::Stdio(x) => Ok((Box::new(x.stdin), Box::new(x.stdout))),
+            Self::WebTransport(x) => {
+                let (read_half, write_half) = x.into_split();
+                Ok((Box::new(read_half), Box::new(write_half)))
             }
         }
     }
@@ -0,0 +1,588 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use argh::FromArgs;
+use futures::future::try_join_all;
+use quic_tunnel::counters::{LatencyHistogram, LatencySnapshot};
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::time::{interval, sleep, timeout, timeout_at, Instant, MissedTickBehavior};
+use tracing::{debug, info, warn};
+
+use super::{human_bytes, parse_bytes, parse_duration};
+
+/// each write. also the most a stream sends past `--stream-size`
+const CHUNK: usize = 64 * 1024;
+
+/// how long to wait for the receiver to count a stream's bytes, and for late datagrams
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Measure what a tunnel carries: goodput, how long streams take to set up, and datagram loss.
+///
+/// Run `bench receive` where a tunnel client forwards to, and `bench send` at the server's listener. Runs with the same
+/// options can be compared across congestion controllers, compression, and the like.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "bench")]
+pub struct BenchSubCommand {
+    #[argh(subcommand)]
+    action: BenchAction,
+}
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand)]
+enum BenchAction {
+    Send(SendAction),
+    Receive(ReceiveAction),
+}
+
+/// Push bytes and datagrams through a tunnel and print what made it.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "send")]
+struct SendAction {
+    /// open streams to this address, like the server's --tcp-listen
+    #[argh(option)]
+    tcp: Option<SocketAddr>,
+
+    /// send datagrams to this address, like a udp_client's local address
+    #[argh(option)]
+    udp: Option<SocketAddr>,
+
+    /// how many streams to send on at once. 1 by default
+    #[argh(option, default = "1")]
+    parallel: usize,
+
+    /// how long to send for. 10s by default
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_duration)
+    )]
+    duration: Duration,
+
+    /// open a new stream after sending this many bytes, like 1M, to measure setup more than once per stream.
+    /// by default each stream lasts the whole run
+    #[argh(option, from_str_fn(parse_bytes))]
+    stream_size: Option<u64>,
+
+    /// datagrams per second. 1000 by default
+    #[argh(option, default = "1000")]
+    rate: u32,
+
+    /// bytes per datagram. 1200 by default
+    #[argh(option, default = "1200", from_str_fn(parse_bytes))]
+    datagram_size: u64,
+
+    /// send text that compresses well instead of random bytes, which don't
+    #[argh(switch)]
+    compressible: bool,
+
+    /// print the summary as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+/// Count what `bench send` pushes through a tunnel.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "receive")]
+struct ReceiveAction {
+    /// take streams on this address, like a client's --tcp-connect. can be repeated
+    #[argh(option)]
+    tcp: Vec<SocketAddr>,
+
+    /// take datagrams on this address, like where a udp_server forwards to. can be repeated
+    #[argh(option)]
+    udp: Vec<SocketAddr>,
+}
+
+impl BenchSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        match self.action {
+            BenchAction::Send(x) => x.main().await,
+            BenchAction::Receive(x) => x.main().await,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    #[serde(with = "humantime_serde")]
+    duration: Duration,
+    streams: Option<StreamSummary>,
+    datagrams: Option<DatagramSummary>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct StreamSummary {
+    parallel: usize,
+    /// opened and answered by the receiver
+    streams: u64,
+    /// broke after they were opened
+    failed: u64,
+    bytes_sent: u64,
+    /// what the receiver counted
+    bytes_received: u64,
+    /// received bits per second, from the first stream opening to the last one being counted
+    goodput_bps: f64,
+    /// from connecting to the receiver answering
+    setup: LatencySnapshot,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct DatagramSummary {
+    sent: u64,
+    /// the receiver sends each one back, so this counts loss both ways
+    returned: u64,
+    loss_percent: f64,
+    rtt: LatencySnapshot,
+}
+
+impl SendAction {
+    async fn main(self) -> anyhow::Result<()> {
+        if self.tcp.is_none() && self.udp.is_none() {
+            anyhow::bail!("nothing to send to. pass --tcp, --udp, or both");
+        }
+
+        if self.parallel == 0 {
+            anyhow::bail!("--parallel must be at least 1");
+        }
+
+        if self.rate == 0 {
+            anyhow::bail!("--rate must be at least 1");
+        }
+
+        if !(8..=65507).contains(&self.datagram_size) {
+            anyhow::bail!("--datagram-size must be from 8 to 65507 bytes");
+        }
+
+        let payload: Arc<[u8]> = payload(self.compressible)?.into();
+
+        let started = Instant::now();
+        let deadline = started + self.duration;
+
+        info!(duration = ?self.duration, tcp = ?self.tcp, udp = ?self.udp, "sending");
+
+        let streams = async {
+            let Some(addr) = self.tcp else {
+                return Ok(None);
+            };
+
+            let setup = Arc::new(LatencyHistogram::default());
+
+            let workers = (0..self.parallel).map(|_| {
+                tokio::spawn(send_streams(
+                    addr,
+                    deadline,
+                    self.stream_size,
+                    payload.clone(),
+                    setup.clone(),
+                ))
+            });
+
+            let mut x = StreamSummary {
+                parallel: self.parallel,
+                ..Default::default()
+            };
+
+            for worker in try_join_all(workers).await? {
+                let worker = worker?;
+
+                x.streams += worker.streams;
+                x.failed += worker.failed;
+                x.bytes_sent += worker.bytes_sent;
+                x.bytes_received += worker.bytes_received;
+            }
+
+            x.goodput_bps = x.bytes_received as f64 * 8.0 / started.elapsed().as_secs_f64();
+            x.setup = setup.snapshot();
+
+            anyhow::Ok(Some(x))
+        };
+
+        let datagrams = async {
+            match self.udp {
+                Some(addr) => send_datagrams(
+                    addr,
+                    deadline,
+                    self.rate,
+                    &payload[..self.datagram_size as usize],
+                )
+                .await
+                .map(Some),
+                None => Ok(None),
+            }
+        };
+
+        let (streams, datagrams) = tokio::try_join!(streams, datagrams)?;
+
+        let summary = Summary {
+            duration: self.duration,
+            streams,
+            datagrams,
+        };
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        } else {
+            print!("{}", summary.text());
+        }
+
+        Ok(())
+    }
+}
+
+/// random bytes don't compress, so compression can't inflate goodput
+fn payload(compressible: bool) -> anyhow::Result<Vec<u8>> {
+    let mut x = vec![0; CHUNK];
+
+    if compressible {
+        for (x, y) in x.iter_mut().zip(
+            b"the quick brown fox jumps over the lazy dog. "
+                .iter()
+                .cycle(),
+        ) {
+            *x = *y;
+        }
+    } else {
+        SystemRandom::new()
+            .fill(&mut x)
+            .map_err(|_| anyhow::anyhow!("unable to generate random bytes"))?;
+    }
+
+    Ok(x)
+}
+
+#[derive(Debug, Default)]
+struct StreamTotals {
+    streams: u64,
+    failed: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// open streams one after another until `deadline`
+async fn send_streams(
+    addr: SocketAddr,
+    deadline: Instant,
+    stream_size: Option<u64>,
+    payload: Arc<[u8]>,
+    setup: Arc<LatencyHistogram>,
+) -> anyhow::Result<StreamTotals> {
+    let mut totals = StreamTotals::default();
+
+    while Instant::now() < deadline {
+        let opened_at = Instant::now();
+
+        // nothing listening means nothing to measure
+        let mut stream = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("connecting to {addr}"))?;
+
+        stream.set_nodelay(true)?;
+
+        // the receiver answers right away, so this is the tunnel opening a stream to it
+        match timeout(DRAIN_TIMEOUT, stream.read_u8()).await {
+            Ok(Ok(_)) => setup.record(opened_at.elapsed()),
+            x => {
+                warn!(
+                    ?x,
+                    "no answer from the receiver. is `bench receive` behind the tunnel?"
+                );
+
+                totals.failed += 1;
+
+                // don't spin on a tunnel that is closing every stream
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        }
+
+        let mut sent = 0;
+
+        let x = async {
+            while stream_size.is_none_or(|x| sent < x) {
+                let n =
+                    stream_size.map_or(payload.len(), |x| payload.len().min((x - sent) as usize));
+
+                // a write either happens or doesn't, so `sent` is exact when time runs out
+                match timeout_at(deadline, stream.write(&payload[..n])).await {
+                    Ok(x) => sent += x? as u64,
+                    Err(_) => break,
+                }
+            }
+
+            stream.shutdown().await?;
+
+            timeout(DRAIN_TIMEOUT, stream.read_u64())
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+        };
+
+        let x = x.await;
+
+        totals.bytes_sent += sent;
+
+        match x {
+            Ok(x) => {
+                totals.streams += 1;
+                totals.bytes_received += x;
+            }
+            Err(err) => {
+                debug!(?err, "stream failed");
+
+                totals.failed += 1;
+            }
+        }
+    }
+
+    Ok(totals)
+}
+
+/// send numbered datagrams at `rate` until `deadline`, and count the ones that come back
+async fn send_datagrams(
+    addr: SocketAddr,
+    deadline: Instant,
+    rate: u32,
+    payload: &[u8],
+) -> anyhow::Result<DatagramSummary> {
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let mut ticks = interval(Duration::from_secs(1) / rate);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+    let rtt = LatencyHistogram::default();
+
+    // when each one was sent, and whether it came back yet
+    let mut sent_at: Vec<Instant> = vec![];
+    let mut returned: Vec<bool> = vec![];
+
+    let mut buf = payload.to_vec();
+    let mut recv_buf = vec![0; 64 * 1024];
+
+    let mut drain_until = None;
+
+    loop {
+        select! {
+            _ = ticks.tick(), if drain_until.is_none() => {
+                if Instant::now() >= deadline {
+                    drain_until = Some(Instant::now() + DRAIN_TIMEOUT.min(Duration::from_secs(1)));
+                    continue;
+                }
+
+                buf[..8].copy_from_slice(&(sent_at.len() as u64).to_be_bytes());
+
+                sent_at.push(Instant::now());
+                returned.push(false);
+
+                // a full socket buffer counts as loss
+                if let Err(err) = socket.try_send(&buf) {
+                    debug!(?err, "send failed");
+                }
+            }
+            x = socket.recv(&mut recv_buf) => {
+                // like ICMP port unreachable while the tunnel isn't up
+                let Ok(n) = x.inspect_err(|err| debug!(?err, "recv failed")) else {
+                    continue;
+                };
+
+                let Some(seq) = recv_buf[..n].get(..8).map(|x| u64::from_be_bytes(x.try_into().unwrap()) as usize) else {
+                    continue;
+                };
+
+                if let Some(false) = returned.get(seq) {
+                    returned[seq] = true;
+                    rtt.record(sent_at[seq].elapsed());
+                }
+            }
+            _ = sleep_until_maybe(drain_until) => break,
+        }
+    }
+
+    let sent = sent_at.len() as u64;
+    let returned = returned.iter().filter(|x| **x).count() as u64;
+
+    Ok(DatagramSummary {
+        sent,
+        returned,
+        loss_percent: match sent {
+            0 => 0.0,
+            _ => (sent - returned) as f64 * 100.0 / sent as f64,
+        },
+        rtt: rtt.snapshot(),
+    })
+}
+
+async fn sleep_until_maybe(x: Option<Instant>) {
+    match x {
+        Some(x) => tokio::time::sleep_until(x).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Summary {
+    fn text(&self) -> String {
+        let ms = |x: Option<u64>| match x {
+            Some(x) => format!("{:.2}ms", x as f64 / 1000.0),
+            None => "-".to_string(),
+        };
+
+        let mut s = format!("duration   {:?}\n", self.duration);
+
+        if let Some(x) = &self.streams {
+            s.push_str(&format!(
+                "goodput    {:.1} Mbit/s, {} received, {} streams at once\n",
+                x.goodput_bps / 1e6,
+                human_bytes(x.bytes_received),
+                x.parallel,
+            ));
+            s.push_str(&format!(
+                "setup      p50 {}, p95 {}, p99 {} over {} streams, {} failed\n",
+                ms(x.setup.p50_us),
+                ms(x.setup.p95_us),
+                ms(x.setup.p99_us),
+                x.streams,
+                x.failed,
+            ));
+
+            if x.bytes_sent != x.bytes_received {
+                s.push_str(&format!(
+                    "lost       {} of {} sent never reached the receiver\n",
+                    human_bytes(x.bytes_sent.saturating_sub(x.bytes_received)),
+                    human_bytes(x.bytes_sent),
+                ));
+            }
+        }
+
+        if let Some(x) = &self.datagrams {
+            s.push_str(&format!(
+                "datagrams  {} sent, {} returned, {:.2}% lost, rtt p50 {}, p99 {}\n",
+                x.sent,
+                x.returned,
+                x.loss_percent,
+                ms(x.rtt.p50_us),
+                ms(x.rtt.p99_us),
+            ));
+        }
+
+        s
+    }
+}
+
+impl ReceiveAction {
+    async fn main(self) -> anyhow::Result<()> {
+        if self.tcp.is_empty() && self.udp.is_empty() {
+            anyhow::bail!("nothing to listen on. pass --tcp, --udp, or both");
+        }
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        let mut tasks = vec![];
+
+        for addr in self.tcp.iter() {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("binding tcp {addr}"))?;
+
+            info!("taking streams on tcp {}", listener.local_addr()?);
+
+            tasks.push(tokio::spawn(receive_streams(listener, shutdown.clone())));
+        }
+
+        for addr in self.udp.iter() {
+            let socket = UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("binding udp {addr}"))?;
+
+            info!("taking datagrams on udp {}", socket.local_addr()?);
+
+            tasks.push(tokio::spawn(receive_datagrams(socket, shutdown.clone())));
+        }
+
+        for x in try_join_all(tasks).await? {
+            x?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn receive_streams(listener: TcpListener, shutdown: CancellationToken) -> anyhow::Result<()> {
+    loop {
+        let (mut stream, peer) = select! {
+            x = listener.accept() => match x {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, "accept failed");
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            // answer, then count until the sender is done, then say how much arrived
+            let x = async {
+                stream.set_nodelay(true)?;
+                stream.write_u8(0).await?;
+
+                let mut buf = vec![0; CHUNK];
+                let mut total = 0u64;
+
+                loop {
+                    match stream.read(&mut buf).await? {
+                        0 => break,
+                        n => total += n as u64,
+                    }
+                }
+
+                stream.write_u64(total).await?;
+                stream.shutdown().await?;
+
+                std::io::Result::Ok(total)
+            };
+
+            match x.await {
+                Ok(n) => debug!(%peer, bytes = n, "stream finished"),
+                Err(err) => debug!(%peer, ?err, "stream failed"),
+            }
+        });
+    }
+}
+
+/// send back each datagram's number
+async fn receive_datagrams(socket: UdpSocket, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let (n, peer) = select! {
+            x = socket.recv_from(&mut buf) => match x {
+                Ok(x) => x,
+                Err(err) => {
+                    debug!(?err, "recv failed");
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        if n < 8 {
+            continue;
+        }
+
+        if let Err(err) = socket.send_to(&buf[..8], peer).await {
+            debug!(%peer, ?err, "send failed");
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use argh::FromArgs;
+use quic_tunnel::config::{Config, ConfigIssue, Severity};
+use serde_json::json;
+use std::path::PathBuf;
+
+/// Validate a config file without starting anything.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "check")]
+pub struct CheckSubCommand {
+    /// the TOML config file to check
+    #[argh(option)]
+    config: PathBuf,
+
+    /// print the problems as JSON instead of one per line
+    #[argh(switch)]
+    json: bool,
+}
+
+impl CheckSubCommand {
+    pub fn main(self) -> anyhow::Result<()> {
+        let issues = match Config::load(&self.config) {
+            Ok(x) => x.validate(),
+            Err(err) => err.issues(),
+        };
+
+        let errors = issues
+            .iter()
+            .filter(|x| x.severity == Severity::Error)
+            .count();
+        let warnings = issues.len() - errors;
+
+        if self.json {
+            let x = json!({
+                "ok": errors == 0,
+                "errors": errors,
+                "warnings": warnings,
+                "issues": issues,
+            });
+
+            println!("{}", serde_json::to_string_pretty(&x)?);
+        } else {
+            issues.iter().for_each(|x: &ConfigIssue| println!("{x}"));
+
+            println!(
+                "{}: {errors} errors, {warnings} warnings",
+                self.config.display()
+            );
+        }
+
+        if errors > 0 {
+            anyhow::bail!("{} is not valid", self.config.display());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Context;
+use argh::FromArgs;
+use futures::future::try_join_all;
+use quic_tunnel::listen::{check_listen_targets, ListenTarget, Listener};
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken};
+use quic_tunnel::stream::Stream;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::select;
+use tracing::{debug, info, warn};
+
+/// Echo back or throw away whatever arrives, so a tunnel can be tried end to end without another service.
+///
+/// Point a client's backend at it, connect to the server's listener, and what you send comes back.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "echo_server")]
+pub struct EchoServerSubCommand {
+    /// answer TCP connections on this address. can be repeated
+    #[argh(option)]
+    tcp: Vec<SocketAddr>,
+
+    /// answer UDP datagrams on this address. can be repeated
+    #[argh(option)]
+    udp: Vec<SocketAddr>,
+
+    /// answer connections on this unix socket. can be repeated
+    #[argh(option)]
+    unix: Vec<PathBuf>,
+
+    /// throw away what arrives instead of sending it back, like the discard protocol
+    #[argh(switch)]
+    discard: bool,
+}
+
+impl EchoServerSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        let targets: Vec<_> = self
+            .tcp
+            .iter()
+            .copied()
+            .map(ListenTarget::Tcp)
+            .chain(self.udp.iter().copied().map(ListenTarget::Udp))
+            .chain(self.unix.iter().cloned().map(ListenTarget::Unix))
+            .collect();
+
+        if targets.is_empty() {
+            anyhow::bail!("nothing to listen on. pass --tcp, --udp, or --unix");
+        }
+
+        check_listen_targets(&targets)?;
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        let mode = if self.discard { "discard" } else { "echo" };
+
+        let mut tasks = vec![];
+
+        for target in targets.iter() {
+            if let ListenTarget::Udp(addr) = target {
+                let socket = UdpSocket::bind(addr).await?;
+
+                info!(mode, "listening on udp {}", socket.local_addr()?);
+
+                tasks.push(tokio::spawn(serve_datagrams(
+                    socket,
+                    self.discard,
+                    shutdown.clone(),
+                )));
+
+                continue;
+            }
+
+            let listener = Listener::bind(target, &Default::default(), &Default::default())
+                .await
+                .with_context(|| format!("binding {target}"))?;
+
+            info!(mode, "listening on {target}");
+
+            tasks.push(tokio::spawn(serve_streams(
+                listener,
+                self.discard,
+                shutdown.clone(),
+            )));
+        }
+
+        for x in try_join_all(tasks).await? {
+            x?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn serve_streams(
+    listener: Listener,
+    discard: bool,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        let stream = select! {
+            x = listener.accept() => match x {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, "accept failed");
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        debug!(?stream, "connected");
+
+        tokio::spawn(async move {
+            let (peer, x) = match stream {
+                Stream::Tcp(x) => (
+                    x.peer_addr().map_or("tcp".to_string(), |x| x.to_string()),
+                    answer(x, discard).await,
+                ),
+                Stream::Unix(x) => ("unix".to_string(), answer(x, discard).await),
+                _ => return,
+            };
+
+            match x {
+                Ok(n) => info!(%peer, bytes = n, "finished"),
+                Err(err) => info!(%peer, ?err, "failed"),
+            }
+        });
+    }
+}
+
+/// send back what arrives, or throw it away, until the peer is done sending. returns how many bytes it sent
+async fn answer(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    discard: bool,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+
+    loop {
+        let n = stream.read(&mut buf).await?;
+
+        if n == 0 {
+            break;
+        }
+
+        total += n as u64;
+
+        if !discard {
+            stream.write_all(&buf[..n]).await?;
+        }
+    }
+
+    // and we're done too
+    stream.shutdown().await?;
+
+    Ok(total)
+}
+
+async fn serve_datagrams(
+    socket: UdpSocket,
+    discard: bool,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let (n, peer) = select! {
+            x = socket.recv_from(&mut buf) => match x {
+                Ok(x) => x,
+                // like ICMP port unreachable from an earlier reply
+                Err(err) => {
+                    debug!(?err, "recv failed");
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        debug!(%peer, bytes = n, "datagram");
+
+        if !discard {
+            if let Err(err) = socket.send_to(&buf[..n], peer).await {
+                debug!(%peer, ?err, "send failed");
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use crate::subcommands::{parse_duration, parse_interval, transport_args};
+use argh::FromArgs;
+use ipnet::IpNet;
+use quic_tunnel::counters::{StatsOptions, StatsOutput, TunnelCounters};
+use quic_tunnel::h3::H3_NO_ERROR;
+use quic_tunnel::listen::{check_listen_targets, ListenTarget};
+use quic_tunnel::masque::{build_masque_endpoint, serve, MasqueOptions};
+use quic_tunnel::quic::{CongestionMode, TransportOptions};
+use quic_tunnel::resolve::{ResolveOptions, ResolveStrategy, Resolver};
+use quic_tunnel::runtime;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken};
+use quic_tunnel::tls::TlsOptions;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::select;
+use tracing::info;
+
+/// Run a MASQUE CONNECT-UDP server (RFC 9298) for standard HTTP/3 clients.
+///
+/// Clients still need a client cert signed by the CA.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "masque_server")]
+pub struct MasqueServerSubCommand {
+    /// prefix for all the certificates to load
+    #[argh(positional)]
+    cert_name: String,
+
+    /// the local address to listen on with HTTP/3. Clients connect here
+    #[argh(positional)]
+    local_addr: SocketAddr,
+
+    /// a network clients may send UDP to, like 10.0.0.0/8 or 203.0.113.7/32. can be repeated. anywhere is allowed if none are given
+    #[argh(option)]
+    allow_target: Vec<IpNet>,
+
+    /// close a CONNECT-UDP request that hasn't moved a datagram in either direction for this long (like "1m"). 60s by default
+    #[argh(option, from_str_fn(parse_interval))]
+    udp_idle_timeout: Option<Duration>,
+
+    /// which of a target hostname's addresses to try first. "happy_eyeballs" (the default), "prefer_ipv6", or "prefer_ipv4"
+    #[argh(option, default = "Default::default()")]
+    resolve_strategy: ResolveStrategy,
+
+    /// congestion mode for QUIC
+    #[argh(option, default = "Default::default()")]
+    congestion_mode: CongestionMode,
+
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives (like "15s"). clients send them, so a server only does with this set
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// close connections from clients whose address changes instead of following them to the new one
+    #[argh(switch)]
+    no_migration: bool,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
+}
+
+impl MasqueServerSubCommand {
+    fn transport_options(&self) -> TransportOptions {
+        transport_args!(self; no_migration).options(false)
+    }
+
+    fn masque_options(&self) -> MasqueOptions {
+        let mut x = MasqueOptions {
+            allow: self.allow_target.clone(),
+            ..Default::default()
+        };
+
+        if let Some(idle_timeout) = self.udp_idle_timeout {
+            x.idle_timeout = idle_timeout;
+        }
+
+        x
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        // a CONNECT in 0-RTT data could be replayed to send the same datagrams again
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            early_data: false,
+        }
+    }
+
+    pub async fn main(self) -> anyhow::Result<()> {
+        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
+        let cert = PathBuf::from(format!("{}_server.pem", self.cert_name));
+        let key = PathBuf::from(format!("{}_server.key.pem", self.cert_name));
+
+        check_listen_targets(&[ListenTarget::Udp(self.local_addr)])?;
+
+        let data_plane = runtime::data_plane();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let endpoint = {
+            let _guard = data_plane.enter();
+
+            build_masque_endpoint(
+                ca,
+                cert,
+                key,
+                self.local_addr,
+                &self.transport_options(),
+                &self.tls_options(),
+            )?
+        };
+
+        info!("MASQUE listening on {}", endpoint.local_addr()?);
+
+        let counts = TunnelCounters::new();
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        let resolver = Resolver::new(ResolveOptions {
+            strategy: self.resolve_strategy,
+            ..Default::default()
+        });
+
+        let mut serve_handle = data_plane.spawn(serve(
+            endpoint.clone(),
+            self.masque_options(),
+            resolver,
+            counts.clone(),
+            shutdown.clone(),
+        ));
+
+        let mut stats_handle = counts.spawn_stats_loop(
+            StatsOptions {
+                interval: self.stats_interval,
+                output: self.stats_output.clone(),
+            },
+            shutdown.clone(),
+        );
+
+        // a finished JoinHandle panics if it is polled again
+        let serve_finished = select! {
+            x = &mut serve_handle => {
+                info!(?x, "masque task finished");
+                true
+            }
+            x = &mut stats_handle => {
+                info!(?x, "stats task finished");
+                false
+            }
+        };
+
+        shutdown.cancel();
+
+        if serve_finished {
+            let _ = stats_handle.await;
+        } else {
+            let _ = serve_handle.await;
+        }
+
+        // HTTP/3 has its own code for a clean close
+        endpoint.close(H3_NO_ERROR.into(), b"server done");
+
+        Ok(())
+    }
+}
@@ -1,11 +1,380 @@
+mod bench;
+mod check;
+mod echo_server;
+mod masque_server;
+mod peer_client;
+mod peer_server;
+mod ping;
 mod quick_certs;
 mod reverse_proxy_client;
 mod reverse_proxy_server;
+mod run;
+mod service;
+mod top;
+mod transparent_client;
+mod tun_client;
+mod tun_server;
 mod udp_client;
 mod udp_server;
 
+pub use bench::BenchSubCommand;
+pub use check::CheckSubCommand;
+pub use echo_server::EchoServerSubCommand;
+pub use masque_server::MasqueServerSubCommand;
+pub use peer_client::PeerClientSubCommand;
+pub use peer_server::PeerServerSubCommand;
+pub use ping::PingSubCommand;
 pub use quick_certs::QuickCertsSubCommand;
 pub use reverse_proxy_client::ReverseProxyClientSubCommand;
 pub use reverse_proxy_server::ReverseProxyServerSubCommand;
+pub use run::RunSubCommand;
+pub use service::ServiceSubCommand;
+pub use top::TopSubCommand;
+pub use transparent_client::TransparentClientSubCommand;
+pub use tun_client::TunClientSubCommand;
+pub use tun_server::TunServerSubCommand;
 pub use udp_client::UdpClientSubCommand;
 pub use udp_server::UdpServerSubCommand;
+
+use anyhow::Context;
+use quic_tunnel::bind::BindOptions;
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::mdns;
+use quic_tunnel::obfs::{Obfuscation, DEFAULT_MAX_PAD};
+use quic_tunnel::proxy::{ProxyKind, ProxyUrl, UdpAssociation};
+use quic_tunnel::quic::{CongestionMode, TransportOptions};
+use quic_tunnel::resolve::Resolver;
+use quic_tunnel::server::ReverseProxyServerHandle;
+use quic_tunnel::shutdown::CancellationToken;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// `--obfuscate-key` turns on the xor_pad shim. config files can pick others
+pub fn obfuscation(key: Option<&String>) -> Obfuscation {
+    match key {
+        Some(key) => Obfuscation::XorPad {
+            key: key.clone(),
+            max_pad: DEFAULT_MAX_PAD,
+        },
+        None => Obfuscation::Off,
+    }
+}
+
+/// The QUIC transport flags, in groups since not every subcommand has every group. argh can't flatten one struct of flags
+/// into another, so each subcommand declares the flags itself, with the names used here, and `transport_args!` collects
+/// the groups it has.
+#[derive(Default)]
+pub struct TransportArgs {
+    pub quic: QuicArgs,
+    pub windows: WindowArgs,
+    pub streams: StreamArgs,
+    pub shims: ShimArgs,
+    /// `--no-migration`, on servers
+    pub no_migration: bool,
+}
+
+/// the flags every subcommand has
+#[derive(Default)]
+pub struct QuicArgs {
+    pub congestion_mode: CongestionMode,
+    pub quic_keep_alive: Option<Duration>,
+    pub quic_idle_timeout: Option<Duration>,
+    pub no_gso: bool,
+    pub no_ecn: bool,
+    pub no_mtu_discovery: bool,
+    pub initial_mtu: Option<u16>,
+    pub max_mtu: Option<u16>,
+}
+
+/// `--receive-window` and `--send-window`
+#[derive(Default)]
+pub struct WindowArgs {
+    pub receive_window: Option<u32>,
+    pub send_window: Option<u64>,
+}
+
+/// `--stream-receive-window` and `--max-concurrent-streams`, for subcommands that open a stream per user
+#[derive(Default)]
+pub struct StreamArgs {
+    pub stream_receive_window: Option<u32>,
+    pub max_concurrent_streams: Option<u32>,
+}
+
+/// `--obfuscate-key` and `--chaos`
+#[derive(Default)]
+pub struct ShimArgs {
+    pub obfuscate_key: Option<String>,
+    pub chaos: Option<ChaosOptions>,
+}
+
+impl TransportArgs {
+    /// only one side of a tunnel sends keep alives, so the subcommand decides `keep_alive` by its role
+    pub fn options(self, keep_alive: bool) -> TransportOptions {
+        TransportOptions {
+            congestion_mode: self.quic.congestion_mode,
+            keep_alive,
+            keep_alive_interval: self.quic.quic_keep_alive,
+            max_idle_timeout: self.quic.quic_idle_timeout,
+            stream_receive_window: self.streams.stream_receive_window,
+            receive_window: self.windows.receive_window,
+            send_window: self.windows.send_window,
+            max_concurrent_bidi_streams: self.streams.max_concurrent_streams,
+            gso: self.quic.no_gso.then_some(false),
+            ecn: self.quic.no_ecn.then_some(false),
+            mtu_discovery: self.quic.no_mtu_discovery.then_some(false),
+            initial_mtu: self.quic.initial_mtu,
+            max_mtu: self.quic.max_mtu,
+            migration: self.no_migration.then_some(false),
+            obfuscation: obfuscation(self.shims.obfuscate_key.as_ref()),
+            chaos: self.shims.chaos.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+/// a `TransportArgs` from a subcommand's flags, like `transport_args!(self; windows, shims)`. the quic group is always
+/// there. groups that aren't listed keep their defaults
+macro_rules! transport_args {
+    ($x:expr $(; $($group:ident),* $(,)?)?) => {{
+        #[allow(unused_mut)]
+        let mut args = $crate::subcommands::TransportArgs {
+            quic: transport_args!(@quic $x),
+            ..Default::default()
+        };
+
+        $($(args.$group = transport_args!(@$group $x);)*)?
+
+        args
+    }};
+    (@quic $x:expr) => {
+        $crate::subcommands::QuicArgs {
+            congestion_mode: $x.congestion_mode,
+            quic_keep_alive: $x.quic_keep_alive,
+            quic_idle_timeout: $x.quic_idle_timeout,
+            no_gso: $x.no_gso,
+            no_ecn: $x.no_ecn,
+            no_mtu_discovery: $x.no_mtu_discovery,
+            initial_mtu: $x.initial_mtu,
+            max_mtu: $x.max_mtu,
+        }
+    };
+    (@windows $x:expr) => {
+        $crate::subcommands::WindowArgs {
+            receive_window: $x.receive_window,
+            send_window: $x.send_window,
+        }
+    };
+    (@streams $x:expr) => {
+        $crate::subcommands::StreamArgs {
+            stream_receive_window: $x.stream_receive_window,
+            max_concurrent_streams: $x.max_concurrent_streams,
+        }
+    };
+    (@shims $x:expr) => {
+        $crate::subcommands::ShimArgs {
+            obfuscate_key: $x.obfuscate_key.clone(),
+            chaos: $x.chaos.clone(),
+        }
+    };
+    (@no_migration $x:expr) => {
+        $x.no_migration
+    };
+}
+
+pub(crate) use transport_args;
+
+/// the relay for `--proxy`. clients without a TCP fallback can only use a SOCKS5 proxy
+pub async fn socks_relay(
+    proxy: Option<&ProxyUrl>,
+    bind: &BindOptions,
+) -> anyhow::Result<Option<Arc<UdpAssociation>>> {
+    let Some(proxy) = proxy else {
+        return Ok(None);
+    };
+
+    if proxy.kind != ProxyKind::Socks5 {
+        anyhow::bail!("--proxy needs a socks5:// proxy. only reverse_proxy_client can use an HTTP proxy, for --tcp-fallback");
+    }
+
+    let x = proxy
+        .associate(&Resolver::default(), bind)
+        .await
+        .with_context(|| format!("asking {proxy} for a UDP relay"))?;
+
+    Ok(Some(Arc::new(x)))
+}
+
+/// answer mDNS queries for a server on QUIC `port` until `shutdown`, for clients with `--discover`
+pub fn advertise(port: u16, shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        if let Err(err) = mdns::advertise(port, shutdown).await {
+            warn!(?err, "stopped advertising on mDNS");
+        }
+    });
+}
+
+/// what an operator asked a server for with a signal. windows doesn't have these signals
+#[cfg_attr(not(unix), allow(dead_code))]
+pub enum ServerSignal {
+    /// SIGUSR1. log what the admin api would show
+    DumpStats,
+    /// SIGUSR2. hand the sockets to a new copy of the binary. see the `upgrade` module
+    Upgrade,
+}
+
+impl ServerSignal {
+    pub async fn apply(self, server: &ReverseProxyServerHandle) {
+        match self {
+            Self::DumpStats => {
+                info!("SIGUSR1 received. dumping stats");
+
+                server.dump_stats();
+            }
+            Self::Upgrade => {
+                info!("SIGUSR2 received. upgrading");
+
+                if let Err(err) = server.upgrade().await {
+                    error!(?err, "upgrade failed. still serving");
+                }
+            }
+        }
+    }
+}
+
+/// the signals a server subcommand answers. the library doesn't listen for any, so a program embedding it keeps its own
+pub struct ServerSignals {
+    #[cfg(unix)]
+    dump_stats: Option<tokio::signal::unix::Signal>,
+    /// only with upgrades on. otherwise SIGUSR2 stops the process like it would anything else
+    #[cfg(unix)]
+    upgrade: Option<tokio::signal::unix::Signal>,
+}
+
+impl ServerSignals {
+    pub fn new(server: &ReverseProxyServerHandle) -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            // the server works fine without these
+            let dump_stats = signal(SignalKind::user_defined1())
+                .inspect_err(|err| warn!(?err, "unable to listen for SIGUSR1"))
+                .ok();
+
+            let upgrade = match server.can_upgrade() {
+                true => signal(SignalKind::user_defined2())
+                    .inspect_err(|err| {
+                        warn!(?err, "unable to listen for SIGUSR2. upgrades are off")
+                    })
+                    .ok(),
+                false => None,
+            };
+
+            Self {
+                dump_stats,
+                upgrade,
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = server;
+
+            Self {}
+        }
+    }
+
+    /// the next signal. never returns if there is nothing to listen for
+    pub async fn recv(&mut self) -> ServerSignal {
+        #[cfg(unix)]
+        {
+            // None once the runtime is shutting down
+            async fn next(x: &mut Option<tokio::signal::unix::Signal>) {
+                if let Some(x) = x {
+                    if x.recv().await.is_some() {
+                        return;
+                    }
+                }
+
+                std::future::pending().await
+            }
+
+            tokio::select! {
+                _ = next(&mut self.dump_stats) => ServerSignal::DumpStats,
+                _ = next(&mut self.upgrade) => ServerSignal::Upgrade,
+            }
+        }
+
+        #[cfg(not(unix))]
+        std::future::pending().await
+    }
+}
+
+/// parse human friendly durations like "30s" or "5m" from the command line
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    humantime::parse_duration(value).map_err(|err| err.to_string())
+}
+
+/// parse sizes like "512", "64k", or "10M". suffixes are powers of 1024
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+
+    let (num, multiplier) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&value[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("\"{value}\" is not a size like 512, 64k, or 10M"))?;
+
+    num.checked_mul(multiplier)
+        .ok_or_else(|| format!("\"{value}\" is too big"))
+}
+
+/// parse octal file modes like "660" or "0o660"
+pub fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|x| *x <= 0o7777)
+        .ok_or_else(|| format!("\"{value}\" is not an octal file mode like 660"))
+}
+
+/// like `parse_duration`, but 0 is an error. for intervals
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let x = parse_duration(value)?;
+
+    if x.is_zero() {
+        return Err("must be more than 0".to_string());
+    }
+
+    Ok(x)
+}
+
+/// like "1.5 MiB"
+pub fn human_bytes(x: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut x = x as f64;
+    let mut unit = 0;
+
+    while x >= 1024.0 && unit < UNITS.len() - 1 {
+        x /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{x} {}", UNITS[unit])
+    } else {
+        format!("{x:.1} {}", UNITS[unit])
+    }
+}
+
+pub fn human_rate(bytes: u64, secs: f64) -> String {
+    format!("{}/s", human_bytes((bytes as f64 / secs) as u64))
+}
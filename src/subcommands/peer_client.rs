@@ -0,0 +1,324 @@
+use crate::subcommands::{parse_duration, parse_interval, transport_args};
+use anyhow::Context;
+use argh::FromArgs;
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::counters::{ScopedCounters, StatsOptions, StatsOutput, TunnelCounters};
+use quic_tunnel::failover::{ServerAddr, ServerList};
+use quic_tunnel::protocol::{CloseCode, Role, PREAMBLE_TIMEOUT};
+use quic_tunnel::quic::{
+    build_client_endpoint, quic_client_config, CongestionMode, TransportOptions,
+};
+use quic_tunnel::rendezvous;
+use quic_tunnel::resolve::Resolver;
+use quic_tunnel::runtime;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken, TaskTracker};
+use quic_tunnel::tls::TlsOptions;
+use quinn::Connection;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::select;
+use tracing::{debug, info, trace, Instrument};
+
+/// Reach a peer_server behind a NAT, through a rendezvous server.
+///
+/// Local TCP connections go straight to the peer_server with UDP hole punching, or through the rendezvous server when the
+/// NATs can't be punched. If the connection closes, so does this client, so run it under something that restarts it.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "peer_client")]
+pub struct PeerClientSubCommand {
+    /// prefix for all the certificates to load
+    #[argh(positional)]
+    cert_name: String,
+
+    /// the rendezvous server. a hostname like example.com:8443, or srv:_quic-tunnel._udp.example.com for the servers in a DNS SRV record
+    #[argh(positional)]
+    rendezvous_addr: ServerAddr,
+
+    /// the name the peer_server took on the rendezvous server
+    #[argh(positional)]
+    name: String,
+
+    /// the local TCP address to listen on. each connection is a stream to the peer_server
+    #[argh(option)]
+    tcp_listen: SocketAddr,
+
+    /// the name on the rendezvous server's certificate. if not set, it is guessed from the client cert's file name
+    #[argh(option)]
+    remote_name: Option<String>,
+
+    /// the name on the peer_server's certificate. defaults to the rendezvous server's
+    #[argh(option)]
+    peer_name: Option<String>,
+
+    /// don't try to connect straight to the peer_server. everything goes through the rendezvous server
+    #[argh(switch)]
+    no_punch: bool,
+
+    /// congestion mode for QUIC
+    #[argh(option, default = "Default::default()")]
+    congestion_mode: CongestionMode,
+
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives, which also keep NAT mappings open while the tunnel is idle (like "15s"). defaults to a third of the idle timeout
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// max bytes the peer may send across all QUIC streams before waiting for us to read
+    #[argh(option)]
+    receive_window: Option<u32>,
+
+    /// max bytes to buffer for sending across all QUIC streams
+    #[argh(option)]
+    send_window: Option<u64>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// XOR every packet with this key and pad it to a random size, for networks that throttle QUIC. the other side needs the same key.
+    /// this hides QUIC from simple filters. it is not encryption
+    #[argh(option)]
+    obfuscate_key: Option<String>,
+
+    /// for testing: drop and delay the packets we send, like "drop=1%,delay=20ms". needs the chaos feature
+    #[argh(option)]
+    chaos: Option<ChaosOptions>,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
+}
+
+impl PeerClientSubCommand {
+    fn transport_options(&self) -> TransportOptions {
+        transport_args!(self; windows, shims).options(true)
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            // a replayed stream would connect to the service again
+            early_data: false,
+        }
+    }
+
+    fn stats_options(&self) -> StatsOptions {
+        StatsOptions {
+            interval: self.stats_interval,
+            output: self.stats_output.clone(),
+        }
+    }
+
+    pub async fn main(self) -> anyhow::Result<()> {
+        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
+        let cert = PathBuf::from(format!("{}_client.pem", self.cert_name));
+        let key = PathBuf::from(format!("{}_client.key.pem", self.cert_name));
+
+        // like the reverse proxy client
+        let remote_name = match &self.remote_name {
+            Some(x) => x.clone(),
+            None => cert
+                .file_stem()
+                .context("no client cert file name")?
+                .to_string_lossy()
+                .replace("client", "server"),
+        };
+
+        let peer_name = self.peer_name.clone().unwrap_or(remote_name.clone());
+
+        let data_plane = runtime::data_plane();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let (endpoint, peer_config, listener) = {
+            let _guard = data_plane.enter();
+
+            let endpoint = build_client_endpoint(
+                ca.clone(),
+                cert.clone(),
+                key.clone(),
+                &self.transport_options(),
+                &self.tls_options(),
+                Role::Rendezvous,
+            )?;
+
+            let peer_config = quic_client_config(
+                ca,
+                cert,
+                key,
+                &self.transport_options(),
+                &self.tls_options(),
+                Role::Peer,
+            )?;
+
+            let listener = std::net::TcpListener::bind(self.tcp_listen)?;
+            listener.set_nonblocking(true)?;
+
+            (endpoint, peer_config, TcpListener::from_std(listener)?)
+        };
+
+        let servers = ServerList::new(vec![self.rendezvous_addr.clone()]);
+
+        let (rendezvous_conn, _) = servers
+            .connect(&endpoint, &remote_name, &Resolver::default(), false)
+            .await?;
+
+        let (_tx, mut rx) = rendezvous::meet(&rendezvous_conn, &self.name, false).await?;
+
+        let peer = rendezvous::next_peer(&mut rx, PREAMBLE_TIMEOUT)
+            .await?
+            .context("the rendezvous server didn't introduce us")?;
+
+        let direct = match self.no_punch {
+            true => None,
+            false => rendezvous::connect_direct(&endpoint, peer_config, peer, &peer_name).await,
+        };
+
+        // the rendezvous server relays the streams on its connection to the peer server
+        let conn = match direct {
+            Some(x) => {
+                info!(%peer, "connected directly");
+                x
+            }
+            None => {
+                info!(%peer, "relaying through {}", rendezvous_conn.remote_address());
+                rendezvous_conn.clone()
+            }
+        };
+
+        info!(
+            tcp_listen = %self.tcp_listen,
+            name = %self.name,
+            "forwarding connections to the peer"
+        );
+
+        let counts = TunnelCounters::new();
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        // every local connection, so the endpoint is only closed once they have stopped
+        let tracker = TaskTracker::new();
+
+        let mut accept_handle = data_plane.spawn(accept_local(
+            listener,
+            conn.clone(),
+            counts
+                .connection(conn.stable_id() as u64)
+                .with_path(&conn)
+                .with_listener(&self.tcp_listen.to_string()),
+            tracker.clone(),
+            shutdown.clone(),
+        ));
+
+        let mut stats_handle = counts.spawn_stats_loop(self.stats_options(), shutdown.clone());
+
+        // a finished JoinHandle panics if it is polled again, so only the others are awaited below
+        let mut accept_finished = false;
+        let mut stats_finished = false;
+
+        let x = select! {
+            x = &mut accept_handle => {
+                info!(?x, "local task finished");
+                accept_finished = true;
+                x?
+            }
+            x = &mut stats_handle => {
+                info!(?x, "stats task finished");
+                stats_finished = true;
+                Ok(())
+            }
+            _ = conn.closed() => {
+                info!("the peer connection closed");
+                Ok(())
+            }
+            _ = shutdown.cancelled() => Ok(()),
+        };
+
+        shutdown.cancel();
+
+        if !accept_finished {
+            let _ = accept_handle.await;
+        }
+        if !stats_finished {
+            let _ = stats_handle.await;
+        }
+
+        tracker.close();
+        tracker.wait().await;
+
+        endpoint.close(CloseCode::Done.into(), b"client done");
+
+        x
+    }
+}
+
+async fn accept_local(
+    listener: TcpListener,
+    conn: Connection,
+    counts: ScopedCounters,
+    tracker: TaskTracker,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = select! {
+            x = listener.accept() => x?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        let conn = conn.clone();
+        let counts = counts.clone();
+        let shutdown = shutdown.clone();
+
+        let span = tracing::info_span!("local", %peer);
+
+        tracker.spawn(
+            async move {
+                let x = select! {
+                    x = rendezvous::forward_to_peer(&conn, stream, counts) => x,
+                    _ = shutdown.cancelled() => return,
+                };
+
+                match x {
+                    Ok((a_to_b, b_to_a)) => trace!(%a_to_b, %b_to_a, "success"),
+                    Err(err) => debug!(?err, "local connection failed"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::subcommands::{parse_duration, parse_interval, transport_args};
+use anyhow::Context;
+use argh::FromArgs;
+use futures::TryFutureExt;
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::counters::{StatsOptions, StatsOutput, TunnelCounters};
+use quic_tunnel::failover::{ServerAddr, ServerList};
+use quic_tunnel::protocol::{CloseCode, Role};
+use quic_tunnel::quic::{
+    build_server_endpoint, quic_client_config, CongestionMode, RetryOptions, TransportOptions,
+};
+use quic_tunnel::rendezvous;
+use quic_tunnel::resolve::Resolver;
+use quic_tunnel::runtime;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken, TaskTracker};
+use quic_tunnel::tls::TlsOptions;
+use quinn::Connecting;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::timeout;
+use tracing::{info, trace};
+
+/// Run a TCP service behind a NAT for peer_clients, through a rendezvous server.
+///
+/// This takes a name on a reverse_proxy_server with --rendezvous. peer_clients that ask for the name connect straight here
+/// with UDP hole punching, or through the rendezvous server when the NATs can't be punched.
+/// If the rendezvous connection closes, so does this, so run it under something that restarts it.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "peer_server")]
+pub struct PeerServerSubCommand {
+    /// prefix for all the certificates to load. this needs both the server and client certs
+    #[argh(positional)]
+    cert_name: String,
+
+    /// the rendezvous server. a hostname like example.com:8443, or srv:_quic-tunnel._udp.example.com for the servers in a DNS SRV record
+    #[argh(positional)]
+    rendezvous_addr: ServerAddr,
+
+    /// the name peer_clients ask the rendezvous server for
+    #[argh(positional)]
+    name: String,
+
+    /// the local TCP service. every stream from a peer_client is connected here
+    #[argh(option)]
+    tcp_connect: SocketAddr,
+
+    /// the name on the rendezvous server's certificate. if not set, it is guessed from the client cert's file name
+    #[argh(option)]
+    remote_name: Option<String>,
+
+    /// the local UDP address for QUIC, to the rendezvous server and from peer_clients
+    #[argh(option, default = "\"0.0.0.0:0\".parse().unwrap()")]
+    bind: SocketAddr,
+
+    /// congestion mode for QUIC
+    #[argh(option, default = "Default::default()")]
+    congestion_mode: CongestionMode,
+
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives, which also keep NAT mappings open while the tunnel is idle (like "15s"). defaults to a third of the idle timeout
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// max bytes the peer may send across all QUIC streams before waiting for us to read
+    #[argh(option)]
+    receive_window: Option<u32>,
+
+    /// max bytes to buffer for sending across all QUIC streams
+    #[argh(option)]
+    send_window: Option<u64>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// make peers prove their address with a retry before the handshake, so spoofed handshakes can't make us send much. costs
+    /// every peer a round trip
+    #[argh(switch)]
+    require_retry: bool,
+
+    /// how long a client has to answer the server's retry (like "15s", the default). raise it for clients with a very long
+    /// round trip
+    #[argh(option, from_str_fn(parse_interval))]
+    retry_token_lifetime: Option<Duration>,
+
+    /// XOR every packet with this key and pad it to a random size, for networks that throttle QUIC. the other side needs the same key.
+    /// this hides QUIC from simple filters. it is not encryption
+    #[argh(option)]
+    obfuscate_key: Option<String>,
+
+    /// for testing: drop and delay the packets we send, like "drop=1%,delay=20ms". needs the chaos feature
+    #[argh(option)]
+    chaos: Option<ChaosOptions>,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
+}
+
+impl PeerServerSubCommand {
+    fn retry_options(&self) -> RetryOptions {
+        let x = RetryOptions::default();
+
+        RetryOptions {
+            require: self.require_retry,
+            token_lifetime: self.retry_token_lifetime.unwrap_or(x.token_lifetime),
+        }
+    }
+
+    fn transport_options(&self) -> TransportOptions {
+        transport_args!(self; windows, shims).options(true)
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            // a replayed stream would connect to the service again
+            early_data: false,
+        }
+    }
+
+    fn stats_options(&self) -> StatsOptions {
+        StatsOptions {
+            interval: self.stats_interval,
+            output: self.stats_output.clone(),
+        }
+    }
+
+    pub async fn main(self) -> anyhow::Result<()> {
+        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
+        let server_cert = PathBuf::from(format!("{}_server.pem", self.cert_name));
+        let server_key = PathBuf::from(format!("{}_server.key.pem", self.cert_name));
+        let client_cert = PathBuf::from(format!("{}_client.pem", self.cert_name));
+        let client_key = PathBuf::from(format!("{}_client.key.pem", self.cert_name));
+
+        // like the reverse proxy client
+        let remote_name = match &self.remote_name {
+            Some(x) => x.clone(),
+            None => client_cert
+                .file_stem()
+                .context("no client cert file name")?
+                .to_string_lossy()
+                .replace("client", "server"),
+        };
+
+        let data_plane = runtime::data_plane();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let endpoint = {
+            let _guard = data_plane.enter();
+
+            // punching only works from the socket the rendezvous server sees, so one endpoint does both
+            let mut endpoint = build_server_endpoint(
+                ca.clone(),
+                server_cert,
+                server_key,
+                &self.retry_options(),
+                self.bind,
+                &self.transport_options(),
+                &self.tls_options(),
+                &[Role::Peer],
+            )?;
+
+            endpoint.set_default_client_config(quic_client_config(
+                ca,
+                client_cert,
+                client_key,
+                &self.transport_options(),
+                &self.tls_options(),
+                Role::Rendezvous,
+            )?);
+
+            endpoint
+        };
+
+        let servers = ServerList::new(vec![self.rendezvous_addr.clone()]);
+
+        let (conn, _) = servers
+            .connect(&endpoint, &remote_name, &Resolver::default(), false)
+            .await?;
+
+        let (_tx, mut rx) = rendezvous::meet(&conn, &self.name, true).await?;
+
+        info!(
+            name = %self.name,
+            local_addr = %endpoint.local_addr()?,
+            "waiting for peers at {}",
+            conn.remote_address()
+        );
+
+        let counts = TunnelCounters::new();
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        // every direct connection and punch, so the endpoint is only closed once they have stopped
+        let tracker = TaskTracker::new();
+
+        // streams the rendezvous server relays
+        let mut relay_handle = {
+            let conn = conn.clone();
+            let counts = counts.connection(conn.stable_id() as u64).with_path(&conn);
+            let backend = self.tcp_connect;
+            let shutdown = shutdown.clone();
+
+            data_plane.spawn(async move {
+                select! {
+                    x = rendezvous::serve_peer_streams(&conn, backend, counts) => x,
+                    _ = shutdown.cancelled() => Ok(()),
+                }
+            })
+        };
+
+        let mut punch_handle = {
+            let endpoint = endpoint.clone();
+            let shutdown = shutdown.clone();
+            let tracker = tracker.clone();
+
+            data_plane.spawn(async move {
+                loop {
+                    // the peer server waits as long as it takes
+                    let peer = select! {
+                        x = rendezvous::next_peer(&mut rx, Duration::MAX) => x?,
+                        _ = shutdown.cancelled() => break,
+                    };
+
+                    let Some(peer) = peer else {
+                        break;
+                    };
+
+                    info!(%peer, "punching toward a peer");
+
+                    let endpoint = endpoint.clone();
+                    let shutdown = shutdown.clone();
+
+                    tracker.spawn(async move {
+                        select! {
+                            _ = rendezvous::punch(&endpoint, peer) => {}
+                            _ = shutdown.cancelled() => {}
+                        }
+                    });
+                }
+
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+
+        let mut accept_handle = {
+            let endpoint = endpoint.clone();
+            let counts = counts.clone();
+            let backend = self.tcp_connect;
+            let shutdown = shutdown.clone();
+            let tracker = tracker.clone();
+
+            data_plane.spawn(async move {
+                loop {
+                    let conn = select! {
+                        x = endpoint.accept() => x,
+                        _ = shutdown.cancelled() => break,
+                    };
+
+                    let Some(conn) = conn else {
+                        break;
+                    };
+
+                    let f = handle_connection(conn, backend, counts.clone(), shutdown.clone());
+
+                    // spawn to handle multiple connections at once
+                    tracker.spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
+                }
+            })
+        };
+
+        let mut stats_handle = counts.spawn_stats_loop(self.stats_options(), shutdown.clone());
+
+        // a finished JoinHandle panics if it is polled again, so only the others are awaited below
+        let mut relay_finished = false;
+        let mut punch_finished = false;
+        let mut accept_finished = false;
+        let mut stats_finished = false;
+
+        let x = select! {
+            x = &mut relay_handle => {
+                info!(?x, "rendezvous connection finished");
+                relay_finished = true;
+                x?
+            }
+            x = &mut punch_handle => {
+                info!(?x, "rendezvous stream finished");
+                punch_finished = true;
+                x?
+            }
+            x = &mut accept_handle => {
+                info!(?x, "accept task finished");
+                accept_finished = true;
+                Ok(())
+            }
+            x = &mut stats_handle => {
+                info!(?x, "stats task finished");
+                stats_finished = true;
+                Ok(())
+            }
+            _ = shutdown.cancelled() => Ok(()),
+        };
+
+        shutdown.cancel();
+
+        if !relay_finished {
+            let _ = relay_handle.await;
+        }
+        if !punch_finished {
+            let _ = punch_handle.await;
+        }
+        if !accept_finished {
+            let _ = accept_handle.await;
+        }
+        if !stats_finished {
+            let _ = stats_handle.await;
+        }
+
+        tracker.close();
+        tracker.wait().await;
+
+        endpoint.close(CloseCode::Done.into(), b"peer server done");
+
+        x
+    }
+}
+
+async fn handle_connection(
+    conn: Connecting,
+    backend: SocketAddr,
+    counts: Arc<TunnelCounters>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let conn = select! {
+        x = timeout(Duration::from_secs(30), conn) => x??,
+        _ = shutdown.cancelled() => return Ok(()),
+    };
+
+    info!(peer = %conn.remote_address(), "peer connected directly");
+
+    let counts = counts.connection(conn.stable_id() as u64).with_path(&conn);
+
+    select! {
+        x = rendezvous::serve_peer_streams(&conn, backend, counts) => x,
+        _ = shutdown.cancelled() => {
+            conn.close(CloseCode::Done.into(), b"peer server done");
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use argh::FromArgs;
+use futures::StreamExt;
+use quic_tunnel::failover::{ServerAddr, ServerList};
+use quic_tunnel::protocol::{ControlMessage, Role};
+use quic_tunnel::quic::{build_client_endpoint, TransportOptions};
+use quic_tunnel::resolve::Resolver;
+use quic_tunnel::tls::TlsOptions;
+use quinn::{Connection, ConnectionError, Endpoint};
+use quinn_proto::TransportErrorCode;
+use serde::Serialize;
+use tokio::select;
+use tokio::time::{interval, sleep_until, timeout, Instant, MissedTickBehavior};
+
+use super::{parse_duration, parse_interval};
+
+/// the TLS alert a server sends when it doesn't take any of our ALPNs
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+/// Check that a server answers, and how quickly: the handshake, then round trips over a stream.
+///
+/// Nothing is forwarded, and the server doesn't count it as a tunnel client.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "ping")]
+pub struct PingSubCommand {
+    /// prefix for all the certificates to load
+    #[argh(positional)]
+    cert_name: String,
+
+    /// the address of the remote QUIC server, like example.com:8443. srv:_quic-tunnel._udp.example.com also works
+    #[argh(positional)]
+    remote_addr: ServerAddr,
+
+    /// the name on the server's certificate. if not set, it is guessed from the client cert's file name
+    #[argh(option)]
+    remote_name: Option<String>,
+
+    /// how many pings to send. 10 by default
+    #[argh(option, default = "10")]
+    count: u64,
+
+    /// how long between pings. 1s by default
+    #[argh(
+        option,
+        default = "Duration::from_secs(1)",
+        from_str_fn(parse_interval)
+    )]
+    interval: Duration,
+
+    /// how long to wait for the handshake, and for the last pong. 5s by default
+    #[argh(
+        option,
+        default = "Duration::from_secs(5)",
+        from_str_fn(parse_duration)
+    )]
+    timeout: Duration,
+
+    /// print only a summary, as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Summary {
+    server: SocketAddr,
+    server_name: String,
+    /// from sending the first packet to the TLS handshake finishing
+    handshake_us: u64,
+    sent: u64,
+    answered: u64,
+    loss_percent: f64,
+    rtt_min_us: Option<u64>,
+    rtt_avg_us: Option<u64>,
+    rtt_p99_us: Option<u64>,
+    rtt_max_us: Option<u64>,
+}
+
+impl PingSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        if self.count == 0 {
+            anyhow::bail!("--count must be at least 1");
+        }
+
+        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
+        let cert = PathBuf::from(format!("{}_client.pem", self.cert_name));
+        let key = PathBuf::from(format!("{}_client.key.pem", self.cert_name));
+
+        // like the reverse proxy client
+        let server_name = match &self.remote_name {
+            Some(x) => x.clone(),
+            None => cert
+                .file_stem()
+                .context("no client cert file name")?
+                .to_string_lossy()
+                .replace("client", "server"),
+        };
+
+        let endpoint = build_client_endpoint(
+            ca,
+            cert,
+            key,
+            &TransportOptions::default(),
+            // a ping that rode on a resumed session wouldn't time the whole handshake
+            &TlsOptions {
+                early_data: false,
+                ..Default::default()
+            },
+            Role::Probe,
+        )?;
+
+        let (conn, handshake) =
+            connect(&endpoint, &self.remote_addr, &server_name, self.timeout).await?;
+
+        let server = conn.remote_address();
+
+        if !self.json {
+            println!("PING {server_name} ({server}): handshake {}", ms(handshake));
+        }
+
+        let rtts = self.ping(&conn).await?;
+
+        conn.close(0u32.into(), b"done");
+        endpoint.wait_idle().await;
+
+        let mut sorted = rtts.clone();
+        sorted.sort();
+
+        let us = |x: &Duration| x.as_micros() as u64;
+
+        let summary = Summary {
+            server,
+            server_name,
+            handshake_us: us(&handshake),
+            sent: self.count,
+            answered: rtts.len() as u64,
+            loss_percent: (self.count - rtts.len() as u64) as f64 * 100.0 / self.count as f64,
+            rtt_min_us: sorted.first().map(us),
+            rtt_avg_us: (!rtts.is_empty())
+                .then(|| us(&(rtts.iter().sum::<Duration>() / rtts.len() as u32))),
+            // the nearest rank, so it is never below the median
+            rtt_p99_us: (!sorted.is_empty())
+                .then(|| us(&sorted[(sorted.len() * 99).div_ceil(100) - 1])),
+            rtt_max_us: sorted.last().map(us),
+        };
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        } else {
+            let us_ms =
+                |x: Option<u64>| x.map_or("-".to_string(), |x| ms(Duration::from_micros(x)));
+
+            println!("--- {} ---", summary.server);
+            println!(
+                "{} sent, {} answered, {:.1}% lost",
+                summary.sent, summary.answered, summary.loss_percent
+            );
+            println!(
+                "rtt min {}, avg {}, p99 {}, max {}",
+                us_ms(summary.rtt_min_us),
+                us_ms(summary.rtt_avg_us),
+                us_ms(summary.rtt_p99_us),
+                us_ms(summary.rtt_max_us),
+            );
+        }
+
+        if rtts.is_empty() {
+            anyhow::bail!("the server never answered");
+        }
+
+        Ok(())
+    }
+
+    /// send `count` pings, each carrying when it was sent, and return the round trip of each pong
+    async fn ping(&self, conn: &Connection) -> anyhow::Result<Vec<Duration>> {
+        let (mut tx, rx) = conn.open_bi().await?;
+
+        // a read cut off by a tick would lose its place in the stream, so reads carry on across loops
+        let pongs = futures::stream::unfold(rx, |mut rx| async {
+            let x = ControlMessage::read(&mut rx, Duration::MAX).await;
+
+            Some((x, rx))
+        });
+        tokio::pin!(pongs);
+
+        let start = Instant::now();
+
+        let mut ticks = interval(self.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut sent = 0;
+        let mut rtts = vec![];
+
+        // after the last ping, how long its pong has
+        let mut deadline = None;
+
+        while (rtts.len() as u64) < self.count {
+            select! {
+                _ = ticks.tick(), if sent < self.count => {
+                    let timestamp = start.elapsed().as_micros() as u64;
+
+                    tx.write_all(&ControlMessage::Ping(timestamp).encode()?).await?;
+
+                    sent += 1;
+
+                    if sent == self.count {
+                        deadline = Some(Instant::now() + self.timeout);
+                    }
+                }
+                Some(x) = pongs.next() => match x.context("reading pongs")? {
+                    Some(ControlMessage::Pong(timestamp)) => {
+                        let rtt = start.elapsed().saturating_sub(Duration::from_micros(timestamp));
+
+                        rtts.push(rtt);
+
+                        if !self.json {
+                            println!("pong {}: {}", rtts.len(), ms(rtt));
+                        }
+                    }
+                    Some(x) => anyhow::bail!("the server answered a ping with {x:?}"),
+                    None => anyhow::bail!("the server closed the stream"),
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => break,
+            }
+        }
+
+        Ok(rtts)
+    }
+}
+
+/// try the server's addresses until one finishes a handshake. returns how long that took
+async fn connect(
+    endpoint: &Endpoint,
+    addr: &ServerAddr,
+    server_name: &str,
+    max_wait: Duration,
+) -> anyhow::Result<(Connection, Duration)> {
+    // the endpoint is only bound for IPv4
+    let candidates: Vec<_> = ServerList::new(vec![addr.clone()])
+        .candidates(&Resolver::default())
+        .await?
+        .into_iter()
+        .filter(SocketAddr::is_ipv4)
+        .collect();
+
+    let mut last_err = None;
+
+    for addr in candidates {
+        let started = Instant::now();
+
+        let Ok(x) = timeout(max_wait, endpoint.connect(addr, server_name)?).await else {
+            last_err = Some(anyhow::anyhow!("{addr} didn't answer in {max_wait:?}"));
+            continue;
+        };
+
+        match x {
+            Ok(x) => return Ok((x, started.elapsed())),
+            Err(ConnectionError::ConnectionClosed(x))
+                if x.error_code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL) =>
+            {
+                anyhow::bail!("{addr} doesn't answer pings. it may be older than this client");
+            }
+            Err(err) => {
+                last_err = Some(anyhow::Error::from(err).context(format!("connecting to {addr}")));
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("{addr} has no IPv4 addresses")))
+}
+
+fn ms(x: Duration) -> String {
+    format!("{:.2}ms", x.as_secs_f64() * 1000.0)
+}
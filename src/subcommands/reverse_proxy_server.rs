@@ -1,18 +1,39 @@
+use crate::subcommands::{
+    advertise, parse_bytes, parse_duration, parse_interval, parse_mode, transport_args,
+    ServerSignals,
+};
 use argh::FromArgs;
-use flume::Receiver;
-use futures::TryFutureExt;
-use quic_tunnel::compress::{copy_bidirectional_with_compression, CompressAlgo};
-use quic_tunnel::counters::TunnelCounters;
-use quic_tunnel::quic::{build_server_endpoint, CongestionMode};
-use quic_tunnel::stream::Stream;
-use quinn::Connecting;
+use ipnet::IpNet;
+use quic_tunnel::chain::HopOptions;
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::compress::{CloseMode, CompressAlgo};
+use quic_tunnel::control::HeartbeatOptions;
+use quic_tunnel::counters::{StatsOptions, StatsOutput};
+use quic_tunnel::datagram::DatagramTarget;
+use quic_tunnel::dump::{DumpOptions, DEFAULT_MAX_BYTES};
+use quic_tunnel::http_route::HttpRule;
+use quic_tunnel::listen::ListenTarget;
+use quic_tunnel::padding::{PaddingMode, PaddingOptions};
+use quic_tunnel::quic::{CongestionMode, RetryOptions, TransportOptions};
+use quic_tunnel::quota::QuotaOptions;
+use quic_tunnel::registry::{IdentityLimit, IdentityLimitPolicy};
+use quic_tunnel::server::{ListenerConfig, ReverseProxyServer};
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken};
+use quic_tunnel::sni::SniRule;
+use quic_tunnel::stream::TcpOptions;
+use quic_tunnel::terminate::TlsTerminator;
+use quic_tunnel::tls::TlsOptions;
+use quic_tunnel::transform::TransformPipeline;
+use quic_tunnel::transparent::ConnectOptions;
+use quic_tunnel::unix::UnixSocketOptions;
+use quic_tunnel::upgrade;
+use quic_tunnel::vsock::VsockAddr;
+use quic_tunnel::webhook::{WebhookConfig, WebhookUrl};
+use quic_tunnel::webtransport::WebTransportOptions;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::{TcpListener, UdpSocket, UnixListener};
-use tokio::select;
-use tokio::time::timeout;
-use tracing::{debug, error, info, trace};
 
 /// Run the QUIC Tunnel Server.
 #[derive(Debug, FromArgs, PartialEq)]
@@ -28,6 +49,14 @@ pub struct ReverseProxyServerSubCommand {
     #[argh(positional)]
     quic_addr: SocketAddr,
 
+    /// another address to listen on with QUIC, like [::]:8443 next to 0.0.0.0:8443. can be repeated. IPv6 addresses only take IPv6 then
+    #[argh(option)]
+    quic_listen: Vec<SocketAddr>,
+
+    /// how many QUIC sockets to bind to each QUIC address with SO_REUSEPORT. more sockets spread the UDP work over more cores
+    #[argh(option, default = "1")]
+    quic_sockets: usize,
+
     /// the TCP address to bind. users that connect here will be forwarded to any clients connected to the QUIC address.
     #[argh(option)]
     tcp_listen: Option<SocketAddr>,
@@ -37,210 +66,745 @@ pub struct ReverseProxyServerSubCommand {
     udp_listen: Option<SocketAddr>,
 
     /// the Unix socket path to bind. users that connect here will be forwarded to any clients connected to the QUIC address.
+    /// on linux, a name starting with @ is an abstract socket
     #[argh(option)]
     unix_listen: Option<PathBuf>,
 
+    /// the Windows named pipe to create, like \\.\pipe\quic-tunnel. users that connect here will be forwarded to any clients connected to the QUIC address.
+    #[argh(option)]
+    pipe_listen: Option<String>,
+
+    /// the vsock address to listen on, like any:5000. users in a VM guest or host that connect here will be forwarded to any clients connected to the QUIC address.
+    #[argh(option)]
+    vsock_listen: Option<VsockAddr>,
+
     /// congestion mode for QUIC
     #[argh(option, default = "CongestionMode::NewReno")]
     congestion_mode: CongestionMode,
 
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives (like "15s"). clients send them, so a server only does with this set
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// max bytes the peer may send on one QUIC stream before waiting for us to read
+    #[argh(option)]
+    stream_receive_window: Option<u32>,
+
+    /// max bytes the peer may send across all QUIC streams before waiting for us to read
+    #[argh(option)]
+    receive_window: Option<u32>,
+
+    /// max bytes to buffer for sending across all QUIC streams
+    #[argh(option)]
+    send_window: Option<u64>,
+
+    /// max number of QUIC streams the peer may have open at once
+    #[argh(option)]
+    max_concurrent_streams: Option<u32>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// XOR every packet with this key and pad it to a random size, for networks that throttle QUIC. the other side needs the same key.
+    /// this hides QUIC from simple filters. it is not encryption
+    #[argh(option)]
+    obfuscate_key: Option<String>,
+
+    /// for testing: drop and delay the packets we send, and reset streams, like "drop=1%,delay=20ms,reset-streams=0.1%".
+    /// dropping and delaying needs the chaos feature
+    #[argh(option)]
+    chaos: Option<ChaosOptions>,
+
+    /// close connections from clients whose address changes instead of following them to the new one
+    #[argh(switch)]
+    no_migration: bool,
+
+    /// set TCP_NODELAY on user connections so small writes are sent right away
+    #[argh(switch)]
+    tcp_nodelay: bool,
+
+    /// turn on TCP keepalive for user connections and wait this long (like "1m") before the first probe
+    #[argh(option, from_str_fn(parse_interval))]
+    tcp_keepalive: Option<Duration>,
+
+    /// time between TCP keepalive probes. turns keepalive on
+    #[argh(option, from_str_fn(parse_interval))]
+    tcp_keepalive_interval: Option<Duration>,
+
+    /// unanswered TCP keepalive probes before a connection is dropped. turns keepalive on
+    #[argh(option)]
+    tcp_keepalive_probes: Option<u32>,
+
+    /// SO_RCVBUF in bytes for user connections
+    #[argh(option)]
+    tcp_recv_buffer: Option<u32>,
+
+    /// SO_SNDBUF in bytes for user connections
+    #[argh(option)]
+    tcp_send_buffer: Option<u32>,
+
+    /// how many users `tcp_listen` queues before they are accepted. 1024 by default. the kernel may cap it, like at
+    /// net.core.somaxconn on linux
+    #[argh(option)]
+    tcp_backlog: Option<u32>,
+
     /// compression mode for the QUIC tunnel.
     ///
     /// Be very careful with this! See: [CRIME](https://en.wikipedia.org/wiki/CRIME) attack!
     #[argh(option, default = "CompressAlgo::None")]
     compress: CompressAlgo,
+
+    /// what to do when one side of a stream sends EOF. "half" passes it along and keeps copying the other way. "full" closes the whole stream
+    #[argh(option, default = "CloseMode::Half")]
+    close_mode: CloseMode,
+
+    /// pad streams so their sizes and timing leak less. "off" (the default), "bucketed" rounds writes up to a power of two, "constant_rate"
+    /// sends at `padding-rate` all the time. clients pad the same way
+    #[argh(option, default = "PaddingMode::Off")]
+    padding: PaddingMode,
+
+    /// bytes per second for each direction of a constant_rate stream, like "64k" (the default)
+    #[argh(option, from_str_fn(parse_bytes))]
+    padding_rate: Option<u64>,
+
+    /// stream transformers to apply to users connecting to `tcp_listen`, in order. available: proxy_v1
+    #[argh(option)]
+    tcp_transform: Vec<String>,
+
+    /// only allow users connecting to `tcp_listen` from these networks (like "10.0.0.0/8"). can be repeated. defaults to everyone
+    #[argh(option)]
+    tcp_allow: Vec<IpNet>,
+
+    /// send tls users of `tcp_listen` to another route by the server name they ask for, like "*.example.com=web". the first
+    /// match wins and users no rule matches use the "tcp" route. without --tcp-tls-cert, tls is not terminated. can be repeated
+    #[argh(option)]
+    tcp_sni: Vec<SniRule>,
+
+    /// send plain http users of `tcp_listen` to another route by the host and path of their first request, like
+    /// "example.com/api=api", "/static=files", or "*.example.com=web". the first match wins and users no rule matches use the
+    /// "tcp" route. can be repeated
+    #[argh(option)]
+    tcp_http: Vec<HttpRule>,
+
+    /// turn away users of `tcp_listen` while this many of them are open. on top of --max-connections
+    #[argh(option)]
+    tcp_max_connections: Option<usize>,
+
+    /// terminate TLS for users of `tcp_listen` with this PEM cert chain, like one from Let's Encrypt, so a plaintext backend
+    /// is served as TLS. it is separate from the tunnel's certs. --tcp-http rules don't match these users
+    #[argh(option)]
+    tcp_tls_cert: Option<PathBuf>,
+
+    /// the key for `tcp-tls-cert`
+    #[argh(option)]
+    tcp_tls_key: Option<PathBuf>,
+
+    /// stream transformers to apply to users connecting to `unix_listen`, in order. available: proxy_v1
+    #[argh(option)]
+    unix_transform: Vec<String>,
+
+    /// stream transformers to apply to users connecting to `pipe_listen`, in order. available: proxy_v1
+    #[argh(option)]
+    pipe_transform: Vec<String>,
+
+    /// stream transformers to apply to users connecting to `vsock_listen`, in order. available: proxy_v1
+    #[argh(option)]
+    vsock_transform: Vec<String>,
+
+    /// forward this process's stdin and stdout as a single user, like for ssh's ProxyCommand or inetd. the server stops once they are done
+    #[argh(switch)]
+    stdio: bool,
+
+    /// also take streams from browsers over WebTransport on this UDP address. a browser session for https://host:port/tcp is a user of the tcp listener
+    #[argh(option)]
+    webtransport_listen: Option<SocketAddr>,
+
+    /// a PEM cert chain browsers trust for the WebTransport address, like one from Let's Encrypt. defaults to the tunnel's server cert
+    #[argh(option)]
+    webtransport_cert: Option<PathBuf>,
+
+    /// the key for `webtransport-cert`
+    #[argh(option)]
+    webtransport_key: Option<PathBuf>,
+
+    /// also take tunnel connections over TLS over TCP on this address, for clients whose networks block UDP. see the client's `tcp-fallback`
+    #[argh(option)]
+    tcp_fallback_listen: Option<SocketAddr>,
+
+    /// also take udp_client connections on the quic port and forward their datagrams here, like udp_server does.
+    /// a UDP address or unix:/path
+    #[argh(option)]
+    udp_forward: Option<DatagramTarget>,
+
+    /// also take transparent_client connections on the quic port, and connect their streams to these networks (like
+    /// "10.0.0.0/8"). can be repeated. "0.0.0.0/0" and "::/0" allow anywhere
+    #[argh(option)]
+    connect_allow: Vec<IpNet>,
+
+    /// also take peer_server and peer_client connections on the quic port, so peers behind NATs can meet here and connect
+    /// to each other directly. streams are relayed through here when they can't
+    #[argh(switch)]
+    rendezvous: bool,
+
+    /// also be a hop for reverse_proxy_clients with --via on the quic port, and forward their packets to servers in these
+    /// networks (like "10.0.0.0/8"). can be repeated
+    #[argh(option)]
+    hop_allow: Vec<IpNet>,
+
+    /// file mode for the unix socket files we create, in octal like 660
+    #[argh(option, from_str_fn(parse_mode))]
+    unix_mode: Option<u32>,
+
+    /// user name or uid to own the unix socket files we create
+    #[argh(option)]
+    unix_owner: Option<String>,
+
+    /// group name or gid to own the unix socket files we create
+    #[argh(option)]
+    unix_group: Option<String>,
+
+    /// how many QUIC streams to open ahead of time for each tunnel client. 0 opens them on demand
+    #[argh(option, default = "0")]
+    stream_pool_size: usize,
+
+    /// refuse user connections while no tunnel client is connected instead of queueing them
+    #[argh(switch)]
+    reject_without_clients: bool,
+
+    /// before closing a refused user connection, write a short error they can understand (HTTP 503, SSH banner text)
+    #[argh(switch)]
+    error_hints: bool,
+
+    /// after a tunnel client connects, ramp up how fast queued users are handed to it over this long (like "5s"). helps a backend that just came back up
+    #[argh(option, from_str_fn(parse_duration))]
+    warm_up: Option<Duration>,
+
+    /// how many queued users a newly connected client gets right away before `warm_up` starts pacing
+    #[argh(option, default = "16")]
+    warm_up_streams: usize,
+
+    /// serve a JSON admin api on this unix socket. lists clients and streams, kills connections, and dumps the config
+    #[argh(option)]
+    admin_socket: Option<PathBuf>,
+
+    /// answer /healthz and /readyz over HTTP on this address. ready is while a tunnel client is connected
+    #[argh(option)]
+    health_listen: Option<SocketAddr>,
+
+    /// keep each client certificate's byte totals in this JSON file across reconnects and restarts. the admin api's usage shows them
+    #[argh(option)]
+    usage_file: Option<PathBuf>,
+
+    /// append every tunnel client connection and user stream to this file, one JSON object per line
+    #[argh(option)]
+    audit_log: Option<PathBuf>,
+
+    /// POST tunnel client connections, disconnections, auth failures, and quota breaches to this URL as JSON. can be repeated
+    #[argh(option)]
+    webhook: Vec<WebhookUrl>,
+
+    /// stop handing users to a client certificate after this many bytes in a calendar month (like "100G")
+    #[argh(option, from_str_fn(parse_bytes))]
+    quota_monthly: Option<u64>,
+
+    /// stop handing users to a tunnel connection after this many bytes (like "10G")
+    #[argh(option, from_str_fn(parse_bytes))]
+    quota_per_connection: Option<u64>,
+
+    /// close the connection of a client over its quota instead of only not handing it users
+    #[argh(switch)]
+    quota_disconnect: bool,
+
+    /// cap each tunnel client to this many bytes per second in each direction (like "10M"), shared by all of its streams
+    #[argh(option, from_str_fn(parse_bytes))]
+    per_client_rate: Option<u64>,
+
+    /// how many bytes a tunnel client can send at once before `per-client-rate` kicks in. defaults to one second of the rate
+    #[argh(option, from_str_fn(parse_bytes))]
+    per_client_burst: Option<u64>,
+
+    /// refuse new users past this many per second across every listener
+    #[argh(option)]
+    max_connection_rate: Option<u64>,
+
+    /// refuse new users past this many per second from one address
+    #[argh(option)]
+    max_connection_rate_per_ip: Option<u64>,
+
+    /// turn away users while this many are open across every listener, so a flood gets a quick "server is full" instead of
+    /// a queue. with --error-hints, http and ssh users are told why
+    #[argh(option)]
+    max_connections: Option<usize>,
+
+    /// hand each tunnel client at most this many users at once. the rest wait for a free slot or another client
+    #[argh(option)]
+    max_streams_per_client: Option<usize>,
+
+    /// let each client cert hold at most this many tunnel connections at once. any number by default. a multipath client
+    /// needs one for each path
+    #[argh(option)]
+    max_connections_per_identity: Option<usize>,
+
+    /// what a connection past --max-connections-per-identity does: "close_oldest" (the default) takes over from the oldest,
+    /// which then stops. "reject_newest" refuses it, and the client tries again later
+    #[argh(option)]
+    identity_limit_policy: Option<IdentityLimitPolicy>,
+
+    /// close user streams after this long (like "5m") with no bytes in either direction
+    #[argh(option, from_str_fn(parse_interval))]
+    stream_idle_timeout: Option<Duration>,
+
+    /// close user streams this long after they start (like "12h"), even busy ones. a blunt guard against leaked connections
+    #[argh(option, from_str_fn(parse_interval))]
+    stream_max_lifetime: Option<Duration>,
+
+    /// how often to ping tunnel clients on the control stream (like "10s", the default)
+    #[argh(option, from_str_fn(parse_interval))]
+    heartbeat_interval: Option<Duration>,
+
+    /// close the connection when tunnel clients hasn't pinged or answered for this long (like "30s", the default)
+    #[argh(option, from_str_fn(parse_interval))]
+    heartbeat_timeout: Option<Duration>,
+
+    /// on SIGUSR2, start a new copy of this binary with the same arguments and hand it the listening sockets. this process stops once its streams finish
+    #[argh(switch)]
+    upgrade: bool,
+
+    /// with --upgrade, how long to wait for streams to finish before closing them (like "10m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_interval))]
+    upgrade_drain_timeout: Option<Duration>,
+
+    /// answer mDNS queries on the LAN for this server, so clients with --discover find it wherever it is. other mDNS responders keep working
+    #[argh(switch)]
+    advertise: bool,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// hexdump the plaintext of streams into files in this directory, both directions in order. only a few new streams a
+    /// second are captured.
+    ///
+    /// The files hold users' data in the clear. Only use this for debugging!
+    #[argh(option)]
+    debug_dump: Option<PathBuf>,
+
+    /// how much of each stream --debug-dump captures (like "64K"). 1M by default
+    #[argh(option, from_str_fn(parse_bytes))]
+    debug_dump_max_bytes: Option<u64>,
+
+    /// don't accept 0-RTT data from clients or send 0.5-RTT data.
+    ///
+    /// Early data can be replayed by an attacker. Use this for replay-sensitive workloads.
+    #[argh(switch)]
+    no_0rtt: bool,
+
+    /// how long a client has to answer the server's retry (like "15s", the default). every new client gets one, so spoofed
+    /// handshakes can't make the server send much. raise it for clients with a very long round trip
+    #[argh(option, from_str_fn(parse_interval))]
+    retry_token_lifetime: Option<Duration>,
 }
 
 impl ReverseProxyServerSubCommand {
-    pub async fn main(self) -> anyhow::Result<()> {
-        if self.tcp_listen.is_none() && self.unix_listen.is_none() {
-            anyhow::bail!("specify tcp_listen or socket_listen or both");
+    fn transport_options(&self, keep_alive: bool) -> TransportOptions {
+        transport_args!(self; windows, streams, shims, no_migration).options(keep_alive)
+    }
+
+    fn debug_dump_options(&self) -> anyhow::Result<Option<DumpOptions>> {
+        match (&self.debug_dump, self.debug_dump_max_bytes) {
+            (Some(dir), max_bytes) => Ok(Some(DumpOptions {
+                max_bytes: max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+                ..DumpOptions::new(dir.clone())
+            })),
+            (None, Some(_)) => anyhow::bail!("debug_dump_max_bytes needs debug_dump"),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn heartbeat_options(&self) -> HeartbeatOptions {
+        let x = HeartbeatOptions::default();
+
+        HeartbeatOptions {
+            interval: self.heartbeat_interval.unwrap_or(x.interval),
+            timeout: self.heartbeat_timeout.unwrap_or(x.timeout),
+        }
+    }
+
+    fn padding_options(&self) -> anyhow::Result<PaddingOptions> {
+        let mut x = PaddingOptions {
+            mode: self.padding,
+            ..Default::default()
+        };
+
+        match self.padding_rate {
+            Some(0) => anyhow::bail!("padding_rate must be more than 0"),
+            Some(rate) => x.rate = rate,
+            None => {}
+        }
+
+        Ok(x)
+    }
+
+    fn stats_options(&self) -> StatsOptions {
+        StatsOptions {
+            interval: self.stats_interval,
+            output: self.stats_output.clone(),
+        }
+    }
+
+    fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: self.tcp_nodelay,
+            keepalive: self.tcp_keepalive,
+            keepalive_interval: self.tcp_keepalive_interval,
+            keepalive_probes: self.tcp_keepalive_probes,
+            recv_buffer: self.tcp_recv_buffer,
+            send_buffer: self.tcp_send_buffer,
+            backlog: self.tcp_backlog,
+            bind: Default::default(),
+        }
+    }
+
+    fn unix_options(&self) -> UnixSocketOptions {
+        UnixSocketOptions {
+            mode: self.unix_mode,
+            owner: self.unix_owner.clone(),
+            group: self.unix_group.clone(),
         }
+    }
 
-        let (stream_sender, stream_receiver) = flume::unbounded::<Stream>();
+    fn retry_options(&self) -> RetryOptions {
+        let x = RetryOptions::default();
+
+        RetryOptions {
+            token_lifetime: self.retry_token_lifetime.unwrap_or(x.token_lifetime),
+            ..x
+        }
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            early_data: !self.no_0rtt,
+        }
+    }
+
+    pub async fn main(self) -> anyhow::Result<()> {
+        if self.tcp_listen.is_none()
+            && self.unix_listen.is_none()
+            && self.pipe_listen.is_none()
+            && self.vsock_listen.is_none()
+            && !self.stdio
+        {
+            anyhow::bail!(
+                "specify at least one of tcp_listen, unix_listen, pipe_listen, vsock_listen, or stdio"
+            );
+        }
 
         let ca = PathBuf::new().join(format!("{}_ca.pem", self.cert_name));
         let cert = PathBuf::new().join(format!("{}_server.pem", self.cert_name));
         let key = PathBuf::new().join(format!("{}_server.key.pem", self.cert_name));
 
-        let endpoint = build_server_endpoint(
-            ca,
-            cert,
-            key,
-            true,
-            self.quic_addr,
-            self.congestion_mode,
-            false,
-        )?;
-
-        info!("QUIC listening on {}", endpoint.local_addr()?);
-
-        let counts = TunnelCounters::new();
-
-        // the tunnel handle listens on quic and forwards messages from a channel for tcp
-        // TODO: better name
-        let mut quic_endpoint_handle = {
-            let endpoint = endpoint.clone();
-            let stream_receiver = stream_receiver.clone();
-            let compression_mode = self.compress;
-
-            let f = async move {
-                while let Some(conn) = endpoint.accept().await {
-                    let f = handle_quic_connection(conn, stream_receiver.clone(), compression_mode);
-
-                    // spawn to handle multiple connections at once? we only have one listener right now
-                    tokio::spawn(f.inspect_err(|err| trace!(?err, "reverse proxy tunnel closed")));
-                }
-            };
-
-            // this handle isn't needed. errors are logged elsewhere
-            tokio::spawn(f)
-        };
+        let mut builder = ReverseProxyServer::builder(ca, cert, key, self.quic_addr)
+            .transport(self.transport_options(false))
+            .tls(self.tls_options())
+            .retry(self.retry_options())
+            .tcp(self.tcp_options())
+            .unix(self.unix_options())
+            .quic_sockets(self.quic_sockets)
+            .compress(self.compress)
+            .close_mode(self.close_mode)
+            .padding(self.padding_options()?)
+            .stream_pool_size(self.stream_pool_size)
+            .reject_without_clients(self.reject_without_clients)
+            .error_hints(self.error_hints)
+            .warm_up(self.warm_up.unwrap_or_default())
+            .warm_up_streams(self.warm_up_streams)
+            .stats(self.stats_options());
+
+        for x in self.quic_listen.iter() {
+            builder = builder.quic_listen(*x);
+        }
 
-        // listens on tcp and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut tcp_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(listen_addr) = self.tcp_listen {
-                let stream_sender = stream_sender.clone();
-
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    let tcp_listener = TcpListener::bind(listen_addr).await?;
-                    info!("TCP listening on {}", tcp_listener.local_addr()?);
-
-                    loop {
-                        match tcp_listener.accept().await {
-                            Ok((stream, _)) => {
-                                // send the stream to a channel. one of multiple connections might handle it
-                                stream_sender.send_async(Stream::Tcp(stream)).await?
-                            }
-                            Err(err) => error!(?err, "tcp accept failed"),
-                        }
-                    }
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
-
-        // listens on udp and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut udp_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(listen_addr) = self.udp_listen {
-                // let stream_sender = stream_sender.clone();
-
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    let udp_socket = UdpSocket::bind(listen_addr).await?;
-                    info!("UDP listening on {}", udp_socket.local_addr()?);
-
-                    todo!("do we actually care about tunneling udp?");
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
-
-        // listens on unix socket and forward all connections through a channel. any clients connected over quic will read the channel and handle the stream
-        let mut unix_listener_handle: tokio::task::JoinHandle<Result<(), anyhow::Error>> =
-            if let Some(unix_listen_path) = self.unix_listen {
-                let f = async move {
-                    // TODO: wait until at least one client has connected to the quic endpoint?
-
-                    info!("UNIX listening at {}", unix_listen_path.display());
-                    let listener = UnixListener::bind(unix_listen_path)?;
-
-                    loop {
-                        match listener.accept().await {
-                            Ok((stream, _)) => {
-                                // send the stream to a channel. one of multiple connections might handle it
-                                stream_sender.send_async(Stream::Unix(stream)).await?
-                            }
-                            Err(err) => error!(?err, "tcp accept failed"),
-                        }
-                    }
-                };
-
-                tokio::spawn(f.inspect_err(|err| trace!(?err, "tcp listener proxy closed")))
-            } else {
-                let f = std::future::pending::<anyhow::Result<()>>();
-
-                tokio::spawn(f)
-            };
-
-        let mut stats_handle = counts.spawn_stats_loop();
-
-        select! {
-            x = &mut quic_endpoint_handle => {
-                info!(?x, "tunnel task finished");
-            }
-            x = &mut tcp_listener_handle => {
-                info!(?x, "tcp task finished");
-            }
-            x = &mut udp_listener_handle => {
-                info!(?x, "udp task finished");
+        if let Some(x) = &self.admin_socket {
+            builder = builder.admin_socket(x.clone());
+        }
+
+        if let Some(x) = self.health_listen {
+            builder = builder.health_listen(x);
+        }
+
+        if let Some(x) = &self.usage_file {
+            builder = builder.usage_file(x.clone());
+        }
+
+        if let Some(x) = &self.audit_log {
+            builder = builder.audit_log(x.clone());
+        }
+
+        if let Some(x) = self.debug_dump_options()? {
+            builder = builder.debug_dump(x);
+        }
+
+        for x in self.webhook.iter() {
+            builder = builder.webhook(WebhookConfig::new(x.clone()));
+        }
+
+        builder = builder.quota(QuotaOptions {
+            monthly: self.quota_monthly,
+            per_connection: self.quota_per_connection,
+            disconnect: self.quota_disconnect,
+            ..Default::default()
+        });
+
+        if let Some(x) = self.per_client_rate {
+            builder = builder.per_client_rate(x);
+        }
+
+        if let Some(x) = self.per_client_burst {
+            builder = builder.per_client_burst(x);
+        }
+
+        if let Some(x) = self.max_connection_rate {
+            builder = builder.max_connection_rate(x);
+        }
+
+        if let Some(x) = self.max_connection_rate_per_ip {
+            builder = builder.max_connection_rate_per_ip(x);
+        }
+
+        if let Some(x) = self.max_connections {
+            builder = builder.max_connections(x);
+        }
+
+        if let Some(x) = self.max_streams_per_client {
+            builder = builder.max_streams_per_client(x);
+        }
+
+        match (
+            self.max_connections_per_identity,
+            self.identity_limit_policy,
+        ) {
+            (Some(max), policy) => {
+                builder = builder.identity_limit(IdentityLimit {
+                    max,
+                    policy: policy.unwrap_or_default(),
+                })
             }
-            x = &mut unix_listener_handle => {
-                info!(?x, "unix task finished");
+            (None, Some(_)) => {
+                anyhow::bail!("identity_limit_policy needs max_connections_per_identity")
             }
-            x = &mut stats_handle => {
-                info!(?x, "stats task finished");
+            (None, None) => {}
+        }
+
+        if let Some(x) = self.stream_idle_timeout {
+            builder = builder.stream_idle_timeout(x);
+        }
+
+        if let Some(x) = self.stream_max_lifetime {
+            builder = builder.stream_max_lifetime(x);
+        }
+
+        builder = builder.heartbeat(self.heartbeat_options());
+
+        if let Some(x) = self.tcp_fallback_listen {
+            builder = builder.tcp_fallback(x);
+        }
+
+        if let Some(x) = &self.udp_forward {
+            builder = builder.forward(x.clone());
+        }
+
+        builder = builder.connect(ConnectOptions {
+            allow: self.connect_allow.clone(),
+        });
+
+        builder = builder.rendezvous(self.rendezvous);
+
+        builder = builder.hop(HopOptions {
+            allow: self.hop_allow.clone(),
+        });
+
+        match (self.webtransport_listen, &self.webtransport_cert) {
+            (Some(listen), _) => {
+                builder = builder.webtransport(WebTransportOptions {
+                    listen,
+                    cert: self.webtransport_cert.clone(),
+                    key: self.webtransport_key.clone(),
+                })
             }
+            (None, Some(_)) => anyhow::bail!("webtransport_cert needs webtransport_listen"),
+            (None, None) => {}
         }
 
-        endpoint.close(0u32.into(), b"server done");
+        match (self.upgrade, self.upgrade_drain_timeout) {
+            (true, x) => builder = builder.upgrade(x.unwrap_or(upgrade::DEFAULT_DRAIN_TIMEOUT)),
+            (false, Some(_)) => anyhow::bail!("upgrade_drain_timeout needs upgrade"),
+            (false, None) => {}
+        }
 
-        quic_endpoint_handle.abort();
-        tcp_listener_handle.abort();
-        udp_listener_handle.abort();
-        unix_listener_handle.abort();
-        stats_handle.abort();
+        if let Some(x) = self.tcp_listen {
+            let mut transform = TransformPipeline::from_names(&self.tcp_transform)?;
+
+            match (&self.tcp_tls_cert, &self.tcp_tls_key) {
+                (Some(cert), Some(key)) => transform.push_front(Arc::new(TlsTerminator::from_pem(
+                    cert.clone(),
+                    key.clone(),
+                    self.keylog.clone(),
+                )?)),
+                (None, None) => {}
+                _ => anyhow::bail!(
+                    "tcp_tls_cert needs tcp_tls_key, and tcp_tls_key needs tcp_tls_cert"
+                ),
+            }
 
-        Ok(())
-    }
-}
+            builder = builder.listener(ListenerConfig {
+                target: ListenTarget::Tcp(x),
+                route: "tcp".to_string(),
+                transform,
+                allow: self.tcp_allow.clone(),
+                compress: None,
+                sni: self.tcp_sni.clone(),
+                http: self.tcp_http.clone(),
+                max_connections: self.tcp_max_connections,
+                priority: 0,
+            });
+        }
 
-async fn handle_quic_connection(
-    conn_a: Connecting,
-    rx_b: Receiver<Stream>,
-    compress_algo: CompressAlgo,
-) -> anyhow::Result<()> {
-    // TODO: are there other things I need to do to set up 0-rtt? this is copypasta
-    let conn_a = match conn_a.into_0rtt() {
-        Ok((conn_a, _)) => {
-            trace!("0-rtt accepted");
-            conn_a
+        if let Some(x) = self.udp_listen {
+            builder = builder.listen(ListenTarget::Udp(x), "udp", Default::default());
         }
-        Err(conn_a) => timeout(Duration::from_secs(30), conn_a).await??,
-    };
 
-    // TODO: look at the handshake data to figure out what client connected? that way we know what TcpListener to connect it to?
+        if let Some(x) = self.unix_listen {
+            let transform = TransformPipeline::from_names(&self.unix_transform)?;
 
-    loop {
-        while let Ok(stream_b) = rx_b.recv_async().await {
-            debug!(?stream_b, "user connected");
+            builder = builder.listen(ListenTarget::Unix(x), "unix", transform);
+        }
 
-            // each new TCP stream gets a new QUIC stream
-            let (tx_a, rx_a) = conn_a.open_bi().await?;
+        if let Some(x) = self.pipe_listen {
+            let transform = TransformPipeline::from_names(&self.pipe_transform)?;
 
-            trace!("reverse proxy stream opened");
+            builder = builder.listen(ListenTarget::NamedPipe(x), "pipe", transform);
+        }
 
-            // TODO: counters while the stream happens
-            let f = copy_bidirectional_with_compression(compress_algo, rx_a, tx_a, stream_b);
+        if let Some(x) = self.vsock_listen {
+            let transform = TransformPipeline::from_names(&self.vsock_transform)?;
 
-            // spawn to handle multiple requests at once
-            tokio::spawn(
-                f.inspect_err(|e| {
-                    error!("failed: {}", e);
-                })
-                .inspect_ok(|(a_to_b, b_to_a)| trace!(%a_to_b, %b_to_a, "success")),
-            );
+            builder = builder.listen(ListenTarget::Vsock(x), "vsock", transform);
+        }
+
+        if self.stdio {
+            builder = builder.listen(ListenTarget::Stdio, "stdio", Default::default());
+        }
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        if self.advertise {
+            advertise(self.quic_addr.port(), shutdown.clone());
         }
+
+        let mut server = builder.shutdown_token(shutdown).start().await?;
+
+        let mut signals = ServerSignals::new(&server);
+
+        let x = loop {
+            tokio::select! {
+                x = server.wait() => break x,
+                x = signals.recv() => x.apply(&server).await,
+            }
+        };
+
+        server.shutdown().await;
+
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> ReverseProxyServerSubCommand {
+        let args = [&["first", "127.0.0.1:4433"], args].concat();
+
+        ReverseProxyServerSubCommand::from_args(&["reverse_proxy_server"], &args).unwrap()
+    }
+
+    #[test]
+    fn transport_flags() {
+        assert_eq!(
+            parse(&[]).transport_options(false),
+            TransportOptions::default()
+        );
+
+        let x = parse(&[
+            "--congestion-mode",
+            "cubic",
+            "--quic-keep-alive",
+            "15s",
+            "--receive-window",
+            "1000",
+            "--max-concurrent-streams",
+            "7",
+            "--no-gso",
+            "--max-mtu",
+            "1400",
+            "--no-migration",
+            "--obfuscate-key",
+            "key",
+        ])
+        .transport_options(true);
+
+        assert_eq!(
+            x,
+            TransportOptions {
+                congestion_mode: CongestionMode::Cubic,
+                keep_alive: true,
+                keep_alive_interval: Some(Duration::from_secs(15)),
+                receive_window: Some(1000),
+                max_concurrent_bidi_streams: Some(7),
+                gso: Some(false),
+                max_mtu: Some(1400),
+                migration: Some(false),
+                obfuscation: crate::subcommands::obfuscation(Some(&"key".to_string())),
+                ..Default::default()
+            }
+        );
     }
 }
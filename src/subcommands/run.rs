@@ -0,0 +1,205 @@
+use crate::subcommands::ServerSignals;
+use argh::FromArgs;
+use quic_tunnel::config::Config;
+use quic_tunnel::server::ReverseProxyServerHandle;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// how often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Run the server and/or client described by a config file.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "run")]
+pub struct RunSubCommand {
+    /// the TOML config file. check it with the `check` subcommand
+    #[argh(option)]
+    config: PathBuf,
+}
+
+impl RunSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        let stop = CancellationToken::new();
+        cancel_on_signal(stop.clone());
+
+        run_config(&self.config, stop, None).await
+    }
+}
+
+/// run everything in the config file until one of them stops or `stop` is cancelled.
+/// with a `drain_timeout`, the server drains for up to that long after `stop` instead of closing every stream right away.
+/// the server's listeners follow the file as it changes. see `reload`
+pub async fn run_config(
+    path: &Path,
+    stop: CancellationToken,
+    drain_timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let (config, warnings) = match Config::load_valid(path) {
+        Ok(x) => x,
+        Err(err) => {
+            for x in err.issues() {
+                eprintln!("{x}");
+            }
+
+            return Err(err.into());
+        }
+    };
+
+    for x in warnings {
+        warn!("{}", x);
+    }
+
+    let mut modified = modified(path);
+    let restart_only = restart_only(path);
+
+    // with a drain timeout, `stop` only starts the drain. everything is shut down after
+    let shutdown = match drain_timeout {
+        Some(_) => CancellationToken::new(),
+        None => stop.clone(),
+    };
+
+    let mut server = match &config.server {
+        Some(x) => Some(
+            x.builder()?
+                .shutdown_token(shutdown.clone())
+                .start()
+                .await?,
+        ),
+        None => None,
+    };
+
+    let mut client = match &config.client {
+        Some(x) => Some(
+            x.builder()?
+                .shutdown_token(shutdown.clone())
+                .start()
+                .await?,
+        ),
+        None => None,
+    };
+
+    let mut watch = tokio::time::interval(WATCH_INTERVAL);
+
+    // without a server, the signals keep their default of stopping the process
+    let mut signals = server.as_ref().map(ServerSignals::new);
+
+    let x = loop {
+        // stop everything if either one stops
+        let wait = async {
+            match (&mut server, &mut client) {
+                (Some(server), Some(client)) => {
+                    tokio::select! {
+                        x = server.wait() => x,
+                        x = client.wait() => x,
+                    }
+                }
+                (Some(server), None) => server.wait().await,
+                (None, Some(client)) => client.wait().await,
+                (None, None) => Ok(()),
+            }
+        };
+
+        tokio::select! {
+            x = wait => break x,
+            _ = stop.cancelled(), if drain_timeout.is_some() => break Ok(()),
+            Some(x) = async { Some(signals.as_mut()?.recv().await) } => {
+                if let Some(server) = &server {
+                    x.apply(server).await;
+                }
+
+                continue;
+            }
+            _ = watch.tick() => {}
+        }
+
+        let x = self::modified(path);
+
+        if x != modified {
+            modified = x;
+
+            reload(path, server.as_mut(), restart_only.as_ref()).await;
+        }
+    };
+
+    if let Some(timeout) = drain_timeout.filter(|_| stop.is_cancelled()) {
+        if let Some(x) = server.take() {
+            info!(?timeout, "draining");
+
+            x.drain(timeout).await;
+        }
+    }
+
+    shutdown.cancel();
+
+    if let Some(x) = client {
+        x.shutdown().await;
+    }
+
+    if let Some(x) = server {
+        x.shutdown().await;
+    }
+
+    x
+}
+
+/// when and how big, so a change that keeps the time still counts
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    let x = std::fs::metadata(path).ok()?;
+
+    Some((x.modified().ok()?, x.len()))
+}
+
+/// the file without the server's listeners, which are all a reload changes
+fn restart_only(path: &Path) -> Option<toml::Value> {
+    let mut x: toml::Value = toml::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+
+    if let Some(server) = x.get_mut("server").and_then(|x| x.as_table_mut()) {
+        server.remove("listeners");
+    }
+
+    Some(x)
+}
+
+/// apply the file's listeners to `server`. a file with errors is logged and left for the next change, while everything
+/// keeps running as it was. `restart_only` is the rest of the file as it was at startup, to warn about changes to it
+async fn reload(
+    path: &Path,
+    server: Option<&mut ReverseProxyServerHandle>,
+    restart_only: Option<&toml::Value>,
+) {
+    info!("{} changed. reloading", path.display());
+
+    let config = match Config::load_valid(path) {
+        Ok((x, _)) => x,
+        Err(err) => {
+            for x in err.issues() {
+                warn!("{}", x);
+            }
+
+            warn!("not reloading. still running the config that was there before");
+            return;
+        }
+    };
+
+    if self::restart_only(path).as_ref() != restart_only {
+        warn!("only the server's listeners change without a restart. restart for the rest");
+    }
+
+    let (Some(server), Some(section)) = (server, &config.server) else {
+        return;
+    };
+
+    let x = match section.listener_configs() {
+        Ok(x) => server.reload(x).await,
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = x {
+        warn!(
+            ?err,
+            "not reloading. still running the listeners that were there before"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use argh::FromArgs;
+use quic_tunnel::config::Config;
+use quic_tunnel::service;
+use tracing::info;
+
+use super::parse_duration;
+use super::run::run_config;
+
+/// how long a stopping service lets streams finish. windows is told to expect about this long
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Install, remove, or run as a Windows service.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "service")]
+pub struct ServiceSubCommand {
+    #[argh(subcommand)]
+    action: ServiceAction,
+}
+
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand)]
+enum ServiceAction {
+    Install(InstallAction),
+    Uninstall(UninstallAction),
+    Run(RunAction),
+}
+
+/// Install a service that runs a config file at boot. Needs an administrator.
+///
+/// Options before `service`, like --log-file, are passed to the service too.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "install")]
+struct InstallAction {
+    /// the TOML config file to run
+    #[argh(option)]
+    config: PathBuf,
+
+    /// the service's name
+    #[argh(option, default = "service::DEFAULT_NAME.to_string()")]
+    name: String,
+
+    /// when the service is stopped, how long to let streams finish. 30s by default
+    #[argh(option, default = "DEFAULT_DRAIN_TIMEOUT", from_str_fn(parse_duration))]
+    drain_timeout: Duration,
+}
+
+/// Stop and remove the service. Needs an administrator.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "uninstall")]
+struct UninstallAction {
+    /// the service's name
+    #[argh(option, default = "service::DEFAULT_NAME.to_string()")]
+    name: String,
+}
+
+/// What the installed service runs. Windows starts this, not you.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "run")]
+struct RunAction {
+    /// the TOML config file to run
+    #[argh(option)]
+    config: PathBuf,
+
+    /// the service's name
+    #[argh(option, default = "service::DEFAULT_NAME.to_string()")]
+    name: String,
+
+    /// when the service is stopped, how long to let streams finish
+    #[argh(option, default = "DEFAULT_DRAIN_TIMEOUT", from_str_fn(parse_duration))]
+    drain_timeout: Duration,
+}
+
+impl ServiceSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        match self.action {
+            ServiceAction::Install(x) => x.main(),
+            ServiceAction::Uninstall(x) => {
+                service::uninstall(&x.name)
+                    .with_context(|| format!("unable to remove service {}", x.name))?;
+
+                info!(name = x.name, "service removed");
+
+                Ok(())
+            }
+            ServiceAction::Run(x) => x.main().await,
+        }
+    }
+}
+
+impl InstallAction {
+    fn main(self) -> anyhow::Result<()> {
+        // the service would only fail at boot, where nobody is looking
+        if let Err(err) = Config::load_valid(&self.config) {
+            for x in err.issues() {
+                eprintln!("{x}");
+            }
+
+            return Err(err.into());
+        }
+
+        // services start in the system directory
+        let config = std::fs::canonicalize(&self.config)
+            .with_context(|| format!("unable to find {}", self.config.display()))?;
+
+        let exe = std::env::current_exe().context("unable to find our own binary")?;
+
+        let mut command_line = vec![quote(&exe.to_string_lossy())];
+
+        command_line.extend(
+            std::env::args()
+                .skip(1)
+                .take_while(|x| x != "service")
+                .map(|x| quote(&x)),
+        );
+
+        command_line.extend([
+            "service".to_string(),
+            "run".to_string(),
+            "--config".to_string(),
+            quote(&config.to_string_lossy()),
+            "--name".to_string(),
+            quote(&self.name),
+            "--drain-timeout".to_string(),
+            humantime::format_duration(self.drain_timeout).to_string(),
+        ]);
+
+        let command_line = command_line.join(" ");
+
+        service::install(&self.name, &command_line)
+            .with_context(|| format!("unable to install service {}", self.name))?;
+
+        info!(
+            name = self.name,
+            command_line, "service installed. start it with `sc start`"
+        );
+
+        Ok(())
+    }
+}
+
+impl RunAction {
+    async fn main(self) -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Handle::current();
+
+        let Self {
+            config,
+            name,
+            drain_timeout,
+        } = self;
+
+        // the service control manager keeps this thread until the service stops, and runs the service on another one
+        tokio::task::spawn_blocking(move || {
+            service::dispatch(&name, move |stop| {
+                runtime.block_on(run_config(&config, stop, Some(drain_timeout)))
+            })
+        })
+        .await?
+        .context(
+            "unable to reach the service control manager. only windows should start `service run`",
+        )
+    }
+}
+
+/// windows splits a service's command line itself, so anything with spaces needs quotes
+fn quote(x: &str) -> String {
+    format!("\"{}\"", x.replace('"', "\\\""))
+}
@@ -0,0 +1,173 @@
+use argh::FromArgs;
+use quic_tunnel::admin::{AdminClient, AdminRequest, AdminStatus};
+use quic_tunnel::registry::{ClientInfo, StreamInfo};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+
+use super::{human_bytes, human_rate};
+
+/// Watch a running server's clients and streams, like `top`.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "top")]
+pub struct TopSubCommand {
+    /// the server's `admin_socket`
+    #[argh(positional)]
+    admin_socket: PathBuf,
+
+    /// how many of the busiest streams to show
+    #[argh(option, default = "20")]
+    max_streams: usize,
+}
+
+/// what we saw last time so we can show rates
+#[derive(Default)]
+struct Previous {
+    at: Option<Instant>,
+    clients: HashMap<u64, (u64, u64)>,
+    streams: HashMap<u64, u64>,
+}
+
+impl TopSubCommand {
+    pub async fn main(self) -> anyhow::Result<()> {
+        let mut admin = AdminClient::connect(&self.admin_socket).await?;
+
+        let mut previous = Previous::default();
+
+        let mut i = interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                _ = i.tick() => {}
+                _ = tokio::signal::ctrl_c() => break,
+            }
+
+            let status: AdminStatus = admin.request(&AdminRequest::Status).await?;
+            let clients: Vec<ClientInfo> = admin.request(&AdminRequest::Clients).await?;
+            let streams: Vec<StreamInfo> = admin.request(&AdminRequest::Streams).await?;
+
+            let screen = self.render(&status, &clients, streams, &mut previous);
+
+            // clear the screen and go home. then draw
+            print!("\x1b[2J\x1b[H{screen}");
+        }
+
+        Ok(())
+    }
+
+    fn render(
+        &self,
+        status: &AdminStatus,
+        clients: &[ClientInfo],
+        mut streams: Vec<StreamInfo>,
+        previous: &mut Previous,
+    ) -> String {
+        let now = Instant::now();
+        let secs = previous
+            .at
+            .map(|x| now.duration_since(x).as_secs_f64())
+            .unwrap_or(1.0)
+            .max(0.001);
+
+        let mut x = String::new();
+
+        let _ = writeln!(
+            x,
+            "quic-tunnel  up {}  clients {}  streams {}\n",
+            humantime::format_duration(Duration::from_secs(status.uptime_secs)),
+            status.clients,
+            status.streams,
+        );
+
+        let _ = writeln!(
+            x,
+            "{:>6} {:<24} {:>8} {:>8} {:>7} {:>12} {:>12}",
+            "ID", "REMOTE", "STREAMS", "RTT", "LOSS", "TX/s", "RX/s"
+        );
+
+        let mut next_clients = HashMap::new();
+
+        for c in clients {
+            let (tx, rx) = previous
+                .clients
+                .get(&c.id)
+                .copied()
+                .unwrap_or((c.udp_tx_bytes, c.udp_rx_bytes));
+
+            let loss = if c.sent_packets == 0 {
+                0.0
+            } else {
+                100.0 * c.lost_packets as f64 / c.sent_packets as f64
+            };
+
+            let _ = writeln!(
+                x,
+                "{:>6} {:<24} {:>8} {:>6}ms {:>6.2}% {:>12} {:>12}",
+                c.id,
+                c.remote_addr.to_string(),
+                c.streams,
+                c.rtt_ms,
+                loss,
+                human_rate(c.udp_tx_bytes.saturating_sub(tx), secs),
+                human_rate(c.udp_rx_bytes.saturating_sub(rx), secs),
+            );
+
+            next_clients.insert(c.id, (c.udp_tx_bytes, c.udp_rx_bytes));
+        }
+
+        let mut next_streams = HashMap::new();
+
+        let mut rates: Vec<_> = streams
+            .drain(..)
+            .map(|s| {
+                let total = s.bytes_from_tunnel + s.bytes_to_tunnel;
+                let before = previous.streams.get(&s.id).copied().unwrap_or(total);
+
+                next_streams.insert(s.id, total);
+
+                (total.saturating_sub(before), s)
+            })
+            .collect();
+
+        // busiest first
+        rates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.id.cmp(&b.1.id)));
+
+        let _ = writeln!(
+            x,
+            "\n{:>6} {:>6} {:<12} {:<24} {:>8} {:>12} {:>12} {:>12}",
+            "ID", "CLIENT", "ROUTE", "PEER", "AGE", "FROM TUN", "TO TUN", "RATE"
+        );
+
+        for (rate, s) in rates.iter().take(self.max_streams) {
+            let peer = s
+                .peer_addr
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "-".to_string());
+
+            let _ = writeln!(
+                x,
+                "{:>6} {:>6} {:<12} {:<24} {:>7}s {:>12} {:>12} {:>12}",
+                s.id,
+                s.client_id,
+                s.route,
+                peer,
+                s.age_secs,
+                human_bytes(s.bytes_from_tunnel),
+                human_bytes(s.bytes_to_tunnel),
+                human_rate(*rate, secs),
+            );
+        }
+
+        if rates.len() > self.max_streams {
+            let _ = writeln!(x, "... and {} more", rates.len() - self.max_streams);
+        }
+
+        previous.at = Some(now);
+        previous.clients = next_clients;
+        previous.streams = next_streams;
+
+        x
+    }
+}
@@ -0,0 +1,322 @@
+use crate::subcommands::{advertise, parse_duration, parse_interval, transport_args};
+use argh::FromArgs;
+use futures::TryFutureExt;
+use ipnet::Ipv4Net;
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::control::answer_pings;
+use quic_tunnel::counters::{StatsOptions, StatsOutput, TunnelCounters};
+use quic_tunnel::listen::{check_listen_targets, ListenTarget};
+use quic_tunnel::protocol::{CloseCode, Role};
+use quic_tunnel::quic::{build_server_endpoint, CongestionMode, RetryOptions, TransportOptions};
+use quic_tunnel::runtime;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken, TaskTracker};
+use quic_tunnel::tls::TlsOptions;
+use quic_tunnel::vpn::{AddressPool, TunOptions, VpnServer, DEFAULT_MTU};
+use quinn::Connecting;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::timeout;
+use tracing::{info, trace};
+
+/// Run the QUIC Tunnel Server as a VPN. Needs the tun feature.
+///
+/// Each tun_client gets an address from the pool, and IP packets between them and this host's TUN device go in QUIC datagrams.
+#[derive(Debug, FromArgs, PartialEq)]
+#[argh(subcommand, name = "tun_server")]
+pub struct TunServerSubCommand {
+    /// prefix for all the certificates to load
+    #[argh(positional)]
+    cert_name: String,
+
+    /// the local address to listen on with QUIC. Clients connect here
+    #[argh(positional)]
+    local_addr: SocketAddr,
+
+    /// the addresses to give out. the server takes the first one and each client gets the next free one
+    #[argh(option, default = "\"10.8.0.0/24\".parse().unwrap()")]
+    pool: Ipv4Net,
+
+    /// the name of the TUN device, like qt0. the OS picks one if not set
+    #[argh(option)]
+    tun_name: Option<String>,
+
+    /// the TUN device's MTU. bigger packets than fit in a QUIC datagram are dropped
+    #[argh(option, default = "DEFAULT_MTU")]
+    mtu: u16,
+
+    /// congestion mode for QUIC
+    #[argh(option, default = "Default::default()")]
+    congestion_mode: CongestionMode,
+
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives (like "15s"). clients send them, so a server only does with this set
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// max bytes the peer may send across all QUIC streams before waiting for us to read
+    #[argh(option)]
+    receive_window: Option<u32>,
+
+    /// max bytes to buffer for sending across all QUIC streams
+    #[argh(option)]
+    send_window: Option<u64>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// XOR every packet with this key and pad it to a random size, for networks that throttle QUIC. the other side needs the same key.
+    /// this hides QUIC from simple filters. it is not encryption
+    #[argh(option)]
+    obfuscate_key: Option<String>,
+
+    /// for testing: drop and delay the packets we send, like "drop=1%,delay=20ms". needs the chaos feature
+    #[argh(option)]
+    chaos: Option<ChaosOptions>,
+
+    /// close connections from clients whose address changes instead of following them to the new one
+    #[argh(switch)]
+    no_migration: bool,
+
+    /// how long a client has to answer the server's retry (like "15s", the default). raise it for clients with a very long
+    /// round trip
+    #[argh(option, from_str_fn(parse_interval))]
+    retry_token_lifetime: Option<Duration>,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// don't accept 0-RTT data from clients or send 0.5-RTT data.
+    ///
+    /// Early data can be replayed by an attacker. Use this for replay-sensitive workloads.
+    #[argh(switch)]
+    no_0rtt: bool,
+
+    /// answer mDNS queries on the LAN for this server, so clients with --discover find it wherever it is. other mDNS responders keep working
+    #[argh(switch)]
+    advertise: bool,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
+}
+
+impl TunServerSubCommand {
+    fn retry_options(&self) -> RetryOptions {
+        let x = RetryOptions::default();
+
+        RetryOptions {
+            token_lifetime: self.retry_token_lifetime.unwrap_or(x.token_lifetime),
+            ..x
+        }
+    }
+
+    fn transport_options(&self) -> TransportOptions {
+        transport_args!(self; windows, shims, no_migration).options(false)
+    }
+
+    fn tun_options(&self) -> TunOptions {
+        TunOptions {
+            name: self.tun_name.clone(),
+            mtu: self.mtu,
+        }
+    }
+
+    fn stats_options(&self) -> StatsOptions {
+        StatsOptions {
+            interval: self.stats_interval,
+            output: self.stats_output.clone(),
+        }
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            early_data: !self.no_0rtt,
+        }
+    }
+
+    pub async fn main(self) -> anyhow::Result<()> {
+        let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
+        let cert = PathBuf::from(format!("{}_server.pem", self.cert_name));
+        let key = PathBuf::from(format!("{}_server.key.pem", self.cert_name));
+
+        check_listen_targets(&[ListenTarget::Udp(self.local_addr)])?;
+
+        let pool = AddressPool::new(self.pool)?;
+
+        let counts = TunnelCounters::new();
+
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        let data_plane = runtime::data_plane();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let (endpoint, vpn, mut device_handle) = {
+            let _guard = data_plane.enter();
+
+            let endpoint = build_server_endpoint(
+                ca,
+                cert,
+                key,
+                &self.retry_options(),
+                self.local_addr,
+                &self.transport_options(),
+                &self.tls_options(),
+                &[Role::Tun, Role::Probe],
+            )?;
+
+            let (vpn, handle) =
+                VpnServer::start(pool, &self.tun_options(), counts.clone(), shutdown.clone())?;
+
+            (endpoint, vpn, handle)
+        };
+
+        info!(
+            "QUIC listening on {} for tun clients",
+            endpoint.local_addr()?
+        );
+
+        if self.advertise {
+            advertise(endpoint.local_addr()?.port(), shutdown.clone());
+        }
+
+        // every connection, so the endpoint is only closed once they have closed themselves
+        let tracker = TaskTracker::new();
+
+        let mut tunnel_handle = {
+            let endpoint = endpoint.clone();
+            let shutdown = shutdown.clone();
+            let tracker = tracker.clone();
+
+            data_plane.spawn(async move {
+                loop {
+                    let conn = select! {
+                        x = endpoint.accept() => x,
+                        _ = shutdown.cancelled() => break,
+                    };
+
+                    let Some(conn) = conn else {
+                        break;
+                    };
+
+                    let f = handle_connection(conn, vpn.clone(), shutdown.clone());
+
+                    // spawn to handle multiple connections at once
+                    tracker.spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
+                }
+            })
+        };
+
+        let mut stats_handle = counts.spawn_stats_loop(self.stats_options(), shutdown.clone());
+
+        // a finished JoinHandle panics if it is polled again, so only the others are awaited below
+        let mut tunnel_finished = false;
+        let mut device_finished = false;
+        let mut stats_finished = false;
+
+        select! {
+            x = &mut tunnel_handle => {
+                info!(?x, "tunnel task finished");
+                tunnel_finished = true;
+            }
+            x = &mut device_handle => {
+                info!(?x, "tun device task finished");
+                device_finished = true;
+            }
+            x = &mut stats_handle => {
+                info!(?x, "stats task finished");
+                stats_finished = true;
+            }
+        }
+
+        shutdown.cancel();
+
+        if !tunnel_finished {
+            let _ = tunnel_handle.await;
+        }
+        if !device_finished {
+            let _ = device_handle.await;
+        }
+        if !stats_finished {
+            let _ = stats_handle.await;
+        }
+
+        tracker.close();
+        tracker.wait().await;
+
+        endpoint.close(CloseCode::Done.into(), b"server done");
+
+        Ok(())
+    }
+}
+
+async fn handle_connection(
+    conn_a: Connecting,
+    vpn: Arc<VpnServer>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let conn_a = match conn_a.into_0rtt() {
+        Ok((conn_a, _)) => {
+            trace!("0-rtt accepted");
+            conn_a
+        }
+        Err(conn_a) => select! {
+            x = timeout(Duration::from_secs(30), conn_a) => x??,
+            _ = shutdown.cancelled() => return Ok(()),
+        },
+    };
+
+    // the datagrams end once the connection is closed
+    let closed = async {
+        shutdown.cancelled().await;
+
+        conn_a.close(CloseCode::Done.into(), b"server done");
+    };
+
+    if let Some(Role::Probe) = Role::negotiated(&conn_a) {
+        return select! {
+            x = answer_pings(&conn_a) => Ok(x?),
+            _ = closed => Ok(()),
+        };
+    }
+
+    select! {
+        x = vpn.serve_connection(&conn_a) => x,
+        _ = closed => Ok(()),
+    }
+}
@@ -1,17 +1,24 @@
+use crate::subcommands::{advertise, parse_duration, parse_interval, transport_args};
 use argh::FromArgs;
 use futures::TryFutureExt;
-use quic_tunnel::counters::TunnelCounters;
-use quic_tunnel::quic::{build_server_endpoint, matching_bind_address, CongestionMode};
+use quic_tunnel::chaos::ChaosOptions;
+use quic_tunnel::control::answer_pings;
+use quic_tunnel::counters::{StatsOptions, StatsOutput, TunnelCounters};
+use quic_tunnel::datagram::{forward_datagrams, forward_streams, DatagramTarget};
+use quic_tunnel::listen::{check_listen_targets, ListenTarget};
+use quic_tunnel::protocol::{CloseCode, Role};
+use quic_tunnel::quic::{build_server_endpoint, CongestionMode, RetryOptions, TransportOptions};
+use quic_tunnel::runtime;
+use quic_tunnel::shutdown::{cancel_on_signal, CancellationToken, TaskTracker};
+use quic_tunnel::tls::TlsOptions;
 use quinn::Connecting;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::AsyncReadExt;
-use tokio::net::UdpSocket;
 use tokio::select;
 use tokio::time::timeout;
-use tracing::{debug, error, info, trace};
+use tracing::{info, trace};
 
 /// Run the QUIC Tunnel Server.
 ///
@@ -27,30 +34,157 @@ pub struct UdpServerSubCommand {
     #[argh(positional)]
     local_addr: SocketAddr,
 
-    /// the remote address to forward client data to
+    /// the remote address to forward client data to. a UDP address or unix:/path for a unix datagram socket. on linux, unix:@name is an abstract socket
     #[argh(positional)]
-    remote_addr: SocketAddr,
+    remote_addr: DatagramTarget,
 
     /// congestion mode for QUIC
     #[argh(option, default = "Default::default()")]
     congestion_mode: CongestionMode,
+
+    /// how long a QUIC connection can be idle before it is closed (like "30s" or "5m"). defaults to 5m
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_idle_timeout: Option<Duration>,
+
+    /// how often to send QUIC keep alives (like "15s"). clients send them, so a server only does with this set
+    #[argh(option, from_str_fn(parse_duration))]
+    quic_keep_alive: Option<Duration>,
+
+    /// max bytes the peer may send on one QUIC stream before waiting for us to read
+    #[argh(option)]
+    stream_receive_window: Option<u32>,
+
+    /// max bytes the peer may send across all QUIC streams before waiting for us to read
+    #[argh(option)]
+    receive_window: Option<u32>,
+
+    /// max bytes to buffer for sending across all QUIC streams
+    #[argh(option)]
+    send_window: Option<u64>,
+
+    /// max number of QUIC streams the peer may have open at once
+    #[argh(option)]
+    max_concurrent_streams: Option<u32>,
+
+    /// don't use UDP segmentation offload (GSO) when sending. some NICs and VPS kernels drop or mangle offloaded packets
+    #[argh(switch)]
+    no_gso: bool,
+
+    /// don't mark packets as ECN capable. for paths with middleboxes that drop or mangle marked packets
+    #[argh(switch)]
+    no_ecn: bool,
+
+    /// don't probe for a bigger path MTU once connected
+    #[argh(switch)]
+    no_mtu_discovery: bool,
+
+    /// the UDP payload size QUIC starts with, in bytes. at least 1200, which is the default
+    #[argh(option)]
+    initial_mtu: Option<u16>,
+
+    /// the biggest UDP payload MTU discovery tries, in bytes. defaults to 1452. lower it for PPPoE or a VPN underneath
+    #[argh(option)]
+    max_mtu: Option<u16>,
+
+    /// XOR every packet with this key and pad it to a random size, for networks that throttle QUIC. the other side needs the same key.
+    /// this hides QUIC from simple filters. it is not encryption
+    #[argh(option)]
+    obfuscate_key: Option<String>,
+
+    /// for testing: drop and delay the packets we send, like "drop=1%,delay=20ms". needs the chaos feature
+    #[argh(option)]
+    chaos: Option<ChaosOptions>,
+
+    /// close connections from clients whose address changes instead of following them to the new one
+    #[argh(switch)]
+    no_migration: bool,
+
+    /// how long a client has to answer the server's retry (like "15s", the default). raise it for clients with a very long
+    /// round trip
+    #[argh(option, from_str_fn(parse_interval))]
+    retry_token_lifetime: Option<Duration>,
+
+    /// write TLS secrets to this file so captured traffic can be decrypted in Wireshark. `SSLKEYLOGFILE` is also honored.
+    ///
+    /// Only use this for debugging!
+    #[argh(option)]
+    keylog: Option<PathBuf>,
+
+    /// don't accept 0-RTT data from clients or send 0.5-RTT data.
+    ///
+    /// Early data can be replayed by an attacker. Use this for replay-sensitive workloads.
+    #[argh(switch)]
+    no_0rtt: bool,
+
+    /// answer mDNS queries on the LAN for this server, so clients with --discover find it wherever it is. other mDNS responders keep working
+    #[argh(switch)]
+    advertise: bool,
+
+    /// how often to write the traffic counters (like "10s" or "1m"). nothing is written if they haven't changed
+    #[argh(
+        option,
+        default = "Duration::from_secs(10)",
+        from_str_fn(parse_interval)
+    )]
+    stats_interval: Duration,
+
+    /// where to write the traffic counters: stderr, off, or a file path for one JSON line per interval. files are rotated at 10 MiB
+    #[argh(option, default = "Default::default()")]
+    stats_output: StatsOutput,
 }
 
 impl UdpServerSubCommand {
+    fn retry_options(&self) -> RetryOptions {
+        let x = RetryOptions::default();
+
+        RetryOptions {
+            token_lifetime: self.retry_token_lifetime.unwrap_or(x.token_lifetime),
+            ..x
+        }
+    }
+
+    fn transport_options(&self, keep_alive: bool) -> TransportOptions {
+        transport_args!(self; windows, streams, shims, no_migration).options(keep_alive)
+    }
+
+    fn stats_options(&self) -> StatsOptions {
+        StatsOptions {
+            interval: self.stats_interval,
+            output: self.stats_output.clone(),
+        }
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            keylog: self.keylog.clone(),
+            early_data: !self.no_0rtt,
+        }
+    }
+
     pub async fn main(self) -> anyhow::Result<()> {
         let ca = PathBuf::from(format!("{}_ca.pem", self.cert_name));
         let cert = PathBuf::from(format!("{}_server.pem", self.cert_name));
         let key = PathBuf::from(format!("{}_server.key.pem", self.cert_name));
 
-        let endpoint = build_server_endpoint(
-            ca,
-            cert,
-            key,
-            true,
-            self.local_addr,
-            self.congestion_mode,
-            false,
-        )?;
+        check_listen_targets(&[ListenTarget::Udp(self.local_addr)])?;
+
+        let data_plane = runtime::data_plane();
+
+        // quinn's drivers are spawned on the runtime that is current when the endpoint is built
+        let endpoint = {
+            let _guard = data_plane.enter();
+
+            build_server_endpoint(
+                ca,
+                cert,
+                key,
+                &self.retry_options(),
+                self.local_addr,
+                &self.transport_options(false),
+                &self.tls_options(),
+                &[Role::Forward, Role::Probe],
+            )?
+        };
 
         info!(
             "QUIC listening on {} and forwarding to {}",
@@ -60,153 +194,117 @@ impl UdpServerSubCommand {
 
         let counts = TunnelCounters::new();
 
+        let shutdown = CancellationToken::new();
+        cancel_on_signal(shutdown.clone());
+
+        if self.advertise {
+            advertise(endpoint.local_addr()?.port(), shutdown.clone());
+        }
+
+        // every connection, so the endpoint is only closed once they have closed themselves
+        let tracker = TaskTracker::new();
+
         let mut tunnel_handle = {
             let endpoint = endpoint.clone();
-            let addr_b = self.remote_addr;
+            let addr_b = self.remote_addr.clone();
+            let shutdown = shutdown.clone();
+            let counts = counts.clone();
+            let tracker = tracker.clone();
 
-            tokio::spawn(async move {
-                while let Some(conn) = endpoint.accept().await {
-                    let f = handle_connection(conn, addr_b);
+            data_plane.spawn(async move {
+                loop {
+                    let conn = select! {
+                        x = endpoint.accept() => x,
+                        _ = shutdown.cancelled() => break,
+                    };
+
+                    let Some(conn) = conn else {
+                        break;
+                    };
+
+                    let f =
+                        handle_connection(conn, addr_b.clone(), counts.clone(), shutdown.clone());
 
                     // spawn to handle multiple connections at once
-                    tokio::spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
+                    tracker.spawn(f.inspect_err(|e| trace!("connection closed: {}", e)));
                 }
             })
         };
 
-        let mut stats_handle = counts.spawn_stats_loop();
+        let mut stats_handle = counts.spawn_stats_loop(self.stats_options(), shutdown.clone());
 
-        select! {
+        // a finished JoinHandle panics if it is polled again
+        let tunnel_finished = select! {
             x = &mut tunnel_handle => {
                 info!(?x, "tunnel task finished");
+                true
             }
             x = &mut stats_handle => {
                 info!(?x, "stats task finished");
+                false
             }
+        };
+
+        shutdown.cancel();
+
+        if tunnel_finished {
+            let _ = stats_handle.await;
+        } else {
+            let _ = tunnel_handle.await;
         }
 
-        tunnel_handle.abort();
-        stats_handle.abort();
+        tracker.close();
+        tracker.wait().await;
 
-        endpoint.close(0u32.into(), b"server done");
+        endpoint.close(CloseCode::Done.into(), b"server done");
 
         Ok(())
     }
 }
 
-async fn handle_connection(conn_a: Connecting, addr_b: SocketAddr) -> anyhow::Result<()> {
+async fn handle_connection(
+    conn_a: Connecting,
+    addr_b: DatagramTarget,
+    counts: Arc<TunnelCounters>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
     // TODO: are there other things I need to do to set up 0-rtt?
     let conn_a = match conn_a.into_0rtt() {
         Ok((conn_a, _)) => {
             trace!("0-rtt accepted");
             conn_a
         }
-        Err(conn_a) => timeout(Duration::from_secs(30), conn_a).await??,
+        Err(conn_a) => select! {
+            x = timeout(Duration::from_secs(30), conn_a) => x??,
+            _ = shutdown.cancelled() => return Ok(()),
+        },
     };
 
-    // TODO: look at the handshake data to figure out what client connected. that way we know what TcpListener to connect it to
-    // conn.handshake_data()
-
-    loop {
-        // each new QUIC stream gets a new UDP socket
-        let stream_a = conn_a.accept_bi().await;
-
-        let bind_b = matching_bind_address(conn_a.remote_address())?;
+    // the streams and datagrams end once the connection is closed
+    let closed = async {
+        shutdown.cancelled().await;
 
-        let socket_b = UdpSocket::bind(bind_b).await?;
-        socket_b.connect(addr_b).await?;
-
-        let socket_b = Arc::new(socket_b);
+        conn_a.close(CloseCode::Done.into(), b"server done");
+    };
 
-        let (tx_a, rx_a) = match stream_a {
-            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                debug!("connection closed");
-                return Ok(());
-            }
-            Err(e) => {
-                return Err(e.into());
-            }
-            Ok(s) => s,
+    if let Some(Role::Probe) = Role::negotiated(&conn_a) {
+        return select! {
+            x = answer_pings(&conn_a) => Ok(x?),
+            _ = closed => Ok(()),
         };
-
-        let f = handle_request(tx_a, rx_a, socket_b);
-
-        // spawn to handle multiple requests at once
-        tokio::spawn(async move {
-            if let Err(e) = f.await {
-                error!("failed: {reason}", reason = e.to_string());
-            }
-        });
     }
-}
-
-/// TODO: counters
-/// TODO: i think if we use UdpFramed, we can use tokio::io::copy
-async fn handle_request(
-    mut tx_a: quinn::SendStream,
-    mut rx_a: quinn::RecvStream,
-    socket_b: Arc<UdpSocket>,
-) -> anyhow::Result<()> {
-    // listen on rx. when anything arrives, forward it to socket_b
-    let read_f = {
-        let socket_b = socket_b.clone();
-
-        async move {
-            // let max_size = rx_a.max_datagram_size().unwrap_or(8096);
-            let max_size = 8096;
-
-            let mut buf = Vec::with_capacity(max_size);
-
-            loop {
-                let n = rx_a.read_buf(&mut buf).await?;
-
-                trace!("rx_a -> socket_b = {}", n);
-
-                socket_b.send(&buf[..n]).await?;
-            }
-        }
-    };
-    // TODO: log errors and return ()
-    let mut read_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(read_f);
-
-    let write_f = async move {
-        loop {
-            socket_b.readable().await?;
 
-            let mut buf = [0; 8096];
-
-            match socket_b.recv(&mut buf).await {
-                Ok(n) => {
-                    trace!("socket_b -> tx_a = {}", n);
-
-                    tx_a.write_all(&buf[..n]).await?;
-                }
-                Err(e) => {
-                    error!("failed to read from socket: {}", e);
-                    break;
-                }
-            }
-        }
-
-        Ok(())
-    };
+    // TODO: look at the handshake data to figure out what client connected. that way we know what TcpListener to connect it to
+    // conn.handshake_data()
 
-    // TODO: log errors and return ()
-    let mut write_f: tokio::task::JoinHandle<anyhow::Result<()>> = tokio::spawn(write_f);
+    let counts = counts
+        .connection(conn_a.stable_id() as u64)
+        .with_path(&conn_a);
 
+    // a client sends on streams or in datagrams, and either ends when the connection does
     select! {
-        x = &mut read_f => {
-            trace!("read_f finished: {:?}", x);
-        }
-        x = &mut write_f => {
-            trace!("write_f finished: {:?}", x);
-        }
+        x = forward_streams(&conn_a, &addr_b, counts.clone()) => x,
+        x = forward_datagrams(&conn_a, &addr_b, counts) => x,
+        _ = closed => Ok(()),
     }
-
-    read_f.abort();
-    write_f.abort();
-
-    info!("request finished");
-
-    Ok(())
 }
@@ -0,0 +1,509 @@
+//! Carrying the tunnel over TCP for networks that drop UDP, like a lot of corporate ones.
+//!
+//! Nothing above the socket changes. The same QUIC packets go over a TCP connection instead, each with a two byte length in front,
+//! and that is wrapped in TLS with the tunnel's certs so it looks like any other TLS on 443. quinn sees each TCP connection as
+//! a UDP peer, so streams, 0-RTT, and the admin api all work like they do over UDP. QUIC's own congestion control runs on top of
+//! TCP's, so this is slower on lossy networks and is only meant for when UDP doesn't get through.
+//!
+//! A server listens with `--tcp-fallback-listen`. A client that hasn't connected over QUIC after `TcpFallbackOptions::after` tries TCP too,
+//! and uses whichever connects first. It tries QUIC again every time it reconnects. The TCP connection can go through an HTTP or
+//! SOCKS5 proxy, see the `proxy` module.
+//!
+//! TODO: a WebSocket framing for networks that only let HTTP proxies out
+
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use quinn::udp::{RecvMeta, Transmit, UdpState};
+use quinn::{
+    AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig,
+    ZeroRttAccepted,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{debug, info, trace, warn};
+
+use crate::bind::BindOptions;
+use crate::error::TunnelError;
+use crate::protocol::Role;
+use crate::proxy::ProxyUrl;
+use crate::quic::{connect_with_0rtt, quic_client_config, TransportOptions};
+use crate::resolve::Resolver;
+use crate::tls::{self, TlsOptions};
+
+/// packets waiting to go out on one TCP connection. more are dropped, like a full UDP socket buffer
+const SEND_QUEUE: usize = 1024;
+
+/// packets read from every TCP connection that quinn hasn't taken yet
+const RECV_QUEUE: usize = 4096;
+
+/// how long to wait for the TCP handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When a client falls back to TCP and where the server takes it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpFallbackOptions {
+    /// the server's TCP port. the host is the same as for QUIC
+    pub port: u16,
+    /// how long QUIC gets to connect before TCP is tried too
+    #[serde(with = "humantime_serde")]
+    pub after: Duration,
+}
+
+impl Default for TcpFallbackOptions {
+    fn default() -> Self {
+        Self {
+            port: 443,
+            after: Duration::from_secs(5),
+        }
+    }
+}
+
+/// QUIC packets from and to TCP connections, so quinn can use them like a UDP socket
+#[derive(Debug)]
+struct PacketSocket {
+    local_addr: SocketAddr,
+    /// each TCP connection's queue, by the address quinn knows it as
+    peers: Peers,
+    incoming: Mutex<mpsc::Receiver<(SocketAddr, Vec<u8>)>>,
+}
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+impl AsyncUdpSocket for PacketSocket {
+    fn poll_send(
+        &self,
+        _state: &UdpState,
+        _cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let peers = self.peers.lock().expect("peers lock poisoned");
+
+        for x in transmits {
+            let Some(tx) = peers.get(&x.destination) else {
+                trace!(destination = %x.destination, "dropping packet for a closed TCP connection");
+                continue;
+            };
+
+            // with segmentation offload, one transmit is several packets of the same size
+            for packet in x
+                .contents
+                .chunks(x.segment_size.unwrap_or(x.contents.len()).max(1))
+            {
+                if tx.try_send(packet.to_vec()).is_err() {
+                    trace!(destination = %x.destination, "dropping packet. the TCP connection is behind");
+                }
+            }
+        }
+
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let mut incoming = self.incoming.lock().expect("incoming lock poisoned");
+
+        let mut n = 0;
+
+        while n < bufs.len().min(meta.len()) {
+            let x = if n == 0 {
+                match incoming.poll_recv(cx) {
+                    Poll::Ready(x) => x,
+                    Poll::Pending => return Poll::Pending,
+                }
+            } else {
+                match incoming.try_recv() {
+                    Ok(x) => Some(x),
+                    Err(_) => break,
+                }
+            };
+
+            let Some((addr, packet)) = x else {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "every TCP connection is closed",
+                )));
+            };
+
+            if packet.len() > bufs[n].len() {
+                trace!(
+                    len = packet.len(),
+                    "dropping a packet too big for quinn's buffer"
+                );
+                continue;
+            }
+
+            bufs[n][..packet.len()].copy_from_slice(&packet);
+
+            meta[n] = RecvMeta {
+                addr,
+                len: packet.len(),
+                stride: packet.len(),
+                ecn: None,
+                dst_ip: None,
+            };
+
+            n += 1;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+/// move packets between quinn and one TLS over TCP connection until either side closes
+async fn pump(
+    mut stream: TcpStream,
+    mut tls: rustls::Connection,
+    peer: SocketAddr,
+    incoming: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    mut outgoing: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    let mut buf = vec![0; 64 * 1024];
+    // plaintext that doesn't make a whole packet yet
+    let mut pending = vec![];
+
+    loop {
+        while tls.wants_write() {
+            let mut x = vec![];
+            tls.write_tls(&mut x)?;
+            stream.write_all(&x).await?;
+        }
+
+        select! {
+            n = stream.read(&mut buf) => {
+                let n = n?;
+
+                if n == 0 {
+                    return Ok(());
+                }
+
+                let mut x = &buf[..n];
+                let mut closed = false;
+
+                while !x.is_empty() {
+                    tls.read_tls(&mut x)?;
+
+                    let state = tls.process_new_packets().map_err(io::Error::other)?;
+
+                    if state.plaintext_bytes_to_read() > 0 {
+                        let start = pending.len();
+                        pending.resize(start + state.plaintext_bytes_to_read(), 0);
+                        tls.reader().read_exact(&mut pending[start..])?;
+                    }
+
+                    closed |= state.peer_has_closed();
+                }
+
+                while pending.len() >= 2 {
+                    let len = u16::from_be_bytes([pending[0], pending[1]]) as usize;
+
+                    if pending.len() < 2 + len {
+                        break;
+                    }
+
+                    let packet = pending[2..2 + len].to_vec();
+                    pending.drain(..2 + len);
+
+                    match incoming.try_send((peer, packet)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            trace!("dropping packet. quinn is behind");
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+                    }
+                }
+
+                if closed {
+                    return Ok(());
+                }
+            }
+            x = outgoing.recv() => {
+                let Some(packet) = x else {
+                    // quinn is done with this connection
+                    tls.send_close_notify();
+
+                    while tls.wants_write() {
+                        let mut x = vec![];
+                        tls.write_tls(&mut x)?;
+                        stream.write_all(&x).await?;
+                    }
+
+                    return Ok(());
+                };
+
+                let mut packet = Some(packet);
+
+                // everything that is already queued goes in the same TLS records
+                while let Some(x) = packet.take() {
+                    let len = u16::try_from(x.len())
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too big"))?;
+
+                    tls.writer().write_all(&len.to_be_bytes())?;
+                    tls.writer().write_all(&x)?;
+
+                    packet = outgoing.try_recv().ok();
+                }
+            }
+        }
+    }
+}
+
+/// a server endpoint that takes tunnel clients over TLS over TCP on `listen`. its tasks run on the current runtime
+pub fn bind(
+    listen: SocketAddr,
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    mut server_config: ServerConfig,
+    tls_options: &TlsOptions,
+) -> Result<Endpoint, TunnelError> {
+    let bind_err = |source| TunnelError::Bind {
+        addr: listen,
+        source,
+    };
+
+    // the outer TLS only hides the QUIC packets, so it doesn't need early data
+    let tls_options = TlsOptions {
+        early_data: false,
+        ..tls_options.clone()
+    };
+
+    let (tls_config, _root_ca) = tls::build_server_config(ca, cert, key, &tls_options)
+        .map_err(|err| TunnelError::Tls(err.into()))?;
+
+    let tls_config = Arc::new(tls_config);
+
+    let listener = std::net::TcpListener::bind(listen)
+        .and_then(|x| {
+            x.set_nonblocking(true)?;
+            TcpListener::from_std(x)
+        })
+        .map_err(bind_err)?;
+
+    let local_addr = listener.local_addr().map_err(bind_err)?;
+
+    let peers = Peers::default();
+    let (incoming_tx, incoming_rx) = mpsc::channel(RECV_QUEUE);
+
+    let socket = PacketSocket {
+        local_addr,
+        peers: peers.clone(),
+        incoming: Mutex::new(incoming_rx),
+    };
+
+    // a TCP client can't spoof its address, so there is nothing for a retry to check
+    server_config.use_retry(false);
+
+    let endpoint = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+    .map_err(bind_err)?;
+
+    info!("TCP fallback listening on {local_addr}");
+
+    tokio::spawn(async move {
+        loop {
+            let accepted = select! {
+                x = listener.accept() => x,
+                // the endpoint is gone
+                _ = incoming_tx.closed() => return,
+            };
+
+            let (stream, peer) = match accepted {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, "TCP fallback accept failed");
+                    continue;
+                }
+            };
+
+            let tls = match rustls::ServerConnection::new(tls_config.clone()) {
+                Ok(x) => x,
+                Err(err) => {
+                    warn!(?err, "failed starting TLS");
+                    continue;
+                }
+            };
+
+            debug!(%peer, "tunnel client connected over TCP");
+
+            let (tx, rx) = mpsc::channel(SEND_QUEUE);
+
+            peers.lock().expect("peers lock poisoned").insert(peer, tx);
+
+            let peers = peers.clone();
+            let incoming_tx = incoming_tx.clone();
+
+            tokio::spawn(async move {
+                let x = pump(stream, tls.into(), peer, incoming_tx, rx).await;
+
+                debug!(?x, %peer, "TCP fallback connection closed");
+
+                peers.lock().expect("peers lock poisoned").remove(&peer);
+            });
+        }
+    });
+
+    Ok(endpoint)
+}
+
+/// what a client needs to connect over TCP
+#[derive(Debug)]
+pub struct TcpFallbackClient {
+    options: TcpFallbackOptions,
+    quic: ClientConfig,
+    tls: Arc<rustls::ClientConfig>,
+    proxy: Option<(ProxyUrl, Arc<Resolver>)>,
+    bind: BindOptions,
+}
+
+impl TcpFallbackClient {
+    pub fn new(
+        options: TcpFallbackOptions,
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+        transport: &TransportOptions,
+        tls_options: &TlsOptions,
+    ) -> Result<Self, TunnelError> {
+        let quic = quic_client_config(
+            ca.clone(),
+            cert.clone(),
+            key.clone(),
+            transport,
+            tls_options,
+            // only the reverse proxy client falls back to tcp
+            Role::Reverse,
+        )?;
+
+        let tls_options = TlsOptions {
+            early_data: false,
+            ..tls_options.clone()
+        };
+
+        let tls = tls::build_client_config(ca, cert, key, &tls_options)
+            .map_err(|err| TunnelError::Tls(err.into()))?;
+
+        Ok(Self {
+            options,
+            quic,
+            tls: Arc::new(tls),
+            proxy: None,
+            bind: transport.bind.clone(),
+        })
+    }
+
+    /// connect to the server through a SOCKS5 or HTTP proxy. see the `proxy` module
+    pub fn proxy(mut self, proxy: ProxyUrl, resolver: Arc<Resolver>) -> Self {
+        self.proxy = Some((proxy, resolver));
+        self
+    }
+
+    pub fn options(&self) -> &TcpFallbackOptions {
+        &self.options
+    }
+
+    /// connect to the server at `server`'s host over TCP. the connection's remote address is `server`, like it would be over QUIC
+    pub async fn connect(
+        &self,
+        server: SocketAddr,
+        server_name: &str,
+        zero_rtt: bool,
+    ) -> Result<(Connection, Option<ZeroRttAccepted>), TunnelError> {
+        let addr = SocketAddr::new(server.ip(), self.options.port);
+
+        let connect = async {
+            match &self.proxy {
+                Some((proxy, resolver)) => proxy.connect_tcp(addr, resolver, &self.bind).await,
+                None => {
+                    let socket = match addr {
+                        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+                    };
+
+                    self.bind.apply_to_tcp(&socket, addr)?;
+
+                    socket.connect(addr).await
+                }
+            }
+        };
+
+        let stream = timeout(CONNECT_TIMEOUT, connect)
+            .await
+            .map_err(|_| TunnelError::Timeout(CONNECT_TIMEOUT))??;
+
+        let name = server_name
+            .try_into()
+            .map_err(|_| TunnelError::Config(format!("{server_name:?} is not a server name")))?;
+
+        let tls = rustls::ClientConnection::new(self.tls.clone(), name)
+            .map_err(|err| TunnelError::Tls(err.into()))?;
+
+        let (incoming_tx, incoming_rx) = mpsc::channel(RECV_QUEUE);
+        let (tx, rx) = mpsc::channel(SEND_QUEUE);
+
+        let socket = PacketSocket {
+            local_addr: stream.local_addr()?,
+            peers: Arc::new(Mutex::new(HashMap::from([(server, tx)]))),
+            incoming: Mutex::new(incoming_rx),
+        };
+
+        let mut pump = tokio::spawn(pump(stream, tls.into(), server, incoming_tx, rx));
+
+        let endpoint = Endpoint::new_with_abstract_socket(
+            EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+
+        let connecting = endpoint
+            .connect_with(self.quic.clone(), server, server_name)
+            .map_err(|err| TunnelError::Config(err.to_string()))?;
+
+        let (conn, zero_rtt) = connect_with_0rtt(connecting, zero_rtt).await?;
+
+        // a closed TCP connection can't come back, so there is no reason to wait for the idle timeout
+        let watched = conn.clone();
+
+        tokio::spawn(async move {
+            select! {
+                x = &mut pump => {
+                    debug!(?x, %addr, "TCP connection to the server closed");
+
+                    watched.close(0u32.into(), b"TCP connection closed");
+                }
+                _ = watched.closed() => {}
+            }
+        });
+
+        Ok((conn, zero_rtt))
+    }
+}
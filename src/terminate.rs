@@ -0,0 +1,251 @@
+//! Terminating TLS on a public listener, so a plaintext backend behind the tunnel can be served as a TLS service.
+//!
+//! [`TlsTerminator`] is a stream transformer. It goes first in a listener's pipeline, does the handshake with the user, and
+//! hands the decrypted bytes to the rest. Its cert is separate from the tunnel's, since users check it, so it is usually
+//! one from a public CA. Nothing here asks users for client certs.
+//!
+//! The user's ClientHello still comes in the clear, so `sni` rules route these listeners as before. `http` rules see the
+//! TLS bytes, not the request, so they don't match.
+//!
+//! rustls only has a sync API here, so a task moves bytes between it and the user's socket. The pipeline gets the other
+//! end of an in-memory pipe. The `originate` module uses the same task toward a client's backends.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use rustls::{Connection, ServerConfig, ServerConnection};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::select;
+use tokio::time::timeout;
+use tracing::{debug, Instrument};
+
+use crate::certs::{certs_from_pem, key_from_pem};
+use crate::tls::build_key_log;
+use crate::transform::{BoxedRead, BoxedWrite, StreamTransformer, TransformContext};
+
+/// the other side gets this long to finish the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how much is read from either side at once. a TLS record is at most 16 KiB of plaintext
+const BUF_LEN: usize = 16 * 1024;
+
+/// Do TLS with users and pass their plaintext on.
+#[derive(Clone)]
+pub struct TlsTerminator {
+    config: Arc<ServerConfig>,
+}
+
+impl std::fmt::Debug for TlsTerminator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TlsTerminator")
+    }
+}
+
+impl TlsTerminator {
+    /// `cert` is a PEM file with the leaf first, then its intermediates. `keylog` is like `TlsOptions::keylog`
+    pub fn from_pem(cert: PathBuf, key: PathBuf, keylog: Option<PathBuf>) -> anyhow::Result<Self> {
+        let certs = certs_from_pem(cert.clone())
+            .with_context(|| format!("loading the TLS cert {}", cert.display()))?;
+        let key = key_from_pem(key.clone())
+            .with_context(|| format!("loading the TLS key {}", key.display()))?;
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+        config.key_log = build_key_log(keylog)?;
+
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl StreamTransformer for TlsTerminator {
+    fn transform<'a>(
+        &'a self,
+        _ctx: &'a TransformContext,
+        read: BoxedRead,
+        write: BoxedWrite,
+    ) -> BoxFuture<'a, anyhow::Result<(BoxedRead, BoxedWrite)>> {
+        Box::pin(async move {
+            let conn = ServerConnection::new(self.config.clone())?;
+
+            over_tls(conn.into(), read, write)
+                .await
+                .context("TLS with the user failed")
+        })
+    }
+}
+
+/// do the handshake for `conn` over `read` and `write`, then return the plaintext halves. a task copies between the two
+/// until both directions are done
+pub(crate) async fn over_tls(
+    mut conn: Connection,
+    mut read: BoxedRead,
+    mut write: BoxedWrite,
+) -> anyhow::Result<(BoxedRead, BoxedWrite)> {
+    let leftover = timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake(&mut conn, &mut read, &mut write),
+    )
+    .await
+    .context("the TLS handshake timed out")??;
+
+    let (ours, theirs) = tokio::io::duplex(BUF_LEN);
+
+    tokio::spawn(
+        async move {
+            if let Err(err) = pump(conn, read, write, theirs, leftover).await {
+                debug!(?err, "tls stream failed");
+            }
+        }
+        .in_current_span(),
+    );
+
+    let (read, write) = tokio::io::split(ours);
+
+    Ok((Box::new(read), Box::new(write)))
+}
+
+/// returns what the other side sent after its last handshake message
+async fn handshake(
+    conn: &mut Connection,
+    read: &mut BoxedRead,
+    write: &mut BoxedWrite,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; BUF_LEN];
+
+    loop {
+        send_tls(conn, write).await?;
+
+        if !conn.is_handshaking() {
+            return Ok(vec![]);
+        }
+
+        let n = read.read(&mut buf).await?;
+
+        anyhow::ensure!(n > 0, "the connection closed during the TLS handshake");
+
+        let mut data = &buf[..n];
+
+        while !data.is_empty() && conn.is_handshaking() {
+            conn.read_tls(&mut data)?;
+
+            if let Err(err) = conn.process_new_packets() {
+                // tell the other side why
+                let _ = send_tls(conn, write).await;
+
+                return Err(err.into());
+            }
+        }
+
+        if !data.is_empty() {
+            send_tls(conn, write).await?;
+
+            return Ok(data.to_vec());
+        }
+    }
+}
+
+/// copy both ways between the TLS side and the plaintext side, until both are done
+async fn pump(
+    mut conn: Connection,
+    mut read: BoxedRead,
+    mut write: BoxedWrite,
+    plain: DuplexStream,
+    leftover: Vec<u8>,
+) -> anyhow::Result<()> {
+    let (mut plain_rx, mut plain_tx) = tokio::io::split(plain);
+
+    let mut tls_done = !feed(&mut conn, &leftover, &mut plain_tx).await?;
+    let mut plain_done = false;
+
+    if tls_done {
+        plain_tx.shutdown().await?;
+    }
+
+    let mut tls_buf = vec![0; BUF_LEN];
+    let mut plain_buf = vec![0; BUF_LEN];
+
+    while !(tls_done && plain_done) {
+        send_tls(&mut conn, &mut write).await?;
+
+        select! {
+            n = read.read(&mut tls_buf), if !tls_done => {
+                let n = n?;
+
+                // without close_notify, but the plaintext side still hears it as EOF
+                tls_done = n == 0 || !feed(&mut conn, &tls_buf[..n], &mut plain_tx).await?;
+
+                if tls_done {
+                    plain_tx.shutdown().await?;
+                }
+            }
+            n = plain_rx.read(&mut plain_buf), if !plain_done => {
+                match n? {
+                    0 => {
+                        conn.send_close_notify();
+                        send_tls(&mut conn, &mut write).await?;
+                        write.shutdown().await?;
+
+                        plain_done = true;
+                    }
+                    n => conn.writer().write_all(&plain_buf[..n])?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// give `data` from the TLS side to rustls, and what it decrypts to `plain`. false once close_notify came
+async fn feed(
+    conn: &mut Connection,
+    mut data: &[u8],
+    plain: &mut WriteHalf<DuplexStream>,
+) -> anyhow::Result<bool> {
+    let mut buf = vec![0; BUF_LEN];
+
+    // the handshake can leave plaintext behind without any `data`
+    loop {
+        if !data.is_empty() {
+            conn.read_tls(&mut data)?;
+            conn.process_new_packets()?;
+        }
+
+        loop {
+            match conn.reader().read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => plain.write_all(&buf[..n]).await?,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if data.is_empty() {
+            return Ok(true);
+        }
+    }
+}
+
+async fn send_tls(conn: &mut Connection, write: &mut BoxedWrite) -> io::Result<()> {
+    let mut buf = vec![];
+
+    while conn.wants_write() {
+        conn.write_tls(&mut buf)?;
+    }
+
+    if !buf.is_empty() {
+        write.write_all(&buf).await?;
+        write.flush().await?;
+    }
+
+    Ok(())
+}
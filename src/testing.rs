@@ -0,0 +1,346 @@
+//! A server and client in one process, on ports picked by the OS and with certs made for the test, for end-to-end tests
+//! that don't run the binary.
+//!
+//! ```ignore
+//! let backend = echo_server().await?;
+//! let tunnel = TestTunnel::start(Backend::Tcp(backend.into())).await?;
+//!
+//! assert_eq!(round_trip(tunnel.listen_addr(), b"hello").await?, b"hello");
+//! ```
+//!
+//! Everything is on 127.0.0.1. The certs are written to a new directory under the system's temp directory, which is
+//! removed when the tunnel is dropped.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use quinn::{Endpoint, VarInt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, warn};
+
+use crate::certs::{cert_from_pem, CertificateAuthority, TunnelCertificate, TunnelEnd};
+use crate::client::{
+    Backend, ReverseProxyClient, ReverseProxyClientBuilder, ReverseProxyClientHandle,
+};
+use crate::h3::{self, encode_fields, put_varint, Frame};
+use crate::listen::ListenTarget;
+use crate::server::{ReverseProxyServer, ReverseProxyServerBuilder, ReverseProxyServerHandle};
+use crate::tls::build_root_store;
+use crate::webtransport::{self, SETTING_ENABLE_WEBTRANSPORT, STREAM_WEBTRANSPORT};
+
+/// how long `TestTunnel::start` waits for the client to connect
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the name the certs are made for. the client guesses the server's name from its own cert, like the binary does
+pub const CERT_NAME: &str = "test";
+
+/// a CA, and a server and client cert signed by it, in a directory of their own
+pub struct TestCerts {
+    dir: PathBuf,
+}
+
+impl TestCerts {
+    pub fn generate() -> anyhow::Result<Self> {
+        // the pid keeps test binaries apart, and the counter keeps tests in one binary apart
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "quic-tunnel-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // left over from an earlier run that had the same pid
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+
+        std::fs::create_dir_all(&dir)?;
+
+        let x = Self { dir };
+
+        let ca = CertificateAuthority::new(x.ca(), x.dir.join(format!("{CERT_NAME}_ca.key.pem")))?;
+
+        for (end, cert, key) in [
+            (TunnelEnd::Server, x.server_cert(), x.server_key()),
+            (TunnelEnd::Client, x.client_cert(), x.client_key()),
+        ] {
+            TunnelCertificate::load_or_new(&ca.cert_gen, cert, key, end)?;
+        }
+
+        Ok(x)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// the prefix the subcommands take, like `<dir>/test`
+    pub fn cert_name(&self) -> PathBuf {
+        self.dir.join(CERT_NAME)
+    }
+
+    pub fn ca(&self) -> PathBuf {
+        self.dir.join(format!("{CERT_NAME}_ca.pem"))
+    }
+
+    pub fn server_cert(&self) -> PathBuf {
+        self.dir.join(format!("{CERT_NAME}_server.pem"))
+    }
+
+    pub fn server_key(&self) -> PathBuf {
+        self.dir.join(format!("{CERT_NAME}_server.key.pem"))
+    }
+
+    pub fn client_cert(&self) -> PathBuf {
+        self.dir.join(format!("{CERT_NAME}_client.pem"))
+    }
+
+    pub fn client_key(&self) -> PathBuf {
+        self.dir.join(format!("{CERT_NAME}_client.key.pem"))
+    }
+}
+
+impl Drop for TestCerts {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            warn!(?err, dir = %self.dir.display(), "unable to remove test certs");
+        }
+    }
+}
+
+/// a reverse proxy server with one TCP listener, and a client that forwards its streams to a backend
+pub struct TestTunnel {
+    pub server: ReverseProxyServerHandle,
+    pub client: ReverseProxyClientHandle,
+    listen_addr: SocketAddr,
+    // dropped last, after the endpoints are done with the files
+    certs: TestCerts,
+}
+
+impl TestTunnel {
+    /// start a tunnel to `backend` and wait for the client to connect
+    pub async fn start(backend: Backend) -> anyhow::Result<Self> {
+        Self::start_with(backend, |x| x, |x| x).await
+    }
+
+    /// like `start`, but the builders can be changed first, like to add compression. the server already listens for TCP on
+    /// an ephemeral port, and the client connects to it
+    pub async fn start_with(
+        backend: Backend,
+        server: impl FnOnce(ReverseProxyServerBuilder) -> ReverseProxyServerBuilder,
+        client: impl FnOnce(ReverseProxyClientBuilder) -> ReverseProxyClientBuilder,
+    ) -> anyhow::Result<Self> {
+        let certs = TestCerts::generate()?;
+
+        let localhost: SocketAddr = "127.0.0.1:0".parse()?;
+
+        let builder = ReverseProxyServer::builder(
+            certs.ca(),
+            certs.server_cert(),
+            certs.server_key(),
+            localhost,
+        )
+        .listen(ListenTarget::Tcp(localhost), "tcp", Default::default());
+
+        let server = server(builder).start().await?;
+
+        let listen_addr = server.listener_addrs()[0].context("the test listener has no address")?;
+
+        let builder = ReverseProxyClient::builder(
+            certs.ca(),
+            certs.client_cert(),
+            certs.client_key(),
+            server.quic_addr().into(),
+            backend,
+        );
+
+        let client = client(builder).start().await?;
+
+        let x = Self {
+            server,
+            client,
+            listen_addr,
+            certs,
+        };
+
+        x.wait_for_client(CONNECT_TIMEOUT).await?;
+
+        Ok(x)
+    }
+
+    /// where to connect to reach the backend through the tunnel
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    pub fn quic_addr(&self) -> SocketAddr {
+        self.server.quic_addr()
+    }
+
+    pub fn certs(&self) -> &TestCerts {
+        &self.certs
+    }
+
+    /// wait until the server has a client to send streams to, like after the client reconnects. a client that was refused
+    /// never counts, so this times out
+    pub async fn wait_for_client(&self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        // a client is only sent streams once its hello is answered
+        while !self
+            .server
+            .clients()
+            .iter()
+            .any(|x| x.protocol_version.is_some())
+        {
+            if Instant::now() >= deadline {
+                anyhow::bail!("the test client didn't connect in {timeout:?}");
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        Ok(())
+    }
+
+    /// stop the client, then the server, and wait for both
+    pub async fn shutdown(self) {
+        self.client.shutdown().await;
+        self.server.shutdown().await;
+    }
+}
+
+/// a TCP server on an ephemeral port that sends back whatever arrives. it runs until the runtime stops
+pub async fn echo_server() -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, peer)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut rx, mut tx) = stream.split();
+
+                let x = tokio::io::copy(&mut rx, &mut tx).await;
+
+                debug!(%peer, ?x, "echo finished");
+
+                let _ = tx.shutdown().await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// send `data` to `addr`, close our side, and return everything that comes back
+pub async fn round_trip(addr: SocketAddr, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    let (mut rx, mut tx) = stream.split();
+
+    // reading while writing, so an echo of more than the buffers hold doesn't wait on us forever
+    let send = async {
+        tx.write_all(data).await?;
+        tx.shutdown().await
+    };
+
+    let mut x = vec![];
+
+    tokio::try_join!(send, rx.read_to_end(&mut x))?;
+
+    Ok(x)
+}
+
+/// open a WebTransport session for `route` at `addr` like a browser would, send `data` on a stream in it, and return
+/// everything that comes back. the server's cert is checked against `ca`, for the name the test certs are made for
+pub async fn webtransport_round_trip(
+    addr: SocketAddr,
+    ca: &Path,
+    route: &str,
+    data: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let ca = cert_from_pem(ca.to_path_buf())?;
+
+    let mut tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(build_root_store(&[&ca])?)
+        .with_no_client_auth();
+
+    tls.alpn_protocols = vec![webtransport::ALPN.to_vec()];
+
+    let mut endpoint = Endpoint::client("127.0.0.1:0".parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+
+    let conn = endpoint
+        .connect(addr, &format!("{CERT_NAME}_server"))?
+        .await?;
+
+    let _control = h3::open_control(
+        &conn,
+        &[
+            (h3::SETTING_ENABLE_CONNECT_PROTOCOL, 1),
+            (SETTING_ENABLE_WEBTRANSPORT, 1),
+        ],
+    )
+    .await?;
+
+    // the session lasts as long as its CONNECT stream, so both halves are kept until the end
+    let (mut session_tx, mut session_rx) = conn.open_bi().await?;
+    let session_id = VarInt::from(session_tx.id()).into_inner();
+
+    let request = encode_fields(&[
+        (":method", "CONNECT"),
+        (":protocol", "webtransport"),
+        (":scheme", "https"),
+        (":authority", &format!("{CERT_NAME}_server")),
+        (":path", &format!("/{route}")),
+    ]);
+
+    session_tx
+        .write_all(&Frame::new(h3::FRAME_HEADERS, request).encode())
+        .await?;
+
+    let response = Frame::read(&mut session_rx, &mut 0)
+        .await?
+        .context("the session ended without a response")?;
+
+    let fields = h3::decode_fields(&response.payload)?;
+
+    let status = fields
+        .iter()
+        .find(|(x, _)| x == ":status")
+        .map(|(_, x)| x.as_str())
+        .unwrap_or_default();
+
+    anyhow::ensure!(status == "200", "the session was refused with {status}");
+
+    let (mut tx, mut rx) = conn.open_bi().await?;
+
+    let mut header = vec![];
+    put_varint(&mut header, STREAM_WEBTRANSPORT);
+    put_varint(&mut header, session_id);
+
+    // reading while writing, like `round_trip`
+    let send = async {
+        tx.write_all(&header).await?;
+        tx.write_all(data).await?;
+        tx.finish().await?;
+
+        anyhow::Ok(())
+    };
+
+    let recv = async { anyhow::Ok(rx.read_to_end(usize::MAX).await?) };
+
+    let (_, x) = tokio::try_join!(send, recv)?;
+
+    conn.close(h3::H3_NO_ERROR.into(), b"");
+    drop((session_tx, session_rx));
+
+    Ok(x)
+}
@@ -1,9 +1,86 @@
 // TODO: compare with <https://github.com/quinn-rs/quinn/blob/main/quinn/examples/common/mod.rs>
 
-use crate::certs::{cert_from_pem, key_from_pem};
+use crate::certs::{cert_from_pem, certs_from_pem, key_from_pem};
 use rustls::server::AllowAnyAuthenticatedClient;
-use rustls::{Certificate, ClientConfig, RootCertStore, ServerConfig};
+use rustls::{Certificate, ClientConfig, KeyLog, KeyLogFile, RootCertStore, ServerConfig};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// write TLS secrets in the NSS key log format so tools like Wireshark can decrypt captured QUIC traffic.
+///
+/// This is for debugging only! Anyone with this file can read your tunnel.
+pub struct KeyLogPath {
+    file: Mutex<File>,
+}
+
+impl KeyLogPath {
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        warn!("logging TLS secrets to \"{}\"", path.display());
+
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for KeyLogPath {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = format!("{label} ");
+        for x in client_random {
+            line.push_str(&format!("{x:02x}"));
+        }
+        line.push(' ');
+        for x in secret {
+            line.push_str(&format!("{x:02x}"));
+        }
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!(?err, "failed writing TLS key log");
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TlsOptions {
+    /// where to write TLS secrets for debugging. `SSLKEYLOGFILE` is honored if this is `None`
+    pub keylog: Option<PathBuf>,
+    /// 0-RTT on the client and 0.5-RTT on the server.
+    ///
+    /// Early data can be replayed by an attacker! Disable this for replay-sensitive workloads.
+    pub early_data: bool,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            keylog: None,
+            early_data: true,
+        }
+    }
+}
+
+/// an explicit path wins. otherwise, honor `SSLKEYLOGFILE` like most other TLS tools do.
+pub fn build_key_log(keylog: Option<PathBuf>) -> anyhow::Result<Arc<dyn KeyLog>> {
+    match keylog {
+        Some(path) => Ok(Arc::new(KeyLogPath::new(path)?)),
+        None => {
+            if std::env::var_os("SSLKEYLOGFILE").is_some() {
+                info!("logging TLS secrets to SSLKEYLOGFILE");
+            }
+
+            Ok(Arc::new(KeyLogFile::new()))
+        }
+    }
+}
 
 pub fn build_root_store(root_certs: &[&Certificate]) -> anyhow::Result<RootCertStore> {
     let mut root_store = RootCertStore::empty();
@@ -19,6 +96,7 @@ pub fn build_client_config(
     ca: PathBuf,
     cert: PathBuf,
     key: PathBuf,
+    options: &TlsOptions,
 ) -> anyhow::Result<ClientConfig> {
     let ca = cert_from_pem(ca)?;
     let cert = cert_from_pem(cert)?;
@@ -31,15 +109,18 @@ pub fn build_client_config(
         .with_root_certificates(root_store)
         .with_client_auth_cert(vec![cert], key)?;
 
-    // // TODO: set alpn protocols?
-    // config.alpn_protocols = vec!["quic-tunnel".into()];
-
-    // TODO: make early data optional?
-    config.enable_early_data = true;
+    // session tickets are kept in memory by the default resumption store, so 0-RTT works when reconnecting within one
+    // process. they can't be saved for the next one yet. the byte based `StoresClientSessions` went away in rustls 0.21,
+    // and its `ClientSessionStore` hands over `Tls13ClientSessionValue`s whose ticket and secret are private and which
+    // have no encoding, so a file store could neither write them nor read them back. quinn 0.10 pins rustls 0.21
+    // TODO: a `--session-cache <path>` file (mode 0600) once quinn is on a rustls that can serialize client sessions
+    config.enable_early_data = options.early_data;
 
     // sni isn't needed since we're connecting to a single server
     config.enable_sni = false;
 
+    config.key_log = build_key_log(options.keylog.clone())?;
+
     Ok(config)
 }
 
@@ -47,6 +128,7 @@ pub fn build_server_config(
     ca: PathBuf,
     cert: PathBuf,
     key: PathBuf,
+    options: &TlsOptions,
 ) -> anyhow::Result<(ServerConfig, RootCertStore)> {
     let ca = cert_from_pem(ca)?;
     let cert = cert_from_pem(cert)?;
@@ -65,11 +147,58 @@ pub fn build_server_config(
         .with_client_cert_verifier(client_cert_verifier)
         .with_single_cert(vec![cert, ca], key)?;
 
-    // // TODO: set alpn protocols?
-    // config.alpn_protocols = vec!["quic-tunnel".into()];
+    config.send_half_rtt_data = options.early_data;
+
+    if options.early_data {
+        // quic requires this to be exactly u32::MAX for 0-RTT to be accepted
+        config.max_early_data_size = u32::MAX;
+    }
 
-    // TODO: make 0.5-rtt optional
-    config.send_half_rtt_data = true;
+    config.key_log = build_key_log(options.keylog.clone())?;
 
     Ok((config, root_store))
 }
+
+/// a server config for browsers and other clients without our client certs. `chain` is a leaf and its intermediates.
+/// `None` uses the tunnel's server cert, which browsers only trust if our CA is installed
+pub fn build_public_server_config(
+    chain: Option<(PathBuf, PathBuf)>,
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    options: &TlsOptions,
+) -> anyhow::Result<ServerConfig> {
+    let (certs, key) = match chain {
+        Some((cert, key)) => (certs_from_pem(cert)?, key_from_pem(key)?),
+        None => (
+            vec![cert_from_pem(cert)?, cert_from_pem(ca)?],
+            key_from_pem(key)?,
+        ),
+    };
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    config.send_half_rtt_data = options.early_data;
+
+    if options.early_data {
+        config.max_early_data_size = u32::MAX;
+    }
+
+    config.key_log = build_key_log(options.keylog.clone())?;
+
+    Ok(config)
+}
+
+/// sha256 of the peer's leaf certificate as hex. this identifies a tunnel client no matter what address it connects from
+pub fn peer_fingerprint(conn: &quinn::Connection) -> Option<String> {
+    let certs = conn.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+
+    let leaf = certs.first()?;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, &leaf.0);
+
+    Some(digest.as_ref().iter().map(|x| format!("{x:02x}")).collect())
+}
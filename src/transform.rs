@@ -0,0 +1,140 @@
+//! Small protocol adaptations applied between the public listener and the QUIC stream.
+//!
+//! Transformers run in order. Each one gets the halves the previous one returned and can wrap them however it likes.
+
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use serde::{Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+pub type BoxedRead = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxedWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// what transformers know about the user connection
+#[derive(Clone, Debug, Default)]
+pub struct TransformContext {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+}
+
+pub trait StreamTransformer: Debug + Send + Sync {
+    fn transform<'a>(
+        &'a self,
+        ctx: &'a TransformContext,
+        read: BoxedRead,
+        write: BoxedWrite,
+    ) -> BoxFuture<'a, anyhow::Result<(BoxedRead, BoxedWrite)>>;
+}
+
+/// an ordered list of transformers. cheap to clone
+#[derive(Clone, Debug, Default)]
+pub struct TransformPipeline {
+    transformers: Vec<Arc<dyn StreamTransformer>>,
+}
+
+/// serialized as the `Debug` of each transformer
+impl Serialize for TransformPipeline {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.transformers.iter().map(|x| format!("{x:?}")))
+    }
+}
+
+impl TransformPipeline {
+    /// build a pipeline from the names of built-in transformers
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> anyhow::Result<Self> {
+        let mut x = Self::default();
+
+        for name in names {
+            let name = name.as_ref();
+
+            let transformer =
+                builtin(name).with_context(|| format!("unknown stream transformer: {name}"))?;
+
+            x.push(transformer);
+        }
+
+        Ok(x)
+    }
+
+    pub fn push(&mut self, transformer: Arc<dyn StreamTransformer>) {
+        self.transformers.push(transformer);
+    }
+
+    /// for transformers that have to see the user's bytes before the others, like `TlsTerminator`
+    pub fn push_front(&mut self, transformer: Arc<dyn StreamTransformer>) {
+        self.transformers.insert(0, transformer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
+    }
+
+    pub async fn apply(
+        &self,
+        ctx: &TransformContext,
+        mut read: BoxedRead,
+        mut write: BoxedWrite,
+    ) -> anyhow::Result<(BoxedRead, BoxedWrite)> {
+        for transformer in self.transformers.iter() {
+            (read, write) = transformer.transform(ctx, read, write).await?;
+        }
+
+        Ok((read, write))
+    }
+}
+
+/// the transformers that can be chosen by name on the command line
+pub fn builtin(name: &str) -> Option<Arc<dyn StreamTransformer>> {
+    match name {
+        "proxy_v1" => Some(Arc::new(ProxyProtocolV1)),
+        _ => None,
+    }
+}
+
+/// Prefix the user's data with a [PROXY protocol v1](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) header.
+///
+/// This lets the backend see the real user address instead of the tunnel client's.
+#[derive(Debug)]
+pub struct ProxyProtocolV1;
+
+impl ProxyProtocolV1 {
+    pub fn header(ctx: &TransformContext) -> String {
+        match (ctx.peer_addr, ctx.local_addr) {
+            (Some(peer), Some(local)) if peer.is_ipv4() == local.is_ipv4() => {
+                let family = if peer.is_ipv4() { "TCP4" } else { "TCP6" };
+
+                format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    family,
+                    peer.ip(),
+                    local.ip(),
+                    peer.port(),
+                    local.port()
+                )
+            }
+            _ => "PROXY UNKNOWN\r\n".to_string(),
+        }
+    }
+}
+
+impl StreamTransformer for ProxyProtocolV1 {
+    fn transform<'a>(
+        &'a self,
+        ctx: &'a TransformContext,
+        read: BoxedRead,
+        write: BoxedWrite,
+    ) -> BoxFuture<'a, anyhow::Result<(BoxedRead, BoxedWrite)>> {
+        Box::pin(async move {
+            let header = Cursor::new(Self::header(ctx).into_bytes());
+
+            let read = Box::new(header.chain(read)) as BoxedRead;
+
+            Ok((read, write))
+        })
+    }
+}
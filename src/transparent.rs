@@ -0,0 +1,230 @@
+//! A transparent gateway: the client takes connections that iptables diverted to it, and the server makes them for it.
+//!
+//! Connections redirected with `-j REDIRECT` keep where they were going in `SO_ORIGINAL_DST`. Ones diverted with
+//! `-j TPROXY` arrive on a socket bound with `IP_TRANSPARENT`, and their local address is where they were going. Either
+//! way, the client opens a stream with [`Role::Connect`], sends a `Connect` frame with that address, and copies the
+//! connection over it. The server connects there if `ConnectOptions::allow` has it, and resets the stream with
+//! [`CloseCode::Unreachable`] if it doesn't or can't.
+//!
+//! Only TCP is carried, and only on Linux.
+//!
+//! [`Role::Connect`]: crate::protocol::Role::Connect
+//! [`CloseCode::Unreachable`]: crate::protocol::CloseCode::Unreachable
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use quinn::{Connection, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tracing::{debug, info, trace, Instrument};
+
+use crate::compress::{copy_bidirectional_with_compression, CompressAlgo};
+use crate::counters::{ScopedCounters, StreamCounters};
+use crate::protocol::{CloseCode, ControlMessage, PREAMBLE_TIMEOUT};
+use crate::resolve::Resolver;
+use crate::stream::{Stream, TcpOptions};
+
+/// where the server lets `transparent_client`s connect. nowhere by default
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectOptions {
+    /// like 10.0.0.0/8. 0.0.0.0/0 and ::/0 allow anywhere
+    pub allow: Vec<IpNet>,
+}
+
+impl ConnectOptions {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty()
+    }
+
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        self.allow.iter().any(|x| x.contains(&addr.ip()))
+    }
+}
+
+/// how connections are diverted to the client
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Divert {
+    /// `-j REDIRECT`. the destination is in `SO_ORIGINAL_DST`
+    #[default]
+    Redirect,
+    /// `-j TPROXY`. the listener needs `IP_TRANSPARENT`, which needs `CAP_NET_ADMIN`
+    Tproxy,
+}
+
+/// listen for diverted connections. a TPROXY listener gets `IP_TRANSPARENT` so it can take connections for any address
+pub fn bind(addr: SocketAddr, divert: Divert) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    socket.set_reuse_address(true)?;
+
+    if divert == Divert::Tproxy {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        socket.set_ip_transparent(true)?;
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return Err(unsupported());
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// where a diverted connection was going before it reached us
+pub fn original_destination(stream: &TcpStream, divert: Divert) -> std::io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+
+    if divert == Divert::Tproxy {
+        return Ok(local);
+    }
+
+    original_dst(stream, local)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn original_dst(stream: &TcpStream, local: SocketAddr) -> std::io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+
+    let x = match local {
+        SocketAddr::V4(_) => socket.original_dst()?,
+        SocketAddr::V6(_) => socket.original_dst_ipv6()?,
+    };
+
+    x.as_socket()
+        .ok_or_else(|| std::io::Error::other("the original destination is not an IP address"))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn original_dst(_stream: &TcpStream, _local: SocketAddr) -> std::io::Result<SocketAddr> {
+    Err(unsupported())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "diverted connections only work on linux",
+    )
+}
+
+/// open a stream to the server and ask it to connect to `destination`
+pub async fn connect(
+    conn: &Connection,
+    destination: SocketAddr,
+) -> anyhow::Result<(SendStream, RecvStream)> {
+    let (mut tx, rx) = conn.open_bi().await?;
+
+    let x = ControlMessage::Connect { addr: destination };
+
+    tx.write_all(&x.encode()?).await?;
+
+    Ok((tx, rx))
+}
+
+/// the server's side: connect each stream to where its `Connect` frame asks, until the connection closes
+pub async fn serve_connect_streams(
+    conn: &Connection,
+    options: &ConnectOptions,
+    tcp: &TcpOptions,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    let resolver = Arc::new(Resolver::default());
+
+    loop {
+        let (tx, rx) = match conn.accept_bi().await {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                debug!("connection closed");
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        counts.stream_opened();
+        counts.rtt(conn.rtt());
+
+        let options = options.clone();
+        let tcp = tcp.clone();
+        let resolver = resolver.clone();
+        let counts = counts.clone();
+
+        let span = tracing::info_span!("connect", stream_id = rx.id().index());
+
+        tokio::spawn(
+            async move {
+                match serve_connect_stream(tx, rx, &options, &tcp, &resolver, counts).await {
+                    Ok((a_to_b, b_to_a)) => trace!(%a_to_b, %b_to_a, "success"),
+                    Err(err) => debug!(?err, "connect stream failed"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn serve_connect_stream(
+    mut tx: SendStream,
+    mut rx: RecvStream,
+    options: &ConnectOptions,
+    tcp: &TcpOptions,
+    resolver: &Resolver,
+    counts: ScopedCounters,
+) -> anyhow::Result<(u64, u64)> {
+    let accepted_at = Instant::now();
+
+    let destination = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+        Some(ControlMessage::Connect { addr }) => addr,
+        x => {
+            let _ = tx.reset(CloseCode::ProtocolError.into());
+            let _ = rx.stop(CloseCode::ProtocolError.into());
+
+            anyhow::bail!("expected Connect, got {x:?}");
+        }
+    };
+
+    let refuse = |mut tx: SendStream, mut rx: RecvStream| {
+        let _ = tx.reset(CloseCode::Unreachable.into());
+        let _ = rx.stop(CloseCode::Unreachable.into());
+    };
+
+    if !options.allows(destination) {
+        info!(%destination, "refusing to connect outside the allow list");
+
+        refuse(tx, rx);
+
+        anyhow::bail!("{destination} is not allowed");
+    }
+
+    let stream = match resolver.connect_tcp(&destination.into(), tcp).await {
+        Ok(x) => x,
+        Err(err) => {
+            refuse(tx, rx);
+
+            return Err(anyhow::Error::from(err).context(format!("connecting to {destination}")));
+        }
+    };
+
+    debug!(%destination, "connected");
+
+    let counters = StreamCounters::new(counts, accepted_at);
+
+    let x = copy_bidirectional_with_compression(
+        CompressAlgo::None,
+        rx,
+        tx,
+        Stream::Tcp(stream),
+        Default::default(),
+        counters.into(),
+        Default::default(),
+    )
+    .await?;
+
+    Ok(x)
+}
@@ -0,0 +1,282 @@
+//! The udp_client's table of who is sending to it.
+//!
+//! Each sender gets its own stream through the tunnel, so a flood from many addresses, spoofed or not, would open a
+//! stream and hold its buffers for each one. [`UdpSessions`] caps that with `max_sessions` in all and `max_per_source`
+//! from one IP address, and closes a session once its sender has been quiet for `idle_timeout`.
+//!
+//! A datagram that would open a session over a limit is dropped and counted as rejected. Sessions that are already open
+//! keep working, so a flood of new senders can't push out the ones that were there first.
+//!
+//! With [`UdpMode::Dns`], each sender is expected to send a query and wait for one answer, like a resolver does from a
+//! new port for every query. Its session closes after the first reply, or after a few seconds without one, instead of
+//! sitting in the table for the full idle timeout.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
+use quinn::{RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use strum::EnumString;
+
+use crate::counters::TunnelCounters;
+use crate::datagram::DatagramPeer;
+use crate::get_tunnel_timeout;
+
+/// How a udp_client carries its senders' datagrams through the tunnel.
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, Eq, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpTransport {
+    /// a QUIC stream for each sender. nothing is lost or reordered, for things like syslog
+    #[default]
+    Stream,
+    /// QUIC datagrams. a lost packet stays lost and the ones after it don't wait, for things like WireGuard and games
+    Datagram,
+}
+
+/// What the datagrams going through a udp_client look like.
+#[derive(Copy, Clone, Debug, Default, Deserialize, EnumString, Eq, PartialEq, Serialize)]
+#[strum(ascii_case_insensitive)]
+#[serde(rename_all = "snake_case")]
+pub enum UdpMode {
+    /// a sender's session lasts until it goes idle, for things like WireGuard
+    #[default]
+    Sessions,
+    /// one reply per sender, then its session closes
+    Dns,
+}
+
+impl UdpMode {
+    /// the idle timeout when none is given
+    pub fn idle_timeout(self) -> Duration {
+        match self {
+            Self::Sessions => get_tunnel_timeout(),
+            // resolvers give up and retry well before this
+            Self::Dns => Duration::from_secs(5),
+        }
+    }
+}
+
+/// How many senders a udp_client keeps sessions for, and for how long.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionLimits {
+    /// 10000 by default
+    pub max_sessions: usize,
+    /// sessions one IP address can have. unix senders aren't limited by this. no limit by default
+    pub max_per_source: Option<usize>,
+    /// close a session once its sender hasn't sent anything for this long. 5 minutes by default
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 10_000,
+            max_per_source: None,
+            idle_timeout: get_tunnel_timeout(),
+        }
+    }
+}
+
+/// counted for the whole process, in [`TunnelCounters`]
+#[derive(Debug, Default)]
+pub struct SessionCounters {
+    active: AtomicU64,
+    opened: AtomicU64,
+    expired: AtomicU64,
+    answered: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SessionSnapshot {
+    pub active: u64,
+    pub opened: u64,
+    /// closed for being idle
+    pub expired: u64,
+    /// closed after their reply, with [`UdpMode::Dns`]
+    #[serde(default)]
+    pub answered: u64,
+    /// datagrams dropped because a new session would have gone over a limit
+    pub rejected: u64,
+}
+
+impl SessionCounters {
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            active: self.active.load(Ordering::Relaxed),
+            opened: self.opened.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            answered: self.answered.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// a sender's stream, unless the sessions hold something else. the receiving half is taken by whatever sends the replies
+/// back
+pub type Session = (
+    Arc<tokio::sync::Mutex<SendStream>>,
+    Arc<tokio::sync::Mutex<Option<RecvStream>>>,
+);
+
+/// sessions that are open or being opened
+#[derive(Debug, Default)]
+struct Slots {
+    total: usize,
+    per_source: HashMap<IpAddr, usize>,
+}
+
+impl Slots {
+    fn release(&mut self, peer: &DatagramPeer) {
+        self.total = self.total.saturating_sub(1);
+
+        if let DatagramPeer::Udp(x) = peer {
+            if let Some(n) = self.per_source.get_mut(&x.ip()) {
+                *n -= 1;
+
+                if *n == 0 {
+                    self.per_source.remove(&x.ip());
+                }
+            }
+        }
+    }
+}
+
+/// A stream through the tunnel for each sender, or whatever `V` is, within [`SessionLimits`]. Clones share the table.
+#[derive(Clone)]
+pub struct UdpSessions<V = Session> {
+    cache: Cache<DatagramPeer, V>,
+    slots: Arc<Mutex<Slots>>,
+    limits: SessionLimits,
+    counts: Arc<TunnelCounters>,
+}
+
+impl<V: Clone + Send + Sync + 'static> UdpSessions<V> {
+    pub fn new(limits: SessionLimits, counts: Arc<TunnelCounters>) -> Self {
+        Self::with_on_close(limits, counts, |_, _| {})
+    }
+
+    /// like `new`, and `on_close` gets each session that closes, for whatever has to go with it
+    pub fn with_on_close<F>(limits: SessionLimits, counts: Arc<TunnelCounters>, on_close: F) -> Self
+    where
+        F: Fn(&DatagramPeer, V) + Send + Sync + 'static,
+    {
+        let slots: Arc<Mutex<Slots>> = Default::default();
+
+        let on_removed = {
+            let slots = slots.clone();
+            let counts = counts.clone();
+
+            move |peer: Arc<DatagramPeer>, session: V, cause: RemovalCause| {
+                let x = counts.udp_sessions();
+
+                if cause == RemovalCause::Expired {
+                    x.expired.fetch_add(1, Ordering::Relaxed);
+                }
+
+                x.active.fetch_sub(1, Ordering::Relaxed);
+
+                slots.lock().unwrap().release(&peer);
+
+                on_close(&peer, session);
+            }
+        };
+
+        let cache = CacheBuilder::new(limits.max_sessions as u64)
+            .time_to_idle(limits.idle_timeout)
+            .eviction_listener(on_removed)
+            .build();
+
+        Self {
+            cache,
+            slots,
+            limits,
+            counts,
+        }
+    }
+
+    /// the session for `peer`, from `open` if it doesn't have one yet. `None` if a new one would go over a limit
+    pub async fn get_or_open<F>(&self, peer: &DatagramPeer, open: F) -> anyhow::Result<Option<V>>
+    where
+        F: Future<Output = anyhow::Result<V>>,
+    {
+        if let Some(x) = self.cache.get(peer).await {
+            return Ok(Some(x));
+        }
+
+        if !self.reserve(peer) {
+            // sessions that went idle only give their slots back when the cache gets around to it
+            self.cache.run_pending_tasks().await;
+
+            if !self.reserve(peer) {
+                self.counts
+                    .udp_sessions()
+                    .rejected
+                    .fetch_add(1, Ordering::Relaxed);
+
+                return Ok(None);
+            }
+        }
+
+        let x = match open.await {
+            Ok(x) => x,
+            Err(err) => {
+                self.slots.lock().unwrap().release(peer);
+
+                return Err(err);
+            }
+        };
+
+        let counts = self.counts.udp_sessions();
+        counts.opened.fetch_add(1, Ordering::Relaxed);
+        counts.active.fetch_add(1, Ordering::Relaxed);
+
+        self.cache.insert(peer.clone(), x.clone()).await;
+
+        Ok(Some(x))
+    }
+
+    /// close `peer`'s session because it got its reply, and return it. its next datagram opens a new one
+    pub async fn answered(&self, peer: &DatagramPeer) -> Option<V> {
+        let x = self.cache.remove(peer).await?;
+
+        self.counts
+            .udp_sessions()
+            .answered
+            .fetch_add(1, Ordering::Relaxed);
+
+        Some(x)
+    }
+
+    /// take a slot for a new session from `peer`, if there is one
+    fn reserve(&self, peer: &DatagramPeer) -> bool {
+        let mut slots = self.slots.lock().unwrap();
+
+        if slots.total >= self.limits.max_sessions {
+            return false;
+        }
+
+        if let (DatagramPeer::Udp(x), Some(max)) = (peer, self.limits.max_per_source) {
+            let n = slots.per_source.entry(x.ip()).or_default();
+
+            if *n >= max {
+                return false;
+            }
+
+            *n += 1;
+        }
+
+        slots.total += 1;
+
+        true
+    }
+}
@@ -0,0 +1,504 @@
+//! Unix socket addresses and the socket files they leave behind.
+//!
+//! On Linux, a path starting with `@` is an abstract address like `@quic-tunnel`. It lives in the network namespace instead of the filesystem,
+//! so there is no file to clean up and no permissions. Anyone in the same network namespace can connect.
+//!
+//! The types exist everywhere so the rest of the code doesn't need cfgs, but binding or connecting only works on Unix.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::upgrade;
+
+#[cfg(unix)]
+pub use os::{
+    bind_datagram, bind_listener, check_bind, connect_datagram, connect_stream, peer_path, send_to,
+    unbound_datagram, UnixDatagram, UnixListener, UnixStream,
+};
+
+#[cfg(not(unix))]
+pub use other::{
+    bind_datagram, bind_listener, check_bind, connect_datagram, connect_stream, peer_path, send_to,
+    unbound_datagram, UnixDatagram, UnixListener, UnixStream,
+};
+
+/// Who can use the socket files we create. Abstract sockets ignore these.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixSocketOptions {
+    /// file mode like 0o660. the socket is briefly created with the umask's mode first
+    pub mode: Option<u32>,
+    /// user name or uid
+    pub owner: Option<String>,
+    /// group name or gid
+    pub group: Option<String>,
+}
+
+/// the name after the `@`, if this is an abstract address
+#[cfg(unix)]
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+
+/// the name after the `@`, if this is an abstract address
+#[cfg(not(unix))]
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+    path.to_str()?.strip_prefix('@').map(str::as_bytes)
+}
+
+/// removes the socket file when dropped. abstract sockets don't have one
+#[derive(Debug, Default)]
+pub struct SocketFile {
+    path: Option<PathBuf>,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let Some(path) = self.path.take() else {
+            return;
+        };
+
+        // the new process is still listening on it
+        if upgrade::handed_over() {
+            return;
+        }
+
+        if let Err(err) = fs::remove_file(&path) {
+            debug!(?err, path = %path.display(), "failed to remove socket file");
+        }
+    }
+}
+
+#[cfg(unix)]
+mod os {
+    use std::ffi::OsStr;
+    use std::fs::{self, Permissions};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    use socket2::{Domain, SockAddr, SockRef, Socket, Type};
+    use tokio::io::Interest;
+    pub use tokio::net::{UnixDatagram, UnixListener, UnixStream};
+    use tracing::info;
+
+    use super::{abstract_name, SocketFile, UnixSocketOptions};
+    use crate::listen::ListenTarget;
+    use crate::upgrade;
+
+    impl UnixSocketOptions {
+        pub fn apply(&self, path: &Path) -> io::Result<()> {
+            if let Some(x) = self.mode {
+                fs::set_permissions(path, Permissions::from_mode(x))?;
+            }
+
+            if self.owner.is_some() || self.group.is_some() {
+                let uid = self
+                    .owner
+                    .as_deref()
+                    .map(|x| lookup_id("/etc/passwd", x))
+                    .transpose()?;
+                let gid = self
+                    .group
+                    .as_deref()
+                    .map(|x| lookup_id("/etc/group", x))
+                    .transpose()?;
+
+                std::os::unix::fs::chown(path, uid, gid)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// the third field of passwd and group lines is the id.
+    /// names that only exist in ldap or similar aren't in these files, so use a number for them
+    fn lookup_id(db: &str, name: &str) -> io::Result<u32> {
+        if let Ok(x) = name.parse() {
+            return Ok(x);
+        }
+
+        fs::read_to_string(db)?
+            .lines()
+            .find_map(|line| {
+                let mut fields = line.split(':');
+
+                if fields.next() != Some(name) {
+                    return None;
+                }
+
+                fields.nth(1)?.parse().ok()
+            })
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("{name} is not in {db}"))
+            })
+    }
+
+    fn socket_addr(path: &Path) -> io::Result<SockAddr> {
+        match abstract_name(path) {
+            Some([]) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "abstract unix socket names can't be empty",
+            )),
+            Some(x) => abstract_addr(x),
+            None => SockAddr::unix(path),
+        }
+    }
+
+    /// a leading nul byte is what makes an address abstract
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn abstract_addr(name: &[u8]) -> io::Result<SockAddr> {
+        let mut x = vec![0];
+        x.extend_from_slice(name);
+
+        SockAddr::unix(OsStr::from_bytes(&x))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn abstract_addr(_name: &[u8]) -> io::Result<SockAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "abstract unix sockets only exist on linux",
+        ))
+    }
+
+    /// who sent a datagram. abstract senders come back with a leading `@`
+    pub fn peer_path(addr: tokio::net::unix::SocketAddr) -> Option<PathBuf> {
+        if let Some(x) = addr.as_pathname() {
+            return Some(x.to_path_buf());
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr: std::os::unix::net::SocketAddr = addr.into();
+
+            if let Some(x) = addr.as_abstract_name() {
+                let mut path = b"@".to_vec();
+                path.extend_from_slice(x);
+
+                return Some(OsStr::from_bytes(&path).into());
+            }
+        }
+
+        None
+    }
+
+    impl SocketFile {
+        /// a file someone else bound, like the server we are upgrading from
+        fn adopt(path: &Path) -> Self {
+            Self {
+                path: abstract_name(path).is_none().then(|| path.to_path_buf()),
+            }
+        }
+    }
+
+    /// why binding `path` would fail, if it would. a stale socket file is fine because binding removes it
+    pub fn check_bind(path: &Path, datagram: bool) -> Option<String> {
+        if abstract_name(path).is_some() {
+            let ty = if datagram { Type::DGRAM } else { Type::STREAM };
+
+            let err = socket_addr(path)
+                .and_then(|addr| Socket::new(Domain::UNIX, ty, None)?.bind(&addr))
+                .err()?;
+
+            return Some(match err.kind() {
+                io::ErrorKind::AddrInUse => {
+                    "something is already listening on this abstract socket".to_string()
+                }
+                _ => err.to_string(),
+            });
+        }
+
+        is_stale(path, datagram).err().map(|x| x.to_string())
+    }
+
+    /// Ok(true) for a socket file that nothing is listening on, Ok(false) if there is no file
+    fn is_stale(path: &Path, datagram: bool) -> io::Result<bool> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(x) => x,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that isn't a socket already exists at this path",
+            ));
+        }
+
+        let in_use = if datagram {
+            std::os::unix::net::UnixDatagram::unbound()
+                .and_then(|x| x.connect(path))
+                .is_ok()
+        } else {
+            std::os::unix::net::UnixStream::connect(path).is_ok()
+        };
+
+        if in_use {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "something is already listening on this socket",
+            ));
+        }
+
+        Ok(true)
+    }
+
+    /// a nonblocking socket bound to `path`. a stale socket file there is removed first
+    fn bind(
+        path: &Path,
+        ty: Type,
+        options: &UnixSocketOptions,
+    ) -> io::Result<(Socket, SocketFile)> {
+        let addr = socket_addr(path)?;
+        let is_abstract = abstract_name(path).is_some();
+
+        // a process that crashed leaves its socket file behind and binding over it fails
+        if !is_abstract && is_stale(path, ty == Type::DGRAM)? {
+            fs::remove_file(path)?;
+
+            info!(path = %path.display(), "removed stale socket file");
+        }
+
+        let socket = Socket::new(Domain::UNIX, ty, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr)?;
+
+        // only take ownership of the file once we know we made it
+        let file = SocketFile {
+            path: (!is_abstract).then(|| path.to_path_buf()),
+        };
+
+        if !is_abstract {
+            options.apply(path)?;
+        }
+
+        Ok((socket, file))
+    }
+
+    /// this registers with the current runtime's reactor, so enter the right one first.
+    /// a socket handed over by an upgrade is used instead of binding a new one
+    pub fn bind_listener(
+        path: &Path,
+        options: &UnixSocketOptions,
+    ) -> io::Result<(UnixListener, SocketFile)> {
+        if let Some(fd) = upgrade::take(&ListenTarget::Unix(path.to_path_buf())) {
+            let x = std::os::unix::net::UnixListener::from(fd);
+            x.set_nonblocking(true)?;
+
+            return Ok((UnixListener::from_std(x)?, SocketFile::adopt(path)));
+        }
+
+        let (socket, file) = bind(path, Type::STREAM, options)?;
+
+        socket.listen(1024)?;
+
+        Ok((UnixListener::from_std(socket.into())?, file))
+    }
+
+    /// this registers with the current runtime's reactor, so enter the right one first
+    pub fn bind_datagram(
+        path: &Path,
+        options: &UnixSocketOptions,
+    ) -> io::Result<(UnixDatagram, SocketFile)> {
+        let (socket, file) = bind(path, Type::DGRAM, options)?;
+
+        Ok((UnixDatagram::from_std(socket.into())?, file))
+    }
+
+    pub async fn connect_stream(path: &Path) -> io::Result<UnixStream> {
+        if abstract_name(path).is_none() {
+            return UnixStream::connect(path).await;
+        }
+
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+
+        // unix sockets connect right away or fail. there is nothing to wait for
+        socket.connect(&socket_addr(path)?)?;
+
+        UnixStream::from_std(socket.into())
+    }
+
+    pub fn connect_datagram(socket: &UnixDatagram, path: &Path) -> io::Result<()> {
+        SockRef::from(socket).connect(&socket_addr(path)?)
+    }
+
+    /// tokio only sends to paths
+    pub async fn send_to(socket: &UnixDatagram, buf: &[u8], path: &Path) -> io::Result<usize> {
+        if abstract_name(path).is_none() {
+            return socket.send_to(buf, path).await;
+        }
+
+        let addr = socket_addr(path)?;
+
+        socket
+            .async_io(Interest::WRITABLE, || {
+                SockRef::from(socket).send_to(buf, &addr)
+            })
+            .await
+    }
+
+    /// the target can only reply to a socket with an address. linux gives an unnamed socket one if it binds to an empty path
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn unbound_datagram() -> io::Result<UnixDatagram> {
+        let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+        socket.bind(&SockAddr::unix("")?)?;
+        socket.set_nonblocking(true)?;
+
+        UnixDatagram::from_std(socket.into())
+    }
+
+    /// elsewhere we'd have to make and clean up a file, so the target can't reply
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn unbound_datagram() -> io::Result<UnixDatagram> {
+        UnixDatagram::unbound()
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+
+    use super::{SocketFile, UnixSocketOptions};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets only exist on unix",
+        )
+    }
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum SocketAddr {}
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum UnixStream {}
+
+    impl UnixStream {
+        pub fn into_split(self) -> (ReadHalf<Self>, WriteHalf<Self>) {
+            tokio::io::split(self)
+        }
+    }
+
+    impl AsyncRead for UnixStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+
+    impl AsyncWrite for UnixStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum UnixListener {}
+
+    impl UnixListener {
+        pub async fn accept(&self) -> io::Result<(UnixStream, SocketAddr)> {
+            match *self {}
+        }
+    }
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum UnixDatagram {}
+
+    impl UnixDatagram {
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            match *self {}
+        }
+
+        pub async fn readable(&self) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn try_recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            match *self {}
+        }
+
+        pub async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+            match *self {}
+        }
+
+        pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            match *self {}
+        }
+    }
+
+    pub fn peer_path(addr: SocketAddr) -> Option<PathBuf> {
+        match addr {}
+    }
+
+    pub fn check_bind(_path: &Path, _datagram: bool) -> Option<String> {
+        Some(unsupported().to_string())
+    }
+
+    pub fn bind_listener(
+        _path: &Path,
+        _options: &UnixSocketOptions,
+    ) -> io::Result<(UnixListener, SocketFile)> {
+        Err(unsupported())
+    }
+
+    pub fn bind_datagram(
+        _path: &Path,
+        _options: &UnixSocketOptions,
+    ) -> io::Result<(UnixDatagram, SocketFile)> {
+        Err(unsupported())
+    }
+
+    pub async fn connect_stream(_path: &Path) -> io::Result<UnixStream> {
+        Err(unsupported())
+    }
+
+    pub fn connect_datagram(socket: &UnixDatagram, _path: &Path) -> io::Result<()> {
+        match *socket {}
+    }
+
+    pub async fn send_to(socket: &UnixDatagram, _buf: &[u8], _path: &Path) -> io::Result<usize> {
+        match *socket {}
+    }
+
+    pub fn unbound_datagram() -> io::Result<UnixDatagram> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,769 @@
+//! Upgrading the server binary without dropping tunnels.
+//!
+//! On SIGUSR2, or when an embedder calls `ReverseProxyServerHandle::upgrade`, the server starts a new copy of its binary with the same arguments and hands it every listening socket as an inherited file descriptor.
+//! Once the new process says it is listening, the old one stops accepting, tells its tunnel clients to reconnect with `GoAway`, and exits when its streams finish.
+//!
+//! Both processes share the QUIC socket until the old one exits. Each process starts its connection ids with a different byte, so the old one
+//! can tell which packets are for its connections. It keeps reading the socket and passes everything else to the new process over a unix socket pair.
+//! The new process only reads the socket itself once the old one is gone.
+//!
+//! The types exist everywhere so the rest of the code doesn't need cfgs, but upgrading only works on Unix.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use quinn_proto::{ConnectionId, ConnectionIdGenerator, RandomConnectionIdGenerator};
+
+#[cfg(unix)]
+pub use unix::{
+    handed_over, is_inherited, notify_ready, spawn_successor, take, QuicHandover, QuicSocket,
+    RelaySocket,
+};
+
+#[cfg(unix)]
+pub use std::os::fd::OwnedFd;
+
+#[cfg(not(unix))]
+pub use other::{
+    handed_over, is_inherited, notify_ready, spawn_successor, take, OwnedFd, QuicHandover,
+    QuicSocket, RelaySocket,
+};
+
+/// the inherited sockets, one "fd target" per line like "7 tcp 127.0.0.1:8080"
+pub const FDS_ENV: &str = "QUIC_TUNNEL_UPGRADE_FDS";
+
+/// the new process writes to this fd once it is listening
+pub const READY_ENV: &str = "QUIC_TUNNEL_UPGRADE_READY";
+
+/// "socket relay" fd pairs. the old process passes packets for a QUIC socket's new connections through its relay
+pub const RELAYS_ENV: &str = "QUIC_TUNNEL_UPGRADE_RELAYS";
+
+/// the old process's connection id prefix. ours is the next one
+pub const CID_PREFIX_ENV: &str = "QUIC_TUNNEL_UPGRADE_CID_PREFIX";
+
+/// quinn's default
+const CID_LEN: usize = 8;
+
+/// how long the old process waits for its streams before closing them
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+static CID_PREFIX: OnceLock<u8> = OnceLock::new();
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// true if an old server started us
+pub fn is_successor() -> bool {
+    std::env::var_os(FDS_ENV).is_some()
+}
+
+/// we might be upgraded, so our connection ids need a prefix. call this before building the endpoints
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// the first byte of our connection ids, if we are or might be part of an upgrade.
+/// it only has to be different from the process we are upgrading from or to
+pub fn cid_prefix() -> Option<u8> {
+    if !ENABLED.load(Ordering::SeqCst) && !is_successor() {
+        return None;
+    }
+
+    Some(*CID_PREFIX.get_or_init(|| {
+        std::env::var(CID_PREFIX_ENV)
+            .ok()
+            .and_then(|x| x.parse::<u8>().ok())
+            .map_or(0, |x| x.wrapping_add(1))
+    }))
+}
+
+/// random connection ids that start with `prefix`. see `cid_prefix`
+pub fn cid_generator(prefix: u8) -> Box<dyn ConnectionIdGenerator> {
+    Box::new(PrefixedCids {
+        prefix,
+        random: RandomConnectionIdGenerator::new(CID_LEN),
+    })
+}
+
+struct PrefixedCids {
+    prefix: u8,
+    random: RandomConnectionIdGenerator,
+}
+
+impl ConnectionIdGenerator for PrefixedCids {
+    fn generate_cid(&mut self) -> ConnectionId {
+        let mut x = self.random.generate_cid().to_vec();
+        x[0] = self.prefix;
+
+        ConnectionId::new(&x)
+    }
+
+    fn cid_len(&self) -> usize {
+        CID_LEN
+    }
+
+    fn cid_lifetime(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::collections::HashMap;
+    use std::io::{self, IoSliceMut, Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::task::{ready, Context as TaskContext, Poll};
+    use std::time::Duration;
+
+    use anyhow::Context;
+    use quinn::udp::{EcnCodepoint, RecvMeta, Transmit, UdpState};
+    use quinn::{AsyncUdpSocket, Runtime};
+    use socket2::{Domain, SockRef, Socket, Type};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+    use tokio::process::Command;
+    use tokio::time::timeout;
+    use tracing::{debug, info, warn};
+
+    use super::{cid_prefix, CID_LEN, CID_PREFIX_ENV, FDS_ENV, READY_ENV, RELAYS_ENV};
+    use crate::listen::ListenTarget;
+
+    /// ecn, family, address, port, destination family, destination address
+    const RELAY_HEADER_LEN: usize = 1 + 1 + 16 + 2 + 1 + 16;
+
+    /// how long the new process has to load its config and start listening
+    const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+    static INHERITED: OnceLock<Mutex<HashMap<String, Vec<OwnedFd>>>> = OnceLock::new();
+
+    static RELAYS: OnceLock<Mutex<HashMap<RawFd, OwnedFd>>> = OnceLock::new();
+
+    static READY_SENT: AtomicBool = AtomicBool::new(false);
+
+    static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+    fn inherited() -> &'static Mutex<HashMap<String, Vec<OwnedFd>>> {
+        INHERITED.get_or_init(|| {
+            let mut x: HashMap<String, Vec<OwnedFd>> = HashMap::new();
+
+            for (fd, target) in std::env::var(FDS_ENV)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+            {
+                let Ok(fd) = fd.parse::<RawFd>() else {
+                    warn!(fd, "ignoring a bad fd in {}", FDS_ENV);
+                    continue;
+                };
+
+                // SAFETY: the old process left this fd open just for us and nothing else in this process knows about it
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+                x.entry(target.to_string()).or_default().push(fd);
+            }
+
+            Mutex::new(x)
+        })
+    }
+
+    /// the socket the old process was listening on for `target`, if there was one. each socket can only be taken once
+    pub fn take(target: &ListenTarget) -> Option<OwnedFd> {
+        let mut x = inherited().lock().expect("inherited sockets lock poisoned");
+
+        let fds = x.get_mut(&target.to_string())?;
+
+        (!fds.is_empty()).then(|| fds.remove(0))
+    }
+
+    /// the relay the old process passes `socket`'s packets through, if it was inherited
+    fn take_relay(socket: RawFd) -> Option<OwnedFd> {
+        let relays = RELAYS.get_or_init(|| {
+            let mut x = HashMap::new();
+
+            for (socket, relay) in std::env::var(RELAYS_ENV)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+            {
+                let (Ok(socket), Ok(relay)) = (socket.parse::<RawFd>(), relay.parse::<RawFd>())
+                else {
+                    warn!(socket, relay, "ignoring a bad fd in {}", RELAYS_ENV);
+                    continue;
+                };
+
+                // SAFETY: same as the inherited sockets
+                x.insert(socket, unsafe { OwnedFd::from_raw_fd(relay) });
+            }
+
+            Mutex::new(x)
+        });
+
+        relays
+            .lock()
+            .expect("inherited relays lock poisoned")
+            .remove(&socket)
+    }
+
+    /// the first byte of the connection id a packet is for. the client picks the id for its first packets and we pick it after that
+    fn dst_cid_prefix(packet: &[u8]) -> Option<u8> {
+        let first = *packet.first()?;
+
+        // short headers have the connection id right away. long headers have a version and its length first
+        if first & 0x80 == 0 {
+            return packet.get(1).copied();
+        }
+
+        (*packet.get(5)? as usize == CID_LEN)
+            .then(|| packet.get(6).copied())
+            .flatten()
+    }
+
+    /// A UDP socket for a QUIC server that might be upgraded.
+    #[derive(Debug)]
+    pub struct QuicSocket {
+        /// what we were told to listen on. the new process looks sockets up by it
+        pub listen: SocketAddr,
+        socket: std::net::UdpSocket,
+        relay: Arc<Mutex<Relay>>,
+        /// from the old process, until `into_async` registers it with the runtime
+        inherited_relay: Option<OwnedFd>,
+    }
+
+    #[derive(Debug)]
+    enum Relay {
+        /// read the socket ourselves
+        Direct,
+        /// the old process reads the socket and passes our packets through this, until it exits
+        Following(AsyncFd<Socket>),
+        /// we were upgraded. packets that aren't for our connections go through this to the new process
+        Forwarding { relay: Socket, prefix: u8 },
+    }
+
+    impl QuicSocket {
+        /// a socket that was handed over by an upgrade comes with a relay from the old process
+        pub fn new(listen: SocketAddr, socket: std::net::UdpSocket) -> Self {
+            let inherited_relay = take_relay(socket.as_raw_fd());
+
+            Self {
+                listen,
+                socket,
+                relay: Arc::new(Mutex::new(Relay::Direct)),
+                inherited_relay,
+            }
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+
+        /// a copy of the socket for the new process, and a way to start forwarding to it
+        pub fn handover(&self) -> io::Result<QuicHandover> {
+            Ok(QuicHandover {
+                listen: self.listen,
+                socket: self.socket.try_clone()?.into(),
+                relay: self.relay.clone(),
+            })
+        }
+
+        /// this registers with the current runtime's reactor, so enter the right one first
+        pub fn into_async(self, runtime: &dyn Runtime) -> io::Result<RelaySocket> {
+            if let Some(x) = self.inherited_relay {
+                let x = Socket::from(x);
+                x.set_nonblocking(true)?;
+
+                *self.relay.lock().expect("relay lock poisoned") =
+                    Relay::Following(AsyncFd::new(x)?);
+            }
+
+            Ok(RelaySocket {
+                inner: runtime.wrap_udp_socket(self.socket)?,
+                relay: self.relay,
+            })
+        }
+    }
+
+    /// See `QuicSocket::handover`.
+    #[derive(Debug)]
+    pub struct QuicHandover {
+        listen: SocketAddr,
+        socket: OwnedFd,
+        relay: Arc<Mutex<Relay>>,
+    }
+
+    /// what quinn reads and writes. it sends on the socket like normal and only does something different when reading during an upgrade
+    #[derive(Debug)]
+    pub struct RelaySocket {
+        inner: Box<dyn AsyncUdpSocket>,
+        relay: Arc<Mutex<Relay>>,
+    }
+
+    impl AsyncUdpSocket for RelaySocket {
+        fn poll_send(
+            &self,
+            state: &UdpState,
+            cx: &mut TaskContext,
+            transmits: &[Transmit],
+        ) -> Poll<io::Result<usize>> {
+            self.inner.poll_send(state, cx, transmits)
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut TaskContext,
+            bufs: &mut [IoSliceMut<'_>],
+            meta: &mut [RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            let mut relay = self.relay.lock().expect("relay lock poisoned");
+
+            if let Relay::Following(x) = &*relay {
+                match poll_relayed(x, cx, bufs, meta) {
+                    Poll::Ready(Ok(0)) => {
+                        info!("the old server is gone. reading the QUIC socket");
+                        *relay = Relay::Direct;
+                    }
+                    x => return x,
+                }
+            }
+
+            let n = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+
+            if let Relay::Forwarding { relay, prefix } = &*relay {
+                for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()).take(n) {
+                    forward(relay, *prefix, &mut buf[..meta.len], meta);
+                }
+            }
+
+            Poll::Ready(Ok(n))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        fn may_fragment(&self) -> bool {
+            self.inner.may_fragment()
+        }
+    }
+
+    /// send the packets in `buf` that aren't for our connections to the new process, and move ours to the front.
+    /// with GRO, `buf` is several packets of `meta.stride` bytes from the same peer, which might be for either process
+    fn forward(relay: &Socket, prefix: u8, buf: &mut [u8], meta: &mut RecvMeta) {
+        let stride = if meta.stride == 0 {
+            buf.len()
+        } else {
+            meta.stride
+        };
+
+        let mut kept = 0;
+
+        for start in (0..buf.len()).step_by(stride.max(1)) {
+            let end = (start + stride).min(buf.len());
+
+            if dst_cid_prefix(&buf[start..end]) == Some(prefix) {
+                buf.copy_within(start..end, kept);
+                kept += end - start;
+                continue;
+            }
+
+            let mut x = Vec::with_capacity(RELAY_HEADER_LEN + end - start);
+            encode_relay_header(meta, &mut x);
+            x.extend_from_slice(&buf[start..end]);
+
+            // it is UDP. if the new process is behind, QUIC resends
+            if let Err(err) = (&*relay).write(&x) {
+                debug!(?err, "unable to pass a packet to the new server");
+            }
+        }
+
+        // quinn skips empty buffers
+        meta.len = kept;
+    }
+
+    /// read packets the old process passed us. Ok(0) once it is gone
+    fn poll_relayed(
+        relay: &AsyncFd<Socket>,
+        cx: &mut TaskContext,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(relay.poll_read_ready(cx))?;
+
+            let mut n = 0;
+
+            while n < bufs.len() {
+                let buf = &mut *bufs[n];
+
+                match guard.try_io(|x| x.get_ref().read(buf)) {
+                    Ok(Ok(len)) if len >= RELAY_HEADER_LEN => {
+                        meta[n] =
+                            decode_relay_header(&buf[..RELAY_HEADER_LEN], len - RELAY_HEADER_LEN);
+                        buf.copy_within(RELAY_HEADER_LEN..len, 0);
+                        n += 1;
+                    }
+                    // the relay is a SEQPACKET pair, so this is the end and not an empty packet
+                    Ok(Ok(0)) if n == 0 => return Poll::Ready(Ok(0)),
+                    Ok(Ok(0)) => break,
+                    Ok(Ok(_)) => warn!("ignoring a truncated packet from the old server"),
+                    Ok(Err(err)) if n == 0 => {
+                        debug!(?err, "the relay from the old server failed");
+                        return Poll::Ready(Ok(0));
+                    }
+                    Ok(Err(_)) => break,
+                    Err(_would_block) => break,
+                }
+            }
+
+            if n > 0 {
+                return Poll::Ready(Ok(n));
+            }
+        }
+    }
+
+    fn encode_ip(x: IpAddr, out: &mut Vec<u8>) {
+        match x {
+            IpAddr::V4(x) => {
+                out.push(4);
+                out.extend_from_slice(&x.to_ipv6_mapped().octets());
+            }
+            IpAddr::V6(x) => {
+                out.push(6);
+                out.extend_from_slice(&x.octets());
+            }
+        }
+    }
+
+    fn decode_ip(family: u8, octets: &[u8]) -> Option<IpAddr> {
+        let x = Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?);
+
+        match family {
+            4 => x.to_ipv4_mapped().map(IpAddr::V4),
+            6 => Some(IpAddr::V6(x)),
+            _ => None,
+        }
+    }
+
+    fn encode_relay_header(meta: &RecvMeta, out: &mut Vec<u8>) {
+        out.push(meta.ecn.map_or(0, |x| x as u8));
+
+        encode_ip(meta.addr.ip(), out);
+        out.extend_from_slice(&meta.addr.port().to_be_bytes());
+
+        match meta.dst_ip {
+            Some(x) => encode_ip(x, out),
+            None => out.extend_from_slice(&[0; 17]),
+        }
+    }
+
+    fn decode_relay_header(x: &[u8], len: usize) -> RecvMeta {
+        let ip = decode_ip(x[1], &x[2..18]).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let port = u16::from_be_bytes([x[18], x[19]]);
+
+        RecvMeta {
+            addr: SocketAddr::new(ip, port),
+            len,
+            stride: len,
+            ecn: EcnCodepoint::from_bits(x[0]),
+            dst_ip: decode_ip(x[20], &x[21..37]),
+        }
+    }
+
+    /// true if `take` has a socket for `target`. it is already bound, so there is nothing to check
+    pub fn is_inherited(target: &ListenTarget) -> bool {
+        let x = inherited().lock().expect("inherited sockets lock poisoned");
+
+        x.get(&target.to_string()).is_some_and(|x| !x.is_empty())
+    }
+
+    /// true once a new process has taken our sockets. their socket files are its to remove now
+    pub fn handed_over() -> bool {
+        HANDED_OVER.load(Ordering::SeqCst)
+    }
+
+    /// tell the old process that we are listening, if it started us. inherited sockets the new config doesn't use are closed
+    pub async fn notify_ready() {
+        let Ok(fd) = std::env::var(READY_ENV) else {
+            return;
+        };
+
+        if READY_SENT.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        for (target, fds) in inherited()
+            .lock()
+            .expect("inherited sockets lock poisoned")
+            .drain()
+        {
+            if !fds.is_empty() {
+                info!(%target, "closing a handed over socket that isn't in this config");
+            }
+        }
+
+        if let Some(x) = RELAYS.get() {
+            x.lock().expect("inherited relays lock poisoned").clear();
+        }
+
+        let Ok(fd) = fd.parse::<RawFd>() else {
+            warn!(fd, "ignoring a bad fd in {}", READY_ENV);
+            return;
+        };
+
+        // SAFETY: same as the inherited sockets. READY_SENT makes sure this is only done once
+        let x = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+
+        let x = async {
+            x.set_nonblocking(true)?;
+
+            UnixStream::from_std(x)?.write_all(b"ready").await
+        };
+
+        if let Err(err) = x.await {
+            warn!(?err, "unable to tell the old process we are ready");
+        }
+    }
+
+    /// start a new copy of this binary with our arguments, `sockets`, and `quic`, and wait until it is listening. returns its pid.
+    ///
+    /// The sockets keep working here too. Stop accepting on them once this returns. The QUIC sockets start passing packets for the new process's
+    /// connections to it.
+    pub async fn spawn_successor(
+        sockets: &[(ListenTarget, OwnedFd)],
+        quic: &[QuicHandover],
+    ) -> anyhow::Result<u32> {
+        for x in quic {
+            if matches!(
+                *x.relay.lock().expect("relay lock poisoned"),
+                Relay::Following(_)
+            ) {
+                anyhow::bail!(
+                    "the last upgrade is still draining. try again once the old server exits"
+                );
+            }
+        }
+
+        let prefix = cid_prefix().context("upgrades were never enabled")?;
+
+        let exe = std::env::current_exe().context("unable to find our own binary")?;
+
+        let (mut ours, theirs) = UnixStream::pair()?;
+
+        // SEQPACKET keeps packets apart and tells the new process when we are gone
+        let relays = quic
+            .iter()
+            .map(|_| Socket::pair(Domain::UNIX, Type::SEQPACKET, None))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut fds = String::new();
+
+        for (target, fd) in sockets {
+            fds.push_str(&format!("{} {}\n", fd.as_raw_fd(), target));
+        }
+
+        let mut relay_fds = String::new();
+
+        for (x, (_, theirs)) in quic.iter().zip(relays.iter()) {
+            fds.push_str(&format!(
+                "{} {}\n",
+                x.socket.as_raw_fd(),
+                ListenTarget::Udp(x.listen)
+            ));
+
+            relay_fds.push_str(&format!(
+                "{} {}\n",
+                x.socket.as_raw_fd(),
+                theirs.as_raw_fd()
+            ));
+        }
+
+        // only the new process should get these. anything else we spawn later shouldn't hold our sockets open
+        let set_inheritable = |x: bool| -> io::Result<()> {
+            for (_, fd) in sockets {
+                SockRef::from(fd).set_cloexec(!x)?;
+            }
+
+            for (q, (_, theirs)) in quic.iter().zip(relays.iter()) {
+                SockRef::from(&q.socket).set_cloexec(!x)?;
+                theirs.set_cloexec(!x)?;
+            }
+
+            SockRef::from(&theirs).set_cloexec(!x)
+        };
+
+        set_inheritable(true)?;
+
+        let child = Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .env(FDS_ENV, fds)
+            .env(RELAYS_ENV, relay_fds)
+            .env(CID_PREFIX_ENV, prefix.to_string())
+            .env(READY_ENV, theirs.as_raw_fd().to_string())
+            .stdin(std::process::Stdio::null())
+            .spawn();
+
+        set_inheritable(false)?;
+
+        // otherwise our copies keep the pairs open and we never see the new process exit
+        drop(theirs);
+
+        let relays = relays.into_iter().map(|(ours, _)| ours).collect::<Vec<_>>();
+
+        let mut child = child.with_context(|| format!("unable to start {}", exe.display()))?;
+
+        let pid = child.id().unwrap_or_default();
+
+        info!(pid, exe = %exe.display(), "started the new server");
+
+        let mut buf = [0; 5];
+
+        let x = match timeout(READY_TIMEOUT, ours.read_exact(&mut buf)).await {
+            Ok(Ok(_)) if &buf == b"ready" => Ok(pid),
+            Ok(Ok(_)) => Err(anyhow::anyhow!("the new server said something strange")),
+            Ok(Err(_)) => Err(anyhow::anyhow!(
+                "the new server exited before it was listening. check its logs"
+            )),
+            Err(_) => Err(anyhow::anyhow!(
+                "the new server wasn't listening after {:?}",
+                READY_TIMEOUT
+            )),
+        };
+
+        if x.is_ok() {
+            HANDED_OVER.store(true, Ordering::SeqCst);
+
+            for (q, relay) in quic.iter().zip(relays) {
+                relay.set_nonblocking(true)?;
+
+                *q.relay.lock().expect("relay lock poisoned") = Relay::Forwarding { relay, prefix };
+            }
+        } else {
+            // it might be stuck holding our sockets. we are still serving, so it has to go
+            let _ = child.kill().await;
+        }
+
+        x
+    }
+}
+
+#[cfg(not(unix))]
+mod other {
+    use std::io::{self, IoSliceMut};
+    use std::net::SocketAddr;
+    use std::task::{Context, Poll};
+
+    use quinn::udp::{RecvMeta, Transmit, UdpState};
+    use quinn::{AsyncUdpSocket, Runtime};
+
+    use crate::listen::ListenTarget;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "upgrades only work on unix")
+    }
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum OwnedFd {}
+
+    impl From<OwnedFd> for std::net::UdpSocket {
+        fn from(x: OwnedFd) -> Self {
+            match x {}
+        }
+    }
+
+    impl From<OwnedFd> for std::net::TcpListener {
+        fn from(x: OwnedFd) -> Self {
+            match x {}
+        }
+    }
+
+    pub fn take(_target: &ListenTarget) -> Option<OwnedFd> {
+        None
+    }
+
+    pub fn is_inherited(_target: &ListenTarget) -> bool {
+        false
+    }
+
+    pub fn handed_over() -> bool {
+        false
+    }
+
+    pub async fn notify_ready() {}
+
+    pub async fn spawn_successor(
+        _sockets: &[(ListenTarget, OwnedFd)],
+        _quic: &[QuicHandover],
+    ) -> anyhow::Result<u32> {
+        Err(unsupported().into())
+    }
+
+    /// A UDP socket for a QUIC server. It can't be upgraded here.
+    #[derive(Debug)]
+    pub struct QuicSocket {
+        pub listen: SocketAddr,
+        socket: std::net::UdpSocket,
+    }
+
+    impl QuicSocket {
+        pub fn new(listen: SocketAddr, socket: std::net::UdpSocket) -> Self {
+            Self { listen, socket }
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+
+        pub fn handover(&self) -> io::Result<QuicHandover> {
+            Err(unsupported())
+        }
+
+        /// this registers with the current runtime's reactor, so enter the right one first
+        pub fn into_async(self, runtime: &dyn Runtime) -> io::Result<RelaySocket> {
+            Ok(RelaySocket {
+                inner: runtime.wrap_udp_socket(self.socket)?,
+            })
+        }
+    }
+
+    /// can't be made outside of unix
+    #[derive(Debug)]
+    pub enum QuicHandover {}
+
+    /// what quinn reads and writes. only the socket, since nothing is relayed
+    #[derive(Debug)]
+    pub struct RelaySocket {
+        inner: Box<dyn AsyncUdpSocket>,
+    }
+
+    impl AsyncUdpSocket for RelaySocket {
+        fn poll_send(
+            &self,
+            state: &UdpState,
+            cx: &mut Context,
+            transmits: &[Transmit],
+        ) -> Poll<io::Result<usize>> {
+            self.inner.poll_send(state, cx, transmits)
+        }
+
+        fn poll_recv(
+            &self,
+            cx: &mut Context,
+            bufs: &mut [IoSliceMut<'_>],
+            meta: &mut [RecvMeta],
+        ) -> Poll<io::Result<usize>> {
+            self.inner.poll_recv(cx, bufs, meta)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+
+        fn may_fragment(&self) -> bool {
+            self.inner.may_fragment()
+        }
+    }
+}
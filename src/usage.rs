@@ -0,0 +1,317 @@
+//! Bytes per tunnel client identity, kept across reconnects and restarts, for usage reports and quotas.
+//!
+//! A client's identity is the SHA-256 fingerprint of its certificate, the same one in the logs and traces. Its streams are
+//! counted towards it as well as towards their connection and listener. Streams that start before a 0-RTT handshake
+//! finishes don't know the certificate yet, so they aren't counted here.
+//!
+//! With a file, the totals are loaded when the server starts and saved every [`SAVE_INTERVAL`] and when it stops. Each save
+//! adds what changed since the one before to what is in the file, so two processes sharing the file during an upgrade
+//! don't undo each other's counts.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::counters::{CounterSet, CounterSnapshot};
+
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// everything counted for one identity
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClientUsage {
+    #[serde(flatten)]
+    pub counts: CounterSnapshot,
+    /// tunnel connections, counted once their handshake finishes
+    pub connections: u64,
+    #[serde(with = "humantime_serde")]
+    pub first_seen: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub last_seen: SystemTime,
+    /// the calendar month `month_bytes` is for, in UTC, like "2026-10"
+    #[serde(default)]
+    pub month: String,
+    /// bytes in both directions this month. see the `quota` module
+    #[serde(default)]
+    pub month_bytes: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UsageFile {
+    /// keyed by identity
+    clients: BTreeMap<String, ClientUsage>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    counts: Arc<CounterSet>,
+    connections: u64,
+    first_seen: SystemTime,
+    last_seen: SystemTime,
+    /// what `counts` and `connections` were at the last save
+    saved: CounterSnapshot,
+    saved_connections: u64,
+    month: String,
+    /// bytes counted before `month` started
+    month_base: u64,
+}
+
+impl Entry {
+    fn new(now: SystemTime) -> Self {
+        Self {
+            counts: Default::default(),
+            connections: 0,
+            first_seen: now,
+            last_seen: now,
+            saved: Default::default(),
+            saved_connections: 0,
+            month: month(now),
+            month_base: 0,
+        }
+    }
+
+    /// starts the month over if it has changed
+    fn usage(&mut self) -> ClientUsage {
+        let counts = self.counts.snapshot();
+        let month = month(SystemTime::now());
+
+        if self.month != month {
+            self.month = month;
+            self.month_base = bytes(&counts);
+        }
+
+        ClientUsage {
+            month_bytes: bytes(&counts).saturating_sub(self.month_base),
+            counts,
+            connections: self.connections,
+            first_seen: self.first_seen,
+            last_seen: self.last_seen,
+            month: self.month.clone(),
+        }
+    }
+}
+
+/// data in both directions
+fn bytes(x: &CounterSnapshot) -> u64 {
+    x.bytes_sent.saturating_add(x.bytes_recv)
+}
+
+/// like "2026-10", in UTC
+fn month(x: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(x).to_string()[..7].to_string()
+}
+
+/// Totals for every identity this server has seen, and the file they are saved to.
+#[derive(Debug)]
+pub struct UsageStore {
+    path: Option<PathBuf>,
+    clients: Mutex<BTreeMap<String, Entry>>,
+    /// saves read the file and write it back, so only one at a time
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl UsageStore {
+    /// load the totals in `path`, if there is one. a file that doesn't exist yet is empty
+    pub async fn open(path: Option<PathBuf>) -> io::Result<Arc<Self>> {
+        let file = match &path {
+            Some(x) => read_file(x).await?,
+            None => Default::default(),
+        };
+
+        let clients = file
+            .clients
+            .into_iter()
+            .map(|(id, x)| {
+                let entry = Entry {
+                    counts: Arc::new(CounterSet::from(&x.counts)),
+                    connections: x.connections,
+                    first_seen: x.first_seen,
+                    last_seen: x.last_seen,
+                    month_base: bytes(&x.counts).saturating_sub(x.month_bytes),
+                    month: x.month,
+                    saved: x.counts,
+                    saved_connections: x.connections,
+                };
+
+                (id, entry)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(x) = &path {
+            info!(path = %x.display(), clients = clients.len(), "loaded client usage");
+        }
+
+        Ok(Arc::new(Self {
+            path,
+            clients: Mutex::new(clients),
+            saving: Default::default(),
+        }))
+    }
+
+    /// a tunnel connection from `identity` finished its handshake
+    pub fn connected(&self, identity: &str) {
+        let now = SystemTime::now();
+
+        let mut clients = self.clients.lock().unwrap();
+
+        let x = clients
+            .entry(identity.to_string())
+            .or_insert_with(|| Entry::new(now));
+
+        x.connections += 1;
+        x.last_seen = now;
+    }
+
+    /// what to count `identity`'s streams in. see `ScopedCounters::with_identity`
+    pub fn counters(&self, identity: &str) -> Arc<CounterSet> {
+        let now = SystemTime::now();
+
+        let mut clients = self.clients.lock().unwrap();
+
+        let x = clients
+            .entry(identity.to_string())
+            .or_insert_with(|| Entry::new(now));
+
+        x.last_seen = now;
+
+        x.counts.clone()
+    }
+
+    /// what `identity` has used this month, for quotas
+    pub fn month_bytes(&self, identity: &str) -> u64 {
+        self.clients
+            .lock()
+            .unwrap()
+            .get_mut(identity)
+            .map_or(0, |x| x.usage().month_bytes)
+    }
+
+    /// keyed by identity. what was in the file when we started, and what we have counted since
+    pub fn snapshot(&self) -> BTreeMap<String, ClientUsage> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|(id, x)| (id.clone(), x.usage()))
+            .collect()
+    }
+
+    /// add what changed since the last save to the file. does nothing without a file
+    pub async fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let _saving = self.saving.lock().await;
+
+        let mut file = read_file(path).await?;
+
+        let current: BTreeMap<_, _> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .map(|(id, x)| {
+                (
+                    id.clone(),
+                    (x.usage(), x.saved.clone(), x.saved_connections),
+                )
+            })
+            .collect();
+
+        for (id, (x, saved, saved_connections)) in current.iter() {
+            let counts = x.counts.combine(saved, u64::saturating_sub);
+            let connections = x.connections.saturating_sub(*saved_connections);
+
+            // a save just after the month starts puts the last minute of the old month in the new one
+            match file.clients.get_mut(id) {
+                Some(y) => {
+                    if y.month < x.month {
+                        y.month = x.month.clone();
+                        y.month_bytes = 0;
+                    }
+
+                    y.counts = y.counts.combine(&counts, u64::saturating_add);
+                    y.month_bytes = y.month_bytes.saturating_add(bytes(&counts));
+                    y.connections += connections;
+                    y.first_seen = y.first_seen.min(x.first_seen);
+                    y.last_seen = y.last_seen.max(x.last_seen);
+                }
+                None => {
+                    file.clients.insert(
+                        id.clone(),
+                        ClientUsage {
+                            month_bytes: bytes(&counts),
+                            counts,
+                            connections,
+                            ..x.clone()
+                        },
+                    );
+                }
+            }
+        }
+
+        write_file(path, &file).await?;
+
+        // only once it is on disk, so a failed save is tried again next time
+        let mut clients = self.clients.lock().unwrap();
+
+        for (id, (x, _, _)) in current {
+            if let Some(entry) = clients.get_mut(&id) {
+                entry.saved = x.counts;
+                entry.saved_connections = x.connections;
+            }
+        }
+
+        debug!(path = %path.display(), clients = clients.len(), "saved client usage");
+
+        Ok(())
+    }
+
+    /// save every `SAVE_INTERVAL` until `shutdown` is cancelled. the last save is up to whoever stops the server,
+    /// once its streams are done
+    pub async fn save_loop(self: Arc<Self>, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let mut ticks = interval_at(Instant::now() + SAVE_INTERVAL, SAVE_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            select! {
+                _ = ticks.tick() => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+
+            if let Err(err) = self.save().await {
+                warn!(?err, "unable to save client usage");
+            }
+        }
+    }
+}
+
+async fn read_file(path: &Path) -> io::Result<UsageFile> {
+    match tokio::fs::read(path).await {
+        Ok(x) => serde_json::from_slice(&x).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {err}", path.display()),
+            )
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Default::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// through a temporary file, so a crash while writing leaves the old totals. the pid keeps two processes' apart
+async fn write_file(path: &Path, file: &UsageFile) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(file)?).await?;
+    tokio::fs::rename(&tmp, path).await
+}
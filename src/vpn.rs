@@ -0,0 +1,391 @@
+//! A point-to-point layer 3 VPN: a TUN device on each side, with the IP packets between them in QUIC datagrams.
+//!
+//! The server takes the first address in its pool, like 10.8.0.1 in 10.8.0.0/24, and gives each client the next free one
+//! in an `Address` frame. The client brings up its device with that address and the pool's prefix, so the whole pool routes
+//! through the tunnel. Routing more than the pool, like a LAN behind the server, is up to the routes and forwarding rules on
+//! each side.
+//!
+//! Packets the server's device reads go to the client with that destination, and are dropped if no client has it. The
+//! server drops packets from a client whose source isn't the client's address, so clients can't pretend to be each other.
+//! Datagrams are lost and reordered like packets on any network, and TCP inside the tunnel copes with that the same way,
+//! without QUIC retransmitting under it. Only IPv4, and only with quic-tunnel built with the `tun` feature.
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use ipnet::{IpNet, Ipv4Net};
+use quinn::{Connection, SendDatagramError};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tokio_util::bytes::Bytes;
+use tracing::{debug, info, trace, warn};
+
+use crate::counters::{ScopedCounters, TunnelCounters};
+use crate::error::TunnelError;
+use crate::protocol::{ControlMessage, PREAMBLE_TIMEOUT};
+use crate::shutdown::CancellationToken;
+use crate::transform::{BoxedRead, BoxedWrite};
+
+/// fits in a QUIC datagram on the smallest path QUIC allows, so nothing is dropped for being too big before the path MTU is
+/// found
+pub const DEFAULT_MTU: u16 = 1160;
+
+/// packets from clients waiting for the server's device. more are dropped, like a router's queue
+const QUEUE_LEN: usize = 1024;
+
+/// an IPv4 header without options
+const MIN_HEADER_LEN: usize = 20;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunOptions {
+    /// like "qt0". the OS picks one if not set
+    pub name: Option<String>,
+    pub mtu: u16,
+}
+
+impl Default for TunOptions {
+    fn default() -> Self {
+        Self {
+            name: None,
+            mtu: DEFAULT_MTU,
+        }
+    }
+}
+
+/// the addresses the server gives out
+#[derive(Debug)]
+pub struct AddressPool {
+    net: Ipv4Net,
+    taken: Mutex<HashSet<Ipv4Addr>>,
+}
+
+impl AddressPool {
+    /// the server takes the first host in `net`, so it needs room for at least one client too
+    pub fn new(net: Ipv4Net) -> Result<Self, TunnelError> {
+        if net.hosts().nth(1).is_none() {
+            return Err(TunnelError::Config(format!(
+                "the pool {net} has no room for clients"
+            )));
+        }
+
+        Ok(Self {
+            net,
+            taken: Default::default(),
+        })
+    }
+
+    /// ours, with the pool's prefix
+    pub fn server_addr(&self) -> Ipv4Net {
+        self.with_prefix(self.net.hosts().next().unwrap())
+    }
+
+    /// the lowest free address, with the pool's prefix. `None` if all are given out
+    pub fn take(&self) -> Option<Ipv4Net> {
+        let mut taken = self.taken.lock().unwrap();
+
+        let x = self.net.hosts().skip(1).find(|x| !taken.contains(x))?;
+
+        taken.insert(x);
+
+        Some(self.with_prefix(x))
+    }
+
+    pub fn give_back(&self, x: Ipv4Addr) {
+        self.taken.lock().unwrap().remove(&x);
+    }
+
+    fn with_prefix(&self, x: Ipv4Addr) -> Ipv4Net {
+        Ipv4Net::new(x, self.net.prefix_len()).unwrap()
+    }
+}
+
+struct Peer {
+    conn: Connection,
+    counts: ScopedCounters,
+}
+
+/// one TUN device for every client on the endpoint
+pub struct VpnServer {
+    pool: AddressPool,
+    /// each client's address and connection
+    peers: Arc<Mutex<HashMap<Ipv4Addr, Peer>>>,
+    /// packets for the device
+    to_device: flume::Sender<Bytes>,
+    counts: Arc<TunnelCounters>,
+}
+
+impl VpnServer {
+    /// bring up the device with the pool's first address. the task reads and writes it until `shutdown` is cancelled
+    pub fn start(
+        pool: AddressPool,
+        options: &TunOptions,
+        counts: Arc<TunnelCounters>,
+        shutdown: CancellationToken,
+    ) -> Result<(Arc<Self>, JoinHandle<anyhow::Result<()>>), TunnelError> {
+        let (mut read, mut write) = create_device(pool.server_addr(), options)?;
+
+        info!(addr = %pool.server_addr(), "tun device up");
+
+        let peers: Arc<Mutex<HashMap<Ipv4Addr, Peer>>> = Default::default();
+
+        let (to_device, from_peers) = flume::bounded::<Bytes>(QUEUE_LEN);
+
+        let writer = async move {
+            while let Ok(x) = from_peers.recv_async().await {
+                write.write_all(&x).await?;
+            }
+
+            Ok::<_, anyhow::Error>(())
+        };
+
+        let reader = {
+            let peers = peers.clone();
+            let mtu = options.mtu as usize;
+
+            async move {
+                let mut buf = vec![0; mtu];
+
+                loop {
+                    let n = read.read(&mut buf).await?;
+
+                    if n == 0 {
+                        anyhow::bail!("the tun device closed");
+                    }
+
+                    let Some((_, destination)) = ipv4_addrs(&buf[..n]) else {
+                        trace!(n, "dropping a packet that isn't IPv4");
+                        continue;
+                    };
+
+                    let peers = peers.lock().unwrap();
+
+                    let Some(peer) = peers.get(&destination) else {
+                        trace!(%destination, "no client has this address");
+                        continue;
+                    };
+
+                    if send_packet(&peer.conn, &buf[..n]) {
+                        peer.counts.sent(n, 0);
+                    }
+                }
+            }
+        };
+
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                x = reader => x,
+                x = writer => x,
+                _ = shutdown.cancelled() => Ok(()),
+            }
+        });
+
+        let x = Self {
+            pool,
+            peers,
+            to_device,
+            counts,
+        };
+
+        Ok((Arc::new(x), handle))
+    }
+
+    /// give `conn` an address and send its packets to the device until it closes
+    pub async fn serve_connection(&self, conn: &Connection) -> anyhow::Result<()> {
+        let Some(addr) = self.pool.take() else {
+            warn!(peer = %conn.remote_address(), "the address pool is used up");
+
+            anyhow::bail!("no addresses left in the pool");
+        };
+
+        let x = self.run_connection(conn, addr).await;
+
+        self.peers.lock().unwrap().remove(&addr.addr());
+        self.pool.give_back(addr.addr());
+
+        debug!(%addr, "address given back");
+
+        x
+    }
+
+    async fn run_connection(&self, conn: &Connection, addr: Ipv4Net) -> anyhow::Result<()> {
+        let counts = self.counts.connection(conn.stable_id() as u64);
+
+        // uni streams are off, so the client's half of this one is never used
+        let (mut tx, _rx) = conn.open_bi().await?;
+
+        let x = ControlMessage::Address { addr: addr.into() };
+
+        tx.write_all(&x.encode()?).await?;
+        tx.finish().await?;
+
+        info!(peer = %conn.remote_address(), %addr, "tun client connected");
+
+        self.peers.lock().unwrap().insert(
+            addr.addr(),
+            Peer {
+                conn: conn.clone(),
+                counts: counts.clone(),
+            },
+        );
+
+        loop {
+            let x = match conn.read_datagram().await {
+                Ok(x) => x,
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                    debug!("connection closed");
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            match ipv4_addrs(&x) {
+                Some((source, _)) if source == addr.addr() => {}
+                _ => {
+                    trace!(%addr, "dropping a packet that isn't from the client's address");
+                    continue;
+                }
+            }
+
+            counts.recv(x.len(), 0);
+
+            if self.to_device.try_send(x).is_err() {
+                trace!("the device is behind. dropping a packet");
+            }
+        }
+    }
+}
+
+/// wait for the server's `Address`, bring up a device with it, and move packets until the connection closes
+pub async fn run_client(
+    conn: &Connection,
+    options: &TunOptions,
+    counts: ScopedCounters,
+) -> anyhow::Result<()> {
+    let (_tx, mut rx) = conn.accept_bi().await?;
+
+    let addr = match ControlMessage::read(&mut rx, PREAMBLE_TIMEOUT).await? {
+        Some(ControlMessage::Address { addr: IpNet::V4(x) }) => x,
+        x => anyhow::bail!("expected an IPv4 Address, got {x:?}"),
+    };
+
+    if let Some(max) = conn.max_datagram_size() {
+        if options.mtu as usize > max {
+            warn!(
+                mtu = options.mtu,
+                max, "bigger packets than fit in a datagram on this path will be dropped"
+            );
+        }
+    }
+
+    let (mut read, mut write) = create_device(addr, options)?;
+
+    info!(%addr, "tun device up");
+
+    let to_tunnel = async {
+        let mut buf = vec![0; options.mtu as usize];
+
+        loop {
+            let n = read.read(&mut buf).await?;
+
+            if n == 0 {
+                anyhow::bail!("the tun device closed");
+            }
+
+            if send_packet(conn, &buf[..n]) {
+                counts.sent(n, 0);
+            }
+        }
+    };
+
+    let from_tunnel = async {
+        loop {
+            let x = match conn.read_datagram().await {
+                Ok(x) => x,
+                Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+                    debug!("connection closed");
+                    return Ok(());
+                }
+                Err(err) => return Err(anyhow::Error::from(err)),
+            };
+
+            counts.recv(x.len(), 0);
+
+            write.write_all(&x).await?;
+        }
+    };
+
+    tokio::select! {
+        x = to_tunnel => x,
+        x = from_tunnel => x,
+    }
+}
+
+/// false if the packet was dropped. a router drops what it can't send, and so do we
+fn send_packet(conn: &Connection, packet: &[u8]) -> bool {
+    match conn.send_datagram(Bytes::copy_from_slice(packet)) {
+        Ok(()) => true,
+        Err(SendDatagramError::TooLarge) => {
+            trace!(n = packet.len(), "dropping a packet too big for a datagram");
+            false
+        }
+        Err(err) => {
+            trace!(?err, "dropping a packet");
+            false
+        }
+    }
+}
+
+/// the source and destination of an IPv4 packet
+fn ipv4_addrs(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    if packet.len() < MIN_HEADER_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+
+    let source: [u8; 4] = packet[12..16].try_into().unwrap();
+    let destination: [u8; 4] = packet[16..20].try_into().unwrap();
+
+    Some((source.into(), destination.into()))
+}
+
+#[cfg(feature = "tun")]
+fn create_device(
+    addr: Ipv4Net,
+    options: &TunOptions,
+) -> Result<(BoxedRead, BoxedWrite), TunnelError> {
+    let mut config = tun::Configuration::default();
+
+    config
+        .address(addr.addr())
+        .netmask(addr.netmask())
+        .mtu(options.mtu as i32)
+        .up();
+
+    if let Some(x) = &options.name {
+        config.name(x);
+    }
+
+    #[cfg(target_os = "linux")]
+    config.platform(|x| {
+        x.packet_information(false);
+    });
+
+    let device = tun::create_as_async(&config)
+        .map_err(|err| TunnelError::Config(format!("creating the tun device: {err}")))?;
+
+    let (read, write) = tokio::io::split(device);
+
+    Ok((Box::new(read), Box::new(write)))
+}
+
+#[cfg(not(feature = "tun"))]
+fn create_device(
+    _addr: Ipv4Net,
+    _options: &TunOptions,
+) -> Result<(BoxedRead, BoxedWrite), TunnelError> {
+    Err(TunnelError::Unsupported(
+        "tun devices without the tun feature",
+    ))
+}
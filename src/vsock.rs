@@ -0,0 +1,316 @@
+//! Linux vsock sockets, for bridging a VM guest and its host (Firecracker, cloud-hypervisor, QEMU) without a network between them.
+//!
+//! Addresses are `cid:port`. The host is cid 2 and `any` listens on every cid. The types exist everywhere, but binding or connecting only works on Linux.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use linux::{check_bind, VsockListener, VsockStream};
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub use other::{check_bind, VsockListener, VsockStream};
+
+/// VMADDR_CID_ANY
+pub const CID_ANY: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl FromStr for VsockAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("\"{s}\" is not a vsock address like 3:5000 or any:5000");
+
+        let (cid, port) = s.split_once(':').ok_or_else(err)?;
+
+        let cid = match cid {
+            "any" => CID_ANY,
+            x => x.parse().map_err(|_| err())?,
+        };
+
+        Ok(Self {
+            cid,
+            port: port.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+impl VsockAddr {
+    /// true if binding both would fight over the same port
+    pub fn conflicts_with(&self, other: &Self) -> bool {
+        self.port == other.port
+            && (self.cid == other.cid || self.cid == CID_ANY || other.cid == CID_ANY)
+    }
+}
+
+impl Display for VsockAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.cid {
+            CID_ANY => write!(f, "any:{}", self.port),
+            x => write!(f, "{x}:{}", self.port),
+        }
+    }
+}
+
+/// serialized like it is displayed, "3:5000"
+impl Serialize for VsockAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for VsockAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::net::Shutdown;
+    use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+
+    use socket2::{Domain, SockAddr, Socket, Type};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::VsockAddr;
+
+    /// std doesn't name errno values
+    const EINPROGRESS: i32 = 115;
+
+    /// tokio doesn't have vsock, so this drives a nonblocking socket with its reactor
+    #[derive(Debug)]
+    pub struct VsockListener(AsyncFd<Socket>);
+
+    fn bind_socket(addr: VsockAddr) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SockAddr::vsock(addr.cid, addr.port))?;
+
+        Ok(socket)
+    }
+
+    /// bind and close right away. works without a runtime
+    pub fn check_bind(addr: VsockAddr) -> io::Result<()> {
+        bind_socket(addr).map(drop)
+    }
+
+    impl VsockListener {
+        /// this registers with the current runtime's reactor, so enter the right one first
+        pub fn bind(addr: VsockAddr) -> io::Result<Self> {
+            let socket = bind_socket(addr)?;
+            socket.listen(1024)?;
+
+            Ok(Self(AsyncFd::new(socket)?))
+        }
+
+        /// a listening socket from somewhere else, like the server we are upgrading from
+        pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+            let socket = Socket::from(fd);
+            socket.set_nonblocking(true)?;
+
+            Ok(Self(AsyncFd::new(socket)?))
+        }
+
+        pub async fn accept(&self) -> io::Result<(VsockStream, Option<VsockAddr>)> {
+            loop {
+                let mut guard = self.0.readable().await?;
+
+                let Ok(x) = guard.try_io(|x| x.get_ref().accept()) else {
+                    continue;
+                };
+
+                let (socket, peer) = x?;
+                socket.set_nonblocking(true)?;
+
+                let peer = peer
+                    .as_vsock_address()
+                    .map(|(cid, port)| VsockAddr { cid, port });
+
+                return Ok((VsockStream(AsyncFd::new(socket)?), peer));
+            }
+        }
+    }
+
+    impl AsFd for VsockListener {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.0.get_ref().as_fd()
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct VsockStream(AsyncFd<Socket>);
+
+    impl VsockStream {
+        pub async fn connect(addr: VsockAddr) -> io::Result<Self> {
+            let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+            socket.set_nonblocking(true)?;
+
+            match socket.connect(&SockAddr::vsock(addr.cid, addr.port)) {
+                Ok(()) => {}
+                Err(err) if err.raw_os_error() == Some(EINPROGRESS) => {}
+                Err(err) => return Err(err),
+            }
+
+            let socket = AsyncFd::new(socket)?;
+
+            // a nonblocking connect is done when the socket is writable. the result is in SO_ERROR
+            let _ = socket.writable().await?;
+
+            if let Some(err) = socket.get_ref().take_error()? {
+                return Err(err);
+            }
+
+            Ok(Self(socket))
+        }
+    }
+
+    impl AsyncRead for VsockStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+                // SAFETY: recv only writes initialized bytes into the buffer and returns how many
+                let unfilled: &mut [MaybeUninit<u8>] = unsafe { buf.unfilled_mut() };
+
+                match guard.try_io(|x| x.get_ref().recv(unfilled)) {
+                    Ok(Ok(n)) => {
+                        // SAFETY: recv initialized the first n bytes
+                        unsafe { buf.assume_init(n) };
+                        buf.advance(n);
+
+                        return Poll::Ready(Ok(()));
+                    }
+                    Ok(Err(err)) => return Poll::Ready(Err(err)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for VsockStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.0.poll_write_ready(cx))?;
+
+                match guard.try_io(|x| x.get_ref().send(buf)) {
+                    Ok(x) => return Poll::Ready(x),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.0.get_ref().shutdown(Shutdown::Write))
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod other {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::VsockAddr;
+    use crate::upgrade::OwnedFd;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "vsock only exists on linux")
+    }
+
+    pub fn check_bind(_addr: VsockAddr) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// can't be made outside of linux
+    #[derive(Debug)]
+    pub enum VsockListener {}
+
+    impl VsockListener {
+        pub fn bind(_addr: VsockAddr) -> io::Result<Self> {
+            Err(unsupported())
+        }
+
+        pub fn from_fd(_fd: OwnedFd) -> io::Result<Self> {
+            Err(unsupported())
+        }
+
+        pub async fn accept(&self) -> io::Result<(VsockStream, Option<VsockAddr>)> {
+            match *self {}
+        }
+    }
+
+    #[cfg(unix)]
+    impl std::os::fd::AsFd for VsockListener {
+        fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+            match *self {}
+        }
+    }
+
+    /// can't be made outside of linux
+    #[derive(Debug)]
+    pub enum VsockStream {}
+
+    impl VsockStream {
+        pub async fn connect(_addr: VsockAddr) -> io::Result<Self> {
+            Err(unsupported())
+        }
+    }
+
+    impl AsyncRead for VsockStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+
+    impl AsyncWrite for VsockStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match *self.get_mut() {}
+        }
+    }
+}
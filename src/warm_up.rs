@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Hand queued streams to a freshly connected client slowly at first, so a backend that just came back up isn't hit with the whole backlog at once.
+///
+/// Like TCP slow start, the number of streams the client may have been given doubles every `duration / DOUBLINGS` until `duration` has passed.
+#[derive(Debug)]
+pub struct WarmUp {
+    start: Instant,
+    duration: Duration,
+    initial_streams: u64,
+    assigned: u64,
+}
+
+/// how many times the allowance doubles during the warm up
+const DOUBLINGS: u32 = 8;
+
+impl WarmUp {
+    /// a `duration` of 0 disables the warm up
+    pub fn new(duration: Duration, initial_streams: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            duration,
+            initial_streams: initial_streams.max(1) as u64,
+            assigned: 0,
+        }
+    }
+
+    /// how long to wait before assigning another stream. `None` once warmed up
+    pub fn delay(&self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.start);
+
+        if elapsed >= self.duration {
+            return None;
+        }
+
+        if self.assigned < self.initial_streams {
+            return None;
+        }
+
+        let step = self.duration / DOUBLINGS;
+
+        // the allowance is initial_streams * 2^(elapsed / step). solve for when it passes what we've already assigned
+        let doublings = ((self.assigned + 1) as f64 / self.initial_streams as f64).log2();
+
+        let ready_at = step.mul_f64(doublings).min(self.duration);
+
+        ready_at.checked_sub(elapsed).filter(|x| !x.is_zero())
+    }
+
+    pub fn assigned(&mut self) {
+        self.assigned += 1;
+    }
+}
@@ -0,0 +1,367 @@
+//! HTTP webhooks for tunnel lifecycle events, for alerts in Slack, PagerDuty, and friends without scraping logs.
+//!
+//! Each event is POSTed as JSON: the same object as its line in the `audit` log, plus a `text` summary, which is what
+//! Slack's incoming webhooks show. Hooks get `client_connected`, `client_disconnected`, `auth_failed`, and
+//! `quota_exceeded` unless they pick their own `events`. `stream` is there too, but only for hooks that ask for it.
+//!
+//! Events are sent one at a time from their own thread, so a slow webhook never holds up the tunnel. A failed POST is tried
+//! [`ATTEMPTS`] times, and then dropped with a warning. So are events that arrive while [`MAX_QUEUED`] are waiting.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerName, StreamOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{debug, warn};
+
+use crate::audit::{AuditEvent, AuditLine};
+
+pub const ATTEMPTS: u32 = 3;
+pub const MAX_QUEUED: usize = 1024;
+
+/// for connecting, and again for the response
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// what hooks get without `events`
+const DEFAULT_EVENTS: &[&str] = &[
+    "client_connected",
+    "client_disconnected",
+    "auth_failed",
+    "quota_exceeded",
+];
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: WebhookUrl,
+    /// like "auth_failed". see `AuditEvent::KINDS`
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// sent with every POST, like "Authorization"
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl WebhookConfig {
+    pub fn new(url: WebhookUrl) -> Self {
+        Self {
+            url,
+            events: vec![],
+            headers: Default::default(),
+        }
+    }
+
+    pub fn wants(&self, kind: &str) -> bool {
+        if self.events.is_empty() {
+            DEFAULT_EVENTS.contains(&kind)
+        } else {
+            self.events.iter().any(|x| x == kind)
+        }
+    }
+}
+
+/// an http:// or https:// URL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookUrl {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// with the query
+    pub path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("\"{s}\" is not a URL like https://hooks.example.com/path");
+
+        let (tls, rest) = if let Some(x) = s.strip_prefix("https://") {
+            (true, x)
+        } else if let Some(x) = s.strip_prefix("http://") {
+            (false, x)
+        } else {
+            return Err(err());
+        };
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+
+        let default_port = if tls { 443 } else { 80 };
+
+        // "[::1]:8080", "example.com:8080", or without the port
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(x) => match x.split_once(']') {
+                Some((host, "")) => (host, default_port),
+                Some((host, port)) => (
+                    host,
+                    port.strip_prefix(':')
+                        .and_then(|x| x.parse().ok())
+                        .ok_or_else(err)?,
+                ),
+                None => return Err(err()),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().map_err(|_| err())?),
+                None => (authority, default_port),
+            },
+        };
+
+        if host.is_empty() || authority.contains('@') {
+            return Err(err());
+        }
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+impl WebhookUrl {
+    /// the host, and the port if it isn't the scheme's. for the Host header
+    pub fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+
+        match (self.tls, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{host}:{port}"),
+        }
+    }
+
+    /// without the path, which for services like Slack is the secret. for logs
+    pub fn redacted(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+
+        format!("{scheme}://{}/…", self.authority())
+    }
+}
+
+impl Display for WebhookUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+/// serialized like it is displayed
+impl Serialize for WebhookUrl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for WebhookUrl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where events go. Without hooks, they go nowhere.
+#[derive(Debug, Default)]
+pub struct Webhooks {
+    queue: Option<flume::Sender<(Arc<WebhookConfig>, Vec<u8>)>>,
+    hooks: Vec<Arc<WebhookConfig>>,
+    /// queued or being sent
+    pending: Arc<AtomicUsize>,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<WebhookConfig>) -> anyhow::Result<Self> {
+        if hooks.is_empty() {
+            return Ok(Default::default());
+        }
+
+        let tls = Arc::new(tls_config()?);
+
+        let (queue, jobs) = flume::bounded::<(Arc<WebhookConfig>, Vec<u8>)>(MAX_QUEUED);
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let worker_pending = pending.clone();
+
+        std::thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || {
+                for (hook, body) in jobs.iter() {
+                    deliver(&hook, &body, &tls);
+
+                    worker_pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+            .context("starting the webhook thread")?;
+
+        Ok(Self {
+            queue: Some(queue),
+            hooks: hooks.into_iter().map(Arc::new).collect(),
+            pending,
+        })
+    }
+
+    pub fn wants(&self, kind: &str) -> bool {
+        self.hooks.iter().any(|x| x.wants(kind))
+    }
+
+    /// queue `event` for every hook that wants it
+    pub fn send(&self, event: &AuditEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        let kind = event.kind();
+
+        if !self.wants(kind) {
+            return;
+        }
+
+        let body = serde_json::to_value(AuditLine::now(event)).and_then(|mut x| {
+            if let Some(x) = x.as_object_mut() {
+                x.insert("text".to_string(), event.summary().into());
+            }
+
+            serde_json::to_vec(&x)
+        });
+
+        let body = match body {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, kind, "unable to encode a webhook");
+                return;
+            }
+        };
+
+        for hook in self.hooks.iter().filter(|x| x.wants(kind)) {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+
+            if queue.try_send((hook.clone(), body.clone())).is_err() {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+
+                warn!(url = %hook.url.redacted(), kind, "too many webhooks waiting. dropping one");
+            }
+        }
+    }
+
+    /// wait up to `timeout` for queued events to be sent, like before exiting
+    pub async fn flush(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        while self.pending.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+fn tls_config() -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+
+    // a cert the system has but rustls can't parse shouldn't stop the others from working
+    for x in
+        rustls_native_certs::load_native_certs().context("loading the system's CA certificates")?
+    {
+        let _ = roots.add(&rustls::Certificate(x.0));
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// POST `body`, trying again a few times
+fn deliver(hook: &WebhookConfig, body: &[u8], tls: &Arc<ClientConfig>) {
+    for attempt in 1..=ATTEMPTS {
+        match post(hook, body, tls) {
+            Ok(()) => {
+                debug!(url = %hook.url.redacted(), "sent webhook");
+                return;
+            }
+            Err(err) if attempt == ATTEMPTS => {
+                warn!(url = %hook.url.redacted(), ?err, "unable to send webhook. dropping it");
+            }
+            Err(err) => {
+                debug!(url = %hook.url.redacted(), ?err, attempt, "unable to send webhook. trying again");
+
+                std::thread::sleep(Duration::from_secs(attempt.into()));
+            }
+        }
+    }
+}
+
+fn post(hook: &WebhookConfig, body: &[u8], tls: &Arc<ClientConfig>) -> anyhow::Result<()> {
+    let url = &hook.url;
+
+    let addr = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("{} has no addresses", url.host))?;
+
+    let socket = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut stream: Box<dyn ReadWrite> = if url.tls {
+        let name = ServerName::try_from(url.host.as_str())?;
+
+        Box::new(StreamOwned::new(
+            ClientConnection::new(tls.clone(), name)?,
+            socket,
+        ))
+    } else {
+        Box::new(socket)
+    };
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: quic-tunnel/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.authority(),
+        env!("CARGO_PKG_VERSION"),
+        body.len(),
+    );
+
+    for (name, value) in hook.headers.iter() {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    // only the status matters
+    let mut status = String::new();
+    BufReader::new(stream.take(1024)).read_line(&mut status)?;
+
+    let code = status.split(' ').nth(1).and_then(|x| x.parse::<u16>().ok());
+
+    match code {
+        Some(200..=299) => Ok(()),
+        Some(x) => bail!("{} answered {x}", url.redacted()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not an HTTP response: {status:?}"),
+        )
+        .into()),
+    }
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
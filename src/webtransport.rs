@@ -0,0 +1,511 @@
+//! A WebTransport frontend for the reverse proxy server, so web pages can open tunneled streams without a client of their own.
+//!
+//! A browser opens a session with an extended CONNECT for `/{route}`, like `new WebTransport("https://example.com:4443/ssh")`.
+//! Every bidirectional stream it opens in that session is then handled like a user connecting to the listener with that route:
+//! its allow list and rate limits apply, and a tunnel client forwards it to the backend. Datagrams and unidirectional streams aren't
+//! tunneled.
+//!
+//! Browsers don't have our client certs, so unlike the tunnel and MASQUE endpoints, anyone can connect. They do need to trust the
+//! server's cert, so `WebTransportOptions::cert` should usually be one from a public CA. This follows draft-ietf-webtrans-http3-02,
+//! which is what browsers speak.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::Context as _;
+use flume::Sender;
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tracing::{debug, info, info_span, trace, Instrument};
+
+use crate::error::TunnelError;
+use crate::h3::{self, encode_fields, read_varint, CapsuleReader, Frame, H3Error};
+use crate::protocol::PREAMBLE_TIMEOUT;
+use crate::quic::{transport_config, TransportOptions};
+use crate::shutdown::CancellationToken;
+use crate::stream::Stream;
+use crate::tls::{self, TlsOptions};
+
+pub const ALPN: &[u8] = b"h3";
+
+pub(crate) const SETTING_ENABLE_WEBTRANSPORT: u64 = 0x2b603742;
+const SETTING_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671706a;
+/// the draft name for `h3::SETTING_H3_DATAGRAM`. some browsers still want it
+const SETTING_H3_DATAGRAM_DRAFT04: u64 = 0xffd277;
+
+/// the first thing on a bidirectional stream that belongs to a session, followed by the session id
+pub(crate) const STREAM_WEBTRANSPORT: u64 = 0x41;
+
+const CAPSULE_CLOSE_SESSION: u64 = 0x2843;
+
+/// sessions a browser may have open on one connection
+const MAX_SESSIONS: u64 = 16;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebTransportOptions {
+    /// where browsers connect with HTTP/3
+    pub listen: SocketAddr,
+    /// a cert chain browsers trust for this server's name. defaults to the tunnel's server cert
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    /// the key for `cert`
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+}
+
+/// a bidirectional stream a browser opened in a session
+#[derive(Debug)]
+pub struct WebTransportStream {
+    send: SendStream,
+    recv: RecvStream,
+    peer_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+}
+
+impl WebTransportStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn into_split(self) -> (RecvStream, SendStream) {
+        (self.recv, self.send)
+    }
+}
+
+impl AsyncRead for WebTransportStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebTransportStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send)
+            .poll_write(cx, buf)
+            .map_err(Into::into)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+/// an HTTP/3 endpoint for browsers. `ca`, `cert`, and `key` are the tunnel's, used when `options` has no cert of its own
+pub fn build_webtransport_endpoint(
+    ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+    options: &WebTransportOptions,
+    transport: &TransportOptions,
+    tls_options: &TlsOptions,
+) -> Result<Endpoint, TunnelError> {
+    let chain = match (options.cert.clone(), options.key.clone()) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => {
+            return Err(TunnelError::Config(
+                "a WebTransport cert needs a key, and a key needs a cert".to_string(),
+            ))
+        }
+    };
+
+    let mut tls_config = tls::build_public_server_config(chain, ca, cert, key, tls_options)
+        .map_err(|err| TunnelError::Tls(err.into()))?;
+
+    tls_config.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(tls_config));
+
+    let mut transport_config = transport_config(transport)?;
+
+    // the browser's control stream and its two QPACK streams
+    transport_config.max_concurrent_uni_streams(3u32.into());
+
+    server_config.transport_config(Arc::new(transport_config));
+    server_config.migration(transport.migration.unwrap_or(true));
+
+    Endpoint::server(server_config, options.listen).map_err(|source| TunnelError::Bind {
+        addr: options.listen,
+        source,
+    })
+}
+
+/// accept browsers on `endpoint` until `stop` is cancelled. each stream they open goes to `streams` with the route its session is for
+pub async fn serve(
+    endpoint: Endpoint,
+    routes: Vec<String>,
+    streams: Sender<(String, Stream)>,
+    stop: CancellationToken,
+) {
+    let shared = Arc::new(Shared {
+        routes,
+        streams,
+        local_addr: endpoint.local_addr().ok(),
+    });
+
+    loop {
+        let conn = select! {
+            x = endpoint.accept() => x,
+            _ = stop.cancelled() => break,
+        };
+
+        let Some(conn) = conn else {
+            break;
+        };
+
+        let span = info_span!("webtransport", peer = %conn.remote_address());
+
+        let f = handle_connection(conn, shared.clone());
+
+        tokio::spawn(
+            async move {
+                if let Err(err) = f.await {
+                    debug!(?err, "connection closed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    routes: Vec<String>,
+    streams: Sender<(String, Stream)>,
+    local_addr: Option<SocketAddr>,
+}
+
+/// the route of each open session on one connection, by session id
+type Sessions = Arc<watch::Sender<HashMap<u64, String>>>;
+
+async fn handle_connection(conn: Connecting, shared: Arc<Shared>) -> anyhow::Result<()> {
+    let conn = timeout(PREAMBLE_TIMEOUT, conn)
+        .await
+        .context("handshake timed out")??;
+
+    debug!("browser connected");
+
+    let control = h3::open_control(
+        &conn,
+        &[
+            (h3::SETTING_QPACK_MAX_TABLE_CAPACITY, 0),
+            (h3::SETTING_MAX_FIELD_SECTION_SIZE, h3::MAX_FRAME_LEN),
+            (h3::SETTING_ENABLE_CONNECT_PROTOCOL, 1),
+            (h3::SETTING_H3_DATAGRAM, 1),
+            (SETTING_H3_DATAGRAM_DRAFT04, 1),
+            (SETTING_ENABLE_WEBTRANSPORT, 1),
+            (SETTING_WEBTRANSPORT_MAX_SESSIONS, MAX_SESSIONS),
+        ],
+    )
+    .await?;
+
+    // nothing the browser sets changes what we do
+    let (settings_tx, _settings_rx) = watch::channel(None);
+
+    let sessions = Sessions::default();
+
+    let err = select! {
+        x = h3::accept_uni_streams(&conn, settings_tx) => x,
+        x = accept_streams(&conn, &shared, &sessions) => x,
+    };
+
+    drop(control);
+
+    match err {
+        Err(err) => {
+            if let Some(x) = err.downcast_ref::<H3Error>() {
+                conn.close(x.code().into(), x.to_string().as_bytes());
+            }
+
+            Err(err)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn accept_streams(
+    conn: &Connection,
+    shared: &Arc<Shared>,
+    sessions: &Sessions,
+) -> anyhow::Result<()> {
+    loop {
+        let (tx, rx) = match conn.accept_bi().await {
+            Ok(x) => x,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let conn = conn.clone();
+        let shared = shared.clone();
+        let sessions = sessions.clone();
+
+        let span = info_span!("stream", stream_id = rx.id().index());
+
+        tokio::spawn(
+            async move {
+                if let Err(err) = handle_stream(conn, shared, sessions, tx, rx).await {
+                    debug!(?err, "stream failed");
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// a stream starts with either a session's CONNECT request or the id of the session it belongs to
+async fn handle_stream(
+    conn: Connection,
+    shared: Arc<Shared>,
+    sessions: Sessions,
+    mut tx: SendStream,
+    mut rx: RecvStream,
+) -> anyhow::Result<()> {
+    let ty = match timeout(PREAMBLE_TIMEOUT, read_varint(&mut rx)).await {
+        Ok(x) => x?,
+        Err(_) => anyhow::bail!("nothing on the stream after {PREAMBLE_TIMEOUT:?}"),
+    };
+
+    match ty {
+        Some(STREAM_WEBTRANSPORT) => {
+            let session_id = read_varint(&mut rx)
+                .await?
+                .context("stream ended before its session id")?;
+
+            // the stream can get here before its session's CONNECT has been read
+            let mut rx_sessions = sessions.subscribe();
+            let route = timeout(
+                PREAMBLE_TIMEOUT,
+                rx_sessions.wait_for(|x| x.contains_key(&session_id)),
+            )
+            .await
+            .ok()
+            .and_then(|x| x.ok())
+            .and_then(|x| x.get(&session_id).cloned());
+
+            let Some(route) = route else {
+                let _ = rx.stop(h3::H3_REQUEST_REJECTED.into());
+                let _ = tx.reset(h3::H3_REQUEST_REJECTED.into());
+
+                anyhow::bail!("stream for unknown session {session_id}");
+            };
+
+            trace!(session_id, route, "session stream");
+
+            let stream = WebTransportStream {
+                send: tx,
+                recv: rx,
+                peer_addr: conn.remote_address(),
+                local_addr: shared.local_addr,
+            };
+
+            shared
+                .streams
+                .send_async((route, Stream::WebTransport(stream)))
+                .await
+                .context("the server stopped taking streams")
+        }
+        Some(h3::FRAME_HEADERS) => {
+            let session = Session {
+                id: VarInt::from(rx.id()).into_inner(),
+                shared,
+                sessions,
+            };
+
+            session.handle(tx, rx).await
+        }
+        Some(ty) => {
+            let _ = rx.stop(h3::H3_FRAME_UNEXPECTED.into());
+            let _ = tx.reset(h3::H3_FRAME_UNEXPECTED.into());
+
+            anyhow::bail!("stream starts with unexpected frame {ty:#x}")
+        }
+        None => Ok(()),
+    }
+}
+
+/// the route a session's CONNECT is for, or the status to turn it away with
+fn parse_session(
+    fields: &[(String, String)],
+    routes: &[String],
+) -> Result<String, (&'static str, String)> {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, x)| x.as_str())
+    };
+
+    if field(":method") != Some("CONNECT") {
+        return Err(("405", "only WebTransport is supported".to_string()));
+    }
+
+    if field(":protocol") != Some("webtransport") {
+        return Err(("501", "only WebTransport is supported".to_string()));
+    }
+
+    if field(":scheme") != Some("https") || field(":authority").is_none() {
+        return Err(("400", "missing :scheme or :authority".to_string()));
+    }
+
+    let path = field(":path").unwrap_or_default();
+
+    let route = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/');
+
+    match routes.iter().find(|x| *x == route) {
+        Some(x) => Ok(x.clone()),
+        None => Err(("404", format!("no listener has route {route:?}"))),
+    }
+}
+
+struct Session {
+    /// the CONNECT stream's id
+    id: u64,
+    shared: Arc<Shared>,
+    sessions: Sessions,
+}
+
+/// forgets a session when its CONNECT stream ends
+struct SessionGuard {
+    id: u64,
+    sessions: Sessions,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.send_modify(|x| {
+            x.remove(&self.id);
+        });
+    }
+}
+
+impl Session {
+    async fn handle(self, mut tx: SendStream, mut rx: RecvStream) -> anyhow::Result<()> {
+        let mut pending = 0;
+
+        // the HEADERS type was already read to tell this apart from a session stream
+        let frame = timeout(
+            PREAMBLE_TIMEOUT,
+            Frame::read_after_type(&mut rx, h3::FRAME_HEADERS, &mut pending),
+        )
+        .await
+        .context("no request headers in time")?;
+
+        let fields = match frame.and_then(|x| h3::decode_fields(&x.payload)) {
+            Ok(x) => x,
+            Err(err) => {
+                let code = err.code();
+
+                let _ = rx.stop(code.into());
+                let _ = tx.reset(code.into());
+
+                return Err(err.into());
+            }
+        };
+
+        trace!(?fields, "session request");
+
+        let route = match parse_session(&fields, &self.shared.routes) {
+            Ok(x) => x,
+            Err((status, reason)) => {
+                info!(status, reason, "refused session");
+
+                let frame = Frame::new(h3::FRAME_HEADERS, encode_fields(&[(":status", status)]));
+
+                tx.write_all(&frame.encode()).await?;
+                tx.finish().await?;
+
+                return Ok(());
+            }
+        };
+
+        if self.sessions.borrow().len() as u64 >= MAX_SESSIONS {
+            let frame = Frame::new(h3::FRAME_HEADERS, encode_fields(&[(":status", "429")]));
+
+            tx.write_all(&frame.encode()).await?;
+            tx.finish().await?;
+
+            anyhow::bail!("too many sessions");
+        }
+
+        let frame = Frame::new(
+            h3::FRAME_HEADERS,
+            encode_fields(&[
+                (":status", "200"),
+                ("sec-webtransport-http3-draft", "draft02"),
+            ]),
+        );
+
+        tx.write_all(&frame.encode()).await?;
+
+        self.sessions.send_modify(|x| {
+            x.insert(self.id, route.clone());
+        });
+
+        let _guard = SessionGuard {
+            id: self.id,
+            sessions: self.sessions.clone(),
+        };
+
+        info!(route, "session opened");
+
+        // TODO: streams already handed to the server keep going after their session closes. browsers reset them on their end
+        let mut capsules = CapsuleReader::default();
+
+        let x = async {
+            while let Some(x) = Frame::read(&mut rx, &mut pending).await? {
+                if x.ty != h3::FRAME_DATA {
+                    continue;
+                }
+
+                capsules.push(&x.payload);
+
+                while let Some((ty, _)) = capsules.next_capsule()? {
+                    if ty == CAPSULE_CLOSE_SESSION {
+                        return Ok(());
+                    }
+
+                    trace!(capsule_type = ty, "ignoring capsule");
+                }
+            }
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        info!("session closed");
+
+        let _ = tx.finish().await;
+
+        x
+    }
+}
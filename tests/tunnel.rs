@@ -0,0 +1,277 @@
+use anyhow::Context;
+use quic_tunnel::client::Backend;
+use quic_tunnel::compress::CompressAlgo;
+use quic_tunnel::counters::TunnelCounters;
+use quic_tunnel::protocol::{CloseCode, Role};
+use quic_tunnel::quic::{
+    build_client_endpoint, build_server_endpoint, quic_client_config, RetryOptions,
+};
+use quic_tunnel::rendezvous;
+use quic_tunnel::testing::{echo_server, round_trip, webtransport_round_trip, TestTunnel};
+use quic_tunnel::transparent::{self, ConnectOptions};
+use quic_tunnel::webtransport::WebTransportOptions;
+use std::time::Duration;
+
+#[tokio::test]
+async fn streams_reach_the_backend_and_come_back() -> anyhow::Result<()> {
+    let backend = echo_server().await?;
+
+    let tunnel = TestTunnel::start(Backend::Tcp(backend.into())).await?;
+
+    // bigger than a stream's first flow control window, and more than one at a time
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|x| (x % 251) as u8).collect();
+
+    let answers =
+        futures::future::try_join_all((0..4).map(|_| round_trip(tunnel.listen_addr(), &data)))
+            .await?;
+
+    for x in answers {
+        assert!(
+            x == data,
+            "{} bytes came back instead of {}",
+            x.len(),
+            data.len()
+        );
+    }
+
+    tunnel.shutdown().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn compressed_streams_come_back_the_same() -> anyhow::Result<()> {
+    let backend = echo_server().await?;
+
+    let tunnel = TestTunnel::start_with(
+        Backend::Tcp(backend.into()),
+        |x| x.compress(CompressAlgo::Lz4),
+        |x| x.compress(CompressAlgo::Lz4),
+    )
+    .await?;
+
+    let data = b"hello, tunnel. ".repeat(10_000);
+
+    assert_eq!(round_trip(tunnel.listen_addr(), &data).await?, data);
+
+    tunnel.shutdown().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn connect_streams_only_reach_allowed_networks() -> anyhow::Result<()> {
+    let backend = echo_server().await?;
+
+    let tunnel = TestTunnel::start_with(
+        Backend::Tcp(backend.into()),
+        |x| {
+            x.connect(ConnectOptions {
+                allow: vec!["127.0.0.1/32".parse().unwrap()],
+            })
+        },
+        |x| x,
+    )
+    .await?;
+
+    let certs = tunnel.certs();
+
+    let endpoint = build_client_endpoint(
+        certs.ca(),
+        certs.client_cert(),
+        certs.client_key(),
+        &Default::default(),
+        &Default::default(),
+        Role::Connect,
+    )?;
+
+    let conn = endpoint.connect(tunnel.quic_addr(), "test_server")?.await?;
+
+    let (mut tx, mut rx) = transparent::connect(&conn, backend).await?;
+
+    tx.write_all(b"hello").await?;
+    tx.finish().await?;
+
+    assert_eq!(rx.read_to_end(1024).await?, b"hello");
+
+    // outside the allow list
+    let (mut tx, mut rx) = transparent::connect(&conn, "127.0.0.2:9".parse()?).await?;
+
+    // the server stops it as well
+    let _ = tx.finish().await;
+
+    match rx.read_to_end(1024).await {
+        Err(quinn::ReadToEndError::Read(quinn::ReadError::Reset(x))) => {
+            assert_eq!(x, CloseCode::Unreachable.into())
+        }
+        x => panic!("expected a reset, got {x:?}"),
+    }
+
+    conn.close(CloseCode::Done.into(), b"test done");
+    tunnel.shutdown().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn peers_meet_directly_and_through_the_relay() -> anyhow::Result<()> {
+    let backend = echo_server().await?;
+
+    let tunnel =
+        TestTunnel::start_with(Backend::Tcp(backend.into()), |x| x.rendezvous(true), |x| x).await?;
+
+    let certs = tunnel.certs();
+
+    // the peer server takes direct connections on the endpoint it reaches the rendezvous with
+    let mut peer_endpoint = build_server_endpoint(
+        certs.ca(),
+        certs.server_cert(),
+        certs.server_key(),
+        &RetryOptions {
+            require: false,
+            ..Default::default()
+        },
+        "127.0.0.1:0".parse()?,
+        &Default::default(),
+        &Default::default(),
+        &[Role::Peer],
+    )?;
+
+    peer_endpoint.set_default_client_config(quic_client_config(
+        certs.ca(),
+        certs.client_cert(),
+        certs.client_key(),
+        &Default::default(),
+        &Default::default(),
+        Role::Rendezvous,
+    )?);
+
+    let peer_conn = peer_endpoint
+        .connect(tunnel.quic_addr(), "test_server")?
+        .await?;
+
+    let (_peer_tx, mut peer_rx) = rendezvous::meet(&peer_conn, "test", true).await?;
+
+    {
+        let conn = peer_conn.clone();
+        tokio::spawn(async move {
+            rendezvous::serve_peer_streams(&conn, backend, TunnelCounters::new().connection(0))
+                .await
+        });
+
+        let endpoint = peer_endpoint.clone();
+        tokio::spawn(async move {
+            let conn = endpoint.accept().await.unwrap().await.unwrap();
+            rendezvous::serve_peer_streams(&conn, backend, TunnelCounters::new().connection(0))
+                .await
+        });
+    }
+
+    let endpoint = build_client_endpoint(
+        certs.ca(),
+        certs.client_cert(),
+        certs.client_key(),
+        &Default::default(),
+        &Default::default(),
+        Role::Rendezvous,
+    )?;
+
+    // nothing says when the peer server's name is taken, and the rendezvous closes connections that ask too early
+    let mut tries = 0;
+
+    let (conn, peer) = loop {
+        let conn = endpoint.connect(tunnel.quic_addr(), "test_server")?.await?;
+
+        let (_tx, mut rx) = rendezvous::meet(&conn, "test", false).await?;
+
+        match rendezvous::next_peer(&mut rx, Duration::from_secs(1)).await {
+            Ok(Some(x)) => break (conn, x),
+            _ if tries < 50 => {
+                tries += 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            x => anyhow::bail!("no peer: {x:?}"),
+        }
+    };
+
+    assert_eq!(peer, peer_endpoint.local_addr()?);
+
+    // the peer server hears where the client's packets come from, to punch toward it
+    let client_addr = ([127, 0, 0, 1], endpoint.local_addr()?.port()).into();
+
+    assert_eq!(
+        rendezvous::next_peer(&mut peer_rx, Duration::from_secs(5)).await?,
+        Some(client_addr)
+    );
+
+    let peer_config = quic_client_config(
+        certs.ca(),
+        certs.client_cert(),
+        certs.client_key(),
+        &Default::default(),
+        &Default::default(),
+        Role::Peer,
+    )?;
+
+    let direct = rendezvous::connect_direct(&endpoint, peer_config, peer, "test_server")
+        .await
+        .expect("a direct connection on localhost");
+
+    for conn in [&direct, &conn] {
+        let (mut tx, mut rx) = conn.open_bi().await?;
+
+        tx.write_all(b"hello").await?;
+        tx.finish().await?;
+
+        assert_eq!(rx.read_to_end(1024).await?, b"hello");
+    }
+
+    direct.close(CloseCode::Done.into(), b"test done");
+    conn.close(CloseCode::Done.into(), b"test done");
+    peer_conn.close(CloseCode::Done.into(), b"test done");
+    tunnel.shutdown().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn webtransport_sessions_reach_the_backend() -> anyhow::Result<()> {
+    let backend = echo_server().await?;
+
+    let tunnel = TestTunnel::start_with(
+        Backend::Tcp(backend.into()),
+        |x| {
+            x.webtransport(WebTransportOptions {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                cert: None,
+                key: None,
+            })
+        },
+        |x| x,
+    )
+    .await?;
+
+    let addr = tunnel
+        .server
+        .webtransport_addr()
+        .context("no WebTransport endpoint")?;
+    let ca = tunnel.certs().ca();
+
+    let data = b"hello, browser. ".repeat(10_000);
+
+    assert_eq!(
+        webtransport_round_trip(addr, &ca, "tcp", &data).await?,
+        data
+    );
+
+    // only the routes of the server's listeners
+    let err = webtransport_round_trip(addr, &ca, "nope", b"hello")
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("404"), "{err:#}");
+
+    tunnel.shutdown().await;
+
+    Ok(())
+}